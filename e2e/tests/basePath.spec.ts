@@ -0,0 +1,29 @@
+import { expect, test } from '@playwright/test';
+
+import { defaultUserAdmin, routes } from '../config';
+import { loginBasic } from '../utils/controllers/login';
+import { dockerDown, dockerRestart } from '../utils/docker';
+import { waitForBase } from '../utils/waitForBase';
+
+test.describe('Test serving under base path', () => {
+  // path of BASE_URL, e.g. /defguard when core runs with DEFGUARD_URL=http://localhost:8000/defguard
+  const basePath = new URL(routes.base).pathname.replace(/\/$/, '');
+
+  test.beforeEach(() => {
+    dockerRestart();
+  });
+
+  test.afterAll(() => dockerDown());
+
+  test('API is called under base path', async ({ page }) => {
+    await waitForBase(page);
+    const pageBasePath = await page
+      .locator('meta[name="defguard-base-path"]')
+      .getAttribute('content');
+    expect(pageBasePath).toBe(basePath);
+    const authRequest = page.waitForRequest('**/api/v1/auth');
+    await loginBasic(page, defaultUserAdmin);
+    const { pathname } = new URL((await authRequest).url());
+    expect(pathname).toBe(`${basePath}/api/v1/auth`);
+  });
+});
@@ -0,0 +1,7 @@
+CREATE TABLE
+    recovery_code_usage (
+        id bigserial PRIMARY KEY NOT NULL,
+        user_id bigint NOT NULL,
+        used_at timestamp without time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY (user_id) REFERENCES "user" (id) ON DELETE CASCADE
+    );
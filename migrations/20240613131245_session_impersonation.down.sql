@@ -0,0 +1,3 @@
+DELETE FROM "session" WHERE impersonator_id IS NOT NULL;
+ALTER TABLE "session" DROP COLUMN impersonator_session_id;
+ALTER TABLE "session" DROP COLUMN impersonator_id;
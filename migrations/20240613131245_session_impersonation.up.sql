@@ -0,0 +1,4 @@
+ALTER TABLE "session" ADD COLUMN impersonator_id bigint NULL;
+ALTER TABLE "session" ADD COLUMN impersonator_session_id text NULL;
+ALTER TABLE "session" ADD FOREIGN KEY (impersonator_id) REFERENCES "user" (id) ON DELETE CASCADE;
+ALTER TABLE "session" ADD FOREIGN KEY (impersonator_session_id) REFERENCES "session" (id) ON DELETE CASCADE;
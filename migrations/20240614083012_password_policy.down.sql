@@ -0,0 +1,10 @@
+ALTER TABLE settings
+DROP COLUMN password_min_length,
+DROP COLUMN password_require_lowercase,
+DROP COLUMN password_require_uppercase,
+DROP COLUMN password_require_digit,
+DROP COLUMN password_require_special,
+DROP COLUMN password_disallow_user_data,
+DROP COLUMN password_min_score,
+DROP COLUMN password_breach_check,
+DROP COLUMN password_breach_check_timeout;
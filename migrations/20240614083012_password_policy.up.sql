@@ -0,0 +1,10 @@
+ALTER TABLE settings
+ADD COLUMN password_min_length integer NOT NULL DEFAULT 8,
+ADD COLUMN password_require_lowercase boolean NOT NULL DEFAULT true,
+ADD COLUMN password_require_uppercase boolean NOT NULL DEFAULT true,
+ADD COLUMN password_require_digit boolean NOT NULL DEFAULT true,
+ADD COLUMN password_require_special boolean NOT NULL DEFAULT true,
+ADD COLUMN password_disallow_user_data boolean NOT NULL DEFAULT false,
+ADD COLUMN password_min_score integer NULL,
+ADD COLUMN password_breach_check boolean NOT NULL DEFAULT false,
+ADD COLUMN password_breach_check_timeout integer NOT NULL DEFAULT 2000;
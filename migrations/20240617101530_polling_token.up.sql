@@ -0,0 +1,7 @@
+CREATE TABLE polling_token (
+    id bigserial PRIMARY KEY,
+    token text NOT NULL UNIQUE,
+    device_id bigint NOT NULL UNIQUE,
+    created_at timestamp without time zone NOT NULL,
+    FOREIGN KEY(device_id) REFERENCES "device"(id) ON DELETE CASCADE
+);
@@ -0,0 +1,10 @@
+CREATE TABLE background_job_status (
+    name text PRIMARY KEY,
+    last_started_at timestamp without time zone NULL,
+    last_finished_at timestamp without time zone NULL,
+    last_duration_ms bigint NULL,
+    success boolean NULL,
+    last_error text NULL,
+    consecutive_failures integer NOT NULL DEFAULT 0,
+    next_run_at timestamp without time zone NULL
+);
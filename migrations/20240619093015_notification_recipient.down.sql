@@ -0,0 +1,2 @@
+DROP TABLE notification_recipient;
+DROP TYPE notification_channel;
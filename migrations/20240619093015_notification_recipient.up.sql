@@ -0,0 +1,13 @@
+CREATE TYPE notification_channel AS ENUM (
+    'email',
+    'webhook'
+);
+CREATE TABLE notification_recipient (
+    id bigserial PRIMARY KEY,
+    name text NOT NULL,
+    channel notification_channel NOT NULL,
+    email text NULL,
+    webhook_url text NULL,
+    webhook_secret text NULL,
+    categories text[] NOT NULL DEFAULT '{}'
+);
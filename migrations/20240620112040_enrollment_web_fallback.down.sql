@@ -0,0 +1 @@
+ALTER TABLE settings DROP COLUMN enrollment_web_fallback_enabled;
@@ -0,0 +1 @@
+ALTER TABLE settings ADD COLUMN enrollment_web_fallback_enabled boolean NOT NULL DEFAULT false;
@@ -0,0 +1 @@
+ALTER TABLE wireguard_network ADD COLUMN archived boolean NOT NULL DEFAULT false;
@@ -0,0 +1,3 @@
+DROP TABLE oauth2session;
+ALTER TABLE authorization_code DROP COLUMN session_id;
+ALTER TABLE oauth2client DROP COLUMN backchannel_logout_uri;
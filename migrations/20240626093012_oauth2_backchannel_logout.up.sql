@@ -0,0 +1,20 @@
+ALTER TABLE oauth2client ADD COLUMN backchannel_logout_uri text NULL;
+ALTER TABLE authorization_code ADD COLUMN session_id text NULL;
+
+CREATE TABLE oauth2session (
+    id bigserial PRIMARY KEY,
+    oauth2client_id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    session_id text NOT NULL,
+    sid text NOT NULL,
+    created timestamp without time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ended timestamp without time zone NULL,
+    logout_attempts integer NOT NULL DEFAULT 0,
+    logout_last_attempt timestamp without time zone NULL,
+    logout_delivered timestamp without time zone NULL,
+    logout_error text NULL,
+    UNIQUE (oauth2client_id, session_id),
+    FOREIGN KEY(oauth2client_id) REFERENCES "oauth2client"(id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) REFERENCES "user"(id) ON DELETE CASCADE
+);
+CREATE INDEX oauth2session_session_id ON oauth2session (session_id);
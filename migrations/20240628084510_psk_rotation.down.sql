@@ -0,0 +1,5 @@
+ALTER TABLE wireguard_network_device
+    DROP COLUMN preshared_key_rotated,
+    DROP COLUMN pending_preshared_key,
+    DROP COLUMN pending_preshared_key_created;
+ALTER TABLE wireguard_network DROP COLUMN psk_rotation_days;
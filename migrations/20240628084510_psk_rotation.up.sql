@@ -0,0 +1,5 @@
+ALTER TABLE wireguard_network ADD COLUMN psk_rotation_days integer NULL;
+ALTER TABLE wireguard_network_device
+    ADD COLUMN preshared_key_rotated timestamp without time zone NULL,
+    ADD COLUMN pending_preshared_key text NULL,
+    ADD COLUMN pending_preshared_key_created timestamp without time zone NULL;
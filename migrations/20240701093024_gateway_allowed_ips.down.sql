@@ -0,0 +1 @@
+ALTER TABLE wireguard_network DROP COLUMN gateway_allowed_ips;
@@ -0,0 +1 @@
+ALTER TABLE wireguard_network ADD COLUMN gateway_allowed_ips inet[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,3 @@
+DROP TABLE user_field_value;
+DROP TABLE user_field_definition;
+DROP TYPE user_field_type;
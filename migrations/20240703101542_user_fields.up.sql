@@ -0,0 +1,20 @@
+CREATE TYPE user_field_type AS ENUM (
+    'text',
+    'number',
+    'date',
+    'choice'
+);
+CREATE TABLE user_field_definition (
+    id bigserial PRIMARY KEY,
+    name text NOT NULL UNIQUE,
+    field_type user_field_type NOT NULL,
+    options text[] NOT NULL DEFAULT '{}',
+    required boolean NOT NULL DEFAULT false,
+    ldap_attr text NULL
+);
+CREATE TABLE user_field_value (
+    user_id bigint NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+    definition_id bigint NOT NULL REFERENCES user_field_definition(id) ON DELETE CASCADE,
+    value text NOT NULL,
+    PRIMARY KEY (user_id, definition_id)
+);
@@ -0,0 +1 @@
+ALTER TABLE "group" ADD COLUMN parent_id bigint NULL REFERENCES "group"(id) ON DELETE SET NULL;
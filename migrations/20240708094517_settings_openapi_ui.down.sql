@@ -0,0 +1 @@
+ALTER TABLE settings DROP COLUMN openapi_ui_enabled;
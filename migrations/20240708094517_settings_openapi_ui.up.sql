@@ -0,0 +1 @@
+ALTER TABLE settings ADD COLUMN openapi_ui_enabled boolean NOT NULL DEFAULT false;
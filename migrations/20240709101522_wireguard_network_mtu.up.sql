@@ -0,0 +1 @@
+ALTER TABLE wireguard_network ADD COLUMN mtu int4 NULL;
@@ -0,0 +1,5 @@
+CREATE TABLE gateway_event_outbox (
+    id bigserial PRIMARY KEY,
+    event jsonb NOT NULL,
+    created_at timestamp without time zone NOT NULL DEFAULT now()
+);
@@ -0,0 +1,10 @@
+ALTER TABLE settings
+DROP COLUMN mfa_totp_allowed,
+DROP COLUMN mfa_email_allowed,
+DROP COLUMN mfa_webauthn_allowed,
+DROP COLUMN mfa_web3_allowed,
+DROP COLUMN mfa_recovery_codes_allowed,
+DROP COLUMN mfa_disallowed_policy,
+DROP COLUMN mfa_grace_period_days,
+DROP COLUMN mfa_grace_period_end;
+DROP TYPE disallowed_mfa_policy;
@@ -0,0 +1,13 @@
+CREATE TYPE disallowed_mfa_policy AS ENUM (
+    'grace_period',
+    'invalidate'
+);
+ALTER TABLE settings
+ADD COLUMN mfa_totp_allowed boolean NOT NULL DEFAULT true,
+ADD COLUMN mfa_email_allowed boolean NOT NULL DEFAULT true,
+ADD COLUMN mfa_webauthn_allowed boolean NOT NULL DEFAULT true,
+ADD COLUMN mfa_web3_allowed boolean NOT NULL DEFAULT true,
+ADD COLUMN mfa_recovery_codes_allowed boolean NOT NULL DEFAULT true,
+ADD COLUMN mfa_disallowed_policy disallowed_mfa_policy NOT NULL DEFAULT 'grace_period',
+ADD COLUMN mfa_grace_period_days integer NOT NULL DEFAULT 14,
+ADD COLUMN mfa_grace_period_end timestamp without time zone NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE "user" DROP COLUMN last_login;
+ALTER TABLE "user" DROP COLUMN break_glass;
@@ -0,0 +1,2 @@
+ALTER TABLE "user" ADD COLUMN break_glass boolean NOT NULL DEFAULT false;
+ALTER TABLE "user" ADD COLUMN last_login timestamp without time zone NULL;
@@ -0,0 +1,8 @@
+-- flag, but keep, devices with public keys gateways can't use, so operators can fix them
+ALTER TABLE device ADD COLUMN pubkey_issue text NULL;
+UPDATE device SET pubkey_issue = 'invalid'
+    WHERE wireguard_pubkey !~ '^[A-Za-z0-9+/]{42}[AEIMQUYcgkosw048]=$';
+UPDATE device SET pubkey_issue = 'duplicate'
+    WHERE pubkey_issue IS NULL AND wireguard_pubkey IN (
+        SELECT wireguard_pubkey FROM device GROUP BY wireguard_pubkey HAVING count(*) > 1
+    );
@@ -0,0 +1,11 @@
+DROP TABLE device_dns_record;
+ALTER TABLE wireguard_network DROP COLUMN dns_zone;
+ALTER TABLE settings
+DROP COLUMN dns_provider,
+DROP COLUMN dns_server,
+DROP COLUMN dns_tsig_key_name,
+DROP COLUMN dns_tsig_secret,
+DROP COLUMN dns_webhook_url,
+DROP COLUMN dns_webhook_secret,
+DROP COLUMN dns_record_ttl;
+DROP TYPE dns_provider;
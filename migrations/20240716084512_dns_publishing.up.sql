@@ -0,0 +1,30 @@
+CREATE TYPE dns_provider AS ENUM (
+    'none',
+    'rfc2136',
+    'webhook'
+);
+ALTER TABLE settings
+ADD COLUMN dns_provider dns_provider NOT NULL DEFAULT 'none',
+ADD COLUMN dns_server text NULL,
+ADD COLUMN dns_tsig_key_name text NULL,
+ADD COLUMN dns_tsig_secret text NULL,
+ADD COLUMN dns_webhook_url text NULL,
+ADD COLUMN dns_webhook_secret text NULL,
+ADD COLUMN dns_record_ttl integer NOT NULL DEFAULT 300;
+ALTER TABLE wireguard_network ADD COLUMN dns_zone text NULL;
+-- no foreign keys, records of removed devices and locations have to be deleted from DNS first
+CREATE TABLE device_dns_record (
+    id bigserial PRIMARY KEY,
+    device_id bigint NOT NULL,
+    network_id bigint NOT NULL,
+    zone text NOT NULL,
+    name text NOT NULL,
+    address inet NULL,
+    published_zone text NULL,
+    published_name text NULL,
+    published_address inet NULL,
+    error text NULL,
+    attempts integer NOT NULL DEFAULT 0,
+    updated_at timestamp without time zone NOT NULL DEFAULT now(),
+    UNIQUE (device_id, network_id)
+);
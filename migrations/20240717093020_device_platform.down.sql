@@ -0,0 +1,4 @@
+ALTER TABLE device
+DROP COLUMN os,
+DROP COLUMN os_version,
+DROP COLUMN client_version;
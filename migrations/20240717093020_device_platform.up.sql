@@ -0,0 +1,4 @@
+ALTER TABLE device
+ADD COLUMN os text NULL,
+ADD COLUMN os_version text NULL,
+ADD COLUMN client_version text NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE settings DROP COLUMN session_idle_timeout;
+ALTER TABLE "session" DROP COLUMN last_activity;
@@ -0,0 +1,3 @@
+ALTER TABLE "session" ADD COLUMN last_activity timestamp without time zone NOT NULL DEFAULT now();
+-- in minutes, sessions don't expire due to inactivity if not set
+ALTER TABLE settings ADD COLUMN session_idle_timeout integer NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE "user" DROP COLUMN max_devices;
+ALTER TABLE settings DROP COLUMN max_devices_per_user;
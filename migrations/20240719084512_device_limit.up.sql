@@ -0,0 +1,4 @@
+-- devices a user can own; unlimited if not set
+ALTER TABLE settings ADD COLUMN max_devices_per_user integer NULL;
+-- overrides the global limit for particular user
+ALTER TABLE "user" ADD COLUMN max_devices integer NULL;
@@ -0,0 +1 @@
+-- canonical form routes the same addresses, original entries aren't restored
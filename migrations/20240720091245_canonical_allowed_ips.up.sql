@@ -0,0 +1,27 @@
+-- Bring allowed_ips of existing networks to canonical form, as stored by the API:
+-- network addresses without host bits, no duplicates, no ranges contained in other entries.
+-- Changed networks are reported; keep in sync with `canonical_networks()`.
+DO $$
+DECLARE
+    network_row record;
+    canonical inet[];
+BEGIN
+    FOR network_row IN SELECT id, name, allowed_ips FROM wireguard_network LOOP
+        SELECT coalesce(array_agg(entry ORDER BY idx), '{}') INTO canonical FROM (
+            SELECT DISTINCT ON (entry) entry, idx FROM (
+                SELECT network(ip)::inet entry, idx
+                FROM unnest(network_row.allowed_ips) WITH ORDINALITY AS entries(ip, idx)
+            ) normalized
+            ORDER BY entry, idx
+        ) deduplicated
+        WHERE NOT EXISTS (
+            SELECT 1 FROM unnest(network_row.allowed_ips) other
+            WHERE network(other)::inet >> deduplicated.entry
+        );
+        IF canonical IS DISTINCT FROM network_row.allowed_ips THEN
+            RAISE NOTICE 'allowed_ips of network % (id %) changed from % to %',
+                network_row.name, network_row.id, network_row.allowed_ips, canonical;
+            UPDATE wireguard_network SET allowed_ips = canonical WHERE id = network_row.id;
+        END IF;
+    END LOOP;
+END $$;
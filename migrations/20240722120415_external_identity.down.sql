@@ -0,0 +1,2 @@
+ALTER TABLE "user" DROP COLUMN merged_into;
+DROP TABLE external_identity;
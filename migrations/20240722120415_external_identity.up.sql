@@ -0,0 +1,13 @@
+-- identities of users at external OpenID providers
+CREATE TABLE external_identity (
+    id bigserial PRIMARY KEY,
+    provider text NOT NULL,
+    subject text NOT NULL,
+    email text NOT NULL,
+    -- local account the identity logs in as
+    user_id bigint NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+    created timestamp without time zone NOT NULL DEFAULT now(),
+    CONSTRAINT external_identity_provider_subject UNIQUE (provider, subject)
+);
+-- set on accounts merged into another one; such accounts are kept disabled
+ALTER TABLE "user" ADD COLUMN merged_into bigint NULL REFERENCES "user"(id) ON DELETE SET NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE "user" DROP COLUMN new_country_alert_opt_out;
+ALTER TABLE settings DROP COLUMN known_country_retention_months;
+ALTER TABLE settings DROP COLUMN new_country_alert_enabled;
+DROP TABLE device_country;
@@ -0,0 +1,12 @@
+-- countries devices connected from, used to detect connections from new countries
+CREATE TABLE device_country (
+    device_id bigint NOT NULL REFERENCES device(id) ON DELETE CASCADE,
+    country text NOT NULL,
+    first_seen timestamp without time zone NOT NULL DEFAULT now(),
+    last_seen timestamp without time zone NOT NULL DEFAULT now(),
+    PRIMARY KEY (device_id, country)
+);
+ALTER TABLE settings ADD COLUMN new_country_alert_enabled boolean NOT NULL DEFAULT false;
+-- countries not seen for this long are forgotten
+ALTER TABLE settings ADD COLUMN known_country_retention_months integer NOT NULL DEFAULT 12;
+ALTER TABLE "user" ADD COLUMN new_country_alert_opt_out boolean NOT NULL DEFAULT false;
@@ -0,0 +1,12 @@
+-- users suspended in bulk with other members of a group, kept apart from plain disabling
+-- so they can be reactivated automatically
+CREATE TABLE user_suspension (
+    user_id bigint PRIMARY KEY REFERENCES "user"(id) ON DELETE CASCADE,
+    batch_id uuid NOT NULL,
+    group_name text NOT NULL,
+    suspended_by bigint NULL REFERENCES "user"(id) ON DELETE SET NULL,
+    suspended_at timestamp without time zone NOT NULL DEFAULT now(),
+    reactivate_at timestamp without time zone NULL
+);
+CREATE INDEX user_suspension_reactivate_at ON user_suspension (reactivate_at)
+    WHERE reactivate_at IS NOT NULL;
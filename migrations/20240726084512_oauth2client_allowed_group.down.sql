@@ -0,0 +1 @@
+DROP TABLE oauth2client_allowed_group;
@@ -0,0 +1,5 @@
+CREATE TABLE oauth2client_allowed_group (
+    oauth2client_id bigint REFERENCES "oauth2client"(id) ON DELETE CASCADE,
+    group_id bigint REFERENCES "group"(id) ON DELETE CASCADE,
+    CONSTRAINT oauth2client_group_unique UNIQUE (oauth2client_id, group_id)
+);
@@ -0,0 +1,2 @@
+DROP TABLE worker_job;
+DROP TYPE worker_job_status;
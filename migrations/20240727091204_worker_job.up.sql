@@ -0,0 +1,25 @@
+CREATE TYPE worker_job_status AS ENUM (
+    'pending',
+    'claimed',
+    'done',
+    'failed'
+);
+-- jobs queued for workers (e.g. YubiKey provisioning), persisted so they survive restarts
+CREATE TABLE worker_job (
+    id bigserial PRIMARY KEY,
+    worker_id text NOT NULL,
+    username text NOT NULL,
+    first_name text NOT NULL,
+    last_name text NOT NULL,
+    email text NOT NULL,
+    status worker_job_status NOT NULL DEFAULT 'pending',
+    created_at timestamp without time zone NOT NULL DEFAULT now(),
+    claimed_at timestamp without time zone NULL,
+    finished_at timestamp without time zone NULL,
+    yubikey_serial text NULL,
+    ssh_key text NULL,
+    public_key text NULL,
+    error text NULL
+);
+CREATE INDEX worker_job_unfinished ON worker_job (status)
+    WHERE status IN ('pending', 'claimed');
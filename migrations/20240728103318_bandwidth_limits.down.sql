@@ -0,0 +1,4 @@
+ALTER TABLE wireguard_network_device DROP COLUMN download_limit_kbps;
+ALTER TABLE wireguard_network_device DROP COLUMN upload_limit_kbps;
+ALTER TABLE wireguard_network DROP COLUMN download_limit_kbps;
+ALTER TABLE wireguard_network DROP COLUMN upload_limit_kbps;
@@ -0,0 +1,5 @@
+-- per-peer bandwidth limits enforced by gateways; device values override location defaults
+ALTER TABLE wireguard_network ADD COLUMN upload_limit_kbps int4 NULL;
+ALTER TABLE wireguard_network ADD COLUMN download_limit_kbps int4 NULL;
+ALTER TABLE wireguard_network_device ADD COLUMN upload_limit_kbps int4 NULL;
+ALTER TABLE wireguard_network_device ADD COLUMN download_limit_kbps int4 NULL;
@@ -0,0 +1,14 @@
+-- one-time links to device configs, handed out to device owners
+CREATE TABLE shared_config (
+    id bigserial PRIMARY KEY,
+    token text NOT NULL UNIQUE,
+    device_id bigint NOT NULL REFERENCES device(id) ON DELETE CASCADE,
+    network_id bigint NOT NULL REFERENCES wireguard_network(id) ON DELETE CASCADE,
+    created_by bigint NULL REFERENCES "user"(id) ON DELETE SET NULL,
+    created_at timestamp without time zone NOT NULL DEFAULT now(),
+    expires_at timestamp without time zone NOT NULL,
+    used_at timestamp without time zone NULL,
+    used_from text NULL,
+    revoked_at timestamp without time zone NULL
+);
+CREATE INDEX shared_config_device_id ON shared_config (device_id);
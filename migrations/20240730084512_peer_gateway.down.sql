@@ -0,0 +1,2 @@
+ALTER TABLE wireguard_network_device DROP COLUMN gateway_handshake;
+ALTER TABLE wireguard_network_device DROP COLUMN gateway_hostname;
@@ -0,0 +1,3 @@
+-- gateway through which the peer was last seen in multi-gateway locations
+ALTER TABLE wireguard_network_device ADD COLUMN gateway_hostname text NULL;
+ALTER TABLE wireguard_network_device ADD COLUMN gateway_handshake timestamp without time zone NULL;
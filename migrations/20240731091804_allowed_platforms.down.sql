@@ -0,0 +1,2 @@
+ALTER TABLE wireguard_network DROP COLUMN deny_unknown_platform;
+ALTER TABLE wireguard_network DROP COLUMN allowed_platforms;
@@ -0,0 +1,3 @@
+-- operating systems of devices allowed in a location; empty means no restriction
+ALTER TABLE wireguard_network ADD COLUMN allowed_platforms text[] NOT NULL DEFAULT '{}';
+ALTER TABLE wireguard_network ADD COLUMN deny_unknown_platform boolean NOT NULL DEFAULT false;
@@ -0,0 +1,10 @@
+-- overrides of feature flags declared in code, for the whole instance or a single location
+CREATE TABLE feature_flag (
+    id bigserial PRIMARY KEY,
+    name text NOT NULL,
+    network_id bigint NULL REFERENCES wireguard_network(id) ON DELETE CASCADE,
+    enabled boolean NOT NULL,
+    updated_by bigint NULL REFERENCES "user"(id) ON DELETE SET NULL,
+    updated_at timestamp without time zone NOT NULL DEFAULT now()
+);
+CREATE UNIQUE INDEX feature_flag_name_network ON feature_flag (name, coalesce(network_id, 0));
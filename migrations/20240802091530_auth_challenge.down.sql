@@ -0,0 +1,9 @@
+ALTER TABLE settings
+DROP COLUMN auth_challenge,
+DROP COLUMN auth_challenge_on_pressure,
+DROP COLUMN auth_challenge_pow_difficulty,
+DROP COLUMN auth_challenge_site_key,
+DROP COLUMN auth_challenge_secret,
+DROP COLUMN auth_challenge_timeout,
+DROP COLUMN auth_challenge_fail_open;
+DROP TYPE auth_challenge;
@@ -0,0 +1,14 @@
+CREATE TYPE auth_challenge AS ENUM (
+    'none',
+    'proof_of_work',
+    'hcaptcha',
+    'turnstile'
+);
+ALTER TABLE settings
+ADD COLUMN auth_challenge auth_challenge NOT NULL DEFAULT 'none',
+ADD COLUMN auth_challenge_on_pressure boolean NOT NULL DEFAULT true,
+ADD COLUMN auth_challenge_pow_difficulty integer NOT NULL DEFAULT 18,
+ADD COLUMN auth_challenge_site_key text NULL,
+ADD COLUMN auth_challenge_secret text NULL,
+ADD COLUMN auth_challenge_timeout integer NOT NULL DEFAULT 3000,
+ADD COLUMN auth_challenge_fail_open boolean NOT NULL DEFAULT false;
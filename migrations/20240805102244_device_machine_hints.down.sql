@@ -0,0 +1,3 @@
+DROP INDEX device_user_id_machine_hash_idx;
+ALTER TABLE device DROP COLUMN machine_hash;
+ALTER TABLE device DROP COLUMN hostname;
@@ -0,0 +1,4 @@
+-- machine identity hints reported by clients on enrollment, used to spot re-enrolled machines
+ALTER TABLE device ADD COLUMN hostname text NULL;
+ALTER TABLE device ADD COLUMN machine_hash text NULL;
+CREATE INDEX device_user_id_machine_hash_idx ON device (user_id, machine_hash);
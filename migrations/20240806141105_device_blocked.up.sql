@@ -0,0 +1,2 @@
+-- blocked devices aren't configured on gateways until an admin unblocks them
+ALTER TABLE device ADD COLUMN blocked boolean NOT NULL DEFAULT false;
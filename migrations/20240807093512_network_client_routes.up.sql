@@ -0,0 +1,2 @@
+-- extra destinations routed through the tunnel by clients, not used for gateway peers
+ALTER TABLE wireguard_network ADD COLUMN client_routes inet[] NOT NULL DEFAULT '{}';
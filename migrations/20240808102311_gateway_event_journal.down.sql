@@ -0,0 +1,2 @@
+DROP TABLE gateway_sync_pending;
+DROP TABLE gateway_event_journal;
@@ -0,0 +1,12 @@
+CREATE TABLE gateway_event_journal (
+    seq bigserial PRIMARY KEY,
+    network_id bigint NOT NULL,
+    event jsonb NOT NULL,
+    created_at timestamp without time zone NOT NULL DEFAULT now()
+);
+CREATE INDEX gateway_event_journal_network_seq ON gateway_event_journal (network_id, seq);
+
+CREATE TABLE gateway_sync_pending (
+    network_id bigint PRIMARY KEY REFERENCES wireguard_network(id) ON DELETE CASCADE,
+    marked_at timestamp without time zone NOT NULL
+);
@@ -0,0 +1,6 @@
+DROP TABLE ssh_certificate;
+DROP TABLE ssh_ca_key;
+ALTER TABLE settings
+DROP COLUMN ssh_ca_enabled,
+DROP COLUMN ssh_ca_max_validity_minutes,
+DROP COLUMN ssh_ca_principal_mapping;
@@ -0,0 +1,25 @@
+ALTER TABLE settings
+ADD COLUMN ssh_ca_enabled boolean NOT NULL DEFAULT false,
+ADD COLUMN ssh_ca_max_validity_minutes integer NOT NULL DEFAULT 60,
+ADD COLUMN ssh_ca_principal_mapping text NULL;
+
+-- generated CA key, used unless one is provided in configuration
+CREATE TABLE ssh_ca_key (
+    id integer PRIMARY KEY DEFAULT 1 CHECK (id = 1),
+    private_key text NOT NULL,
+    created_at timestamp without time zone NOT NULL DEFAULT now()
+);
+
+-- id is the certificate serial number
+CREATE TABLE ssh_certificate (
+    id bigserial PRIMARY KEY,
+    user_id bigint NULL REFERENCES "user"(id) ON DELETE SET NULL,
+    key_id text NOT NULL,
+    principals text[] NOT NULL,
+    public_key text NOT NULL,
+    valid_after timestamp without time zone NOT NULL,
+    valid_before timestamp without time zone NOT NULL,
+    issued_at timestamp without time zone NOT NULL,
+    revoked_at timestamp without time zone NULL
+);
+CREATE INDEX ssh_certificate_revoked ON ssh_certificate (revoked_at) WHERE revoked_at IS NOT NULL;
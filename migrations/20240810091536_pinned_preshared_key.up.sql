@@ -0,0 +1 @@
+ALTER TABLE wireguard_network_device ADD COLUMN preshared_key_pinned boolean NOT NULL DEFAULT false;
@@ -0,0 +1,12 @@
+CREATE TABLE idempotency_record (
+    user_id bigint NOT NULL,
+    key text NOT NULL,
+    request_hash bytea NOT NULL,
+    response_status integer NULL,
+    response_body bytea NULL,
+    created_at timestamp without time zone NOT NULL,
+    expires_at timestamp without time zone NOT NULL,
+    PRIMARY KEY (user_id, key),
+    FOREIGN KEY (user_id) REFERENCES "user"(id) ON DELETE CASCADE
+);
+CREATE INDEX idempotency_record_expires_at ON idempotency_record (expires_at);
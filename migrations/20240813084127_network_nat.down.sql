@@ -0,0 +1,2 @@
+ALTER TABLE wireguard_network DROP COLUMN nat_exempt_networks;
+ALTER TABLE wireguard_network DROP COLUMN masquerade_enabled;
@@ -0,0 +1,3 @@
+-- gateways masquerade client traffic leaving the location, except towards exempt networks
+ALTER TABLE wireguard_network ADD COLUMN masquerade_enabled boolean NOT NULL DEFAULT false;
+ALTER TABLE wireguard_network ADD COLUMN nat_exempt_networks inet[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,15 @@
+-- time-limited access of a guest user to a single location, torn down at expiry
+CREATE TABLE guest_access (
+    id bigserial PRIMARY KEY,
+    user_id bigint NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+    network_id bigint NOT NULL REFERENCES wireguard_network(id) ON DELETE CASCADE,
+    group_name text NOT NULL,
+    -- group was created for the guest and is removed with the access
+    group_created boolean NOT NULL,
+    sponsor_id bigint NULL REFERENCES "user"(id) ON DELETE SET NULL,
+    created_at timestamp without time zone NOT NULL DEFAULT now(),
+    expires_at timestamp without time zone NOT NULL,
+    notified_at timestamp without time zone NULL,
+    ended_at timestamp without time zone NULL
+);
+CREATE INDEX guest_access_expires_at ON guest_access (expires_at) WHERE ended_at IS NULL;
@@ -0,0 +1 @@
+DROP TABLE oauth2_token_issuance;
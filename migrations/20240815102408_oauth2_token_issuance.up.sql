@@ -0,0 +1,9 @@
+-- history of tokens issued to OpenID clients, tokens themselves are replaced on refresh
+CREATE TABLE oauth2_token_issuance (
+    id bigserial PRIMARY KEY,
+    user_id bigint NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+    oauth2client_id bigint NOT NULL REFERENCES oauth2client(id) ON DELETE CASCADE,
+    grant_type text NOT NULL,
+    issued_at timestamp without time zone NOT NULL DEFAULT now()
+);
+CREATE INDEX oauth2_token_issuance_user_id ON oauth2_token_issuance (user_id, issued_at);
@@ -0,0 +1,4 @@
+ALTER TABLE settings
+DROP COLUMN device_name_policy,
+DROP COLUMN device_name_max_length;
+DROP TYPE device_name_policy;
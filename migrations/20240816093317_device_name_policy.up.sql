@@ -0,0 +1,8 @@
+CREATE TYPE device_name_policy AS ENUM (
+    'none',
+    'reject',
+    'sanitize'
+);
+ALTER TABLE settings
+ADD COLUMN device_name_policy device_name_policy NOT NULL DEFAULT 'none',
+ADD COLUMN device_name_max_length integer NOT NULL DEFAULT 64;
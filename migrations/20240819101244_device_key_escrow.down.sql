@@ -0,0 +1,3 @@
+DROP TABLE device_key_escrow_access;
+DROP TABLE device_key_escrow;
+ALTER TABLE wireguard_network DROP COLUMN server_managed_keys;
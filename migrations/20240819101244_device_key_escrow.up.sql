@@ -0,0 +1,20 @@
+ALTER TABLE wireguard_network ADD COLUMN server_managed_keys boolean NOT NULL DEFAULT false;
+
+-- private keys of devices generated by the server, encrypted with key-encryption key from configuration
+CREATE TABLE device_key_escrow (
+    device_id bigint PRIMARY KEY REFERENCES device(id) ON DELETE CASCADE,
+    encrypted_private_key text NOT NULL,
+    created_at timestamp without time zone NOT NULL DEFAULT now(),
+    rotated_at timestamp without time zone NULL
+);
+
+-- every read of an escrowed private key, kept after the device is removed
+CREATE TABLE device_key_escrow_access (
+    id bigserial PRIMARY KEY,
+    device_id bigint NOT NULL,
+    network_id bigint NULL,
+    user_id bigint NULL REFERENCES "user"(id) ON DELETE SET NULL,
+    purpose text NOT NULL,
+    accessed_at timestamp without time zone NOT NULL DEFAULT now()
+);
+CREATE INDEX device_key_escrow_access_device_id ON device_key_escrow_access (device_id);
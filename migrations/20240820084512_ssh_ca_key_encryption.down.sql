@@ -0,0 +1,2 @@
+DELETE FROM ssh_ca_key;
+ALTER TABLE ssh_ca_key RENAME COLUMN encrypted_private_key TO private_key;
@@ -0,0 +1,4 @@
+-- generated CA keys were stored in plaintext; they're dropped and generated again,
+-- encrypted, on next use
+DELETE FROM ssh_ca_key;
+ALTER TABLE ssh_ca_key RENAME COLUMN private_key TO encrypted_private_key;
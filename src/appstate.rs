@@ -1,4 +1,8 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use axum::extract::FromRef;
 use axum_extra::extract::cookie::Key;
@@ -16,21 +20,32 @@ use uaparser::UserAgentParser;
 use webauthn_rs::prelude::*;
 
 use crate::{
-    auth::failed_login::FailedLoginMap,
-    db::{AppEvent, DbPool, GatewayEvent, WebHook},
+    auth::{authz_trace::AuthzDenials, failed_login::FailedLoginMap, failed_token::FailedTokenMap},
+    db::{AppEvent, DbPool, GatewayEvent, ReadPool, WebHook},
+    jobs::JobRunner,
     mail::Mail,
     server_config,
+    wireguard_config_qr::ConfigQrLinks,
 };
 
 #[derive(Clone)]
 pub struct AppState {
     pub pool: DbPool,
+    // used by read-only endpoints, see `ReadPool`
+    pub read_pool: ReadPool,
     tx: UnboundedSender<AppEvent>,
-    wireguard_tx: Sender<GatewayEvent>,
+    pub(crate) wireguard_tx: Sender<GatewayEvent>,
     pub mail_tx: UnboundedSender<Mail>,
     pub webauthn: Arc<Webauthn>,
     pub user_agent_parser: Arc<UserAgentParser>,
     pub failed_logins: Arc<Mutex<FailedLoginMap>>,
+    pub(crate) failed_tokens: Arc<Mutex<FailedTokenMap>>,
+    pub job_runner: Arc<JobRunner>,
+    pub(crate) config_qr_links: Arc<Mutex<ConfigQrLinks>>,
+    // latest authorization denials of each user
+    pub(crate) authz_denials: Arc<Mutex<AuthzDenials>>,
+    // time of the last full gateway resync requested for each network
+    pub(crate) gateway_resyncs: Arc<Mutex<HashMap<i64, Instant>>>,
     key: Key,
 }
 
@@ -98,12 +113,14 @@ impl AppState {
     /// Create application state
     pub fn new(
         pool: DbPool,
+        read_pool: ReadPool,
         tx: UnboundedSender<AppEvent>,
         rx: UnboundedReceiver<AppEvent>,
         wireguard_tx: Sender<GatewayEvent>,
         mail_tx: UnboundedSender<Mail>,
         user_agent_parser: Arc<UserAgentParser>,
         failed_logins: Arc<Mutex<FailedLoginMap>>,
+        job_runner: Arc<JobRunner>,
     ) -> Self {
         spawn(Self::handle_triggers(pool.clone(), rx));
 
@@ -126,12 +143,18 @@ impl AppState {
 
         Self {
             pool,
+            read_pool,
             tx,
             wireguard_tx,
             mail_tx,
             webauthn,
             user_agent_parser,
             failed_logins,
+            failed_tokens: Arc::default(),
+            job_runner,
+            config_qr_links: Arc::default(),
+            authz_denials: Arc::default(),
+            gateway_resyncs: Arc::default(),
             key,
         }
     }
@@ -1,9 +1,11 @@
 use axum::{
     http::{header, StatusCode, Uri},
-    response::{IntoResponse, Response},
+    response::{Html, IntoResponse, Response},
 };
 use rust_embed::Embed;
 
+use crate::server_config;
+
 pub async fn web_asset(uri: Uri) -> impl IntoResponse {
     let mut path = uri.path().trim_start_matches('/').to_string();
     // Rewrite the path to match the structure of the embedded files
@@ -11,8 +13,32 @@ pub async fn web_asset(uri: Uri) -> impl IntoResponse {
     StaticFile(path)
 }
 
-pub async fn index() -> impl IntoResponse {
-    web_asset(Uri::from_static("/index.html")).await
+pub async fn index() -> Response {
+    match WebAsset::get("dist/index.html") {
+        Some(content) => Html(with_base_path(
+            &String::from_utf8_lossy(&content.data),
+            server_config().base_path(),
+        ))
+        .into_response(),
+        None => (StatusCode::NOT_FOUND, "404 Not Found").into_response(),
+    }
+}
+
+/// Make the SPA index work when served under a subpath: set `<base>` for relative URLs
+/// and prefix root-relative asset URLs, which the frontend is built with. The base path
+/// is also passed in a `<meta>` tag, the frontend prefixes API calls and routes with it.
+fn with_base_path(index: &str, base_path: &str) -> String {
+    index
+        .replace("src=\"/", &format!("src=\"{base_path}/"))
+        .replace("href=\"/", &format!("href=\"{base_path}/"))
+        .replacen(
+            "<head>",
+            &format!(
+                "<head><meta name=\"defguard-base-path\" content=\"{base_path}\" />\
+                <base href=\"{base_path}/\" />"
+            ),
+            1,
+        )
 }
 
 pub async fn svg(uri: Uri) -> impl IntoResponse {
@@ -46,3 +72,21 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_with_base_path() {
+        let index = r#"<html><head><link rel="icon" href="/favicon.ico" /><script src="/assets/index.js"></script></head></html>"#;
+        assert_eq!(
+            with_base_path(index, "/defguard"),
+            r#"<html><head><meta name="defguard-base-path" content="/defguard" /><base href="/defguard/" /><link rel="icon" href="/defguard/favicon.ico" /><script src="/defguard/assets/index.js"></script></head></html>"#
+        );
+        assert_eq!(
+            with_base_path(index, ""),
+            r#"<html><head><meta name="defguard-base-path" content="" /><base href="/" /><link rel="icon" href="/favicon.ico" /><script src="/assets/index.js"></script></head></html>"#
+        );
+    }
+}
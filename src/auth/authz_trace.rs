@@ -0,0 +1,138 @@
+//! Trace of authorization denials, for troubleshooting unexpected 403 responses.
+//!
+//! Extractors which deny access record the route, the method and the checks which ran,
+//! without any request contents. Responses carry a trace ID, which admins can look up among
+//! the latest denials of the user. Denials are kept in memory, successful checks aren't kept.
+
+use std::collections::{HashMap, VecDeque};
+
+use axum::{extract::MatchedPath, http::request::Parts};
+use chrono::{NaiveDateTime, Utc};
+use utoipa::ToSchema;
+
+use crate::{error::WebError, random::gen_alphanumeric};
+
+// denials kept per user, older ones are dropped
+const MAX_DENIALS_PER_USER: usize = 50;
+const TRACE_ID_LENGTH: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthzCheck {
+    /// Valid session of an existing user, with MFA verified.
+    Session,
+    /// Membership in one of the groups required by the route.
+    GroupMembership,
+    /// Only reads are allowed while impersonating a user.
+    Impersonation,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct AuthzDenial {
+    pub trace_id: String,
+    pub method: String,
+    // route pattern, without path parameters
+    pub route: String,
+    // checks which passed before the denying one, in order
+    pub passed: Vec<AuthzCheck>,
+    pub denied_by: AuthzCheck,
+    // groups the route requires, if denied by group membership
+    pub required_groups: Vec<String>,
+    pub denied_at: NaiveDateTime,
+}
+
+#[derive(Default)]
+pub struct AuthzDenials(HashMap<i64, VecDeque<AuthzDenial>>);
+
+impl AuthzDenials {
+    /// Record denial of a request made by given user and return error with its trace ID.
+    pub fn deny(
+        &mut self,
+        user_id: i64,
+        parts: &Parts,
+        passed: Vec<AuthzCheck>,
+        denied_by: AuthzCheck,
+        required_groups: Vec<String>,
+        msg: &str,
+    ) -> WebError {
+        let denial = AuthzDenial {
+            trace_id: gen_alphanumeric(TRACE_ID_LENGTH),
+            method: parts.method.to_string(),
+            route: parts
+                .extensions
+                .get::<MatchedPath>()
+                .map_or_else(|| "unknown".into(), |path| path.as_str().into()),
+            passed,
+            denied_by,
+            required_groups,
+            denied_at: Utc::now().naive_utc(),
+        };
+        debug!(
+            authz_trace_id = %denial.trace_id,
+            "Denied {} {} for user {user_id} by {:?} check",
+            denial.method,
+            denial.route,
+            denial.denied_by
+        );
+        let trace_id = denial.trace_id.clone();
+        let denials = self.0.entry(user_id).or_default();
+        if denials.len() == MAX_DENIALS_PER_USER {
+            denials.pop_front();
+        }
+        denials.push_back(denial);
+        WebError::AccessDenied {
+            msg: msg.into(),
+            trace_id,
+        }
+    }
+
+    /// Latest denials of a user, newest first.
+    #[must_use]
+    pub fn for_user(&self, user_id: i64) -> Vec<AuthzDenial> {
+        self.0
+            .get(&user_id)
+            .map(|denials| denials.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::{Method, Request};
+
+    use super::*;
+
+    #[test]
+    fn test_denials_ring_buffer() {
+        let (parts, ()) = Request::builder()
+            .method(Method::DELETE)
+            .uri("/api/v1/user/hpotter")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let mut denials = AuthzDenials::default();
+        let mut trace_ids = Vec::new();
+        for _ in 0..=MAX_DENIALS_PER_USER {
+            let WebError::AccessDenied { trace_id, .. } = denials.deny(
+                1,
+                &parts,
+                vec![AuthzCheck::Session],
+                AuthzCheck::GroupMembership,
+                vec!["admin".into()],
+                "access denied",
+            ) else {
+                panic!("denials should be reported with trace ID");
+            };
+            trace_ids.push(trace_id);
+        }
+
+        let recorded = denials.for_user(1);
+        assert_eq!(recorded.len(), MAX_DENIALS_PER_USER);
+        assert_eq!(recorded[0].trace_id, trace_ids[MAX_DENIALS_PER_USER]);
+        assert_eq!(recorded.last().unwrap().trace_id, trace_ids[1]);
+        // requested path isn't recorded, only the route
+        assert_eq!(recorded[0].route, "unknown");
+        assert_eq!(recorded[0].method, "DELETE");
+        assert!(denials.for_user(2).is_empty());
+    }
+}
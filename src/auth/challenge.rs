@@ -0,0 +1,592 @@
+//! Optional challenge for unauthenticated login, enrollment and password reset requests.
+//!
+//! Per-username lockouts don't stop credential stuffing spread over many usernames,
+//! so internet-facing instances can require a challenge to be solved first: either a
+//! CAPTCHA (hCaptcha or Turnstile) verified with its provider, or a built-in proof of
+//! work which needs no third party. It can be required always, or only while failed
+//! attempts suggest an ongoing attack. Solutions are sent in the [`CHALLENGE_HEADER`],
+//! or in the `challenge` field of password reset requests relayed by the proxy.
+//!
+//! Proof of work nonces are signed with the server secret and carry their expiry and
+//! difficulty, so they don't have to be stored. A solution is `nonce:counter`, with
+//! SHA-256 of it starting with at least `difficulty` zero bits. Accepted nonces are
+//! remembered until they expire, so each can be used once per instance.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use axum::http::HeaderMap;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, Url};
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use sqlx::{Error as SqlxError, PgExecutor};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use super::{failed_login::FailedLoginMap, failed_token::FailedTokenMap};
+use crate::{
+    db::{models::settings::AuthChallenge, DbPool, Settings},
+    error::WebError,
+    hex::{hex_decode, to_lower_hex},
+    random::gen_alphanumeric,
+    server_config,
+};
+
+/// Request header carrying challenge solution.
+pub const CHALLENGE_HEADER: &str = "x-auth-challenge";
+/// Proof of work difficulty can't be set higher, solving it would take too long.
+pub const MAX_POW_DIFFICULTY: i32 = 28;
+// How long proof of work nonces can be used for
+const NONCE_LIFETIME_SECONDS: i64 = 5 * 60;
+const NONCE_RANDOM_LENGTH: usize = 16;
+// Failed attempts within their time windows, across all users or addresses,
+// which make challenges required under pressure-only setting
+const PRESSURE_LOGIN_ATTEMPTS: u32 = 20;
+const PRESSURE_TOKEN_ATTEMPTS: u32 = 20;
+const HCAPTCHA_VERIFY_URL: &str = "https://api.hcaptcha.com/siteverify";
+const TURNSTILE_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+// Nonces accepted by this instance, with their expiry timestamps
+static USED_NONCES: OnceLock<Mutex<HashMap<String, i64>>> = OnceLock::new();
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ChallengeError {
+    #[error("Challenge solution required")]
+    Missing,
+    #[error("Invalid challenge solution")]
+    Invalid,
+    #[error("Challenge expired")]
+    Expired,
+    #[error("Challenge already used")]
+    Reused,
+    #[error("Challenge verification unavailable: {0}")]
+    ProviderUnavailable(String),
+}
+
+/// Challenge details for clients, returned before sending protected requests.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChallengeInfo {
+    pub kind: AuthChallenge,
+    /// Requests without a solution are rejected right now; may change under pressure.
+    pub required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub difficulty: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct CaptchaVerification {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+/// Challenge configured in [`Settings`].
+#[derive(Clone)]
+pub struct ChallengePolicy {
+    pub kind: AuthChallenge,
+    pub on_pressure: bool,
+    pub difficulty: u8,
+    pub site_key: Option<String>,
+    pub secret: Option<String>,
+    pub verify_url: Option<Url>,
+    pub timeout: Duration,
+    pub fail_open: bool,
+    // proof of work nonce signing key
+    pub key: Vec<u8>,
+}
+
+impl ChallengePolicy {
+    #[must_use]
+    pub fn from_settings(settings: &Settings) -> Self {
+        let verify_url = match settings.auth_challenge {
+            AuthChallenge::Hcaptcha => Url::parse(HCAPTCHA_VERIFY_URL).ok(),
+            AuthChallenge::Turnstile => Url::parse(TURNSTILE_VERIFY_URL).ok(),
+            AuthChallenge::None | AuthChallenge::ProofOfWork => None,
+        };
+        Self {
+            kind: settings.auth_challenge,
+            on_pressure: settings.auth_challenge_on_pressure,
+            difficulty: u8::try_from(settings.auth_challenge_pow_difficulty).unwrap_or_default(),
+            site_key: settings.auth_challenge_site_key.clone(),
+            secret: settings
+                .auth_challenge_secret
+                .as_ref()
+                .map(|secret| secret.expose_secret().to_string()),
+            verify_url,
+            timeout: Duration::from_millis(
+                u64::try_from(settings.auth_challenge_timeout).unwrap_or_default(),
+            ),
+            fail_open: settings.auth_challenge_fail_open,
+            key: server_config()
+                .secret_key
+                .expose_secret()
+                .as_bytes()
+                .to_vec(),
+        }
+    }
+
+    pub async fn load<'e, E>(executor: E) -> Result<Self, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let settings = Settings::get_settings(executor).await?;
+        Ok(Self::from_settings(&settings))
+    }
+
+    /// Check if challenge values stored in settings make sense.
+    pub fn validate_settings(settings: &Settings) -> Result<(), String> {
+        if !(1..=MAX_POW_DIFFICULTY).contains(&settings.auth_challenge_pow_difficulty) {
+            return Err(format!(
+                "Proof of work difficulty must be between 1 and {MAX_POW_DIFFICULTY}"
+            ));
+        }
+        if settings.auth_challenge_timeout <= 0 {
+            return Err("Challenge verification timeout must be positive".into());
+        }
+        if matches!(
+            settings.auth_challenge,
+            AuthChallenge::Hcaptcha | AuthChallenge::Turnstile
+        ) && (settings.auth_challenge_site_key.is_none()
+            || settings.auth_challenge_secret.is_none())
+        {
+            return Err("CAPTCHA challenge requires site key and secret".into());
+        }
+        Ok(())
+    }
+
+    /// Check if requests have to solve the challenge, given current failed attempts.
+    #[must_use]
+    pub fn is_required(&self, under_pressure: bool) -> bool {
+        self.kind != AuthChallenge::None && (!self.on_pressure || under_pressure)
+    }
+
+    /// Challenge details for clients, with a fresh nonce for proof of work.
+    #[must_use]
+    pub fn info(&self, required: bool) -> ChallengeInfo {
+        let mut info = ChallengeInfo {
+            kind: self.kind,
+            required,
+            site_key: None,
+            nonce: None,
+            difficulty: None,
+        };
+        match self.kind {
+            AuthChallenge::None => (),
+            AuthChallenge::ProofOfWork => {
+                info.nonce = Some(issue_nonce(&self.key, self.difficulty));
+                info.difficulty = Some(self.difficulty);
+            }
+            AuthChallenge::Hcaptcha | AuthChallenge::Turnstile => {
+                info.site_key.clone_from(&self.site_key);
+            }
+        }
+        info
+    }
+
+    /// Verify challenge solution sent by a client.
+    pub async fn verify(
+        &self,
+        solution: Option<&str>,
+        client_ip: IpAddr,
+    ) -> Result<(), ChallengeError> {
+        let solution = solution
+            .filter(|solution| !solution.is_empty())
+            .ok_or(ChallengeError::Missing)?;
+        match self.kind {
+            AuthChallenge::None => Ok(()),
+            AuthChallenge::ProofOfWork => {
+                verify_proof_of_work(&self.key, solution, self.difficulty)?;
+                mark_used(solution)
+            }
+            AuthChallenge::Hcaptcha | AuthChallenge::Turnstile => {
+                self.verify_captcha(solution, client_ip).await
+            }
+        }
+    }
+
+    // Send the CAPTCHA response to provider's verification endpoint.
+    // Both providers share the same API.
+    async fn verify_captcha(
+        &self,
+        response: &str,
+        client_ip: IpAddr,
+    ) -> Result<(), ChallengeError> {
+        let (Some(url), Some(secret)) = (&self.verify_url, &self.secret) else {
+            return Err(ChallengeError::ProviderUnavailable(
+                "CAPTCHA is not configured".into(),
+            ));
+        };
+        let result = async {
+            Client::builder()
+                .timeout(self.timeout)
+                .build()?
+                .post(url.clone())
+                .form(&[
+                    ("secret", secret.as_str()),
+                    ("response", response),
+                    ("remoteip", &client_ip.to_string()),
+                ])
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<CaptchaVerification>()
+                .await
+        }
+        .await;
+        match result {
+            Ok(verification) if verification.success => Ok(()),
+            Ok(verification) => {
+                debug!(
+                    "CAPTCHA verification for {client_ip} failed: {:?}",
+                    verification.error_codes
+                );
+                Err(ChallengeError::Invalid)
+            }
+            Err(err) if self.fail_open => {
+                warn!(
+                    "CAPTCHA verification failed, letting request from {client_ip} through: {err}"
+                );
+                Ok(())
+            }
+            Err(err) => {
+                error!("CAPTCHA verification failed: {err}");
+                Err(ChallengeError::ProviderUnavailable(err.to_string()))
+            }
+        }
+    }
+}
+
+fn sign(key: &[u8], payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(b"auth-challenge:");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+// Nonce format is `expires.difficulty.random.signature`.
+fn issue_nonce(key: &[u8], difficulty: u8) -> String {
+    let expires = Utc::now().timestamp() + NONCE_LIFETIME_SECONDS;
+    let payload = format!(
+        "{expires}.{difficulty}.{}",
+        gen_alphanumeric(NONCE_RANDOM_LENGTH)
+    );
+    let signature = to_lower_hex(&sign(key, &payload).finalize().into_bytes());
+    format!("{payload}.{signature}")
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte != 0 {
+            return bits + byte.leading_zeros();
+        }
+        bits += 8;
+    }
+    bits
+}
+
+fn verify_proof_of_work(
+    key: &[u8],
+    solution: &str,
+    min_difficulty: u8,
+) -> Result<(), ChallengeError> {
+    let (nonce, _counter) = solution.rsplit_once(':').ok_or(ChallengeError::Invalid)?;
+    let (payload, signature) = nonce.rsplit_once('.').ok_or(ChallengeError::Invalid)?;
+    let signature = hex_decode(signature).map_err(|_| ChallengeError::Invalid)?;
+    sign(key, payload)
+        .verify_slice(&signature)
+        .map_err(|_| ChallengeError::Invalid)?;
+    let mut fields = payload.splitn(3, '.');
+    let expires: i64 = fields
+        .next()
+        .and_then(|expires| expires.parse().ok())
+        .ok_or(ChallengeError::Invalid)?;
+    let difficulty: u8 = fields
+        .next()
+        .and_then(|difficulty| difficulty.parse().ok())
+        .ok_or(ChallengeError::Invalid)?;
+    if expires < Utc::now().timestamp() {
+        return Err(ChallengeError::Expired);
+    }
+    // nonces issued before difficulty was raised are no longer good enough
+    if difficulty < min_difficulty {
+        return Err(ChallengeError::Invalid);
+    }
+    if leading_zero_bits(&Sha256::digest(solution.as_bytes())) < u32::from(difficulty) {
+        return Err(ChallengeError::Invalid);
+    }
+    Ok(())
+}
+
+// Remember accepted nonce until it expires, rejecting ones used before.
+fn mark_used(solution: &str) -> Result<(), ChallengeError> {
+    let (nonce, _counter) = solution.rsplit_once(':').ok_or(ChallengeError::Invalid)?;
+    let expires = nonce
+        .split_once('.')
+        .and_then(|(expires, _)| expires.parse::<i64>().ok())
+        .ok_or(ChallengeError::Invalid)?;
+    let now = Utc::now().timestamp();
+    let mut used = USED_NONCES
+        .get_or_init(Mutex::default)
+        .lock()
+        .expect("Failed to get a lock on used nonces.");
+    used.retain(|_, expires| *expires >= now);
+    if used.insert(nonce.to_string(), expires).is_some() {
+        return Err(ChallengeError::Reused);
+    }
+    Ok(())
+}
+
+/// Find proof of work solution for a nonce, in the format expected in [`CHALLENGE_HEADER`].
+#[must_use]
+pub fn solve_proof_of_work(nonce: &str, difficulty: u8) -> String {
+    (0_u64..)
+        .map(|counter| format!("{nonce}:{counter}"))
+        .find(|solution| {
+            leading_zero_bits(&Sha256::digest(solution.as_bytes())) >= u32::from(difficulty)
+        })
+        .expect("Proof of work counter space exhausted")
+}
+
+/// Check if failed attempts suggest an ongoing attack. A username which failed to log in
+/// recently is considered under pressure on its own.
+#[must_use]
+pub fn under_pressure(
+    failed_logins: &Mutex<FailedLoginMap>,
+    failed_tokens: &Mutex<FailedTokenMap>,
+    username: Option<&str>,
+) -> bool {
+    let failed_logins = failed_logins
+        .lock()
+        .expect("Failed to get a lock on failed login map.");
+    if username.is_some_and(|username| failed_logins.has_recent_failure(username))
+        || failed_logins.recent_attempt_count() >= PRESSURE_LOGIN_ATTEMPTS
+    {
+        return true;
+    }
+    drop(failed_logins);
+    failed_tokens
+        .lock()
+        .expect("Failed to get a lock on failed token map.")
+        .recent_attempt_count()
+        >= PRESSURE_TOKEN_ATTEMPTS
+}
+
+/// Challenge solution sent with a request, if any.
+#[must_use]
+pub fn challenge_solution(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(CHALLENGE_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Reject unauthenticated request without a valid challenge solution, if one is required.
+pub async fn check_challenge(
+    pool: &DbPool,
+    failed_logins: &Mutex<FailedLoginMap>,
+    failed_tokens: &Mutex<FailedTokenMap>,
+    solution: Option<&str>,
+    client_ip: IpAddr,
+    username: Option<&str>,
+) -> Result<(), WebError> {
+    let policy = ChallengePolicy::load(pool).await?;
+    if policy.kind == AuthChallenge::None {
+        return Ok(());
+    }
+    let under_pressure =
+        policy.on_pressure && under_pressure(failed_logins, failed_tokens, username);
+    if !policy.is_required(under_pressure) {
+        return Ok(());
+    }
+    if let Err(err) = policy.verify(solution, client_ip).await {
+        info!("Rejecting request from {client_ip} without valid challenge solution: {err}");
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{routing::post, serve, Form, Json, Router};
+    use claims::{assert_err_eq, assert_ok};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn test_policy(kind: AuthChallenge) -> ChallengePolicy {
+        ChallengePolicy {
+            kind,
+            on_pressure: false,
+            difficulty: 8,
+            site_key: Some("site-key".into()),
+            secret: Some("secret".into()),
+            verify_url: None,
+            timeout: Duration::from_millis(500),
+            fail_open: false,
+            key: b"test key".to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_required() {
+        let mut policy = test_policy(AuthChallenge::None);
+        assert!(!policy.is_required(true));
+        policy.kind = AuthChallenge::ProofOfWork;
+        assert!(policy.is_required(false));
+        policy.on_pressure = true;
+        assert!(!policy.is_required(false));
+        assert!(policy.is_required(true));
+    }
+
+    #[tokio::test]
+    async fn test_proof_of_work() {
+        let policy = test_policy(AuthChallenge::ProofOfWork);
+        let info = policy.info(true);
+        assert_eq!(info.difficulty, Some(8));
+        let nonce = info.nonce.unwrap();
+        assert_err_eq!(
+            policy.verify(None, LOCALHOST).await,
+            ChallengeError::Missing
+        );
+
+        let solution = solve_proof_of_work(&nonce, 8);
+        assert_ok!(policy.verify(Some(&solution), LOCALHOST).await);
+        // nonce can't be reused, even with a different counter
+        assert_err_eq!(
+            policy.verify(Some(&solution), LOCALHOST).await,
+            ChallengeError::Reused
+        );
+
+        // tampered difficulty breaks the signature
+        let nonce = policy.info(true).nonce.unwrap();
+        let tampered = nonce.replacen(".8.", ".1.", 1);
+        let solution = solve_proof_of_work(&tampered, 1);
+        assert_err_eq!(
+            policy.verify(Some(&solution), LOCALHOST).await,
+            ChallengeError::Invalid
+        );
+
+        // nonce signed with other key
+        let mut other = test_policy(AuthChallenge::ProofOfWork);
+        other.key = b"other key".to_vec();
+        let solution = solve_proof_of_work(&other.info(true).nonce.unwrap(), 8);
+        assert_err_eq!(
+            policy.verify(Some(&solution), LOCALHOST).await,
+            ChallengeError::Invalid
+        );
+
+        // difficulty raised since nonce was issued
+        let mut easier = test_policy(AuthChallenge::ProofOfWork);
+        easier.difficulty = 4;
+        let solution = solve_proof_of_work(&easier.info(true).nonce.unwrap(), 4);
+        assert_err_eq!(
+            policy.verify(Some(&solution), LOCALHOST).await,
+            ChallengeError::Invalid
+        );
+    }
+
+    #[test]
+    fn test_expired_nonce() {
+        let key = b"test key";
+        let payload = format!("{}.4.abcdef", Utc::now().timestamp() - 1);
+        let signature = to_lower_hex(&sign(key, &payload).finalize().into_bytes());
+        let solution = solve_proof_of_work(&format!("{payload}.{signature}"), 4);
+        assert_err_eq!(
+            verify_proof_of_work(key, &solution, 4),
+            ChallengeError::Expired
+        );
+    }
+
+    // Serve a fake CAPTCHA verification endpoint accepting given response.
+    async fn mock_captcha(valid_response: &'static str) -> Url {
+        let app = Router::new().route(
+            "/siteverify",
+            post(
+                move |Form(form): Form<HashMap<String, String>>| async move {
+                    let success = form.get("secret").map(String::as_str) == Some("secret")
+                        && form.get("response").map(String::as_str) == Some(valid_response);
+                    Json::<Value>(json!({ "success": success, "error-codes": [] }))
+                },
+            ),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { serve(listener, app).await.unwrap() });
+        Url::parse(&format!("http://{addr}/siteverify")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_captcha() {
+        let mut policy = test_policy(AuthChallenge::Turnstile);
+        policy.verify_url = Some(mock_captcha("solved").await);
+        assert_eq!(policy.info(true).site_key.as_deref(), Some("site-key"));
+        assert_ok!(policy.verify(Some("solved"), LOCALHOST).await);
+        assert_err_eq!(
+            policy.verify(Some("guessed"), LOCALHOST).await,
+            ChallengeError::Invalid
+        );
+    }
+
+    #[tokio::test]
+    async fn test_captcha_provider_down() {
+        let mut policy = test_policy(AuthChallenge::Hcaptcha);
+        // nothing listens there
+        policy.verify_url = Some(Url::parse("http://127.0.0.1:1/siteverify").unwrap());
+        assert!(matches!(
+            policy.verify(Some("solved"), LOCALHOST).await,
+            Err(ChallengeError::ProviderUnavailable(_))
+        ));
+
+        policy.fail_open = true;
+        assert_ok!(policy.verify(Some("solved"), LOCALHOST).await);
+        // missing solution is rejected regardless
+        assert_err_eq!(
+            policy.verify(None, LOCALHOST).await,
+            ChallengeError::Missing
+        );
+    }
+
+    #[test]
+    fn test_pressure() {
+        let failed_logins = Mutex::new(FailedLoginMap::new());
+        let failed_tokens = Mutex::new(FailedTokenMap::new());
+        assert!(!under_pressure(
+            &failed_logins,
+            &failed_tokens,
+            Some("hpotter")
+        ));
+
+        failed_logins.lock().unwrap().log_failed_attempt("hpotter");
+        assert!(under_pressure(
+            &failed_logins,
+            &failed_tokens,
+            Some("hpotter")
+        ));
+        assert!(!under_pressure(
+            &failed_logins,
+            &failed_tokens,
+            Some("admin")
+        ));
+
+        for index in 0..PRESSURE_LOGIN_ATTEMPTS {
+            failed_logins
+                .lock()
+                .unwrap()
+                .log_failed_attempt(&format!("user{index}"));
+        }
+        assert!(under_pressure(&failed_logins, &failed_tokens, None));
+    }
+}
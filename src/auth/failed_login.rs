@@ -43,6 +43,10 @@ impl FailedLogin {
         Local::now().signed_duration_since(self.last_attempt)
     }
 
+    fn is_recent(&self) -> bool {
+        self.time_since_last_attempt() <= Duration::seconds(FAILED_LOGIN_WINDOW)
+    }
+
     fn increment(&mut self) {
         self.attempt_count += 1;
         self.last_attempt = Local::now();
@@ -112,6 +116,22 @@ impl FailedLoginMap {
         }
         Ok(())
     }
+
+    // Check if given username had a failed login attempt within the time window
+    #[must_use]
+    pub fn has_recent_failure(&self, username: &str) -> bool {
+        self.0.get(username).is_some_and(FailedLogin::is_recent)
+    }
+
+    // Number of failed login attempts within the time window, across all usernames
+    #[must_use]
+    pub fn recent_attempt_count(&self) -> u32 {
+        self.0
+            .values()
+            .filter(|failed_login| failed_login.is_recent())
+            .map(|failed_login| failed_login.attempt_count)
+            .sum()
+    }
 }
 
 // Check if auth request with a given username can proceed
@@ -0,0 +1,209 @@
+//! Tracking of invalid enrollment and password reset token attempts.
+//!
+//! Attempts with unknown tokens are counted per source IP address and per token prefix.
+//! Addresses crossing the threshold are locked out for a while. Too many attempts
+//! sharing a prefix mean someone is after a specific token, so such tokens get invalidated.
+//! Only prefixes are kept, so guessed tokens never end up in memory as a whole.
+
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Duration as StdDuration};
+
+use chrono::{DateTime, Duration, Utc};
+use thiserror::Error;
+
+// Time window in seconds
+const FAILED_TOKEN_WINDOW: i64 = 15 * 60;
+// Failed attempt count threshold for a single IP address
+const FAILED_TOKEN_IP_COUNT: u32 = 10;
+// How long (in seconds) to lock IP addresses out after crossing the threshold
+const FAILED_TOKEN_TIMEOUT: i64 = 15 * 60;
+// Failed attempt count threshold for a single token prefix
+const FAILED_TOKEN_PREFIX_COUNT: u32 = 5;
+// Length of token prefix attempts are tracked by
+pub const TOKEN_PREFIX_LENGTH: usize = 8;
+// Locked out requests are answered after a delay to slow down automated guessing
+pub const LOCKOUT_RESPONSE_DELAY: StdDuration = StdDuration::from_secs(1);
+
+#[derive(Error, Debug)]
+#[error("Too many invalid token attempts")]
+pub struct FailedTokenError;
+
+struct FailedAttempts {
+    attempt_count: u32,
+    first_attempt: DateTime<Utc>,
+    last_attempt: DateTime<Utc>,
+}
+
+impl Default for FailedAttempts {
+    fn default() -> Self {
+        Self {
+            attempt_count: 1,
+            first_attempt: Utc::now(),
+            last_attempt: Utc::now(),
+        }
+    }
+}
+
+impl FailedAttempts {
+    fn increment(&mut self) {
+        self.attempt_count += 1;
+        self.last_attempt = Utc::now();
+    }
+
+    // Check if further attempts should be rejected
+    fn is_locked(&self, threshold: u32) -> bool {
+        self.attempt_count >= threshold
+            && Utc::now().signed_duration_since(self.last_attempt)
+                <= Duration::seconds(FAILED_TOKEN_TIMEOUT)
+    }
+
+    // Counter can be reset after the window has passed, unless attempts are locked
+    fn is_outdated(&self, threshold: u32) -> bool {
+        let now = Utc::now();
+        now.signed_duration_since(self.first_attempt) > Duration::seconds(FAILED_TOKEN_WINDOW)
+            && !self.is_locked(threshold)
+    }
+}
+
+#[derive(Default)]
+pub struct FailedTokenMap {
+    ips: HashMap<IpAddr, FailedAttempts>,
+    prefixes: HashMap<String, FailedAttempts>,
+}
+
+/// Prefix of token attempts are tracked by, `None` for tokens too short to have one.
+#[must_use]
+pub fn token_prefix(token: &str) -> Option<&str> {
+    token.get(..TOKEN_PREFIX_LENGTH)
+}
+
+impl FailedTokenMap {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Check if token attempts from given address can proceed
+    pub fn verify_ip(&mut self, ip: IpAddr) -> Result<(), FailedTokenError> {
+        if let Some(attempts) = self.ips.get_mut(&ip) {
+            if attempts.is_locked(FAILED_TOKEN_IP_COUNT) {
+                debug!("Rejecting token attempt from locked out address {ip}");
+                // log a failed attempt to prolong timeout
+                attempts.increment();
+                return Err(FailedTokenError);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add failed attempt to tracker. Returns prefix of tokens which have to be invalidated
+    /// once attempts sharing it cross the threshold.
+    pub fn log_failed_attempt(&mut self, ip: Option<IpAddr>, token: &str) -> Option<String> {
+        if let Some(ip) = ip {
+            info!("Logging invalid token attempt from {ip}");
+            Self::log(&mut self.ips, ip, FAILED_TOKEN_IP_COUNT);
+        }
+        let prefix = token_prefix(token)?;
+        Self::log(
+            &mut self.prefixes,
+            prefix.to_string(),
+            FAILED_TOKEN_PREFIX_COUNT,
+        );
+        if self.prefixes[prefix].attempt_count >= FAILED_TOKEN_PREFIX_COUNT {
+            self.prefixes.remove(prefix);
+            return Some(prefix.to_string());
+        }
+        None
+    }
+
+    // Number of failed token attempts within the time window, across all addresses
+    #[must_use]
+    pub fn recent_attempt_count(&self) -> u32 {
+        let now = Utc::now();
+        self.ips
+            .values()
+            .filter(|attempts| {
+                now.signed_duration_since(attempts.last_attempt)
+                    <= Duration::seconds(FAILED_TOKEN_WINDOW)
+            })
+            .map(|attempts| attempts.attempt_count)
+            .sum()
+    }
+
+    fn log<K: Eq + std::hash::Hash>(
+        attempts: &mut HashMap<K, FailedAttempts>,
+        key: K,
+        threshold: u32,
+    ) {
+        match attempts.get_mut(&key) {
+            Some(failed) if failed.is_outdated(threshold) => *failed = FailedAttempts::default(),
+            Some(failed) => failed.increment(),
+            None => {
+                attempts.insert(key, FailedAttempts::default());
+            }
+        }
+    }
+}
+
+// Check if token attempt from a given address can proceed
+pub fn check_token_attempt(
+    failed_tokens: &Mutex<FailedTokenMap>,
+    ip: Option<IpAddr>,
+) -> Result<(), FailedTokenError> {
+    let Some(ip) = ip else {
+        return Ok(());
+    };
+    let mut failed_tokens = failed_tokens
+        .lock()
+        .expect("Failed to get a lock on failed token map.");
+    failed_tokens.verify_ip(ip)
+}
+
+// Helper to log failed token attempt
+pub fn log_failed_token_attempt(
+    failed_tokens: &Mutex<FailedTokenMap>,
+    ip: Option<IpAddr>,
+    token: &str,
+) -> Option<String> {
+    let mut failed_tokens = failed_tokens
+        .lock()
+        .expect("Failed to get a lock on failed token map.");
+    failed_tokens.log_failed_attempt(ip, token)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ip_lockout() {
+        let mut map = FailedTokenMap::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        for index in 0..FAILED_TOKEN_IP_COUNT {
+            assert!(map.verify_ip(ip).is_ok());
+            // different prefix each time
+            map.log_failed_attempt(Some(ip), &format!("{index:0>8}guess"));
+        }
+        assert!(map.verify_ip(ip).is_err());
+        assert!(map.verify_ip(other).is_ok());
+    }
+
+    #[test]
+    fn test_prefix_threshold() {
+        let mut map = FailedTokenMap::new();
+        for index in 1..FAILED_TOKEN_PREFIX_COUNT {
+            let ip = IpAddr::from([10, 0, 0, index as u8]);
+            assert_eq!(map.log_failed_attempt(Some(ip), "abcdefghXYZ"), None);
+        }
+        assert_eq!(
+            map.log_failed_attempt(None, "abcdefgh123").as_deref(),
+            Some("abcdefgh")
+        );
+        // counter starts over
+        assert_eq!(map.log_failed_attempt(None, "abcdefgh123"), None);
+        // too short to be tracked
+        for _ in 0..FAILED_TOKEN_PREFIX_COUNT {
+            assert_eq!(map.log_failed_attempt(None, "abc"), None);
+        }
+    }
+}
@@ -1,4 +1,7 @@
+pub mod authz_trace;
+pub mod challenge;
 pub mod failed_login;
+pub mod failed_token;
 
 use std::{
     env,
@@ -8,7 +11,7 @@ use std::{
 use axum::{
     async_trait,
     extract::{FromRef, FromRequestParts},
-    http::request::Parts,
+    http::{request::Parts, Method},
 };
 use axum_extra::extract::cookie::CookieJar;
 use jsonwebtoken::{
@@ -16,10 +19,12 @@ use jsonwebtoken::{
 };
 use serde::{Deserialize, Serialize};
 
+use self::authz_trace::AuthzCheck;
 use crate::{
     appstate::AppState,
     db::{Group, OAuth2AuthorizedApp, OAuth2Token, Session, SessionState, User},
     error::WebError,
+    expired_cleanup::remove_stale_session,
     handlers::SESSION_COOKIE_NAME,
     server_config,
 };
@@ -129,11 +134,19 @@ where
             if let Some(session_cookie) = cookies.get(SESSION_COOKIE_NAME) {
                 return {
                     match Session::find_by_id(&appstate.pool, session_cookie.value()).await {
-                        Ok(Some(session)) => {
+                        Ok(Some(mut session)) => {
                             if session.expired() {
-                                let _result = session.delete(&appstate.pool).await;
+                                remove_stale_session(&appstate.pool, session).await;
                                 Err(WebError::Authorization("Session expired".into()))
+                            } else if session.idle(&appstate.pool).await? {
+                                info!(
+                                    "Session of user {} expired due to inactivity",
+                                    session.user_id
+                                );
+                                remove_stale_session(&appstate.pool, session).await;
+                                Err(WebError::SessionIdle)
                             } else {
+                                session.touch(&appstate.pool).await?;
                                 Ok(session)
                             }
                         }
@@ -149,10 +162,13 @@ where
 
 // Extension of base user session that contains user data fetched from database.
 // This represents a session for a user who completed the login process (including MFA, if enabled).
+// Impersonation sessions are read-only: only safe HTTP methods are allowed.
 pub struct SessionInfo {
     pub session: Session,
     pub user: User,
     pub is_admin: bool,
+    // username of the admin impersonating `user`
+    pub impersonator: Option<String>,
     groups: Vec<Group>,
 }
 
@@ -163,6 +179,7 @@ impl SessionInfo {
             session,
             user,
             is_admin,
+            impersonator: None,
             groups: Vec::new(),
         }
     }
@@ -170,6 +187,15 @@ impl SessionInfo {
     fn contains_group(&self, group_name: &str) -> bool {
         self.groups.iter().any(|group| group.name == group_name)
     }
+
+    /// Admins in the key escrow group can read escrowed device private keys,
+    /// but not while impersonating someone.
+    #[must_use]
+    pub fn can_access_escrowed_keys(&self) -> bool {
+        self.is_admin
+            && self.impersonator.is_none()
+            && self.contains_group(&server_config().key_escrow_groupname)
+    }
 }
 
 #[async_trait]
@@ -189,14 +215,73 @@ where
             if user.mfa_enabled && session.state != SessionState::MultiFactorVerified {
                 return Err(WebError::Authorization("MFA not verified".into()));
             }
-            let Ok(groups) = user.member_of(&appstate.pool).await else {
+            let Ok(groups) = user.effective_member_of(&appstate.pool).await else {
                 return Err(WebError::DbError("cannot fetch groups".into()));
             };
+            let impersonator = match session.impersonator_id {
+                Some(impersonator_id) => {
+                    let Some(impersonator) =
+                        User::find_by_id(&appstate.pool, impersonator_id).await?
+                    else {
+                        return Err(WebError::Authorization(
+                            "Impersonating user not found".into(),
+                        ));
+                    };
+                    // admin could have been disabled or removed from admin group since
+                    if !impersonator.is_active
+                        || !impersonator
+                            .effective_member_of_names(&appstate.pool)
+                            .await?
+                            .contains(&server_config().admin_groupname)
+                    {
+                        warn!(
+                            "User {} is no longer an active admin, ending impersonation of user {}",
+                            impersonator.username, user.username
+                        );
+                        session.delete(&appstate.pool).await?;
+                        return Err(WebError::Authorization(
+                            "Impersonating user is no longer an active admin".into(),
+                        ));
+                    }
+                    info!(
+                        "User {} impersonating user {}: {} {}",
+                        impersonator.username, user.username, parts.method, parts.uri
+                    );
+                    if !matches!(parts.method, Method::GET | Method::HEAD | Method::OPTIONS) {
+                        warn!(
+                            "User {} tried to modify data while impersonating user {}",
+                            impersonator.username, user.username
+                        );
+                        // recorded for the admin, not the impersonated user
+                        return Err(appstate
+                            .authz_denials
+                            .lock()
+                            .expect("Failed to lock authorization denials")
+                            .deny(
+                                impersonator_id,
+                                parts,
+                                vec![AuthzCheck::Session],
+                                AuthzCheck::Impersonation,
+                                Vec::new(),
+                                "Changes are not allowed while impersonating a user",
+                            ));
+                    }
+                    Some(impersonator.username)
+                }
+                None => None,
+            };
+            if user.break_glass {
+                warn!(
+                    break_glass = true,
+                    "Break-glass user {}: {} {}", user.username, parts.method, parts.uri
+                );
+            }
             let groupname = server_config().admin_groupname.clone();
             Ok(SessionInfo {
                 session,
                 user,
                 is_admin: groups.iter().any(|group| group.name == groupname),
+                impersonator,
                 groups,
             })
         } else {
@@ -228,7 +313,21 @@ macro_rules! role {
                     return Ok(Self {});
                 }
                 )*
-                Err(WebError::Forbidden("access denied".into()))
+                let Some(user_id) = session_info.user.id else {
+                    return Err(WebError::Forbidden("access denied".into()));
+                };
+                Err(AppState::from_ref(state)
+                    .authz_denials
+                    .lock()
+                    .expect("Failed to lock authorization denials")
+                    .deny(
+                        user_id,
+                        parts,
+                        vec![AuthzCheck::Session],
+                        AuthzCheck::GroupMembership,
+                        vec![$(server_config().$config_field.clone()),*],
+                        "access denied",
+                    ))
             }
         }
     };
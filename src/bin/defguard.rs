@@ -1,23 +1,48 @@
 use std::{
     fs::read_to_string,
+    path::Path,
     sync::{Arc, Mutex},
 };
 
+use anyhow::anyhow;
 use secrecy::ExposeSecret;
 use tokio::sync::{broadcast, mpsc::unbounded_channel};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use defguard::{
     auth::failed_login::FailedLoginMap,
+    break_glass::init_break_glass_account,
+    cli::{run_admin_command, run_check_command, run_settings_command},
     config::{Command, DefGuardConfig},
-    db::{init_db, AppEvent, GatewayEvent, Settings, User},
-    grpc::{run_grpc_bidi_stream, run_grpc_server, GatewayMap, WorkerState},
+    config_schema::config_schema,
+    db::{
+        init_db_from_config, models::worker_job::WorkerJob, AppEvent, GatewayEvent, ReadPool,
+        Settings, User,
+    },
+    dns::{dns_publish_job, run_dns_publisher},
+    expired_cleanup::expired_cleanup_job,
+    feature_flags::{self, run_feature_flag_listener},
+    gateway_event_journal::{journal_purge_job, run_gateway_event_journal},
+    gateway_event_relay::{outbox_purge_job, run_outbox_publisher, OutboxConsumer},
+    geoip::init_geoip,
+    grpc::{
+        run_grpc_bidi_stream, run_grpc_server, worker::worker_job_reclaim_job, GatewayMap,
+        WorkerState,
+    },
+    guest_access::guest_access_job,
     headers::create_user_agent_parser,
     init_dev_env, init_vpn_location,
+    jobs::JobRunner,
     mail::{run_mail_handler, Mail},
+    mfa_policy::mfa_policy_job,
+    openid_backchannel_logout::backchannel_logout_job,
     run_web_server,
-    wireguard_peer_disconnect::run_periodic_peer_disconnect,
-    wireguard_stats_purge::run_periodic_stats_purge,
+    ssh_ca::read_ca_key_file,
+    tls::TlsIdentity,
+    user_suspension::user_reactivation_job,
+    wireguard_peer_disconnect::peer_disconnect_job,
+    wireguard_psk_rotation::psk_rotation_job,
+    wireguard_stats_purge::stats_purge_job,
     SERVER_CONFIG,
 };
 
@@ -29,6 +54,11 @@ async fn main() -> Result<(), anyhow::Error> {
     if dotenvy::from_filename(".env.local").is_err() {
         dotenvy::dotenv().ok();
     }
+    // the reference doesn't depend on configuration, print it before required options are checked
+    if std::env::args().nth(1).as_deref() == Some("config-schema") {
+        println!("{}", serde_json::to_string_pretty(&config_schema())?);
+        return Ok(());
+    }
     let config = DefGuardConfig::new();
     SERVER_CONFIG.set(config.clone())?;
     // initialize tracing
@@ -48,14 +78,7 @@ async fn main() -> Result<(), anyhow::Error> {
     info!("Starting defguard");
     debug!("Using config: {config:?}");
 
-    let pool = init_db(
-        &config.database_host,
-        config.database_port,
-        &config.database_name,
-        &config.database_user,
-        config.database_password.expose_secret(),
-    )
-    .await;
+    let pool = init_db_from_config(&config).await;
 
     // handle optional subcommands
     if let Some(command) = &config.cmd {
@@ -67,6 +90,18 @@ async fn main() -> Result<(), anyhow::Error> {
                 let token = init_vpn_location(&pool, args).await?;
                 println!("{token}");
             }
+            Command::Admin(command) => {
+                run_admin_command(&pool, command).await?;
+            }
+            Command::Settings(command) => {
+                run_settings_command(&pool, command).await?;
+            }
+            Command::Check(args) => {
+                run_check_command(&pool, args).await?;
+            }
+            Command::ConfigSchema => {
+                println!("{}", serde_json::to_string_pretty(&config_schema())?);
+            }
         };
 
         // return early
@@ -80,10 +115,35 @@ async fn main() -> Result<(), anyhow::Error> {
     }
 
     let (webhook_tx, webhook_rx) = unbounded_channel::<AppEvent>();
-    let (wireguard_tx, _wireguard_rx) = broadcast::channel::<GatewayEvent>(256);
+    let (wireguard_tx, _wireguard_rx) =
+        broadcast::channel::<GatewayEvent>(config.gateway_events_capacity);
+    // with multiple instances gateways receive events relayed through the database,
+    // otherwise directly from producers
+    feature_flags::reload(&pool).await?;
+    let gateway_events_tx = if config.ha_enabled {
+        tokio::spawn(run_feature_flag_listener(pool.clone()));
+        let (gateway_events_tx, _gateway_events_rx) =
+            broadcast::channel::<GatewayEvent>(config.gateway_events_capacity);
+        let consumer = OutboxConsumer::new(pool.clone(), gateway_events_tx.clone()).await?;
+        tokio::spawn(consumer.run());
+        tokio::spawn(run_outbox_publisher(pool.clone(), wireguard_tx.subscribe()));
+        gateway_events_tx
+    } else {
+        wireguard_tx.clone()
+    };
+    tokio::spawn(run_dns_publisher(pool.clone(), wireguard_tx.subscribe()));
     let (mail_tx, mail_rx) = unbounded_channel::<Mail>();
-    let worker_state = Arc::new(Mutex::new(WorkerState::new(webhook_tx.clone())));
+    let mut worker_state = WorkerState::new(webhook_tx.clone());
+    // dispatch jobs which didn't finish before restart
+    worker_state.restore_jobs(WorkerJob::unfinished(&pool).await?);
+    let worker_state = Arc::new(Mutex::new(worker_state));
     let gateway_state = Arc::new(Mutex::new(GatewayMap::new()));
+    // events are journaled once, by the instance which produced them
+    tokio::spawn(run_gateway_event_journal(
+        pool.clone(),
+        wireguard_tx.subscribe(),
+        Arc::clone(&gateway_state),
+    ));
     let user_agent_parser = create_user_agent_parser();
 
     // initialize admin user
@@ -92,28 +152,87 @@ async fn main() -> Result<(), anyhow::Error> {
     // initialize default settings
     Settings::init_defaults(&pool).await?;
 
-    // read grpc TLS cert and key
-    let grpc_cert = config
-        .grpc_cert
-        .as_ref()
-        .and_then(|path| read_to_string(path).ok());
-    let grpc_key = config
-        .grpc_key
+    // initialize break-glass account, if configured
+    init_break_glass_account(&pool, &config).await?;
+
+    // load GeoIP database, if configured
+    init_geoip(config.geoip_database.as_deref())?;
+
+    // read grpc TLS cert and key, failing early if they can't be used
+    let (grpc_cert, grpc_key) = match (&config.grpc_cert, &config.grpc_key) {
+        (Some(cert), Some(key)) => {
+            TlsIdentity::load(Path::new(cert), Path::new(key))?;
+            (Some(read_to_string(cert)?), Some(read_to_string(key)?))
+        }
+        (None, None) => (None, None),
+        _ => return Err(anyhow!("Both gRPC certificate and key have to be set")),
+    };
+    let grpc_client_ca = config
+        .grpc_client_ca
         .as_ref()
-        .and_then(|path| read_to_string(path).ok());
+        .map(|path| {
+            read_to_string(path).map_err(|err| anyhow!("Failed to read {}: {err}", path.display()))
+        })
+        .transpose()?;
+    // SSH CA key file is read on use, but has to be usable from the start
+    if let Some(path) = &config.ssh_ca_key {
+        read_ca_key_file(path)
+            .map_err(|err| anyhow!("Invalid SSH CA key {}: {err}", path.display()))?;
+    }
+    // fail early on unusable web server cert and key as well, they're reloaded later on
+    if let (Some(cert), Some(key)) = (&config.http_tls_cert, &config.http_tls_key) {
+        TlsIdentity::load(cert, key)?;
+    }
 
     // initialize failed login attempt tracker
     let failed_logins = FailedLoginMap::new();
     let failed_logins = Arc::new(Mutex::new(failed_logins));
 
+    // register periodic background jobs
+    let mut job_runner = JobRunner::new(pool.clone());
+    job_runner.register(peer_disconnect_job(pool.clone(), wireguard_tx.clone()));
+    job_runner.register(psk_rotation_job(
+        pool.clone(),
+        wireguard_tx.clone(),
+        mail_tx.clone(),
+    ));
+    job_runner.register(backchannel_logout_job(pool.clone()));
+    job_runner.register(mfa_policy_job(pool.clone(), mail_tx.clone()));
+    job_runner.register(dns_publish_job(pool.clone()));
+    job_runner.register(user_reactivation_job(pool.clone(), wireguard_tx.clone()));
+    job_runner.register(guest_access_job(
+        pool.clone(),
+        wireguard_tx.clone(),
+        mail_tx.clone(),
+    ));
+    job_runner.register(expired_cleanup_job(pool.clone()));
+    job_runner.register(journal_purge_job(pool.clone()));
+    job_runner.register(worker_job_reclaim_job(
+        pool.clone(),
+        Arc::clone(&worker_state),
+    ));
+    if config.ha_enabled {
+        job_runner.register(outbox_purge_job(pool.clone()));
+    }
+    if !config.disable_stats_purge {
+        job_runner.register(stats_purge_job(
+            pool.clone(),
+            config.stats_purge_frequency.into(),
+            config.stats_purge_threshold.into(),
+        ));
+    }
+    let job_runner = Arc::new(job_runner);
+
+    // heavy read-only endpoints can be served by a read replica
+    let read_pool = ReadPool::from_config(&config, &pool);
+
     // run services
     tokio::select! {
-        res = run_grpc_bidi_stream(pool.clone(), wireguard_tx.clone(), mail_tx.clone(), user_agent_parser.clone()), if config.proxy_url.is_some() => error!("Proxy gRPC stream returned early: {res:#?}"),
-        res = run_grpc_server(Arc::clone(&worker_state), pool.clone(), Arc::clone(&gateway_state), wireguard_tx.clone(), mail_tx.clone(), grpc_cert, grpc_key, failed_logins.clone()) => error!("gRPC server returned early: {res:#?}"),
-        res = run_web_server(worker_state, gateway_state, webhook_tx, webhook_rx, wireguard_tx.clone(), mail_tx, pool.clone(), user_agent_parser, failed_logins) => error!("Web server returned early: {res:#?}"),
-        res = run_mail_handler(mail_rx, pool.clone()) => error!("Mail handler returned early: {res:#?}"),
-        res = run_periodic_peer_disconnect(pool.clone(), wireguard_tx) => error!("Periodic peer disconnect task returned early: {res:#?}"),
-        res = run_periodic_stats_purge(pool, config.stats_purge_frequency.into(), config.stats_purge_threshold.into()), if !config.disable_stats_purge => error!("Periodic stats purge task returned early: {res:#?}"),
+        res = run_grpc_bidi_stream(pool.clone(), wireguard_tx.clone(), mail_tx.clone(), user_agent_parser.clone(), failed_logins.clone()), if config.proxy_url.is_some() => error!("Proxy gRPC stream returned early: {res:#?}"),
+        res = run_grpc_server(Arc::clone(&worker_state), pool.clone(), Arc::clone(&gateway_state), gateway_events_tx, mail_tx.clone(), grpc_cert, grpc_key, grpc_client_ca, failed_logins.clone()) => error!("gRPC server returned early: {res:#?}"),
+        res = run_web_server(worker_state, gateway_state, webhook_tx, webhook_rx, wireguard_tx, mail_tx, pool.clone(), read_pool, user_agent_parser, failed_logins, Arc::clone(&job_runner)) => error!("Web server returned early: {res:#?}"),
+        res = run_mail_handler(mail_rx, pool) => error!("Mail handler returned early: {res:#?}"),
+        () = job_runner.run() => error!("Background job runner returned early"),
     }
     Ok(())
 }
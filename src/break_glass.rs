@@ -0,0 +1,156 @@
+//! Break-glass account for emergency access.
+//!
+//! If external authentication is down and regular admins are locked out, e.g. after losing
+//! their MFA devices, a designated local account can be used to recover the instance.
+//! It's meant as a last resort, hence additional controls:
+//! - it can't be used while a regular admin has logged in recently,
+//! - admins are notified of each login right away,
+//! - its sessions are short-lived,
+//! - each of its requests is logged with `break_glass` marker.
+//!
+//! The account authenticates with local password only and is never synchronized to LDAP.
+
+use chrono::{Duration, Utc};
+use secrecy::ExposeSecret;
+use sqlx::Error as SqlxError;
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    config::DefGuardConfig,
+    db::{DbPool, Group, User},
+    error::WebError,
+    mail::Mail,
+    notifications::{notify_admins, AdminNotification, NotificationCategory},
+    password_policy::{PasswordPolicy, PasswordPolicyError},
+    server_config,
+    templates::{self, TemplateError},
+};
+
+static BREAK_GLASS_LOGIN_SUBJECT: &str = "Defguard: break-glass account used";
+
+#[derive(Debug, Error)]
+pub enum BreakGlassError {
+    #[error("User {0} already exists")]
+    UserExists(String),
+    #[error("Admin group {0} not found")]
+    AdminGroupNotFound(String),
+    #[error(transparent)]
+    PasswordPolicy(#[from] PasswordPolicyError),
+    #[error(transparent)]
+    DbError(#[from] SqlxError),
+}
+
+/// Create break-glass account as a member of the admin group.
+/// Password is validated against configured password policy.
+pub async fn create_break_glass_account(
+    pool: &DbPool,
+    username: &str,
+    email: &str,
+    password: &str,
+) -> Result<User, BreakGlassError> {
+    if User::find_by_username(pool, username).await?.is_some() {
+        return Err(BreakGlassError::UserExists(username.into()));
+    }
+    let policy = PasswordPolicy::load(pool).await?;
+    policy.validate(password, &[username, email]).await?;
+    let admin_groupname = &server_config().admin_groupname;
+    let Some(admin_group) = Group::find_by_name(pool, admin_groupname).await? else {
+        return Err(BreakGlassError::AdminGroupNotFound(admin_groupname.clone()));
+    };
+
+    let mut user = User::new(
+        username,
+        Some(password),
+        "Account",
+        "Break-glass",
+        email,
+        None,
+    );
+    user.break_glass = true;
+    let mut transaction = pool.begin().await?;
+    user.save(&mut *transaction).await?;
+    user.add_to_group(&mut *transaction, &admin_group).await?;
+    transaction.commit().await?;
+    warn!("Created break-glass account {username}");
+    Ok(user)
+}
+
+/// Create break-glass account set in configuration, unless it already exists.
+pub async fn init_break_glass_account(
+    pool: &DbPool,
+    config: &DefGuardConfig,
+) -> Result<(), BreakGlassError> {
+    let (Some(username), Some(email), Some(password)) = (
+        &config.break_glass_username,
+        &config.break_glass_email,
+        &config.break_glass_password,
+    ) else {
+        if config.break_glass_username.is_some() {
+            warn!("Break-glass account requires username, email and password, skipping");
+        }
+        return Ok(());
+    };
+    match User::find_by_username(pool, username).await? {
+        Some(user) if user.break_glass => {
+            debug!("Break-glass account {username} already exists");
+            Ok(())
+        }
+        Some(_) => {
+            warn!("User {username} already exists and is not a break-glass account, skipping");
+            Ok(())
+        }
+        None => {
+            create_break_glass_account(pool, username, email, password.expose_secret()).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Refuse logging in with break-glass account while regular admins are active.
+pub async fn check_break_glass_login(pool: &DbPool, user: &User) -> Result<(), WebError> {
+    let window = server_config().break_glass_admin_login_window;
+    let since = (Utc::now() - Duration::seconds(window.as_secs() as i64)).naive_utc();
+    if User::regular_admin_logged_in_since(pool, since).await? {
+        warn!(
+            break_glass = true,
+            "Refusing login of break-glass account {}, a regular admin has logged in within {window}",
+            user.username
+        );
+        return Err(WebError::Forbidden(
+            "Break-glass account can't be used while regular admins are active".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Notify admins that break-glass account has logged in.
+/// Notification is delivered in the background, so it doesn't delay logging in.
+pub fn notify_break_glass_login(
+    pool: &DbPool,
+    mail_tx: &UnboundedSender<Mail>,
+    user: &User,
+    ip_address: &str,
+) -> Result<(), TemplateError> {
+    warn!(
+        break_glass = true,
+        "Break-glass account {} logged in from {ip_address}", user.username
+    );
+    let notification = AdminNotification {
+        category: NotificationCategory::Security,
+        subject: BREAK_GLASS_LOGIN_SUBJECT.to_string(),
+        message: format!(
+            "Break-glass account {} logged in from {ip_address}",
+            user.username
+        ),
+        html: templates::break_glass_login_mail(&user.username, ip_address)?,
+    };
+    let pool = pool.clone();
+    let mail_tx = mail_tx.clone();
+    tokio::spawn(async move {
+        if let Err(err) = notify_admins(&pool, &mail_tx, &notification).await {
+            error!("Failed to notify admins of break-glass login: {err}");
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,402 @@
+//! Administrative commands operating directly on the database.
+//!
+//! Meant for recovering a locked-out instance (e.g. lost admin password, broken SMTP
+//! or LDAP settings) without starting the web server. Changes are logged with `cli` actor.
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+use sqlx::Error as SqlxError;
+use thiserror::Error;
+
+use crate::{
+    break_glass::{create_break_glass_account, BreakGlassError},
+    config::{AdminCommand, CheckArgs, SettingsCommand},
+    consistency::run_consistency_checks,
+    db::{DbPool, Settings, User},
+    diagnostics::{run_diagnostics, CheckStatus, DiagnosticsOptions},
+    ldap::utils::ldap_change_password,
+    password_policy::{PasswordPolicy, PasswordPolicyError},
+};
+
+/// Actor recorded for changes made using command-line utilities.
+pub const CLI_ACTOR: &str = "cli";
+
+// settings which are never printed
+const SECRET_SETTINGS: [&str; 4] = [
+    "smtp_password",
+    "ldap_bind_password",
+    "dns_tsig_secret",
+    "dns_webhook_secret",
+];
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("User {0} not found")]
+    UserNotFound(String),
+    #[error("Unknown setting {0}")]
+    UnknownSetting(String),
+    #[error("Invalid value for setting {0}: {1}")]
+    InvalidValue(String, String),
+    #[error("Invalid settings: {0}")]
+    InvalidSettings(String),
+    #[error(transparent)]
+    PasswordPolicy(#[from] PasswordPolicyError),
+    #[error(transparent)]
+    BreakGlass(#[from] BreakGlassError),
+    #[error("Aborted")]
+    Aborted,
+    #[error("Some checks have failed")]
+    ChecksFailed,
+    #[error("Database inconsistencies found")]
+    InconsistenciesFound,
+    #[error(transparent)]
+    DbError(#[from] SqlxError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+async fn find_user(pool: &DbPool, username: &str) -> Result<User, CliError> {
+    User::find_by_username(pool, username)
+        .await?
+        .ok_or_else(|| CliError::UserNotFound(username.into()))
+}
+
+/// Set a new password for the user, validated against configured password policy.
+/// All user sessions are logged out.
+pub async fn reset_password(pool: &DbPool, username: &str, password: &str) -> Result<(), CliError> {
+    let mut user = find_user(pool, username).await?;
+    let policy = PasswordPolicy::load(pool).await?;
+    policy.validate(password, &[username, &user.email]).await?;
+
+    user.set_password(password);
+    user.save(pool).await?;
+    user.logout_all_sessions(pool).await?;
+    if !user.break_glass {
+        if let Err(err) = ldap_change_password(pool, username, password).await {
+            warn!("Failed to change LDAP password of user {username}: {err}");
+        }
+    }
+    info!("User {CLI_ACTOR} reset password of user {username}");
+    Ok(())
+}
+
+/// Disable all MFA methods of the user.
+pub async fn disable_mfa(pool: &DbPool, username: &str) -> Result<(), CliError> {
+    let mut user = find_user(pool, username).await?;
+    user.disable_mfa(pool).await?;
+    info!("User {CLI_ACTOR} disabled MFA of user {username}");
+    Ok(())
+}
+
+/// Get value of a single setting. Secrets are masked.
+pub async fn get_setting(pool: &DbPool, key: &str) -> Result<Value, CliError> {
+    let settings = serde_json::to_value(Settings::get_settings(pool).await?)
+        .expect("Failed to serialize settings");
+    let value = settings
+        .get(key)
+        .ok_or_else(|| CliError::UnknownSetting(key.into()))?;
+    if SECRET_SETTINGS.contains(&key) && !value.is_null() {
+        Ok(json!("********"))
+    } else {
+        Ok(value.clone())
+    }
+}
+
+/// Change a single setting. Value is parsed as JSON, falling back to a plain string,
+/// so `true`, `8` and `null` work as expected for non-string settings.
+pub async fn set_setting(pool: &DbPool, key: &str, value: &str) -> Result<(), CliError> {
+    let settings = Settings::get_settings(pool).await?;
+    let current = serde_json::to_value(&settings).expect("Failed to serialize settings");
+    if current.get(key).is_none() {
+        return Err(CliError::UnknownSetting(key.into()));
+    }
+
+    let with_value = |value: Value| {
+        let mut changed = current.clone();
+        changed[key] = value;
+        serde_json::from_value::<Settings>(changed)
+    };
+    let mut changed = match serde_json::from_str(value).map(with_value) {
+        Ok(Ok(changed)) => changed,
+        _ => with_value(Value::String(value.into()))
+            .map_err(|err| CliError::InvalidValue(key.into(), err.to_string()))?,
+    };
+    // not serialized
+    changed.id = settings.id;
+    changed.uuid = settings.uuid;
+
+    PasswordPolicy::validate_settings(&changed).map_err(CliError::InvalidSettings)?;
+    changed.save(pool).await?;
+    info!("User {CLI_ACTOR} changed setting {key}");
+    Ok(())
+}
+
+/// Ask for confirmation on standard input, unless confirmed with `--yes`.
+fn confirm(question: &str, yes: bool) -> Result<(), CliError> {
+    if yes {
+        return Ok(());
+    }
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(CliError::Aborted),
+    }
+}
+
+// Password is read from standard input, so it doesn't end up in shell history.
+fn read_password(username: &str) -> Result<String, CliError> {
+    print!("New password for user {username}: ");
+    io::stdout().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+pub async fn run_admin_command(pool: &DbPool, command: &AdminCommand) -> Result<(), CliError> {
+    match command {
+        AdminCommand::ResetPassword { username, yes } => {
+            // fail early, before asking for the password
+            find_user(pool, username).await?;
+            confirm(
+                &format!("Reset password of user {username} and log out all their sessions?"),
+                *yes,
+            )?;
+            let password = read_password(username)?;
+            reset_password(pool, username, &password).await?;
+            println!("Password of user {username} has been reset");
+        }
+        AdminCommand::DisableMfa { username, yes } => {
+            find_user(pool, username).await?;
+            confirm(&format!("Disable MFA of user {username}?"), *yes)?;
+            disable_mfa(pool, username).await?;
+            println!("MFA of user {username} has been disabled");
+        }
+        AdminCommand::CreateBreakGlass {
+            username,
+            email,
+            yes,
+        } => {
+            confirm(
+                &format!("Create break-glass admin account {username}?"),
+                *yes,
+            )?;
+            let password = read_password(username)?;
+            create_break_glass_account(pool, username, email, &password).await?;
+            info!("User {CLI_ACTOR} created break-glass account {username}");
+            println!("Break-glass account {username} has been created");
+        }
+    }
+    Ok(())
+}
+
+pub async fn run_settings_command(
+    pool: &DbPool,
+    command: &SettingsCommand,
+) -> Result<(), CliError> {
+    match command {
+        SettingsCommand::Get { key } => {
+            println!("{}", get_setting(pool, key).await?);
+        }
+        SettingsCommand::Set { key, value, yes } => {
+            let current = get_setting(pool, key).await?;
+            confirm(
+                &format!("Change setting {key} from {current} to {value}?"),
+                *yes,
+            )?;
+            set_setting(pool, key, value).await?;
+            println!("Setting {key} has been changed");
+        }
+    }
+    Ok(())
+}
+
+/// Check database consistency and print the report. Fails if any inconsistencies are left.
+async fn run_consistency_check(pool: &DbPool, repair: bool) -> Result<(), CliError> {
+    let report = run_consistency_checks(pool, repair, CLI_ACTOR).await?;
+    for check in &report.checks {
+        let status = match (check.count, check.repaired) {
+            (0, _) => "PASS",
+            (_, Some(_)) => "FIXED",
+            _ => "FAIL",
+        };
+        println!("[{status}] {}: {} found", check.description, check.count);
+        if check.count > 0 {
+            println!("       sample: {}", check.sample.join(", "));
+            if !check.repairable {
+                println!("       needs manual review, not repaired automatically");
+            }
+        }
+    }
+    if report.has_issues() {
+        return Err(CliError::InconsistenciesFound);
+    }
+    Ok(())
+}
+
+/// Run diagnostics and print the report. Fails if any of the checks has failed.
+pub async fn run_check_command(pool: &DbPool, args: &CheckArgs) -> Result<(), CliError> {
+    if args.consistency {
+        return run_consistency_check(pool, args.repair).await;
+    }
+    let options = DiagnosticsOptions {
+        test_mail_to: args.test_mail_to.clone(),
+    };
+    let report = run_diagnostics(pool, None, &options).await;
+    for check in &report.checks {
+        let status = match check.status {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+        };
+        println!("[{status}] {}: {}", check.name, check.message);
+        if let Some(hint) = &check.hint {
+            println!("       {hint}");
+        }
+    }
+    if report.status == CheckStatus::Fail {
+        return Err(CliError::ChecksFailed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use claims::{assert_err, assert_ok};
+
+    use super::*;
+    use crate::{
+        config::DefGuardConfig,
+        db::{models::session::SessionState, Session},
+        SERVER_CONFIG,
+    };
+
+    async fn create_user(pool: &DbPool) -> User {
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(pool).await.unwrap();
+        user
+    }
+
+    #[sqlx::test]
+    async fn test_reset_password(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let user = create_user(&pool).await;
+        let session = Session::new(
+            user.id.unwrap(),
+            SessionState::PasswordVerified,
+            "127.0.0.1".into(),
+            None,
+        );
+        session.save(&pool).await.unwrap();
+
+        assert!(matches!(
+            reset_password(&pool, "nobody", "Str0ng!Passw0rd").await,
+            Err(CliError::UserNotFound(_))
+        ));
+        // same validation as the API
+        assert!(matches!(
+            reset_password(&pool, "hpotter", "short").await,
+            Err(CliError::PasswordPolicy(_))
+        ));
+        let user = User::find_by_username(&pool, "hpotter")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ok!(user.verify_password("pass123"));
+
+        reset_password(&pool, "hpotter", "Str0ng!Passw0rd")
+            .await
+            .unwrap();
+        let user = User::find_by_username(&pool, "hpotter")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ok!(user.verify_password("Str0ng!Passw0rd"));
+        assert_err!(user.verify_password("pass123"));
+        assert!(Session::find_by_id(&pool, &session.id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[sqlx::test]
+    async fn test_disable_mfa(pool: DbPool) {
+        let mut user = create_user(&pool).await;
+        user.new_totp_secret(&pool).await.unwrap();
+        user.enable_totp(&pool).await.unwrap();
+        user.enable_mfa(&pool).await.unwrap();
+        assert!(user.mfa_enabled);
+
+        disable_mfa(&pool, "hpotter").await.unwrap();
+        let user = User::find_by_username(&pool, "hpotter")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!user.mfa_enabled);
+        assert!(!user.totp_enabled);
+        assert!(user.totp_secret.is_none());
+    }
+
+    #[sqlx::test]
+    async fn test_settings(pool: DbPool) {
+        set_setting(&pool, "instance_name", "Hogwarts")
+            .await
+            .unwrap();
+        assert_eq!(
+            get_setting(&pool, "instance_name").await.unwrap(),
+            json!("Hogwarts")
+        );
+        // numeric-looking values of string settings stay strings
+        set_setting(&pool, "instance_name", "42").await.unwrap();
+        assert_eq!(
+            get_setting(&pool, "instance_name").await.unwrap(),
+            json!("42")
+        );
+
+        set_setting(&pool, "smtp_port", "2525").await.unwrap();
+        set_setting(&pool, "smtp_password", "secret").await.unwrap();
+        set_setting(&pool, "enrollment_web_fallback_enabled", "true")
+            .await
+            .unwrap();
+        let settings = Settings::get_settings(&pool).await.unwrap();
+        assert_eq!(settings.smtp_port, Some(2525));
+        assert_eq!(settings.smtp_password.unwrap().expose_secret(), "secret");
+        assert!(settings.enrollment_web_fallback_enabled);
+        assert_eq!(
+            get_setting(&pool, "smtp_password").await.unwrap(),
+            json!("********")
+        );
+
+        set_setting(&pool, "smtp_port", "null").await.unwrap();
+        let settings = Settings::get_settings(&pool).await.unwrap();
+        assert_eq!(settings.smtp_port, None);
+
+        assert!(matches!(
+            get_setting(&pool, "no_such_setting").await,
+            Err(CliError::UnknownSetting(_))
+        ));
+        assert!(matches!(
+            set_setting(&pool, "no_such_setting", "1").await,
+            Err(CliError::UnknownSetting(_))
+        ));
+        assert!(matches!(
+            set_setting(&pool, "smtp_port", "not a number").await,
+            Err(CliError::InvalidValue(..))
+        ));
+        // settings are validated the same way as in the API
+        assert!(matches!(
+            set_setting(&pool, "password_min_length", "0").await,
+            Err(CliError::InvalidSettings(_))
+        ));
+    }
+}
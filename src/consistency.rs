@@ -0,0 +1,337 @@
+//! Detection of orphaned and inconsistent database relations.
+//!
+//! Some relations predate foreign keys, or are kept without them on purpose, so rows can
+//! outlive the objects they point at. Each class of inconsistency is a check in [`CHECKS`]:
+//! a query listing keys of affected rows and, if the rows can be removed without losing
+//! anything meaningful, a statement repairing them. Ambiguous cases, e.g. which of two
+//! devices should keep a duplicated address, are only reported.
+
+use sqlx::{query, query_as, Error as SqlxError, PgConnection};
+use utoipa::ToSchema;
+
+use crate::db::DbPool;
+
+// number of affected row keys included in the report
+const SAMPLE_SIZE: i64 = 10;
+
+/// A class of inconsistencies.
+pub struct ConsistencyCheck {
+    pub name: &'static str,
+    pub description: &'static str,
+    // selects a single text column `key` identifying affected rows
+    detect: &'static str,
+    // removes all rows matched by `detect`; `None` if repair is ambiguous
+    repair: Option<&'static str>,
+}
+
+/// Registry of checks, run in order.
+pub static CHECKS: &[ConsistencyCheck] = &[
+    ConsistencyCheck {
+        name: "orphaned_authorized_app",
+        description: "OpenID app authorizations of removed users or clients",
+        detect: "SELECT app.id::text \"key\" FROM oauth2authorizedapp app \
+            WHERE NOT EXISTS (SELECT 1 FROM \"user\" u WHERE u.id = app.user_id) \
+            OR NOT EXISTS (SELECT 1 FROM oauth2client c WHERE c.id = app.oauth2client_id)",
+        repair: Some(
+            "DELETE FROM oauth2authorizedapp app \
+            WHERE NOT EXISTS (SELECT 1 FROM \"user\" u WHERE u.id = app.user_id) \
+            OR NOT EXISTS (SELECT 1 FROM oauth2client c WHERE c.id = app.oauth2client_id)",
+        ),
+    },
+    ConsistencyCheck {
+        name: "incomplete_group_member",
+        description: "Group memberships without a group or user",
+        detect: "SELECT concat(group_id, '/', user_id) \"key\" FROM group_user \
+            WHERE group_id IS NULL OR user_id IS NULL",
+        repair: Some("DELETE FROM group_user WHERE group_id IS NULL OR user_id IS NULL"),
+    },
+    ConsistencyCheck {
+        name: "orphaned_peer_stats",
+        description: "Peer statistics of removed locations",
+        detect: "SELECT s.id::text \"key\" FROM wireguard_peer_stats s \
+            WHERE NOT EXISTS (SELECT 1 FROM wireguard_network n WHERE n.id = s.network)",
+        repair: Some(
+            "DELETE FROM wireguard_peer_stats s \
+            WHERE NOT EXISTS (SELECT 1 FROM wireguard_network n WHERE n.id = s.network)",
+        ),
+    },
+    ConsistencyCheck {
+        name: "duplicate_device_address",
+        description: "Addresses assigned to more than one device in a location",
+        detect: "SELECT concat(wireguard_network_id, '/', host(wireguard_ip)) \"key\" \
+            FROM wireguard_network_device \
+            GROUP BY wireguard_network_id, wireguard_ip HAVING count(*) > 1",
+        repair: None,
+    },
+    ConsistencyCheck {
+        name: "device_address_outside_location",
+        description: "Device addresses outside of their location address range",
+        detect: "SELECT concat(wnd.wireguard_network_id, '/', wnd.device_id) \"key\" \
+            FROM wireguard_network_device wnd \
+            JOIN wireguard_network n ON n.id = wnd.wireguard_network_id \
+            WHERE NOT wnd.wireguard_ip << n.address",
+        repair: None,
+    },
+    // device names are only checked with a device name policy, which new names follow
+    ConsistencyCheck {
+        name: "invalid_device_name",
+        description: "Device names not allowed by the device name policy",
+        detect: "SELECT d.id::text \"key\" FROM device d, settings s \
+            WHERE s.id = 1 AND s.device_name_policy <> 'none' \
+            AND (d.name !~ '^[A-Za-z0-9_ -]+$' OR d.name <> btrim(d.name) \
+            OR char_length(d.name) > s.device_name_max_length)",
+        repair: None,
+    },
+    ConsistencyCheck {
+        name: "duplicate_device_name",
+        description: "Device names used by more than one device of a user",
+        detect: "SELECT concat(d.user_id, '/', lower(btrim(d.name))) \"key\" \
+            FROM device d, settings s \
+            WHERE s.id = 1 AND s.device_name_policy <> 'none' \
+            GROUP BY d.user_id, lower(btrim(d.name)) HAVING count(*) > 1",
+        repair: None,
+    },
+];
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ConsistencyCheckResult {
+    pub name: String,
+    pub description: String,
+    // number of affected rows before repair
+    pub count: i64,
+    // keys of some of the affected rows
+    pub sample: Vec<String>,
+    pub repairable: bool,
+    // number of removed rows, if repaired
+    pub repaired: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ConsistencyReport {
+    pub checks: Vec<ConsistencyCheckResult>,
+}
+
+impl ConsistencyReport {
+    /// Whether any inconsistencies are left after the run.
+    #[must_use]
+    pub fn has_issues(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|check| check.count > 0 && check.repaired.is_none())
+    }
+}
+
+async fn run_check(
+    conn: &mut PgConnection,
+    check: &ConsistencyCheck,
+    repair: bool,
+    actor: &str,
+) -> Result<ConsistencyCheckResult, SqlxError> {
+    let (count, sample): (i64, Vec<String>) = query_as(&format!(
+        "SELECT count(*), coalesce((array_agg(\"key\"))[1:{SAMPLE_SIZE}], '{{}}') \
+        FROM ({}) found",
+        check.detect
+    ))
+    .fetch_one(&mut *conn)
+    .await?;
+    let repaired = match check.repair {
+        Some(statement) if repair && count > 0 => {
+            let removed = query(statement).execute(&mut *conn).await?.rows_affected();
+            info!(
+                consistency_repair = true,
+                check = check.name,
+                removed,
+                "User {actor} repaired database inconsistencies: removed {removed} {}",
+                check.description.to_lowercase()
+            );
+            Some(removed)
+        }
+        _ => None,
+    };
+    if count > 0 && repaired.is_none() {
+        warn!(
+            check = check.name,
+            "Found {count} database inconsistencies: {}",
+            check.description.to_lowercase()
+        );
+    }
+
+    Ok(ConsistencyCheckResult {
+        name: check.name.into(),
+        description: check.description.into(),
+        count,
+        sample,
+        repairable: check.repair.is_some(),
+        repaired,
+    })
+}
+
+/// Run all checks. With `repair` set, repairable inconsistencies are removed in a single
+/// transaction; `actor` is the username recorded in logs.
+pub async fn run_consistency_checks(
+    pool: &DbPool,
+    repair: bool,
+    actor: &str,
+) -> Result<ConsistencyReport, SqlxError> {
+    let mut transaction = pool.begin().await?;
+    let mut checks = Vec::with_capacity(CHECKS.len());
+    for check in CHECKS {
+        checks.push(run_check(&mut transaction, check, repair, actor).await?);
+    }
+    transaction.commit().await?;
+
+    Ok(ConsistencyReport { checks })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::{Device, User, WireguardNetwork};
+
+    fn result<'a>(report: &'a ConsistencyReport, name: &str) -> &'a ConsistencyCheckResult {
+        report
+            .checks
+            .iter()
+            .find(|check| check.name == name)
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_consistency_checks(pool: DbPool) {
+        let report = run_consistency_checks(&pool, false, "test").await.unwrap();
+        assert_eq!(report.checks.len(), CHECKS.len());
+        assert!(!report.has_issues());
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.save(&pool).await.unwrap();
+        let network_id = network.id.unwrap();
+        let mut devices = Vec::new();
+        for name in ["first", "second", "third"] {
+            let mut device = Device::new(name.into(), name.into(), user.id.unwrap());
+            device.save(&pool).await.unwrap();
+            devices.push(device.id.unwrap());
+        }
+
+        // seed each class of inconsistencies; legacy device names count once a policy is set
+        for name in ["laptop", "Laptop "] {
+            let mut device = Device::new(name.into(), format!("key-{name}"), user.id.unwrap());
+            device.save(&pool).await.unwrap();
+        }
+        let report = run_consistency_checks(&pool, false, "test").await.unwrap();
+        assert_eq!(result(&report, "invalid_device_name").count, 0);
+        query("UPDATE settings SET device_name_policy = 'reject'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        query("INSERT INTO oauth2authorizedapp (oauth2client_id, user_id) VALUES (12345, $1)")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        query("INSERT INTO group_user (group_id, user_id) VALUES (NULL, $1)")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        query(
+            "INSERT INTO wireguard_peer_stats (device_id, network, upload, download, latest_handshake) \
+            VALUES ($1, 12345, 0, 0, now())",
+        )
+        .bind(devices[0])
+        .execute(&pool)
+        .await
+        .unwrap();
+        for (device_id, address) in [
+            (devices[0], "10.1.1.2"),
+            (devices[1], "10.1.1.2"),
+            (devices[2], "10.2.2.2"),
+        ] {
+            query(
+                "INSERT INTO wireguard_network_device (device_id, wireguard_network_id, wireguard_ip) \
+                VALUES ($1, $2, $3::inet)",
+            )
+            .bind(device_id)
+            .bind(network_id)
+            .bind(address)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let report = run_consistency_checks(&pool, false, "test").await.unwrap();
+        assert!(report.has_issues());
+        for name in [
+            "orphaned_authorized_app",
+            "incomplete_group_member",
+            "orphaned_peer_stats",
+            "duplicate_device_address",
+            "device_address_outside_location",
+            "invalid_device_name",
+            "duplicate_device_name",
+        ] {
+            let check = result(&report, name);
+            assert_eq!(check.count, 1, "{name}");
+            assert_eq!(check.sample.len(), 1);
+            assert!(check.repaired.is_none());
+        }
+        assert_eq!(
+            result(&report, "duplicate_device_address").sample,
+            [format!("{network_id}/10.1.1.2")]
+        );
+        assert_eq!(
+            result(&report, "device_address_outside_location").sample,
+            [format!("{network_id}/{}", devices[2])]
+        );
+        assert_eq!(
+            result(&report, "duplicate_device_name").sample,
+            [format!("{}/laptop", user.id.unwrap())]
+        );
+
+        // only safe cases are repaired
+        let report = run_consistency_checks(&pool, true, "test").await.unwrap();
+        for name in [
+            "orphaned_authorized_app",
+            "incomplete_group_member",
+            "orphaned_peer_stats",
+        ] {
+            assert_eq!(result(&report, name).repaired, Some(1), "{name}");
+        }
+        for name in [
+            "duplicate_device_address",
+            "device_address_outside_location",
+            "invalid_device_name",
+            "duplicate_device_name",
+        ] {
+            let check = result(&report, name);
+            assert!(!check.repairable);
+            assert!(check.repaired.is_none());
+        }
+        assert!(report.has_issues());
+
+        let report = run_consistency_checks(&pool, false, "test").await.unwrap();
+        for check in &report.checks {
+            assert_eq!(check.count, i64::from(!check.repairable), "{}", check.name);
+        }
+        // ambiguous cases are left alone
+        let count: (i64,) = query_as("SELECT count(*) FROM wireguard_network_device")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count.0, 3);
+        let count: (i64,) = query_as("SELECT count(*) FROM device")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count.0, 5);
+    }
+}
@@ -1,22 +1,48 @@
 pub mod models;
+pub mod read_pool;
 
-use sqlx::postgres::PgConnectOptions;
+use sqlx::{
+    migrate::Migrator,
+    postgres::{PgConnectOptions, PgPoolOptions},
+};
+
+use crate::config::DefGuardConfig;
 
 pub type DbPool = sqlx::postgres::PgPool;
 
+/// Database migrations of this version.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
 /// Initializes and migrates postgres database. Returns DB pool object.
 pub async fn init_db(host: &str, port: u16, name: &str, user: &str, password: &str) -> DbPool {
-    info!("Initializing DB pool");
     let opts = PgConnectOptions::new()
         .host(host)
         .port(port)
         .username(user)
         .password(password)
         .database(name);
-    let pool = DbPool::connect_with(opts)
+    connect_and_migrate(PgPoolOptions::new(), opts).await
+}
+
+/// Initializes and migrates postgres database using connection and pool options from config.
+/// Returns DB pool object.
+pub async fn init_db_from_config(config: &DefGuardConfig) -> DbPool {
+    let opts = config
+        .database_connect_options()
+        .expect("Invalid database configuration");
+    let pool_opts = PgPoolOptions::new()
+        .max_connections(config.database_max_connections)
+        .acquire_timeout(*config.database_acquire_timeout);
+    connect_and_migrate(pool_opts, opts).await
+}
+
+async fn connect_and_migrate(pool_opts: PgPoolOptions, opts: PgConnectOptions) -> DbPool {
+    info!("Initializing DB pool");
+    let pool = pool_opts
+        .connect_with(opts)
         .await
         .expect("Database connection failed");
-    sqlx::migrate!()
+    MIGRATOR
         .run(&pool)
         .await
         .expect("Cannot run database migrations.");
@@ -38,3 +64,4 @@ pub use models::{
     yubikey::YubiKey,
     MFAInfo, UserDetails, UserInfo,
 };
+pub use read_pool::ReadPool;
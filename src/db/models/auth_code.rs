@@ -16,6 +16,8 @@ pub struct AuthCode {
     pub auth_time: i64,
     pub nonce: Option<String>,
     pub code_challenge: Option<String>,
+    // defguard session in which the code was issued
+    pub session_id: Option<String>,
 }
 
 impl AuthCode {
@@ -27,6 +29,7 @@ impl AuthCode {
         scope: String,
         nonce: Option<String>,
         code_challenge: Option<String>,
+        session_id: Option<String>,
     ) -> Self {
         let code = gen_alphanumeric(24);
         Self {
@@ -39,6 +42,7 @@ impl AuthCode {
             auth_time: Utc::now().timestamp(),
             nonce,
             code_challenge,
+            session_id,
         }
     }
 
@@ -47,7 +51,7 @@ impl AuthCode {
         query_as!(
             Self,
             "SELECT id \"id?\", user_id, client_id, code, redirect_uri, scope, auth_time, nonce, \
-            code_challenge FROM authorization_code WHERE code = $1",
+            code_challenge, session_id FROM authorization_code WHERE code = $1",
             code
         )
         .fetch_optional(pool)
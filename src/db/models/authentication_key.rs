@@ -1,7 +1,8 @@
 use model_derive::Model;
 use sqlx::{query_as, Error as SqlxError, PgExecutor, Type};
+use utoipa::ToSchema;
 
-#[derive(Clone, Debug, Deserialize, Serialize, Type)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema, Type)]
 #[sqlx(type_name = "authentication_key_type", rename_all = "lowercase")]
 #[serde(rename_all = "lowercase")]
 pub(crate) enum AuthenticationKeyType {
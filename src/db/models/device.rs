@@ -1,43 +1,124 @@
 use std::{
+    cmp::Ordering,
+    collections::HashSet,
     fmt::{Display, Formatter},
     net::IpAddr,
 };
 
-use base64::{prelude::BASE64_STANDARD, Engine};
+use base64::{
+    alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    prelude::BASE64_STANDARD,
+    Engine,
+};
 use chrono::{NaiveDateTime, Utc};
 use ipnetwork::IpNetwork;
 use model_derive::Model;
-use sqlx::{query, query_as, Error as SqlxError, FromRow, PgConnection, PgExecutor};
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, query_scalar, Error as SqlxError, FromRow, PgConnection, PgExecutor};
 use thiserror::Error;
+use utoipa::ToSchema;
 
 use super::{
     error::ModelError,
-    wireguard::{WireguardNetwork, WIREGUARD_MAX_HANDSHAKE_MINUTES},
+    settings::{DeviceNamePolicy, Settings},
+    user::User,
+    wireguard::{PeerUpdate, WireguardNetwork, WIREGUARD_MAX_HANDSHAKE_MINUTES},
     DbPool,
 };
-use crate::KEY_LENGTH;
+use crate::{hex::to_lower_hex, wireguard_psk_rotation::key_fingerprint, KEY_LENGTH};
+
+// device private keys aren't stored, configs contain this placeholder instead
+pub const PRIVATE_KEY_PLACEHOLDER: &str = "YOUR_PRIVATE_KEY";
+
+// upper bound for the device name length setting
+const MAX_DEVICE_NAME_LENGTH: i32 = 255;
 
-#[derive(Serialize)]
+// keys are accepted with or without padding, and stored with it
+const PUBKEY_ENGINE: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+#[derive(Serialize, ToSchema)]
 pub struct DeviceConfig {
     pub(crate) network_id: i64,
     pub(crate) network_name: String,
     pub(crate) config: String,
+    #[schema(value_type = String)]
     pub(crate) address: IpAddr,
     pub(crate) endpoint: String,
+    #[schema(value_type = Vec<String>)]
     pub(crate) allowed_ips: Vec<IpNetwork>,
+    #[schema(value_type = Vec<String>)]
+    pub(crate) client_routes: Vec<IpNetwork>,
     pub(crate) pubkey: String,
     pub(crate) dns: Option<String>,
     pub(crate) mfa_enabled: bool,
     pub(crate) keepalive_interval: i32,
+    pub(crate) mtu: Option<i32>,
+}
+
+/// Peer parameters of a device in a network, as currently rendered for the client and the gateway.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct EffectiveDeviceConfig {
+    pub device_id: i64,
+    pub network_id: i64,
+    // device owner is active and belongs to one of network's allowed groups
+    pub allowed: bool,
+    // device is currently configured as a peer on network gateways
+    pub gateway_peer: bool,
+    pub network_settings: EffectivePeerConfig,
+}
+
+/// Parameters derived from network settings.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct EffectivePeerConfig {
+    // `None` if device has no address assigned in the network
+    #[schema(value_type = Option<String>)]
+    pub address: Option<IpAddr>,
+    // client `AllowedIPs`, i.e. destinations routed through the tunnel
+    #[schema(value_type = Vec<String>)]
+    pub allowed_ips: Vec<IpNetwork>,
+    pub endpoint: String,
+    pub dns: Option<String>,
+    pub client_keepalive: i32,
+    pub mtu: Option<i32>,
+    // `AllowedIPs` of the device peer on gateways
+    #[schema(value_type = Vec<String>)]
+    pub gateway_allowed_ips: Vec<IpNetwork>,
+    pub gateway_keepalive: i32,
+    pub preshared_key: bool,
+    // identifies the active key without revealing it
+    pub preshared_key_fingerprint: Option<String>,
+    pub preshared_key_pinned: bool,
+    pub pending_preshared_key: bool,
+    pub mfa_required: bool,
+    pub mfa_authorized: bool,
 }
 
-#[derive(Clone, Deserialize, Model, Serialize, Debug)]
+#[derive(Clone, Deserialize, Model, Serialize, Debug, ToSchema)]
 pub struct Device {
     pub id: Option<i64>,
     pub name: String,
     pub wireguard_pubkey: String,
     pub user_id: i64,
     pub created: NaiveDateTime,
+    // platform last reported by the client, unknown for devices added manually
+    #[serde(default)]
+    pub os: Option<String>,
+    #[serde(default)]
+    pub os_version: Option<String>,
+    #[serde(default)]
+    pub client_version: Option<String>,
+    // machine identity hints reported on enrollment, used to spot re-enrolled machines
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub machine_hash: Option<String>,
+    // blocked devices aren't configured on gateways, e.g. while investigating a compromise
+    #[serde(default)]
+    pub blocked: bool,
 }
 
 impl Display for Device {
@@ -88,18 +169,29 @@ impl DeviceInfo {
             network_info,
         })
     }
+
+    /// Split device info into separate updates for each network the device belongs to
+    pub fn into_peer_updates(self) -> impl Iterator<Item = PeerUpdate> {
+        let device = self.device;
+        self.network_info
+            .into_iter()
+            .map(move |network_info| PeerUpdate {
+                device: device.clone(),
+                network_info,
+            })
+    }
 }
 
 // helper struct which includes full device info
 // including network activity metadata
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct UserDevice {
     #[serde(flatten)]
     pub device: Device,
     pub networks: Vec<UserDeviceNetworkInfo>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct UserDeviceNetworkInfo {
     pub network_id: i64,
     pub network_name: String,
@@ -170,23 +262,157 @@ pub struct WireguardNetworkDevice {
     pub wireguard_network_id: i64,
     pub wireguard_ip: IpAddr,
     pub device_id: i64,
+    #[serde(skip_serializing)]
     pub preshared_key: Option<String>,
     pub is_authorized: bool,
     pub authorized_at: Option<NaiveDateTime>,
+    // when `preshared_key` was last rotated
+    pub preshared_key_rotated: Option<NaiveDateTime>,
+    // `preshared_key` was provided by the operator and is never rotated
+    #[serde(default)]
+    pub preshared_key_pinned: bool,
+    // new preshared key waiting for the device to switch to it, see `wireguard_psk_rotation`
+    #[serde(skip_serializing)]
+    pub pending_preshared_key: Option<String>,
+    pub pending_preshared_key_created: Option<NaiveDateTime>,
+    // bandwidth limits in kbit/s overriding location defaults
+    pub upload_limit_kbps: Option<i32>,
+    pub download_limit_kbps: Option<i32>,
+    // gateway which reported the latest handshake of the peer, and that handshake
+    pub gateway_hostname: Option<String>,
+    pub gateway_handshake: Option<NaiveDateTime>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct AddDevice {
     pub name: String,
+    // empty when the server generates keys
+    #[serde(default)]
     pub wireguard_pubkey: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct ModifyDevice {
     pub name: String,
     pub wireguard_pubkey: String,
 }
 
+// platform values reported by clients are truncated to this many characters
+pub(crate) const MAX_PLATFORM_FIELD_LENGTH: usize = 64;
+
+/// Operating system and client application version reported by a device.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DevicePlatform {
+    pub os: Option<String>,
+    pub os_version: Option<String>,
+    pub client_version: Option<String>,
+}
+
+impl DevicePlatform {
+    /// Normalize values reported by a client: OS name is lowercase, blank values are dropped.
+    #[must_use]
+    pub fn new(
+        os: Option<String>,
+        os_version: Option<String>,
+        client_version: Option<String>,
+    ) -> Self {
+        let clean = |value: Option<String>| {
+            value
+                .map(|value| {
+                    value
+                        .trim()
+                        .chars()
+                        .take(MAX_PLATFORM_FIELD_LENGTH)
+                        .collect::<String>()
+                })
+                .filter(|value| !value.is_empty())
+        };
+        Self {
+            os: clean(os).map(|os| os.to_lowercase()),
+            os_version: clean(os_version),
+            client_version: clean(client_version),
+        }
+    }
+
+    /// Devices enrolled through the web fallback don't run a client application.
+    #[must_use]
+    pub fn browser() -> Self {
+        Self {
+            os: Some("browser".into()),
+            os_version: None,
+            client_version: None,
+        }
+    }
+
+    /// Older clients don't report their platform at all.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.os.is_none() && self.os_version.is_none() && self.client_version.is_none()
+    }
+}
+
+/// Identity of the machine a device was enrolled on, reported by clients which support it.
+/// Machine ID is never stored: it's hashed together with the owner ID, so hashes are stable
+/// for the same user and can't be correlated between users.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MachineHints {
+    pub hostname: Option<String>,
+    pub machine_hash: Option<String>,
+}
+
+impl MachineHints {
+    #[must_use]
+    pub fn new(user_id: i64, machine_id: Option<String>, hostname: Option<String>) -> Self {
+        let machine_hash = machine_id
+            .map(|machine_id| machine_id.trim().to_lowercase())
+            .filter(|machine_id| !machine_id.is_empty())
+            .map(|machine_id| {
+                let digest = Sha256::digest(format!("defguard-machine:{user_id}:{machine_id}"));
+                to_lower_hex(&digest)
+            });
+        let hostname = hostname
+            .map(|hostname| {
+                hostname
+                    .trim()
+                    .chars()
+                    .take(MAX_PLATFORM_FIELD_LENGTH)
+                    .collect::<String>()
+            })
+            .filter(|hostname| !hostname.is_empty());
+        Self {
+            hostname,
+            machine_hash,
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.hostname.is_none() && self.machine_hash.is_none()
+    }
+}
+
+/// Compare dotted version numbers numerically, so that 0.9.2 < 0.10 and 1.0 == 1.0.0.
+/// Leading `v` and suffixes such as `-beta` are ignored.
+#[must_use]
+pub fn compare_versions(left: &str, right: &str) -> Ordering {
+    let parse = |version: &str| -> Vec<u64> {
+        version
+            .trim()
+            .trim_start_matches('v')
+            .split('.')
+            .map(|part| {
+                let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+                digits.parse().unwrap_or_default()
+            })
+            .collect()
+    };
+    let (mut left, mut right) = (parse(left), parse(right));
+    let len = left.len().max(right.len());
+    left.resize(len, 0);
+    right.resize(len, 0);
+    left.cmp(&right)
+}
+
 impl WireguardNetworkDevice {
     #[must_use]
     pub fn new(network_id: i64, device_id: i64, wireguard_ip: IpAddr) -> Self {
@@ -197,6 +423,14 @@ impl WireguardNetworkDevice {
             preshared_key: None,
             is_authorized: false,
             authorized_at: None,
+            preshared_key_rotated: None,
+            preshared_key_pinned: false,
+            pending_preshared_key: None,
+            pending_preshared_key_created: None,
+            upload_limit_kbps: None,
+            download_limit_kbps: None,
+            gateway_hostname: None,
+            gateway_handshake: None,
         }
     }
 
@@ -206,8 +440,9 @@ impl WireguardNetworkDevice {
     {
         query!(
             "INSERT INTO wireguard_network_device \
-                (device_id, wireguard_network_id, wireguard_ip, is_authorized, authorized_at, preshared_key) \
-                VALUES ($1, $2, $3, $4, $5, $6) \
+                (device_id, wireguard_network_id, wireguard_ip, is_authorized, authorized_at, preshared_key, \
+                preshared_key_pinned) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7) \
                 ON CONFLICT ON CONSTRAINT device_network \
                 DO UPDATE SET wireguard_ip = $3, is_authorized = $4",
             self.device_id,
@@ -215,7 +450,8 @@ impl WireguardNetworkDevice {
             IpNetwork::from(self.wireguard_ip.clone()),
             self.is_authorized,
             self.authorized_at,
-            self.preshared_key
+            self.preshared_key,
+            self.preshared_key_pinned
         )
         .execute(executor)
         .await?;
@@ -267,7 +503,9 @@ impl WireguardNetworkDevice {
     {
         let res = query_as!(
             Self,
-            "SELECT device_id, wireguard_network_id, wireguard_ip as \"wireguard_ip: IpAddr\", preshared_key, is_authorized, authorized_at \
+            "SELECT device_id, wireguard_network_id, wireguard_ip as \"wireguard_ip: IpAddr\", preshared_key, is_authorized, authorized_at, \
+            preshared_key_rotated, preshared_key_pinned, pending_preshared_key, pending_preshared_key_created, \
+            upload_limit_kbps, download_limit_kbps, gateway_hostname, gateway_handshake \
             FROM wireguard_network_device \
             WHERE device_id = $1 AND wireguard_network_id = $2",
             device_id,
@@ -284,7 +522,9 @@ impl WireguardNetworkDevice {
     ) -> Result<Option<Vec<Self>>, SqlxError> {
         let result = query_as!(
             Self,
-            "SELECT device_id, wireguard_network_id, wireguard_ip as \"wireguard_ip: IpAddr\", preshared_key, is_authorized, authorized_at \
+            "SELECT device_id, wireguard_network_id, wireguard_ip as \"wireguard_ip: IpAddr\", preshared_key, is_authorized, authorized_at, \
+            preshared_key_rotated, preshared_key_pinned, pending_preshared_key, pending_preshared_key_created, \
+            upload_limit_kbps, download_limit_kbps, gateway_hostname, gateway_handshake \
             FROM wireguard_network_device WHERE device_id = $1",
             device_id
         )
@@ -305,7 +545,9 @@ impl WireguardNetworkDevice {
     {
         let res = query_as!(
             Self,
-            "SELECT device_id, wireguard_network_id, wireguard_ip as \"wireguard_ip: IpAddr\", preshared_key, is_authorized, authorized_at \
+            "SELECT device_id, wireguard_network_id, wireguard_ip as \"wireguard_ip: IpAddr\", preshared_key, is_authorized, authorized_at, \
+            preshared_key_rotated, preshared_key_pinned, pending_preshared_key, pending_preshared_key_created, \
+            upload_limit_kbps, download_limit_kbps, gateway_hostname, gateway_handshake \
             FROM wireguard_network_device \
             WHERE wireguard_network_id = $1",
             network_id
@@ -314,6 +556,249 @@ impl WireguardNetworkDevice {
         .await?;
         Ok(res)
     }
+
+    /// Store bandwidth limits overriding location defaults.
+    pub async fn update_bandwidth_limits<'e, E>(&self, executor: E) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "UPDATE wireguard_network_device SET upload_limit_kbps = $3, download_limit_kbps = $4 \
+            WHERE device_id = $1 AND wireguard_network_id = $2",
+            self.device_id,
+            self.wireguard_network_id,
+            self.upload_limit_kbps,
+            self.download_limit_kbps,
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    /// Attribute the peer to the gateway which reported its handshake, unless another
+    /// gateway has already reported a more recent one. Returns the previous gateway and
+    /// handshake if the attribution was updated.
+    pub async fn record_gateway<'e, E>(
+        executor: E,
+        device_id: i64,
+        network_id: i64,
+        gateway_hostname: &str,
+        handshake: NaiveDateTime,
+    ) -> Result<Option<(Option<String>, Option<NaiveDateTime>)>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        // joined row holds values from before the update
+        let previous = query!(
+            "UPDATE wireguard_network_device wnd SET gateway_hostname = $3, gateway_handshake = $4 \
+            FROM wireguard_network_device prev \
+            WHERE wnd.device_id = $1 AND wnd.wireguard_network_id = $2 \
+            AND prev.device_id = wnd.device_id AND prev.wireguard_network_id = wnd.wireguard_network_id \
+            AND (wnd.gateway_handshake IS NULL OR wnd.gateway_handshake < $4) \
+            RETURNING prev.gateway_hostname, prev.gateway_handshake",
+            device_id,
+            network_id,
+            gateway_hostname,
+            handshake,
+        )
+        .fetch_optional(executor)
+        .await?;
+        Ok(previous.map(|row| (row.gateway_hostname, row.gateway_handshake)))
+    }
+
+    /// Stage a new preshared key, replacing a pending one if there is any.
+    /// Active key stays in use until the pending one is promoted.
+    pub async fn stage_preshared_key<'e, E>(
+        &mut self,
+        executor: E,
+        preshared_key: String,
+    ) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let created = Utc::now().naive_utc();
+        query!(
+            "UPDATE wireguard_network_device \
+            SET pending_preshared_key = $3, pending_preshared_key_created = $4 \
+            WHERE device_id = $1 AND wireguard_network_id = $2",
+            self.device_id,
+            self.wireguard_network_id,
+            preshared_key,
+            created,
+        )
+        .execute(executor)
+        .await?;
+        self.pending_preshared_key = Some(preshared_key);
+        self.pending_preshared_key_created = Some(created);
+        Ok(())
+    }
+
+    /// Replace active preshared key with the pending one.
+    /// Returns `false` if there is no pending key, or it has been changed in the meantime.
+    pub async fn promote_preshared_key<'e, E>(&mut self, executor: E) -> Result<bool, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let Some(preshared_key) = self.pending_preshared_key.clone() else {
+            return Ok(false);
+        };
+        let rotated = Utc::now().naive_utc();
+        let result = query!(
+            "UPDATE wireguard_network_device \
+            SET preshared_key = pending_preshared_key, preshared_key_rotated = $4, \
+            pending_preshared_key = NULL, pending_preshared_key_created = NULL \
+            WHERE device_id = $1 AND wireguard_network_id = $2 AND pending_preshared_key = $3",
+            self.device_id,
+            self.wireguard_network_id,
+            preshared_key,
+            rotated,
+        )
+        .execute(executor)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        self.preshared_key = Some(preshared_key);
+        self.preshared_key_rotated = Some(rotated);
+        self.pending_preshared_key = None;
+        self.pending_preshared_key_created = None;
+        Ok(true)
+    }
+
+    /// Replace preshared key with one provided by the operator and exclude it from rotation.
+    /// A pending key is discarded.
+    pub async fn pin_preshared_key<'e, E>(
+        &mut self,
+        executor: E,
+        preshared_key: String,
+    ) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let rotated = Utc::now().naive_utc();
+        query!(
+            "UPDATE wireguard_network_device \
+            SET preshared_key = $3, preshared_key_pinned = true, preshared_key_rotated = $4, \
+            pending_preshared_key = NULL, pending_preshared_key_created = NULL \
+            WHERE device_id = $1 AND wireguard_network_id = $2",
+            self.device_id,
+            self.wireguard_network_id,
+            preshared_key,
+            rotated,
+        )
+        .execute(executor)
+        .await?;
+        self.preshared_key = Some(preshared_key);
+        self.preshared_key_pinned = true;
+        self.preshared_key_rotated = Some(rotated);
+        self.pending_preshared_key = None;
+        self.pending_preshared_key_created = None;
+        Ok(())
+    }
+
+    /// Let rotation policy of the network replace a pinned preshared key again.
+    /// The key itself stays in use until rotated.
+    pub async fn unpin_preshared_key<'e, E>(&mut self, executor: E) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "UPDATE wireguard_network_device SET preshared_key_pinned = false \
+            WHERE device_id = $1 AND wireguard_network_id = $2",
+            self.device_id,
+            self.wireguard_network_id,
+        )
+        .execute(executor)
+        .await?;
+        self.preshared_key_pinned = false;
+        Ok(())
+    }
+
+    /// Devices in a network whose preshared key was not rotated since `rotated_before`
+    /// and have no key pending already. Devices of disabled users and pinned keys are skipped.
+    pub async fn due_for_psk_rotation<'e, E>(
+        executor: E,
+        network_id: i64,
+        rotated_before: NaiveDateTime,
+    ) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT wnd.device_id, wnd.wireguard_network_id, wnd.wireguard_ip as \"wireguard_ip: IpAddr\", \
+            wnd.preshared_key, wnd.is_authorized, wnd.authorized_at, wnd.preshared_key_rotated, \
+            wnd.preshared_key_pinned, wnd.pending_preshared_key, wnd.pending_preshared_key_created, \
+            wnd.upload_limit_kbps, wnd.download_limit_kbps, wnd.gateway_hostname, \
+            wnd.gateway_handshake \
+            FROM wireguard_network_device wnd \
+            JOIN device d ON d.id = wnd.device_id \
+            JOIN \"user\" u ON u.id = d.user_id \
+            WHERE wnd.wireguard_network_id = $1 AND u.is_active \
+            AND wnd.pending_preshared_key IS NULL AND NOT wnd.preshared_key_pinned \
+            AND (wnd.preshared_key_rotated IS NULL OR wnd.preshared_key_rotated < $2) \
+            ORDER BY wnd.device_id",
+            network_id,
+            rotated_before
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Pending preshared keys staged before `created_before`, in networks which are not archived.
+    pub async fn pending_psk_expired<'e, E>(
+        executor: E,
+        created_before: NaiveDateTime,
+    ) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT wnd.device_id, wnd.wireguard_network_id, wnd.wireguard_ip as \"wireguard_ip: IpAddr\", \
+            wnd.preshared_key, wnd.is_authorized, wnd.authorized_at, wnd.preshared_key_rotated, \
+            wnd.preshared_key_pinned, wnd.pending_preshared_key, wnd.pending_preshared_key_created, \
+            wnd.upload_limit_kbps, wnd.download_limit_kbps, wnd.gateway_hostname, \
+            wnd.gateway_handshake \
+            FROM wireguard_network_device wnd \
+            JOIN wireguard_network n ON n.id = wnd.wireguard_network_id \
+            WHERE NOT n.archived AND wnd.pending_preshared_key_created < $1 \
+            ORDER BY wnd.pending_preshared_key_created",
+            created_before
+        )
+        .fetch_all(executor)
+        .await
+    }
+}
+
+// characters allowed in device names by a device name policy
+fn is_device_name_char(char: char) -> bool {
+    char.is_ascii_alphanumeric() || matches!(char, '-' | '_' | ' ')
+}
+
+/// Device name with whitespace collapsed to single spaces, runs of other characters outside
+/// the safe set replaced with a hyphen, and cut to `max_length` characters,
+/// e.g. `Anna's  NAS 💾` becomes `Anna-s NAS`. `None` if nothing usable is left.
+#[must_use]
+pub fn sanitize_device_name(name: &str, max_length: usize) -> Option<String> {
+    let mut sanitized = String::with_capacity(name.len());
+    for char in name.chars() {
+        let char = if char.is_whitespace() {
+            ' '
+        } else if is_device_name_char(char) {
+            char
+        } else {
+            '-'
+        };
+        if matches!(char, ' ' | '-') && sanitized.ends_with(char) {
+            continue;
+        }
+        sanitized.push(char);
+    }
+    let sanitized = sanitized.trim_matches([' ', '-']);
+    let sanitized: String = sanitized.chars().take(max_length).collect();
+    let sanitized = sanitized.trim_end_matches([' ', '-']);
+    (!sanitized.is_empty()).then(|| sanitized.to_string())
 }
 
 #[derive(Error, Debug)]
@@ -326,6 +811,84 @@ pub enum DeviceError {
     ModelError(#[from] ModelError),
     #[error("Unexpected error: {0}")]
     Unexpected(String),
+    #[error("Device limit reached, user has {count} of {limit} devices")]
+    LimitExceeded { count: i64, limit: i32 },
+    #[error("Invalid device name: {0}")]
+    InvalidName(String),
+    #[error("User already has a device named {name}, try {suggestion}")]
+    NameExists { name: String, suggestion: String },
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PubkeyError {
+    #[error("Public key is empty")]
+    Empty,
+    #[error("Public key {0} is not valid base64")]
+    InvalidBase64(String),
+    #[error("Public key {0} is {1} bytes long, expected {KEY_LENGTH}")]
+    InvalidLength(String, usize),
+}
+
+/// WireGuard public key, validated and normalized to padded base64 without whitespace,
+/// which is the form gateways expect.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WireguardPubkey(String);
+
+impl WireguardPubkey {
+    pub fn parse(pubkey: &str) -> Result<Self, PubkeyError> {
+        let pubkey = pubkey.trim();
+        if pubkey.is_empty() {
+            return Err(PubkeyError::Empty);
+        }
+        let key = PUBKEY_ENGINE
+            .decode(pubkey)
+            .map_err(|_| PubkeyError::InvalidBase64(pubkey.into()))?;
+        if key.len() != KEY_LENGTH {
+            return Err(PubkeyError::InvalidLength(pubkey.into(), key.len()));
+        }
+        Ok(Self(BASE64_STANDARD.encode(key)))
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for WireguardPubkey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<WireguardPubkey> for String {
+    fn from(pubkey: WireguardPubkey) -> Self {
+        pubkey.0
+    }
+}
+
+/// Validate a preshared key provided by the operator and normalize it like public keys.
+/// Returns `None` if it isn't 32 bytes of base64; the key itself is never part of errors.
+#[must_use]
+pub fn parse_preshared_key(preshared_key: &str) -> Option<String> {
+    PUBKEY_ENGINE
+        .decode(preshared_key.trim())
+        .ok()
+        .filter(|key| key.len() == KEY_LENGTH)
+        .map(|key| BASE64_STANDARD.encode(key))
+}
+
+/// Device stored with a public key which gateways can't use, flagged by a migration.
+/// Operators have to fix these keys, as they're never normalized automatically.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct InvalidPubkeyDevice {
+    pub id: i64,
+    pub name: String,
+    pub user_id: i64,
+    pub username: String,
+    pub wireguard_pubkey: String,
+    // "invalid" or "duplicate"
+    pub issue: String,
 }
 
 impl Device {
@@ -337,6 +900,12 @@ impl Device {
             wireguard_pubkey,
             user_id,
             created: Utc::now().naive_utc(),
+            os: None,
+            os_version: None,
+            client_version: None,
+            hostname: None,
+            machine_hash: None,
+            blocked: false,
         }
     }
 
@@ -349,6 +918,258 @@ impl Device {
         self.name = other.name;
         self.wireguard_pubkey = other.wireguard_pubkey;
     }
+
+    #[must_use]
+    pub fn platform(&self) -> DevicePlatform {
+        DevicePlatform {
+            os: self.os.clone(),
+            os_version: self.os_version.clone(),
+            client_version: self.client_version.clone(),
+        }
+    }
+
+    pub fn set_platform(&mut self, platform: DevicePlatform) {
+        self.os = platform.os;
+        self.os_version = platform.os_version;
+        self.client_version = platform.client_version;
+    }
+
+    /// Store platform reported by the client, if it has changed.
+    /// Returns `true` if the device was updated. Platform which wasn't reported at all
+    /// doesn't overwrite the stored one.
+    pub async fn update_platform<'e, E>(
+        &mut self,
+        executor: E,
+        platform: DevicePlatform,
+    ) -> Result<bool, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        if platform.is_empty() || platform == self.platform() {
+            return Ok(false);
+        }
+        let id = self.id.ok_or(SqlxError::RowNotFound)?;
+        query!(
+            "UPDATE device SET os = $2, os_version = $3, client_version = $4 WHERE id = $1",
+            id,
+            platform.os,
+            platform.os_version,
+            platform.client_version
+        )
+        .execute(executor)
+        .await?;
+        self.set_platform(platform);
+        Ok(true)
+    }
+
+    /// Hints which weren't reported don't overwrite the stored ones.
+    pub fn set_machine_hints(&mut self, hints: MachineHints) {
+        if hints.hostname.is_some() {
+            self.hostname = hints.hostname;
+        }
+        if hints.machine_hash.is_some() {
+            self.machine_hash = hints.machine_hash;
+        }
+    }
+
+    /// Whether the device appears to run on the machine described by given hints.
+    /// Hostname is compared too, as it usually survives OS reinstalls while machine ID doesn't.
+    #[must_use]
+    pub fn same_machine(&self, hints: &MachineHints) -> bool {
+        let same = |stored: &Option<String>, reported: &Option<String>| {
+            stored
+                .as_ref()
+                .zip(reported.as_ref())
+                .is_some_and(|(stored, reported)| stored.eq_ignore_ascii_case(reported))
+        };
+        same(&self.machine_hash, &hints.machine_hash) || same(&self.hostname, &hints.hostname)
+    }
+
+    #[must_use]
+    pub fn machine_hints(&self) -> MachineHints {
+        MachineHints {
+            hostname: self.hostname.clone(),
+            machine_hash: self.machine_hash.clone(),
+        }
+    }
+
+    /// Most recently added device of given user which appears to run on the same machine.
+    pub async fn find_same_machine<'e, E>(
+        executor: E,
+        user_id: i64,
+        hints: &MachineHints,
+    ) -> Result<Option<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        if hints.is_empty() {
+            return Ok(None);
+        }
+        query_as!(
+            Self,
+            "SELECT id \"id?\", name, wireguard_pubkey, user_id, created, os, os_version, client_version, hostname, machine_hash, blocked \
+            FROM device WHERE user_id = $1 AND (machine_hash = $2 OR lower(hostname) = lower($3)) \
+            ORDER BY created DESC LIMIT 1",
+            user_id,
+            hints.machine_hash,
+            hints.hostname
+        )
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// Number of devices owned by the user and the limit applying to them:
+    /// personal override or global setting. No limit if neither is set.
+    pub async fn user_device_usage<'e, E>(
+        executor: E,
+        user_id: i64,
+    ) -> Result<(i64, Option<i32>), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let usage = query!(
+            "SELECT (SELECT count(*) FROM device WHERE user_id = $1) \"count!\", \
+            coalesce(max_devices, (SELECT max_devices_per_user FROM settings WHERE id = 1)) \"limit\" \
+            FROM \"user\" WHERE id = $1",
+            user_id
+        )
+        .fetch_one(executor)
+        .await?;
+        Ok((usage.count, usage.limit))
+    }
+
+    /// Number of devices the user can still add, `None` if unlimited.
+    pub async fn remaining_quota<'e, E>(executor: E, user_id: i64) -> Result<Option<i64>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let (count, limit) = Self::user_device_usage(executor, user_id).await?;
+        Ok(limit.map(|limit| (i64::from(limit) - count).max(0)))
+    }
+
+    /// Make sure the user can add another device.
+    ///
+    /// Locks the user row, so concurrent requests can't exceed the limit; must be called
+    /// in the transaction in which the new device is saved.
+    pub async fn check_limit(
+        transaction: &mut PgConnection,
+        user_id: i64,
+    ) -> Result<(), DeviceError> {
+        query!("SELECT id FROM \"user\" WHERE id = $1 FOR UPDATE", user_id)
+            .fetch_one(&mut *transaction)
+            .await?;
+        // separate statement, so devices added while waiting for the lock are counted
+        let (count, limit) = Self::user_device_usage(&mut *transaction, user_id).await?;
+        match limit {
+            Some(limit) if count >= i64::from(limit) => {
+                Err(DeviceError::LimitExceeded { count, limit })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Name to save a new or renamed device with, following the device name policy: trimmed,
+    /// validated or sanitized, and not used by another device of the user (ignoring case).
+    /// Without a policy names are taken as they are.
+    ///
+    /// Locks the user row, so concurrent requests can't add devices with the same name; must be
+    /// called in the transaction in which the device is saved.
+    pub async fn check_name(
+        transaction: &mut PgConnection,
+        user_id: i64,
+        name: &str,
+        device_id: Option<i64>,
+    ) -> Result<String, DeviceError> {
+        let settings = Settings::get_settings(&mut *transaction).await?;
+        let max_length = usize::try_from(settings.device_name_max_length).unwrap_or_default();
+        let name = match settings.device_name_policy {
+            DeviceNamePolicy::None => return Ok(name.into()),
+            DeviceNamePolicy::Reject => {
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err(DeviceError::InvalidName("name can't be empty".into()));
+                }
+                if let Some(char) = name.chars().find(|char| !is_device_name_char(*char)) {
+                    return Err(DeviceError::InvalidName(format!(
+                        "character {char:?} isn't allowed, use letters, digits, hyphens, \
+                        underscores and spaces"
+                    )));
+                }
+                if name.chars().count() > max_length {
+                    return Err(DeviceError::InvalidName(format!(
+                        "name can't be longer than {max_length} characters"
+                    )));
+                }
+                name.to_string()
+            }
+            DeviceNamePolicy::Sanitize => {
+                sanitize_device_name(name, max_length).ok_or_else(|| {
+                    DeviceError::InvalidName(format!("{name:?} has no allowed characters"))
+                })?
+            }
+        };
+
+        query!("SELECT id FROM \"user\" WHERE id = $1 FOR UPDATE", user_id)
+            .fetch_one(&mut *transaction)
+            .await?;
+        let taken: HashSet<String> = query_scalar!(
+            "SELECT name FROM device WHERE user_id = $1 AND id IS DISTINCT FROM $2",
+            user_id,
+            device_id
+        )
+        .fetch_all(&mut *transaction)
+        .await?
+        .into_iter()
+        .map(|name| name.to_lowercase())
+        .collect();
+        if !taken.contains(&name.to_lowercase()) {
+            return Ok(name);
+        }
+        // first free numbered variant, cut to fit the length limit
+        let mut number = 2;
+        let suggestion = loop {
+            let suffix = format!("-{number}");
+            let base: String = name
+                .chars()
+                .take(max_length.saturating_sub(suffix.len()))
+                .collect();
+            let candidate = format!("{}{suffix}", base.trim_end());
+            if !taken.contains(&candidate.to_lowercase()) {
+                break candidate;
+            }
+            number += 1;
+        };
+        Err(DeviceError::NameExists { name, suggestion })
+    }
+
+    /// Name of config files of the device in the network, safe to use in file systems and
+    /// stable for names saved before a device name policy was set.
+    #[must_use]
+    pub fn config_file_name(&self, network: &WireguardNetwork) -> String {
+        let file_part = |name: &str| {
+            sanitize_device_name(name, MAX_PLATFORM_FIELD_LENGTH).map(|name| name.replace(' ', "_"))
+        };
+        let device = file_part(&self.name)
+            .unwrap_or_else(|| format!("device-{}", self.id.unwrap_or_default()));
+        match file_part(&network.name) {
+            Some(network) => format!("{device}_{network}.conf"),
+            None => format!("{device}.conf"),
+        }
+    }
+
+    /// Check if device limit and device name settings are valid.
+    pub fn validate_settings(settings: &Settings) -> Result<(), String> {
+        if settings.max_devices_per_user.is_some_and(|limit| limit < 0) {
+            return Err("Device limit can't be negative".into());
+        }
+        if !(1..=MAX_DEVICE_NAME_LENGTH).contains(&settings.device_name_max_length) {
+            return Err(format!(
+                "Device name length limit must be between 1 and {MAX_DEVICE_NAME_LENGTH}"
+            ));
+        }
+        Ok(())
+    }
+
     /// Create wireguard config for device
     #[must_use]
     pub fn create_config(
@@ -366,14 +1187,18 @@ impl Device {
             }
             None => String::new(),
         };
+        let mtu = network
+            .mtu
+            .map_or(String::new(), |mtu| format!("MTU = {mtu}\n"));
 
-        let allowed_ips = if network.allowed_ips.is_empty() {
+        // routes are only used by clients, gateways use device address as peer `AllowedIPs`
+        let client_allowed_ips = network.client_allowed_ips();
+        let allowed_ips = if client_allowed_ips.is_empty() {
             String::new()
         } else {
             format!(
                 "AllowedIPs = {}\n",
-                network
-                    .allowed_ips
+                client_allowed_ips
                     .iter()
                     .map(IpNetwork::to_string)
                     .collect::<Vec<String>>()
@@ -381,21 +1206,106 @@ impl Device {
             )
         };
 
+        // MFA-protected locations get a new key on every login instead;
+        // a pending key isn't used by gateways until it's promoted, so it's left out
+        let preshared_key = if network.mfa_enabled {
+            None
+        } else {
+            wireguard_network_device.preshared_key.as_ref()
+        };
+        let preshared_key =
+            preshared_key.map_or(String::new(), |key| format!("PresharedKey = {key}\n"));
+
         format!(
             "[Interface]\n\
-            PrivateKey = YOUR_PRIVATE_KEY\n\
+            PrivateKey = {PRIVATE_KEY_PLACEHOLDER}\n\
             Address = {}\n\
             {dns}\n\
+            {mtu}\
             \n\
             [Peer]\n\
             PublicKey = {}\n\
+            {preshared_key}\
             {allowed_ips}\
-            Endpoint = {}:{}\n\
-            PersistentKeepalive = 300",
-            wireguard_network_device.wireguard_ip, network.pubkey, network.endpoint, network.port,
+            Endpoint = {}\n\
+            PersistentKeepalive = {}",
+            wireguard_network_device.wireguard_ip,
+            network.pubkey,
+            network.endpoint_with_port(),
+            network.keepalive_interval,
         )
     }
 
+    /// Compute peer parameters the client and gateways use for this device in given network.
+    pub async fn effective_config(
+        &self,
+        transaction: &mut PgConnection,
+        network: &WireguardNetwork,
+    ) -> Result<EffectiveDeviceConfig, ModelError> {
+        let device_id = self.get_id()?;
+        let network_id = network.id.ok_or(ModelError::IdNotSet)?;
+        let network_device =
+            WireguardNetworkDevice::find(&mut *transaction, device_id, network_id).await?;
+
+        let active_user = match User::find_by_id(&mut *transaction, self.user_id).await? {
+            Some(user) if user.is_active => Some(user),
+            _ => None,
+        };
+        let allowed = match active_user {
+            Some(ref user) => match network.get_allowed_groups(&mut *transaction).await? {
+                Some(allowed_groups) => {
+                    let groups = user.effective_member_of_names(&mut *transaction).await?;
+                    allowed_groups.iter().any(|group| groups.contains(group))
+                }
+                None => true,
+            },
+            None => false,
+        };
+        // same conditions as in `WireguardNetwork::get_peers()`
+        let gateway_peer = active_user.is_some()
+            && !self.blocked
+            && network_device
+                .as_ref()
+                .is_some_and(|wnd| wnd.is_authorized || !network.mfa_enabled);
+
+        let network_settings = EffectivePeerConfig {
+            address: network_device.as_ref().map(|wnd| wnd.wireguard_ip),
+            allowed_ips: network.client_allowed_ips(),
+            endpoint: network.endpoint_with_port(),
+            dns: network.dns.clone().filter(|dns| !dns.is_empty()),
+            client_keepalive: network.keepalive_interval,
+            mtu: network.mtu,
+            gateway_allowed_ips: network_device
+                .as_ref()
+                .map(|wnd| vec![IpNetwork::from(wnd.wireguard_ip)])
+                .unwrap_or_default(),
+            gateway_keepalive: network.keepalive_interval,
+            preshared_key: network_device
+                .as_ref()
+                .is_some_and(|wnd| wnd.preshared_key.is_some()),
+            preshared_key_fingerprint: network_device
+                .as_ref()
+                .and_then(|wnd| wnd.preshared_key.as_deref())
+                .map(key_fingerprint),
+            preshared_key_pinned: network_device
+                .as_ref()
+                .is_some_and(|wnd| wnd.preshared_key_pinned),
+            pending_preshared_key: network_device
+                .as_ref()
+                .is_some_and(|wnd| wnd.pending_preshared_key.is_some()),
+            mfa_required: network.mfa_enabled,
+            mfa_authorized: network_device.as_ref().is_some_and(|wnd| wnd.is_authorized),
+        };
+
+        Ok(EffectiveDeviceConfig {
+            device_id,
+            network_id,
+            allowed,
+            gateway_peer,
+            network_settings,
+        })
+    }
+
     pub async fn find_by_ip<'e, E>(
         executor: E,
         ip: IpAddr,
@@ -406,7 +1316,7 @@ impl Device {
     {
         query_as!(
             Self,
-            "SELECT d.id \"id?\", d.name, d.wireguard_pubkey, d.user_id, d.created \
+            "SELECT d.id \"id?\", d.name, d.wireguard_pubkey, d.user_id, d.created, d.os, d.os_version, d.client_version, d.hostname, d.machine_hash, d.blocked \
             FROM device d \
             JOIN wireguard_network_device wnd \
             ON d.id = wnd.device_id \
@@ -424,7 +1334,7 @@ impl Device {
     {
         query_as!(
             Self,
-            "SELECT id \"id?\", name, wireguard_pubkey, user_id, created \
+            "SELECT id \"id?\", name, wireguard_pubkey, user_id, created, os, os_version, client_version, hostname, machine_hash, blocked \
             FROM device WHERE wireguard_pubkey = $1",
             pubkey
         )
@@ -439,7 +1349,7 @@ impl Device {
     ) -> Result<Option<Self>, SqlxError> {
         query_as!(
             Self,
-            "SELECT device.id \"id?\", name, wireguard_pubkey, user_id, created \
+            "SELECT device.id \"id?\", name, wireguard_pubkey, user_id, created, os, os_version, client_version, hostname, machine_hash, blocked \
             FROM device JOIN \"user\" ON device.user_id = \"user\".id \
             WHERE device.id = $1 AND \"user\".username = $2",
             id,
@@ -456,7 +1366,7 @@ impl Device {
     ) -> Result<Option<Self>, SqlxError> {
         query_as!(
             Self,
-            "SELECT device.id \"id?\", name, wireguard_pubkey, user_id, created \
+            "SELECT device.id \"id?\", name, wireguard_pubkey, user_id, created, os, os_version, client_version, hostname, machine_hash, blocked \
             FROM device JOIN \"user\" ON device.user_id = \"user\".id \
             WHERE device.id = $1 AND \"user\".id = $2",
             id,
@@ -490,7 +1400,7 @@ impl Device {
     pub async fn all_for_username(pool: &DbPool, username: &str) -> Result<Vec<Self>, SqlxError> {
         query_as!(
             Self,
-            "SELECT device.id \"id?\", name, wireguard_pubkey, user_id, created \
+            "SELECT device.id \"id?\", name, wireguard_pubkey, user_id, created, os, os_version, client_version, hostname, machine_hash, blocked \
             FROM device JOIN \"user\" ON device.user_id = \"user\".id \
             WHERE \"user\".username = $1",
             username
@@ -499,13 +1409,13 @@ impl Device {
         .await
     }
 
-    // Add device to all existing networks
+    // Add device to all existing, not archived networks
     pub async fn add_to_all_networks(
         &self,
         transaction: &mut PgConnection,
     ) -> Result<(Vec<DeviceNetworkInfo>, Vec<DeviceConfig>), DeviceError> {
         info!("Adding device {} to all existing networks", self.name);
-        let networks = WireguardNetwork::all(&mut *transaction).await?;
+        let networks = WireguardNetwork::all_active(&mut *transaction).await?;
 
         let mut configs = Vec::new();
         let mut network_info = Vec::new();
@@ -554,15 +1464,17 @@ impl Device {
                 let config = self.create_config(&network, &wireguard_network_device);
                 configs.push(DeviceConfig {
                     network_id,
+                    endpoint: network.endpoint_with_port(),
                     network_name: network.name,
                     config,
-                    endpoint: format!("{}:{}", network.endpoint, network.port),
                     address: wireguard_network_device.wireguard_ip,
                     allowed_ips: network.allowed_ips,
+                    client_routes: network.client_routes,
                     pubkey: network.pubkey,
                     dns: network.dns,
                     mfa_enabled: network.mfa_enabled,
                     keepalive_interval: network.keepalive_interval,
+                    mtu: network.mtu,
                 });
             }
         }
@@ -607,21 +1519,73 @@ impl Device {
         Err(ModelError::CannotCreate)
     }
 
-    pub fn validate_pubkey(pubkey: &str) -> Result<(), String> {
-        if let Ok(key) = BASE64_STANDARD.decode(pubkey) {
-            if key.len() == KEY_LENGTH {
-                return Ok(());
-            }
+    /// Find a device, other than `except_id`, using given public key.
+    /// Two devices with the same key break routing on gateways.
+    pub async fn find_duplicate_pubkey<'e, E>(
+        executor: E,
+        pubkey: &WireguardPubkey,
+        except_id: Option<i64>,
+    ) -> Result<Option<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id \"id?\", name, wireguard_pubkey, user_id, created, os, os_version, client_version, hostname, machine_hash, blocked \
+            FROM device WHERE wireguard_pubkey = $1 AND id IS DISTINCT FROM $2",
+            pubkey.as_str(),
+            except_id
+        )
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// Block or unblock the device. Gateways have to be notified by the caller.
+    pub async fn set_blocked<'e, E>(&mut self, executor: E, blocked: bool) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let id = self.id.ok_or(SqlxError::RowNotFound)?;
+        query!("UPDATE device SET blocked = $2 WHERE id = $1", id, blocked)
+            .execute(executor)
+            .await?;
+        self.blocked = blocked;
+        Ok(())
+    }
+
+    /// Mark device public key as fixed, removing it from the invalid key report.
+    pub async fn clear_pubkey_issue<'e, E>(&self, executor: E) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        if let Some(id) = self.id {
+            query!("UPDATE device SET pubkey_issue = NULL WHERE id = $1", id)
+                .execute(executor)
+                .await?;
         }
+        Ok(())
+    }
 
-        Err(format!("{pubkey} is not a valid pubkey"))
+    /// Devices flagged as having invalid or duplicate public keys.
+    pub async fn invalid_pubkeys<'e, E>(executor: E) -> Result<Vec<InvalidPubkeyDevice>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            InvalidPubkeyDevice,
+            "SELECT d.id, d.name, d.user_id, u.username, d.wireguard_pubkey, \
+            d.pubkey_issue \"issue!\" \
+            FROM device d JOIN \"user\" u ON d.user_id = u.id \
+            WHERE d.pubkey_issue IS NOT NULL ORDER BY d.id"
+        )
+        .fetch_all(executor)
+        .await
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::db::User;
     use claims::{assert_err, assert_ok};
 
     impl Device {
@@ -696,9 +1660,72 @@ mod test {
     #[test]
     fn test_pubkey_validation() {
         let invalid_test_key = "invalid_key";
-        assert_err!(Device::validate_pubkey(invalid_test_key));
+        assert_err!(WireguardPubkey::parse(invalid_test_key));
 
         let valid_test_key = "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=";
-        assert_ok!(Device::validate_pubkey(valid_test_key));
+        assert_ok!(WireguardPubkey::parse(valid_test_key));
+
+        assert_eq!(WireguardPubkey::parse(" \t"), Err(PubkeyError::Empty));
+        assert_eq!(
+            WireguardPubkey::parse("c2hvcnQ="),
+            Err(PubkeyError::InvalidLength("c2hvcnQ=".into(), 5))
+        );
+        // surrounding whitespace and missing padding are normalized
+        for key in [
+            " sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=\n",
+            "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc",
+        ] {
+            assert_eq!(
+                WireguardPubkey::parse(key).unwrap().as_str(),
+                valid_test_key
+            );
+        }
+    }
+
+    #[test]
+    fn test_sanitize_device_name() {
+        assert_eq!(
+            sanitize_device_name("  Anna's  NAS 💾\n", 64).as_deref(),
+            Some("Anna-s NAS")
+        );
+        assert_eq!(
+            sanitize_device_name("work_laptop-2", 64).as_deref(),
+            Some("work_laptop-2")
+        );
+        assert_eq!(sanitize_device_name("my laptop", 3).as_deref(), Some("my"));
+        assert_eq!(sanitize_device_name("💻\t📱", 64), None);
+
+        let network = WireguardNetwork {
+            name: "Main office".into(),
+            ..Default::default()
+        };
+        let mut device = Device::new("laptop\n💻".into(), "key".into(), 1);
+        device.id = Some(7);
+        assert_eq!(device.config_file_name(&network), "laptop_Main_office.conf");
+        device.name = "💻".into();
+        assert_eq!(
+            device.config_file_name(&network),
+            "device-7_Main_office.conf"
+        );
+    }
+
+    #[test]
+    fn test_device_platform() {
+        let platform = DevicePlatform::new(Some(" Windows ".into()), Some("".into()), None);
+        assert_eq!(platform.os.as_deref(), Some("windows"));
+        assert_eq!(platform.os_version, None);
+        assert!(!platform.is_empty());
+        assert!(DevicePlatform::new(Some(" ".into()), None, None).is_empty());
+        let long = "x".repeat(MAX_PLATFORM_FIELD_LENGTH + 10);
+        let platform = DevicePlatform::new(None, None, Some(long));
+        assert_eq!(
+            platform.client_version.unwrap().len(),
+            MAX_PLATFORM_FIELD_LENGTH
+        );
+
+        assert_eq!(compare_versions("0.9.2", "0.10"), Ordering::Less);
+        assert_eq!(compare_versions("1.0", "1.0.0"), Ordering::Equal);
+        assert_eq!(compare_versions("v1.2.0-beta", "1.1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0.0", "1.0.1"), Ordering::Less);
     }
 }
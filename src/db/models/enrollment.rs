@@ -6,8 +6,9 @@ use thiserror::Error;
 use tokio::sync::mpsc::UnboundedSender;
 use tonic::{Code, Status};
 
-use super::{settings::Settings, DbPool, User};
+use super::{device::DeviceError, settings::Settings, DbPool, User};
 use crate::{
+    auth::failed_token::FailedTokenError,
     mail::Mail,
     random::gen_alphanumeric,
     server_config,
@@ -51,12 +52,27 @@ pub enum TokenError {
     TemplateErrorInternal(#[from] tera::Error),
     #[error(transparent)]
     TemplateError(#[from] TemplateError),
+    #[error(transparent)]
+    DeviceError(#[from] DeviceError),
+    #[error(transparent)]
+    TooManyAttempts(#[from] FailedTokenError),
 }
 
 impl From<TokenError> for Status {
     fn from(err: TokenError) -> Self {
         error!("{err}");
         let (code, msg) = match err {
+            // tell the client how many devices the user has and may have
+            TokenError::DeviceError(DeviceError::LimitExceeded { .. }) => {
+                return Status::resource_exhausted(err.to_string());
+            }
+            // the message carries the reason or a suggested name
+            TokenError::DeviceError(DeviceError::InvalidName(_)) => {
+                return Status::invalid_argument(err.to_string());
+            }
+            TokenError::DeviceError(DeviceError::NameExists { .. }) => {
+                return Status::already_exists(err.to_string());
+            }
             TokenError::DbError(_)
             | TokenError::AdminNotFound
             | TokenError::UserNotFound
@@ -65,12 +81,14 @@ impl From<TokenError> for Status {
             | TokenError::WelcomeMsgNotConfigured
             | TokenError::WelcomeEmailNotConfigured
             | TokenError::TemplateError(_)
-            | TokenError::TemplateErrorInternal(_) => (Code::Internal, "unexpected error"),
+            | TokenError::TemplateErrorInternal(_)
+            | TokenError::DeviceError(_) => (Code::Internal, "unexpected error"),
             TokenError::NotFound
             | TokenError::TokenExpired
             | TokenError::SessionExpired
             | TokenError::TokenUsed => (Code::Unauthenticated, "invalid token"),
             TokenError::AlreadyActive => (Code::InvalidArgument, "already active"),
+            TokenError::TooManyAttempts(_) => (Code::ResourceExhausted, "too many attempts"),
         };
         Status::new(code, msg)
     }
@@ -184,6 +202,17 @@ impl Token {
         }
     }
 
+    /// Lock token row until the end of the transaction.
+    ///
+    /// Used to serialize enrollment steps which may be performed concurrently
+    /// with the same token, e.g. from the desktop client and the web browser.
+    pub async fn lock(&self, transaction: &mut PgConnection) -> Result<(), TokenError> {
+        query!("SELECT id FROM token WHERE id = $1 FOR UPDATE", self.id)
+            .fetch_one(transaction)
+            .await?;
+        Ok(())
+    }
+
     pub async fn find_by_id(pool: &DbPool, id: &str) -> Result<Self, TokenError> {
         match query_as!(
             Self,
@@ -199,6 +228,25 @@ impl Token {
         }
     }
 
+    /// Expire unused tokens starting with given prefix. Returns invalidated tokens.
+    pub async fn invalidate_by_prefix(
+        pool: &DbPool,
+        prefix: &str,
+    ) -> Result<Vec<Self>, TokenError> {
+        let now = Utc::now().naive_utc();
+        let tokens = query_as!(
+            Self,
+            "UPDATE token SET expires_at = $2 \
+            WHERE left(id, length($1)) = $1 AND used_at IS NULL AND expires_at > $2 \
+            RETURNING id, user_id, admin_id, email, created_at, expires_at, used_at, token_type",
+            prefix,
+            now
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(tokens)
+    }
+
     pub async fn fetch_all(pool: &DbPool) -> Result<Vec<Self>, TokenError> {
         let tokens = query_as!(
             Self,
@@ -280,6 +328,32 @@ impl Token {
         Ok(())
     }
 
+    /// Delete at most `limit` tokens which can no longer be used: never used ones
+    /// expired before `expired_before`, and used ones whose enrollment session started
+    /// before `session_started_before`. Returns number of deleted tokens.
+    pub async fn delete_stale<'e, E>(
+        executor: E,
+        expired_before: NaiveDateTime,
+        session_started_before: NaiveDateTime,
+        limit: i64,
+    ) -> Result<u64, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let result = query!(
+            "DELETE FROM token \
+            WHERE ((used_at IS NULL AND expires_at < $1) OR used_at < $2) \
+            AND id IN (SELECT id FROM token \
+                WHERE (used_at IS NULL AND expires_at < $1) OR used_at < $2 LIMIT $3)",
+            expired_before,
+            session_started_before,
+            limit
+        )
+        .execute(executor)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
     /// Prepare context for rendering welcome messages
     /// Available tags include:
     /// - first_name
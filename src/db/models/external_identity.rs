@@ -0,0 +1,49 @@
+use chrono::{NaiveDateTime, Utc};
+use model_derive::Model;
+use sqlx::{query_as, Error as SqlxError, PgExecutor};
+
+/// Identity of a user at an external OpenID provider, identified by provider and subject,
+/// linked to a local account. Identities move along with the account when it's merged.
+#[derive(Clone, Debug, Model)]
+#[table(external_identity)]
+pub struct ExternalIdentity {
+    pub id: Option<i64>,
+    pub provider: String,
+    pub subject: String,
+    pub email: String,
+    pub user_id: i64,
+    pub created: NaiveDateTime,
+}
+
+impl ExternalIdentity {
+    #[must_use]
+    pub fn new<S: Into<String>>(provider: S, subject: S, email: S, user_id: i64) -> Self {
+        Self {
+            id: None,
+            provider: provider.into(),
+            subject: subject.into(),
+            email: email.into(),
+            user_id,
+            created: Utc::now().naive_utc(),
+        }
+    }
+
+    pub async fn find_by_subject<'e, E>(
+        executor: E,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id \"id?\", provider, subject, email, user_id, created \
+            FROM external_identity WHERE provider = $1 AND subject = $2",
+            provider,
+            subject
+        )
+        .fetch_optional(executor)
+        .await
+    }
+}
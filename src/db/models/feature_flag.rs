@@ -0,0 +1,74 @@
+use chrono::NaiveDateTime;
+use sqlx::{query, query_as, Error as SqlxError, PgExecutor};
+use utoipa::ToSchema;
+
+/// Stored value of a feature flag, overriding its default for the whole instance
+/// or a single location if `network_id` is set.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct FeatureFlagOverride {
+    pub name: String,
+    pub network_id: Option<i64>,
+    pub enabled: bool,
+    pub updated_by: Option<i64>,
+    pub updated_at: NaiveDateTime,
+}
+
+impl FeatureFlagOverride {
+    pub async fn all<'e, E>(executor: E) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT name, network_id, enabled, updated_by, updated_at FROM feature_flag \
+            ORDER BY name, network_id NULLS FIRST"
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Store flag value, replacing previous override with the same scope.
+    pub async fn set<'e, E>(
+        executor: E,
+        name: &str,
+        network_id: Option<i64>,
+        enabled: bool,
+        updated_by: Option<i64>,
+    ) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "INSERT INTO feature_flag (name, network_id, enabled, updated_by) \
+            VALUES ($1, $2, $3, $4) \
+            ON CONFLICT (name, coalesce(network_id, 0)) \
+            DO UPDATE SET enabled = $3, updated_by = $4, updated_at = now()",
+            name,
+            network_id,
+            enabled,
+            updated_by
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    /// Remove override with given scope; returns `false` if there was none.
+    pub async fn delete<'e, E>(
+        executor: E,
+        name: &str,
+        network_id: Option<i64>,
+    ) -> Result<bool, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let result = query!(
+            "DELETE FROM feature_flag WHERE name = $1 AND network_id IS NOT DISTINCT FROM $2",
+            name,
+            network_id
+        )
+        .execute(executor)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
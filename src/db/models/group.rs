@@ -6,10 +6,13 @@ use crate::{
     server_config,
 };
 
+/// Group of users. Groups can be nested, members of a subgroup are effectively
+/// members of all its ancestors, e.g. for network access and roles.
 #[derive(Model, Debug)]
 pub struct Group {
     pub(crate) id: Option<i64>,
     pub name: String,
+    pub parent_id: Option<i64>,
 }
 
 impl Group {
@@ -18,6 +21,7 @@ impl Group {
         Self {
             id: None,
             name: name.into(),
+            parent_id: None,
         }
     }
 
@@ -27,13 +31,79 @@ impl Group {
     {
         query_as!(
             Self,
-            "SELECT id \"id?\", name FROM \"group\" WHERE name = $1",
+            "SELECT id \"id?\", name, parent_id FROM \"group\" WHERE name = $1",
             name
         )
         .fetch_optional(executor)
         .await
     }
 
+    /// Name of the parent group, if any.
+    pub async fn parent_name<'e, E>(&self, executor: E) -> Result<Option<String>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        if let Some(parent_id) = self.parent_id {
+            query_scalar!("SELECT name FROM \"group\" WHERE id = $1", parent_id)
+                .fetch_optional(executor)
+                .await
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Check if setting group with `parent_id` as parent would create a cycle,
+    /// i.e. the new parent is this group or one of its descendants.
+    pub async fn creates_cycle<'e, E>(&self, executor: E, parent_id: i64) -> Result<bool, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let Some(id) = self.id else {
+            // group which isn't saved yet has no descendants
+            return Ok(false);
+        };
+        // walk up from the new parent; UNION stops on already existing cycles
+        query_scalar!(
+            "WITH RECURSIVE ancestors AS ( \
+                SELECT id, parent_id FROM \"group\" WHERE id = $1 \
+                UNION \
+                SELECT g.id, g.parent_id FROM \"group\" g JOIN ancestors a ON g.id = a.parent_id \
+            ) \
+            SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = $2) \"cycle!\"",
+            parent_id,
+            id
+        )
+        .fetch_one(executor)
+        .await
+    }
+
+    /// Usernames of direct members and members of all subgroups.
+    pub async fn effective_member_usernames<'e, E>(
+        &self,
+        executor: E,
+    ) -> Result<Vec<String>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        if let Some(id) = self.id {
+            query_scalar!(
+                "WITH RECURSIVE subgroups AS ( \
+                    SELECT id FROM \"group\" WHERE id = $1 \
+                    UNION \
+                    SELECT g.id FROM \"group\" g JOIN subgroups s ON g.parent_id = s.id \
+                ) \
+                SELECT DISTINCT u.username FROM \"user\" u \
+                JOIN group_user gu ON u.id = gu.user_id \
+                WHERE gu.group_id IN (SELECT id FROM subgroups) ORDER BY u.username",
+                id
+            )
+            .fetch_all(executor)
+            .await
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
     pub async fn member_usernames<'e, E>(&self, executor: E) -> Result<Vec<String>, SqlxError>
     where
         E: PgExecutor<'e>,
@@ -51,6 +121,35 @@ impl Group {
         }
     }
 
+    /// Direct members and members of all subgroups.
+    pub async fn effective_members<'e, E>(&self, executor: E) -> Result<Vec<User>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        if let Some(id) = self.id {
+            query_as!(
+                User,
+                "WITH RECURSIVE subgroups AS ( \
+                    SELECT id FROM \"group\" WHERE id = $1 \
+                    UNION \
+                    SELECT g.id FROM \"group\" g JOIN subgroups s ON g.parent_id = s.id \
+                ) \
+                SELECT \"user\".id \"id?\", username, password_hash, last_name, first_name, email, \
+                phone, mfa_enabled, totp_enabled, totp_secret, email_mfa_enabled, email_mfa_secret, \
+                mfa_method \"mfa_method: _\", recovery_codes, is_active, break_glass, max_devices, merged_into, new_country_alert_opt_out \
+                FROM \"user\" \
+                WHERE id IN ( \
+                    SELECT user_id FROM group_user WHERE group_id IN (SELECT id FROM subgroups) \
+                )",
+                id
+            )
+            .fetch_all(executor)
+            .await
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
     pub async fn members<'e, E>(&self, executor: E) -> Result<Vec<User>, SqlxError>
     where
         E: PgExecutor<'e>,
@@ -60,7 +159,7 @@ impl Group {
                 User,
                 "SELECT \"user\".id \"id?\", username, password_hash, last_name, first_name, email, \
                 phone, mfa_enabled, totp_enabled, totp_secret, email_mfa_enabled, email_mfa_secret, \
-                mfa_method \"mfa_method: _\", recovery_codes, is_active \
+                mfa_method \"mfa_method: _\", recovery_codes, is_active, break_glass, max_devices, merged_into, new_country_alert_opt_out \
                 FROM \"user\" \
                 JOIN group_user ON \"user\".id = group_user.user_id \
                 WHERE group_user.group_id = $1",
@@ -250,6 +349,57 @@ mod test {
         assert!(fetched_group.is_none());
     }
 
+    #[sqlx::test]
+    async fn test_nested_groups(pool: DbPool) {
+        let mut engineering = Group::new("engineering");
+        engineering.save(&pool).await.unwrap();
+        let mut backend = Group::new("backend");
+        backend.parent_id = engineering.id;
+        backend.save(&pool).await.unwrap();
+        let mut platform = Group::new("platform");
+        platform.parent_id = backend.id;
+        platform.save(&pool).await.unwrap();
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        user.add_to_group(&pool, &platform).await.unwrap();
+
+        assert!(engineering
+            .member_usernames(&pool)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            engineering.effective_member_usernames(&pool).await.unwrap(),
+            ["hpotter"]
+        );
+        assert_eq!(engineering.effective_members(&pool).await.unwrap().len(), 1);
+        let mut groups = user.effective_member_of_names(&pool).await.unwrap();
+        groups.sort();
+        assert_eq!(groups, ["backend", "engineering", "platform"]);
+        assert_eq!(user.member_of_names(&pool).await.unwrap(), ["platform"]);
+
+        assert!(engineering
+            .creates_cycle(&pool, platform.id.unwrap())
+            .await
+            .unwrap());
+        assert!(engineering
+            .creates_cycle(&pool, engineering.id.unwrap())
+            .await
+            .unwrap());
+        assert!(!platform
+            .creates_cycle(&pool, engineering.id.unwrap())
+            .await
+            .unwrap());
+    }
+
     #[sqlx::test]
     async fn test_group_members(pool: DbPool) {
         let mut group = Group::new("worker");
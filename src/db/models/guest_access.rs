@@ -0,0 +1,163 @@
+use chrono::NaiveDateTime;
+use sqlx::{query, query_as, query_scalar, Error as SqlxError, PgExecutor};
+use utoipa::ToSchema;
+
+/// Time-limited access of a guest user to a single location.
+///
+/// The guest is a member of `group_name`, which gives access to the location. If the group
+/// was created along with the access, it's removed once the access ends.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct GuestAccess {
+    pub id: Option<i64>,
+    pub user_id: i64,
+    pub network_id: i64,
+    pub group_name: String,
+    pub group_created: bool,
+    pub sponsor_id: Option<i64>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub notified_at: Option<NaiveDateTime>,
+    pub ended_at: Option<NaiveDateTime>,
+}
+
+/// Guest access along with names of the guest, location and sponsor.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct GuestAccessDetails {
+    #[serde(flatten)]
+    pub access: GuestAccess,
+    pub username: String,
+    pub network_name: String,
+    pub sponsor: Option<String>,
+}
+
+impl GuestAccess {
+    pub async fn save<'e, E>(&mut self, executor: E) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let id = query_scalar!(
+            "INSERT INTO guest_access (user_id, network_id, group_name, group_created, \
+            sponsor_id, created_at, expires_at, notified_at, ended_at) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id",
+            self.user_id,
+            self.network_id,
+            self.group_name,
+            self.group_created,
+            self.sponsor_id,
+            self.created_at,
+            self.expires_at,
+            self.notified_at,
+            self.ended_at
+        )
+        .fetch_one(executor)
+        .await?;
+        self.id = Some(id);
+        Ok(())
+    }
+
+    pub async fn find_by_id<'e, E>(executor: E, id: i64) -> Result<Option<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id \"id?\", user_id, network_id, group_name, group_created, sponsor_id, \
+            created_at, expires_at, notified_at, ended_at FROM guest_access WHERE id = $1",
+            id
+        )
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// Accesses which haven't ended yet, soonest to expire first.
+    pub async fn all_active<'e, E>(executor: E) -> Result<Vec<GuestAccessDetails>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let rows = query!(
+            "SELECT ga.id, ga.user_id, ga.network_id, ga.group_name, ga.group_created, \
+            ga.sponsor_id, ga.created_at, ga.expires_at, ga.notified_at, ga.ended_at, \
+            u.username, n.name AS network_name, s.username AS \"sponsor?\" \
+            FROM guest_access ga JOIN \"user\" u ON u.id = ga.user_id \
+            JOIN wireguard_network n ON n.id = ga.network_id \
+            LEFT JOIN \"user\" s ON s.id = ga.sponsor_id \
+            WHERE ga.ended_at IS NULL ORDER BY ga.expires_at, ga.id"
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| GuestAccessDetails {
+                access: Self {
+                    id: Some(row.id),
+                    user_id: row.user_id,
+                    network_id: row.network_id,
+                    group_name: row.group_name,
+                    group_created: row.group_created,
+                    sponsor_id: row.sponsor_id,
+                    created_at: row.created_at,
+                    expires_at: row.expires_at,
+                    notified_at: row.notified_at,
+                    ended_at: row.ended_at,
+                },
+                username: row.username,
+                network_name: row.network_name,
+                sponsor: row.sponsor,
+            })
+            .collect())
+    }
+
+    /// Active accesses which expire at or before `at`.
+    pub async fn expiring<'e, E>(executor: E, at: NaiveDateTime) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id \"id?\", user_id, network_id, group_name, group_created, sponsor_id, \
+            created_at, expires_at, notified_at, ended_at FROM guest_access \
+            WHERE ended_at IS NULL AND expires_at <= $1 ORDER BY expires_at",
+            at
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    pub async fn mark_notified<'e, E>(
+        &mut self,
+        executor: E,
+        at: NaiveDateTime,
+    ) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "UPDATE guest_access SET notified_at = $2 WHERE id = $1",
+            self.id,
+            at
+        )
+        .execute(executor)
+        .await?;
+        self.notified_at = Some(at);
+        Ok(())
+    }
+
+    pub async fn mark_ended<'e, E>(
+        &mut self,
+        executor: E,
+        at: NaiveDateTime,
+    ) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "UPDATE guest_access SET ended_at = $2 WHERE id = $1",
+            self.id,
+            at
+        )
+        .execute(executor)
+        .await?;
+        self.ended_at = Some(at);
+        Ok(())
+    }
+}
@@ -5,27 +5,44 @@ pub mod device;
 pub mod device_login;
 pub mod enrollment;
 pub mod error;
+pub mod external_identity;
+pub mod feature_flag;
 pub mod group;
+pub mod guest_access;
+pub mod notification_recipient;
 #[cfg(feature = "openid")]
 pub mod oauth2authorizedapp;
 #[cfg(feature = "openid")]
 pub mod oauth2client;
 #[cfg(feature = "openid")]
+pub mod oauth2session;
+#[cfg(feature = "openid")]
 pub mod oauth2token;
+pub mod polling_token;
 pub mod session;
 pub mod settings;
+pub mod shared_config;
+pub mod ssh_certificate;
 pub mod user;
+pub mod user_field;
+pub mod user_suspension;
 pub mod wallet;
 pub mod webauthn;
 pub mod webhook;
 pub mod wireguard;
+#[cfg(feature = "worker")]
+pub mod worker_job;
 pub mod yubikey;
 
 use sqlx::{query_as, Error as SqlxError, PgConnection};
+use utoipa::ToSchema;
 
 use self::{
-    device::UserDevice,
+    device::{Device, UserDevice},
+    settings::Settings,
     user::{MFAMethod, User},
+    user_field::UserFieldValue,
+    user_suspension::UserSuspension,
 };
 use super::{DbPool, Group};
 
@@ -36,9 +53,14 @@ pub struct NewOpenIDClient {
     pub redirect_uri: Vec<String>,
     pub scope: Vec<String>,
     pub enabled: bool,
+    #[serde(default)]
+    pub backchannel_logout_uri: Option<String>,
+    // only members of these groups can use the client, everyone if empty
+    #[serde(default)]
+    pub allowed_groups: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
 pub struct WalletInfo {
     pub address: String,
     pub name: String,
@@ -46,7 +68,7 @@ pub struct WalletInfo {
     pub use_for_mfa: bool,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, ToSchema)]
 pub struct OAuth2AuthorizedAppInfo {
     pub oauth2client_id: i64,
     pub user_id: i64,
@@ -54,14 +76,14 @@ pub struct OAuth2AuthorizedAppInfo {
 }
 
 /// Only `id` and `name` from [`WebAuthn`].
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
 pub struct SecurityKey {
     pub id: i64,
     pub name: String,
 }
 
 // Basic user info used in user list, etc.
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, ToSchema)]
 pub struct UserInfo {
     pub id: Option<i64>,
     pub username: String,
@@ -77,12 +99,39 @@ pub struct UserInfo {
     pub authorized_apps: Vec<OAuth2AuthorizedAppInfo>,
     pub is_active: bool,
     pub enrolled: bool,
+    // overrides global device limit, can be changed by admins only
+    #[serde(default)]
+    pub max_devices: Option<i32>,
+    // number of devices the user can still add, `None` if unlimited; read-only
+    #[serde(default)]
+    pub device_quota: Option<i64>,
+    // don't email the user about VPN connections from new countries
+    #[serde(default)]
+    pub new_country_alert_opt_out: bool,
+    // set if the user was suspended with other group members; read-only
+    #[serde(default)]
+    pub suspension: Option<UserSuspension>,
 }
 
 impl UserInfo {
     pub async fn from_user(pool: &DbPool, user: &User) -> Result<Self, SqlxError> {
-        let groups = user.member_of_names(pool).await?;
-        let authorized_apps = user.oauth2authorizedapps(pool).await?;
+        Self::from_user_in(&mut *pool.acquire().await?, user).await
+    }
+
+    /// Same as [`UserInfo::from_user`], but reads in a transaction, e.g. the one saving the user.
+    pub async fn from_user_in(
+        transaction: &mut PgConnection,
+        user: &User,
+    ) -> Result<Self, SqlxError> {
+        let groups = user.member_of_names(&mut *transaction).await?;
+        let authorized_apps = user.oauth2authorizedapps(&mut *transaction).await?;
+        let (device_quota, suspension) = match user.id {
+            Some(id) => (
+                Device::remaining_quota(&mut *transaction, id).await?,
+                UserSuspension::find_by_user(&mut *transaction, id).await?,
+            ),
+            None => (None, None),
+        };
 
         Ok(Self {
             id: user.id,
@@ -99,6 +148,10 @@ impl UserInfo {
             authorized_apps,
             is_active: user.is_active,
             enrolled: user.has_password(),
+            max_devices: user.max_devices,
+            device_quota,
+            new_country_alert_opt_out: user.new_country_alert_opt_out,
+            suspension,
         })
     }
 
@@ -106,6 +159,7 @@ impl UserInfo {
     ///
     /// Return `true` if status was changed, `false` otherwise.
     /// If status was changed to inactive, all user sessions will be invalidated.
+    /// If status was changed to active, suspension of the user is lifted.
     pub(crate) async fn handle_status_change(
         &self,
         transaction: &mut PgConnection,
@@ -114,6 +168,9 @@ impl UserInfo {
         if self.is_active != user.is_active {
             if !self.is_active {
                 user.logout_all_sessions(&mut *transaction).await?;
+            } else if let Some(id) = user.id {
+                // enabling a suspended user by hand lifts the suspension
+                UserSuspension::delete_for_user(&mut *transaction, id).await?;
             }
             user.is_active = self.is_active;
             user.save(&mut *transaction).await?;
@@ -168,6 +225,7 @@ impl UserInfo {
     pub fn into_user_safe_fields(self, user: &mut User) -> Result<(), SqlxError> {
         user.phone = self.phone;
         user.mfa_method = self.mfa_method;
+        user.new_country_alert_opt_out = self.new_country_alert_opt_out;
         Ok(())
     }
 
@@ -178,13 +236,15 @@ impl UserInfo {
         user.last_name = self.last_name;
         user.first_name = self.first_name;
         user.email = self.email;
+        user.max_devices = self.max_devices;
+        user.new_country_alert_opt_out = self.new_country_alert_opt_out;
 
         Ok(())
     }
 }
 
 // Full user info with related objects
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
 pub struct UserDetails {
     pub user: UserInfo,
     #[serde(default)]
@@ -193,6 +253,11 @@ pub struct UserDetails {
     pub wallets: Vec<WalletInfo>,
     #[serde(default)]
     pub security_keys: Vec<SecurityKey>,
+    // only the number of unused codes is ever exposed, never the codes themselves
+    #[serde(default)]
+    pub recovery_codes_remaining: usize,
+    #[serde(default)]
+    pub custom_fields: Vec<UserFieldValue>,
 }
 
 impl UserDetails {
@@ -200,23 +265,30 @@ impl UserDetails {
         let devices = user.devices(pool).await?;
         let wallets = user.wallets(pool).await?;
         let security_keys = user.security_keys(pool).await?;
+        let custom_fields = match user.id {
+            Some(id) => UserFieldValue::all_for_user(pool, id).await?,
+            None => Vec::new(),
+        };
 
         Ok(Self {
             user: UserInfo::from_user(pool, user).await?,
             devices,
             wallets,
             security_keys,
+            recovery_codes_remaining: user.recovery_codes.len(),
+            custom_fields,
         })
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct MFAInfo {
     mfa_method: MFAMethod,
     totp_available: bool,
     web3_available: bool,
     webauthn_available: bool,
     email_available: bool,
+    recovery_codes_available: bool,
 }
 
 impl MFAInfo {
@@ -226,7 +298,8 @@ impl MFAInfo {
                 Self,
                 "SELECT mfa_method \"mfa_method: _\", totp_enabled totp_available, email_mfa_enabled email_available, \
                 (SELECT count(*) > 0 FROM wallet WHERE user_id = $1 AND wallet.use_for_mfa) \"web3_available!\", \
-                (SELECT count(*) > 0 FROM webauthn WHERE user_id = $1) \"webauthn_available!\" \
+                (SELECT count(*) > 0 FROM webauthn WHERE user_id = $1) \"webauthn_available!\", \
+                cardinality(recovery_codes) > 0 \"recovery_codes_available!\" \
                 FROM \"user\" WHERE \"user\".id = $1",
                 id
             ).fetch_optional(pool).await
@@ -243,6 +316,22 @@ impl MFAInfo {
             || self.email_available
     }
 
+    /// Hide methods which can't be used for logging in under instance settings.
+    /// If the current method is one of them, the first remaining one is used instead.
+    pub fn restrict_to(&mut self, settings: &Settings) {
+        self.totp_available &= settings.mfa_method_usable(&MFAMethod::OneTimePassword);
+        self.email_available &= settings.mfa_method_usable(&MFAMethod::Email);
+        self.webauthn_available &= settings.mfa_method_usable(&MFAMethod::Webauthn);
+        self.web3_available &= settings.mfa_method_usable(&MFAMethod::Web3);
+        self.recovery_codes_available &= settings.mfa_recovery_codes_allowed;
+        if !settings.mfa_method_usable(&self.mfa_method) {
+            self.mfa_method = self
+                .list_available_methods()
+                .and_then(|methods| methods.into_iter().next())
+                .unwrap_or(MFAMethod::None);
+        }
+    }
+
     #[must_use]
     pub fn current_mfa_method(&self) -> &MFAMethod {
         &self.mfa_method
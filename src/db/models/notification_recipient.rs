@@ -0,0 +1,79 @@
+use model_derive::Model;
+use sqlx::{query, Error as SqlxError, PgExecutor, Type};
+use utoipa::ToSchema;
+
+use crate::{notifications::NotificationCategory, secret::SecretString};
+
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Type, Debug, ToSchema)]
+#[sqlx(type_name = "notification_channel", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationChannel {
+    Email,
+    Webhook,
+}
+
+/// Destination for admin notifications configured in settings.
+#[derive(Clone, Debug, Deserialize, Model, Serialize, ToSchema)]
+#[table(notification_recipient)]
+pub struct NotificationRecipient {
+    #[serde(default)]
+    pub id: Option<i64>,
+    pub name: String,
+    #[model(enum)]
+    pub channel: NotificationChannel,
+    pub email: Option<String>,
+    pub webhook_url: Option<String>,
+    #[model(secret)]
+    #[schema(value_type = Option<String>)]
+    pub webhook_secret: Option<SecretString>,
+    // notification category names, see `NotificationCategory`
+    #[model(ref)]
+    pub categories: Vec<String>,
+}
+
+impl NotificationRecipient {
+    /// Remove all configured recipients.
+    pub async fn delete_all<'e, E>(executor: E) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!("DELETE FROM notification_recipient")
+            .execute(executor)
+            .await?;
+        Ok(())
+    }
+
+    /// Check if recipient is subscribed to given notification category.
+    #[must_use]
+    pub fn accepts(&self, category: NotificationCategory) -> bool {
+        self.categories.iter().any(|name| name == category.as_str())
+    }
+
+    /// Check if channel specific fields are set and valid.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("Recipient name can't be empty".into());
+        }
+        for name in &self.categories {
+            if name.parse::<NotificationCategory>().is_err() {
+                return Err(format!("Unknown notification category: {name}"));
+            }
+        }
+        match self.channel {
+            NotificationChannel::Email => match &self.email {
+                Some(email) if email.parse::<lettre::Address>().is_ok() => Ok(()),
+                _ => Err(format!(
+                    "Recipient {} requires a valid email address",
+                    self.name
+                )),
+            },
+            NotificationChannel::Webhook => match &self.webhook_url {
+                Some(url) if reqwest::Url::parse(url).is_ok() => Ok(()),
+                _ => Err(format!(
+                    "Recipient {} requires a valid webhook URL",
+                    self.name
+                )),
+            },
+        }
+    }
+}
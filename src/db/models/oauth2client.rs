@@ -1,7 +1,7 @@
-use super::{DbPool, NewOpenIDClient};
+use super::{DbPool, NewOpenIDClient, User};
 use crate::random::gen_alphanumeric;
 use model_derive::Model;
-use sqlx::{query_as, Error as SqlxError};
+use sqlx::{query, query_as, query_scalar, Error as SqlxError, PgConnection, PgExecutor};
 
 #[derive(Deserialize, Model, Serialize)]
 pub struct OAuth2Client {
@@ -15,6 +15,8 @@ pub struct OAuth2Client {
     // informational
     pub name: String,
     pub enabled: bool,
+    // OpenID Connect Back-Channel Logout endpoint of the client
+    pub backchannel_logout_uri: Option<String>,
 }
 
 impl OAuth2Client {
@@ -30,6 +32,7 @@ impl OAuth2Client {
             scope,
             name,
             enabled: true,
+            backchannel_logout_uri: None,
         }
     }
 
@@ -45,6 +48,7 @@ impl OAuth2Client {
             scope: new.scope,
             name: new.name,
             enabled: new.enabled,
+            backchannel_logout_uri: new.backchannel_logout_uri,
         }
     }
 
@@ -55,8 +59,8 @@ impl OAuth2Client {
     ) -> Result<Option<Self>, SqlxError> {
         query_as!(
             Self,
-            "SELECT id \"id?\", client_id, client_secret, redirect_uri, scope, name, enabled \
-            FROM oauth2client WHERE client_id = $1",
+            "SELECT id \"id?\", client_id, client_secret, redirect_uri, scope, name, enabled, \
+            backchannel_logout_uri FROM oauth2client WHERE client_id = $1",
             client_id
         )
         .fetch_optional(pool)
@@ -71,8 +75,8 @@ impl OAuth2Client {
     ) -> Result<Option<Self>, SqlxError> {
         query_as!(
             Self,
-            "SELECT id \"id?\", client_id, client_secret, redirect_uri, scope, name, enabled \
-            FROM oauth2client WHERE client_id = $1 AND client_secret = $2 AND enabled",
+            "SELECT id \"id?\", client_id, client_secret, redirect_uri, scope, name, enabled, \
+            backchannel_logout_uri FROM oauth2client WHERE client_id = $1 AND client_secret = $2 AND enabled",
             client_id,
             client_secret
         )
@@ -87,13 +91,93 @@ impl OAuth2Client {
     ) -> Result<Option<Self>, SqlxError> {
         query_as!(
             Self,
-            "SELECT id \"id?\", client_id, client_secret, redirect_uri, scope, name, enabled \
-            FROM oauth2client WHERE client_id = $1 AND enabled",
+            "SELECT id \"id?\", client_id, client_secret, redirect_uri, scope, name, enabled, \
+            backchannel_logout_uri FROM oauth2client WHERE client_id = $1 AND enabled",
             client_id
         )
         .fetch_optional(pool)
         .await
     }
+
+    /// Names of groups allowed to use the client. Empty if everyone is allowed.
+    pub async fn fetch_allowed_groups<'e, E>(&self, executor: E) -> Result<Vec<String>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_scalar!(
+            "SELECT name FROM oauth2client_allowed_group ag \
+            JOIN \"group\" g ON ag.group_id = g.id WHERE ag.oauth2client_id = $1 ORDER BY name",
+            self.id
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Replace allowed groups. Returns names of groups which don't exist, in which case
+    /// nothing is changed.
+    pub async fn set_allowed_groups(
+        &self,
+        transaction: &mut PgConnection,
+        allowed_groups: &[String],
+    ) -> Result<Vec<String>, SqlxError> {
+        let missing = query_scalar!(
+            "SELECT DISTINCT name \"name!\" FROM UNNEST($1::text[]) name \
+            WHERE name NOT IN (SELECT name FROM \"group\") ORDER BY 1",
+            allowed_groups
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+        if !missing.is_empty() {
+            return Ok(missing);
+        }
+        query!(
+            "DELETE FROM oauth2client_allowed_group WHERE oauth2client_id = $1",
+            self.id
+        )
+        .execute(&mut *transaction)
+        .await?;
+        query!(
+            "INSERT INTO oauth2client_allowed_group (oauth2client_id, group_id) \
+            SELECT $1, id FROM \"group\" WHERE name = ANY($2)",
+            self.id,
+            allowed_groups
+        )
+        .execute(&mut *transaction)
+        .await?;
+        Ok(Vec::new())
+    }
+
+    /// Check if `user` can use the client, i.e. no groups are allowed explicitly,
+    /// or the user is an effective member of one of them.
+    pub async fn is_allowed_for(&self, pool: &DbPool, user: &User) -> Result<bool, SqlxError> {
+        let allowed_groups = self.fetch_allowed_groups(pool).await?;
+        if allowed_groups.is_empty() {
+            return Ok(true);
+        }
+        Ok(user
+            .effective_member_of_names(pool)
+            .await?
+            .iter()
+            .any(|group| allowed_groups.contains(group)))
+    }
+}
+
+/// OpenID client with groups allowed to use it, as shown to admins.
+#[derive(Deserialize, Serialize)]
+pub struct OAuth2ClientInfo {
+    #[serde(flatten)]
+    pub client: OAuth2Client,
+    pub allowed_groups: Vec<String>,
+}
+
+impl OAuth2ClientInfo {
+    pub async fn from_client(pool: &DbPool, client: OAuth2Client) -> Result<Self, SqlxError> {
+        let allowed_groups = client.fetch_allowed_groups(pool).await?;
+        Ok(Self {
+            client,
+            allowed_groups,
+        })
+    }
 }
 
 // Safe to show for not privileged users
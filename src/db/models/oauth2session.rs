@@ -0,0 +1,153 @@
+use chrono::NaiveDateTime;
+use sqlx::{query, query_as, query_scalar, Error as SqlxError, PgExecutor};
+
+use crate::random::gen_alphanumeric;
+
+/// Defguard session which authenticated a user to an OpenID client.
+///
+/// Recorded when an ID token is issued. Once the session ends,
+/// the client is notified using OpenID Connect Back-Channel Logout.
+pub struct OAuth2Session {
+    pub id: i64,
+    pub oauth2client_id: i64,
+    pub user_id: i64,
+    pub session_id: String,
+    // session identifier sent to clients, unlike `session_id` it's not a secret
+    pub sid: String,
+    pub created: NaiveDateTime,
+    pub ended: Option<NaiveDateTime>,
+}
+
+/// Ended session waiting for back-channel logout delivery.
+pub struct PendingLogout {
+    pub id: i64,
+    pub sid: String,
+    pub username: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub client_name: String,
+    pub backchannel_logout_uri: String,
+    pub logout_attempts: i32,
+}
+
+/// Back-channel logout delivery status, shown to admins.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogoutDeliveryStatus {
+    pub username: String,
+    pub ended: NaiveDateTime,
+    pub attempts: i32,
+    pub last_attempt: Option<NaiveDateTime>,
+    pub delivered: Option<NaiveDateTime>,
+    pub error: Option<String>,
+}
+
+impl OAuth2Session {
+    /// Link defguard session with an OpenID client and return its `sid`.
+    ///
+    /// All clients authenticated by the same session get the same `sid`.
+    /// Returns `None` if the session doesn't exist anymore.
+    pub async fn link<'e, E>(
+        executor: E,
+        oauth2client_id: i64,
+        session_id: &str,
+    ) -> Result<Option<String>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_scalar!(
+            "INSERT INTO oauth2session (oauth2client_id, user_id, session_id, sid) \
+            SELECT $1, user_id, id, \
+                COALESCE((SELECT sid FROM oauth2session WHERE session_id = $2 LIMIT 1), $3) \
+            FROM session WHERE id = $2 \
+            ON CONFLICT (oauth2client_id, session_id) DO UPDATE SET sid = oauth2session.sid \
+            RETURNING sid",
+            oauth2client_id,
+            session_id,
+            gen_alphanumeric(24),
+        )
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// Ended sessions of clients with back-channel logout configured, not yet delivered.
+    pub async fn pending_logouts<'e, E>(
+        executor: E,
+        max_attempts: i32,
+    ) -> Result<Vec<PendingLogout>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            PendingLogout,
+            "SELECT s.id, s.sid, u.username, c.client_id, c.client_secret, c.name client_name, \
+            c.backchannel_logout_uri \"backchannel_logout_uri!\", s.logout_attempts \
+            FROM oauth2session s \
+            JOIN oauth2client c ON c.id = s.oauth2client_id \
+            JOIN \"user\" u ON u.id = s.user_id \
+            WHERE s.ended IS NOT NULL AND s.logout_delivered IS NULL AND s.logout_attempts < $1 \
+            AND c.enabled AND c.backchannel_logout_uri IS NOT NULL \
+            ORDER BY s.ended",
+            max_attempts
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Store outcome of logout delivery; `error` is `None` if it succeeded.
+    pub async fn record_delivery<'e, E>(
+        executor: E,
+        id: i64,
+        attempts: i32,
+        error: Option<&str>,
+    ) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "UPDATE oauth2session SET logout_attempts = logout_attempts + $2, \
+            logout_last_attempt = now(), logout_error = $3, \
+            logout_delivered = CASE WHEN $3::text IS NULL THEN now() END \
+            WHERE id = $1",
+            id,
+            attempts,
+            error
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    /// Recent logouts of client's sessions, newest first.
+    pub async fn delivery_status<'e, E>(
+        executor: E,
+        oauth2client_id: i64,
+        limit: i64,
+    ) -> Result<Vec<LogoutDeliveryStatus>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            LogoutDeliveryStatus,
+            "SELECT u.username, s.ended \"ended!\", s.logout_attempts attempts, \
+            s.logout_last_attempt last_attempt, s.logout_delivered delivered, s.logout_error error \
+            FROM oauth2session s JOIN \"user\" u ON u.id = s.user_id \
+            WHERE s.oauth2client_id = $1 AND s.ended IS NOT NULL \
+            ORDER BY s.ended DESC LIMIT $2",
+            oauth2client_id,
+            limit
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Remove sessions which ended before given time.
+    pub async fn purge_ended<'e, E>(executor: E, before: NaiveDateTime) -> Result<u64, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let result = query!("DELETE FROM oauth2session WHERE ended < $1", before)
+            .execute(executor)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
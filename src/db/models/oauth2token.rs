@@ -70,6 +70,19 @@ impl OAuth2Token {
         Ok(())
     }
 
+    /// Record that the token has been issued, for the timeline of its user.
+    pub async fn record_issuance(&self, pool: &DbPool, grant_type: &str) -> Result<(), SqlxError> {
+        query!(
+            "INSERT INTO oauth2_token_issuance (user_id, oauth2client_id, grant_type) \
+            SELECT user_id, oauth2client_id, $2 FROM oauth2authorizedapp WHERE id = $1",
+            self.oauth2authorizedapp_id,
+            grant_type
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
     /// Delete token from the database.
     pub async fn delete(self, pool: &DbPool) -> Result<(), SqlxError> {
         query!(
@@ -0,0 +1,53 @@
+use chrono::{NaiveDateTime, Utc};
+use model_derive::Model;
+use sqlx::{query, query_as, Error as SqlxError, PgExecutor};
+
+use crate::random::gen_alphanumeric;
+
+/// Token issued to a desktop client device during enrollment.
+/// Used to poll for current location configuration without re-enrolling.
+#[derive(Clone, Debug, Model)]
+#[table(polling_token)]
+pub struct PollingToken {
+    pub id: Option<i64>,
+    pub token: String,
+    pub device_id: i64,
+    pub created_at: NaiveDateTime,
+}
+
+impl PollingToken {
+    #[must_use]
+    pub fn new(device_id: i64) -> Self {
+        Self {
+            id: None,
+            token: gen_alphanumeric(32),
+            device_id,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub async fn find<'e, E>(executor: E, token: &str) -> Result<Option<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id \"id?\", token, device_id, created_at \
+            FROM polling_token WHERE token = $1",
+            token
+        )
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// Remove token previously issued for a device, so that only one valid token exists.
+    pub async fn delete_for_device<'e, E>(executor: E, device_id: i64) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!("DELETE FROM polling_token WHERE device_id = $1", device_id)
+            .execute(executor)
+            .await?;
+        Ok(())
+    }
+}
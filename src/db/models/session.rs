@@ -1,10 +1,13 @@
 use chrono::{Duration, NaiveDateTime, Utc};
-use sqlx::{query, query_as, Error as SqlxError, PgExecutor, Type};
+use sqlx::{query, query_as, query_scalar, Error as SqlxError, PgExecutor, Type};
 use webauthn_rs::prelude::{PasskeyAuthentication, PasskeyRegistration};
 
-use super::DbPool;
+use super::{DbPool, Settings, User};
 use crate::{random::gen_alphanumeric, server_config};
 
+// last activity is stored at most this often, so that requests don't all write to the database
+const ACTIVITY_REFRESH_SECONDS: i64 = 60;
+
 #[derive(Clone, PartialEq, Type)]
 #[repr(i16)]
 pub enum SessionState {
@@ -26,6 +29,11 @@ pub struct Session {
     pub web3_challenge: Option<String>,
     pub ip_address: String,
     pub device_info: Option<String>,
+    // set for sessions in which an admin views defguard as another user
+    pub impersonator_id: Option<i64>,
+    pub impersonator_session_id: Option<String>,
+    // refreshed on authenticated requests, with up to a minute of delay
+    pub last_activity: NaiveDateTime,
 }
 
 impl Session {
@@ -48,19 +56,142 @@ impl Session {
             web3_challenge: None,
             ip_address,
             device_info,
+            impersonator_id: None,
+            impersonator_session_id: None,
+            last_activity: now.naive_utc(),
         }
     }
 
+    /// Create a read-only session acting as `user_id` on behalf of an admin.
+    /// It expires after configured impersonation timeout, but never later than admin's session.
+    #[must_use]
+    pub fn new_impersonation(user_id: i64, admin_session: &Self) -> Self {
+        let timeout = server_config().impersonation_timeout;
+        let expires = (Utc::now() + Duration::seconds(timeout.as_secs() as i64)).naive_utc();
+        Self {
+            expires: expires.min(admin_session.expires),
+            impersonator_id: Some(admin_session.user_id),
+            impersonator_session_id: Some(admin_session.id.clone()),
+            ..Self::new(
+                user_id,
+                SessionState::MultiFactorVerified,
+                admin_session.ip_address.clone(),
+                admin_session.device_info.clone(),
+            )
+        }
+    }
+
+    /// Create a session of the break-glass account. It expires after configured
+    /// break-glass session timeout, which is meant to be much shorter than regular one.
+    #[must_use]
+    pub fn new_break_glass(
+        user_id: i64,
+        state: SessionState,
+        ip_address: String,
+        device_info: Option<String>,
+    ) -> Self {
+        let timeout = server_config().break_glass_session_timeout;
+        let expires = (Utc::now() + Duration::seconds(timeout.as_secs() as i64)).naive_utc();
+        let session = Self::new(user_id, state, ip_address, device_info);
+        Self {
+            expires: expires.min(session.expires),
+            ..session
+        }
+    }
+
+    #[must_use]
+    pub fn is_impersonation(&self) -> bool {
+        self.impersonator_id.is_some()
+    }
+
     #[must_use]
     pub fn expired(&self) -> bool {
         self.expires < Utc::now().naive_utc()
     }
 
+    /// Inactivity period after which the session expires, if any. Sessions waiting for
+    /// the second factor always have one, never longer than `idle_timeout` from settings.
+    #[must_use]
+    pub fn idle_timeout(
+        &self,
+        idle_timeout: Option<Duration>,
+        mfa_pending: bool,
+    ) -> Option<Duration> {
+        if mfa_pending {
+            let mfa_timeout =
+                Duration::seconds(server_config().mfa_session_idle_timeout.as_secs() as i64);
+            Some(idle_timeout.map_or(mfa_timeout, |timeout| timeout.min(mfa_timeout)))
+        } else {
+            idle_timeout
+        }
+    }
+
+    /// Check if the session has expired due to inactivity.
+    pub async fn idle<'e, E>(&self, executor: E) -> Result<bool, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let Some(limits) = query!(
+            "SELECT mfa_enabled, (SELECT session_idle_timeout FROM settings WHERE id = 1) \
+            \"session_idle_timeout?\" FROM \"user\" WHERE id = $1",
+            self.user_id
+        )
+        .fetch_optional(executor)
+        .await?
+        else {
+            return Ok(false);
+        };
+        let mfa_pending = limits.mfa_enabled && self.state != SessionState::MultiFactorVerified;
+        let idle_timeout = limits
+            .session_idle_timeout
+            .map(|minutes| Duration::minutes(minutes.into()));
+        Ok(self
+            .idle_timeout(idle_timeout, mfa_pending)
+            .is_some_and(|timeout| self.last_activity + timeout < Utc::now().naive_utc()))
+    }
+
+    /// Record activity in the session. To avoid a write on each request, the stored
+    /// timestamp is only refreshed once it's older than a minute.
+    /// Returns `true` if the session was updated.
+    pub async fn touch<'e, E>(&mut self, executor: E) -> Result<bool, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let now = Utc::now().naive_utc();
+        let refresh_before = now - Duration::seconds(ACTIVITY_REFRESH_SECONDS);
+        if self.last_activity > refresh_before {
+            return Ok(false);
+        }
+        // concurrent requests can't refresh it twice
+        let result = query!(
+            "UPDATE session SET last_activity = $2 WHERE id = $1 AND last_activity <= $3",
+            self.id,
+            now,
+            refresh_before
+        )
+        .execute(executor)
+        .await?;
+        self.last_activity = now;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Check if inactivity timeout in settings is valid.
+    pub fn validate_settings(settings: &Settings) -> Result<(), String> {
+        if settings
+            .session_idle_timeout
+            .is_some_and(|minutes| minutes < 1)
+        {
+            return Err("Session idle timeout must be at least one minute".into());
+        }
+        Ok(())
+    }
+
     pub async fn find_by_id(pool: &DbPool, id: &str) -> Result<Option<Self>, SqlxError> {
         query_as!(
             Self,
             "SELECT id, user_id, state \"state: SessionState\", created, expires, webauthn_challenge, \
-            web3_challenge, ip_address, device_info FROM session WHERE id = $1",
+            web3_challenge, ip_address, device_info, impersonator_id, impersonator_session_id, \
+            last_activity FROM session WHERE id = $1",
             id
         )
         .fetch_optional(pool)
@@ -69,8 +200,9 @@ impl Session {
 
     pub async fn save(&self, pool: &DbPool) -> Result<(), SqlxError> {
         query!(
-            "INSERT INTO session (id, user_id, state, created, expires, webauthn_challenge, web3_challenge, ip_address, device_info) \
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            "INSERT INTO session (id, user_id, state, created, expires, webauthn_challenge, web3_challenge, \
+            ip_address, device_info, impersonator_id, impersonator_session_id, last_activity) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
             self.id,
             self.user_id,
             self.state.clone() as i16,
@@ -80,6 +212,9 @@ impl Session {
             self.web3_challenge,
             self.ip_address,
             self.device_info,
+            self.impersonator_id,
+            self.impersonator_session_id,
+            self.last_activity,
         )
         .execute(pool)
         .await?;
@@ -94,6 +229,10 @@ impl Session {
         )
         .execute(pool)
         .await?;
+        // completing MFA finishes logging in
+        if state == SessionState::MultiFactorVerified {
+            User::record_login(pool, self.user_id).await?;
+        }
         self.state = state;
         Ok(())
     }
@@ -173,33 +312,148 @@ impl Session {
         Ok(())
     }
 
+    /// Delete session, marking OpenID clients it authenticated for back-channel logout.
     pub async fn delete<'e, E>(self, executor: E) -> Result<(), SqlxError>
     where
         E: PgExecutor<'e>,
     {
-        query!("DELETE FROM session WHERE id = $1", self.id)
-            .execute(executor)
-            .await?;
+        query!(
+            "WITH ended AS ( \
+                UPDATE oauth2session SET ended = now() WHERE session_id = $1 AND ended IS NULL \
+            ) DELETE FROM session WHERE id = $1",
+            self.id
+        )
+        .execute(executor)
+        .await?;
         Ok(())
     }
 
-    pub async fn delete_expired<'e, E>(executor: E) -> Result<(), SqlxError>
+    /// Delete session found expired or idle by a validator, unless it has been refreshed
+    /// in the meantime, marking OpenID clients it authenticated for back-channel logout.
+    /// Returns `false` if the session no longer exists or is still in use.
+    pub async fn delete_stale<'e, E>(self, executor: E) -> Result<bool, SqlxError>
     where
         E: PgExecutor<'e>,
     {
-        query!("DELETE FROM session WHERE expires < now()",)
-            .execute(executor)
-            .await?;
-        Ok(())
+        let deleted = query_scalar!(
+            "WITH deleted AS ( \
+                DELETE FROM session WHERE id = $1 AND (expires < now() OR last_activity <= $2) \
+                RETURNING id \
+            ), ended AS ( \
+                UPDATE oauth2session SET ended = now() \
+                WHERE ended IS NULL AND session_id IN (SELECT id FROM deleted) \
+            ) SELECT count(*) \"count!\" FROM deleted",
+            self.id,
+            self.last_activity
+        )
+        .fetch_one(executor)
+        .await?;
+        Ok(deleted > 0)
     }
 
+    /// Delete at most `limit` expired sessions. OpenID clients are not notified,
+    /// their sessions expire as well. Returns number of deleted sessions.
+    pub async fn delete_expired<'e, E>(executor: E, limit: i64) -> Result<u64, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let deleted = query_scalar!(
+            "WITH expired AS ( \
+                DELETE FROM session WHERE expires < now() \
+                AND id IN (SELECT id FROM session WHERE expires < now() LIMIT $1) \
+                RETURNING id \
+            ), ended AS ( \
+                DELETE FROM oauth2session WHERE ended IS NULL \
+                AND session_id IN (SELECT id FROM expired) \
+            ) SELECT count(*) \"count!\" FROM expired",
+            limit
+        )
+        .fetch_one(executor)
+        .await?;
+        Ok(deleted as u64)
+    }
+
+    /// Delete all user sessions, marking OpenID clients they authenticated for back-channel logout.
     pub async fn delete_all_for_user<'e, E>(executor: E, user_id: i64) -> Result<(), SqlxError>
     where
         E: PgExecutor<'e>,
     {
-        query!("DELETE FROM session WHERE user_id = $1", user_id)
-            .execute(executor)
-            .await?;
+        query!(
+            "WITH ended AS ( \
+                UPDATE oauth2session SET ended = now() WHERE user_id = $1 AND ended IS NULL \
+            ) DELETE FROM session WHERE user_id = $1",
+            user_id
+        )
+        .execute(executor)
+        .await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::DefGuardConfig, SERVER_CONFIG};
+
+    #[sqlx::test]
+    async fn test_mfa_pending_session_idle(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.mfa_enabled = true;
+        user.save(&pool).await.unwrap();
+
+        let mut session = Session::new(
+            user.id.unwrap(),
+            SessionState::PasswordVerified,
+            "127.0.0.1".into(),
+            None,
+        );
+        session.save(&pool).await.unwrap();
+        assert!(!session.idle(&pool).await.unwrap());
+
+        // waiting for the second factor has its own limit even if idle timeout isn't set
+        session.last_activity -= Duration::minutes(6);
+        assert!(session.idle(&pool).await.unwrap());
+        session.state = SessionState::MultiFactorVerified;
+        assert!(!session.idle(&pool).await.unwrap());
+
+        // the shorter limit applies
+        let mfa_timeout = Duration::minutes(5);
+        assert_eq!(session.idle_timeout(None, true), Some(mfa_timeout));
+        assert_eq!(
+            session.idle_timeout(Some(Duration::minutes(30)), true),
+            Some(mfa_timeout)
+        );
+        assert_eq!(
+            session.idle_timeout(Some(Duration::minutes(2)), true),
+            Some(Duration::minutes(2))
+        );
+        assert_eq!(
+            session.idle_timeout(Some(Duration::minutes(30)), false),
+            Some(Duration::minutes(30))
+        );
+        assert_eq!(session.idle_timeout(None, false), None);
+
+        // activity is stored at most once a minute, also by concurrent requests
+        query!(
+            "UPDATE session SET last_activity = $2 WHERE id = $1",
+            session.id,
+            session.last_activity
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let mut concurrent = session.clone();
+        assert!(session.touch(&pool).await.unwrap());
+        assert!(!session.touch(&pool).await.unwrap());
+        assert!(!concurrent.touch(&pool).await.unwrap());
+    }
+}
@@ -1,13 +1,15 @@
 use std::collections::HashMap;
 
+use chrono::{NaiveDateTime, Utc};
 use model_derive::Model;
 use sqlx::{query, query_as, Error as SqlxError, PgExecutor, Type};
 use struct_patch::Patch;
+use utoipa::ToSchema;
 
-use super::DbPool;
+use super::{DbPool, MFAMethod};
 use crate::secret::SecretString;
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Type, Debug)]
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Type, Debug, ToSchema)]
 #[sqlx(type_name = "smtp_encryption", rename_all = "lowercase")]
 pub enum SmtpEncryption {
     None,
@@ -15,7 +17,57 @@ pub enum SmtpEncryption {
     ImplicitTls,
 }
 
-#[derive(Debug, Clone, Model, Serialize, Deserialize, PartialEq, Patch)]
+/// How to handle users who already use an MFA method which gets disallowed.
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Type, Debug, ToSchema)]
+#[sqlx(type_name = "disallowed_mfa_policy", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum DisallowedMfaPolicy {
+    /// Keep the method working for a while and ask users to switch.
+    GracePeriod,
+    /// Remove the method from user accounts right away.
+    Invalidate,
+}
+
+/// Where DNS records of devices are published.
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Type, Debug, ToSchema)]
+#[sqlx(type_name = "dns_provider", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProvider {
+    /// Publishing is disabled.
+    None,
+    /// RFC 2136 dynamic updates signed with TSIG.
+    Rfc2136,
+    /// Updates are posted to a webhook.
+    Webhook,
+}
+
+/// Challenge unauthenticated login and enrollment requests have to solve.
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Type, Debug, ToSchema)]
+#[sqlx(type_name = "auth_challenge", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuthChallenge {
+    None,
+    /// Built-in proof of work, solved by the client without user interaction.
+    ProofOfWork,
+    Hcaptcha,
+    Turnstile,
+}
+
+/// Rules applied to names of new and renamed devices.
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Type, Debug, ToSchema)]
+#[sqlx(type_name = "device_name_policy", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceNamePolicy {
+    /// Names are taken as they are.
+    None,
+    /// Names with characters outside the safe set, too long, or used by another device
+    /// of the user are rejected.
+    Reject,
+    /// Unsafe characters are replaced and long names are cut; duplicates are rejected.
+    Sanitize,
+}
+
+#[derive(Debug, Clone, Model, Serialize, Deserialize, PartialEq, Patch, ToSchema)]
 #[patch_derive(Serialize, Deserialize)]
 pub struct Settings {
     #[serde(skip)]
@@ -38,6 +90,7 @@ pub struct Settings {
     pub smtp_encryption: SmtpEncryption,
     pub smtp_user: Option<String>,
     #[model(secret)]
+    #[schema(value_type = Option<String>)]
     pub smtp_password: Option<SecretString>,
     pub smtp_sender: Option<String>,
     // Enrollment
@@ -46,6 +99,8 @@ pub struct Settings {
     pub enrollment_welcome_email: Option<String>,
     pub enrollment_welcome_email_subject: Option<String>,
     pub enrollment_use_welcome_message_as_email: bool,
+    // allow finishing enrollment in the browser without the desktop client
+    pub enrollment_web_fallback_enabled: bool,
     // Instance UUID needed for desktop client
     #[serde(skip)]
     pub uuid: uuid::Uuid,
@@ -53,6 +108,7 @@ pub struct Settings {
     pub ldap_url: Option<String>,
     pub ldap_bind_username: Option<String>,
     #[model(secret)]
+    #[schema(value_type = Option<String>)]
     pub ldap_bind_password: Option<SecretString>,
     pub ldap_group_search_base: Option<String>,
     pub ldap_user_search_base: Option<String>,
@@ -62,6 +118,80 @@ pub struct Settings {
     pub ldap_groupname_attr: Option<String>,
     pub ldap_group_member_attr: Option<String>,
     pub ldap_member_attr: Option<String>,
+    // Password policy
+    pub password_min_length: i32,
+    pub password_require_lowercase: bool,
+    pub password_require_uppercase: bool,
+    pub password_require_digit: bool,
+    pub password_require_special: bool,
+    pub password_disallow_user_data: bool,
+    // minimum zxcvbn score (0-4)
+    pub password_min_score: Option<i32>,
+    pub password_breach_check: bool,
+    // breach check request timeout in milliseconds
+    pub password_breach_check_timeout: i32,
+    // Swagger UI for browsing the REST API, available to admins
+    pub openapi_ui_enabled: bool,
+    // MFA methods users can set up and log in with
+    pub mfa_totp_allowed: bool,
+    pub mfa_email_allowed: bool,
+    pub mfa_webauthn_allowed: bool,
+    pub mfa_web3_allowed: bool,
+    pub mfa_recovery_codes_allowed: bool,
+    #[model(enum)]
+    pub mfa_disallowed_policy: DisallowedMfaPolicy,
+    pub mfa_grace_period_days: i32,
+    // set when methods get disallowed under grace period policy; can't be changed directly
+    pub mfa_grace_period_end: Option<NaiveDateTime>,
+    // DNS records of devices in locations with a DNS zone
+    #[model(enum)]
+    pub dns_provider: DnsProvider,
+    // RFC 2136 server in `host:port` format
+    pub dns_server: Option<String>,
+    pub dns_tsig_key_name: Option<String>,
+    // base64 encoded HMAC-SHA256 key
+    #[model(secret)]
+    #[schema(value_type = Option<String>)]
+    pub dns_tsig_secret: Option<SecretString>,
+    pub dns_webhook_url: Option<String>,
+    #[model(secret)]
+    #[schema(value_type = Option<String>)]
+    pub dns_webhook_secret: Option<SecretString>,
+    pub dns_record_ttl: i32,
+    // web sessions inactive for this many minutes expire; disabled if not set
+    pub session_idle_timeout: Option<i32>,
+    // number of devices a user can own, can be overridden per user; unlimited if not set
+    pub max_devices_per_user: Option<i32>,
+    // notify users about VPN connections of their devices from new countries
+    pub new_country_alert_enabled: bool,
+    // countries a device hasn't connected from for this long are no longer known
+    pub known_country_retention_months: i32,
+    // challenge required from unauthenticated login and enrollment requests
+    #[model(enum)]
+    pub auth_challenge: AuthChallenge,
+    // only require the challenge while failed attempts suggest an ongoing attack
+    pub auth_challenge_on_pressure: bool,
+    // leading zero bits required from proof of work solutions
+    pub auth_challenge_pow_difficulty: i32,
+    pub auth_challenge_site_key: Option<String>,
+    #[model(secret)]
+    #[schema(value_type = Option<String>)]
+    pub auth_challenge_secret: Option<SecretString>,
+    // CAPTCHA verification request timeout in milliseconds
+    pub auth_challenge_timeout: i32,
+    // let requests through if the CAPTCHA provider can't be reached
+    pub auth_challenge_fail_open: bool,
+    // users can get SSH certificates for their registered keys signed
+    pub ssh_ca_enabled: bool,
+    // upper bound for validity of issued SSH certificates
+    pub ssh_ca_max_validity_minutes: i32,
+    // principals granted to group members, one `group: principal, ...` entry per line
+    pub ssh_ca_principal_mapping: Option<String>,
+    // validation of device names, which also makes them unique per user
+    #[model(enum)]
+    pub device_name_policy: DeviceNamePolicy,
+    // longest device name accepted, in characters; only applies with a device name policy
+    pub device_name_max_length: i32,
 }
 
 impl Settings {
@@ -108,9 +238,71 @@ impl Settings {
             && self.smtp_password.is_some()
             && self.smtp_sender.is_some()
     }
+
+    /// Check if users can set up given MFA method.
+    #[must_use]
+    pub fn mfa_method_allowed(&self, method: &MFAMethod) -> bool {
+        match method {
+            MFAMethod::None => true,
+            MFAMethod::OneTimePassword => self.mfa_totp_allowed,
+            MFAMethod::Email => self.mfa_email_allowed,
+            MFAMethod::Webauthn => self.mfa_webauthn_allowed,
+            MFAMethod::Web3 => self.mfa_web3_allowed,
+        }
+    }
+
+    fn mfa_methods_filtered(&self, allowed: bool) -> Vec<MFAMethod> {
+        [
+            MFAMethod::OneTimePassword,
+            MFAMethod::Email,
+            MFAMethod::Webauthn,
+            MFAMethod::Web3,
+        ]
+        .into_iter()
+        .filter(|method| self.mfa_method_allowed(method) == allowed)
+        .collect()
+    }
+
+    /// MFA methods users can set up.
+    #[must_use]
+    pub fn allowed_mfa_methods(&self) -> Vec<MFAMethod> {
+        self.mfa_methods_filtered(true)
+    }
+
+    /// MFA methods users can't set up.
+    #[must_use]
+    pub fn disallowed_mfa_methods(&self) -> Vec<MFAMethod> {
+        self.mfa_methods_filtered(false)
+    }
+
+    /// Check if disallowed MFA methods still work for users who had them set up before.
+    #[must_use]
+    pub fn mfa_grace_period_active(&self) -> bool {
+        self.mfa_disallowed_policy == DisallowedMfaPolicy::GracePeriod
+            && self
+                .mfa_grace_period_end
+                .is_some_and(|end| Utc::now().naive_utc() < end)
+    }
+
+    /// Check if given MFA method can be used for logging in.
+    #[must_use]
+    pub fn mfa_method_usable(&self, method: &MFAMethod) -> bool {
+        self.mfa_method_allowed(method) || self.mfa_grace_period_active()
+    }
+
+    /// Clear grace period end once disallowed MFA methods have been removed.
+    pub(crate) async fn end_mfa_grace_period<'e, E>(executor: E) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!("UPDATE settings SET mfa_grace_period_end = NULL WHERE id = 1")
+            .execute(executor)
+            .await?;
+        Ok(())
+    }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct SettingsEssentials {
     pub instance_name: String,
     pub main_logo_url: String,
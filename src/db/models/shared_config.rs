@@ -0,0 +1,171 @@
+use chrono::{NaiveDateTime, Utc};
+use model_derive::Model;
+use sqlx::{query, query_as, Error as SqlxError, PgExecutor};
+use utoipa::ToSchema;
+
+use crate::random::gen_alphanumeric;
+
+const SHARED_CONFIG_TOKEN_LENGTH: usize = 32;
+
+/// One-time link to a device config, e.g. for a device an admin created on behalf of a user.
+///
+/// Configs are rendered when the link is opened, so they reflect the current location
+/// settings. Links can be used once, and expire unused at `expires_at` unless revoked earlier.
+#[derive(Clone, Debug, Model, Serialize, ToSchema)]
+#[table(shared_config)]
+pub struct SharedConfig {
+    pub id: Option<i64>,
+    #[serde(skip_serializing)]
+    pub token: String,
+    pub device_id: i64,
+    pub network_id: i64,
+    pub created_by: Option<i64>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub used_from: Option<String>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl SharedConfig {
+    #[must_use]
+    pub fn new(
+        device_id: i64,
+        network_id: i64,
+        created_by: Option<i64>,
+        expires_at: NaiveDateTime,
+    ) -> Self {
+        Self {
+            id: None,
+            token: gen_alphanumeric(SHARED_CONFIG_TOKEN_LENGTH),
+            device_id,
+            network_id,
+            created_by,
+            created_at: Utc::now().naive_utc(),
+            expires_at,
+            used_at: None,
+            used_from: None,
+            revoked_at: None,
+        }
+    }
+
+    pub async fn find_by_token<'e, E>(executor: E, token: &str) -> Result<Option<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id \"id?\", token, device_id, network_id, created_by, created_at, expires_at, \
+            used_at, used_from, revoked_at FROM shared_config WHERE token = $1",
+            token
+        )
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// Links of a device which can still be used, newest first.
+    pub async fn active_for_device<'e, E>(
+        executor: E,
+        device_id: i64,
+    ) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id \"id?\", token, device_id, network_id, created_by, created_at, expires_at, \
+            used_at, used_from, revoked_at FROM shared_config \
+            WHERE device_id = $1 AND used_at IS NULL AND revoked_at IS NULL AND expires_at > now() \
+            ORDER BY id DESC",
+            device_id
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.used_at.is_none()
+            && self.revoked_at.is_none()
+            && self.expires_at > Utc::now().naive_utc()
+    }
+
+    /// Mark link as used from `ip_address`. Returns `false` if it has been used, revoked
+    /// or has expired in the meantime, so that concurrent requests can't both succeed.
+    pub async fn consume<'e, E>(&mut self, executor: E, ip_address: &str) -> Result<bool, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let used_at = Utc::now().naive_utc();
+        let result = query!(
+            "UPDATE shared_config SET used_at = $2, used_from = $3 \
+            WHERE id = $1 AND used_at IS NULL AND revoked_at IS NULL AND expires_at > $2",
+            self.id,
+            used_at,
+            ip_address
+        )
+        .execute(executor)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        self.used_at = Some(used_at);
+        self.used_from = Some(ip_address.into());
+        Ok(true)
+    }
+
+    pub async fn revoke<'e, E>(&mut self, executor: E) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let revoked_at = Utc::now().naive_utc();
+        query!(
+            "UPDATE shared_config SET revoked_at = $2 WHERE id = $1 AND revoked_at IS NULL",
+            self.id,
+            revoked_at
+        )
+        .execute(executor)
+        .await?;
+        self.revoked_at = Some(revoked_at);
+        Ok(())
+    }
+
+    /// Revoke unused links whose tokens start with `prefix`, targeted by guessing attempts.
+    /// Returns number of revoked links.
+    pub async fn revoke_by_prefix<'e, E>(executor: E, prefix: &str) -> Result<u64, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let result = query!(
+            "UPDATE shared_config SET revoked_at = now() \
+            WHERE left(token, length($1)) = $1 AND used_at IS NULL AND revoked_at IS NULL",
+            prefix
+        )
+        .execute(executor)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Delete at most `limit` links used, revoked or expired before `before`.
+    /// Returns number of deleted links.
+    pub async fn delete_stale<'e, E>(
+        executor: E,
+        before: NaiveDateTime,
+        limit: i64,
+    ) -> Result<u64, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let result = query!(
+            "DELETE FROM shared_config \
+            WHERE (expires_at < $1 OR used_at < $1 OR revoked_at < $1) \
+            AND id IN (SELECT id FROM shared_config \
+                WHERE expires_at < $1 OR used_at < $1 OR revoked_at < $1 LIMIT $2)",
+            before,
+            limit
+        )
+        .execute(executor)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
@@ -0,0 +1,117 @@
+use chrono::NaiveDateTime;
+use sqlx::{query, query_as, query_scalar, Error as SqlxError, PgExecutor};
+use utoipa::ToSchema;
+
+/// SSH certificate issued by the certificate authority, kept for audit and revocation.
+///
+/// `id` is the certificate serial number.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct SshCertificate {
+    pub id: Option<i64>,
+    pub user_id: Option<i64>,
+    pub key_id: String,
+    pub principals: Vec<String>,
+    pub public_key: String,
+    pub valid_after: NaiveDateTime,
+    pub valid_before: NaiveDateTime,
+    pub issued_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl SshCertificate {
+    /// Reserve the next serial number. Certificate details are stored with `save` once
+    /// it's signed, so this should run in the same transaction.
+    pub async fn next_serial<'e, E>(executor: E) -> Result<i64, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_scalar!("SELECT nextval('ssh_certificate_id_seq') \"serial!\"")
+            .fetch_one(executor)
+            .await
+    }
+
+    pub async fn save<'e, E>(&self, executor: E) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "INSERT INTO ssh_certificate (id, user_id, key_id, principals, public_key, \
+            valid_after, valid_before, issued_at, revoked_at) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            self.id,
+            self.user_id,
+            self.key_id,
+            &self.principals,
+            self.public_key,
+            self.valid_after,
+            self.valid_before,
+            self.issued_at,
+            self.revoked_at
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn all<'e, E>(executor: E) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id \"id?\", user_id, key_id, principals, public_key, valid_after, \
+            valid_before, issued_at, revoked_at FROM ssh_certificate ORDER BY id DESC"
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Revoke certificate with given serial. Returns `None` if there's no such certificate;
+    /// revoking it again keeps the original revocation time.
+    pub async fn revoke<'e, E>(
+        executor: E,
+        serial: i64,
+        revoked_at: NaiveDateTime,
+    ) -> Result<Option<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "UPDATE ssh_certificate SET revoked_at = coalesce(revoked_at, $2) WHERE id = $1 \
+            RETURNING id \"id?\", user_id, key_id, principals, public_key, valid_after, \
+            valid_before, issued_at, revoked_at",
+            serial,
+            revoked_at
+        )
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// Serial numbers of revoked certificates which haven't expired yet.
+    pub async fn revoked_serials<'e, E>(
+        executor: E,
+        now: NaiveDateTime,
+    ) -> Result<Vec<i64>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_scalar!(
+            "SELECT id FROM ssh_certificate \
+            WHERE revoked_at IS NOT NULL AND valid_before > $1 ORDER BY id",
+            now
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Time of the latest revocation, used as KRL version.
+    pub async fn last_revoked_at<'e, E>(executor: E) -> Result<Option<NaiveDateTime>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_scalar!("SELECT max(revoked_at) FROM ssh_certificate")
+            .fetch_one(executor)
+            .await
+    }
+}
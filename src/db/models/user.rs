@@ -8,15 +8,18 @@ use argon2::{
     Argon2,
 };
 use axum::http::StatusCode;
+use chrono::{NaiveDateTime, Utc};
 use model_derive::Model;
 use otpauth::TOTP;
-use sqlx::{query, query_as, query_scalar, Error as SqlxError, PgExecutor, Type};
+use sqlx::{query, query_as, query_scalar, Error as SqlxError, PgConnection, PgExecutor, Type};
+use utoipa::ToSchema;
 
 use super::{
-    device::{Device, UserDevice},
+    device::{Device, DeviceNetworkInfo, UserDevice, WireguardNetworkDevice},
     group::Group,
     wallet::Wallet,
     webauthn::WebAuthn,
+    wireguard::{GatewayEvent, PeerUpdate, WireguardNetwork},
     DbPool, MFAInfo, OAuth2AuthorizedAppInfo, SecurityKey, WalletInfo,
 };
 use crate::{
@@ -29,7 +32,7 @@ use crate::{
 
 const RECOVERY_CODES_COUNT: usize = 8;
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Type, Debug)]
+#[derive(Clone, Deserialize, Serialize, PartialEq, Type, Debug, ToSchema)]
 #[sqlx(type_name = "mfa_method", rename_all = "snake_case")]
 pub enum MFAMethod {
     None,
@@ -83,6 +86,14 @@ pub struct User {
     pub(crate) mfa_method: MFAMethod,
     #[model(ref)]
     pub(crate) recovery_codes: Vec<String>,
+    // emergency admin account, see `crate::break_glass`
+    pub break_glass: bool,
+    // overrides `max_devices_per_user` setting, set by admins
+    pub max_devices: Option<i32>,
+    // account merged into another one, see `User::merge_into()`
+    pub merged_into: Option<i64>,
+    // don't notify about VPN connections from new countries, see `crate::new_country_alert`
+    pub new_country_alert_opt_out: bool,
 }
 
 impl User {
@@ -120,6 +131,10 @@ impl User {
             mfa_method: MFAMethod::None,
             recovery_codes: Vec::new(),
             is_active: true,
+            break_glass: false,
+            max_devices: None,
+            merged_into: None,
+            new_country_alert_opt_out: false,
         }
     }
 
@@ -342,6 +357,54 @@ impl User {
         Ok(Some(self.recovery_codes.clone()))
     }
 
+    /// Replace all recovery codes with a fresh set and return it.
+    /// Old codes and their usage history are discarded in a single transaction.
+    pub async fn regenerate_recovery_codes(
+        &mut self,
+        pool: &DbPool,
+    ) -> Result<Vec<String>, SqlxError> {
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODES_COUNT)
+            .map(|_| gen_alphanumeric(16))
+            .collect();
+        if let Some(id) = self.id {
+            let mut transaction = pool.begin().await?;
+            query!(
+                "UPDATE \"user\" SET recovery_codes = $2 WHERE id = $1",
+                id,
+                &recovery_codes
+            )
+            .execute(&mut *transaction)
+            .await?;
+            query!("DELETE FROM recovery_code_usage WHERE user_id = $1", id)
+                .execute(&mut *transaction)
+                .await?;
+            transaction.commit().await?;
+        }
+        self.recovery_codes = recovery_codes;
+
+        Ok(self.recovery_codes.clone())
+    }
+
+    /// Timestamps of recovery codes used since they were last generated, oldest first.
+    pub async fn recovery_codes_used_at<'e, E>(
+        &self,
+        executor: E,
+    ) -> Result<Vec<NaiveDateTime>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        if let Some(id) = self.id {
+            query_scalar!(
+                "SELECT used_at FROM recovery_code_usage WHERE user_id = $1 ORDER BY used_at",
+                id
+            )
+            .fetch_all(executor)
+            .await
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
     /// Disable MFA; discard recovery codes, TOTP secret, and security keys.
     pub async fn disable_mfa(&mut self, pool: &DbPool) -> Result<(), SqlxError> {
         if let Some(id) = self.id {
@@ -352,6 +415,9 @@ impl User {
             )
             .execute(pool)
             .await?;
+            query!("DELETE FROM recovery_code_usage WHERE user_id = $1", id)
+                .execute(pool)
+                .await?;
             Wallet::disable_mfa_for_user(pool, id).await?;
             WebAuthn::delete_all_for_user(pool, id).await?;
         }
@@ -444,6 +510,46 @@ impl User {
         Ok(())
     }
 
+    /// Remove given MFA method from user account. MFA flag, current method and recovery codes
+    /// are left as they are, use [`Self::verify_mfa_state`] to update them.
+    pub async fn remove_mfa_method(
+        &mut self,
+        pool: &DbPool,
+        method: &MFAMethod,
+    ) -> Result<(), SqlxError> {
+        let Some(id) = self.id else {
+            return Ok(());
+        };
+        info!("Removing MFA method {method:?} of user {}", self.username);
+        match method {
+            MFAMethod::None => (),
+            MFAMethod::OneTimePassword => {
+                query!(
+                    "UPDATE \"user\" SET totp_enabled = FALSE, totp_secret = NULL WHERE id = $1",
+                    id
+                )
+                .execute(pool)
+                .await?;
+                self.totp_enabled = false;
+                self.totp_secret = None;
+            }
+            MFAMethod::Email => {
+                query!(
+                    "UPDATE \"user\" SET email_mfa_enabled = FALSE, email_mfa_secret = NULL \
+                    WHERE id = $1",
+                    id
+                )
+                .execute(pool)
+                .await?;
+                self.email_mfa_enabled = false;
+                self.email_mfa_secret = None;
+            }
+            MFAMethod::Webauthn => WebAuthn::delete_all_for_user(pool, id).await?,
+            MFAMethod::Web3 => Wallet::disable_mfa_for_user(pool, id).await?,
+        }
+        Ok(())
+    }
+
     /// Select all users without sensitive data.
     // FIXME: Remove it when Model macro will support SecretString
     pub async fn all_without_sensitive_data(
@@ -471,26 +577,50 @@ impl User {
         Ok(res)
     }
 
-    /// Return all members of group
+    /// Return all members of group, including members of its subgroups
     pub async fn find_by_group_name(
         pool: &DbPool,
         group_name: &str,
     ) -> Result<Vec<User>, SqlxError> {
-        let users = query_as!(
-            User,
-            "SELECT \"user\".id \"id?\", username, password_hash, last_name, first_name, email, \
-            phone, mfa_enabled, totp_enabled, totp_secret, \
-            email_mfa_enabled, email_mfa_secret, \
-            mfa_method \"mfa_method: _\", recovery_codes, is_active \
-            FROM \"user\"
-            INNER JOIN \"group_user\" ON \"user\".id = \"group_user\".user_id
-            INNER JOIN \"group\" ON \"group_user\".group_id = \"group\".id
-            WHERE \"group\".name = $1",
-            group_name
+        match Group::find_by_name(pool, group_name).await? {
+            Some(group) => group.effective_members(pool).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Record that user has finished logging in.
+    pub async fn record_login<'e, E>(executor: E, user_id: i64) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "UPDATE \"user\" SET last_login = $1 WHERE id = $2",
+            Utc::now().naive_utc(),
+            user_id
         )
-        .fetch_all(pool)
+        .execute(executor)
         .await?;
-        Ok(users)
+        Ok(())
+    }
+
+    /// Check if any admin, other than break-glass accounts, has logged in since given time.
+    pub async fn regular_admin_logged_in_since(
+        pool: &DbPool,
+        since: NaiveDateTime,
+    ) -> Result<bool, SqlxError> {
+        let admin_ids: Vec<i64> = Self::find_by_group_name(pool, &server_config().admin_groupname)
+            .await?
+            .into_iter()
+            .filter_map(|admin| admin.id)
+            .collect();
+        query_scalar!(
+            "SELECT EXISTS (SELECT 1 FROM \"user\" \
+            WHERE id = ANY($1) AND NOT break_glass AND last_login >= $2) \"exists!\"",
+            &admin_ids,
+            since
+        )
+        .fetch_one(pool)
+        .await
     }
 
     /// Check if TOTP `code` is valid.
@@ -547,13 +677,18 @@ impl User {
             // Note: swap_remove() should be faster than remove().
             self.recovery_codes.swap_remove(index);
             if let Some(id) = self.id {
+                let mut transaction = pool.begin().await?;
                 query!(
                     "UPDATE \"user\" SET recovery_codes = $2 WHERE id = $1",
                     id,
                     &self.recovery_codes
                 )
-                .execute(pool)
+                .execute(&mut *transaction)
                 .await?;
+                query!("INSERT INTO recovery_code_usage (user_id) VALUES ($1)", id)
+                    .execute(&mut *transaction)
+                    .await?;
+                transaction.commit().await?;
             }
             Ok(true)
         } else {
@@ -572,7 +707,7 @@ impl User {
             Self,
             "SELECT id \"id?\", username, password_hash, last_name, first_name, email, \
             phone, mfa_enabled, totp_enabled, email_mfa_enabled, \
-            totp_secret, email_mfa_secret, mfa_method \"mfa_method: _\", recovery_codes, is_active \
+            totp_secret, email_mfa_secret, mfa_method \"mfa_method: _\", recovery_codes, is_active, break_glass, max_devices, merged_into, new_country_alert_opt_out \
             FROM \"user\" WHERE username = $1",
             username
         )
@@ -588,7 +723,7 @@ impl User {
             Self,
             "SELECT id \"id?\", username, password_hash, last_name, first_name, email, \
             phone, mfa_enabled, totp_enabled, email_mfa_enabled, \
-            totp_secret, email_mfa_secret, mfa_method \"mfa_method: _\", recovery_codes, is_active \
+            totp_secret, email_mfa_secret, mfa_method \"mfa_method: _\", recovery_codes, is_active, break_glass, max_devices, merged_into, new_country_alert_opt_out \
             FROM \"user\" WHERE email = $1",
             email
         )
@@ -620,7 +755,7 @@ impl User {
         if let Some(id) = self.id {
             query_as!(
                 Group,
-                "SELECT id \"id?\", name FROM \"group\" JOIN group_user ON \"group\".id = group_user.group_id \
+                "SELECT id \"id?\", name, parent_id FROM \"group\" JOIN group_user ON \"group\".id = group_user.group_id \
                 WHERE group_user.user_id = $1",
                 id
             )
@@ -631,11 +766,51 @@ impl User {
         }
     }
 
+    /// Groups user belongs to directly, together with all their ancestors.
+    pub async fn effective_member_of<'e, E>(&self, executor: E) -> Result<Vec<Group>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        if let Some(id) = self.id {
+            query_as!(
+                Group,
+                "WITH RECURSIVE membership AS ( \
+                    SELECT g.id, g.name, g.parent_id FROM \"group\" g \
+                    JOIN group_user gu ON g.id = gu.group_id WHERE gu.user_id = $1 \
+                    UNION \
+                    SELECT g.id, g.name, g.parent_id FROM \"group\" g \
+                    JOIN membership m ON g.id = m.parent_id \
+                ) \
+                SELECT id \"id?\", name \"name!\", parent_id FROM membership",
+                id
+            )
+            .fetch_all(executor)
+            .await
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    pub async fn effective_member_of_names<'e, E>(
+        &self,
+        executor: E,
+    ) -> Result<Vec<String>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        Ok(self
+            .effective_member_of(executor)
+            .await?
+            .into_iter()
+            .map(|group| group.name)
+            .collect())
+    }
+
     pub async fn devices(&self, pool: &DbPool) -> Result<Vec<UserDevice>, SqlxError> {
         if let Some(id) = self.id {
             let devices = query_as!(
                 Device,
-                "SELECT device.id \"id?\", name, wireguard_pubkey, user_id, created \
+                "SELECT device.id \"id?\", name, wireguard_pubkey, user_id, created, os, os_version, client_version, hostname, machine_hash, blocked \
                 FROM device WHERE user_id = $1",
                 id
             )
@@ -808,6 +983,217 @@ impl User {
         }
         Ok(())
     }
+
+    /// Lock the user row until the end of the transaction.
+    pub async fn lock(transaction: &mut PgConnection, id: i64) -> Result<(), SqlxError> {
+        query!("SELECT id FROM \"user\" WHERE id = $1 FOR UPDATE", id)
+            .fetch_one(transaction)
+            .await?;
+        Ok(())
+    }
+
+    /// Names of devices which can't be moved to `other` user, because
+    /// the user already has devices with the same names.
+    pub async fn conflicting_device_names<'e, E>(
+        &self,
+        executor: E,
+        other_id: i64,
+    ) -> Result<Vec<String>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_scalar!(
+            "SELECT name FROM device WHERE user_id = $1 \
+            AND name IN (SELECT name FROM device WHERE user_id = $2) ORDER BY name",
+            self.id,
+            other_id
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Sever all VPN connections of this user at once, e.g. when the account is compromised.
+    /// Devices are removed from gateways of all locations they're assigned to and lose
+    /// authorization in MFA-protected ones. Unless `block` is set, they can connect again,
+    /// once gateways reload their configuration or after MFA in protected locations.
+    ///
+    /// Returns disconnected devices along with gateway events, which have to be sent once
+    /// the transaction is committed.
+    pub async fn disconnect_devices(
+        &self,
+        transaction: &mut PgConnection,
+        block: bool,
+    ) -> Result<(Vec<DisconnectedDevice>, Vec<GatewayEvent>), SqlxError> {
+        let Some(id) = self.id else {
+            return Err(SqlxError::RowNotFound);
+        };
+        let devices = query_as!(
+            Device,
+            "SELECT id \"id?\", name, wireguard_pubkey, user_id, created, os, os_version, client_version, hostname, machine_hash, blocked \
+            FROM device WHERE user_id = $1 ORDER BY id",
+            id
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+        let networks = WireguardNetwork::all(&mut *transaction).await?;
+
+        let mut disconnected = Vec::new();
+        let mut events = Vec::new();
+        for mut device in devices {
+            let Some(device_id) = device.id else {
+                continue;
+            };
+            if block {
+                device.set_blocked(&mut *transaction, true).await?;
+            }
+            let mut locations = Vec::new();
+            for network in &networks {
+                let Some(network_id) = network.id else {
+                    continue;
+                };
+                let Some(mut network_device) =
+                    WireguardNetworkDevice::find(&mut *transaction, device_id, network_id).await?
+                else {
+                    continue;
+                };
+                // preshared keys are only issued on MFA, static ones stay valid
+                if network.mfa_enabled {
+                    network_device.is_authorized = false;
+                    network_device.authorized_at = None;
+                    network_device.preshared_key = None;
+                    network_device.update(&mut *transaction).await?;
+                }
+                events.push(GatewayEvent::PeerRemoved(PeerUpdate {
+                    device: device.clone(),
+                    network_info: DeviceNetworkInfo {
+                        network_id,
+                        device_wireguard_ip: network_device.wireguard_ip,
+                        preshared_key: network_device.preshared_key,
+                        is_authorized: network_device.is_authorized,
+                    },
+                }));
+                locations.push(DisconnectedLocation {
+                    id: network_id,
+                    name: network.name.clone(),
+                });
+            }
+            disconnected.push(DisconnectedDevice {
+                id: device_id,
+                name: device.name,
+                blocked: device.blocked,
+                locations,
+            });
+        }
+
+        Ok((disconnected, events))
+    }
+
+    /// Merge this account into `target`, e.g. a local account into the one used with
+    /// external identity provider. Devices, authentication keys, YubiKeys, group memberships
+    /// and external identities are moved to `target`. This account is kept disabled and marked
+    /// as merged, so that references to it stay valid.
+    ///
+    /// Device names must not conflict, see [`User::conflicting_device_names`].
+    pub async fn merge_into(
+        &mut self,
+        transaction: &mut PgConnection,
+        target: &User,
+    ) -> Result<MergeSummary, SqlxError> {
+        let (Some(id), Some(target_id)) = (self.id, target.id) else {
+            return Err(SqlxError::RowNotFound);
+        };
+        let devices = query!(
+            "UPDATE device SET user_id = $2 WHERE user_id = $1",
+            id,
+            target_id
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+        // keys which the target already has would violate uniqueness
+        query!(
+            "DELETE FROM authentication_key k WHERE user_id = $1 AND EXISTS \
+            (SELECT 1 FROM authentication_key t WHERE t.user_id = $2 \
+            AND t.key_type = k.key_type AND t.key = k.key)",
+            id,
+            target_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+        query!(
+            "UPDATE yubikey SET user_id = $2 WHERE user_id = $1",
+            id,
+            target_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+        let authentication_keys = query!(
+            "UPDATE authentication_key SET user_id = $2 WHERE user_id = $1",
+            id,
+            target_id
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+        let groups = query!(
+            "INSERT INTO group_user (group_id, user_id) \
+            SELECT group_id, $2 FROM group_user WHERE user_id = $1 ON CONFLICT DO NOTHING",
+            id,
+            target_id
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+        query!("DELETE FROM group_user WHERE user_id = $1", id)
+            .execute(&mut *transaction)
+            .await?;
+        let external_identities = query!(
+            "UPDATE external_identity SET user_id = $2 WHERE user_id = $1",
+            id,
+            target_id
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        self.logout_all_sessions(&mut *transaction).await?;
+        self.is_active = false;
+        self.merged_into = Some(target_id);
+        self.save(&mut *transaction).await?;
+
+        Ok(MergeSummary {
+            devices,
+            authentication_keys,
+            groups,
+            external_identities,
+        })
+    }
+}
+
+/// Location a device was removed from by [`User::disconnect_devices`].
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DisconnectedLocation {
+    pub id: i64,
+    pub name: String,
+}
+
+/// Device disconnected by [`User::disconnect_devices`], with locations it was removed from.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DisconnectedDevice {
+    pub id: i64,
+    pub name: String,
+    pub blocked: bool,
+    pub locations: Vec<DisconnectedLocation>,
+}
+
+/// Number of objects moved to the surviving account by [`User::merge_into`].
+/// Groups the surviving account already belonged to are not counted.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MergeSummary {
+    pub devices: u64,
+    pub authentication_keys: u64,
+    pub groups: u64,
+    pub external_identities: u64,
 }
 
 #[cfg(test)]
@@ -898,9 +1284,49 @@ mod test {
             .await
             .unwrap());
         let codes = user.recovery_codes.clone();
-        for code in &codes {
+        for (used, code) in codes.iter().enumerate() {
             assert!(user.verify_recovery_code(&pool, code).await.unwrap());
+            assert_eq!(user.recovery_codes.len(), RECOVERY_CODES_COUNT - used - 1);
         }
         assert_eq!(user.recovery_codes.len(), 0);
+        assert_eq!(
+            user.recovery_codes_used_at(&pool).await.unwrap().len(),
+            RECOVERY_CODES_COUNT
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_regenerate_recovery_codes(pool: DbPool) {
+        let mut harry = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        harry.save(&pool).await.unwrap();
+        let old_codes = harry.get_recovery_codes(&pool).await.unwrap().unwrap();
+        assert!(harry
+            .verify_recovery_code(&pool, &old_codes[0])
+            .await
+            .unwrap());
+
+        let new_codes = harry.regenerate_recovery_codes(&pool).await.unwrap();
+        assert_eq!(new_codes.len(), RECOVERY_CODES_COUNT);
+        assert!(harry
+            .recovery_codes_used_at(&pool)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut user = User::find_by_id(&pool, harry.id.unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.recovery_codes, new_codes);
+        for code in &old_codes {
+            assert!(!user.verify_recovery_code(&pool, code).await.unwrap());
+        }
     }
 }
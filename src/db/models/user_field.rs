@@ -0,0 +1,292 @@
+use chrono::NaiveDate;
+use model_derive::Model;
+use sqlx::{query, query_as, query_scalar, Error as SqlxError, PgExecutor, Type};
+use utoipa::ToSchema;
+
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Type, Debug, ToSchema)]
+#[sqlx(type_name = "user_field_type", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum UserFieldType {
+    Text,
+    Number,
+    Date,
+    Choice,
+}
+
+/// Custom user profile field defined by administrators, e.g. cost center or office location.
+#[derive(Clone, Debug, Deserialize, Model, Serialize, ToSchema)]
+#[table(user_field_definition)]
+pub struct UserFieldDefinition {
+    #[serde(default)]
+    pub id: Option<i64>,
+    pub name: String,
+    #[model(enum)]
+    pub field_type: UserFieldType,
+    // allowed values of `choice` fields
+    #[model(ref)]
+    #[serde(default)]
+    pub options: Vec<String>,
+    #[serde(default)]
+    pub required: bool,
+    // LDAP attribute imported into this field
+    #[serde(default)]
+    pub ldap_attr: Option<String>,
+}
+
+/// Value of a custom field set for a user.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize, ToSchema)]
+pub struct UserFieldValue {
+    pub definition_id: i64,
+    pub name: String,
+    pub value: String,
+}
+
+impl UserFieldDefinition {
+    pub async fn find_by_name<'e, E>(executor: E, name: &str) -> Result<Option<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id \"id?\", name, field_type \"field_type: _\", options, required, ldap_attr \
+            FROM user_field_definition WHERE name = $1",
+            name
+        )
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// Definitions with LDAP attribute mapping configured.
+    pub async fn ldap_mapped<'e, E>(executor: E) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id \"id?\", name, field_type \"field_type: _\", options, required, ldap_attr \
+            FROM user_field_definition WHERE ldap_attr IS NOT NULL ORDER BY name"
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Names of required fields which have no value set for given user.
+    pub async fn missing_required<'e, E>(
+        executor: E,
+        user_id: i64,
+    ) -> Result<Vec<String>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_scalar!(
+            "SELECT d.name FROM user_field_definition d \
+            LEFT JOIN user_field_value v ON v.definition_id = d.id AND v.user_id = $1 \
+            WHERE d.required AND v.value IS NULL ORDER BY d.name",
+            user_id
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Number of users with a value set for this field.
+    pub async fn value_count<'e, E>(&self, executor: E) -> Result<i64, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_scalar!(
+            "SELECT count(*) \"count!\" FROM user_field_value WHERE definition_id = $1",
+            self.id
+        )
+        .fetch_one(executor)
+        .await
+    }
+
+    /// Values currently set for this field.
+    pub async fn values<'e, E>(&self, executor: E) -> Result<Vec<String>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_scalar!(
+            "SELECT value FROM user_field_value WHERE definition_id = $1",
+            self.id
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Check if the definition itself is consistent.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("Field name can't be empty".into());
+        }
+        match self.field_type {
+            UserFieldType::Choice => {
+                if self.options.is_empty() {
+                    return Err(format!("Choice field {} requires options", self.name));
+                }
+                for (index, option) in self.options.iter().enumerate() {
+                    if option.trim().is_empty() {
+                        return Err(format!("Field {} has an empty option", self.name));
+                    }
+                    if self.options[..index].contains(option) {
+                        return Err(format!("Field {} has duplicate option {option}", self.name));
+                    }
+                }
+            }
+            _ => {
+                if !self.options.is_empty() {
+                    return Err(format!(
+                        "Only choice fields can have options ({})",
+                        self.name
+                    ));
+                }
+            }
+        }
+        if let Some(attr) = &self.ldap_attr {
+            if attr.trim().is_empty() {
+                return Err(format!("Field {} has an empty LDAP attribute", self.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if the value matches declared field type.
+    pub fn validate_value(&self, value: &str) -> Result<(), String> {
+        let valid = match self.field_type {
+            UserFieldType::Text => !value.trim().is_empty(),
+            UserFieldType::Number => value.parse::<f64>().is_ok_and(f64::is_finite),
+            UserFieldType::Date => NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok(),
+            UserFieldType::Choice => self.options.iter().any(|option| option == value),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(match self.field_type {
+                UserFieldType::Text => format!("Field {} can't be empty", self.name),
+                UserFieldType::Number => format!("Field {} requires a number", self.name),
+                UserFieldType::Date => {
+                    format!("Field {} requires a date in YYYY-MM-DD format", self.name)
+                }
+                UserFieldType::Choice => format!(
+                    "Field {} requires one of: {}",
+                    self.name,
+                    self.options.join(", ")
+                ),
+            })
+        }
+    }
+}
+
+impl UserFieldValue {
+    /// Custom field values of given user, ordered by field name.
+    pub async fn all_for_user<'e, E>(executor: E, user_id: i64) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT v.definition_id, d.name, v.value FROM user_field_value v \
+            JOIN user_field_definition d ON d.id = v.definition_id \
+            WHERE v.user_id = $1 ORDER BY d.name",
+            user_id
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Set field value for a user. Value has to be validated by the caller.
+    pub async fn set<'e, E>(
+        executor: E,
+        user_id: i64,
+        definition_id: i64,
+        value: &str,
+    ) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "INSERT INTO user_field_value (user_id, definition_id, value) VALUES ($1, $2, $3) \
+            ON CONFLICT (user_id, definition_id) DO UPDATE SET value = $3",
+            user_id,
+            definition_id,
+            value
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn remove<'e, E>(
+        executor: E,
+        user_id: i64,
+        definition_id: i64,
+    ) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "DELETE FROM user_field_value WHERE user_id = $1 AND definition_id = $2",
+            user_id,
+            definition_id
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn definition(field_type: UserFieldType, options: &[&str]) -> UserFieldDefinition {
+        UserFieldDefinition {
+            id: None,
+            name: "field".into(),
+            field_type,
+            options: options.iter().map(ToString::to_string).collect(),
+            required: false,
+            ldap_attr: None,
+        }
+    }
+
+    #[test]
+    fn test_validate_definition() {
+        assert!(definition(UserFieldType::Text, &[]).validate().is_ok());
+        assert!(definition(UserFieldType::Choice, &["a", "b"])
+            .validate()
+            .is_ok());
+        assert!(definition(UserFieldType::Choice, &[]).validate().is_err());
+        assert!(definition(UserFieldType::Choice, &["a", "a"])
+            .validate()
+            .is_err());
+        assert!(definition(UserFieldType::Number, &["1"])
+            .validate()
+            .is_err());
+        let mut unnamed = definition(UserFieldType::Text, &[]);
+        unnamed.name = " ".into();
+        assert!(unnamed.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_value() {
+        let text = definition(UserFieldType::Text, &[]);
+        assert!(text.validate_value("Warsaw").is_ok());
+        assert!(text.validate_value("  ").is_err());
+
+        let number = definition(UserFieldType::Number, &[]);
+        assert!(number.validate_value("1200").is_ok());
+        assert!(number.validate_value("-3.5").is_ok());
+        assert!(number.validate_value("12a").is_err());
+        assert!(number.validate_value("NaN").is_err());
+
+        let date = definition(UserFieldType::Date, &[]);
+        assert!(date.validate_value("2024-07-03").is_ok());
+        assert!(date.validate_value("2024-02-30").is_err());
+        assert!(date.validate_value("03.07.2024").is_err());
+
+        let choice = definition(UserFieldType::Choice, &["Warsaw", "Berlin"]);
+        assert!(choice.validate_value("Berlin").is_ok());
+        assert!(choice.validate_value("berlin").is_err());
+    }
+}
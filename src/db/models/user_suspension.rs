@@ -0,0 +1,82 @@
+use chrono::NaiveDateTime;
+use sqlx::{query, query_as, Error as SqlxError, PgExecutor};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Suspension of a user disabled together with other members of a group.
+///
+/// Suspended users are inactive like disabled ones, but are reactivated automatically
+/// at `reactivate_at`, if set. Enabling the user by hand lifts the suspension.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct UserSuspension {
+    pub user_id: i64,
+    pub batch_id: Uuid,
+    pub group_name: String,
+    pub suspended_by: Option<i64>,
+    pub suspended_at: NaiveDateTime,
+    pub reactivate_at: Option<NaiveDateTime>,
+}
+
+impl UserSuspension {
+    pub async fn save<'e, E>(&self, executor: E) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "INSERT INTO user_suspension \
+            (user_id, batch_id, group_name, suspended_by, suspended_at, reactivate_at) \
+            VALUES ($1, $2, $3, $4, $5, $6) \
+            ON CONFLICT (user_id) DO UPDATE SET batch_id = $2, group_name = $3, \
+            suspended_by = $4, suspended_at = $5, reactivate_at = $6",
+            self.user_id,
+            self.batch_id,
+            self.group_name,
+            self.suspended_by,
+            self.suspended_at,
+            self.reactivate_at
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn find_by_user<'e, E>(executor: E, user_id: i64) -> Result<Option<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT user_id, batch_id, group_name, suspended_by, suspended_at, reactivate_at \
+            FROM user_suspension WHERE user_id = $1",
+            user_id
+        )
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// Suspensions which should be lifted at `now`.
+    pub async fn due<'e, E>(executor: E, now: NaiveDateTime) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT user_id, batch_id, group_name, suspended_by, suspended_at, reactivate_at \
+            FROM user_suspension WHERE reactivate_at <= $1 ORDER BY reactivate_at",
+            now
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Lift suspension of a user; returns `false` if the user wasn't suspended.
+    pub async fn delete_for_user<'e, E>(executor: E, user_id: i64) -> Result<bool, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let result = query!("DELETE FROM user_suspension WHERE user_id = $1", user_id)
+            .execute(executor)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
@@ -1,7 +1,7 @@
 use std::{
     collections::HashMap,
     fmt::{Debug, Display, Formatter},
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     str::FromStr,
 };
 
@@ -10,12 +10,16 @@ use chrono::{Duration, NaiveDateTime, Utc};
 use ipnetwork::{IpNetwork, IpNetworkError, NetworkSize};
 use model_derive::Model;
 use rand_core::OsRng;
-use sqlx::{query_as, query_scalar, Error as SqlxError, FromRow, PgConnection, PgExecutor};
+use sqlx::{query, query_as, query_scalar, Error as SqlxError, FromRow, PgConnection, PgExecutor};
 use thiserror::Error;
+use utoipa::ToSchema;
 use x25519_dalek::{PublicKey, StaticSecret};
 
 use super::{
-    device::{Device, DeviceError, DeviceInfo, DeviceNetworkInfo, WireguardNetworkDevice},
+    device::{
+        parse_preshared_key, Device, DeviceError, DeviceInfo, DeviceNetworkInfo, PubkeyError,
+        WireguardNetworkDevice, WireguardPubkey,
+    },
     error::ModelError,
     DbPool, User, UserInfo,
 };
@@ -23,18 +27,27 @@ use crate::{
     appstate::AppState,
     grpc::{gateway::Peer, GatewayState},
     wg_config::ImportedDevice,
+    wireguard_psk_rotation::key_fingerprint,
 };
 
 pub const DEFAULT_KEEPALIVE_INTERVAL: i32 = 25;
 pub const DEFAULT_DISCONNECT_THRESHOLD: i32 = 180;
+// bounds of interface MTU in client configs
+pub const MIN_MTU_IPV4: i32 = 576;
+pub const MIN_MTU_IPV6: i32 = 1280;
+pub const MAX_MTU: i32 = 1500;
 
 // Used in process of importing network from wireguard config
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct MappedDevice {
     pub user_id: i64,
     pub name: String,
     pub wireguard_pubkey: String,
+    #[schema(value_type = String)]
     pub wireguard_ip: IpAddr,
+    // preshared key provided by the operator, pinned in the imported network
+    #[serde(default, skip_serializing)]
+    pub preshared_key: Option<String>,
 }
 
 pub static WIREGUARD_MAX_HANDSHAKE_MINUTES: i64 = 5;
@@ -56,23 +69,165 @@ impl DateTimeAggregation {
     }
 }
 
+/// Address range of a network overlapping with a range of another network.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize, ToSchema)]
+pub struct NetworkOverlap {
+    // not set for networks which are being created
+    pub network_id: Option<i64>,
+    pub network: String,
+    #[schema(value_type = String)]
+    pub range: IpNetwork,
+    pub other_network_id: Option<i64>,
+    pub other_network: String,
+    #[schema(value_type = String)]
+    pub other_range: IpNetwork,
+}
+
+impl Display for NetworkOverlap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}) overlaps with {} ({})",
+            self.range, self.network, self.other_range, self.other_network
+        )
+    }
+}
+
+// CIDR ranges are either disjoint or one contains the other;
+// ranges of different IP versions never overlap
+fn ranges_overlap(range: &IpNetwork, other: &IpNetwork) -> bool {
+    range.contains(other.network()) || other.contains(range.network())
+}
+
+/// Entry of an address list which couldn't be parsed
+#[derive(Debug, Serialize)]
+pub struct InvalidAddress {
+    pub entry: String,
+    pub error: String,
+}
+
+/// Parse comma-separated list of networks, reporting every invalid entry.
+/// Empty entries, e.g. after a trailing comma, are skipped.
+pub fn parse_networks(list: &str) -> Result<Vec<IpNetwork>, Vec<InvalidAddress>> {
+    let mut networks = Vec::new();
+    let mut errors = Vec::new();
+    for entry in list
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        match entry.parse::<IpNetwork>() {
+            Ok(network) => networks.push(network),
+            Err(err) => errors.push(InvalidAddress {
+                entry: entry.into(),
+                error: err.to_string(),
+            }),
+        }
+    }
+    if errors.is_empty() {
+        Ok(networks)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Canonical form of a list of routed networks: network addresses without host bits,
+/// without duplicates and without ranges contained in other entries. Order of the
+/// remaining entries is preserved.
+///
+/// Keep in sync with the `canonical_allowed_ips` migration.
+#[must_use]
+pub fn canonical_networks(networks: &[IpNetwork]) -> Vec<IpNetwork> {
+    let mut normalized: Vec<IpNetwork> = Vec::with_capacity(networks.len());
+    for network in networks {
+        let network = IpNetwork::new(network.network(), network.prefix()).unwrap_or(*network);
+        if !normalized.contains(&network) {
+            normalized.push(network);
+        }
+    }
+    normalized
+        .iter()
+        .filter(|network| {
+            !normalized
+                .iter()
+                .any(|other| other.prefix() < network.prefix() && other.contains(network.network()))
+        })
+        .copied()
+        .collect()
+}
+
+/// Change of a single peer in a single network
+#[derive(Clone, Debug)]
+pub struct PeerUpdate {
+    pub device: Device,
+    pub network_info: DeviceNetworkInfo,
+}
+
+impl PeerUpdate {
+    #[must_use]
+    pub fn network_id(&self) -> i64 {
+        self.network_info.network_id
+    }
+}
+
+/// Events broadcast to gateway update streams
+///
+/// Peer events carry only the affected peer, so gateways can apply them incrementally.
+/// Full network configuration is sent only when the interface itself changes;
+/// gateways also fetch it on (re)connect.
+/// Events are delivered in the order they were sent, so producers have to emit
+/// them in the order changes should be applied.
 #[derive(Clone, Debug)]
 pub enum GatewayEvent {
     NetworkCreated(i64, WireguardNetwork),
     NetworkModified(i64, WireguardNetwork, Vec<Peer>),
     NetworkDeleted(i64, String),
-    DeviceCreated(DeviceInfo),
-    DeviceModified(DeviceInfo),
-    DeviceDeleted(DeviceInfo),
+    PeerAdded(PeerUpdate),
+    /// Peer address or key changed; holds previous public key if it was replaced
+    PeerModified(PeerUpdate, Option<String>),
+    PeerRemoved(PeerUpdate),
+    /// Complete configuration freshly loaded on admin request; gateways replace
+    /// their whole peer set with it, whatever state they are in
+    FullResync(i64, WireguardNetwork, Vec<Peer>),
+}
+
+impl GatewayEvent {
+    /// Add device as a peer in all networks it belongs to
+    #[must_use]
+    pub fn peers_added(device_info: DeviceInfo) -> Vec<Self> {
+        device_info
+            .into_peer_updates()
+            .map(Self::PeerAdded)
+            .collect()
+    }
+
+    /// Update device peer in all networks it belongs to
+    #[must_use]
+    pub fn peers_modified(device_info: DeviceInfo, previous_pubkey: Option<String>) -> Vec<Self> {
+        device_info
+            .into_peer_updates()
+            .map(|peer| Self::PeerModified(peer, previous_pubkey.clone()))
+            .collect()
+    }
+
+    /// Remove device peer from all networks it belongs to
+    #[must_use]
+    pub fn peers_removed(device_info: DeviceInfo) -> Vec<Self> {
+        device_info
+            .into_peer_updates()
+            .map(Self::PeerRemoved)
+            .collect()
+    }
 }
 
 /// Stores configuration required to setup a WireGuard network
-#[derive(Clone, Debug, Model, Deserialize, Serialize, PartialEq)]
+#[derive(Clone, Debug, Model, Deserialize, Serialize, PartialEq, ToSchema)]
 #[table(wireguard_network)]
 pub struct WireguardNetwork {
     pub id: Option<i64>,
     pub name: String,
     #[model(enum)]
+    #[schema(value_type = String)]
     pub address: IpNetwork,
     pub port: i32,
     pub pubkey: String,
@@ -81,11 +236,56 @@ pub struct WireguardNetwork {
     pub endpoint: String,
     pub dns: Option<String>,
     #[model(ref)]
+    #[schema(value_type = Vec<String>)]
     pub allowed_ips: Vec<IpNetwork>,
     pub connected_at: Option<NaiveDateTime>,
     pub mfa_enabled: bool,
     pub keepalive_interval: i32,
     pub peer_disconnect_threshold: i32,
+    // archived networks keep their data, but are not served to gateways and clients
+    #[serde(default)]
+    pub archived: bool,
+    // preshared keys of devices are rotated after this many days, if set
+    #[serde(default)]
+    pub psk_rotation_days: Option<i32>,
+    // gateways may only connect from these addresses; empty means no restriction
+    #[model(ref)]
+    #[serde(default)]
+    #[schema(value_type = Vec<String>)]
+    pub gateway_allowed_ips: Vec<IpNetwork>,
+    // interface MTU written to client configs; WireGuard picks one if not set
+    #[serde(default)]
+    pub mtu: Option<i32>,
+    // devices get DNS records in this zone, if set and DNS publishing is configured
+    #[serde(default)]
+    pub dns_zone: Option<String>,
+    // default per-peer bandwidth limits in kbit/s, enforced by gateways
+    #[serde(default)]
+    pub upload_limit_kbps: Option<i32>,
+    #[serde(default)]
+    pub download_limit_kbps: Option<i32>,
+    // lowercase operating systems of devices allowed in the location; empty means any
+    #[model(ref)]
+    #[serde(default)]
+    pub allowed_platforms: Vec<String>,
+    // whether devices which haven't reported their platform are excluded by `allowed_platforms`
+    #[serde(default)]
+    pub deny_unknown_platform: bool,
+    // extra destinations clients route through the tunnel; not used for gateway peers
+    #[model(ref)]
+    #[serde(default)]
+    #[schema(value_type = Vec<String>)]
+    pub client_routes: Vec<IpNetwork>,
+    // gateways masquerade client traffic, except towards `nat_exempt_networks`
+    #[serde(default)]
+    pub masquerade_enabled: bool,
+    #[model(ref)]
+    #[serde(default)]
+    #[schema(value_type = Vec<String>)]
+    pub nat_exempt_networks: Vec<IpNetwork>,
+    // devices can have keypairs generated by the server, which escrows the private key
+    #[serde(default)]
+    pub server_managed_keys: bool,
 }
 
 pub struct WireguardKey {
@@ -115,9 +315,25 @@ pub enum WireguardNetworkError {
     #[error("Unexpected error: {0}")]
     Unexpected(String),
     #[error("Invalid device pubkey: {0}")]
-    InvalidDevicePubkey(String),
+    InvalidDevicePubkey(#[from] PubkeyError),
+    #[error("Device {0} pubkey is already used by device {1}")]
+    DuplicateDevicePubkey(String, String),
     #[error("Device {0} not allowed in network")]
     DeviceNotAllowed(String),
+    #[error("Preshared key of device {0} must be 32 bytes of base64")]
+    InvalidPresharedKey(String),
+    #[error("Preshared keys in MFA-protected location {0} are renewed on every login")]
+    PresharedKeyNotAllowed(String),
+    #[error(
+        "Device {device} running {platform} is not allowed in location {network}, \
+        allowed platforms: {allowed}"
+    )]
+    PlatformNotAllowed {
+        device: String,
+        platform: String,
+        network: String,
+        allowed: String,
+    },
     #[error("Device error")]
     DeviceError(#[from] DeviceError),
 }
@@ -145,11 +361,24 @@ impl WireguardNetwork {
             prvkey: BASE64_STANDARD.encode(prvkey.to_bytes()),
             endpoint,
             dns,
-            allowed_ips,
+            allowed_ips: canonical_networks(&allowed_ips),
             connected_at: None,
             mfa_enabled,
             keepalive_interval,
             peer_disconnect_threshold,
+            archived: false,
+            psk_rotation_days: None,
+            gateway_allowed_ips: Vec::new(),
+            mtu: None,
+            dns_zone: None,
+            upload_limit_kbps: None,
+            download_limit_kbps: None,
+            allowed_platforms: Vec::new(),
+            deny_unknown_platform: false,
+            client_routes: Vec::new(),
+            masquerade_enabled: false,
+            nat_exempt_networks: Vec::new(),
+            server_managed_keys: false,
         })
     }
 
@@ -158,6 +387,158 @@ impl WireguardNetwork {
         Ok(id)
     }
 
+    /// Endpoint in `host:port` format used in client configs.
+    /// IPv6 addresses have to be enclosed in brackets.
+    #[must_use]
+    pub fn endpoint_with_port(&self) -> String {
+        match self.endpoint.parse::<Ipv6Addr>() {
+            Ok(address) => format!("[{address}]:{}", self.port),
+            Err(_) => format!("{}:{}", self.endpoint, self.port),
+        }
+    }
+
+    /// Check if a gateway connecting from `address` is allowed to serve this network.
+    /// Empty allowlist means gateways can connect from any address.
+    #[must_use]
+    pub fn gateway_source_allowed(&self, address: Option<IpAddr>) -> bool {
+        if self.gateway_allowed_ips.is_empty() {
+            return true;
+        }
+        // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+        address
+            .map(|address| address.to_canonical())
+            .is_some_and(|address| {
+                self.gateway_allowed_ips
+                    .iter()
+                    .any(|network| network.contains(address))
+            })
+    }
+
+    /// Check if devices running operating system `os` may use this network.
+    /// Empty allowlist means any platform is allowed. Devices which haven't reported
+    /// their platform are allowed unless `deny_unknown_platform` is set.
+    #[must_use]
+    pub fn platform_allowed(&self, os: Option<&str>) -> bool {
+        if self.allowed_platforms.is_empty() {
+            return true;
+        }
+        match os {
+            Some(os) => self
+                .allowed_platforms
+                .iter()
+                .any(|platform| platform.eq_ignore_ascii_case(os)),
+            None => !self.deny_unknown_platform,
+        }
+    }
+
+    /// Reject devices excluded by the platform policy, naming the policy in the error.
+    pub fn ensure_platform_allowed(&self, device: &Device) -> Result<(), WireguardNetworkError> {
+        if self.platform_allowed(device.os.as_deref()) {
+            return Ok(());
+        }
+        Err(WireguardNetworkError::PlatformNotAllowed {
+            device: device.name.clone(),
+            platform: device
+                .os
+                .clone()
+                .unwrap_or_else(|| "unknown platform".into()),
+            network: self.name.clone(),
+            allowed: self.allowed_platforms.join(", "),
+        })
+    }
+
+    /// Address ranges used by the network: its own subnet and routed `allowed_ips`,
+    /// normalized to network addresses.
+    #[must_use]
+    pub fn address_ranges(&self) -> Vec<IpNetwork> {
+        let mut ranges: Vec<IpNetwork> = Vec::new();
+        for range in std::iter::once(&self.address).chain(&self.allowed_ips) {
+            let Ok(range) = IpNetwork::new(range.network(), range.prefix()) else {
+                continue;
+            };
+            if !ranges.contains(&range) {
+                ranges.push(range);
+            }
+        }
+        ranges
+    }
+
+    /// Destinations clients route through the tunnel: `allowed_ips` followed by
+    /// `client_routes`, in canonical form.
+    #[must_use]
+    pub fn client_allowed_ips(&self) -> Vec<IpNetwork> {
+        let networks: Vec<IpNetwork> = self
+            .allowed_ips
+            .iter()
+            .chain(&self.client_routes)
+            .copied()
+            .collect();
+        canonical_networks(&networks)
+    }
+
+    /// Client routes overlapping with the network's own subnet. Such routes are allowed,
+    /// but usually a mistake, as the subnet is routed through the tunnel anyway.
+    #[must_use]
+    pub fn client_route_overlaps(&self) -> Vec<IpNetwork> {
+        self.client_routes
+            .iter()
+            .filter(|route| ranges_overlap(route, &self.address))
+            .copied()
+            .collect()
+    }
+
+    /// Ranges of this network overlapping with ranges of `other` network.
+    #[must_use]
+    pub fn overlaps_with(&self, other: &Self) -> Vec<NetworkOverlap> {
+        let other_ranges = other.address_ranges();
+        let mut overlaps = Vec::new();
+        for range in self.address_ranges() {
+            for other_range in &other_ranges {
+                if ranges_overlap(&range, other_range) {
+                    overlaps.push(NetworkOverlap {
+                        network_id: self.id,
+                        network: self.name.clone(),
+                        range,
+                        other_network_id: other.id,
+                        other_network: other.name.clone(),
+                        other_range: *other_range,
+                    });
+                }
+            }
+        }
+        overlaps
+    }
+
+    /// Find ranges overlapping with other non-archived networks.
+    pub async fn find_overlaps<'e, E>(&self, executor: E) -> Result<Vec<NetworkOverlap>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let networks = Self::all_active(executor).await?;
+        Ok(networks
+            .iter()
+            .filter(|other| self.id.is_none() || other.id != self.id)
+            .flat_map(|other| self.overlaps_with(other))
+            .collect())
+    }
+
+    /// Find all overlapping ranges between non-archived networks, each pair reported once.
+    pub async fn all_overlaps<'e, E>(executor: E) -> Result<Vec<NetworkOverlap>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let networks = Self::all_active(executor).await?;
+        Ok(networks
+            .iter()
+            .enumerate()
+            .flat_map(|(index, network)| {
+                networks[index + 1..]
+                    .iter()
+                    .flat_map(|other| network.overlaps_with(other))
+            })
+            .collect())
+    }
+
     pub async fn find_by_name<'e, E>(
         executor: E,
         name: &str,
@@ -169,7 +550,10 @@ impl WireguardNetwork {
             WireguardNetwork,
             "SELECT \
                 id as \"id?\", name, address, port, pubkey, prvkey, endpoint, dns, allowed_ips, \
-                connected_at, mfa_enabled, keepalive_interval, peer_disconnect_threshold \
+                connected_at, mfa_enabled, keepalive_interval, peer_disconnect_threshold, archived, \
+                psk_rotation_days, gateway_allowed_ips, mtu, dns_zone, upload_limit_kbps, download_limit_kbps, \
+                allowed_platforms, deny_unknown_platform, client_routes, masquerade_enabled, \
+                nat_exempt_networks, server_managed_keys \
             FROM wireguard_network WHERE name = $1",
             name
         )
@@ -183,11 +567,49 @@ impl WireguardNetwork {
         Ok(Some(networks))
     }
 
-    // run sync_allowed_devices on all wireguard networks
+    /// Fetch all networks which are not archived.
+    pub async fn all_active<'e, E>(executor: E) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            WireguardNetwork,
+            "SELECT \
+                id as \"id?\", name, address, port, pubkey, prvkey, endpoint, dns, allowed_ips, \
+                connected_at, mfa_enabled, keepalive_interval, peer_disconnect_threshold, archived, \
+                psk_rotation_days, gateway_allowed_ips, mtu, dns_zone, upload_limit_kbps, download_limit_kbps, \
+                allowed_platforms, deny_unknown_platform, client_routes, masquerade_enabled, \
+                nat_exempt_networks, server_managed_keys \
+            FROM wireguard_network WHERE NOT archived ORDER BY id",
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Fetch all archived networks.
+    pub async fn all_archived<'e, E>(executor: E) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            WireguardNetwork,
+            "SELECT \
+                id as \"id?\", name, address, port, pubkey, prvkey, endpoint, dns, allowed_ips, \
+                connected_at, mfa_enabled, keepalive_interval, peer_disconnect_threshold, archived, \
+                psk_rotation_days, gateway_allowed_ips, mtu, dns_zone, upload_limit_kbps, download_limit_kbps, \
+                allowed_platforms, deny_unknown_platform, client_routes, masquerade_enabled, \
+                nat_exempt_networks, server_managed_keys \
+            FROM wireguard_network WHERE archived ORDER BY id",
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    // run sync_allowed_devices on all active wireguard networks
     pub async fn sync_all_networks(app: &AppState) -> Result<(), WireguardNetworkError> {
         info!("Syncing allowed devices for all WireGuard locations");
         let mut transaction = app.pool.begin().await?;
-        let networks = Self::all(&mut *transaction).await?;
+        let networks = Self::all_active(&mut *transaction).await?;
         for network in networks {
             let gateway_events = network.sync_allowed_devices(&mut transaction, None).await?;
             app.send_multiple_wireguard_events(gateway_events);
@@ -196,6 +618,27 @@ impl WireguardNetwork {
         Ok(())
     }
 
+    /// Sync allowed devices of all active locations within `transaction`, returning at most
+    /// one gateway event per location. Locations with more than one changed peer are sent
+    /// their full configuration instead of separate peer updates.
+    pub async fn sync_all_networks_consolidated(
+        transaction: &mut PgConnection,
+    ) -> Result<Vec<GatewayEvent>, WireguardNetworkError> {
+        let mut events = Vec::new();
+        for network in Self::all_active(&mut *transaction).await? {
+            let mut network_events = network.sync_allowed_devices(transaction, None).await?;
+            match (network_events.len(), network.id) {
+                (0 | 1, _) | (_, None) => events.append(&mut network_events),
+                (count, Some(network_id)) => {
+                    debug!("Replacing {count} peer updates with full configuration of {network}");
+                    let peers = network.get_peers(&mut *transaction).await?;
+                    events.push(GatewayEvent::NetworkModified(network_id, network, peers));
+                }
+            }
+        }
+        Ok(events)
+    }
+
     /// Return number of devices that use this network.
     async fn device_count(
         &self,
@@ -226,6 +669,23 @@ impl WireguardNetwork {
         Ok(())
     }
 
+    /// Check if changes compared to `previous` affect gateway interface configuration
+    /// or the set of peers it should serve, so that a full configuration has to be sent
+    /// instead of separate peer updates.
+    #[must_use]
+    pub fn requires_gateway_resync(&self, previous: &Self) -> bool {
+        self.name != previous.name
+            || self.address != previous.address
+            || self.port != previous.port
+            || self.prvkey != previous.prvkey
+            || self.mfa_enabled != previous.mfa_enabled
+            || self.keepalive_interval != previous.keepalive_interval
+            || self.upload_limit_kbps != previous.upload_limit_kbps
+            || self.download_limit_kbps != previous.download_limit_kbps
+            || self.masquerade_enabled != previous.masquerade_enabled
+            || self.nat_exempt_networks != previous.nat_exempt_networks
+    }
+
     /// Utility method to create WireGuard keypair
     #[must_use]
     pub fn genkey() -> WireguardKey {
@@ -308,29 +768,38 @@ impl WireguardNetwork {
         Ok(())
     }
 
-    /// Get a list of all devices belonging to users in allowed groups.
+    /// Get a list of all devices belonging to users in allowed groups,
+    /// running platforms allowed in the network.
     /// Admin users should always be allowed to access a network.
+    /// Guests are only allowed in the network their active guest access is for.
     async fn get_allowed_devices(
         &self,
         transaction: &mut PgConnection,
     ) -> Result<Vec<Device>, ModelError> {
         debug!("Fetching all allowed devices for network {}", self);
-        let devices = match self
+        let mut devices = match self
             .get_allowed_groups(&mut *transaction)
             .await? {
-            // devices need to be filtered by allowed group
+            // devices need to be filtered by allowed group, members of subgroups are allowed too
             Some(allowed_groups) => {
                 query_as!(
                     Device,
-                    "SELECT DISTINCT ON (d.id) d.id as \"id?\", d.name, d.wireguard_pubkey, d.user_id, d.created \
+                    "WITH RECURSIVE allowed AS ( \
+                        SELECT id FROM \"group\" WHERE name IN (SELECT * FROM UNNEST($1::text[])) \
+                        UNION \
+                        SELECT g.id FROM \"group\" g JOIN allowed a ON g.parent_id = a.id \
+                    ) \
+                    SELECT DISTINCT ON (d.id) d.id as \"id?\", d.name, d.wireguard_pubkey, d.user_id, d.created, d.os, d.os_version, d.client_version, d.hostname, d.machine_hash, d.blocked \
                     FROM device d \
                     JOIN \"user\" u ON d.user_id = u.id \
                     JOIN group_user gu ON u.id = gu.user_id \
-                    JOIN \"group\" g ON gu.group_id = g.id \
-                    WHERE g.\"name\" IN (SELECT * FROM UNNEST($1::text[]))
+                    WHERE gu.group_id IN (SELECT id FROM allowed)
                     AND u.is_active = true
+                    AND NOT EXISTS (SELECT 1 FROM guest_access ga \
+                        WHERE ga.user_id = u.id AND ga.ended_at IS NULL AND ga.network_id <> $2) \
                     ORDER BY d.id ASC",
-                    &allowed_groups
+                    &allowed_groups,
+                    self.id
                 )
                 .fetch_all(&mut *transaction)
                 .await?
@@ -339,14 +808,18 @@ impl WireguardNetwork {
             None => {
                 query_as!(
                     Device,
-                    "SELECT d.id as \"id?\", d.name, d.wireguard_pubkey, d.user_id, d.created \
+                    "SELECT d.id as \"id?\", d.name, d.wireguard_pubkey, d.user_id, d.created, d.os, d.os_version, d.client_version, d.hostname, d.machine_hash, d.blocked \
                     FROM device d \
                     JOIN \"user\" u ON d.user_id = u.id \
                     WHERE u.is_active = true \
-                    ORDER BY d.id ASC"
+                    AND NOT EXISTS (SELECT 1 FROM guest_access ga \
+                        WHERE ga.user_id = u.id AND ga.ended_at IS NULL AND ga.network_id <> $1) \
+                    ORDER BY d.id ASC",
+                    self.id
                 ).fetch_all(&mut *transaction).await?
             }
         };
+        devices.retain(|device| self.platform_allowed(device.os.as_deref()));
 
         Ok(devices)
     }
@@ -378,6 +851,10 @@ impl WireguardNetwork {
         reserved_ips: Option<&[IpAddr]>,
     ) -> Result<WireguardNetworkDevice, WireguardNetworkError> {
         info!("Assigning IP in network {self} for {device}");
+        if let Err(err) = self.ensure_platform_allowed(device) {
+            warn!("{err}");
+            return Err(err);
+        }
         let allowed_devices = self.get_allowed_devices(&mut *transaction).await?;
         let allowed_device_ids: Vec<i64> =
             allowed_devices.iter().filter_map(|dev| dev.id).collect();
@@ -433,15 +910,18 @@ impl WireguardNetwork {
                     let wireguard_network_device = device
                         .assign_network_ip(&mut *transaction, self, reserved_ips)
                         .await?;
-                    events.push(GatewayEvent::DeviceModified(DeviceInfo {
-                        device,
-                        network_info: vec![DeviceNetworkInfo {
-                            network_id,
-                            device_wireguard_ip: wireguard_network_device.wireguard_ip,
-                            preshared_key: wireguard_network_device.preshared_key,
-                            is_authorized: wireguard_network_device.is_authorized,
-                        }],
-                    }));
+                    events.push(GatewayEvent::PeerModified(
+                        PeerUpdate {
+                            device,
+                            network_info: DeviceNetworkInfo {
+                                network_id,
+                                device_wireguard_ip: wireguard_network_device.wireguard_ip,
+                                preshared_key: wireguard_network_device.preshared_key,
+                                is_authorized: wireguard_network_device.is_authorized,
+                            },
+                        },
+                        None,
+                    ));
                 }
             // device is no longer allowed
             } else {
@@ -453,14 +933,14 @@ impl WireguardNetwork {
                 if let Some(device) =
                     Device::find_by_id(&mut *transaction, device_network_config.device_id).await?
                 {
-                    events.push(GatewayEvent::DeviceDeleted(DeviceInfo {
+                    events.push(GatewayEvent::PeerRemoved(PeerUpdate {
                         device,
-                        network_info: vec![DeviceNetworkInfo {
+                        network_info: DeviceNetworkInfo {
                             network_id,
                             device_wireguard_ip: device_network_config.wireguard_ip,
                             preshared_key: device_network_config.preshared_key,
                             is_authorized: device_network_config.is_authorized,
-                        }],
+                        },
                     }));
                 } else {
                     let msg = format!("Device {} does not exist", device_network_config.device_id);
@@ -475,14 +955,14 @@ impl WireguardNetwork {
             let wireguard_network_device = device
                 .assign_network_ip(&mut *transaction, self, reserved_ips)
                 .await?;
-            events.push(GatewayEvent::DeviceCreated(DeviceInfo {
+            events.push(GatewayEvent::PeerAdded(PeerUpdate {
                 device,
-                network_info: vec![DeviceNetworkInfo {
+                network_info: DeviceNetworkInfo {
                     network_id,
                     device_wireguard_ip: wireguard_network_device.wireguard_ip,
                     preshared_key: wireguard_network_device.preshared_key,
                     is_authorized: wireguard_network_device.is_authorized,
-                }],
+                },
             }));
         }
 
@@ -532,14 +1012,14 @@ impl WireguardNetwork {
                             // store ID of device with already generated config
                             assigned_device_ids.push(existing_device.id);
                             // send device to connected gateways
-                            events.push(GatewayEvent::DeviceModified(DeviceInfo {
+                            events.push(GatewayEvent::PeerAdded(PeerUpdate {
                                 device: existing_device,
-                                network_info: vec![DeviceNetworkInfo {
+                                network_info: DeviceNetworkInfo {
                                     network_id,
                                     device_wireguard_ip: wireguard_network_device.wireguard_ip,
                                     preshared_key: wireguard_network_device.preshared_key,
                                     is_authorized: wireguard_network_device.is_authorized,
-                                }],
+                                },
                             }));
                         }
                         None => {
@@ -573,17 +1053,39 @@ impl WireguardNetwork {
         for mapped_device in &mapped_devices {
             debug!("Mapping device {}", mapped_device.name);
             // validate device pubkey
-            Device::validate_pubkey(&mapped_device.wireguard_pubkey).map_err(|_| {
-                WireguardNetworkError::InvalidDevicePubkey(mapped_device.wireguard_pubkey.clone())
-            })?;
+            let pubkey = WireguardPubkey::parse(&mapped_device.wireguard_pubkey)?;
+            let preshared_key = match &mapped_device.preshared_key {
+                Some(_) if self.mfa_enabled => {
+                    return Err(WireguardNetworkError::PresharedKeyNotAllowed(
+                        self.name.clone(),
+                    ))
+                }
+                Some(key) => Some(parse_preshared_key(key).ok_or_else(|| {
+                    WireguardNetworkError::InvalidPresharedKey(mapped_device.name.clone())
+                })?),
+                None => None,
+            };
+            if let Some(existing) =
+                Device::find_duplicate_pubkey(&mut *transaction, &pubkey, None).await?
+            {
+                return Err(WireguardNetworkError::DuplicateDevicePubkey(
+                    mapped_device.name.clone(),
+                    existing.name,
+                ));
+            }
             // save a new device
-            let mut device = Device::new(
-                mapped_device.name.clone(),
-                mapped_device.wireguard_pubkey.clone(),
+            let name = Device::check_name(
+                &mut *transaction,
                 mapped_device.user_id,
-            );
+                &mapped_device.name,
+                None,
+            )
+            .await?;
+            let mut device = Device::new(name, pubkey.into(), mapped_device.user_id);
             device.save(&mut *transaction).await?;
             debug!("Saved new device {device}");
+            // imported devices haven't reported their platform yet
+            self.ensure_platform_allowed(&device)?;
 
             // get a list of groups user is assigned to
             let groups = match user_groups.get(&device.user_id) {
@@ -592,7 +1094,7 @@ impl WireguardNetwork {
                 // fetch user info
                 None => match User::find_by_id(&mut *transaction, device.user_id).await? {
                     Some(user) => {
-                        let groups = user.member_of_names(&mut *transaction).await?;
+                        let groups = user.effective_member_of_names(&mut *transaction).await?;
                         user_groups.insert(device.user_id, groups);
                         // FIXME: ugly workaround to get around `groups` being dropped
                         user_groups.get(&device.user_id).unwrap()
@@ -604,11 +1106,13 @@ impl WireguardNetwork {
             let mut network_info = Vec::new();
             match &allowed_groups {
                 None => {
-                    let wireguard_network_device = WireguardNetworkDevice::new(
+                    let mut wireguard_network_device = WireguardNetworkDevice::new(
                         network_id,
                         device.id.expect("Device ID is missing"),
                         mapped_device.wireguard_ip,
                     );
+                    wireguard_network_device.preshared_key_pinned = preshared_key.is_some();
+                    wireguard_network_device.preshared_key = preshared_key.clone();
                     wireguard_network_device.insert(&mut *transaction).await?;
                     network_info.push(DeviceNetworkInfo {
                         network_id,
@@ -621,11 +1125,13 @@ impl WireguardNetwork {
                     // check if user belongs to an allowed group
                     if allowed.iter().any(|group| groups.contains(group)) {
                         // assign specified IP in imported network
-                        let wireguard_network_device = WireguardNetworkDevice::new(
+                        let mut wireguard_network_device = WireguardNetworkDevice::new(
                             network_id,
                             device.id.expect("Device ID is missing"),
                             mapped_device.wireguard_ip,
                         );
+                        wireguard_network_device.preshared_key_pinned = preshared_key.is_some();
+                        wireguard_network_device.preshared_key = preshared_key.clone();
                         wireguard_network_device.insert(&mut *transaction).await?;
                         network_info.push(DeviceNetworkInfo {
                             network_id,
@@ -637,6 +1143,13 @@ impl WireguardNetwork {
                 }
             }
 
+            if let (Some(key), false) = (&preshared_key, network_info.is_empty()) {
+                info!(
+                    "Pinned preshared key {} of device {device} in location {self}",
+                    key_fingerprint(key)
+                );
+            }
+
             // assign IPs in other networks
             let (mut all_network_info, _configs) =
                 device.add_to_all_networks(&mut *transaction).await?;
@@ -644,12 +1157,10 @@ impl WireguardNetwork {
             network_info.append(&mut all_network_info);
 
             // send device to connected gateways
-            if !network_info.is_empty() {
-                events.push(GatewayEvent::DeviceCreated(DeviceInfo {
-                    device,
-                    network_info,
-                }));
-            }
+            events.extend(GatewayEvent::peers_added(DeviceInfo {
+                device,
+                network_info,
+            }));
         }
         Ok(events)
     }
@@ -759,6 +1270,12 @@ impl WireguardNetwork {
         for device in devices {
             let Some(device_id) = device.id else { continue };
             let latest_stats = self.fetch_latest_stats(conn, device_id).await?;
+            let gateway = match self.id {
+                Some(network_id) => WireguardNetworkDevice::find(conn, device_id, network_id)
+                    .await?
+                    .and_then(|network_device| network_device.gateway_hostname),
+                None => None,
+            };
             result.push(WireguardDeviceStatsRow {
                 id: device_id,
                 user_id: device.user_id,
@@ -766,6 +1283,7 @@ impl WireguardNetwork {
                 wireguard_ip: latest_stats.as_ref().and_then(Self::parse_wireguard_ip),
                 public_ip: latest_stats.as_ref().and_then(Self::parse_public_ip),
                 connected_at: self.connected_at(conn, device_id).await?,
+                gateway,
                 // Filter stats for this device
                 stats: stats
                     .iter()
@@ -796,7 +1314,7 @@ impl WireguardNetwork {
                 ORDER BY device_id, latest_handshake DESC \
             ) \
             SELECT \
-                d.id \"id?\", d.name, d.wireguard_pubkey, d.user_id, d.created \
+                d.id \"id?\", d.name, d.wireguard_pubkey, d.user_id, d.created, d.os, d.os_version, d.client_version, d.hostname, d.machine_hash, d.blocked \
             FROM device d \
             JOIN s ON d.id = s.device_id \
             WHERE s.latest_handshake >= $1 AND s.network = $2",
@@ -918,6 +1436,92 @@ impl WireguardNetwork {
             transfer_series,
         })
     }
+
+    /// Oldest handshake of a peer which is still considered connected. Peers of MFA-protected
+    /// locations are disconnected once both their handshake and authorization are older.
+    #[must_use]
+    pub fn peer_active_since(&self, now: NaiveDateTime) -> NaiveDateTime {
+        now - Duration::seconds(self.peer_disconnect_threshold.into())
+    }
+
+    /// Devices connected to the location, i.e. whose latest handshake is more recent than
+    /// `peer_active_since()`. In MFA-protected locations only authorized devices are included,
+    /// as others aren't configured on gateways. Optionally limited to devices of a single user.
+    pub async fn connected_clients(
+        &self,
+        conn: &DbPool,
+        now: NaiveDateTime,
+        username: Option<&str>,
+    ) -> Result<Vec<ConnectedClient>, SqlxError> {
+        let active_since = self.peer_active_since(now);
+        // latest stats are picked the same way as in `disconnect_inactive_peers()`
+        let rows = query!(
+            "WITH stats AS ( \
+                SELECT DISTINCT ON (device_id) device_id, endpoint, latest_handshake \
+                FROM wireguard_peer_stats \
+                WHERE network = $1 \
+                ORDER BY device_id, collected_at DESC \
+            ) \
+            SELECT d.id, d.name, u.id user_id, u.username, u.first_name, u.last_name, \
+            wnd.wireguard_ip \"wireguard_ip: IpAddr\", wnd.authorized_at, \
+            stats.endpoint, stats.latest_handshake \
+            FROM device d \
+            JOIN \"user\" u ON u.id = d.user_id \
+            JOIN wireguard_network_device wnd \
+                ON wnd.device_id = d.id AND wnd.wireguard_network_id = $1 \
+            JOIN stats ON stats.device_id = d.id \
+            WHERE stats.latest_handshake >= $2 AND (wnd.is_authorized OR NOT $3) \
+            AND ($4::text IS NULL OR u.username = $4) \
+            ORDER BY d.id",
+            self.id,
+            active_since,
+            self.mfa_enabled,
+            username,
+        )
+        .fetch_all(conn)
+        .await?;
+
+        let mut clients = Vec::with_capacity(rows.len());
+        for row in rows {
+            let connected_since = self.connected_at(conn, row.id).await?;
+            let transfer = query!(
+                "SELECT coalesce(sum(upload), 0)::bigint \"upload!\", \
+                coalesce(sum(download), 0)::bigint \"download!\" \
+                FROM wireguard_peer_stats_view \
+                WHERE device_id = $1 AND network = $2 AND collected_at >= $3",
+                row.id,
+                self.id,
+                connected_since.unwrap_or(row.latest_handshake),
+            )
+            .fetch_one(conn)
+            .await?;
+            // the same condition as in `disconnect_inactive_peers()`
+            let mfa_session_remaining = self.mfa_enabled.then(|| {
+                let last_active = row
+                    .authorized_at
+                    .map_or(row.latest_handshake, |authorized_at| {
+                        authorized_at.max(row.latest_handshake)
+                    });
+                (last_active - active_since).num_seconds().max(0)
+            });
+            clients.push(ConnectedClient {
+                device_id: row.id,
+                device_name: row.name,
+                user_id: row.user_id,
+                username: row.username,
+                first_name: row.first_name,
+                last_name: row.last_name,
+                wireguard_ip: row.wireguard_ip,
+                endpoint: row.endpoint,
+                connected_since,
+                latest_handshake: row.latest_handshake,
+                upload: transfer.upload,
+                download: transfer.download,
+                mfa_session_remaining,
+            });
+        }
+        Ok(clients)
+    }
 }
 
 // [`IpNetwork`] does not implement [`Default`]
@@ -937,11 +1541,24 @@ impl Default for WireguardNetwork {
             mfa_enabled: false,
             keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
             peer_disconnect_threshold: DEFAULT_DISCONNECT_THRESHOLD,
+            archived: false,
+            psk_rotation_days: None,
+            gateway_allowed_ips: Vec::new(),
+            mtu: None,
+            dns_zone: None,
+            upload_limit_kbps: None,
+            download_limit_kbps: None,
+            allowed_platforms: Vec::new(),
+            deny_unknown_platform: false,
+            client_routes: Vec::new(),
+            masquerade_enabled: false,
+            nat_exempt_networks: Vec::new(),
+            server_managed_keys: false,
         }
     }
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug, ToSchema)]
 pub struct WireguardNetworkInfo {
     #[serde(flatten)]
     pub network: WireguardNetwork,
@@ -950,14 +1567,14 @@ pub struct WireguardNetworkInfo {
     pub allowed_groups: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
 pub struct WireguardStatsRow {
     pub collected_at: Option<NaiveDateTime>,
     pub upload: Option<i64>,
     pub download: Option<i64>,
 }
 
-#[derive(FromRow, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(FromRow, Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
 pub struct WireguardDeviceTransferRow {
     pub device_id: i64,
     pub collected_at: Option<NaiveDateTime>,
@@ -965,7 +1582,7 @@ pub struct WireguardDeviceTransferRow {
     pub download: i64,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
 pub struct WireguardDeviceStatsRow {
     pub id: i64,
     pub stats: Vec<WireguardDeviceTransferRow>,
@@ -974,14 +1591,38 @@ pub struct WireguardDeviceStatsRow {
     pub wireguard_ip: Option<String>,
     pub public_ip: Option<String>,
     pub connected_at: Option<NaiveDateTime>,
+    // hostname of the gateway the device was last seen through
+    pub gateway: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct WireguardUserStatsRow {
     pub user: UserInfo,
     pub devices: Vec<WireguardDeviceStatsRow>,
 }
 
+/// Device connected to a location, with details of its current session.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ConnectedClient {
+    pub device_id: i64,
+    pub device_name: String,
+    pub user_id: i64,
+    pub username: String,
+    pub first_name: String,
+    pub last_name: String,
+    #[schema(value_type = String)]
+    pub wireguard_ip: IpAddr,
+    pub endpoint: Option<String>,
+    // start of the session, `None` if stats don't cover it
+    pub connected_since: Option<NaiveDateTime>,
+    pub latest_handshake: NaiveDateTime,
+    // transfer in bytes since the session started
+    pub upload: i64,
+    pub download: i64,
+    // in MFA-protected locations, seconds until the device is disconnected if inactive
+    pub mfa_session_remaining: Option<i64>,
+}
+
 #[derive(Model, Serialize, Deserialize, Debug)]
 #[table(wireguard_peer_stats)]
 pub struct WireguardPeerStats {
@@ -997,6 +1638,53 @@ pub struct WireguardPeerStats {
     pub allowed_ips: Option<String>,
 }
 
+impl WireguardPeerStats {
+    /// Insert multiple stats rows using a single query.
+    /// Returns number of inserted rows.
+    pub async fn save_batch<'e, E>(executor: E, stats: &[Self]) -> Result<u64, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let mut device_ids = Vec::with_capacity(stats.len());
+        let mut collected_at = Vec::with_capacity(stats.len());
+        let mut networks = Vec::with_capacity(stats.len());
+        let mut endpoints = Vec::with_capacity(stats.len());
+        let mut uploads = Vec::with_capacity(stats.len());
+        let mut downloads = Vec::with_capacity(stats.len());
+        let mut latest_handshakes = Vec::with_capacity(stats.len());
+        let mut allowed_ips = Vec::with_capacity(stats.len());
+        for row in stats {
+            device_ids.push(row.device_id);
+            collected_at.push(row.collected_at);
+            networks.push(row.network);
+            endpoints.push(row.endpoint.clone());
+            uploads.push(row.upload);
+            downloads.push(row.download);
+            latest_handshakes.push(row.latest_handshake);
+            allowed_ips.push(row.allowed_ips.clone());
+        }
+
+        let result = query(
+            "INSERT INTO wireguard_peer_stats \
+            (device_id, collected_at, network, endpoint, upload, download, latest_handshake, allowed_ips) \
+            SELECT * FROM UNNEST($1::bigint[], $2::timestamp[], $3::bigint[], $4::text[], \
+            $5::bigint[], $6::bigint[], $7::timestamp[], $8::text[])",
+        )
+        .bind(device_ids)
+        .bind(collected_at)
+        .bind(networks)
+        .bind(endpoints)
+        .bind(uploads)
+        .bind(downloads)
+        .bind(latest_handshakes)
+        .bind(allowed_ips)
+        .execute(executor)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
 pub struct WireguardNetworkActivityStats {
     pub active_users: i64,
     pub active_devices: i64,
@@ -1007,7 +1695,7 @@ pub struct WireguardNetworkTransferStats {
     pub download: i64,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct WireguardNetworkStats {
     pub current_active_users: i64,
     pub current_active_devices: i64,
@@ -1021,6 +1709,7 @@ pub struct WireguardNetworkStats {
 #[cfg(test)]
 mod test {
     use chrono::{Duration, SubsecRound};
+    use sqlx::Executor;
 
     use crate::db::models::device::WireguardNetworkDevice;
 
@@ -1083,6 +1772,138 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_endpoint_with_port() {
+        let mut network = WireguardNetwork {
+            port: 51820,
+            ..Default::default()
+        };
+        network.endpoint = "vpn.example.com".into();
+        assert_eq!(network.endpoint_with_port(), "vpn.example.com:51820");
+        network.endpoint = "192.168.4.14".into();
+        assert_eq!(network.endpoint_with_port(), "192.168.4.14:51820");
+        network.endpoint = "2001:db8::1".into();
+        assert_eq!(network.endpoint_with_port(), "[2001:db8::1]:51820");
+    }
+
+    #[test]
+    fn test_gateway_source_allowed() {
+        let mut network = WireguardNetwork::default();
+        // no restriction by default
+        assert!(network.gateway_source_allowed(Some("203.0.113.7".parse().unwrap())));
+        assert!(network.gateway_source_allowed(None));
+
+        network.gateway_allowed_ips = vec![
+            "192.168.4.0/24".parse().unwrap(),
+            "2001:db8:1::/48".parse().unwrap(),
+        ];
+        assert!(network.gateway_source_allowed(Some("192.168.4.14".parse().unwrap())));
+        assert!(network.gateway_source_allowed(Some("2001:db8:1::14".parse().unwrap())));
+        assert!(network.gateway_source_allowed(Some("::ffff:192.168.4.14".parse().unwrap())));
+        assert!(!network.gateway_source_allowed(Some("192.168.5.14".parse().unwrap())));
+        assert!(!network.gateway_source_allowed(Some("2001:db8:2::14".parse().unwrap())));
+        assert!(!network.gateway_source_allowed(None));
+    }
+
+    fn network_with_ranges(name: &str, address: &str, allowed_ips: &[&str]) -> WireguardNetwork {
+        let mut network = WireguardNetwork {
+            name: name.into(),
+            allowed_ips: allowed_ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            ..Default::default()
+        };
+        network.try_set_address(address).unwrap();
+        network
+    }
+
+    #[test]
+    fn test_network_overlaps() {
+        let office = network_with_ranges("office", "10.1.1.1/24", &["10.1.1.0/24"]);
+        // own subnet and allowed IPs are deduplicated
+        assert_eq!(
+            office.address_ranges(),
+            ["10.1.1.0/24".parse::<IpNetwork>().unwrap()]
+        );
+
+        let same = network_with_ranges("same", "10.1.1.1/24", &[]);
+        assert_eq!(office.overlaps_with(&same).len(), 1);
+
+        // partial overlap: larger range routed by other network
+        let routed = network_with_ranges("routed", "10.2.0.1/24", &["10.0.0.0/8"]);
+        let overlaps = office.overlaps_with(&routed);
+        assert_eq!(overlaps.len(), 1);
+        assert_eq!(
+            overlaps[0].range,
+            "10.1.1.0/24".parse::<IpNetwork>().unwrap()
+        );
+        assert_eq!(
+            overlaps[0].other_range,
+            "10.0.0.0/8".parse::<IpNetwork>().unwrap()
+        );
+        // and the other way round
+        assert_eq!(routed.overlaps_with(&office).len(), 1);
+
+        let adjacent = network_with_ranges("adjacent", "10.1.2.1/24", &["192.168.0.0/16"]);
+        assert!(office.overlaps_with(&adjacent).is_empty());
+
+        // IPv6
+        let v6 = network_with_ranges("v6", "fd00::1/64", &["fd00::/48"]);
+        let other_v6 = network_with_ranges("other v6", "fd00:0:0:1::1/64", &[]);
+        assert_eq!(v6.overlaps_with(&other_v6).len(), 1);
+        assert!(v6.overlaps_with(&office).is_empty());
+        // IPv4-mapped range doesn't overlap with IPv4 one
+        let mapped = network_with_ranges("mapped", "fd01::1/64", &["::ffff:10.1.1.0/120"]);
+        assert!(mapped.overlaps_with(&office).is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_assign_ipv6(pool: DbPool) {
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("fd00::1/64").unwrap();
+        network.save(&pool).await.unwrap();
+
+        let mut user = User::new(
+            "testuser",
+            Some("hunter2"),
+            "Tester",
+            "Test",
+            "test@test.com",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+
+        let mut transaction = pool.begin().await.unwrap();
+        let mut ips = Vec::new();
+        for i in 0..2 {
+            let mut device = Device::new(format!("dev{i}"), format!("key{i}"), user.id.unwrap());
+            device.save(&mut *transaction).await.unwrap();
+            let network_device = device
+                .assign_network_ip(&mut transaction, &network, None)
+                .await
+                .unwrap();
+            ips.push(network_device.wireguard_ip);
+        }
+        transaction.commit().await.unwrap();
+
+        assert_eq!(
+            ips,
+            vec![
+                "fd00::2".parse::<IpAddr>().unwrap(),
+                "fd00::3".parse().unwrap()
+            ]
+        );
+        let device = Device::find_by_pubkey(&pool, "key0")
+            .await
+            .unwrap()
+            .unwrap();
+        let network_device =
+            WireguardNetworkDevice::find(&pool, device.id.unwrap(), network.id.unwrap())
+                .await
+                .unwrap()
+                .unwrap();
+        let config = device.create_config(&network, &network_device);
+        assert!(config.contains("Address = fd00::2\n"));
+    }
+
     #[sqlx::test]
     async fn test_change_address_wont_fit(pool: DbPool) {
         let mut network = WireguardNetwork::default();
@@ -1199,4 +2020,117 @@ mod test {
             (now - Duration::minutes(samples)).trunc_subsecs(6),
         );
     }
+
+    fn networks(list: &[&str]) -> Vec<IpNetwork> {
+        list.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_canonical_networks() {
+        assert!(canonical_networks(&[]).is_empty());
+        assert_eq!(
+            canonical_networks(&networks(&[
+                "10.1.1.0/24",
+                "10.1.1.7/24",
+                "192.168.1.1",
+                "10.1.0.0/16",
+                "fd00::1/64",
+                "0.0.0.0/0",
+                "fd00:0:0:0:1::/80",
+            ])),
+            networks(&["fd00::/64", "0.0.0.0/0"])
+        );
+        // order is preserved, different address families never contain each other
+        assert_eq!(
+            canonical_networks(&networks(&["192.168.1.1", "10.1.1.0/24", "::/0"])),
+            networks(&["192.168.1.1/32", "10.1.1.0/24", "::/0"])
+        );
+    }
+
+    #[test]
+    fn test_parse_networks() {
+        assert_eq!(parse_networks(" ,").unwrap(), Vec::<IpNetwork>::new());
+        assert_eq!(
+            parse_networks("10.1.1.0/24, fd00::/64,").unwrap(),
+            networks(&["10.1.1.0/24", "fd00::/64"])
+        );
+        let errors = parse_networks("10.1.1.0/33, 10.1.1.0/24, vpn").unwrap_err();
+        let entries: Vec<&str> = errors.iter().map(|error| error.entry.as_str()).collect();
+        assert_eq!(entries, ["10.1.1.0/33", "vpn"]);
+    }
+
+    #[test]
+    fn test_client_routes() {
+        let mut network = WireguardNetwork {
+            address: "10.1.1.1/24".parse().unwrap(),
+            allowed_ips: networks(&["10.1.1.0/24", "192.168.1.0/24"]),
+            ..Default::default()
+        };
+        assert_eq!(network.client_allowed_ips(), network.allowed_ips);
+        assert!(network.client_route_overlaps().is_empty());
+
+        network.client_routes = networks(&["172.16.5.0/24", "192.168.1.0/24", "10.0.0.0/8"]);
+        assert_eq!(
+            network.client_allowed_ips(),
+            networks(&["192.168.1.0/24", "172.16.5.0/24", "10.0.0.0/8"])
+        );
+        assert_eq!(network.client_route_overlaps(), networks(&["10.0.0.0/8"]));
+        // routed networks aren't used for overlap checks with other locations
+        assert_eq!(
+            network.address_ranges(),
+            networks(&["10.1.1.0/24", "192.168.1.0/24"])
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_canonical_allowed_ips_migration(pool: DbPool) {
+        // stored before the API normalized allowed IPs
+        let mut legacy = network_with_ranges(
+            "legacy",
+            "10.1.1.1/24",
+            &[
+                "10.1.1.5/24",
+                "10.1.1.0/24",
+                "10.1.0.0/16",
+                "192.168.1.1",
+                "192.168.1.1",
+                "fd00::1/64",
+            ],
+        );
+        legacy.save(&pool).await.unwrap();
+        let mut canonical =
+            network_with_ranges("canonical", "10.2.1.1/24", &["10.2.1.0/24", "10.3.0.0/16"]);
+        canonical.save(&pool).await.unwrap();
+        let mut empty = network_with_ranges("empty", "10.4.1.1/24", &[]);
+        empty.save(&pool).await.unwrap();
+
+        // migration can be re-applied safely
+        for _ in 0..2 {
+            pool.execute(include_str!(
+                "../../../migrations/20240720091245_canonical_allowed_ips.up.sql"
+            ))
+            .await
+            .unwrap();
+        }
+
+        let legacy = WireguardNetwork::find_by_id(&pool, legacy.id.unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            legacy.allowed_ips,
+            networks(&["10.1.0.0/16", "192.168.1.1/32", "fd00::/64"])
+        );
+        assert_eq!(legacy.allowed_ips, canonical_networks(&legacy.allowed_ips));
+        let stored = WireguardNetwork::find_by_id(&pool, canonical.id.unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.allowed_ips, canonical.allowed_ips);
+        let empty = WireguardNetwork::find_by_id(&pool, empty.id.unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(empty.allowed_ips.is_empty());
+    }
 }
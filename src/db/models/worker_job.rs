@@ -0,0 +1,169 @@
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use sqlx::{query, query_as, query_scalar, Error as SqlxError, PgExecutor, Type};
+use utoipa::ToSchema;
+
+use super::User;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Type, ToSchema)]
+#[sqlx(type_name = "worker_job_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerJobStatus {
+    Pending,
+    Claimed,
+    Done,
+    Failed,
+}
+
+/// Outcome of a job reported by a worker.
+pub struct WorkerJobResult<'a> {
+    pub success: bool,
+    pub yubikey_serial: &'a str,
+    pub ssh_key: &'a str,
+    pub public_key: &'a str,
+    pub error: &'a str,
+}
+
+/// Job queued for a worker, e.g. YubiKey provisioning.
+///
+/// Jobs are stored when submitted and dispatched to workers from `WorkerState`,
+/// which is rebuilt from unfinished jobs on startup.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct WorkerJob {
+    pub id: i64,
+    pub worker_id: String,
+    pub username: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub status: WorkerJobStatus,
+    pub created_at: NaiveDateTime,
+    pub claimed_at: Option<NaiveDateTime>,
+    pub finished_at: Option<NaiveDateTime>,
+    pub yubikey_serial: Option<String>,
+    pub ssh_key: Option<String>,
+    pub public_key: Option<String>,
+    pub error: Option<String>,
+}
+
+impl WorkerJob {
+    /// Store a new pending job for `user` on a given worker.
+    pub async fn create<'e, E>(executor: E, worker_id: &str, user: &User) -> Result<Self, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "INSERT INTO worker_job (worker_id, username, first_name, last_name, email) \
+            VALUES ($1, $2, $3, $4, $5) \
+            RETURNING id, worker_id, username, first_name, last_name, email, \
+            status \"status: WorkerJobStatus\", created_at, claimed_at, finished_at, \
+            yubikey_serial, ssh_key, public_key, error",
+            worker_id,
+            user.username,
+            user.first_name,
+            user.last_name,
+            user.email
+        )
+        .fetch_one(executor)
+        .await
+    }
+
+    /// Pending and claimed jobs, oldest first.
+    pub async fn unfinished<'e, E>(executor: E) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id, worker_id, username, first_name, last_name, email, \
+            status \"status: WorkerJobStatus\", created_at, claimed_at, finished_at, \
+            yubikey_serial, ssh_key, public_key, error \
+            FROM worker_job WHERE status IN ('pending', 'claimed') ORDER BY id"
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Most recent jobs, newest first.
+    pub async fn recent<'e, E>(executor: E, limit: i64) -> Result<Vec<Self>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_as!(
+            Self,
+            "SELECT id, worker_id, username, first_name, last_name, email, \
+            status \"status: WorkerJobStatus\", created_at, claimed_at, finished_at, \
+            yubikey_serial, ssh_key, public_key, error \
+            FROM worker_job ORDER BY id DESC LIMIT $1",
+            limit
+        )
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Mark pending job as claimed by its worker.
+    pub async fn claim<'e, E>(executor: E, id: i64) -> Result<(), SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query!(
+            "UPDATE worker_job SET status = 'claimed', claimed_at = now() \
+            WHERE id = $1 AND status = 'pending'",
+            id
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    /// Store job outcome. Returns `false` if the job doesn't exist or is already finished.
+    pub async fn finish<'e, E>(
+        executor: E,
+        id: i64,
+        result: &WorkerJobResult<'_>,
+    ) -> Result<bool, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let status = if result.success {
+            WorkerJobStatus::Done
+        } else {
+            WorkerJobStatus::Failed
+        };
+        let result = query!(
+            "UPDATE worker_job SET status = $2, finished_at = now(), \
+            yubikey_serial = $3, ssh_key = $4, public_key = $5, error = $6 \
+            WHERE id = $1 AND status IN ('pending', 'claimed')",
+            id,
+            status as WorkerJobStatus,
+            result.yubikey_serial,
+            result.ssh_key,
+            result.public_key,
+            result.error
+        )
+        .execute(executor)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Return jobs claimed longer than `timeout` ago to pending, e.g. when a worker
+    /// disappeared while processing them. Returns IDs of returned jobs.
+    pub async fn reclaim_abandoned<'e, E>(
+        executor: E,
+        timeout: Duration,
+    ) -> Result<Vec<i64>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        query_scalar!(
+            "UPDATE worker_job SET status = 'pending', claimed_at = NULL \
+            WHERE status = 'claimed' AND claimed_at < now() - make_interval(secs => $1) \
+            RETURNING id",
+            timeout.as_secs_f64()
+        )
+        .fetch_all(executor)
+        .await
+    }
+}
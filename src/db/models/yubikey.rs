@@ -1,7 +1,8 @@
 use model_derive::Model;
 use sqlx::{query, query_as, Error as SqlxError, PgExecutor};
+use utoipa::ToSchema;
 
-#[derive(Deserialize, Model, Serialize)]
+#[derive(Deserialize, Model, Serialize, ToSchema)]
 pub struct YubiKey {
     pub id: Option<i64>,
     pub name: String,
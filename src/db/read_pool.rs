@@ -0,0 +1,194 @@
+//! Connection pool for read-only queries, optionally served by a read replica.
+
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, Request},
+    http::{request::Parts, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    query_scalar, Executor,
+};
+
+use super::DbPool;
+use crate::{appstate::AppState, config::DefGuardConfig, server_config};
+
+/// Set on responses served from a lagging read replica, holds the lag in seconds.
+pub static REPLICA_LAG_HEADER: &str = "x-defguard-replica-lag";
+
+// replica lag is measured at most this often
+const LAG_CACHE_TTL: Duration = Duration::from_secs(5);
+
+// time of the last lag measurement and its result
+type LagMeasurement = (Instant, Option<Duration>);
+
+/// Pool for endpoints which only read data, e.g. listings and statistics.
+///
+/// It's the primary pool unless a read replica is configured. Replica sessions are
+/// read-only, so anything writing through this pool fails instead of silently going
+/// to the replica; writes always have to use `AppState::pool`.
+///
+/// Handlers opt in by taking `ReadPool` as an extractor.
+#[derive(Clone)]
+pub struct ReadPool {
+    pool: DbPool,
+    replica: bool,
+    acquisitions: Arc<AtomicUsize>,
+    lag: Arc<Mutex<Option<LagMeasurement>>>,
+}
+
+impl ReadPool {
+    /// Serve reads from the primary database.
+    #[must_use]
+    pub fn primary(pool: DbPool) -> Self {
+        Self {
+            pool,
+            replica: false,
+            acquisitions: Arc::default(),
+            lag: Arc::default(),
+        }
+    }
+
+    /// Serve reads from a replica, connecting lazily. Sessions are read-only.
+    #[must_use]
+    pub fn replica(options: PgConnectOptions, pool_options: PgPoolOptions) -> Self {
+        let acquisitions = Arc::new(AtomicUsize::new(0));
+        // new connections are handed out right away, others are checked before reuse
+        let connected = Arc::clone(&acquisitions);
+        let reused = Arc::clone(&acquisitions);
+        let pool = pool_options
+            .after_connect(move |conn, _meta| {
+                connected.fetch_add(1, Ordering::Relaxed);
+                Box::pin(async move {
+                    conn.execute("SET SESSION CHARACTERISTICS AS TRANSACTION READ ONLY")
+                        .await?;
+                    Ok(())
+                })
+            })
+            .before_acquire(move |_conn, _meta| {
+                reused.fetch_add(1, Ordering::Relaxed);
+                Box::pin(async { Ok(true) })
+            })
+            .connect_lazy_with(options);
+        Self {
+            pool,
+            replica: true,
+            acquisitions,
+            lag: Arc::default(),
+        }
+    }
+
+    /// Configured read replica, or the primary pool if there's none.
+    #[must_use]
+    pub fn from_config(config: &DefGuardConfig, primary: &DbPool) -> Self {
+        match config
+            .database_read_connect_options()
+            .expect("Invalid read replica configuration")
+        {
+            Some(options) => {
+                info!("Using read replica for read-only queries");
+                let pool_options = PgPoolOptions::new()
+                    .max_connections(config.database_max_connections)
+                    .acquire_timeout(*config.database_acquire_timeout);
+                Self::replica(options, pool_options)
+            }
+            None => Self::primary(primary.clone()),
+        }
+    }
+
+    #[must_use]
+    pub fn pool(&self) -> &DbPool {
+        &self.pool
+    }
+
+    #[must_use]
+    pub fn is_replica(&self) -> bool {
+        self.replica
+    }
+
+    /// Number of connections acquired from the replica pool; always zero for the primary.
+    #[must_use]
+    pub fn acquisitions(&self) -> usize {
+        self.acquisitions.load(Ordering::Relaxed)
+    }
+
+    /// Replay lag of the replica, measured at most once per `LAG_CACHE_TTL`.
+    /// `None` for the primary pool or if the lag can't be measured.
+    pub async fn lag(&self) -> Option<Duration> {
+        if !self.replica {
+            return None;
+        }
+        if let Some((measured_at, lag)) = *self.lag.lock().unwrap() {
+            if measured_at.elapsed() < LAG_CACHE_TTL {
+                return lag;
+            }
+        }
+
+        // replicas which caught up have no lag, even if nothing was written for a while
+        let lag = match query_scalar!(
+            "SELECT CASE \
+                WHEN NOT pg_is_in_recovery() \
+                    OR pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+                ELSE extract(epoch FROM now() - pg_last_xact_replay_timestamp())::float8 \
+            END \"lag\""
+        )
+        .fetch_one(&self.pool)
+        .await
+        {
+            Ok(lag) => lag.map(|lag| Duration::from_secs_f64(lag.max(0.0))),
+            Err(err) => {
+                warn!("Failed to measure read replica lag: {err}");
+                None
+            }
+        };
+        *self.lag.lock().unwrap() = Some((Instant::now(), lag));
+        lag
+    }
+}
+
+/// Replica lag of the current request, set by the `ReadPool` extractor.
+#[derive(Clone, Default)]
+struct RequestReplicaLag(Arc<Mutex<Option<Duration>>>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ReadPool
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let read_pool = AppState::from_ref(state).read_pool;
+        if let Some(request_lag) = parts.extensions.get::<RequestReplicaLag>().cloned() {
+            let lag = read_pool.lag().await;
+            *request_lag.0.lock().unwrap() = lag;
+        }
+        Ok(read_pool)
+    }
+}
+
+/// Middleware marking responses served from a replica lagging behind more than configured.
+pub async fn replica_lag_header(mut request: Request, next: Next) -> Response {
+    let request_lag = RequestReplicaLag::default();
+    request.extensions_mut().insert(request_lag.clone());
+    let mut response = next.run(request).await;
+    let lag = *request_lag.0.lock().unwrap();
+    if let Some(lag) = lag.filter(|lag| *lag > *server_config().database_read_lag_threshold) {
+        response
+            .headers_mut()
+            .insert(REPLICA_LAG_HEADER, HeaderValue::from(lag.as_secs()));
+    }
+    response
+}
@@ -0,0 +1,472 @@
+//! Checks of instance configuration and its external dependencies.
+//!
+//! Misconfiguration, e.g. wrong public URL behind a reverse proxy or unreachable SMTP server,
+//! tends to surface much later as confusing errors. Diagnostics check all of these at once
+//! and report each problem with a hint how to fix it. They're available as `defguard check`
+//! and to admins in the web UI.
+//!
+//! Checks run concurrently, each one with its own timeout, so that a single hung dependency
+//! doesn't hold up the whole report.
+
+use std::{collections::HashSet, future::Future, sync::Mutex, time::Duration};
+
+use reqwest::{Client, Url};
+use serde_json::Value;
+use sqlx::query_as;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    db::{DbPool, Settings, WireguardNetwork, MIGRATOR},
+    grpc::GatewayMap,
+    ldap::LDAPConnection,
+    mail::{send_mail_now, test_smtp_connection, Mail},
+    notifications::sign_payload,
+    random::gen_alphanumeric,
+    server_config,
+};
+
+// individual checks taking longer than this fail
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+static TEST_MAIL_SUBJECT: &str = "Defguard: diagnostics test mail";
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+    // how to fix the problem
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn pass<S: Into<String>>(name: &str, message: S) -> Self {
+        Self {
+            name: name.into(),
+            status: CheckStatus::Pass,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn warn<S: Into<String>>(name: &str, message: S, hint: &str) -> Self {
+        Self {
+            name: name.into(),
+            status: CheckStatus::Warn,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail<S: Into<String>>(name: &str, message: S, hint: &str) -> Self {
+        Self {
+            name: name.into(),
+            status: CheckStatus::Fail,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct DiagnosticsReport {
+    // worst status of all checks
+    pub status: CheckStatus,
+    pub checks: Vec<CheckResult>,
+}
+
+impl DiagnosticsReport {
+    #[must_use]
+    pub fn new(checks: Vec<CheckResult>) -> Self {
+        let status = checks
+            .iter()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(CheckStatus::Pass);
+        Self { status, checks }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct DiagnosticsOptions {
+    // send a test mail to this address
+    #[serde(default)]
+    pub test_mail_to: Option<String>,
+}
+
+/// Run a check, failing it if it doesn't finish in time.
+pub async fn run_check<F>(name: &str, timeout: Duration, check: F) -> Vec<CheckResult>
+where
+    F: Future<Output = Vec<CheckResult>>,
+{
+    match tokio::time::timeout(timeout, check).await {
+        Ok(results) => results,
+        Err(_) => vec![CheckResult::fail(
+            name,
+            format!("Check didn't finish within {}s", timeout.as_secs()),
+            "The service is not responding, check if it's running and reachable from this host",
+        )],
+    }
+}
+
+/// Check database connection and whether its schema matches this version.
+pub async fn check_database(pool: &DbPool) -> Vec<CheckResult> {
+    const NAME: &str = "database";
+    let applied: Vec<(i64, bool)> = match query_as("SELECT version, success FROM _sqlx_migrations")
+        .fetch_all(pool)
+        .await
+    {
+        Ok(applied) => applied,
+        Err(err) => {
+            return vec![CheckResult::fail(
+                NAME,
+                format!("Failed to query the database: {err}"),
+                "Check database connection settings and that the database server is running",
+            )]
+        }
+    };
+    let known: HashSet<i64> = MIGRATOR.iter().map(|migration| migration.version).collect();
+    if let Some((version, _)) = applied.iter().find(|(_, success)| !success) {
+        return vec![CheckResult::fail(
+            NAME,
+            format!("Migration {version} has failed"),
+            "Restore the database from a backup taken before upgrading and start defguard again",
+        )];
+    }
+    let applied: HashSet<i64> = applied.into_iter().map(|(version, _)| version).collect();
+    let pending = known.difference(&applied).count();
+    if pending > 0 {
+        return vec![CheckResult::fail(
+            NAME,
+            format!("{pending} migrations haven't been applied"),
+            "Restart defguard to apply database migrations",
+        )];
+    }
+    if !applied.is_subset(&known) {
+        return vec![CheckResult::warn(
+            NAME,
+            "Database has been migrated by a newer version of defguard",
+            "Upgrade this instance, all instances sharing the database should run the same version",
+        )];
+    }
+    vec![CheckResult::pass(NAME, "Connected, schema is up to date")]
+}
+
+/// Check connection to SMTP server and optionally send a test mail.
+pub async fn check_smtp(settings: &Settings, test_mail_to: Option<&str>) -> Vec<CheckResult> {
+    const NAME: &str = "smtp";
+    if !settings.smtp_configured() {
+        return vec![CheckResult::warn(
+            NAME,
+            "SMTP is not configured",
+            "Configure SMTP in settings, otherwise enrollment and notification emails aren't sent",
+        )];
+    }
+    let hint = "Check SMTP server address, port, encryption and credentials in settings";
+    match test_smtp_connection(settings.clone()).await {
+        Ok(true) => (),
+        Ok(false) => {
+            return vec![CheckResult::fail(
+                NAME,
+                "SMTP server didn't accept the connection",
+                hint,
+            )]
+        }
+        Err(err) => {
+            return vec![CheckResult::fail(
+                NAME,
+                format!("Failed to connect to SMTP server: {err}"),
+                hint,
+            )]
+        }
+    }
+    let mut results = vec![CheckResult::pass(NAME, "Connected to SMTP server")];
+    if let Some(to) = test_mail_to {
+        let mail = Mail {
+            to: to.into(),
+            subject: TEST_MAIL_SUBJECT.into(),
+            content: "This is a test mail sent by defguard diagnostics".into(),
+            attachments: Vec::new(),
+            result_tx: None,
+        };
+        results.push(match send_mail_now(settings.clone(), mail).await {
+            Ok(_) => CheckResult::pass("smtp_test_mail", format!("Test mail sent to {to}")),
+            Err(err) => CheckResult::fail(
+                "smtp_test_mail",
+                format!("Failed to send test mail to {to}: {err}"),
+                "Check SMTP sender address and whether the server relays mail to this recipient",
+            ),
+        });
+    }
+    results
+}
+
+/// Check LDAP bind, if LDAP is configured.
+pub async fn check_ldap(pool: &DbPool, settings: &Settings) -> Vec<CheckResult> {
+    const NAME: &str = "ldap";
+    if settings.ldap_url.is_none() {
+        return vec![CheckResult::pass(NAME, "LDAP is not configured")];
+    }
+    match LDAPConnection::create(pool).await {
+        Ok(_) => vec![CheckResult::pass(NAME, "Bound to LDAP server")],
+        Err(err) => vec![CheckResult::fail(
+            NAME,
+            format!("Failed to bind to LDAP server: {err}"),
+            "Check LDAP URL, bind username and password in settings",
+        )],
+    }
+}
+
+/// Value returned by the probe endpoint for a nonce. Proves that the response comes
+/// from an instance sharing this instance's database, without revealing its id.
+#[must_use]
+pub fn probe_proof(instance_id: &Uuid, nonce: &str) -> String {
+    sign_payload(&instance_id.to_string(), nonce.as_bytes())
+}
+
+/// Check that public URL leads to this instance, by sending it a random nonce.
+pub async fn check_public_url(url: &Url, instance_id: &Uuid) -> Vec<CheckResult> {
+    const NAME: &str = "public_url";
+    let hint = "Set DEFGUARD_URL to the address users open defguard at, \
+        and make sure it's reachable from this host";
+    let nonce = gen_alphanumeric(24);
+    let mut probe_url = url.join("api/v1/system/probe").unwrap();
+    probe_url.set_query(Some(&format!("nonce={nonce}")));
+    let response = match Client::new()
+        .get(probe_url)
+        .timeout(CHECK_TIMEOUT)
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        Err(err) => {
+            return vec![CheckResult::fail(
+                NAME,
+                format!("{url} is not reachable: {err}"),
+                hint,
+            )]
+        }
+    };
+    if !response.status().is_success() {
+        return vec![CheckResult::fail(
+            NAME,
+            format!("{url} responded with {}", response.status()),
+            "Check reverse proxy configuration, requests to /api have to reach defguard",
+        )];
+    }
+    let proof = response
+        .json::<Value>()
+        .await
+        .ok()
+        .and_then(|body| body["proof"].as_str().map(ToString::to_string));
+    if proof.as_deref() == Some(probe_proof(instance_id, &nonce).as_str()) {
+        vec![CheckResult::pass(
+            NAME,
+            format!("{url} leads to this instance"),
+        )]
+    } else {
+        vec![CheckResult::fail(
+            NAME,
+            format!("{url} leads to a different service or defguard instance"),
+            hint,
+        )]
+    }
+}
+
+/// Check that every active location has its gateways connected.
+/// Gateways connect to the running server, so this can't be checked without one.
+pub async fn check_gateways(
+    pool: &DbPool,
+    gateway_state: Option<&Mutex<GatewayMap>>,
+) -> Vec<CheckResult> {
+    const NAME: &str = "gateways";
+    let Some(gateway_state) = gateway_state else {
+        return vec![CheckResult::warn(
+            NAME,
+            "Gateway connections can only be checked by the running server",
+            "Run diagnostics from the web UI to check gateways",
+        )];
+    };
+    let networks = match WireguardNetwork::all_active(pool).await {
+        Ok(networks) => networks,
+        Err(err) => {
+            return vec![CheckResult::fail(
+                NAME,
+                format!("Failed to load locations: {err}"),
+                "Check database connection",
+            )]
+        }
+    };
+    let gateway_state = gateway_state
+        .lock()
+        .expect("Failed to acquire lock on gateway state");
+    networks
+        .into_iter()
+        .filter_map(|network| {
+            let network_id = network.id?;
+            let name = format!("gateways:{}", network.name);
+            let gateways = gateway_state.get_network_gateway_status(network_id);
+            let connected = gateways.iter().filter(|gateway| gateway.connected).count();
+            Some(if gateways.is_empty() {
+                CheckResult::fail(
+                    &name,
+                    "No gateway has connected",
+                    "Start a gateway using a token generated for this location",
+                )
+            } else if connected == 0 {
+                CheckResult::fail(
+                    &name,
+                    format!("None of {} gateways is connected", gateways.len()),
+                    "Check if gateways are running and can reach defguard gRPC port",
+                )
+            } else if connected < gateways.len() {
+                CheckResult::warn(
+                    &name,
+                    format!("{connected} of {} gateways connected", gateways.len()),
+                    "Check if disconnected gateways are running, or remove ones no longer used",
+                )
+            } else {
+                CheckResult::pass(&name, format!("{connected} gateways connected"))
+            })
+        })
+        .collect()
+}
+
+/// Run all checks concurrently.
+pub async fn run_diagnostics(
+    pool: &DbPool,
+    gateway_state: Option<&Mutex<GatewayMap>>,
+    options: &DiagnosticsOptions,
+) -> DiagnosticsReport {
+    debug!("Running diagnostics");
+    let settings = match Settings::get_settings(pool).await {
+        Ok(settings) => settings,
+        Err(err) => {
+            return DiagnosticsReport::new(vec![CheckResult::fail(
+                "database",
+                format!("Failed to load settings: {err}"),
+                "Check database connection settings and that the database server is running",
+            )])
+        }
+    };
+    let (database, smtp, ldap, public_url, gateways) = tokio::join!(
+        run_check("database", CHECK_TIMEOUT, check_database(pool)),
+        run_check(
+            "smtp",
+            CHECK_TIMEOUT,
+            check_smtp(&settings, options.test_mail_to.as_deref())
+        ),
+        run_check("ldap", CHECK_TIMEOUT, check_ldap(pool, &settings)),
+        run_check(
+            "public_url",
+            CHECK_TIMEOUT,
+            check_public_url(&server_config().url, &settings.uuid)
+        ),
+        run_check(
+            "gateways",
+            CHECK_TIMEOUT,
+            check_gateways(pool, gateway_state)
+        ),
+    );
+    let report = DiagnosticsReport::new(
+        [database, smtp, ldap, public_url, gateways]
+            .into_iter()
+            .flatten()
+            .collect(),
+    );
+    info!("Diagnostics finished with status {:?}", report.status);
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        config::DefGuardConfig, db::models::settings::SmtpEncryption, secret::SecretString,
+        SERVER_CONFIG,
+    };
+
+    // port nothing listens on
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn test_check_timeout() {
+        let results = run_check(
+            "hung",
+            Duration::from_millis(50),
+            std::future::pending::<Vec<CheckResult>>(),
+        )
+        .await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].status, CheckStatus::Fail);
+        assert_eq!(results[0].name, "hung");
+
+        let report = DiagnosticsReport::new(vec![
+            CheckResult::pass("a", ""),
+            CheckResult::warn("b", "", ""),
+        ]);
+        assert_eq!(report.status, CheckStatus::Warn);
+    }
+
+    #[sqlx::test]
+    async fn test_failing_dependencies(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let mut settings = Settings::get_settings(&pool).await.unwrap();
+
+        assert_eq!(check_database(&pool).await[0].status, CheckStatus::Pass);
+
+        // not configured
+        assert_eq!(
+            check_smtp(&settings, None).await[0].status,
+            CheckStatus::Warn
+        );
+        assert_eq!(
+            check_ldap(&pool, &settings).await[0].status,
+            CheckStatus::Pass
+        );
+
+        // configured, but unreachable
+        let port = closed_port().await;
+        settings.smtp_server = Some("127.0.0.1".into());
+        settings.smtp_port = Some(port.into());
+        settings.smtp_encryption = SmtpEncryption::None;
+        settings.smtp_user = Some("defguard".into());
+        settings.smtp_password = Some(SecretString::from_str("secret").unwrap());
+        settings.smtp_sender = Some("defguard@example.com".into());
+        settings.ldap_url = Some(format!("ldap://127.0.0.1:{port}"));
+        settings.ldap_bind_username = Some("cn=admin,dc=example,dc=org".into());
+        settings.ldap_bind_password = Some(SecretString::from_str("secret").unwrap());
+        settings.save(&pool).await.unwrap();
+
+        let results = check_smtp(&settings, Some("admin@example.com")).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].status, CheckStatus::Fail);
+        assert!(results[0].hint.is_some());
+        let results = check_ldap(&pool, &settings).await;
+        assert_eq!(results[0].status, CheckStatus::Fail);
+
+        // public URL where nothing listens
+        let url = Url::parse(&format!("http://127.0.0.1:{port}")).unwrap();
+        let results = check_public_url(&url, &settings.uuid).await;
+        assert_eq!(results[0].status, CheckStatus::Fail);
+
+        // gateways can't be checked without the server
+        let results = check_gateways(&pool, None).await;
+        assert_eq!(results[0].status, CheckStatus::Warn);
+    }
+}
@@ -0,0 +1,1555 @@
+//! Publishing DNS records of devices.
+//!
+//! Devices get an A or AAAA record named after them in the DNS zone of each location
+//! which has one set, e.g. `printer.office.vpn.example.com`. Records are published with
+//! RFC 2136 dynamic updates signed with TSIG, or posted to a webhook, depending on settings.
+//!
+//! Wanted records are derived from device addresses and stored in `device_dns_record`
+//! along with what was last published, so changes and removals aren't lost while the DNS
+//! server is unavailable. Syncing is triggered by device and location changes; records
+//! which failed to publish are retried by a background job.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use axum::async_trait;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{NaiveDateTime, Utc};
+use hmac::{Hmac, Mac};
+use ipnetwork::IpNetwork;
+use reqwest::{Client, Url};
+use sha2::Sha256;
+use sqlx::{query, query_as, query_scalar, Error as SqlxError};
+use thiserror::Error;
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::{
+        broadcast::{
+            error::{RecvError, TryRecvError},
+            Receiver,
+        },
+        Mutex, Notify,
+    },
+    time::timeout,
+};
+use utoipa::ToSchema;
+
+use crate::{
+    db::{models::settings::DnsProvider, DbPool, GatewayEvent, Settings},
+    jobs::{Job, JobSchedule},
+    notifications::{sign_payload, SIGNATURE_HEADER},
+};
+
+// How often records which failed to publish are retried
+const DNS_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const DNS_UPDATE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_DNS_PORT: u16 = 53;
+const MAX_LABEL_LENGTH: usize = 63;
+const MAX_NAME_LENGTH: usize = 253;
+// Zones have to leave room for device labels
+const MAX_ZONE_LENGTH: usize = MAX_NAME_LENGTH - MAX_LABEL_LENGTH - 1;
+// Allowed difference between signing time and server clock, in seconds
+const TSIG_FUDGE: u16 = 300;
+const TSIG_ALGORITHM: &str = "hmac-sha256";
+
+// DNS wire format
+const OPCODE_UPDATE: u8 = 5;
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+const HEADER_LENGTH: usize = 12;
+
+static SYNC_REQUESTED: OnceLock<Notify> = OnceLock::new();
+// Syncs within an instance must not publish the same records concurrently
+static SYNC_LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+
+#[derive(Debug, Error)]
+pub enum DnsError {
+    #[error("Device name {0} can't be used as a DNS label")]
+    InvalidLabel(String),
+    #[error("Device {0} would get the same DNS record as device {1}")]
+    NameCollision(String, String),
+    #[error("Invalid DNS settings: {0}")]
+    Settings(String),
+    #[error("DNS update failed: {0}")]
+    UpdateFailed(String),
+    #[error(transparent)]
+    DbError(#[from] SqlxError),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum RecordType {
+    A,
+    #[serde(rename = "AAAA")]
+    Aaaa,
+}
+
+impl RecordType {
+    #[must_use]
+    pub fn for_address(address: IpAddr) -> Self {
+        if address.is_ipv4() {
+            Self::A
+        } else {
+            Self::Aaaa
+        }
+    }
+
+    fn code(self) -> u16 {
+        match self {
+            Self::A => TYPE_A,
+            Self::Aaaa => TYPE_AAAA,
+        }
+    }
+}
+
+/// Change of records of a single name and type.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RecordChange {
+    /// Replace all records with a single address.
+    Upsert {
+        name: String,
+        #[serde(rename = "type")]
+        record_type: RecordType,
+        address: IpAddr,
+        ttl: u32,
+    },
+    /// Remove all records.
+    Delete {
+        name: String,
+        #[serde(rename = "type")]
+        record_type: RecordType,
+    },
+}
+
+/// Changes within a single zone, applied atomically. Webhooks receive it as JSON body.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DnsUpdate {
+    pub zone: String,
+    pub changes: Vec<RecordChange>,
+}
+
+impl DnsUpdate {
+    fn add(updates: &mut Vec<Self>, zone: &str, change: RecordChange) {
+        match updates.iter_mut().find(|update| update.zone == zone) {
+            Some(update) => update.changes.push(change),
+            None => updates.push(Self {
+                zone: zone.into(),
+                changes: vec![change],
+            }),
+        }
+    }
+}
+
+/// DNS label for a device name: lowercase letters, digits and single hyphens in place
+/// of anything else, e.g. `Anna's NAS` becomes `anna-s-nas`.
+/// `None` if nothing usable is left.
+#[must_use]
+pub fn device_label(name: &str) -> Option<String> {
+    let mut label = String::with_capacity(name.len());
+    for char in name.chars() {
+        if char.is_ascii_alphanumeric() {
+            label.push(char.to_ascii_lowercase());
+        } else if !label.is_empty() && !label.ends_with('-') {
+            label.push('-');
+        }
+    }
+    label.truncate(MAX_LABEL_LENGTH);
+    let label = label.trim_end_matches('-');
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+/// Lowercase DNS name without trailing dot, used for zones and TSIG key names.
+pub fn normalize_name(name: &str) -> Result<String, String> {
+    let normalized = name.trim().trim_end_matches('.').to_ascii_lowercase();
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= MAX_LABEL_LENGTH
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+    };
+    if normalized.len() > MAX_ZONE_LENGTH || !normalized.split('.').all(valid_label) {
+        return Err(format!("Invalid DNS name {name}"));
+    }
+    Ok(normalized)
+}
+
+/// RFC 2136 server address in `host:port` format; port defaults to 53.
+fn server_address(server: &str) -> String {
+    let server = server.trim();
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return SocketAddr::new(ip, DEFAULT_DNS_PORT).to_string();
+    }
+    match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => server.to_string(),
+        _ => format!("{server}:{DEFAULT_DNS_PORT}"),
+    }
+}
+
+fn push_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        // names are validated, labels fit in 63 bytes
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+fn push_rrset_delete(buf: &mut Vec<u8>, name: &str, record_type: RecordType) {
+    push_name(buf, name);
+    buf.extend_from_slice(&record_type.code().to_be_bytes());
+    buf.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    buf.extend_from_slice(&0u32.to_be_bytes());
+    buf.extend_from_slice(&0u16.to_be_bytes());
+}
+
+/// TSIG key used to sign updates, see RFC 8945.
+pub struct TsigKey {
+    name: String,
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    pub fn new(name: &str, secret: &str) -> Result<Self, String> {
+        let secret = BASE64_STANDARD
+            .decode(secret.trim())
+            .map_err(|_| "TSIG secret is not valid base64".to_string())?;
+        if secret.is_empty() {
+            return Err("TSIG secret is empty".into());
+        }
+        Ok(Self {
+            name: normalize_name(name)?,
+            secret,
+        })
+    }
+
+    /// MAC of a message without its TSIG record, see RFC 8945 section 4.3.
+    /// Responses are signed along with the MAC of the request they answer.
+    fn mac(
+        &self,
+        request_mac: Option<&[u8]>,
+        message: &[u8],
+        time_signed: &[u8],
+        error: u16,
+        other: &[u8],
+    ) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        if let Some(request_mac) = request_mac {
+            mac.update(&(request_mac.len() as u16).to_be_bytes());
+            mac.update(request_mac);
+        }
+        mac.update(message);
+        let mut variables = Vec::new();
+        push_name(&mut variables, &self.name);
+        variables.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        variables.extend_from_slice(&0u32.to_be_bytes());
+        push_name(&mut variables, TSIG_ALGORITHM);
+        variables.extend_from_slice(time_signed);
+        variables.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+        variables.extend_from_slice(&error.to_be_bytes());
+        variables.extend_from_slice(&(other.len() as u16).to_be_bytes());
+        variables.extend_from_slice(other);
+        mac.update(&variables);
+        mac
+    }
+
+    /// Append TSIG record to a complete message; `request_mac` is set when signing a response.
+    fn sign(&self, message: &mut Vec<u8>, time_signed: u64, request_mac: Option<&[u8]>) {
+        let mut algorithm = Vec::new();
+        push_name(&mut algorithm, TSIG_ALGORITHM);
+        // 48-bit seconds since epoch
+        let time_signed = &time_signed.to_be_bytes()[2..];
+        let mac = self
+            .mac(request_mac, message, time_signed, 0, &[])
+            .finalize()
+            .into_bytes();
+
+        let mut rdata = algorithm;
+        rdata.extend_from_slice(time_signed);
+        rdata.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+        rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&mac);
+        // original message id
+        rdata.extend_from_slice(&message[..2]);
+        rdata.extend_from_slice(&[0; 4]);
+
+        push_name(message, &self.name);
+        message.extend_from_slice(&TYPE_TSIG.to_be_bytes());
+        message.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        message.extend_from_slice(&0u32.to_be_bytes());
+        message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        message.extend_from_slice(&rdata);
+        let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+        message[10..12].copy_from_slice(&additional.to_be_bytes());
+    }
+
+    /// Verify TSIG of a response to a request signed with `request_mac`.
+    fn verify(
+        &self,
+        response: &[u8],
+        tsig: &TsigRecord,
+        request_mac: &[u8],
+        now: u64,
+    ) -> Result<(), DnsError> {
+        let failed = |reason: &str| DnsError::UpdateFailed(format!("response {reason}"));
+        if tsig.key_name != self.name || tsig.algorithm != TSIG_ALGORITHM {
+            return Err(failed("is signed with another key"));
+        }
+        if tsig.error != 0 {
+            let error = match tsig.error {
+                16 => "BADSIG",
+                17 => "BADKEY",
+                18 => "BADTIME",
+                _ => "unknown error",
+            };
+            return Err(DnsError::UpdateFailed(format!(
+                "server rejected TSIG with {error}"
+            )));
+        }
+        // the message as signed: original id and TSIG not counted in the additional section
+        let mut unsigned = response[..tsig.start].to_vec();
+        unsigned[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        let additional = u16::from_be_bytes([unsigned[10], unsigned[11]]) - 1;
+        unsigned[10..12].copy_from_slice(&additional.to_be_bytes());
+        let time_signed = &tsig.time_signed.to_be_bytes()[2..];
+        self.mac(Some(request_mac), &unsigned, time_signed, 0, &tsig.other)
+            .verify_slice(&tsig.mac)
+            .map_err(|_| failed("signature is invalid"))?;
+        if now.abs_diff(tsig.time_signed) > u64::from(tsig.fudge) {
+            return Err(failed("was signed outside the allowed time window"));
+        }
+        Ok(())
+    }
+}
+
+/// TSIG record read from the end of a message.
+struct TsigRecord {
+    // offset of the record in the message
+    start: usize,
+    key_name: String,
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl TsigRecord {
+    fn read(start: usize, key_name: String, rdata: &[u8]) -> Option<Self> {
+        let mut pos = 0;
+        let algorithm = read_name(rdata, &mut pos)?;
+        // 48-bit seconds since epoch
+        let time_signed = read_bytes(rdata, &mut pos, 6)?
+            .iter()
+            .fold(0, |time, byte| (time << 8) | u64::from(*byte));
+        let fudge = read_u16(rdata, &mut pos)?;
+        let mac_length = read_u16(rdata, &mut pos)? as usize;
+        let mac = read_bytes(rdata, &mut pos, mac_length)?;
+        let original_id = read_u16(rdata, &mut pos)?;
+        let error = read_u16(rdata, &mut pos)?;
+        let other_length = read_u16(rdata, &mut pos)? as usize;
+        let other = read_bytes(rdata, &mut pos, other_length)?;
+        Some(Self {
+            start,
+            key_name,
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+}
+
+/// Read a possibly compressed name, lowercase and without trailing dot.
+fn read_name(message: &[u8], pos: &mut usize) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut cursor = *pos;
+    let mut jumped = false;
+    // bounds the loop on malformed messages with pointer cycles
+    for _ in 0..message.len() {
+        let length = *message.get(cursor)? as usize;
+        if length & 0xc0 == 0xc0 {
+            let pointer = ((length & 0x3f) << 8) | *message.get(cursor + 1)? as usize;
+            if !jumped {
+                *pos = cursor + 2;
+                jumped = true;
+            }
+            cursor = pointer;
+            continue;
+        }
+        cursor += 1;
+        if length == 0 {
+            if !jumped {
+                *pos = cursor;
+            }
+            return Some(labels.join(".").to_lowercase());
+        }
+        let label = message.get(cursor..cursor + length)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        cursor += length;
+    }
+    None
+}
+
+fn read_u16(message: &[u8], pos: &mut usize) -> Option<u16> {
+    let bytes = message.get(*pos..*pos + 2)?;
+    *pos += 2;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_bytes(message: &[u8], pos: &mut usize, length: usize) -> Option<Vec<u8>> {
+    let bytes = message.get(*pos..*pos + length)?.to_vec();
+    *pos += length;
+    Some(bytes)
+}
+
+/// TSIG record of a message, `Ok(None)` if the message isn't signed.
+/// TSIG has to be the last record of the additional section.
+fn read_tsig(message: &[u8]) -> Result<Option<TsigRecord>, DnsError> {
+    let malformed = || DnsError::UpdateFailed("malformed response".into());
+    let mut pos = 4;
+    let mut counts = [0; 4];
+    for count in &mut counts {
+        *count = read_u16(message, &mut pos).ok_or_else(malformed)?;
+    }
+    let [zones, prerequisites, updates, additional] = counts;
+    if additional == 0 {
+        return Ok(None);
+    }
+    for _ in 0..zones {
+        read_name(message, &mut pos).ok_or_else(malformed)?;
+        pos += 4;
+    }
+    let records = usize::from(prerequisites) + usize::from(updates) + usize::from(additional);
+    for index in 0..records {
+        let start = pos;
+        let name = read_name(message, &mut pos).ok_or_else(malformed)?;
+        let record_type = read_u16(message, &mut pos).ok_or_else(malformed)?;
+        pos += 6;
+        let length = read_u16(message, &mut pos).ok_or_else(malformed)? as usize;
+        let rdata = read_bytes(message, &mut pos, length).ok_or_else(malformed)?;
+        if index + 1 < records {
+            continue;
+        }
+        if record_type != TYPE_TSIG || pos != message.len() {
+            return Ok(None);
+        }
+        return TsigRecord::read(start, name, &rdata)
+            .map(Some)
+            .ok_or_else(malformed);
+    }
+    Ok(None)
+}
+
+/// Encode RFC 2136 update message. Upserts delete existing records of the same name
+/// and type before adding the new one.
+#[must_use]
+pub fn encode_update(id: u16, update: &DnsUpdate, key: &TsigKey, time_signed: u64) -> Vec<u8> {
+    let mut message = Vec::with_capacity(512);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.push(OPCODE_UPDATE << 3);
+    message.push(0);
+    let update_count: usize = update
+        .changes
+        .iter()
+        .map(|change| match change {
+            RecordChange::Upsert { .. } => 2,
+            RecordChange::Delete { .. } => 1,
+        })
+        .sum();
+    // zone, prerequisite, update and additional section counts
+    for count in [1, 0, update_count as u16, 0] {
+        message.extend_from_slice(&count.to_be_bytes());
+    }
+    push_name(&mut message, &update.zone);
+    message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    for change in &update.changes {
+        match change {
+            RecordChange::Upsert {
+                name,
+                record_type,
+                address,
+                ttl,
+            } => {
+                push_rrset_delete(&mut message, name, *record_type);
+                push_name(&mut message, name);
+                message.extend_from_slice(&record_type.code().to_be_bytes());
+                message.extend_from_slice(&CLASS_IN.to_be_bytes());
+                message.extend_from_slice(&ttl.to_be_bytes());
+                let rdata = match address {
+                    IpAddr::V4(address) => address.octets().to_vec(),
+                    IpAddr::V6(address) => address.octets().to_vec(),
+                };
+                message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                message.extend_from_slice(&rdata);
+            }
+            RecordChange::Delete { name, record_type } => {
+                push_rrset_delete(&mut message, name, *record_type);
+            }
+        }
+    }
+    key.sign(&mut message, time_signed, None);
+    message
+}
+
+/// Check the response to an update `request`. Its TSIG has to be valid, spoofed responses
+/// must not be taken for successful updates.
+fn check_response(
+    key: &TsigKey,
+    request: &[u8],
+    response: &[u8],
+    now: u64,
+) -> Result<(), DnsError> {
+    if response.len() < HEADER_LENGTH {
+        return Err(DnsError::UpdateFailed("truncated response".into()));
+    }
+    if response[..2] != request[..2] {
+        return Err(DnsError::UpdateFailed("response to another message".into()));
+    }
+    let request_mac = read_tsig(request)?.expect("Updates are always signed").mac;
+    match read_tsig(response)? {
+        Some(tsig) => key.verify(response, &tsig, &request_mac, now)?,
+        // servers can't sign errors with a key they don't know, those are only reported
+        None if response[3] & 0x0f == 0 => {
+            return Err(DnsError::UpdateFailed("response isn't signed".into()));
+        }
+        None => (),
+    }
+    let rcode = match response[3] & 0x0f {
+        0 => return Ok(()),
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET",
+        8 => "NXRRSET",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        _ => "unknown error",
+    };
+    Err(DnsError::UpdateFailed(format!(
+        "server responded with {rcode}"
+    )))
+}
+
+/// Sends RFC 2136 messages to a DNS server and returns its response.
+#[async_trait]
+pub trait Rfc2136Transport: Send + Sync {
+    async fn exchange(&self, server: &str, message: &[u8]) -> Result<Vec<u8>, DnsError>;
+}
+
+/// Sends updates over UDP, they're small enough to fit in a single datagram.
+pub struct UdpTransport;
+
+#[async_trait]
+impl Rfc2136Transport for UdpTransport {
+    async fn exchange(&self, server: &str, message: &[u8]) -> Result<Vec<u8>, DnsError> {
+        let failed = |err: std::io::Error| DnsError::UpdateFailed(format!("{server}: {err}"));
+        let address = lookup_host(server)
+            .await
+            .map_err(failed)?
+            .next()
+            .ok_or_else(|| DnsError::UpdateFailed(format!("{server} can't be resolved")))?;
+        let local: SocketAddr = if address.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local).await.map_err(failed)?;
+        socket.connect(address).await.map_err(failed)?;
+        socket.send(message).await.map_err(failed)?;
+        let mut response = vec![0; 4096];
+        let length = timeout(DNS_UPDATE_TIMEOUT, socket.recv(&mut response))
+            .await
+            .map_err(|_| DnsError::UpdateFailed(format!("no response from {server}")))?
+            .map_err(failed)?;
+        response.truncate(length);
+        Ok(response)
+    }
+}
+
+enum Publisher<'a> {
+    Rfc2136 {
+        server: String,
+        key: TsigKey,
+        transport: &'a dyn Rfc2136Transport,
+    },
+    Webhook {
+        client: Client,
+        url: Url,
+        secret: Option<String>,
+    },
+}
+
+impl<'a> Publisher<'a> {
+    /// `None` if publishing is disabled.
+    fn from_settings(
+        settings: &Settings,
+        transport: &'a dyn Rfc2136Transport,
+    ) -> Result<Option<Self>, String> {
+        match settings.dns_provider {
+            DnsProvider::None => Ok(None),
+            DnsProvider::Rfc2136 => {
+                let Some(server) = settings.dns_server.as_deref().filter(|s| !s.is_empty()) else {
+                    return Err("DNS server is required for RFC 2136 updates".into());
+                };
+                let (Some(key_name), Some(secret)) =
+                    (&settings.dns_tsig_key_name, &settings.dns_tsig_secret)
+                else {
+                    return Err("TSIG key name and secret are required for RFC 2136 updates".into());
+                };
+                Ok(Some(Self::Rfc2136 {
+                    server: server_address(server),
+                    key: TsigKey::new(key_name, secret.expose_secret())?,
+                    transport,
+                }))
+            }
+            DnsProvider::Webhook => {
+                let Some(url) = &settings.dns_webhook_url else {
+                    return Err("Webhook URL is required for webhook updates".into());
+                };
+                let url = Url::parse(url).map_err(|err| format!("Invalid webhook URL: {err}"))?;
+                if !matches!(url.scheme(), "http" | "https") {
+                    return Err("Webhook URL has to use HTTP or HTTPS".into());
+                }
+                let client = Client::builder()
+                    .timeout(DNS_UPDATE_TIMEOUT)
+                    .build()
+                    .expect("Failed to build HTTP client");
+                Ok(Some(Self::Webhook {
+                    client,
+                    url,
+                    secret: settings
+                        .dns_webhook_secret
+                        .as_ref()
+                        .map(|secret| secret.expose_secret().to_string()),
+                }))
+            }
+        }
+    }
+
+    async fn publish(&self, update: &DnsUpdate) -> Result<(), DnsError> {
+        match self {
+            Self::Rfc2136 {
+                server,
+                key,
+                transport,
+            } => {
+                let id = rand::random();
+                let time_signed = Utc::now().timestamp().unsigned_abs();
+                let message = encode_update(id, update, key, time_signed);
+                let response = transport.exchange(server, &message).await?;
+                let now = Utc::now().timestamp().unsigned_abs();
+                check_response(key, &message, &response, now)
+            }
+            Self::Webhook {
+                client,
+                url,
+                secret,
+            } => {
+                let body = serde_json::to_vec(update).expect("Failed to serialize DNS update");
+                let mut request = client
+                    .post(url.clone())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json");
+                if let Some(secret) = secret {
+                    request = request.header(SIGNATURE_HEADER, sign_payload(secret, &body));
+                }
+                let response = request
+                    .body(body)
+                    .send()
+                    .await
+                    .map_err(|err| DnsError::UpdateFailed(err.to_string()))?;
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(DnsError::UpdateFailed(format!(
+                        "webhook responded with status {}",
+                        response.status()
+                    )))
+                }
+            }
+        }
+    }
+}
+
+/// Check if DNS publishing settings are complete.
+pub fn validate_settings(settings: &Settings) -> Result<(), String> {
+    if settings.dns_record_ttl < 1 {
+        return Err("DNS record TTL must be at least one second".into());
+    }
+    Publisher::from_settings(settings, &UdpTransport).map(|_| ())
+}
+
+/// Reject device names which can't be published, or would get the same record as another
+/// device. Names are only checked if publishing is enabled and some location has a zone.
+pub async fn ensure_publishable_name(
+    pool: &DbPool,
+    device_name: &str,
+    device_id: Option<i64>,
+) -> Result<(), DnsError> {
+    let settings = Settings::get_settings(pool).await?;
+    if settings.dns_provider == DnsProvider::None {
+        return Ok(());
+    }
+    let zone_set = query_scalar!(
+        "SELECT EXISTS (SELECT 1 FROM wireguard_network WHERE dns_zone IS NOT NULL AND NOT archived) \"exists!\""
+    )
+    .fetch_one(pool)
+    .await?;
+    if !zone_set {
+        return Ok(());
+    }
+    let Some(label) = device_label(device_name) else {
+        return Err(DnsError::InvalidLabel(device_name.into()));
+    };
+    let devices = query!(
+        "SELECT DISTINCT d.id, d.name FROM device d \
+        JOIN wireguard_network_device wnd ON wnd.device_id = d.id \
+        JOIN wireguard_network n ON n.id = wnd.wireguard_network_id \
+        WHERE n.dns_zone IS NOT NULL AND NOT n.archived AND d.id IS DISTINCT FROM $1",
+        device_id
+    )
+    .fetch_all(pool)
+    .await?;
+    match devices
+        .into_iter()
+        .find(|device| device_label(&device.name).as_deref() == Some(label.as_str()))
+    {
+        Some(existing) => Err(DnsError::NameCollision(device_name.into(), existing.name)),
+        None => Ok(()),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsRecordState {
+    Published,
+    /// Not published yet, or changes not published yet.
+    Pending,
+    /// Last attempt failed, it will be retried.
+    Failed,
+}
+
+/// DNS record of a device in a location, as shown in device details.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeviceDnsStatus {
+    pub network_id: i64,
+    pub name: String,
+    #[schema(value_type = String)]
+    pub address: IpAddr,
+    pub state: DnsRecordState,
+    pub error: Option<String>,
+    pub attempts: i32,
+    pub updated_at: NaiveDateTime,
+}
+
+impl DeviceDnsStatus {
+    pub async fn for_device(pool: &DbPool, device_id: i64) -> Result<Vec<Self>, SqlxError> {
+        let records = query!(
+            "SELECT network_id, zone, name, address \"address!: IpAddr\", published_zone, \
+            published_name, published_address \"published_address: IpAddr\", error, attempts, \
+            updated_at FROM device_dns_record \
+            WHERE device_id = $1 AND address IS NOT NULL ORDER BY network_id",
+            device_id
+        )
+        .fetch_all(pool)
+        .await?;
+        let statuses = records
+            .into_iter()
+            .map(|record| {
+                let published = record.published_zone.as_ref() == Some(&record.zone)
+                    && record.published_name.as_ref() == Some(&record.name)
+                    && record.published_address == Some(record.address);
+                let state = if record.error.is_some() {
+                    DnsRecordState::Failed
+                } else if published {
+                    DnsRecordState::Published
+                } else {
+                    DnsRecordState::Pending
+                };
+                Self {
+                    network_id: record.network_id,
+                    name: record.name,
+                    address: record.address,
+                    state,
+                    error: record.error,
+                    attempts: record.attempts,
+                    updated_at: record.updated_at,
+                }
+            })
+            .collect();
+        Ok(statuses)
+    }
+}
+
+// Wanted record and the one last published; no wanted address means removal
+struct DnsRecord {
+    id: i64,
+    zone: String,
+    name: String,
+    address: Option<IpAddr>,
+    published_zone: Option<String>,
+    published_name: Option<String>,
+    published_address: Option<IpAddr>,
+}
+
+impl DnsRecord {
+    fn updates(&self, ttl: u32) -> Vec<DnsUpdate> {
+        let mut updates = Vec::new();
+        if let (Some(zone), Some(name), Some(address)) = (
+            &self.published_zone,
+            &self.published_name,
+            self.published_address,
+        ) {
+            let record_type = RecordType::for_address(address);
+            // otherwise replaced by the upsert
+            let replaced = self.address.is_some_and(|address| {
+                RecordType::for_address(address) == record_type && name == &self.name
+            });
+            if !replaced {
+                DnsUpdate::add(
+                    &mut updates,
+                    zone,
+                    RecordChange::Delete {
+                        name: name.clone(),
+                        record_type,
+                    },
+                );
+            }
+        }
+        if let Some(address) = self.address {
+            DnsUpdate::add(
+                &mut updates,
+                &self.zone,
+                RecordChange::Upsert {
+                    name: self.name.clone(),
+                    record_type: RecordType::for_address(address),
+                    address,
+                    ttl,
+                },
+            );
+        }
+        updates
+    }
+}
+
+struct WantedRecord {
+    device_id: i64,
+    device_name: String,
+    network_id: i64,
+    zone: String,
+    address: IpAddr,
+}
+
+/// Store records wanted for current device addresses. Records no longer wanted
+/// are kept until they're removed from DNS.
+async fn reconcile(pool: &DbPool) -> Result<(), SqlxError> {
+    let wanted = query_as!(
+        WantedRecord,
+        "SELECT d.id device_id, d.name device_name, n.id network_id, n.dns_zone \"zone!\", \
+        wnd.wireguard_ip \"address: IpAddr\" \
+        FROM wireguard_network_device wnd \
+        JOIN device d ON d.id = wnd.device_id \
+        JOIN wireguard_network n ON n.id = wnd.wireguard_network_id \
+        WHERE n.dns_zone IS NOT NULL AND NOT n.archived ORDER BY d.id, n.id"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut transaction = pool.begin().await?;
+    let mut owners = HashMap::<String, String>::new();
+    let mut kept = HashSet::new();
+    for record in wanted {
+        let Some(label) = device_label(&record.device_name) else {
+            warn!(
+                "Device {} name can't be used as a DNS label, skipping its record",
+                record.device_name
+            );
+            continue;
+        };
+        let name = format!("{label}.{}", record.zone);
+        // names are checked when devices are added, but may still collide after imports
+        if let Some(owner) = owners.get(&name) {
+            warn!(
+                "DNS record {name} of device {} is already used by device {owner}, skipping",
+                record.device_name
+            );
+            continue;
+        }
+        query!(
+            "INSERT INTO device_dns_record (device_id, network_id, zone, name, address) \
+            VALUES ($1, $2, $3, $4, $5) \
+            ON CONFLICT (device_id, network_id) DO UPDATE \
+            SET zone = $3, name = $4, address = $5, error = NULL, attempts = 0, updated_at = now() \
+            WHERE (device_dns_record.zone, device_dns_record.name, device_dns_record.address) \
+            IS DISTINCT FROM ($3, $4, $5)",
+            record.device_id,
+            record.network_id,
+            record.zone,
+            name,
+            IpNetwork::from(record.address),
+        )
+        .execute(&mut *transaction)
+        .await?;
+        owners.insert(name, record.device_name);
+        kept.insert((record.device_id, record.network_id));
+    }
+
+    let stored =
+        query!("SELECT id, device_id, network_id FROM device_dns_record WHERE address IS NOT NULL")
+            .fetch_all(&mut *transaction)
+            .await?;
+    for record in stored {
+        if !kept.contains(&(record.device_id, record.network_id)) {
+            query!(
+                "UPDATE device_dns_record SET address = NULL, error = NULL, attempts = 0, \
+                updated_at = now() WHERE id = $1",
+                record.id
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+    }
+    query!("DELETE FROM device_dns_record WHERE address IS NULL AND published_address IS NULL")
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await
+}
+
+/// Publish changed records. Returns the number of records which failed.
+async fn publish_changes(
+    pool: &DbPool,
+    publisher: &Publisher<'_>,
+    ttl: u32,
+) -> Result<usize, SqlxError> {
+    let records = query_as!(
+        DnsRecord,
+        "SELECT id, zone, name, address \"address: IpAddr\", published_zone, published_name, \
+        published_address \"published_address: IpAddr\" \
+        FROM device_dns_record \
+        WHERE (zone, name, address) IS DISTINCT FROM (published_zone, published_name, published_address) \
+        ORDER BY id"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut failed = 0;
+    for record in records {
+        let mut result = Ok(());
+        for update in record.updates(ttl) {
+            result = publisher.publish(&update).await;
+            if result.is_err() {
+                break;
+            }
+        }
+        match result {
+            Ok(()) if record.address.is_none() => {
+                debug!("Removed DNS record {}", record.name);
+                query!("DELETE FROM device_dns_record WHERE id = $1", record.id)
+                    .execute(pool)
+                    .await?;
+            }
+            Ok(()) => {
+                debug!("Published DNS record {}", record.name);
+                query!(
+                    "UPDATE device_dns_record SET published_zone = zone, published_name = name, \
+                    published_address = address, error = NULL, attempts = 0, updated_at = now() \
+                    WHERE id = $1",
+                    record.id
+                )
+                .execute(pool)
+                .await?;
+            }
+            Err(err) => {
+                warn!("Failed to publish DNS record {}: {err}", record.name);
+                failed += 1;
+                query!(
+                    "UPDATE device_dns_record SET error = $2, attempts = attempts + 1, \
+                    updated_at = now() WHERE id = $1",
+                    record.id,
+                    err.to_string()
+                )
+                .execute(pool)
+                .await?;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// Bring published DNS records in line with device addresses.
+/// Fails if some records couldn't be published; they're retried on next sync.
+pub async fn sync_dns_records(
+    pool: &DbPool,
+    transport: &dyn Rfc2136Transport,
+) -> Result<(), DnsError> {
+    let _lock = SYNC_LOCK.get_or_init(Mutex::default).lock().await;
+    let settings = Settings::get_settings(pool).await?;
+    let Some(publisher) =
+        Publisher::from_settings(&settings, transport).map_err(DnsError::Settings)?
+    else {
+        return Ok(());
+    };
+    reconcile(pool).await?;
+    let ttl = u32::try_from(settings.dns_record_ttl).unwrap_or_default();
+    match publish_changes(pool, &publisher, ttl).await? {
+        0 => Ok(()),
+        failed => Err(DnsError::UpdateFailed(format!(
+            "{failed} records not published"
+        ))),
+    }
+}
+
+fn sync_requested() -> &'static Notify {
+    SYNC_REQUESTED.get_or_init(Notify::new)
+}
+
+/// Sync DNS records soon, for changes which aren't sent to gateways, e.g. device names.
+pub fn request_sync() {
+    sync_requested().notify_one();
+}
+
+/// Sync DNS records after device and location changes sent to gateways, or when requested.
+pub async fn run_dns_publisher(pool: DbPool, mut events_rx: Receiver<GatewayEvent>) {
+    info!("Publishing DNS records of devices");
+    loop {
+        tokio::select! {
+            event = events_rx.recv() => if let Err(RecvError::Closed) = event {
+                return;
+            },
+            () = sync_requested().notified() => (),
+        }
+        // a single sync covers all changes made so far
+        while matches!(events_rx.try_recv(), Ok(_) | Err(TryRecvError::Lagged(_))) {}
+        if let Err(err) = sync_dns_records(&pool, &UdpTransport).await {
+            warn!("Failed to sync DNS records, they will be retried: {err}");
+        }
+    }
+}
+
+/// Background job retrying DNS records which failed to publish.
+#[must_use]
+pub fn dns_publish_job(pool: DbPool) -> Job {
+    Job::new(
+        "dns_publish",
+        JobSchedule::Interval(DNS_RETRY_INTERVAL),
+        move || {
+            let pool = pool.clone();
+            async move {
+                sync_dns_records(&pool, &UdpTransport).await?;
+                Ok(())
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        str::FromStr,
+        sync::{
+            atomic::{AtomicU8, Ordering},
+            Mutex as StdMutex,
+        },
+    };
+
+    use super::*;
+    use crate::{
+        db::{Device, User, WireguardNetwork},
+        secret::SecretString,
+    };
+
+    // RFC 2136 server answering every update with configured response code
+    #[derive(Default)]
+    struct MockTransport {
+        messages: StdMutex<Vec<Vec<u8>>>,
+        rcode: AtomicU8,
+    }
+
+    impl MockTransport {
+        fn take_updates(&self) -> Vec<DecodedUpdate> {
+            self.messages
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|message| decode_update(&message))
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl Rfc2136Transport for MockTransport {
+        async fn exchange(&self, server: &str, message: &[u8]) -> Result<Vec<u8>, DnsError> {
+            assert_eq!(server, "127.0.0.1:53");
+            self.messages.lock().unwrap().push(message.to_vec());
+            Ok(signed_response(
+                message,
+                self.rcode.load(Ordering::SeqCst),
+                Utc::now().timestamp().unsigned_abs(),
+            ))
+        }
+    }
+
+    // response to `request` signed with the key from `enable_rfc2136`
+    fn signed_response(request: &[u8], rcode: u8, time_signed: u64) -> Vec<u8> {
+        let key = TsigKey::new("defguard.", "c2VjcmV0IGtleQ==").unwrap();
+        let request_mac = read_tsig(request).unwrap().unwrap().mac;
+        let mut response = vec![0; HEADER_LENGTH];
+        response[..2].copy_from_slice(&request[..2]);
+        response[2] = 0xa8;
+        response[3] = rcode;
+        key.sign(&mut response, time_signed, Some(&request_mac));
+        response
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct DecodedRecord {
+        name: String,
+        record_type: u16,
+        class: u16,
+        ttl: u32,
+        rdata: Vec<u8>,
+    }
+
+    #[derive(Debug)]
+    struct DecodedUpdate {
+        zone: String,
+        updates: Vec<DecodedRecord>,
+        tsig: DecodedRecord,
+    }
+
+    fn read_u16(message: &[u8], pos: &mut usize) -> u16 {
+        let value = u16::from_be_bytes([message[*pos], message[*pos + 1]]);
+        *pos += 2;
+        value
+    }
+
+    fn read_name(message: &[u8], pos: &mut usize) -> String {
+        let mut labels = Vec::new();
+        loop {
+            let length = message[*pos] as usize;
+            *pos += 1;
+            if length == 0 {
+                return labels.join(".");
+            }
+            labels.push(String::from_utf8(message[*pos..*pos + length].to_vec()).unwrap());
+            *pos += length;
+        }
+    }
+
+    fn read_record(message: &[u8], pos: &mut usize) -> DecodedRecord {
+        let name = read_name(message, pos);
+        let record_type = read_u16(message, pos);
+        let class = read_u16(message, pos);
+        let ttl = (u32::from(read_u16(message, pos)) << 16) | u32::from(read_u16(message, pos));
+        let length = read_u16(message, pos) as usize;
+        let rdata = message[*pos..*pos + length].to_vec();
+        *pos += length;
+        DecodedRecord {
+            name,
+            record_type,
+            class,
+            ttl,
+            rdata,
+        }
+    }
+
+    fn decode_update(message: &[u8]) -> DecodedUpdate {
+        assert_eq!(message[2] >> 3, OPCODE_UPDATE);
+        let mut pos = 4;
+        let counts: Vec<_> = (0..4).map(|_| read_u16(message, &mut pos)).collect();
+        assert_eq!(counts[0], 1);
+        assert_eq!(counts[1], 0);
+        assert_eq!(counts[3], 1);
+        let zone = read_name(message, &mut pos);
+        assert_eq!(read_u16(message, &mut pos), TYPE_SOA);
+        assert_eq!(read_u16(message, &mut pos), CLASS_IN);
+        let updates = (0..counts[2])
+            .map(|_| read_record(message, &mut pos))
+            .collect();
+        let tsig = read_record(message, &mut pos);
+        assert_eq!(pos, message.len());
+        DecodedUpdate {
+            zone,
+            updates,
+            tsig,
+        }
+    }
+
+    fn delete(name: &str, record_type: u16) -> DecodedRecord {
+        DecodedRecord {
+            name: name.into(),
+            record_type,
+            class: CLASS_ANY,
+            ttl: 0,
+            rdata: Vec::new(),
+        }
+    }
+
+    fn add(name: &str, address: &str) -> DecodedRecord {
+        let (record_type, rdata) = match IpAddr::from_str(address).unwrap() {
+            IpAddr::V4(address) => (TYPE_A, address.octets().to_vec()),
+            IpAddr::V6(address) => (TYPE_AAAA, address.octets().to_vec()),
+        };
+        DecodedRecord {
+            name: name.into(),
+            record_type,
+            class: CLASS_IN,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    async fn enable_rfc2136(pool: &DbPool) {
+        let mut settings = Settings::get_settings(pool).await.unwrap();
+        settings.dns_provider = DnsProvider::Rfc2136;
+        settings.dns_server = Some("127.0.0.1".into());
+        settings.dns_tsig_key_name = Some("defguard.".into());
+        settings.dns_tsig_secret = Some(SecretString::from_str("c2VjcmV0IGtleQ==").unwrap());
+        settings.save(pool).await.unwrap();
+    }
+
+    async fn setup(pool: &DbPool) -> (WireguardNetwork, i64) {
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.dns_zone = Some("office.vpn.example.com".into());
+        network.save(pool).await.unwrap();
+        let mut user = User::new(
+            "testuser",
+            Some("hunter2"),
+            "Tester",
+            "Test",
+            "test@test.com",
+            None,
+        );
+        user.save(pool).await.unwrap();
+        (network, user.id.unwrap())
+    }
+
+    async fn statuses(pool: &DbPool, device: &Device) -> Vec<DeviceDnsStatus> {
+        DeviceDnsStatus::for_device(pool, device.id.unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_device_label() {
+        assert_eq!(device_label("printer").as_deref(), Some("printer"));
+        assert_eq!(device_label("Anna's NAS").as_deref(), Some("anna-s-nas"));
+        assert_eq!(
+            device_label(" -- Living room TV!").as_deref(),
+            Some("living-room-tv")
+        );
+        assert_eq!(device_label("żółw 2").as_deref(), Some("w-2"));
+        assert_eq!(device_label("!!!"), None);
+        assert_eq!(device_label(&"a".repeat(100)).unwrap().len(), 63);
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(
+            normalize_name(" VPN.Example.com. ").unwrap(),
+            "vpn.example.com"
+        );
+        assert!(normalize_name("").is_err());
+        assert!(normalize_name("vpn..example.com").is_err());
+        assert!(normalize_name("-vpn.example.com").is_err());
+        assert!(normalize_name("vpn example.com").is_err());
+        assert_eq!(server_address("10.0.0.53"), "10.0.0.53:53");
+        assert_eq!(server_address("fd00::53"), "[fd00::53]:53");
+        assert_eq!(server_address("ns1.example.com"), "ns1.example.com:53");
+        assert_eq!(
+            server_address("ns1.example.com:5353"),
+            "ns1.example.com:5353"
+        );
+    }
+
+    #[test]
+    fn test_encode_update() {
+        let key = TsigKey::new("Defguard", "c2VjcmV0IGtleQ==").unwrap();
+        let update = DnsUpdate {
+            zone: "vpn.example.com".into(),
+            changes: vec![
+                RecordChange::Delete {
+                    name: "old.vpn.example.com".into(),
+                    record_type: RecordType::A,
+                },
+                RecordChange::Upsert {
+                    name: "nas.vpn.example.com".into(),
+                    record_type: RecordType::Aaaa,
+                    address: "fd00::2".parse().unwrap(),
+                    ttl: 300,
+                },
+            ],
+        };
+        let message = encode_update(0x1234, &update, &key, 1_700_000_000);
+        assert_eq!(message[..2], [0x12, 0x34]);
+        let decoded = decode_update(&message);
+        assert_eq!(decoded.zone, "vpn.example.com");
+        assert_eq!(
+            decoded.updates,
+            [
+                delete("old.vpn.example.com", TYPE_A),
+                delete("nas.vpn.example.com", TYPE_AAAA),
+                add("nas.vpn.example.com", "fd00::2"),
+            ]
+        );
+        assert_eq!(decoded.tsig.name, "defguard");
+        assert_eq!(decoded.tsig.record_type, TYPE_TSIG);
+
+        // TSIG record per RFC 8945, MAC over the message and TSIG variables
+        let rdata = &decoded.tsig.rdata;
+        let mut pos = 0;
+        assert_eq!(read_name(rdata, &mut pos), TSIG_ALGORITHM);
+        assert_eq!(rdata[pos..pos + 6], 1_700_000_000u64.to_be_bytes()[2..]);
+        pos += 6;
+        assert_eq!(read_u16(rdata, &mut pos), TSIG_FUDGE);
+        let mac_length = read_u16(rdata, &mut pos) as usize;
+        let mac = &rdata[pos..pos + mac_length];
+        pos += mac_length;
+        assert_eq!(rdata[pos..], [0x12, 0x34, 0, 0, 0, 0]);
+
+        let tsig_start = message.len() - (decoded.tsig.rdata.len() + 10 + 10);
+        let mut unsigned = message[..tsig_start].to_vec();
+        unsigned[11] = 0;
+        let mut expected = Hmac::<Sha256>::new_from_slice(b"secret key").unwrap();
+        expected.update(&unsigned);
+        expected.update(b"\x08defguard\x00\x00\xff\x00\x00\x00\x00");
+        expected.update(b"\x0bhmac-sha256\x00");
+        expected.update(&1_700_000_000u64.to_be_bytes()[2..]);
+        expected.update(&[0x01, 0x2c, 0, 0, 0, 0]);
+        expected.verify_slice(mac).unwrap();
+
+        // responses are signed along with the request MAC
+        let request_mac = read_tsig(&message).unwrap().unwrap().mac;
+        let now = 1_700_000_010;
+        let response = signed_response(&message, 0, 1_700_000_000);
+        let tsig = read_tsig(&response).unwrap().unwrap();
+        assert_eq!(tsig.key_name, "defguard");
+        assert_eq!(tsig.original_id, 0x1234);
+        let mut expected = Hmac::<Sha256>::new_from_slice(b"secret key").unwrap();
+        expected.update(&[0, 32]);
+        expected.update(&request_mac);
+        expected.update(&[0x12, 0x34, 0xa8, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        expected.update(b"\x08defguard\x00\x00\xff\x00\x00\x00\x00");
+        expected.update(b"\x0bhmac-sha256\x00");
+        expected.update(&1_700_000_000u64.to_be_bytes()[2..]);
+        expected.update(&[0x01, 0x2c, 0, 0, 0, 0]);
+        expected.verify_slice(&tsig.mac).unwrap();
+        assert!(check_response(&key, &message, &response, now).is_ok());
+
+        let update_failed = |result: Result<(), DnsError>, reason: &str| {
+            assert!(
+                matches!(&result, Err(DnsError::UpdateFailed(message)) if message.contains(reason)),
+                "expected {reason}, got {result:?}"
+            );
+        };
+        let response = signed_response(&message, 5, 1_700_000_000);
+        update_failed(check_response(&key, &message, &response, now), "REFUSED");
+        // errors can't always be signed
+        let response = [0x12, 0x34, 0xa8, 0x09, 0, 0, 0, 0, 0, 0, 0, 0];
+        update_failed(check_response(&key, &message, &response, now), "NOTAUTH");
+
+        // spoofed success
+        let response = [0x12, 0x34, 0xa8, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        update_failed(
+            check_response(&key, &message, &response, now),
+            "isn't signed",
+        );
+        let mut response = signed_response(&message, 5, 1_700_000_000);
+        response[3] = 0;
+        update_failed(
+            check_response(&key, &message, &response, now),
+            "signature is invalid",
+        );
+        let other_key = TsigKey::new("defguard", "b3RoZXIga2V5").unwrap();
+        let mut response = vec![0x12, 0x34, 0xa8, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        other_key.sign(&mut response, 1_700_000_000, Some(&request_mac));
+        update_failed(
+            check_response(&key, &message, &response, now),
+            "signature is invalid",
+        );
+        let response = signed_response(&message, 0, 1_700_000_000);
+        update_failed(
+            check_response(&key, &message, &response, now + 3600),
+            "time window",
+        );
+        let other_message = encode_update(0x4321, &update, &key, 1_700_000_000);
+        update_failed(
+            check_response(&key, &other_message, &response, now),
+            "another message",
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_sync_dns_records(pool: DbPool) {
+        let transport = MockTransport::default();
+        let (network, user_id) = setup(&pool).await;
+
+        // nothing is published while disabled
+        let (device, _) =
+            Device::new_with_ip(&pool, user_id, "Anna's NAS".into(), "key".into(), &network)
+                .await
+                .unwrap();
+        sync_dns_records(&pool, &transport).await.unwrap();
+        assert!(transport.take_updates().is_empty());
+        assert!(statuses(&pool, &device).await.is_empty());
+
+        // create
+        enable_rfc2136(&pool).await;
+        sync_dns_records(&pool, &transport).await.unwrap();
+        let updates = transport.take_updates();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].zone, "office.vpn.example.com");
+        assert_eq!(
+            updates[0].updates,
+            [
+                delete("anna-s-nas.office.vpn.example.com", TYPE_A),
+                add("anna-s-nas.office.vpn.example.com", "10.1.1.2"),
+            ]
+        );
+        assert_eq!(updates[0].tsig.name, "defguard");
+        let status = statuses(&pool, &device).await;
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].name, "anna-s-nas.office.vpn.example.com");
+        assert_eq!(status[0].state, DnsRecordState::Published);
+
+        // unchanged records aren't published again
+        sync_dns_records(&pool, &transport).await.unwrap();
+        assert!(transport.take_updates().is_empty());
+
+        // IP change
+        query!(
+            "UPDATE wireguard_network_device SET wireguard_ip = $1 WHERE device_id = $2",
+            IpNetwork::from_str("10.1.1.20").unwrap(),
+            device.id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sync_dns_records(&pool, &transport).await.unwrap();
+        let updates = transport.take_updates();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(
+            updates[0].updates,
+            [
+                delete("anna-s-nas.office.vpn.example.com", TYPE_A),
+                add("anna-s-nas.office.vpn.example.com", "10.1.1.20"),
+            ]
+        );
+
+        // rename removes the old record
+        let mut device = device;
+        device.name = "nas".into();
+        device.save(&pool).await.unwrap();
+        sync_dns_records(&pool, &transport).await.unwrap();
+        let updates = transport.take_updates();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(
+            updates[0].updates,
+            [
+                delete("anna-s-nas.office.vpn.example.com", TYPE_A),
+                delete("nas.office.vpn.example.com", TYPE_A),
+                add("nas.office.vpn.example.com", "10.1.1.20"),
+            ]
+        );
+
+        // delete
+        let device_id = device.id.unwrap();
+        device.delete(&pool).await.unwrap();
+        sync_dns_records(&pool, &transport).await.unwrap();
+        let updates = transport.take_updates();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(
+            updates[0].updates,
+            [delete("nas.office.vpn.example.com", TYPE_A)]
+        );
+        let remaining = query_scalar!(
+            "SELECT count(*) \"count!\" FROM device_dns_record WHERE device_id = $1",
+            device_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    #[sqlx::test]
+    async fn test_failed_updates_retried(pool: DbPool) {
+        let transport = MockTransport::default();
+        let (network, user_id) = setup(&pool).await;
+        enable_rfc2136(&pool).await;
+        let (device, _) = Device::new_with_ip(&pool, user_id, "nas".into(), "key".into(), &network)
+            .await
+            .unwrap();
+
+        // REFUSED
+        transport.rcode.store(5, Ordering::SeqCst);
+        assert!(matches!(
+            sync_dns_records(&pool, &transport).await,
+            Err(DnsError::UpdateFailed(_))
+        ));
+        let status = statuses(&pool, &device).await;
+        assert_eq!(status[0].state, DnsRecordState::Failed);
+        assert_eq!(status[0].attempts, 1);
+        assert!(status[0].error.as_ref().unwrap().contains("REFUSED"));
+
+        transport.rcode.store(0, Ordering::SeqCst);
+        sync_dns_records(&pool, &transport).await.unwrap();
+        assert_eq!(transport.take_updates().len(), 2);
+        let status = statuses(&pool, &device).await;
+        assert_eq!(status[0].state, DnsRecordState::Published);
+        assert_eq!(status[0].attempts, 0);
+        assert!(status[0].error.is_none());
+    }
+
+    #[sqlx::test]
+    async fn test_name_collision(pool: DbPool) {
+        let (network, user_id) = setup(&pool).await;
+        let (device, _) =
+            Device::new_with_ip(&pool, user_id, "Anna's NAS".into(), "key".into(), &network)
+                .await
+                .unwrap();
+
+        // names aren't checked while publishing is disabled
+        ensure_publishable_name(&pool, "anna-s-nas", None)
+            .await
+            .unwrap();
+
+        enable_rfc2136(&pool).await;
+        assert!(matches!(
+            ensure_publishable_name(&pool, "ANNA S NAS", None).await,
+            Err(DnsError::NameCollision(name, existing)) if name == "ANNA S NAS" && existing == "Anna's NAS"
+        ));
+        assert!(matches!(
+            ensure_publishable_name(&pool, "???", None).await,
+            Err(DnsError::InvalidLabel(_))
+        ));
+        ensure_publishable_name(&pool, "printer", None)
+            .await
+            .unwrap();
+        // renaming a device to a name with the same label
+        ensure_publishable_name(&pool, "anna-s-nas", device.id)
+            .await
+            .unwrap();
+    }
+}
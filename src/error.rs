@@ -3,14 +3,25 @@ use sqlx::error::Error as SqlxError;
 use thiserror::Error;
 
 use crate::{
-    auth::failed_login::FailedLoginError,
+    auth::{challenge::ChallengeError, failed_login::FailedLoginError},
     db::models::{
-        device::DeviceError, enrollment::TokenError, error::ModelError,
-        wireguard::WireguardNetworkError,
+        device::DeviceError,
+        enrollment::TokenError,
+        error::ModelError,
+        wireguard::{InvalidAddress, WireguardNetworkError},
     },
+    dns::DnsError,
     grpc::GatewayMapError,
+    guest_access::GuestAccessError,
+    jobs::JobError,
+    key_escrow::KeyEscrowError,
     ldap::error::LdapError,
+    password_policy::PasswordPolicyError,
+    ssh_ca::SshCaError,
     templates::TemplateError,
+    user_suspension::SuspensionError,
+    wireguard_config_qr::ConfigQrError,
+    wireguard_psk_rotation::PskRotationError,
 };
 
 /// Represents kinds of error that occurred
@@ -32,24 +43,43 @@ pub enum WebError {
     Serialization(String),
     #[error("Authorization error: {0}")]
     Authorization(String),
+    #[error("Session expired due to inactivity")]
+    SessionIdle,
     #[error("Forbidden error: {0}")]
     Forbidden(String),
+    // denied by an extractor, see `crate::auth::authz_trace`
+    #[error("Access denied: {msg}")]
+    AccessDenied { msg: String, trace_id: String },
     #[error("Database error: {0}")]
     DbError(String),
     #[error("Model error: {0}")]
     ModelError(String),
-    #[error("Public key invalid {0}")]
+    #[error("Public key invalid: {0}")]
     PubkeyValidation(String),
-    #[error("Public key already exists {0}")]
+    #[error("Public key already exists: {0}")]
     PubkeyExists(String),
     #[error("HTTP error: {0}")]
     Http(StatusCode),
     #[error(transparent)]
     TooManyLoginAttempts(#[from] FailedLoginError),
+    #[error(transparent)]
+    Challenge(#[from] ChallengeError),
     #[error("Bad request: {0}")]
     BadRequest(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Device limit reached, user has {count} of {limit} devices")]
+    DeviceLimitExceeded { count: i64, limit: i32 },
+    #[error("User already has a device named {name}, try {suggestion}")]
+    DeviceNameExists { name: String, suggestion: String },
+    #[error("Platform not allowed: {0}")]
+    PlatformNotAllowed(String),
+    #[error("Invalid addresses: {0:?}")]
+    InvalidAddresses(Vec<InvalidAddress>),
     #[error(transparent)]
     TemplateError(#[from] TemplateError),
+    #[error(transparent)]
+    PasswordPolicy(#[from] PasswordPolicyError),
     #[error("Server config missing")]
     ServerConfigMissing,
 }
@@ -91,10 +121,31 @@ impl From<ModelError> for WebError {
 impl From<DeviceError> for WebError {
     fn from(error: DeviceError) -> Self {
         match error {
-            DeviceError::PubkeyConflict(..) => Self::PubkeyValidation(error.to_string()),
+            DeviceError::PubkeyConflict(..) | DeviceError::InvalidName(_) => {
+                Self::BadRequest(error.to_string())
+            }
             DeviceError::DatabaseError(_) => Self::DbError(error.to_string()),
             DeviceError::ModelError(_) => Self::ModelError(error.to_string()),
             DeviceError::Unexpected(_) => Self::Http(StatusCode::INTERNAL_SERVER_ERROR),
+            DeviceError::LimitExceeded { count, limit } => {
+                Self::DeviceLimitExceeded { count, limit }
+            }
+            DeviceError::NameExists { name, suggestion } => {
+                Self::DeviceNameExists { name, suggestion }
+            }
+        }
+    }
+}
+
+impl From<DnsError> for WebError {
+    fn from(error: DnsError) -> Self {
+        match error {
+            DnsError::InvalidLabel(_) | DnsError::Settings(_) => {
+                Self::BadRequest(error.to_string())
+            }
+            DnsError::NameCollision(..) => Self::Conflict(error.to_string()),
+            DnsError::DbError(_) => Self::DbError(error.to_string()),
+            DnsError::UpdateFailed(_) => Self::Http(StatusCode::INTERNAL_SERVER_ERROR),
         }
     }
 }
@@ -116,7 +167,22 @@ impl From<WireguardNetworkError> for WebError {
         match error {
             WireguardNetworkError::NetworkTooSmall
             | WireguardNetworkError::IpNetworkError(_)
-            | WireguardNetworkError::InvalidDevicePubkey(_) => Self::BadRequest(error.to_string()),
+            | WireguardNetworkError::InvalidPresharedKey(_)
+            | WireguardNetworkError::PresharedKeyNotAllowed(_) => {
+                Self::BadRequest(error.to_string())
+            }
+            WireguardNetworkError::DeviceError(
+                error @ (DeviceError::InvalidName(_) | DeviceError::NameExists { .. }),
+            ) => error.into(),
+            WireguardNetworkError::InvalidDevicePubkey(_) => {
+                Self::PubkeyValidation(error.to_string())
+            }
+            WireguardNetworkError::DuplicateDevicePubkey(..) => {
+                Self::PubkeyExists(error.to_string())
+            }
+            WireguardNetworkError::PlatformNotAllowed { .. } => {
+                Self::PlatformNotAllowed(error.to_string())
+            }
             WireguardNetworkError::DbError(_)
             | WireguardNetworkError::ModelError(_)
             | WireguardNetworkError::Unexpected(_)
@@ -128,6 +194,59 @@ impl From<WireguardNetworkError> for WebError {
     }
 }
 
+impl From<PskRotationError> for WebError {
+    fn from(error: PskRotationError) -> Self {
+        match error {
+            PskRotationError::DbError(_) => Self::DbError(error.to_string()),
+            PskRotationError::TemplateError(err) => Self::TemplateError(err),
+            PskRotationError::OwnerNotFound(_) | PskRotationError::DeviceNotFound(_) => {
+                Self::ObjectNotFound(error.to_string())
+            }
+            PskRotationError::EventError(_) => Self::Http(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+impl From<SuspensionError> for WebError {
+    fn from(error: SuspensionError) -> Self {
+        match error {
+            SuspensionError::DbError(err) => err.into(),
+            SuspensionError::NetworkError(err) => err.into(),
+        }
+    }
+}
+
+impl From<GuestAccessError> for WebError {
+    fn from(error: GuestAccessError) -> Self {
+        match error {
+            GuestAccessError::DbError(err) => err.into(),
+            GuestAccessError::NetworkError(err) => err.into(),
+            GuestAccessError::TokenError(err) => err.into(),
+            GuestAccessError::TemplateError(err) => Self::TemplateError(err),
+        }
+    }
+}
+
+impl From<ConfigQrError> for WebError {
+    fn from(error: ConfigQrError) -> Self {
+        match error {
+            ConfigQrError::Encode(_) => Self::BadRequest(error.to_string()),
+            ConfigQrError::Image(_) => Self::Serialization(error.to_string()),
+        }
+    }
+}
+
+impl From<JobError> for WebError {
+    fn from(error: JobError) -> Self {
+        match error {
+            JobError::NotFound(_) => Self::ObjectNotFound(error.to_string()),
+            JobError::AlreadyRunning(_) => Self::Http(StatusCode::CONFLICT),
+            JobError::Failed(..) => Self::Http(StatusCode::INTERNAL_SERVER_ERROR),
+            JobError::DbError(_) => Self::DbError(error.to_string()),
+        }
+    }
+}
+
 impl From<TokenError> for WebError {
     fn from(err: TokenError) -> Self {
         error!("{}", err);
@@ -148,6 +267,34 @@ impl From<TokenError> for WebError {
             | TokenError::TemplateErrorInternal(_) => {
                 WebError::Http(StatusCode::INTERNAL_SERVER_ERROR)
             }
+            TokenError::DeviceError(err) => err.into(),
+            TokenError::TooManyAttempts(_) => WebError::Http(StatusCode::TOO_MANY_REQUESTS),
+        }
+    }
+}
+
+impl From<KeyEscrowError> for WebError {
+    fn from(error: KeyEscrowError) -> Self {
+        match error {
+            KeyEscrowError::DbError(_) => Self::DbError(error.to_string()),
+            KeyEscrowError::NotConfigured => Self::BadRequest(error.to_string()),
+            KeyEscrowError::Encryption | KeyEscrowError::Decryption => {
+                error!("Device key escrow error: {error}");
+                Self::Http(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+impl From<SshCaError> for WebError {
+    fn from(error: SshCaError) -> Self {
+        match error {
+            SshCaError::DbError(_) => Self::DbError(error.to_string()),
+            SshCaError::KeyError(_) | SshCaError::KeyFileError(_) | SshCaError::EncryptedKey => {
+                error!("SSH certificate authority error: {error}");
+                Self::Http(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            SshCaError::KeyEncryptionError(error) => error.into(),
         }
     }
 }
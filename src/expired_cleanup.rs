@@ -0,0 +1,387 @@
+//! Removal of expired sessions, tokens and idempotency records.
+//!
+//! Validators only ignore expired rows, so without cleanup their tables grow forever.
+//! A background job deletes them in bounded batches, and validators delete the ones they
+//! come across. Each delete repeats the checks the validator uses, so a row which is still
+//! valid for an in-flight request is never removed. Used, revoked and expired tokens are
+//! kept for a grace period, so that their owners get a meaningful error shortly after.
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use chrono::{Duration as ChronoDuration, Utc};
+use sqlx::Error as SqlxError;
+use utoipa::ToSchema;
+
+use crate::{
+    db::{
+        models::{enrollment::Token, shared_config::SharedConfig},
+        DbPool, Session,
+    },
+    idempotency,
+    jobs::{Job, JobSchedule},
+    server_config,
+};
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// rows removed by a single statement, keeps locks held by the cleanup short
+const CLEANUP_BATCH_SIZE: i64 = 500;
+// how long tokens which can't be used anymore are kept
+const TOKEN_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+static METRICS: CleanupMetrics = CleanupMetrics::new();
+
+/// Counters of rows removed since server start, by the job and inline.
+struct CleanupMetrics {
+    runs: AtomicU64,
+    sessions: AtomicU64,
+    tokens: AtomicU64,
+    shared_configs: AtomicU64,
+    client_mfa_sessions: AtomicU64,
+    idempotency_records: AtomicU64,
+}
+
+impl CleanupMetrics {
+    const fn new() -> Self {
+        Self {
+            runs: AtomicU64::new(0),
+            sessions: AtomicU64::new(0),
+            tokens: AtomicU64::new(0),
+            shared_configs: AtomicU64::new(0),
+            client_mfa_sessions: AtomicU64::new(0),
+            idempotency_records: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CleanupSnapshot {
+    pub runs: u64,
+    pub sessions: u64,
+    pub tokens: u64,
+    pub shared_configs: u64,
+    pub client_mfa_sessions: u64,
+    pub idempotency_records: u64,
+}
+
+/// Current cleanup metrics.
+#[must_use]
+pub fn cleanup_metrics() -> CleanupSnapshot {
+    CleanupSnapshot {
+        runs: METRICS.runs.load(Ordering::Relaxed),
+        sessions: METRICS.sessions.load(Ordering::Relaxed),
+        tokens: METRICS.tokens.load(Ordering::Relaxed),
+        shared_configs: METRICS.shared_configs.load(Ordering::Relaxed),
+        client_mfa_sessions: METRICS.client_mfa_sessions.load(Ordering::Relaxed),
+        idempotency_records: METRICS.idempotency_records.load(Ordering::Relaxed),
+    }
+}
+
+/// Rows removed by a single cleanup run.
+#[derive(Debug, Default, PartialEq)]
+pub struct CleanupResult {
+    pub sessions: u64,
+    pub tokens: u64,
+    pub shared_configs: u64,
+    pub idempotency_records: u64,
+}
+
+// repeat batch deletion until there's nothing more to delete
+async fn delete_in_batches<F, Fut>(mut delete_batch: F) -> Result<u64, SqlxError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<u64, SqlxError>>,
+{
+    let mut total = 0;
+    loop {
+        let deleted = delete_batch().await?;
+        total += deleted;
+        if deleted < CLEANUP_BATCH_SIZE as u64 {
+            return Ok(total);
+        }
+    }
+}
+
+/// Delete expired sessions and idempotency records, and tokens and shared config links
+/// which can't be used anymore.
+pub async fn remove_expired(pool: &DbPool) -> Result<CleanupResult, SqlxError> {
+    let now = Utc::now().naive_utc();
+    let threshold = (Utc::now()
+        - ChronoDuration::from_std(TOKEN_GRACE_PERIOD).expect("Failed to parse duration"))
+    .naive_utc();
+    let session_timeout = ChronoDuration::from_std(*server_config().enrollment_session_timeout)
+        .expect("Failed to parse duration");
+
+    let result = CleanupResult {
+        sessions: delete_in_batches(|| Session::delete_expired(pool, CLEANUP_BATCH_SIZE)).await?,
+        tokens: delete_in_batches(|| {
+            Token::delete_stale(
+                pool,
+                threshold,
+                threshold - session_timeout,
+                CLEANUP_BATCH_SIZE,
+            )
+        })
+        .await?,
+        shared_configs: delete_in_batches(|| {
+            SharedConfig::delete_stale(pool, threshold, CLEANUP_BATCH_SIZE)
+        })
+        .await?,
+        idempotency_records: delete_in_batches(|| {
+            idempotency::delete_expired(pool, now, CLEANUP_BATCH_SIZE)
+        })
+        .await?,
+    };
+
+    METRICS.runs.fetch_add(1, Ordering::Relaxed);
+    METRICS
+        .sessions
+        .fetch_add(result.sessions, Ordering::Relaxed);
+    METRICS.tokens.fetch_add(result.tokens, Ordering::Relaxed);
+    METRICS
+        .shared_configs
+        .fetch_add(result.shared_configs, Ordering::Relaxed);
+    METRICS
+        .idempotency_records
+        .fetch_add(result.idempotency_records, Ordering::Relaxed);
+    info!(
+        "Removed {} expired sessions, {} enrollment and password reset tokens, \
+        {} shared config links and {} idempotency records",
+        result.sessions, result.tokens, result.shared_configs, result.idempotency_records
+    );
+    Ok(result)
+}
+
+/// Delete a single batch of expired sessions, e.g. on login.
+pub async fn remove_expired_sessions(pool: &DbPool) -> Result<(), SqlxError> {
+    let deleted = Session::delete_expired(pool, CLEANUP_BATCH_SIZE).await?;
+    METRICS.sessions.fetch_add(deleted, Ordering::Relaxed);
+    debug!("Removed {deleted} expired sessions");
+    Ok(())
+}
+
+/// Delete session a validator found expired or idle, unless another request refreshed it.
+/// Failures are only logged, the session is rejected anyway.
+pub async fn remove_stale_session(pool: &DbPool, session: Session) {
+    let id = session.id.clone();
+    match session.delete_stale(pool).await {
+        Ok(true) => {
+            METRICS.sessions.fetch_add(1, Ordering::Relaxed);
+            debug!("Removed stale session {id}");
+        }
+        Ok(false) => debug!("Session {id} already removed or still in use"),
+        Err(err) => warn!("Failed to remove stale session {id}: {err}"),
+    }
+}
+
+/// Record desktop client MFA login sessions dropped after their token expired.
+pub(crate) fn record_client_mfa_sessions(count: usize) {
+    METRICS
+        .client_mfa_sessions
+        .fetch_add(count as u64, Ordering::Relaxed);
+}
+
+/// Background job periodically removing expired sessions, tokens and idempotency records.
+#[must_use]
+pub fn expired_cleanup_job(pool: DbPool) -> Job {
+    Job::new(
+        "expired_cleanup",
+        JobSchedule::Interval(CLEANUP_INTERVAL),
+        move || {
+            let pool = pool.clone();
+            async move {
+                remove_expired(&pool).await?;
+                Ok(())
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+    use crate::{
+        config::DefGuardConfig,
+        db::{models::session::SessionState, Device, User, WireguardNetwork},
+        SERVER_CONFIG,
+    };
+
+    fn ago(duration: ChronoDuration) -> NaiveDateTime {
+        (Utc::now() - duration).naive_utc()
+    }
+
+    async fn session(pool: &DbPool, user_id: i64, expires: NaiveDateTime) -> Session {
+        let session = Session {
+            expires,
+            ..Session::new(
+                user_id,
+                SessionState::PasswordVerified,
+                "127.0.0.1".into(),
+                None,
+            )
+        };
+        session.save(pool).await.unwrap();
+        session
+    }
+
+    async fn token(
+        pool: &DbPool,
+        user_id: i64,
+        expires_at: NaiveDateTime,
+        used_at: Option<NaiveDateTime>,
+    ) -> Token {
+        let token = Token {
+            expires_at,
+            used_at,
+            ..Token::new(user_id, None, None, 0, None)
+        };
+        let mut conn = pool.acquire().await.unwrap();
+        token.save(&mut conn).await.unwrap();
+        token
+    }
+
+    async fn session_exists(pool: &DbPool, session: &Session) -> bool {
+        Session::find_by_id(pool, &session.id)
+            .await
+            .unwrap()
+            .is_some()
+    }
+
+    async fn token_exists(pool: &DbPool, token: &Token) -> bool {
+        Token::find_by_id(pool, &token.id).await.is_ok()
+    }
+
+    #[sqlx::test]
+    async fn test_remove_expired(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let user_id = user.id.unwrap();
+
+        let expired_session = session(&pool, user_id, ago(ChronoDuration::seconds(1))).await;
+        let valid_session = session(&pool, user_id, ago(ChronoDuration::hours(-1))).await;
+
+        let day = ChronoDuration::days(1);
+        let hour = ChronoDuration::hours(1);
+        let expired_token = token(&pool, user_id, ago(day + hour), None).await;
+        let recently_expired_token = token(&pool, user_id, ago(hour), None).await;
+        let valid_token = token(&pool, user_id, ago(-hour), None).await;
+        let used_token = token(&pool, user_id, ago(-hour), Some(ago(day * 2))).await;
+        let recently_used_token = token(&pool, user_id, ago(day * 2), Some(ago(day))).await;
+
+        let mut device = Device::new(
+            "laptop".into(),
+            "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=".into(),
+            user_id,
+        );
+        device.save(&pool).await.unwrap();
+        let mut network = WireguardNetwork::default();
+        network.save(&pool).await.unwrap();
+        let link = |expires_at, used_at, revoked_at| {
+            let mut link = SharedConfig::new(
+                device.id.unwrap(),
+                network.id.unwrap(),
+                Some(user_id),
+                expires_at,
+            );
+            link.used_at = used_at;
+            link.revoked_at = revoked_at;
+            link
+        };
+        let mut links = [
+            link(ago(-hour), None, None),
+            link(ago(-hour), None, Some(ago(hour))),
+            link(ago(-hour), Some(ago(day + hour)), None),
+            link(ago(-hour), None, Some(ago(day + hour))),
+            link(ago(day + hour), None, None),
+        ];
+        for link in &mut links {
+            link.save(&pool).await.unwrap();
+        }
+
+        let result = remove_expired(&pool).await.unwrap();
+        assert_eq!(
+            result,
+            CleanupResult {
+                sessions: 1,
+                tokens: 2,
+                shared_configs: 3,
+                idempotency_records: 0,
+            }
+        );
+
+        assert!(!session_exists(&pool, &expired_session).await);
+        assert!(session_exists(&pool, &valid_session).await);
+        assert!(!token_exists(&pool, &expired_token).await);
+        assert!(!token_exists(&pool, &used_token).await);
+        assert!(token_exists(&pool, &recently_expired_token).await);
+        assert!(token_exists(&pool, &valid_token).await);
+        assert!(token_exists(&pool, &recently_used_token).await);
+        for (i, link) in links.iter().enumerate() {
+            let exists = SharedConfig::find_by_id(&pool, link.id.unwrap())
+                .await
+                .unwrap()
+                .is_some();
+            assert_eq!(exists, i < 2, "link {i}");
+        }
+
+        // nothing left to remove
+        assert_eq!(
+            remove_expired(&pool).await.unwrap(),
+            CleanupResult::default()
+        );
+        assert!(cleanup_metrics().runs >= 2);
+    }
+
+    #[sqlx::test]
+    async fn test_concurrent_validation(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let user_id = user.id.unwrap();
+
+        // session which expired while being validated is removed exactly once
+        let expired = session(&pool, user_id, ago(ChronoDuration::seconds(1))).await;
+        let (job, validator) =
+            tokio::join!(remove_expired(&pool), expired.clone().delete_stale(&pool));
+        assert_eq!(job.unwrap().sessions + u64::from(validator.unwrap()), 1);
+        assert!(!session_exists(&pool, &expired).await);
+        // validator coming late doesn't fail
+        assert!(!expired.delete_stale(&pool).await.unwrap());
+
+        // session found idle isn't removed if another request has refreshed it meanwhile
+        let idle = Session {
+            last_activity: ago(ChronoDuration::hours(1)),
+            ..Session::new(
+                user_id,
+                SessionState::PasswordVerified,
+                "127.0.0.1".into(),
+                None,
+            )
+        };
+        idle.save(&pool).await.unwrap();
+        let mut refreshed = idle.clone();
+        assert!(refreshed.touch(&pool).await.unwrap());
+        assert!(!idle.clone().delete_stale(&pool).await.unwrap());
+        assert!(session_exists(&pool, &idle).await);
+    }
+}
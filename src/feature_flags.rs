@@ -0,0 +1,293 @@
+//! Feature flags for gradual rollout of risky behavior changes.
+//!
+//! Flags are declared in code with a default, which can be overridden for the whole
+//! instance or a single location in the `feature_flag` table. Overrides are cached in
+//! memory, so checking a flag doesn't touch the database. Changes made through the API
+//! update the cache of the instance handling the request right away, and are announced
+//! to other instances sharing the database with a notification, making them reload it.
+
+use std::{
+    collections::HashMap,
+    sync::{OnceLock, RwLock},
+    time::Duration,
+};
+
+use sqlx::{postgres::PgListener, query, Error as SqlxError};
+use tokio::time::{sleep, timeout};
+use utoipa::ToSchema;
+
+use crate::db::{models::feature_flag::FeatureFlagOverride, DbPool};
+
+// Notification channel announcing changed overrides
+const FEATURE_FLAG_CHANNEL: &str = "feature_flag";
+// Overrides are also reloaded periodically, in case notifications were missed while reconnecting
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+static FEATURE_FLAGS: OnceLock<RwLock<FeatureFlags>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FeatureFlag {
+    /// Send changed peers to gateways instead of full location configuration.
+    GatewayPeerDiffs,
+    /// Resync gateways which missed updates in place instead of closing their stream.
+    GatewayLagResync,
+}
+
+impl FeatureFlag {
+    pub const ALL: [Self; 2] = [Self::GatewayPeerDiffs, Self::GatewayLagResync];
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::GatewayPeerDiffs => "gateway_peer_diffs",
+            Self::GatewayLagResync => "gateway_lag_resync",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|flag| flag.name() == name)
+    }
+
+    #[must_use]
+    pub fn default_enabled(self) -> bool {
+        match self {
+            Self::GatewayPeerDiffs | Self::GatewayLagResync => true,
+        }
+    }
+
+    #[must_use]
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::GatewayPeerDiffs => {
+                "Send changed peers to gateways instead of full location configuration"
+            }
+            Self::GatewayLagResync => {
+                "Resync gateways which missed updates instead of making them reconnect"
+            }
+        }
+    }
+}
+
+/// Flag overrides, by flag and location; `None` applies to the whole instance.
+#[derive(Debug, Default)]
+pub struct FeatureFlags {
+    overrides: HashMap<(FeatureFlag, Option<i64>), bool>,
+}
+
+impl FeatureFlags {
+    /// Build from stored overrides. Overrides of flags no longer declared are ignored.
+    #[must_use]
+    pub fn new(overrides: Vec<FeatureFlagOverride>) -> Self {
+        let overrides = overrides
+            .into_iter()
+            .filter_map(|stored| match FeatureFlag::from_name(&stored.name) {
+                Some(flag) => Some(((flag, stored.network_id), stored.enabled)),
+                None => {
+                    debug!("Ignoring override of unknown feature flag {}", stored.name);
+                    None
+                }
+            })
+            .collect();
+        Self { overrides }
+    }
+
+    /// Location override wins over instance-wide one, which wins over the default.
+    #[must_use]
+    pub fn is_enabled(&self, flag: FeatureFlag, network_id: Option<i64>) -> bool {
+        network_id
+            .and_then(|network_id| self.overrides.get(&(flag, Some(network_id))))
+            .or_else(|| self.overrides.get(&(flag, None)))
+            .copied()
+            .unwrap_or_else(|| flag.default_enabled())
+    }
+
+    /// Set or remove (if `enabled` is `None`) an override.
+    pub fn set(&mut self, flag: FeatureFlag, network_id: Option<i64>, enabled: Option<bool>) {
+        match enabled {
+            Some(enabled) => self.overrides.insert((flag, network_id), enabled),
+            None => self.overrides.remove(&(flag, network_id)),
+        };
+    }
+
+    /// Current state of a flag, resolved for the whole instance or given location.
+    #[must_use]
+    pub fn info(&self, flag: FeatureFlag, network_id: Option<i64>) -> FeatureFlagInfo {
+        let mut locations: Vec<_> = self
+            .overrides
+            .iter()
+            .filter_map(|(&(overridden, network_id), &enabled)| {
+                network_id
+                    .filter(|_| overridden == flag)
+                    .map(|network_id| LocationFeatureFlag {
+                        network_id,
+                        enabled,
+                    })
+            })
+            .collect();
+        locations.sort_by_key(|location| location.network_id);
+        FeatureFlagInfo {
+            name: flag,
+            description: flag.description(),
+            default: flag.default_enabled(),
+            enabled: self.is_enabled(flag, network_id),
+            instance_override: self.overrides.get(&(flag, None)).copied(),
+            locations,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LocationFeatureFlag {
+    pub network_id: i64,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FeatureFlagInfo {
+    pub name: FeatureFlag,
+    pub description: &'static str,
+    pub default: bool,
+    pub enabled: bool,
+    pub instance_override: Option<bool>,
+    pub locations: Vec<LocationFeatureFlag>,
+}
+
+fn feature_flags() -> &'static RwLock<FeatureFlags> {
+    FEATURE_FLAGS.get_or_init(RwLock::default)
+}
+
+/// Check if a flag is enabled, for given location if set.
+#[must_use]
+pub fn is_enabled(flag: FeatureFlag, network_id: Option<i64>) -> bool {
+    feature_flags().read().unwrap().is_enabled(flag, network_id)
+}
+
+/// State of all flags, resolved for the whole instance or given location.
+#[must_use]
+pub fn list(network_id: Option<i64>) -> Vec<FeatureFlagInfo> {
+    let flags = feature_flags().read().unwrap();
+    FeatureFlag::ALL
+        .into_iter()
+        .map(|flag| flags.info(flag, network_id))
+        .collect()
+}
+
+/// Replace cached overrides with the stored ones.
+pub async fn reload(pool: &DbPool) -> Result<(), SqlxError> {
+    let overrides = FeatureFlagOverride::all(pool).await?;
+    *feature_flags().write().unwrap() = FeatureFlags::new(overrides);
+    Ok(())
+}
+
+/// Store or remove (if `enabled` is `None`) an override, and notify other instances.
+pub async fn set_override(
+    pool: &DbPool,
+    flag: FeatureFlag,
+    network_id: Option<i64>,
+    enabled: Option<bool>,
+    updated_by: Option<i64>,
+) -> Result<(), SqlxError> {
+    let mut transaction = pool.begin().await?;
+    match enabled {
+        Some(enabled) => {
+            FeatureFlagOverride::set(
+                &mut *transaction,
+                flag.name(),
+                network_id,
+                enabled,
+                updated_by,
+            )
+            .await?;
+        }
+        None => {
+            FeatureFlagOverride::delete(&mut *transaction, flag.name(), network_id).await?;
+        }
+    }
+    query!("SELECT pg_notify($1, '')", FEATURE_FLAG_CHANNEL)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    feature_flags()
+        .write()
+        .unwrap()
+        .set(flag, network_id, enabled);
+    Ok(())
+}
+
+/// Reload overrides whenever another instance changes them.
+pub async fn run_feature_flag_listener(pool: DbPool) -> Result<(), SqlxError> {
+    let mut listener = PgListener::connect_with(&pool).await?;
+    listener.listen(FEATURE_FLAG_CHANNEL).await?;
+    info!("Receiving feature flag changes from other instances");
+    loop {
+        // wake up on notification or after reload interval, whichever comes first
+        if let Ok(Err(err)) = timeout(RELOAD_INTERVAL, listener.recv()).await {
+            error!("Failed to receive feature flag notification: {err}");
+            sleep(RELOAD_INTERVAL).await;
+        }
+        if let Err(err) = reload(&pool).await {
+            error!("Failed to reload feature flags: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn stored(flag: &str, network_id: Option<i64>, enabled: bool) -> FeatureFlagOverride {
+        FeatureFlagOverride {
+            name: flag.into(),
+            network_id,
+            enabled,
+            updated_by: None,
+            updated_at: Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn test_flag_resolution() {
+        let flag = FeatureFlag::GatewayPeerDiffs;
+        let mut flags = FeatureFlags::default();
+        assert!(flags.is_enabled(flag, None));
+        assert!(flags.is_enabled(flag, Some(1)));
+
+        // instance-wide override applies to all locations
+        flags.set(flag, None, Some(false));
+        assert!(!flags.is_enabled(flag, None));
+        assert!(!flags.is_enabled(flag, Some(1)));
+        assert!(flags.is_enabled(FeatureFlag::GatewayLagResync, Some(1)));
+
+        // location override wins
+        flags.set(flag, Some(1), Some(true));
+        assert!(flags.is_enabled(flag, Some(1)));
+        assert!(!flags.is_enabled(flag, Some(2)));
+        assert!(!flags.is_enabled(flag, None));
+
+        // removing instance-wide override restores default for other locations
+        flags.set(flag, None, None);
+        assert!(flags.is_enabled(flag, Some(2)));
+        let info = flags.info(flag, Some(2));
+        assert!(info.enabled);
+        assert_eq!(info.instance_override, None);
+        assert_eq!(info.locations.len(), 1);
+        assert_eq!(info.locations[0].network_id, 1);
+    }
+
+    #[test]
+    fn test_stored_overrides() {
+        let flags = FeatureFlags::new(vec![
+            stored("gateway_lag_resync", None, false),
+            stored("gateway_lag_resync", Some(3), true),
+            stored("removed_flag", None, false),
+        ]);
+        assert_eq!(flags.overrides.len(), 2);
+        assert!(!flags.is_enabled(FeatureFlag::GatewayLagResync, Some(2)));
+        assert!(flags.is_enabled(FeatureFlag::GatewayLagResync, Some(3)));
+        assert!(flags.is_enabled(FeatureFlag::GatewayPeerDiffs, None));
+    }
+}
@@ -0,0 +1,237 @@
+//! Journal of gateway events, used to bring gateways up to date after reconnecting.
+//!
+//! Update streams only receive events broadcast while they are connected. Every event
+//! is also stored in the `gateway_event_journal` table under a sequence number, so
+//! a gateway which was disconnected briefly can request events it missed since the
+//! sequence number it was given on its previous connection.
+//!
+//! Locations which had events published while none of their gateways was connected
+//! are additionally marked in `gateway_sync_pending`, so their gateways get full
+//! configuration on the next connection even if the journal can't be replayed.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{Duration as ChronoDuration, NaiveDateTime, Utc};
+use sqlx::{query, query_scalar, Error as SqlxError};
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver},
+    time::sleep,
+};
+
+use crate::{
+    db::{DbPool, GatewayEvent, WireguardNetwork},
+    gateway_event_relay::OutboxEvent,
+    grpc::GatewayMap,
+    jobs::{Job, JobSchedule},
+};
+
+// Delay before retrying to store an event
+const JOURNAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Gateways disconnected for longer get full configuration instead of a replay
+const JOURNAL_RETENTION: Duration = Duration::from_secs(3600);
+const JOURNAL_PURGE_INTERVAL: Duration = Duration::from_secs(600);
+
+async fn record_event(pool: &DbPool, event: &OutboxEvent) -> Result<i64, SqlxError> {
+    let network_id = event.network_id();
+    let event = serde_json::to_value(event).expect("Failed to serialize gateway event");
+    let mut transaction = pool.begin().await?;
+    // sequence numbers have to be committed in order, otherwise replays could skip events
+    query!("LOCK TABLE gateway_event_journal IN EXCLUSIVE MODE")
+        .execute(&mut *transaction)
+        .await?;
+    let seq = query_scalar!(
+        "INSERT INTO gateway_event_journal (network_id, event) VALUES ($1, $2) RETURNING seq",
+        network_id,
+        event
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(seq)
+}
+
+// locations removed in the meantime are skipped, there's nothing left to sync
+async fn mark_sync_pending(pool: &DbPool, network_id: i64) -> Result<(), SqlxError> {
+    query!(
+        "INSERT INTO gateway_sync_pending (network_id, marked_at) \
+        SELECT id, $2 FROM wireguard_network WHERE id = $1 \
+        ON CONFLICT (network_id) DO UPDATE SET marked_at = $2",
+        network_id,
+        Utc::now().naive_utc()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Store gateway events in the journal and mark locations without a connected gateway
+/// for full sync.
+///
+/// If some events were missed, full configuration of all locations is journaled instead.
+pub async fn run_gateway_event_journal(
+    pool: DbPool,
+    mut events_rx: Receiver<GatewayEvent>,
+    gateway_state: Arc<Mutex<GatewayMap>>,
+) -> Result<(), anyhow::Error> {
+    info!("Journaling gateway events");
+    loop {
+        let events = match events_rx.recv().await {
+            Ok(event) => vec![OutboxEvent::from(event)],
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Missed {skipped} gateway events, marking all locations for full sync");
+                match WireguardNetwork::all_active(&pool).await {
+                    Ok(networks) => networks
+                        .into_iter()
+                        .filter_map(|network| network.id)
+                        .map(|network_id| OutboxEvent::FullResync { network_id })
+                        .collect(),
+                    Err(err) => {
+                        error!("Failed to fetch locations, gateways may be out of sync: {err}");
+                        Vec::new()
+                    }
+                }
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        for event in events {
+            // retry until stored, replays can't skip over events
+            let seq = loop {
+                match record_event(&pool, &event).await {
+                    Ok(seq) => break seq,
+                    Err(err) => {
+                        error!("Failed to store gateway event in journal, retrying: {err}");
+                        sleep(JOURNAL_RETRY_DELAY).await;
+                    }
+                }
+            };
+            let network_id = event.network_id();
+            let connected = gateway_state.lock().unwrap().connected(network_id);
+            if !connected && !matches!(event, OutboxEvent::NetworkDeleted { .. }) {
+                debug!(
+                    "No gateway received event {seq} of location {network_id}, marking it for full sync"
+                );
+                if let Err(err) = mark_sync_pending(&pool, network_id).await {
+                    error!("Failed to mark location {network_id} for full sync: {err}");
+                }
+            }
+        }
+    }
+}
+
+/// Sequence number of the latest journaled event, zero if there are none.
+pub async fn journal_head(pool: &DbPool) -> Result<i64, SqlxError> {
+    query_scalar!("SELECT coalesce(max(seq), 0) \"seq!\" FROM gateway_event_journal")
+        .fetch_one(pool)
+        .await
+}
+
+/// Events of a location journaled after sequence number `since`, in order.
+///
+/// Returns `None` if some of them may be missing, i.e. were already purged or `since`
+/// doesn't come from this journal; the gateway needs full configuration then.
+pub async fn events_since(
+    pool: &DbPool,
+    network_id: i64,
+    since: i64,
+) -> Result<Option<Vec<GatewayEvent>>, SqlxError> {
+    let bounds = query!(
+        "SELECT coalesce(min(seq), 0) \"first!\", coalesce(max(seq), 0) \"last!\" \
+        FROM gateway_event_journal"
+    )
+    .fetch_one(pool)
+    .await?;
+    if since > bounds.last || since + 1 < bounds.first {
+        debug!(
+            "Gateway events of location {network_id} since {since} are not in the journal, \
+            which holds events {} to {}",
+            bounds.first, bounds.last
+        );
+        return Ok(None);
+    }
+
+    let rows = query!(
+        "SELECT seq, event FROM gateway_event_journal \
+        WHERE network_id = $1 AND seq > $2 ORDER BY seq",
+        network_id,
+        since
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut events = Vec::with_capacity(rows.len());
+    for row in rows {
+        match serde_json::from_value::<OutboxEvent>(row.event) {
+            Ok(event) => {
+                if let Some(event) = event.into_event(pool).await? {
+                    events.push(event);
+                }
+            }
+            Err(err) => {
+                error!("Invalid journaled gateway event {}: {err}", row.seq);
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(events))
+}
+
+/// Time the location was marked for full sync, if it was.
+pub async fn sync_pending_since(
+    pool: &DbPool,
+    network_id: i64,
+) -> Result<Option<NaiveDateTime>, SqlxError> {
+    query_scalar!(
+        "SELECT marked_at FROM gateway_sync_pending WHERE network_id = $1",
+        network_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Clear full sync mark of a location synced at `synced_at`. Marks set later are kept,
+/// as the gateway may have missed events published in the meantime.
+pub async fn clear_sync_pending(
+    pool: &DbPool,
+    network_id: i64,
+    synced_at: NaiveDateTime,
+) -> Result<(), SqlxError> {
+    query!(
+        "DELETE FROM gateway_sync_pending WHERE network_id = $1 AND marked_at <= $2",
+        network_id,
+        synced_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Background job removing journaled events too old to be replayed.
+#[must_use]
+pub fn journal_purge_job(pool: DbPool) -> Job {
+    Job::new(
+        "gateway_event_journal_purge",
+        JobSchedule::Interval(JOURNAL_PURGE_INTERVAL),
+        move || {
+            let pool = pool.clone();
+            async move {
+                let threshold = (Utc::now()
+                    - ChronoDuration::from_std(JOURNAL_RETENTION)
+                        .expect("Failed to parse duration"))
+                .naive_utc();
+                let result = query!(
+                    "DELETE FROM gateway_event_journal WHERE created_at < $1",
+                    threshold
+                )
+                .execute(&pool)
+                .await?;
+                debug!(
+                    "Removed {} old gateway events from journal",
+                    result.rows_affected()
+                );
+                Ok(())
+            }
+        },
+    )
+}
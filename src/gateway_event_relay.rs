@@ -0,0 +1,460 @@
+//! Relay of gateway events between core instances sharing one database.
+//!
+//! Gateway update streams only receive events broadcast within their own instance.
+//! With multiple instances, events sent by producers are stored in the
+//! `gateway_event_outbox` table instead, and every instance (including the sending one)
+//! reads them back and broadcasts them to its own gateways. Inserts are serialized with
+//! a table lock, so all instances read events in the order they were stored, which keeps
+//! per location ordering intact.
+
+use std::{net::IpAddr, time::Duration};
+
+use chrono::{Duration as ChronoDuration, Utc};
+use sqlx::{postgres::PgListener, query, query_scalar, Error as SqlxError};
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver, Sender},
+    time::{sleep, timeout},
+};
+
+use crate::{
+    db::{
+        models::{device::DeviceNetworkInfo, wireguard::PeerUpdate},
+        DbPool, Device, GatewayEvent, WireguardNetwork,
+    },
+    jobs::{Job, JobSchedule},
+};
+
+// Notification channel waking up consumers after an event is stored
+const OUTBOX_CHANNEL: &str = "gateway_event_outbox";
+// Consumers also poll periodically, in case notifications were missed while reconnecting
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+// Delay before retrying to store an event
+const PUBLISH_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Events are only needed until all instances read them
+const OUTBOX_RETENTION: Duration = Duration::from_secs(3600);
+const OUTBOX_PURGE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Stored form of [`GatewayEvent`].
+///
+/// Network events only reference the network: receiving instances load its current
+/// configuration, which is sent to gateways in full anyway.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum OutboxEvent {
+    NetworkCreated {
+        network_id: i64,
+    },
+    NetworkModified {
+        network_id: i64,
+    },
+    NetworkDeleted {
+        network_id: i64,
+        name: String,
+    },
+    PeerAdded {
+        peer: OutboxPeer,
+    },
+    PeerModified {
+        peer: OutboxPeer,
+        previous_pubkey: Option<String>,
+    },
+    PeerRemoved {
+        peer: OutboxPeer,
+    },
+    FullResync {
+        network_id: i64,
+    },
+}
+
+// Stored form of `PeerUpdate`; its network info doesn't serialize preshared keys
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct OutboxPeer {
+    device: Device,
+    network_id: i64,
+    device_wireguard_ip: IpAddr,
+    preshared_key: Option<String>,
+    is_authorized: bool,
+}
+
+impl From<PeerUpdate> for OutboxPeer {
+    fn from(peer: PeerUpdate) -> Self {
+        Self {
+            device: peer.device,
+            network_id: peer.network_info.network_id,
+            device_wireguard_ip: peer.network_info.device_wireguard_ip,
+            preshared_key: peer.network_info.preshared_key,
+            is_authorized: peer.network_info.is_authorized,
+        }
+    }
+}
+
+impl From<OutboxPeer> for PeerUpdate {
+    fn from(peer: OutboxPeer) -> Self {
+        Self {
+            device: peer.device,
+            network_info: DeviceNetworkInfo {
+                network_id: peer.network_id,
+                device_wireguard_ip: peer.device_wireguard_ip,
+                preshared_key: peer.preshared_key,
+                is_authorized: peer.is_authorized,
+            },
+        }
+    }
+}
+
+impl From<GatewayEvent> for OutboxEvent {
+    fn from(event: GatewayEvent) -> Self {
+        match event {
+            GatewayEvent::NetworkCreated(network_id, _) => Self::NetworkCreated { network_id },
+            GatewayEvent::NetworkModified(network_id, ..) => Self::NetworkModified { network_id },
+            GatewayEvent::NetworkDeleted(network_id, name) => {
+                Self::NetworkDeleted { network_id, name }
+            }
+            GatewayEvent::PeerAdded(peer) => Self::PeerAdded { peer: peer.into() },
+            GatewayEvent::PeerModified(peer, previous_pubkey) => Self::PeerModified {
+                peer: peer.into(),
+                previous_pubkey,
+            },
+            GatewayEvent::PeerRemoved(peer) => Self::PeerRemoved { peer: peer.into() },
+            GatewayEvent::FullResync(network_id, ..) => Self::FullResync { network_id },
+        }
+    }
+}
+
+impl OutboxEvent {
+    /// Location the event applies to.
+    pub(crate) fn network_id(&self) -> i64 {
+        match self {
+            Self::NetworkCreated { network_id }
+            | Self::NetworkModified { network_id }
+            | Self::NetworkDeleted { network_id, .. }
+            | Self::FullResync { network_id } => *network_id,
+            Self::PeerAdded { peer }
+            | Self::PeerModified { peer, .. }
+            | Self::PeerRemoved { peer } => peer.network_id,
+        }
+    }
+
+    /// Rebuild gateway event. Returns `None` for network events of networks
+    /// which no longer exist; their removal is relayed separately.
+    pub(crate) async fn into_event(self, pool: &DbPool) -> Result<Option<GatewayEvent>, SqlxError> {
+        let event = match self {
+            Self::NetworkCreated { network_id } => WireguardNetwork::find_by_id(pool, network_id)
+                .await?
+                .map(|network| GatewayEvent::NetworkCreated(network_id, network)),
+            Self::NetworkModified { network_id } => {
+                match WireguardNetwork::find_by_id(pool, network_id).await? {
+                    Some(network) => {
+                        let peers = network.get_peers(pool).await?;
+                        Some(GatewayEvent::NetworkModified(network_id, network, peers))
+                    }
+                    None => None,
+                }
+            }
+            Self::FullResync { network_id } => {
+                match WireguardNetwork::find_by_id(pool, network_id).await? {
+                    Some(network) => {
+                        let peers = network.get_peers(pool).await?;
+                        Some(GatewayEvent::FullResync(network_id, network, peers))
+                    }
+                    None => None,
+                }
+            }
+            Self::NetworkDeleted { network_id, name } => {
+                Some(GatewayEvent::NetworkDeleted(network_id, name))
+            }
+            Self::PeerAdded { peer } => Some(GatewayEvent::PeerAdded(peer.into())),
+            Self::PeerModified {
+                peer,
+                previous_pubkey,
+            } => Some(GatewayEvent::PeerModified(peer.into(), previous_pubkey)),
+            Self::PeerRemoved { peer } => Some(GatewayEvent::PeerRemoved(peer.into())),
+        };
+        Ok(event)
+    }
+}
+
+async fn store_event(pool: &DbPool, event: &OutboxEvent) -> Result<(), SqlxError> {
+    let event = serde_json::to_value(event).expect("Failed to serialize gateway event");
+    let mut transaction = pool.begin().await?;
+    // ids have to be committed in order, otherwise consumers could skip events
+    query!("LOCK TABLE gateway_event_outbox IN EXCLUSIVE MODE")
+        .execute(&mut *transaction)
+        .await?;
+    query!(
+        "INSERT INTO gateway_event_outbox (event) VALUES ($1)",
+        event
+    )
+    .execute(&mut *transaction)
+    .await?;
+    query!("SELECT pg_notify($1, '')", OUTBOX_CHANNEL)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await
+}
+
+/// Store gateway events sent within this instance in the outbox.
+///
+/// If some events were missed, full configuration of all locations is relayed instead.
+pub async fn run_outbox_publisher(
+    pool: DbPool,
+    mut events_rx: Receiver<GatewayEvent>,
+) -> Result<(), anyhow::Error> {
+    info!("Relaying gateway events to other instances");
+    loop {
+        let events = match events_rx.recv().await {
+            Ok(event) => vec![OutboxEvent::from(event)],
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Missed {skipped} gateway events, relaying full configuration instead");
+                match WireguardNetwork::all_active(&pool).await {
+                    Ok(networks) => networks
+                        .into_iter()
+                        .filter_map(|network| network.id)
+                        .map(|network_id| OutboxEvent::NetworkModified { network_id })
+                        .collect(),
+                    Err(err) => {
+                        error!("Failed to fetch locations, gateways may be out of sync: {err}");
+                        Vec::new()
+                    }
+                }
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        for event in events {
+            // retry until stored, later events can't be relayed before this one
+            while let Err(err) = store_event(&pool, &event).await {
+                error!("Failed to store gateway event, retrying: {err}");
+                sleep(PUBLISH_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Reads events stored in the outbox and broadcasts them to gateways of this instance.
+pub struct OutboxConsumer {
+    pool: DbPool,
+    events_tx: Sender<GatewayEvent>,
+    listener: PgListener,
+    last_id: i64,
+}
+
+impl OutboxConsumer {
+    /// Start listening for new events. Events stored earlier are skipped,
+    /// as gateways fetch full configuration when they connect.
+    pub async fn new(pool: DbPool, events_tx: Sender<GatewayEvent>) -> Result<Self, SqlxError> {
+        let mut listener = PgListener::connect_with(&pool).await?;
+        listener.listen(OUTBOX_CHANNEL).await?;
+        let last_id =
+            query_scalar!("SELECT coalesce(max(id), 0) \"id!\" FROM gateway_event_outbox")
+                .fetch_one(&pool)
+                .await?;
+        Ok(Self {
+            pool,
+            events_tx,
+            listener,
+            last_id,
+        })
+    }
+
+    pub async fn run(mut self) {
+        info!("Receiving gateway events from other instances");
+        loop {
+            if let Err(err) = self.broadcast_new_events().await {
+                error!("Failed to read stored gateway events: {err}");
+            }
+            // wake up on notification or after poll interval, whichever comes first
+            if let Ok(Err(err)) = timeout(POLL_INTERVAL, self.listener.recv()).await {
+                error!("Failed to receive gateway event notification: {err}");
+                sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+
+    async fn broadcast_new_events(&mut self) -> Result<(), SqlxError> {
+        let rows = query!(
+            "SELECT id, event FROM gateway_event_outbox WHERE id > $1 ORDER BY id",
+            self.last_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        for row in rows {
+            match serde_json::from_value::<OutboxEvent>(row.event) {
+                Ok(event) => {
+                    if let Some(event) = event.into_event(&self.pool).await? {
+                        debug!("Broadcasting stored gateway event {}", row.id);
+                        // fails only if no gateways are connected
+                        let _ = self.events_tx.send(event);
+                    }
+                }
+                Err(err) => error!("Skipping invalid stored gateway event {}: {err}", row.id),
+            }
+            self.last_id = row.id;
+        }
+        Ok(())
+    }
+}
+
+/// Background job removing events which all instances have already read.
+#[must_use]
+pub fn outbox_purge_job(pool: DbPool) -> Job {
+    Job::new(
+        "gateway_event_outbox_purge",
+        JobSchedule::Interval(OUTBOX_PURGE_INTERVAL),
+        move || {
+            let pool = pool.clone();
+            async move {
+                let threshold = (Utc::now()
+                    - ChronoDuration::from_std(OUTBOX_RETENTION)
+                        .expect("Failed to parse duration"))
+                .naive_utc();
+                let result = query!(
+                    "DELETE FROM gateway_event_outbox WHERE created_at < $1",
+                    threshold
+                )
+                .execute(&pool)
+                .await?;
+                debug!(
+                    "Removed {} old gateway events from outbox",
+                    result.rows_affected()
+                );
+                Ok(())
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{sync::broadcast, task::spawn};
+
+    use super::*;
+    use crate::db::User;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // Event channels of a single core instance
+    struct Instance {
+        wireguard_tx: Sender<GatewayEvent>,
+        gateway_rx: Receiver<GatewayEvent>,
+    }
+
+    async fn start_instance(pool: &DbPool) -> Instance {
+        let (wireguard_tx, wireguard_rx) = broadcast::channel(16);
+        let (gateway_tx, gateway_rx) = broadcast::channel(16);
+        let consumer = OutboxConsumer::new(pool.clone(), gateway_tx).await.unwrap();
+        spawn(consumer.run());
+        spawn(run_outbox_publisher(pool.clone(), wireguard_rx));
+        Instance {
+            wireguard_tx,
+            gateway_rx,
+        }
+    }
+
+    async fn receive(rx: &mut Receiver<GatewayEvent>) -> GatewayEvent {
+        timeout(TIMEOUT, rx.recv()).await.unwrap().unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_events_relayed_between_instances(pool: DbPool) {
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.save(&pool).await.unwrap();
+        let network_id = network.id.unwrap();
+        let mut user = User::new(
+            "testuser",
+            Some("hunter2"),
+            "Tester",
+            "Test",
+            "test@test.com",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let device = Device::new_with_ip(
+            &pool,
+            user.id.unwrap(),
+            "dev".into(),
+            "key".into(),
+            &network,
+        )
+        .await
+        .unwrap()
+        .0;
+        let peer = PeerUpdate {
+            device,
+            network_info: DeviceNetworkInfo {
+                network_id,
+                device_wireguard_ip: "10.1.1.2".parse().unwrap(),
+                preshared_key: Some("psk".into()),
+                is_authorized: false,
+            },
+        };
+
+        let mut first = start_instance(&pool).await;
+        let mut second = start_instance(&pool).await;
+
+        // events sent by one instance reach gateways of both, in order
+        first
+            .wireguard_tx
+            .send(GatewayEvent::PeerAdded(peer.clone()))
+            .unwrap();
+        first
+            .wireguard_tx
+            .send(GatewayEvent::NetworkModified(
+                network_id,
+                network,
+                Vec::new(),
+            ))
+            .unwrap();
+        first
+            .wireguard_tx
+            .send(GatewayEvent::PeerRemoved(peer))
+            .unwrap();
+        for instance in [&mut first, &mut second] {
+            match receive(&mut instance.gateway_rx).await {
+                GatewayEvent::PeerAdded(peer) => {
+                    assert_eq!(peer.network_id(), network_id);
+                    assert_eq!(peer.device.wireguard_pubkey, "key");
+                    assert_eq!(peer.network_info.preshared_key.as_deref(), Some("psk"));
+                }
+                event => panic!("unexpected event {event:?}"),
+            }
+            match receive(&mut instance.gateway_rx).await {
+                // peers are loaded from the database
+                GatewayEvent::NetworkModified(id, _, peers) => {
+                    assert_eq!(id, network_id);
+                    assert_eq!(peers.len(), 1);
+                }
+                event => panic!("unexpected event {event:?}"),
+            }
+            assert!(matches!(
+                receive(&mut instance.gateway_rx).await,
+                GatewayEvent::PeerRemoved(_)
+            ));
+        }
+
+        second
+            .wireguard_tx
+            .send(GatewayEvent::NetworkDeleted(network_id, "network".into()))
+            .unwrap();
+        for instance in [&mut first, &mut second] {
+            assert!(matches!(
+                receive(&mut instance.gateway_rx).await,
+                GatewayEvent::NetworkDeleted(id, _) if id == network_id
+            ));
+        }
+    }
+
+    #[test]
+    fn test_outbox_event_roundtrip() {
+        let event = OutboxEvent::NetworkDeleted {
+            network_id: 1,
+            name: "network".into(),
+        };
+        let value = serde_json::to_value(event).unwrap();
+        assert_eq!(value["type"], "network_deleted");
+        assert!(matches!(
+            serde_json::from_value(value).unwrap(),
+            OutboxEvent::NetworkDeleted { network_id: 1, name } if name == "network"
+        ));
+    }
+}
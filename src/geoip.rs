@@ -0,0 +1,177 @@
+//! Country lookup of IP addresses.
+//!
+//! The database is an optional CSV file configured with `DEFGUARD_GEOIP_DATABASE`, with one
+//! `first_ip,last_ip,country_code` range per line, e.g. the free "IP to Country Lite" database
+//! from db-ip.com. Without it no addresses resolve.
+
+use std::{
+    fs::read_to_string,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::OnceLock,
+};
+
+use thiserror::Error;
+
+static GEOIP_DATABASE: OnceLock<GeoIpDatabase> = OnceLock::new();
+
+#[derive(Debug, Error)]
+pub enum GeoIpError {
+    #[error("Failed to read GeoIP database: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid GeoIP database entry in line {0}")]
+    InvalidEntry(usize),
+}
+
+#[derive(Debug)]
+struct CountryRange {
+    first: IpAddr,
+    last: IpAddr,
+    country: String,
+}
+
+#[derive(Debug, Default)]
+pub struct GeoIpDatabase {
+    // sorted by first address, IPv4 ranges before IPv6
+    ranges: Vec<CountryRange>,
+}
+
+impl GeoIpDatabase {
+    pub fn parse(content: &str) -> Result<Self, GeoIpError> {
+        let mut ranges = Vec::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split(',').map(|field| field.trim().trim_matches('"'));
+            let (Some(first), Some(last), Some(country)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(GeoIpError::InvalidEntry(index + 1));
+            };
+            let (Ok(first), Ok(last)) = (first.parse::<IpAddr>(), last.parse::<IpAddr>()) else {
+                return Err(GeoIpError::InvalidEntry(index + 1));
+            };
+            if first.is_ipv4() != last.is_ipv4() || first > last || country.is_empty() {
+                return Err(GeoIpError::InvalidEntry(index + 1));
+            }
+            // "ZZ" marks unassigned and reserved ranges
+            if country == "ZZ" {
+                continue;
+            }
+            ranges.push(CountryRange {
+                first,
+                last,
+                country: country.to_uppercase(),
+            });
+        }
+        ranges.sort_unstable_by_key(|range| range.first);
+        Ok(Self { ranges })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, GeoIpError> {
+        Self::parse(&read_to_string(path)?)
+    }
+
+    /// Two-letter code of the country the address is located in.
+    #[must_use]
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        let index = self.ranges.partition_point(|range| range.first <= ip);
+        let range = self.ranges.get(index.checked_sub(1)?)?;
+        (ip <= range.last).then_some(range.country.as_str())
+    }
+
+    /// Country of a WireGuard peer endpoint in `address:port` format.
+    #[must_use]
+    pub fn endpoint_country(&self, endpoint: &str) -> Option<&str> {
+        self.country(endpoint_ip(endpoint)?)
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+/// IP address of a WireGuard peer endpoint in `address:port` format.
+#[must_use]
+pub fn endpoint_ip(endpoint: &str) -> Option<IpAddr> {
+    endpoint
+        .parse::<SocketAddr>()
+        .map(|address| address.ip())
+        .or_else(|_| endpoint.parse::<IpAddr>())
+        .ok()
+}
+
+/// Load the configured database, if any. Called once at startup.
+pub fn init_geoip(path: Option<&Path>) -> Result<(), GeoIpError> {
+    if let Some(path) = path {
+        let database = GeoIpDatabase::load(path)?;
+        info!(
+            "Loaded {} GeoIP ranges from {}",
+            database.len(),
+            path.display()
+        );
+        let _ = GEOIP_DATABASE.set(database);
+    }
+    Ok(())
+}
+
+/// Database loaded at startup, `None` if not configured.
+#[must_use]
+pub fn geoip_database() -> Option<&'static GeoIpDatabase> {
+    GEOIP_DATABASE.get()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_country_lookup() {
+        let database = GeoIpDatabase::parse(
+            "1.0.0.0,1.0.0.255,AU\n\
+            # comment\n\
+            \"2.16.0.0\",\"2.16.255.255\",\"pl\"\n\
+            2.17.0.0,2.17.255.255,ZZ\n\
+            2001:db8::,2001:db8::ffff,DE\n",
+        )
+        .unwrap();
+        assert_eq!(database.len(), 3);
+        assert_eq!(database.country("1.0.0.0".parse().unwrap()), Some("AU"));
+        assert_eq!(database.country("1.0.0.255".parse().unwrap()), Some("AU"));
+        assert_eq!(database.country("2.16.1.1".parse().unwrap()), Some("PL"));
+        assert_eq!(database.country("1.0.1.0".parse().unwrap()), None);
+        assert_eq!(database.country("0.255.255.255".parse().unwrap()), None);
+        assert_eq!(database.country("2.17.0.1".parse().unwrap()), None);
+        assert_eq!(database.country("2001:db8::1".parse().unwrap()), Some("DE"));
+        assert_eq!(database.country("2001:db9::1".parse().unwrap()), None);
+
+        assert_eq!(database.endpoint_country("2.16.0.1:51820"), Some("PL"));
+        assert_eq!(database.endpoint_country("[2001:db8::2]:51820"), Some("DE"));
+        assert_eq!(database.endpoint_country("10.0.0.1:51820"), None);
+        assert_eq!(database.endpoint_country("invalid"), None);
+    }
+
+    #[test]
+    fn test_invalid_database() {
+        assert!(matches!(
+            GeoIpDatabase::parse("1.0.0.0,1.0.0.255,AU\n1.0.1.0,AU\n"),
+            Err(GeoIpError::InvalidEntry(2))
+        ));
+        assert!(matches!(
+            GeoIpDatabase::parse("1.0.0.255,1.0.0.0,AU\n"),
+            Err(GeoIpError::InvalidEntry(1))
+        ));
+        assert!(matches!(
+            GeoIpDatabase::parse("1.0.0.0,2001:db8::,AU\n"),
+            Err(GeoIpError::InvalidEntry(1))
+        ));
+    }
+}
@@ -5,20 +5,33 @@ use super::proto::{
 use crate::{
     auth::{Claims, ClaimsType},
     db::{
-        models::device::{DeviceInfo, DeviceNetworkInfo, WireguardNetworkDevice},
-        DbPool, Device, GatewayEvent, User, UserInfo, WireguardNetwork,
+        models::{
+            device::{DeviceNetworkInfo, WireguardNetworkDevice},
+            wireguard::PeerUpdate,
+        },
+        DbPool, Device, GatewayEvent, MFAMethod, Settings, User, WireguardNetwork,
     },
+    expired_cleanup::record_client_mfa_sessions,
     handlers::mail::send_email_mfa_code_email,
+    live_events::{self, LiveEvent},
     mail::Mail,
 };
 use chrono::Utc;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 use tokio::sync::{broadcast::Sender, mpsc::UnboundedSender};
 use tonic::Status;
 
 const CLIENT_SESSION_TIMEOUT: u64 = 60 * 5; // 10 minutes
 
+// reason of refused connection attempts, shown in logs and live events
+const PLATFORM_NOT_ALLOWED: &str = "platform not allowed";
+
 struct ClientLoginSession {
+    // sessions outlive their token, they're dropped when other logins start
+    started: Instant,
     method: MfaMethod,
     location: WireguardNetwork,
     device: Device,
@@ -69,11 +82,25 @@ impl ClientMfaServer {
         Ok(claims.client_id)
     }
 
+    /// Drop login sessions whose token has expired, so they can't be finished anymore.
+    fn remove_expired_sessions(&mut self) {
+        let timeout = Duration::from_secs(CLIENT_SESSION_TIMEOUT);
+        let count = self.sessions.len();
+        self.sessions
+            .retain(|_, session| session.started.elapsed() < timeout);
+        let removed = count - self.sessions.len();
+        if removed > 0 {
+            debug!("Removed {removed} expired desktop client login sessions");
+            record_client_mfa_sessions(removed);
+        }
+    }
+
     pub async fn start_client_mfa_login(
         &mut self,
         request: ClientMfaStartRequest,
     ) -> Result<ClientMfaStartResponse, Status> {
         debug!("Starting desktop client login: {request:?}");
+        self.remove_expired_sessions();
         // fetch location
         let Ok(Some(location)) =
             WireguardNetwork::find_by_id(&self.pool, request.location_id).await
@@ -87,16 +114,16 @@ impl ClientMfaServer {
             error!("Failed to find device with pubkey {}", request.pubkey);
             return Err(Status::invalid_argument("device not found"));
         };
+        if device.blocked {
+            warn!("Rejected MFA login of blocked device {device}");
+            return Err(Status::permission_denied("device is blocked"));
+        }
 
         // fetch user
         let Ok(Some(user)) = User::find_by_id(&self.pool, device.user_id).await else {
             error!("Failed to find user with ID {}", device.user_id);
             return Err(Status::invalid_argument("user not found"));
         };
-        let user_info = UserInfo::from_user(&self.pool, &user).await.map_err(|_| {
-            error!("Failed to fetch user info for {}", user.username);
-            Status::internal("unexpected error")
-        })?;
 
         // validate user is allowed to connect to a given location
         let mut transaction = self.pool.begin().await.map_err(|_| {
@@ -111,25 +138,66 @@ impl ClientMfaServer {
                 Status::internal("unexpected error")
             })?;
         if let Some(groups) = allowed_groups {
-            // check if user belongs to one of allowed groups
+            // check if user belongs to one of allowed groups, directly or through a subgroup
+            let user_groups = user
+                .effective_member_of_names(&mut *transaction)
+                .await
+                .map_err(|err| {
+                    error!("Failed to fetch groups of user {}: {err}", user.username);
+                    Status::internal("unexpected error")
+                })?;
             if !groups
                 .iter()
-                .any(|allowed_group| user_info.groups.contains(allowed_group))
+                .any(|allowed_group| user_groups.contains(allowed_group))
             {
                 error!(
                     "User {} not allowed to connect to location {location} because he doesn't belong to any of the allowed groups.
                     User groups: {:?}, allowed groups: {:?}",
-                    user.username, user_info.groups, groups
+                    user.username, user_groups, groups
                 );
                 return Err(Status::unauthenticated("unauthorized"));
             }
         }
 
+        // validate device platform is allowed in a given location
+        if !location.platform_allowed(device.os.as_deref()) {
+            warn!(
+                connection_rejected = true,
+                reason = PLATFORM_NOT_ALLOWED,
+                "Rejected connection of device {device} running {} to location {location}: \
+                {PLATFORM_NOT_ALLOWED}, allowed platforms: {}",
+                device.os.as_deref().unwrap_or("unknown platform"),
+                location.allowed_platforms.join(", ")
+            );
+            if let (Some(device_id), Some(network_id)) = (device.id, location.id) {
+                live_events::publish(LiveEvent::ClientRejected {
+                    device_id,
+                    network_id,
+                    reason: PLATFORM_NOT_ALLOWED.into(),
+                });
+            }
+            return Err(Status::permission_denied(PLATFORM_NOT_ALLOWED));
+        }
+
         // check if selected method is enabled
         let method = MfaMethod::try_from(request.method).map_err(|err| {
             error!("Invalid MFA method selected ({}): {err}", request.method);
             Status::invalid_argument("invalid MFA method selected")
         })?;
+        let settings = Settings::get_settings(&self.pool).await.map_err(|err| {
+            error!("Failed to fetch settings: {err}");
+            Status::internal("unexpected error")
+        })?;
+        let mfa_method = match method {
+            MfaMethod::Totp => MFAMethod::OneTimePassword,
+            MfaMethod::Email => MFAMethod::Email,
+        };
+        if !settings.mfa_method_usable(&mfa_method) {
+            error!("MFA method {mfa_method:?} disabled in settings");
+            return Err(Status::invalid_argument(
+                "selected MFA method not available",
+            ));
+        }
         match method {
             MfaMethod::Totp => {
                 if !user.totp_enabled {
@@ -169,6 +237,7 @@ impl ClientMfaServer {
         self.sessions.insert(
             request.pubkey,
             ClientLoginSession {
+                started: Instant::now(),
                 method,
                 location,
                 device,
@@ -197,6 +266,7 @@ impl ClientMfaServer {
             device,
             location,
             user,
+            ..
         } = session;
 
         // validate code
@@ -252,16 +322,15 @@ impl ClientMfaServer {
 
         // send gateway event
         debug!("Sending `peer_create` message to gateway");
-        let device_info = DeviceInfo {
+        let event = GatewayEvent::PeerAdded(PeerUpdate {
             device: device.clone(),
-            network_info: vec![DeviceNetworkInfo {
+            network_info: DeviceNetworkInfo {
                 network_id: location.id.expect("Missing location ID"),
                 device_wireguard_ip: network_device.wireguard_ip,
                 preshared_key: network_device.preshared_key,
                 is_authorized: network_device.is_authorized,
-            }],
-        };
-        let event = GatewayEvent::DeviceCreated(device_info);
+            },
+        });
         self.wireguard_tx.send(event).map_err(|err| {
             error!("Error sending WireGuard event: {err}");
             Status::internal("unexpected error")
@@ -286,3 +355,89 @@ impl ClientMfaServer {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::{broadcast, mpsc::unbounded_channel};
+    use tonic::Code;
+
+    use super::*;
+    use crate::{config::DefGuardConfig, SERVER_CONFIG};
+
+    #[sqlx::test]
+    async fn test_platform_not_allowed(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.totp_enabled = true;
+        user.save(&pool).await.unwrap();
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.mfa_enabled = true;
+        network.allowed_platforms = vec!["linux".into(), "windows".into()];
+        network.save(&pool).await.unwrap();
+        let mut device = Device::new(
+            "phone".into(),
+            "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=".into(),
+            user.id.unwrap(),
+        );
+        device.os = Some("android".into());
+        device.save(&pool).await.unwrap();
+
+        let (mail_tx, _mail_rx) = unbounded_channel();
+        let (wireguard_tx, _wireguard_rx) = broadcast::channel(16);
+        let mut server = ClientMfaServer::new(pool.clone(), mail_tx, wireguard_tx);
+        let location_id = network.id.unwrap();
+        let pubkey = device.wireguard_pubkey.clone();
+        let request = move || ClientMfaStartRequest {
+            location_id,
+            pubkey: pubkey.clone(),
+            method: MfaMethod::Totp.into(),
+        };
+
+        // rejected attempt is published with the reason
+        let mut events = live_events::subscribe();
+        let status = server.start_client_mfa_login(request()).await.unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert_eq!(status.message(), PLATFORM_NOT_ALLOWED);
+        let rejection = loop {
+            let event = events.recv().await.unwrap();
+            if let LiveEvent::ClientRejected { device_id, .. } = &event {
+                if Some(*device_id) == device.id {
+                    break event;
+                }
+            }
+        };
+        assert_eq!(
+            rejection,
+            LiveEvent::ClientRejected {
+                device_id: device.id.unwrap(),
+                network_id: network.id.unwrap(),
+                reason: PLATFORM_NOT_ALLOWED.into(),
+            }
+        );
+        assert!(server.sessions.is_empty());
+
+        // allowed platforms can connect
+        device.os = Some("linux".into());
+        device.save(&pool).await.unwrap();
+        server.start_client_mfa_login(request()).await.unwrap();
+        assert_eq!(server.sessions.len(), 1);
+
+        // devices which haven't reported their platform follow location default
+        device.os = None;
+        device.save(&pool).await.unwrap();
+        server.start_client_mfa_login(request()).await.unwrap();
+        network.deny_unknown_platform = true;
+        network.save(&pool).await.unwrap();
+        let status = server.start_client_mfa_login(request()).await.unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+}
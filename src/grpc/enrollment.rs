@@ -1,14 +1,23 @@
-use std::sync::Arc;
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
 
 use crate::{
+    auth::failed_token::{check_token_attempt, log_failed_token_attempt, FailedTokenMap},
     db::{
         models::{
-            device::{DeviceConfig, DeviceInfo, WireguardNetworkDevice},
-            enrollment::{Token, TokenError, ENROLLMENT_TOKEN_TYPE},
+            device::{
+                DeviceConfig, DeviceError, DeviceInfo, DevicePlatform, MachineHints,
+                WireguardNetworkDevice, WireguardPubkey,
+            },
+            enrollment::{Token, TokenError, ENROLLMENT_TOKEN_TYPE, PASSWORD_RESET_TOKEN_TYPE},
+            polling_token::PollingToken,
             wireguard::WireguardNetwork,
         },
         DbPool, Device, GatewayEvent, Settings, User,
     },
+    dns::{self, DnsError},
     handlers::{mail::send_new_device_added_email, user::check_password_strength},
     headers::get_device_info,
     ldap::utils::ldap_add_user,
@@ -18,15 +27,18 @@ use crate::{
 };
 use ipnetwork::IpNetwork;
 use reqwest::Url;
-use sqlx::Transaction;
+use sqlx::{PgConnection, Transaction};
 use tokio::sync::{broadcast::Sender, mpsc::UnboundedSender};
 use tonic::Status;
 use uaparser::UserAgentParser;
 
-use super::proto::{
-    ActivateUserRequest, AdminInfo, Device as ProtoDevice, DeviceConfig as ProtoDeviceConfig,
-    DeviceConfigResponse, EnrollmentStartRequest, EnrollmentStartResponse, ExistingDevice,
-    InitialUserInfo, NewDevice,
+use super::{
+    password_policy_status,
+    proto::{
+        ActivateUserRequest, AdminInfo, Device as ProtoDevice, DeviceConfig as ProtoDeviceConfig,
+        DeviceConfigResponse, EnrollmentStartRequest, EnrollmentStartResponse, ExistingDevice,
+        InitialUserInfo, NewDevice,
+    },
 };
 
 pub(super) struct EnrollmentServer {
@@ -34,7 +46,7 @@ pub(super) struct EnrollmentServer {
     wireguard_tx: Sender<GatewayEvent>,
     mail_tx: UnboundedSender<Mail>,
     user_agent_parser: Arc<UserAgentParser>,
-    ldap_feature_active: bool,
+    failed_tokens: Arc<Mutex<FailedTokenMap>>,
 }
 
 struct InstanceInfo {
@@ -77,15 +89,14 @@ impl EnrollmentServer {
         wireguard_tx: Sender<GatewayEvent>,
         mail_tx: UnboundedSender<Mail>,
         user_agent_parser: Arc<UserAgentParser>,
+        failed_tokens: Arc<Mutex<FailedTokenMap>>,
     ) -> Self {
-        // FIXME: check if LDAP feature is enabled
-        let ldap_feature_active = true;
         Self {
             pool,
             wireguard_tx,
             mail_tx,
             user_agent_parser,
-            ldap_feature_active,
+            failed_tokens,
         }
     }
 
@@ -97,30 +108,27 @@ impl EnrollmentServer {
         };
         debug!("Validating enrollment session token: {token}");
 
-        let enrollment = Token::find_by_id(&self.pool, token).await?;
-        if enrollment.is_session_valid(server_config().enrollment_session_timeout.as_secs()) {
-            info!("Enrollment session validated");
-            Ok(enrollment)
-        } else {
-            error!("Enrollment session expired");
-            Err(Status::unauthenticated("Enrollment session expired"))
-        }
-    }
-
-    /// Sends given `GatewayEvent` to be handled by gateway GRPC server
-    pub fn send_wireguard_event(&self, event: GatewayEvent) {
-        if let Err(err) = self.wireguard_tx.send(event) {
-            error!("Error sending WireGuard event {err}");
-        }
+        Ok(Token::find_session(&self.pool, token).await?)
     }
 
     pub async fn start_enrollment(
         &self,
         request: EnrollmentStartRequest,
+        req_device_info: Option<super::proto::DeviceInfo>,
     ) -> Result<EnrollmentStartResponse, Status> {
         debug!("Starting enrollment session, request: {request:?}");
+        let ip_address = req_device_info
+            .and_then(|info| info.ip_address)
+            .and_then(|ip| ip.parse().ok());
         // fetch enrollment token
-        let mut enrollment = Token::find_by_id(&self.pool, &request.token).await?;
+        let mut enrollment = Token::find_guarded(
+            &self.pool,
+            &self.mail_tx,
+            &self.failed_tokens,
+            &request.token,
+            ip_address,
+        )
+        .await?;
 
         if let Some(token_type) = &enrollment.token_type {
             if token_type != ENROLLMENT_TOKEN_TYPE {
@@ -212,14 +220,18 @@ impl EnrollmentServer {
             device_info = None;
         }
 
+        // fetch related users
+        let user = enrollment.fetch_user(&self.pool).await?;
+
         // check if password is strong enough
-        if let Err(err) = check_password_strength(&request.password) {
+        if let Err(err) =
+            check_password_strength(&self.pool, &request.password, &user.username, &user.email)
+                .await
+        {
             error!("Password not strong enough: {err}");
-            return Err(Status::invalid_argument("password not strong enough"));
+            return Err(password_policy_status(err));
         }
 
-        // fetch related users
-        let mut user = enrollment.fetch_user(&self.pool).await?;
         if user.has_password() {
             error!("User {} already activated", user.username);
             return Err(Status::invalid_argument("user already activated"));
@@ -233,63 +245,17 @@ impl EnrollmentServer {
             return Err(Status::invalid_argument("user is disabled"));
         }
 
-        let mut transaction = self.pool.begin().await.map_err(|_| {
-            error!("Failed to begin transaction");
-            Status::internal("unexpected error")
-        })?;
-
-        // update user
-        user.phone = request.phone_number;
-        user.set_password(&request.password);
-        user.save(&mut *transaction).await.map_err(|err| {
-            error!("Failed to update user {}: {err}", user.username);
-            Status::internal("unexpected error")
-        })?;
-
-        // sync with LDAP
-        if self.ldap_feature_active {
-            let _result = ldap_add_user(&self.pool, &user, &request.password).await;
-        };
-
-        let settings = Settings::get_settings(&mut *transaction)
-            .await
-            .map_err(|_| {
-                error!("Failed to get settings");
-                Status::internal("unexpected error")
-            })?;
-
-        // send welcome email
         enrollment
-            .send_welcome_email(
-                &mut transaction,
+            .activate_user(
+                &self.pool,
                 &self.mail_tx,
-                &user,
-                &settings,
+                &request.password,
+                request.phone_number,
                 &ip_address,
                 device_info.as_deref(),
             )
             .await?;
 
-        // send success notification to admin
-        let admin = enrollment.fetch_admin(&mut *transaction).await?;
-
-        if let Some(admin) = admin {
-            Token::send_admin_notification(
-                &self.mail_tx,
-                &admin,
-                &user,
-                &ip_address,
-                device_info.as_deref(),
-            )?;
-        }
-
-        transaction.commit().await.map_err(|_| {
-            error!("Failed to commit transaction");
-            Status::internal("unexpected error")
-        })?;
-
-        info!("User {} activated", user.username);
-
         Ok(())
     }
 
@@ -323,56 +289,134 @@ impl EnrollmentServer {
             device_info = None;
         }
 
-        Device::validate_pubkey(&request.pubkey).map_err(|_| {
-            error!("Invalid pubkey {}", request.pubkey);
-            Status::invalid_argument("invalid pubkey")
+        let pubkey = WireguardPubkey::parse(&request.pubkey).map_err(|err| {
+            error!("Invalid pubkey {}: {err}", request.pubkey);
+            Status::invalid_argument(err.to_string())
         })?;
 
         // Make sure there is no device with the same pubkey, such state may lead to unexpected issues
-        if let Some(device) = Device::find_by_pubkey(&self.pool, &request.pubkey)
+        if let Some(device) = Device::find_duplicate_pubkey(&self.pool, &pubkey, None)
             .await
             .map_err(|_| {
-                error!("Failed to get device by its pubkey: {}", request.pubkey);
+                error!("Failed to get device by its pubkey: {pubkey}");
                 Status::internal("unexpected error")
             })?
         {
             warn!(
-                "User {} failed to add device {}, identical pubkey ({}) already exists for device {}",
+                "User {} failed to add device {}, identical pubkey ({pubkey}) already exists for device {}",
                 user.username,
                 request.name,
-                request.pubkey,
                 device.name
             );
-            return Err(Status::invalid_argument("invalid key"));
+            return Err(Status::already_exists(format!(
+                "pubkey already used by device {}",
+                device.name
+            )));
         };
 
-        let mut device = Device::new(request.name, request.pubkey, enrollment.user_id);
+        // Machine enrolled before, e.g. prior to OS reinstall, may take over its previous
+        // device instead of adding another one. Client has to confirm it with the user.
+        let hints = MachineHints::new(enrollment.user_id, request.machine_id, request.hostname);
+        let same_machine = Device::find_same_machine(&self.pool, enrollment.user_id, &hints)
+            .await
+            .map_err(|err| {
+                error!(
+                    "Failed to find devices of user {} by machine: {err}",
+                    user.username
+                );
+                Status::internal("unexpected error")
+            })?;
+        let to_replace = match (same_machine, request.replace_existing) {
+            (Some(device), None) => {
+                info!(
+                    "User {} is enrolling device {} on the machine of device {}, asking to replace it",
+                    user.username, request.name, device.name
+                );
+                return Err(Status::failed_precondition(format!(
+                    "machine already enrolled as device {}",
+                    device.name
+                )));
+            }
+            (Some(device), Some(true)) => Some(device),
+            _ => None,
+        };
+        if to_replace.is_none() {
+            match dns::ensure_publishable_name(&self.pool, &request.name, None).await {
+                Ok(()) => (),
+                Err(err @ DnsError::InvalidLabel(_)) => {
+                    return Err(Status::invalid_argument(err.to_string()))
+                }
+                Err(err @ DnsError::NameCollision(..)) => {
+                    warn!("User {} failed to add device: {err}", user.username);
+                    return Err(Status::already_exists(err.to_string()));
+                }
+                Err(err) => {
+                    error!("Failed to check DNS name of device {}: {err}", request.name);
+                    return Err(Status::internal("unexpected error"));
+                }
+            }
+        }
 
+        let platform = DevicePlatform::new(request.os, request.os_version, request.client_version);
         let mut transaction = self.pool.begin().await.map_err(|_| {
             error!("Failed to begin transaction");
             Status::internal("unexpected error")
         })?;
-        device.save(&mut *transaction).await.map_err(|err| {
-            error!("Failed to save device {}: {err}", device.name);
+        let (device, configs) = match to_replace {
+            Some(device) => {
+                let device = enrollment
+                    .replace_device(
+                        &mut transaction,
+                        &self.wireguard_tx,
+                        device,
+                        pubkey.into(),
+                        platform,
+                        hints,
+                    )
+                    .await?;
+                (device, None)
+            }
+            None => {
+                let (device, configs) = enrollment
+                    .add_device(
+                        &mut transaction,
+                        &self.wireguard_tx,
+                        request.name,
+                        pubkey.into(),
+                        platform,
+                        hints,
+                    )
+                    .await?;
+                (device, Some(configs))
+            }
+        };
+        let device_id = device.get_id().map_err(|_| {
+            error!("Device {} has no id", device.name);
             Status::internal("unexpected error")
         })?;
+        let replaced = configs.is_none();
 
-        let (network_info, configs) =
-            device
-                .add_to_all_networks(&mut transaction)
+        // issue token used by desktop client to poll for location changes;
+        // previous installation of a replaced device won't poll anymore
+        if replaced {
+            PollingToken::delete_for_device(&mut *transaction, device_id)
                 .await
                 .map_err(|err| {
                     error!(
-                        "Failed to add device {} to existing networks: {err}",
+                        "Failed to remove polling tokens of device {}: {err}",
                         device.name
                     );
                     Status::internal("unexpected error")
                 })?;
-
-        self.send_wireguard_event(GatewayEvent::DeviceCreated(DeviceInfo {
-            device: device.clone(),
-            network_info,
-        }));
+        }
+        let mut polling_token = PollingToken::new(device_id);
+        polling_token.save(&mut *transaction).await.map_err(|err| {
+            error!(
+                "Failed to save polling token for device {}: {err}",
+                device.name
+            );
+            Status::internal("unexpected error")
+        })?;
 
         let settings = Settings::get_settings(&mut *transaction)
             .await
@@ -386,11 +430,20 @@ impl EnrollmentServer {
             Status::internal("unexpected error")
         })?;
 
+        // replaced device keeps its addresses, so its configs are built as for existing devices
+        let configs: Vec<ProtoDeviceConfig> = match configs {
+            Some(configs) => configs.into_iter().map(Into::into).collect(),
+            None => {
+                device_config_response(&self.pool, device.clone(), None)
+                    .await?
+                    .configs
+            }
+        };
         let template_locations: Vec<TemplateLocation> = configs
             .iter()
             .map(|c| TemplateLocation {
                 name: c.network_name.clone(),
-                assigned_ip: c.address.to_string(),
+                assigned_ip: c.assigned_ip.clone(),
             })
             .collect();
 
@@ -405,15 +458,23 @@ impl EnrollmentServer {
         )
         .map_err(|_| Status::internal("Failed to render new device added template"))?;
 
-        info!(
-            "Device {} assigned to user {} and added to all networks.",
-            device.name, user.username
-        );
+        if replaced {
+            info!(
+                "Device {} of user {} re-enrolled on the same machine with a new key",
+                device.name, user.username
+            );
+        } else {
+            info!(
+                "Device {} assigned to user {} and added to all networks.",
+                device.name, user.username
+            );
+        }
 
         let response = DeviceConfigResponse {
             device: Some(device.into()),
-            configs: configs.into_iter().map(Into::into).collect(),
+            configs,
             instance: Some(InstanceInfo::new(settings, &user.username).into()),
+            token: Some(polling_token.token),
         };
 
         Ok(response)
@@ -428,80 +489,115 @@ impl EnrollmentServer {
         debug!("Getting network info for device: {:?}", request.pubkey);
         let enrollment = self.validate_session(request.token.as_deref()).await?;
 
-        // get enrollment user
-        let user = enrollment.fetch_user(&self.pool).await?;
-
-        Device::validate_pubkey(&request.pubkey).map_err(|_| {
-            error!("Invalid pubkey {}", request.pubkey);
-            Status::invalid_argument("invalid pubkey")
+        let pubkey = WireguardPubkey::parse(&request.pubkey).map_err(|err| {
+            error!("Invalid pubkey {}: {err}", request.pubkey);
+            Status::invalid_argument(err.to_string())
         })?;
         // Find existing device by public key
-        let device = Device::find_by_pubkey(&self.pool, &request.pubkey)
+        let device = Device::find_by_pubkey(&self.pool, pubkey.as_str())
             .await
             .map_err(|_| {
                 error!("Failed to get device by its pubkey: {}", request.pubkey);
                 Status::internal("unexpected error")
             })?;
 
-        let settings = Settings::get_settings(&self.pool).await.map_err(|_| {
-            error!("Failed to get settings");
-            Status::internal("unexpected error")
-        })?;
-
-        let networks = WireguardNetwork::all(&self.pool).await.map_err(|err| {
-            error!("Failed to fetch all networks: {err}");
-            Status::internal(format!("unexpected error: {err}"))
-        })?;
-
-        let mut configs: Vec<ProtoDeviceConfig> = Vec::new();
-        if let Some(device) = device {
-            for network in networks {
-                let (Some(device_id), Some(network_id)) = (device.id, network.id) else {
-                    continue;
-                };
-                let wireguard_network_device =
-                    WireguardNetworkDevice::find(&self.pool, device_id, network_id)
-                        .await
-                        .map_err(|err| {
-                            error!("Failed to fetch wireguard network device for device {} and network {}: {err}", device_id, network_id);
-                            Status::internal(format!("unexpected error: {err}"))
-                        })?;
-                if let Some(wireguard_network_device) = wireguard_network_device {
-                    let allowed_ips = network
-                        .allowed_ips
-                        .iter()
-                        .map(IpNetwork::to_string)
-                        .collect::<Vec<String>>()
-                        .join(",");
-                    let config = ProtoDeviceConfig {
-                        config: device.create_config(&network, &wireguard_network_device),
-                        network_id,
-                        network_name: network.name,
-                        assigned_ip: wireguard_network_device.wireguard_ip.to_string(),
-                        endpoint: format!("{}:{}", network.endpoint, network.port),
-                        pubkey: network.pubkey,
-                        allowed_ips,
-                        dns: network.dns,
-                        mfa_enabled: network.mfa_enabled,
-                        keepalive_interval: network.keepalive_interval,
-                    };
-                    configs.push(config);
-                }
+        match device {
+            Some(device) if device.user_id == enrollment.user_id => {
+                device_config_response(&self.pool, device, None).await
             }
+            _ => Err(Status::internal("device not found error")),
+        }
+    }
+}
 
-            info!("Device {} configs fetched", device.name);
-
-            let response = DeviceConfigResponse {
-                device: Some(device.into()),
-                configs,
-                instance: Some(InstanceInfo::new(settings, &user.username).into()),
+/// Build configuration of all locations the device is currently assigned to.
+///
+/// Device location assignments follow allowed groups of each location, so the response
+/// always reflects current entitlements of the device owner.
+pub(super) async fn device_config_response(
+    pool: &DbPool,
+    device: Device,
+    token: Option<String>,
+) -> Result<DeviceConfigResponse, Status> {
+    let Some(device_id) = device.id else {
+        error!("Device {} has no id", device.name);
+        return Err(Status::internal("unexpected error"));
+    };
+
+    let user = User::find_by_id(pool, device.user_id)
+        .await
+        .map_err(|err| {
+            error!(
+                "Failed to fetch user {} of device {device_id}: {err}",
+                device.user_id
+            );
+            Status::internal("unexpected error")
+        })?
+        .ok_or_else(|| Status::internal("user not found error"))?;
+
+    let settings = Settings::get_settings(pool).await.map_err(|_| {
+        error!("Failed to get settings");
+        Status::internal("unexpected error")
+    })?;
+
+    let networks = WireguardNetwork::all_active(pool).await.map_err(|err| {
+        error!("Failed to fetch all networks: {err}");
+        Status::internal(format!("unexpected error: {err}"))
+    })?;
+
+    let mut configs: Vec<ProtoDeviceConfig> = Vec::new();
+    for network in networks {
+        let Some(network_id) = network.id else {
+            continue;
+        };
+        let wireguard_network_device = WireguardNetworkDevice::find(pool, device_id, network_id)
+            .await
+            .map_err(|err| {
+                error!(
+                    "Failed to fetch wireguard network device for device {} and network {}: {err}",
+                    device_id, network_id
+                );
+                Status::internal(format!("unexpected error: {err}"))
+            })?;
+        if let Some(wireguard_network_device) = wireguard_network_device {
+            let allowed_ips = network
+                .allowed_ips
+                .iter()
+                .map(IpNetwork::to_string)
+                .collect::<Vec<String>>()
+                .join(",");
+            let client_routes = network
+                .client_routes
+                .iter()
+                .map(IpNetwork::to_string)
+                .collect::<Vec<String>>()
+                .join(",");
+            let config = ProtoDeviceConfig {
+                config: device.create_config(&network, &wireguard_network_device),
+                network_id,
+                endpoint: network.endpoint_with_port(),
+                network_name: network.name,
+                assigned_ip: wireguard_network_device.wireguard_ip.to_string(),
+                pubkey: network.pubkey,
+                allowed_ips,
+                client_routes,
+                dns: network.dns,
+                mfa_enabled: network.mfa_enabled,
+                keepalive_interval: network.keepalive_interval,
+                mtu: network.mtu,
             };
-
-            Ok(response)
-        } else {
-            Err(Status::internal("device not found error"))
+            configs.push(config);
         }
     }
+
+    info!("Device {} configs fetched", device.name);
+
+    Ok(DeviceConfigResponse {
+        device: Some(device.into()),
+        configs,
+        instance: Some(InstanceInfo::new(settings, &user.username).into()),
+        token,
+    })
 }
 
 impl From<User> for AdminInfo {
@@ -540,6 +636,12 @@ impl From<DeviceConfig> for ProtoDeviceConfig {
             .map(IpNetwork::to_string)
             .collect::<Vec<String>>()
             .join(",");
+        let client_routes = config
+            .client_routes
+            .iter()
+            .map(IpNetwork::to_string)
+            .collect::<Vec<String>>()
+            .join(",");
         Self {
             network_id: config.network_id,
             network_name: config.network_name,
@@ -548,9 +650,11 @@ impl From<DeviceConfig> for ProtoDeviceConfig {
             assigned_ip: config.address.to_string(),
             pubkey: config.pubkey,
             allowed_ips,
+            client_routes,
             dns: config.dns,
             mfa_enabled: config.mfa_enabled,
             keepalive_interval: config.keepalive_interval,
+            mtu: config.mtu,
         }
     }
 }
@@ -568,6 +672,221 @@ impl From<Device> for ProtoDevice {
 }
 
 impl Token {
+    /// Find token while guarding against guessing.
+    ///
+    /// Unknown tokens are logged as failed attempts and addresses with too many of them
+    /// are locked out. Tokens targeted by repeated attempts get invalidated, and their
+    /// users and issuing admins are notified.
+    pub(crate) async fn find_guarded(
+        pool: &DbPool,
+        mail_tx: &UnboundedSender<Mail>,
+        failed_tokens: &Mutex<FailedTokenMap>,
+        id: &str,
+        ip_address: Option<IpAddr>,
+    ) -> Result<Self, TokenError> {
+        check_token_attempt(failed_tokens, ip_address)?;
+        match Self::find_by_id(pool, id).await {
+            Err(TokenError::NotFound) => {
+                if let Some(prefix) = log_failed_token_attempt(failed_tokens, ip_address, id) {
+                    warn!("Repeated attempts to guess a token, invalidating matching tokens");
+                    for token in Self::invalidate_by_prefix(pool, &prefix).await? {
+                        token
+                            .send_locked_notifications(pool, mail_tx, ip_address)
+                            .await?;
+                    }
+                }
+                Err(TokenError::NotFound)
+            }
+            result => result,
+        }
+    }
+
+    // Notify user and issuing admin that the token has been invalidated
+    async fn send_locked_notifications(
+        &self,
+        pool: &DbPool,
+        mail_tx: &UnboundedSender<Mail>,
+        ip_address: Option<IpAddr>,
+    ) -> Result<(), TokenError> {
+        let user = self.fetch_user(pool).await?;
+        let token_kind = if self.token_type.as_deref() == Some(PASSWORD_RESET_TOKEN_TYPE) {
+            "password reset"
+        } else {
+            "enrollment"
+        };
+        warn!(
+            "Invalidated {token_kind} token of user {} after repeated guessing attempts",
+            user.username
+        );
+        let ip_address = ip_address.map(|ip| ip.to_string());
+        let mut recipients = vec![(user.email.clone(), false)];
+        if let Some(admin) = self.fetch_admin(pool).await? {
+            recipients.push((admin.email, true));
+        }
+        for (to, to_admin) in recipients {
+            let mail = Mail {
+                to,
+                subject: "[defguard] Token invalidated".into(),
+                content: templates::token_locked_mail(
+                    &user.username,
+                    token_kind,
+                    to_admin,
+                    ip_address.as_deref(),
+                )?,
+                attachments: Vec::new(),
+                result_tx: None,
+            };
+            if let Err(err) = mail_tx.send(mail) {
+                error!("Error sending token invalidation mail: {err}");
+                return Err(TokenError::NotificationError(err.to_string()));
+            }
+        }
+        info!(
+            "Sent token invalidation notifications for user {}",
+            user.username
+        );
+        Ok(())
+    }
+
+    /// Find enrollment token and check that its session is still valid.
+    pub(crate) async fn find_session(pool: &DbPool, id: &str) -> Result<Self, TokenError> {
+        let enrollment = Self::find_by_id(pool, id).await?;
+        if enrollment.is_session_valid(server_config().enrollment_session_timeout.as_secs()) {
+            info!("Enrollment session validated");
+            Ok(enrollment)
+        } else {
+            error!("Enrollment session expired");
+            Err(TokenError::SessionExpired)
+        }
+    }
+
+    /// Set password of the enrolled user, sync it with LDAP and send welcome
+    /// and admin notifications.
+    ///
+    /// Shared by desktop client and web browser enrollment. Token row stays locked
+    /// until the user is saved, so an account can be activated only once.
+    pub(crate) async fn activate_user(
+        &self,
+        pool: &DbPool,
+        mail_tx: &UnboundedSender<Mail>,
+        password: &str,
+        phone_number: Option<String>,
+        ip_address: &str,
+        device_info: Option<&str>,
+    ) -> Result<User, TokenError> {
+        let mut transaction = pool.begin().await?;
+        self.lock(&mut transaction).await?;
+
+        let mut user = self.fetch_user(&mut *transaction).await?;
+        if user.has_password() {
+            error!("User {} already activated", user.username);
+            return Err(TokenError::AlreadyActive);
+        }
+        if !user.is_active {
+            warn!(
+                "Can't finalize enrollment for disabled user {}",
+                user.username
+            );
+            return Err(TokenError::UserDisabled);
+        }
+
+        // update user
+        user.phone = phone_number;
+        user.set_password(password);
+        user.save(&mut *transaction).await?;
+
+        // sync with LDAP
+        let _result = ldap_add_user(pool, &user, password).await;
+
+        let settings = Settings::get_settings(&mut *transaction).await?;
+
+        // send welcome email
+        self.send_welcome_email(
+            &mut transaction,
+            mail_tx,
+            &user,
+            &settings,
+            ip_address,
+            device_info,
+        )
+        .await?;
+
+        // send success notification to admin
+        if let Some(admin) = self.fetch_admin(&mut *transaction).await? {
+            Self::send_admin_notification(mail_tx, &admin, &user, ip_address, device_info)?;
+        }
+
+        transaction.commit().await?;
+        info!("User {} activated", user.username);
+
+        Ok(user)
+    }
+
+    /// Create a device for the enrolled user, add it to all locations and notify gateways.
+    ///
+    /// Public key has to be validated by the caller.
+    pub(crate) async fn add_device(
+        &self,
+        transaction: &mut PgConnection,
+        wireguard_tx: &Sender<GatewayEvent>,
+        name: String,
+        pubkey: String,
+        platform: DevicePlatform,
+        hints: MachineHints,
+    ) -> Result<(Device, Vec<DeviceConfig>), TokenError> {
+        Device::check_limit(&mut *transaction, self.user_id).await?;
+        let name = Device::check_name(&mut *transaction, self.user_id, &name, None).await?;
+        let mut device = Device::new(name, pubkey, self.user_id);
+        device.set_platform(platform);
+        device.set_machine_hints(hints);
+        device.save(&mut *transaction).await?;
+
+        let (network_info, configs) = device.add_to_all_networks(transaction).await?;
+        for event in GatewayEvent::peers_added(DeviceInfo {
+            device: device.clone(),
+            network_info,
+        }) {
+            if let Err(err) = wireguard_tx.send(event) {
+                error!("Error sending WireGuard event {err}");
+            }
+        }
+
+        Ok((device, configs))
+    }
+
+    /// Rotate key of a device of the enrolled user, which is being enrolled again on the same
+    /// machine. Device keeps its addresses and location assignments, gateways only swap the key.
+    ///
+    /// Public key has to be validated by the caller.
+    pub(crate) async fn replace_device(
+        &self,
+        transaction: &mut PgConnection,
+        wireguard_tx: &Sender<GatewayEvent>,
+        mut device: Device,
+        pubkey: String,
+        platform: DevicePlatform,
+        hints: MachineHints,
+    ) -> Result<Device, TokenError> {
+        let previous_pubkey = std::mem::replace(&mut device.wireguard_pubkey, pubkey);
+        if !platform.is_empty() {
+            device.set_platform(platform);
+        }
+        device.set_machine_hints(hints);
+        device.save(&mut *transaction).await?;
+        device.clear_pubkey_issue(&mut *transaction).await?;
+
+        let device_info = DeviceInfo::from_device(&mut *transaction, device.clone())
+            .await
+            .map_err(DeviceError::from)?;
+        for event in GatewayEvent::peers_modified(device_info, Some(previous_pubkey)) {
+            if let Err(err) = wireguard_tx.send(event) {
+                error!("Error sending WireGuard event {err}");
+            }
+        }
+
+        Ok(device)
+    }
+
     // Send configured welcome email to user after finishing enrollment
     async fn send_welcome_email(
         &self,
@@ -639,3 +958,471 @@ impl Token {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use claims::{assert_err, assert_matches};
+    use sqlx::query;
+    use tokio::sync::{broadcast, mpsc::unbounded_channel};
+    use tonic::Code;
+
+    use super::*;
+    use crate::{config::DefGuardConfig, headers::create_user_agent_parser, SERVER_CONFIG};
+
+    #[test]
+    fn test_device_config_client_routes() {
+        let config = DeviceConfig {
+            network_id: 1,
+            network_name: "network".into(),
+            config: String::new(),
+            address: "10.1.1.2".parse().unwrap(),
+            endpoint: "vpn.example.com:51820".into(),
+            allowed_ips: vec!["10.1.1.0/24".parse().unwrap()],
+            client_routes: vec![
+                "172.16.5.0/24".parse().unwrap(),
+                "fd00:5::/64".parse().unwrap(),
+            ],
+            pubkey: "key".into(),
+            dns: None,
+            mfa_enabled: false,
+            keepalive_interval: 25,
+            mtu: None,
+        };
+        let config = ProtoDeviceConfig::from(config);
+        assert_eq!(config.allowed_ips, "10.1.1.0/24");
+        assert_eq!(config.client_routes, "172.16.5.0/24,fd00:5::/64");
+    }
+
+    #[sqlx::test]
+    async fn test_create_device_pubkey(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let mut token = Token::new(
+            user.id.unwrap(),
+            None,
+            Some(user.email.clone()),
+            3600,
+            Some(ENROLLMENT_TOKEN_TYPE.to_string()),
+        );
+        token.used_at = Some(Utc::now().naive_utc());
+        token
+            .save(&mut pool.acquire().await.unwrap())
+            .await
+            .unwrap();
+
+        let (wireguard_tx, _wireguard_rx) = broadcast::channel(16);
+        let (mail_tx, _mail_rx) = unbounded_channel();
+        let server = EnrollmentServer::new(
+            pool.clone(),
+            wireguard_tx,
+            mail_tx,
+            create_user_agent_parser(),
+            Arc::default(),
+        );
+        let request = |name: &str, pubkey: &str| NewDevice {
+            name: name.into(),
+            pubkey: pubkey.into(),
+            token: Some(token.id.clone()),
+            os: Some("macOS".into()),
+            os_version: Some("14.5".into()),
+            client_version: Some("0.9.2".into()),
+            machine_id: None,
+            hostname: None,
+            replace_existing: None,
+        };
+
+        for pubkey in ["", "invalid_key", "c2hvcnQ="] {
+            let status = server
+                .create_device(request("laptop", pubkey), None)
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+
+        // key is stored normalized
+        let response = server
+            .create_device(
+                request("laptop", " LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU\n"),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            response.device.unwrap().pubkey,
+            "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU="
+        );
+
+        // reported platform is stored with the device
+        let device = Device::find_by_pubkey(&pool, "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(device.os.as_deref(), Some("macos"));
+        assert_eq!(device.os_version.as_deref(), Some("14.5"));
+        assert_eq!(device.client_version.as_deref(), Some("0.9.2"));
+
+        let status = server
+            .create_device(
+                request("phone", "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU="),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert_eq!(status.message(), "pubkey already used by device laptop");
+    }
+
+    #[sqlx::test]
+    async fn test_create_device_limit(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let mut token = Token::new(
+            user.id.unwrap(),
+            None,
+            Some(user.email.clone()),
+            3600,
+            Some(ENROLLMENT_TOKEN_TYPE.to_string()),
+        );
+        token.used_at = Some(Utc::now().naive_utc());
+        token
+            .save(&mut pool.acquire().await.unwrap())
+            .await
+            .unwrap();
+        query!("UPDATE settings SET max_devices_per_user = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let (wireguard_tx, _wireguard_rx) = broadcast::channel(16);
+        let (mail_tx, _mail_rx) = unbounded_channel();
+        let server = EnrollmentServer::new(
+            pool.clone(),
+            wireguard_tx,
+            mail_tx,
+            create_user_agent_parser(),
+            Arc::default(),
+        );
+        let request = |name: &str, pubkey: &str| NewDevice {
+            name: name.into(),
+            pubkey: pubkey.into(),
+            token: Some(token.id.clone()),
+            os: None,
+            os_version: None,
+            client_version: None,
+            machine_id: None,
+            hostname: None,
+            replace_existing: None,
+        };
+
+        server
+            .create_device(
+                request("laptop", "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU="),
+                None,
+            )
+            .await
+            .unwrap();
+        let status = server
+            .create_device(
+                request("phone", "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38="),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(
+            status.message(),
+            "Device limit reached, user has 1 of 1 devices"
+        );
+        assert_eq!(user.devices(&pool).await.unwrap().len(), 1);
+
+        // personal limit takes precedence
+        user.max_devices = Some(2);
+        user.save(&pool).await.unwrap();
+        assert_eq!(
+            Device::remaining_quota(&pool, user.id.unwrap())
+                .await
+                .unwrap(),
+            Some(1)
+        );
+        server
+            .create_device(
+                request("phone", "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38="),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            Device::remaining_quota(&pool, user.id.unwrap())
+                .await
+                .unwrap(),
+            Some(0)
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_create_device_name_policy(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let mut token = Token::new(
+            user.id.unwrap(),
+            None,
+            Some(user.email.clone()),
+            3600,
+            Some(ENROLLMENT_TOKEN_TYPE.to_string()),
+        );
+        token.used_at = Some(Utc::now().naive_utc());
+        token
+            .save(&mut pool.acquire().await.unwrap())
+            .await
+            .unwrap();
+        query!("UPDATE settings SET device_name_policy = 'sanitize'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let (wireguard_tx, _wireguard_rx) = broadcast::channel(16);
+        let (mail_tx, _mail_rx) = unbounded_channel();
+        let server = EnrollmentServer::new(
+            pool.clone(),
+            wireguard_tx,
+            mail_tx,
+            create_user_agent_parser(),
+            Arc::default(),
+        );
+        let request = |name: &str, pubkey: &str| NewDevice {
+            name: name.into(),
+            pubkey: pubkey.into(),
+            token: Some(token.id.clone()),
+            os: None,
+            os_version: None,
+            client_version: None,
+            machine_id: None,
+            hostname: None,
+            replace_existing: None,
+        };
+
+        let response = server
+            .create_device(
+                request(
+                    " Laptop 💻\n",
+                    "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+                ),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.device.unwrap().name, "Laptop");
+
+        let status = server
+            .create_device(
+                request("laptop", "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38="),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert_eq!(
+            status.message(),
+            "User already has a device named laptop, try laptop-2"
+        );
+        let status = server
+            .create_device(
+                request("💻", "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38="),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(user.devices(&pool).await.unwrap().len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn test_create_device_same_machine(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let mut token = Token::new(
+            user.id.unwrap(),
+            None,
+            Some(user.email.clone()),
+            3600,
+            Some(ENROLLMENT_TOKEN_TYPE.to_string()),
+        );
+        token.used_at = Some(Utc::now().naive_utc());
+        token
+            .save(&mut pool.acquire().await.unwrap())
+            .await
+            .unwrap();
+        let mut network = WireguardNetwork {
+            name: "hogwarts".into(),
+            ..Default::default()
+        };
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.save(&pool).await.unwrap();
+
+        let (wireguard_tx, mut wireguard_rx) = broadcast::channel(16);
+        let (mail_tx, _mail_rx) = unbounded_channel();
+        let server = EnrollmentServer::new(
+            pool.clone(),
+            wireguard_tx,
+            mail_tx,
+            create_user_agent_parser(),
+            Arc::default(),
+        );
+        let request = |pubkey: &str,
+                       machine_id: &str,
+                       hostname: &str,
+                       replace_existing: Option<bool>| NewDevice {
+            name: "laptop".into(),
+            pubkey: pubkey.into(),
+            token: Some(token.id.clone()),
+            os: Some("linux".into()),
+            os_version: None,
+            client_version: Some("1.0.0".into()),
+            machine_id: Some(machine_id.into()),
+            hostname: Some(hostname.into()),
+            replace_existing,
+        };
+
+        let response = server
+            .create_device(
+                request(
+                    "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+                    "4c4c4544-0051",
+                    "hogwarts-laptop",
+                    None,
+                ),
+                None,
+            )
+            .await
+            .unwrap();
+        let first_token = response.token.unwrap();
+        let device = Device::find_by_pubkey(&pool, "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=")
+            .await
+            .unwrap()
+            .unwrap();
+        let address = WireguardNetworkDevice::find(&pool, device.id.unwrap(), network.id.unwrap())
+            .await
+            .unwrap()
+            .unwrap()
+            .wireguard_ip;
+        // raw machine ID isn't stored
+        let machine_hash = device.machine_hash.clone().unwrap();
+        assert_eq!(machine_hash.len(), 64);
+        assert!(!machine_hash.contains("4c4c4544"));
+        assert_matches!(wireguard_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(_));
+
+        // client has to confirm replacing device enrolled on the same machine
+        let status = server
+            .create_device(
+                request(
+                    "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=",
+                    "4C4C4544-0051",
+                    "ubuntu",
+                    None,
+                ),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert_eq!(
+            status.message(),
+            "machine already enrolled as device laptop"
+        );
+        assert_err!(wireguard_rx.try_recv());
+
+        // replaced device keeps its address, gateways get the new key
+        let response = server
+            .create_device(
+                request(
+                    "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=",
+                    "4C4C4544-0051",
+                    "ubuntu",
+                    Some(true),
+                ),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            response.device.unwrap().pubkey,
+            "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38="
+        );
+        assert_eq!(response.configs.len(), 1);
+        assert_eq!(response.configs[0].assigned_ip, address.to_string());
+        assert_matches!(
+            wireguard_rx.try_recv().unwrap(),
+            GatewayEvent::PeerModified(ref update, Some(ref previous))
+                if update.device.id == device.id
+                    && previous == "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU="
+        );
+        assert_err!(wireguard_rx.try_recv());
+        let devices = user.devices(&pool).await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].device.id, device.id);
+        assert_eq!(devices[0].device.hostname.as_deref(), Some("ubuntu"));
+        assert!(PollingToken::find(&pool, &first_token)
+            .await
+            .unwrap()
+            .is_none());
+
+        // different machines get new devices, as does the same one if user declines replacing
+        let mut different = request(
+            "hNuapt7lOxF93KUqZGUY00oKJxH8LYwwsUVB1uUa0y4=",
+            "8d1a7e2c-0002",
+            "desktop",
+            None,
+        );
+        different.name = "desktop".into();
+        let response = server.create_device(different, None).await.unwrap();
+        assert_ne!(response.device.unwrap().id, device.id.unwrap());
+        assert_matches!(wireguard_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(_));
+        let mut declined = request(
+            "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=",
+            "4c4c4544-0051",
+            "ubuntu",
+            Some(false),
+        );
+        declined.name = "second-laptop".into();
+        server.create_device(declined, None).await.unwrap();
+        assert_matches!(wireguard_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(_));
+        assert_eq!(user.devices(&pool).await.unwrap().len(), 3);
+    }
+}
@@ -1,32 +1,64 @@
 use std::{
+    collections::HashMap,
+    net::IpAddr,
     pin::Pin,
     sync::{Arc, Mutex},
     task::{Context, Poll},
 };
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDateTime, Utc};
 use sqlx::{query, Error as SqlxError, PgExecutor};
 use tokio::{
     sync::{
-        broadcast::{Receiver as BroadcastReceiver, Sender},
+        broadcast::{error::RecvError, Receiver as BroadcastReceiver, Sender},
         mpsc::{self, Receiver, UnboundedSender},
     },
     task::JoinHandle,
 };
-use tokio_stream::Stream;
-use tonic::{metadata::MetadataMap, Code, Request, Response, Status};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{
+    metadata::{MetadataMap, MetadataValue},
+    Code, Request, Response, Status,
+};
 
-use super::GatewayMap;
+use super::{peer_stats::PeerStatsBatcher, GatewayMap};
 use crate::{
     db::{
-        models::wireguard::{WireguardNetwork, WireguardPeerStats},
+        models::{
+            device::WireguardNetworkDevice,
+            wireguard::{
+                PeerUpdate, WireguardNetwork, WireguardPeerStats, WIREGUARD_MAX_HANDSHAKE_MINUTES,
+            },
+        },
         DbPool, Device, GatewayEvent,
     },
+    feature_flags::{self, FeatureFlag},
+    gateway_event_journal::{clear_sync_pending, events_since, journal_head, sync_pending_since},
+    geoip::geoip_database,
+    live_events::{self, ClientConnectionTracker, ConnectionChange},
     mail::Mail,
+    new_country_alert::check_connection_country,
+    server_config,
 };
 
 tonic::include_proto!("gateway");
 
+/// Metadata key holding gateway event journal sequence number. Sent to gateways when
+/// they open the update stream, and back by gateways reopening it to get missed events.
+pub const GATEWAY_EVENT_SEQ_KEY: &str = "gateway-event-seq";
+
+/// Metadata key set to `true` by gateways opening the update stream which accept full
+/// configuration as network configuration followed by batches of peers, like the one
+/// streamed by `ConfigStream`. Older gateways get all peers in the network configuration.
+pub const GATEWAY_PEER_BATCHES_KEY: &str = "gateway-peer-batches";
+
+/// Bandwidth limit of a peer; device overrides take precedence over location defaults.
+fn bandwidth_limit(device_limit: Option<i32>, network_limit: Option<i32>) -> Option<u32> {
+    device_limit
+        .or(network_limit)
+        .and_then(|limit| u32::try_from(limit).ok())
+}
+
 pub struct GatewayServer {
     pool: DbPool,
     state: Arc<Mutex<GatewayMap>>,
@@ -44,17 +76,37 @@ impl WireguardNetwork {
         E: PgExecutor<'e>,
     {
         debug!("Fetching all peers for network {}", self.id.unwrap());
+        let peers = self.fetch_peers(executor, None, None).await?;
+        Ok(peers.into_iter().map(|(_, peer)| peer).collect())
+    }
+
+    /// Get allowed peers ordered by device ID, with the ID, starting after given device
+    /// and limited to given number of peers.
+    async fn fetch_peers<'e, E>(
+        &self,
+        executor: E,
+        after_device_id: Option<i64>,
+        limit: Option<i64>,
+    ) -> Result<Vec<(i64, Peer)>, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
         let rows = query!(
-            "SELECT d.wireguard_pubkey as pubkey, preshared_key, \
-                array[host(wnd.wireguard_ip)] as \"allowed_ips!: Vec<String>\" \
+            "SELECT d.id, d.wireguard_pubkey as pubkey, preshared_key, \
+                array[host(wnd.wireguard_ip)] as \"allowed_ips!: Vec<String>\", \
+                wnd.upload_limit_kbps, wnd.download_limit_kbps \
             FROM wireguard_network_device wnd \
             JOIN device d ON wnd.device_id = d.id \
             JOIN \"user\" u ON d.user_id = u.id \
             WHERE wireguard_network_id = $1 AND (is_authorized = true OR NOT $2) \
-            AND u.is_active = true \
-            ORDER BY d.id ASC",
+            AND u.is_active = true AND NOT d.blocked \
+            AND ($3::bigint IS NULL OR d.id > $3) \
+            ORDER BY d.id ASC \
+            LIMIT $4",
             self.id,
-            self.mfa_enabled
+            self.mfa_enabled,
+            after_device_id,
+            limit
         )
         .fetch_all(executor)
         .await?;
@@ -63,11 +115,24 @@ impl WireguardNetwork {
         // doesn't support unsigned integers
         let result = rows
             .into_iter()
-            .map(|row| Peer {
-                pubkey: row.pubkey,
-                allowed_ips: row.allowed_ips,
-                preshared_key: row.preshared_key,
-                keepalive_interval: Some(self.keepalive_interval as u32),
+            .map(|row| {
+                (
+                    row.id,
+                    Peer {
+                        pubkey: row.pubkey,
+                        allowed_ips: row.allowed_ips,
+                        preshared_key: row.preshared_key,
+                        keepalive_interval: Some(self.keepalive_interval as u32),
+                        upload_limit_kbps: bandwidth_limit(
+                            row.upload_limit_kbps,
+                            self.upload_limit_kbps,
+                        ),
+                        download_limit_kbps: bandwidth_limit(
+                            row.download_limit_kbps,
+                            self.download_limit_kbps,
+                        ),
+                    },
+                )
             })
             .collect();
 
@@ -75,6 +140,94 @@ impl WireguardNetwork {
     }
 }
 
+/// Allowed peers of a network read from the database in batches, ordered by device ID,
+/// so that memory use while sending full configuration doesn't depend on the number of peers.
+struct PeerBatches<'a> {
+    network: &'a WireguardNetwork,
+    batch_size: usize,
+    // last device of the previous batch
+    after_device_id: Option<i64>,
+}
+
+impl<'a> PeerBatches<'a> {
+    fn new(network: &'a WireguardNetwork, batch_size: usize) -> Self {
+        Self {
+            network,
+            batch_size,
+            after_device_id: None,
+        }
+    }
+
+    /// Read next batch. There's always at least one, the last one is marked.
+    async fn next(&mut self, pool: &DbPool) -> Result<PeerBatch, SqlxError> {
+        // one more peer is read to tell whether this is the last batch
+        let mut peers = self
+            .network
+            .fetch_peers(pool, self.after_device_id, Some(self.batch_size as i64 + 1))
+            .await?;
+        let last = peers.len() <= self.batch_size;
+        peers.truncate(self.batch_size);
+        self.after_device_id = peers.last().map(|(device_id, _)| *device_id);
+        Ok(PeerBatch {
+            peers: peers.into_iter().map(|(_, peer)| peer).collect(),
+            last,
+        })
+    }
+}
+
+/// Split peers already loaded into batches, the last one marked.
+/// There's always at least one batch, so that gateways know the peer set is complete.
+fn peer_batches(peers: Vec<Peer>, batch_size: usize) -> Vec<PeerBatch> {
+    let mut peers = peers.into_iter().peekable();
+    let mut batches = Vec::new();
+    loop {
+        let batch: Vec<_> = peers.by_ref().take(batch_size).collect();
+        let last = peers.peek().is_none();
+        batches.push(PeerBatch { peers: batch, last });
+        if last {
+            return batches;
+        }
+    }
+}
+
+/// Stream full network configuration to a connecting gateway: network configuration without
+/// peers first, then peers read from the database in batches. Channel capacity bounds
+/// the number of batches held in memory while the gateway receives them.
+/// Returns the number of peers sent.
+async fn stream_config(
+    pool: &DbPool,
+    network: &WireguardNetwork,
+    tx: &mpsc::Sender<Result<ConfigurationChunk, Status>>,
+) -> Result<usize, Status> {
+    let send = |payload| async move {
+        tx.send(Ok(ConfigurationChunk {
+            payload: Some(payload),
+        }))
+        .await
+        .map_err(|_| Status::cancelled("Gateway disconnected while receiving configuration"))
+    };
+    send(configuration_chunk::Payload::Configuration(gen_config(
+        network,
+    )))
+    .await?;
+    let mut batches = PeerBatches::new(network, server_config().gateway_peer_batch_size);
+    let mut count = 0;
+    loop {
+        let batch = batches.next(pool).await.map_err(|err| {
+            error!("Failed to fetch peers from the database for network {network}: {err}");
+            Status::internal(format!(
+                "Failed to retrieve peers from the database for network: {network}"
+            ))
+        })?;
+        let last = batch.last;
+        count += batch.peers.len();
+        send(configuration_chunk::Payload::Peers(batch)).await?;
+        if last {
+            return Ok(count);
+        }
+    }
+}
+
 impl GatewayServer {
     /// Create new gateway server instance
     #[must_use]
@@ -92,6 +245,50 @@ impl GatewayServer {
         }
     }
 
+    /// Find network of a gateway requesting configuration, and record the gateway as connected.
+    async fn connect_config_client(
+        &self,
+        network_id: i64,
+        request: Request<ConfigurationRequest>,
+    ) -> Result<WireguardNetwork, Status> {
+        let mut network = WireguardNetwork::find_by_id(&self.pool, network_id)
+            .await
+            .map_err(|e| {
+                error!("Network {network_id} not found");
+                Status::new(Code::Internal, format!("Failed to retrieve network: {e}"))
+            })?
+            .ok_or_else(|| {
+                Status::new(
+                    Code::Internal,
+                    format!("Network with id {} not found", network_id),
+                )
+            })?;
+        Self::ensure_source_allowed(&network, request.metadata())?;
+        Self::ensure_not_archived(&network)?;
+        let hostname = Self::get_gateway_hostname(request.metadata())?;
+
+        debug!("Sending configuration to gateway client, network {network}.");
+
+        // store connected gateway in memory
+        {
+            let mut state = self.state.lock().unwrap();
+            state.add_gateway(
+                network_id,
+                &network.name,
+                hostname,
+                request.into_inner().name,
+                self.mail_tx.clone(),
+            );
+        }
+
+        network.connected_at = Some(Utc::now().naive_utc());
+        if let Err(err) = network.save(&self.pool).await {
+            error!("Failed to save updated network {network_id} in the database, status: {err}");
+        }
+
+        Ok(network)
+    }
+
     fn get_network_id(metadata: &MetadataMap) -> Result<i64, Status> {
         match Self::get_network_id_from_metadata(metadata) {
             Some(m) => Ok(m),
@@ -114,6 +311,22 @@ impl GatewayServer {
         None
     }
 
+    // journal sequence number of the last event known to a reconnecting gateway
+    fn get_event_seq(metadata: &MetadataMap) -> Option<i64> {
+        metadata
+            .get(GATEWAY_EVENT_SEQ_KEY)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    }
+
+    // whether a gateway opening the update stream accepts batches of peers
+    fn accepts_peer_batches(metadata: &MetadataMap) -> bool {
+        metadata
+            .get(GATEWAY_PEER_BATCHES_KEY)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value == "true")
+    }
+
     // extract gateway hostname from request headers
     fn get_gateway_hostname(metadata: &MetadataMap) -> Result<String, Status> {
         match metadata.get("hostname") {
@@ -132,15 +345,86 @@ impl GatewayServer {
             )),
         }
     }
+
+    // gateways of archived networks are refused until the network is restored
+    fn ensure_not_archived(network: &WireguardNetwork) -> Result<(), Status> {
+        if network.archived {
+            warn!("Refusing gateway connection for archived network {network}");
+            Err(Status::new(
+                Code::FailedPrecondition,
+                format!("Network {} is archived", network.name),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    // gateways may be restricted to connect only from given addresses;
+    // rejection looks the same as an invalid token, so it doesn't reveal the token is valid
+    fn ensure_source_allowed(
+        network: &WireguardNetwork,
+        metadata: &MetadataMap,
+    ) -> Result<(), Status> {
+        let source_ip = metadata
+            .get("gateway_source_ip")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<IpAddr>().ok());
+        if network.gateway_source_allowed(source_ip) {
+            Ok(())
+        } else {
+            warn!(
+                "Rejected gateway connection for network {network} from address {}, \
+                not in gateway allowlist",
+                source_ip.map_or("unknown".into(), |address| address.to_string())
+            );
+            Err(Status::unauthenticated("Invalid token"))
+        }
+    }
+
+    // find ID of a device with given public key
+    async fn find_device_id(&self, public_key: &str) -> Result<i64, Status> {
+        match Device::find_by_pubkey(&self.pool, public_key).await {
+            Ok(Some(device)) => device.id.ok_or_else(|| {
+                Status::new(
+                    Code::Internal,
+                    format!(
+                        "Device {} (public key: {public_key}) has no ID",
+                        device.name
+                    ),
+                )
+            }),
+            Ok(None) => {
+                error!("Device with public key {public_key} not found");
+                Err(Status::new(
+                    Code::Internal,
+                    format!("Device with public key {public_key} not found"),
+                ))
+            }
+            Err(err) => {
+                error!("Failed to retrieve device with public key {public_key}: {err}",);
+                Err(Status::new(
+                    Code::Internal,
+                    format!("Failed to retrieve device with public key {public_key}: {err}",),
+                ))
+            }
+        }
+    }
 }
 
-fn gen_config(network: &WireguardNetwork, peers: Vec<Peer>) -> Configuration {
+/// Network configuration without peers, which are added to it or sent separately in batches.
+fn gen_config(network: &WireguardNetwork) -> Configuration {
     Configuration {
         name: network.name.clone(),
         port: network.port as u32,
         prvkey: network.prvkey.clone(),
         address: network.address.to_string(),
-        peers,
+        peers: Vec::new(),
+        masquerade_enabled: network.masquerade_enabled,
+        nat_exempt_networks: network
+            .nat_exempt_networks
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
     }
 }
 
@@ -166,6 +450,131 @@ impl WireguardPeerStats {
     }
 }
 
+/// Follows VPN clients in peer stats reported by a single gateway.
+///
+/// In locations with multiple gateways, clients are attributed to the gateway which
+/// reported their most recent handshake. A client moving to another gateway, e.g. on
+/// failover, isn't reported as disconnecting and connecting again.
+struct GatewayClients {
+    network_id: i64,
+    gateway_hostname: Option<String>,
+    connections: ClientConnectionTracker,
+    // latest handshakes already recorded, so unchanged ones aren't written again
+    handshakes: HashMap<i64, NaiveDateTime>,
+}
+
+impl GatewayClients {
+    fn new(network_id: i64, gateway_hostname: Option<String>) -> Self {
+        Self {
+            network_id,
+            gateway_hostname,
+            connections: ClientConnectionTracker::new(),
+            handshakes: HashMap::new(),
+        }
+    }
+
+    /// Record which gateway the client is connected through. Returns `true` if the client
+    /// is attributed to another gateway, which has seen it recently.
+    async fn record_gateway(
+        &mut self,
+        pool: &DbPool,
+        stats: &WireguardPeerStats,
+    ) -> Result<bool, SqlxError> {
+        let Some(hostname) = &self.gateway_hostname else {
+            return Ok(false);
+        };
+        if !self.connections.is_connected(stats.device_id)
+            || self
+                .handshakes
+                .get(&stats.device_id)
+                .is_some_and(|handshake| *handshake >= stats.latest_handshake)
+        {
+            return Ok(false);
+        }
+        self.handshakes
+            .insert(stats.device_id, stats.latest_handshake);
+        let threshold =
+            (Utc::now() - ChronoDuration::minutes(WIREGUARD_MAX_HANDSHAKE_MINUTES)).naive_utc();
+        match WireguardNetworkDevice::record_gateway(
+            pool,
+            stats.device_id,
+            self.network_id,
+            hostname,
+            stats.latest_handshake,
+        )
+        .await?
+        {
+            // another gateway reported a more recent handshake
+            None => Ok(true),
+            Some((Some(previous), previous_handshake)) if previous != *hostname => {
+                debug!(
+                    "Device {} in network {} moved from gateway {previous} to {hostname}",
+                    stats.device_id, self.network_id
+                );
+                Ok(previous_handshake.is_some_and(|handshake| handshake >= threshold))
+            }
+            Some(_) => Ok(false),
+        }
+    }
+
+    // whether client is still attributed to this gateway
+    async fn is_attributed(&self, pool: &DbPool, device_id: i64) -> Result<bool, SqlxError> {
+        let Some(hostname) = &self.gateway_hostname else {
+            return Ok(true);
+        };
+        Ok(
+            WireguardNetworkDevice::find(pool, device_id, self.network_id)
+                .await?
+                .and_then(|network_device| network_device.gateway_hostname)
+                .map_or(true, |gateway| gateway == *hostname),
+        )
+    }
+
+    /// Update client state and publish connection changes. Returns `true` if the client
+    /// has just connected to this gateway.
+    async fn update(&mut self, pool: &DbPool, stats: &WireguardPeerStats) -> bool {
+        let change = self.connections.update(stats);
+        let moved = match self.record_gateway(pool, stats).await {
+            Ok(moved) => moved,
+            Err(err) => {
+                error!(
+                    "Failed to record gateway of device {} in network {}: {err}",
+                    stats.device_id, self.network_id
+                );
+                false
+            }
+        };
+        let publish = match change {
+            Some(ConnectionChange::Connected) => !moved,
+            Some(ConnectionChange::Disconnected) => {
+                match self.is_attributed(pool, stats.device_id).await {
+                    Ok(attributed) => attributed,
+                    Err(err) => {
+                        error!(
+                            "Failed to fetch gateway of device {} in network {}: {err}",
+                            stats.device_id, self.network_id
+                        );
+                        true
+                    }
+                }
+            }
+            None => false,
+        };
+        match change {
+            Some(change) if publish => {
+                live_events::publish(change.into_event(stats, self.gateway_hostname.clone()));
+            }
+            Some(change) => debug!(
+                "Device {} in network {} is connected through another gateway, \
+                not publishing {change:?} reported by {:?}",
+                stats.device_id, self.network_id, self.gateway_hostname
+            ),
+            None => (),
+        }
+        change == Some(ConnectionChange::Connected) && !moved
+    }
+}
+
 /// Helper struct for handling gateway events
 struct GatewayUpdatesHandler {
     network_id: i64,
@@ -173,6 +582,12 @@ struct GatewayUpdatesHandler {
     gateway_hostname: String,
     events_rx: BroadcastReceiver<GatewayEvent>,
     tx: mpsc::Sender<Result<Update, Status>>,
+    pool: DbPool,
+    state: Arc<Mutex<GatewayMap>>,
+    // journal sequence number reported by the gateway, events after it are replayed
+    replay_since: Option<i64>,
+    // full configuration is sent as network configuration followed by batches of peers
+    peer_batches: bool,
 }
 
 impl GatewayUpdatesHandler {
@@ -182,6 +597,10 @@ impl GatewayUpdatesHandler {
         gateway_hostname: String,
         events_rx: BroadcastReceiver<GatewayEvent>,
         tx: mpsc::Sender<Result<Update, Status>>,
+        pool: DbPool,
+        state: Arc<Mutex<GatewayMap>>,
+        replay_since: Option<i64>,
+        peer_batches: bool,
     ) -> Self {
         Self {
             network_id,
@@ -189,7 +608,89 @@ impl GatewayUpdatesHandler {
             gateway_hostname,
             events_rx,
             tx,
+            pool,
+            state,
+            replay_since,
+            peer_batches,
+        }
+    }
+
+    /// Send full network configuration, replacing the peer set on the gateway.
+    /// Used when incremental updates were missed.
+    async fn resync(&mut self) -> Result<(), Status> {
+        let network = match WireguardNetwork::find_by_id(&self.pool, self.network_id).await {
+            Ok(Some(network)) => network,
+            Ok(None) => {
+                let network_name = self.network.name.clone();
+                return self.send_network_delete(&network_name).await;
+            }
+            Err(err) => {
+                error!("Failed to fetch network {} for resync: {err}", self.network);
+                return Err(Status::internal("failed to fetch network"));
+            }
+        };
+        let result = self.send_stored_network_update(&network).await;
+        if let Ok(count) = result {
+            info!(
+                "Resynced gateway {} with {count} peers of network {network}",
+                self.gateway_hostname
+            );
+        }
+        self.network = network;
+        result.map(|_| ())
+    }
+
+    /// Bring the gateway up to date before streaming live events.
+    ///
+    /// Events journaled since the sequence number reported by the gateway are replayed.
+    /// If they can't be, or the gateway didn't report one and the network was changed
+    /// while no gateway was connected, full configuration is sent instead.
+    async fn catch_up(&mut self) -> Result<(), Status> {
+        let started = Utc::now().naive_utc();
+        let pending = sync_pending_since(&self.pool, self.network_id)
+            .await
+            .map_err(|err| {
+                error!(
+                    "Failed to check pending sync of network {}: {err}",
+                    self.network
+                );
+                Status::internal("failed to check pending sync")
+            })?
+            .is_some();
+        let replay = match self.replay_since {
+            Some(since) => events_since(&self.pool, self.network_id, since)
+                .await
+                .map_err(|err| {
+                    error!(
+                        "Failed to read journaled events of network {}: {err}",
+                        self.network
+                    );
+                    Status::internal("failed to read journaled events")
+                })?,
+            None => None,
+        };
+        match replay {
+            Some(events) => {
+                info!(
+                    "Replaying {} events missed by gateway {}, network {}",
+                    events.len(),
+                    self.gateway_hostname,
+                    self.network
+                );
+                for event in events {
+                    self.handle_event(event).await?;
+                }
+            }
+            None if pending || self.replay_since.is_some() => self.resync().await?,
+            None => return Ok(()),
+        }
+        if let Err(err) = clear_sync_pending(&self.pool, self.network_id, started).await {
+            error!(
+                "Failed to clear pending sync of network {}: {err}",
+                self.network
+            );
         }
+        Ok(())
     }
 
     /// Process incoming gateway events
@@ -201,106 +702,53 @@ impl GatewayUpdatesHandler {
             "Starting update stream to gateway: {}, network {}",
             self.gateway_hostname, self.network
         );
-        while let Ok(update) = self.events_rx.recv().await {
-            debug!("Received WireGuard update: {update:?}");
-            let result = match update {
-                GatewayEvent::NetworkCreated(network_id, network) => {
-                    if network_id == self.network_id {
-                        self.send_network_update(&network, Vec::new(), 0).await
-                    } else {
-                        Ok(())
-                    }
-                }
-                GatewayEvent::NetworkModified(network_id, network, peers) => {
-                    if network_id == self.network_id {
-                        let result = self.send_network_update(&network, peers, 1).await;
-                        // update stored network data
-                        self.network = network;
-                        result
-                    } else {
-                        Ok(())
-                    }
-                }
-                GatewayEvent::NetworkDeleted(network_id, network_name) => {
-                    if network_id == self.network_id {
-                        self.send_network_delete(&network_name).await
-                    } else {
-                        Ok(())
-                    }
-                }
-                GatewayEvent::DeviceCreated(device) => {
-                    // check if a peer has to be added in the current network
-                    match device
-                        .network_info
-                        .iter()
-                        .find(|info| info.network_id == self.network_id)
-                    {
-                        Some(network_info) => {
-                            if self.network.mfa_enabled && !network_info.is_authorized {
-                                debug!("Created WireGuard device {} is not authorized to connect to MFA enabled location {}",
-                                device.device.name, self.network.name
-                            );
-                                continue;
-                            };
-                            self.send_peer_update(
-                                Peer {
-                                    pubkey: device.device.wireguard_pubkey,
-                                    allowed_ips: vec![network_info.device_wireguard_ip.to_string()],
-                                    preshared_key: network_info.preshared_key.clone(),
-                                    keepalive_interval: Some(
-                                        self.network.keepalive_interval as u32,
-                                    ),
-                                },
-                                0,
-                            )
-                            .await
-                        }
-                        None => Ok(()),
-                    }
-                }
-                GatewayEvent::DeviceModified(device) => {
-                    // check if a peer has to be updated in the current network
-                    match device
-                        .network_info
-                        .iter()
-                        .find(|info| info.network_id == self.network_id)
-                    {
-                        Some(network_info) => {
-                            if self.network.mfa_enabled && !network_info.is_authorized {
-                                debug!("Modified WireGuard device {} is not authorized to connect to MFA enabled location {}",
-                                device.device.name, self.network.name
-                            );
-                                continue;
-                            };
-                            self.send_peer_update(
-                                Peer {
-                                    pubkey: device.device.wireguard_pubkey,
-                                    allowed_ips: vec![network_info.device_wireguard_ip.to_string()],
-                                    preshared_key: network_info.preshared_key.clone(),
-                                    keepalive_interval: Some(
-                                        self.network.keepalive_interval as u32,
-                                    ),
-                                },
-                                1,
-                            )
-                            .await
-                        }
-                        None => Ok(()),
+        if self.catch_up().await.is_err() {
+            error!(
+                "Closing update steam to gateway: {}, network {}",
+                self.gateway_hostname, self.network
+            );
+            return;
+        }
+        loop {
+            let update = match self.events_rx.recv().await {
+                Ok(update) => update,
+                Err(RecvError::Lagged(skipped)) => {
+                    // incremental updates can't be applied once some were missed,
+                    // so the gateway gets full configuration instead
+                    warn!(
+                        "Gateway {} missed {skipped} updates for network {}, resyncing configuration",
+                        self.gateway_hostname, self.network
+                    );
+                    self.state.lock().unwrap().record_lag(
+                        self.network_id,
+                        &self.gateway_hostname,
+                        skipped,
+                    );
+                    if !feature_flags::is_enabled(
+                        FeatureFlag::GatewayLagResync,
+                        Some(self.network_id),
+                    ) {
+                        // gateway fetches full configuration after reconnecting
+                        info!(
+                            "Closing update stream to gateway {}, network {}, to make it reconnect",
+                            self.gateway_hostname, self.network
+                        );
+                        break;
                     }
-                }
-                GatewayEvent::DeviceDeleted(device) => {
-                    // check if a peer has to be updated in the current network
-                    match device
-                        .network_info
-                        .iter()
-                        .find(|info| info.network_id == self.network_id)
-                    {
-                        Some(_) => self.send_peer_delete(&device.device.wireguard_pubkey).await,
-                        None => Ok(()),
+                    if self.resync().await.is_err() {
+                        // gateway will fetch full configuration after reconnecting
+                        error!(
+                            "Closing update steam to gateway: {}, network {}",
+                            self.gateway_hostname, self.network
+                        );
+                        break;
                     }
+                    continue;
                 }
+                Err(RecvError::Closed) => break,
             };
-            if result.is_err() {
+            debug!("Received WireGuard update: {update:?}");
+            if self.handle_event(update).await.is_err() {
                 error!(
                     "Closing update steam to gateway: {}, network {}",
                     self.gateway_hostname, self.network
@@ -310,12 +758,208 @@ impl GatewayUpdatesHandler {
         }
     }
 
-    /// Sends updated network configuration
+    /// Send event to the gateway, if it applies to its network.
+    async fn handle_event(&mut self, update: GatewayEvent) -> Result<(), Status> {
+        if let GatewayEvent::PeerAdded(peer)
+        | GatewayEvent::PeerModified(peer, _)
+        | GatewayEvent::PeerRemoved(peer) = &update
+        {
+            if peer.network_id() == self.network_id
+                && !feature_flags::is_enabled(FeatureFlag::GatewayPeerDiffs, Some(self.network_id))
+            {
+                debug!(
+                    "Sending full configuration of network {} instead of peer update",
+                    self.network
+                );
+                return self.resync().await;
+            }
+        }
+        match update {
+            GatewayEvent::NetworkCreated(network_id, network) => {
+                if network_id == self.network_id {
+                    self.send_network_update(&network, Vec::new(), 0).await
+                } else {
+                    Ok(())
+                }
+            }
+            GatewayEvent::NetworkModified(network_id, network, peers) => {
+                if network_id == self.network_id {
+                    let result = self.send_network_update(&network, peers, 1).await;
+                    // update stored network data
+                    self.network = network;
+                    result
+                } else {
+                    Ok(())
+                }
+            }
+            GatewayEvent::NetworkDeleted(network_id, network_name) => {
+                if network_id == self.network_id {
+                    self.send_network_delete(&network_name).await
+                } else {
+                    Ok(())
+                }
+            }
+            GatewayEvent::PeerAdded(peer) => {
+                if peer.network_id() == self.network_id {
+                    self.send_peer_create(&peer).await
+                } else {
+                    Ok(())
+                }
+            }
+            GatewayEvent::PeerModified(peer, previous_pubkey) => {
+                if peer.network_id() == self.network_id {
+                    match previous_pubkey {
+                        // peers are identified by public key, so replacing it
+                        // means removing the old peer and adding a new one
+                        Some(previous_pubkey)
+                            if previous_pubkey != peer.device.wireguard_pubkey =>
+                        {
+                            match self.send_peer_delete(&previous_pubkey).await {
+                                Ok(()) => self.send_peer_create(&peer).await,
+                                Err(err) => Err(err),
+                            }
+                        }
+                        _ => match self.peer_config(&peer).await {
+                            Ok(Some(peer_config)) => self.send_peer_update(peer_config, 1).await,
+                            Ok(None) => Ok(()),
+                            Err(err) => Err(err),
+                        },
+                    }
+                } else {
+                    Ok(())
+                }
+            }
+            GatewayEvent::PeerRemoved(peer) => {
+                if peer.network_id() == self.network_id {
+                    self.send_peer_delete(&peer.device.wireguard_pubkey).await
+                } else {
+                    Ok(())
+                }
+            }
+            GatewayEvent::FullResync(network_id, network, peers) => {
+                if network_id == self.network_id {
+                    info!(
+                        "Resyncing gateway {} with {} peers of network {network} on admin request",
+                        self.gateway_hostname,
+                        peers.len()
+                    );
+                    let result = self.send_network_update(&network, peers, 1).await;
+                    self.network = network;
+                    result
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Build gateway peer configuration. Returns `None` if the device is blocked or not
+    /// authorized to connect to an MFA enabled network.
+    async fn peer_config(&self, peer: &PeerUpdate) -> Result<Option<Peer>, Status> {
+        if peer.device.blocked {
+            debug!(
+                "WireGuard device {} is blocked, not configuring it in location {}",
+                peer.device.name, self.network.name
+            );
+            return Ok(None);
+        }
+        if self.network.mfa_enabled && !peer.network_info.is_authorized {
+            debug!(
+                "WireGuard device {} is not authorized to connect to MFA enabled location {}",
+                peer.device.name, self.network.name
+            );
+            return Ok(None);
+        }
+        // device limit overrides aren't part of peer updates, so they're read here
+        let network_device = match peer.device.id {
+            Some(device_id) => WireguardNetworkDevice::find(&self.pool, device_id, self.network_id)
+                .await
+                .map_err(|err| {
+                    Status::internal(format!(
+                        "Failed to fetch device {device_id} in network {}: {err}",
+                        self.network
+                    ))
+                })?,
+            None => None,
+        };
+        let (upload_limit, download_limit) = network_device.map_or((None, None), |device| {
+            (device.upload_limit_kbps, device.download_limit_kbps)
+        });
+        Ok(Some(Peer {
+            pubkey: peer.device.wireguard_pubkey.clone(),
+            allowed_ips: vec![peer.network_info.device_wireguard_ip.to_string()],
+            preshared_key: peer.network_info.preshared_key.clone(),
+            keepalive_interval: Some(self.network.keepalive_interval as u32),
+            upload_limit_kbps: bandwidth_limit(upload_limit, self.network.upload_limit_kbps),
+            download_limit_kbps: bandwidth_limit(download_limit, self.network.download_limit_kbps),
+        }))
+    }
+
+    /// Send create peer command to gateway, if the peer is allowed to connect
+    async fn send_peer_create(&self, peer: &PeerUpdate) -> Result<(), Status> {
+        match self.peer_config(peer).await? {
+            Some(peer_config) => self.send_peer_update(peer_config, 0).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Sends updated network configuration followed by given peers in batches, or with them
+    /// if the gateway doesn't accept batches.
     async fn send_network_update(
         &self,
         network: &WireguardNetwork,
         peers: Vec<Peer>,
         update_type: i32,
+    ) -> Result<(), Status> {
+        if !self.peer_batches {
+            return self.send_network_config(network, peers, update_type).await;
+        }
+        self.send_network_config(network, Vec::new(), update_type)
+            .await?;
+        for batch in peer_batches(peers, server_config().gateway_peer_batch_size) {
+            self.send_peer_batch(batch, update_type).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends full network configuration with peers read from the database in batches,
+    /// replacing the peer set on the gateway. Returns the number of peers sent.
+    async fn send_stored_network_update(
+        &self,
+        network: &WireguardNetwork,
+    ) -> Result<usize, Status> {
+        if !self.peer_batches {
+            let peers = network.get_peers(&self.pool).await.map_err(|err| {
+                error!("Failed to fetch peers of network {network}: {err}");
+                Status::internal("failed to fetch peers")
+            })?;
+            let count = peers.len();
+            self.send_network_config(network, peers, 1).await?;
+            return Ok(count);
+        }
+        self.send_network_config(network, Vec::new(), 1).await?;
+        let mut batches = PeerBatches::new(network, server_config().gateway_peer_batch_size);
+        let mut count = 0;
+        loop {
+            let batch = batches.next(&self.pool).await.map_err(|err| {
+                error!("Failed to fetch peers of network {network}: {err}");
+                Status::internal("failed to fetch peers")
+            })?;
+            let last = batch.last;
+            count += batch.peers.len();
+            self.send_peer_batch(batch, 1).await?;
+            if last {
+                return Ok(count);
+            }
+        }
+    }
+
+    /// Sends network configuration with given peers, none if they follow in batches.
+    async fn send_network_config(
+        &self,
+        network: &WireguardNetwork,
+        peers: Vec<Peer>,
+        update_type: i32,
     ) -> Result<(), Status> {
         debug!("Sending network update for network {network}");
         if let Err(err) = self
@@ -323,11 +967,8 @@ impl GatewayUpdatesHandler {
             .send(Ok(Update {
                 update_type,
                 update: Some(update::Update::Network(Configuration {
-                    name: network.name.clone(),
-                    prvkey: network.prvkey.clone(),
-                    address: network.address.to_string(),
-                    port: network.port as u32,
                     peers,
+                    ..gen_config(network)
                 })),
             }))
             .await
@@ -363,6 +1004,8 @@ impl GatewayUpdatesHandler {
                     address: String::new(),
                     port: 0,
                     peers: Vec::new(),
+                    masquerade_enabled: false,
+                    nat_exempt_networks: Vec::new(),
                 })),
             }))
             .await
@@ -405,6 +1048,31 @@ impl GatewayUpdatesHandler {
         Ok(())
     }
 
+    /// Send batch of peers following network configuration
+    async fn send_peer_batch(&self, batch: PeerBatch, update_type: i32) -> Result<(), Status> {
+        debug!(
+            "Sending batch of {} peers for network {}",
+            batch.peers.len(),
+            self.network
+        );
+        if let Err(err) = self
+            .tx
+            .send(Ok(Update {
+                update_type,
+                update: Some(update::Update::Peers(batch)),
+            }))
+            .await
+        {
+            let msg = format!(
+                "Failed to send batch of peers for network {}, update type: {update_type}, error: {err}",
+                self.network,
+            );
+            error!(msg);
+            return Err(Status::new(Code::Internal, msg));
+        }
+        Ok(())
+    }
+
     /// Send delete peer command to gateway
     async fn send_peer_delete(&self, peer_pubkey: &str) -> Result<(), Status> {
         debug!("Sending peer delete for network {}", self.network);
@@ -417,6 +1085,8 @@ impl GatewayUpdatesHandler {
                     allowed_ips: Vec::new(),
                     preshared_key: None,
                     keepalive_interval: None,
+                    upload_limit_kbps: None,
+                    download_limit_kbps: None,
                 })),
             }))
             .await
@@ -487,6 +1157,7 @@ impl Drop for GatewayUpdatesStream {
 
 #[tonic::async_trait]
 impl gateway_service_server::GatewayService for GatewayServer {
+    type ConfigStreamStream = ReceiverStream<Result<ConfigurationChunk, Status>>;
     type UpdatesStream = GatewayUpdatesStream;
 
     /// Retrieve stats from gateway and save it to database
@@ -495,7 +1166,37 @@ impl gateway_service_server::GatewayService for GatewayServer {
         request: Request<tonic::Streaming<StatsUpdate>>,
     ) -> Result<Response<()>, Status> {
         let network_id = Self::get_network_id(request.metadata())?;
+        match WireguardNetwork::find_by_id(&self.pool, network_id).await {
+            Ok(Some(network)) => {
+                Self::ensure_source_allowed(&network, request.metadata())?;
+                Self::ensure_not_archived(&network)?;
+            }
+            Ok(None) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("Network with id {network_id} not found"),
+                ))
+            }
+            Err(err) => {
+                error!("Failed to fetch network {network_id} from the database: {err}");
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("Failed to retrieve network {network_id} from the database"),
+                ));
+            }
+        }
+        // older gateways don't send their hostname, so their clients aren't attributed
+        let gateway_hostname = Self::get_gateway_hostname(request.metadata()).ok();
         let mut stream = request.into_inner();
+        let config = server_config();
+        let batcher = PeerStatsBatcher::spawn(
+            self.pool.clone(),
+            config.stats_batch_size,
+            *config.stats_flush_interval,
+        );
+        // device IDs by public key, to avoid querying the database for each update
+        let mut device_ids = HashMap::new();
+        let mut clients = GatewayClients::new(network_id, gateway_hostname);
         while let Some(stats_update) = stream.message().await? {
             debug!("Received stats message: {stats_update:?}");
             let Some(stats_update::Payload::PeerStats(peer_stats)) = stats_update.payload else {
@@ -505,84 +1206,53 @@ impl gateway_service_server::GatewayService for GatewayServer {
             let public_key = peer_stats.public_key.clone();
             let mut stats = WireguardPeerStats::from_peer_stats(peer_stats, network_id);
             // Get device by public key and fill in stats.device_id
-            // FIXME: keep an in-memory device map to avoid repeated DB requests
-            stats.device_id = match Device::find_by_pubkey(&self.pool, &public_key).await {
-                Ok(Some(device)) => device.id.ok_or_else(|| {
-                    Status::new(
-                        Code::Internal,
-                        format!(
-                            "Device {} (public key: {public_key}) has no ID",
-                            device.name
-                        ),
-                    )
-                })?,
-                Ok(None) => {
-                    error!("Device with public key {public_key} not found");
-                    return Err(Status::new(
-                        Code::Internal,
-                        format!("Device with public key {public_key} not found"),
-                    ));
-                }
-                Err(err) => {
-                    error!("Failed to retrieve device with public key {public_key}: {err}",);
-                    return Err(Status::new(
-                        Code::Internal,
-                        format!("Failed to retrieve device with public key {public_key}: {err}",),
-                    ));
+            stats.device_id = match device_ids.get(&public_key) {
+                Some(device_id) => *device_id,
+                None => {
+                    let device_id = self.find_device_id(&public_key).await?;
+                    device_ids.insert(public_key, device_id);
+                    device_id
                 }
             };
-            // Save stats to db
-            if let Err(err) = stats.save(&self.pool).await {
-                error!("Saving WireGuard peer stats to db failed: {err}");
-                return Err(Status::new(
-                    Code::Internal,
-                    format!("Saving WireGuard peer stats to db failed: {err}"),
-                ));
+            if clients.update(&self.pool, &stats).await {
+                if let (Some(geoip), Some(endpoint)) = (geoip_database(), stats.endpoint.clone()) {
+                    let (pool, mail_tx) = (self.pool.clone(), self.mail_tx.clone());
+                    let device_id = stats.device_id;
+                    tokio::spawn(async move {
+                        if let Err(err) = check_connection_country(
+                            &pool,
+                            &mail_tx,
+                            Some(geoip),
+                            device_id,
+                            network_id,
+                            &endpoint,
+                        )
+                        .await
+                        {
+                            error!(
+                                "Failed to check country of device {device_id} connection: {err}"
+                            );
+                        }
+                    });
+                }
             }
-            info!("Saved WireGuard peer stats to db: {stats:?}");
+            // Buffered stats are saved to db in batches
+            batcher.push(stats);
         }
+        batcher.close().await;
         Ok(Response::new(()))
     }
 
+    /// Send full configuration with all peers in one message to a connecting gateway.
+    /// Kept for gateways which don't use `ConfigStream` yet.
     async fn config(
         &self,
         request: Request<ConfigurationRequest>,
     ) -> Result<Response<Configuration>, Status> {
         debug!("Sending configuration to gateway client.");
         let network_id = Self::get_network_id(request.metadata())?;
-        let hostname = Self::get_gateway_hostname(request.metadata())?;
-
-        let mut network = WireguardNetwork::find_by_id(&self.pool, network_id)
-            .await
-            .map_err(|e| {
-                error!("Network {network_id} not found");
-                Status::new(Code::Internal, format!("Failed to retrieve network: {e}"))
-            })?
-            .ok_or_else(|| {
-                Status::new(
-                    Code::Internal,
-                    format!("Network with id {} not found", network_id),
-                )
-            })?;
-
-        debug!("Sending configuration to gateway client, network {network}.");
-
-        // store connected gateway in memory
-        {
-            let mut state = self.state.lock().unwrap();
-            state.add_gateway(
-                network_id,
-                &network.name,
-                hostname,
-                request.into_inner().name,
-                self.mail_tx.clone(),
-            );
-        }
-
-        network.connected_at = Some(Utc::now().naive_utc());
-        if let Err(err) = network.save(&self.pool).await {
-            error!("Failed to save updated network {network_id} in the database, status: {err}");
-        }
+        let started = Utc::now().naive_utc();
+        let network = self.connect_config_client(network_id, request).await?;
 
         let peers = network.get_peers(&self.pool).await.map_err(|error| {
             error!("Failed to fetch peers from the database for network {network_id}: {error}",);
@@ -592,14 +1262,58 @@ impl gateway_service_server::GatewayService for GatewayServer {
             )
         })?;
 
+        // full configuration covers changes made while no gateway was connected
+        if let Err(err) = clear_sync_pending(&self.pool, network_id, started).await {
+            error!("Failed to clear pending sync of network {network}: {err}");
+        }
+
         info!("Configuration sent to gateway client, network {network}.");
 
-        Ok(Response::new(gen_config(&network, peers)))
+        Ok(Response::new(Configuration {
+            peers,
+            ..gen_config(&network)
+        }))
+    }
+
+    /// Stream full configuration to a connecting gateway: network configuration first,
+    /// then peers in batches.
+    async fn config_stream(
+        &self,
+        request: Request<ConfigurationRequest>,
+    ) -> Result<Response<Self::ConfigStreamStream>, Status> {
+        debug!("Streaming configuration to gateway client.");
+        let network_id = Self::get_network_id(request.metadata())?;
+        let started = Utc::now().naive_utc();
+        let network = self.connect_config_client(network_id, request).await?;
+
+        let (tx, rx) = mpsc::channel(4);
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            match stream_config(&pool, &network, &tx).await {
+                Ok(count) => {
+                    // full configuration covers changes made while no gateway was connected
+                    if let Err(err) = clear_sync_pending(&pool, network_id, started).await {
+                        error!("Failed to clear pending sync of network {network}: {err}");
+                    }
+                    info!(
+                        "Configuration with {count} peers sent to gateway client, network {network}."
+                    );
+                }
+                Err(status) => {
+                    error!(
+                        "Failed to send configuration to gateway client, network {network}: {}",
+                        status.message()
+                    );
+                    let _ = tx.send(Err(status)).await;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
     async fn updates(&self, request: Request<()>) -> Result<Response<Self::UpdatesStream>, Status> {
         let gateway_network_id = Self::get_network_id(request.metadata())?;
-        let hostname = Self::get_gateway_hostname(request.metadata())?;
 
         let Some(network) = WireguardNetwork::find_by_id(&self.pool, gateway_network_id)
             .await
@@ -616,6 +1330,19 @@ impl gateway_service_server::GatewayService for GatewayServer {
                 format!("Network with id {gateway_network_id} not found"),
             ));
         };
+        Self::ensure_source_allowed(&network, request.metadata())?;
+        Self::ensure_not_archived(&network)?;
+        let hostname = Self::get_gateway_hostname(request.metadata())?;
+        let replay_since = Self::get_event_seq(request.metadata());
+        let peer_batches = Self::accepts_peer_batches(request.metadata());
+        // gateways report it when reconnecting, to get events they missed in the meantime
+        let head = match journal_head(&self.pool).await {
+            Ok(head) => Some(head),
+            Err(err) => {
+                error!("Failed to read gateway event journal: {err}");
+                None
+            }
+        };
 
         info!("New client connected to updates stream: {hostname}, network {network}",);
 
@@ -634,6 +1361,8 @@ impl gateway_service_server::GatewayService for GatewayServer {
 
         // clone here before moving into a closure
         let gateway_hostname = hostname.clone();
+        let pool = self.pool.clone();
+        let gateway_state = Arc::clone(&self.state);
         let handle = tokio::spawn(async move {
             let mut update_handler = GatewayUpdatesHandler::new(
                 gateway_network_id,
@@ -641,17 +1370,662 @@ impl gateway_service_server::GatewayService for GatewayServer {
                 gateway_hostname,
                 events_rx,
                 tx,
+                pool,
+                gateway_state,
+                replay_since,
+                peer_batches,
             );
             update_handler.run().await;
         });
 
-        Ok(Response::new(GatewayUpdatesStream::new(
+        let mut response = Response::new(GatewayUpdatesStream::new(
             handle,
             rx,
             gateway_network_id,
             hostname,
             Arc::clone(&self.state),
             self.pool.clone(),
-        )))
+        ));
+        if let Some(head) = head {
+            response
+                .metadata_mut()
+                .insert(GATEWAY_EVENT_SEQ_KEY, MetadataValue::from(head));
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use prost::Message;
+    use tokio::{sync::broadcast, time::sleep};
+
+    use super::*;
+    use crate::{
+        config::{DefGuardConfig, MAX_ENCODED_PEER_SIZE},
+        db::{models::device::DeviceNetworkInfo, User},
+        gateway_event_journal::run_gateway_event_journal,
+        live_events::LiveEvent,
+        SERVER_CONFIG,
+    };
+
+    // full configuration is a network update followed by batches of peers
+    async fn recv_full_config(
+        rx: &mut Receiver<Result<Update, Status>>,
+    ) -> (Configuration, Vec<Peer>) {
+        let update = rx.recv().await.unwrap().unwrap();
+        assert_eq!(update.update_type, 1);
+        let Some(update::Update::Network(config)) = update.update else {
+            panic!("expected network update");
+        };
+        assert!(config.peers.is_empty());
+        let mut peers = Vec::new();
+        loop {
+            let update = rx.recv().await.unwrap().unwrap();
+            assert_eq!(update.update_type, 1);
+            let Some(update::Update::Peers(batch)) = update.update else {
+                panic!("expected batch of peers");
+            };
+            peers.extend(batch.peers);
+            if batch.last {
+                return (config, peers);
+            }
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_lagged_updates_resync(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.save(&pool).await.unwrap();
+        let network_id = network.id.unwrap();
+        let mut user = User::new(
+            "testuser",
+            Some("hunter2"),
+            "Tester",
+            "Test",
+            "test@test.com",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        for i in 0..2 {
+            Device::new_with_ip(
+                &pool,
+                user.id.unwrap(),
+                format!("dev{i}"),
+                format!("key{i}"),
+                &network,
+            )
+            .await
+            .unwrap();
+        }
+
+        let (mail_tx, _mail_rx) = mpsc::unbounded_channel();
+        let state = Arc::new(Mutex::new(GatewayMap::new()));
+        state
+            .lock()
+            .unwrap()
+            .add_gateway(network_id, &network.name, "gw".into(), None, mail_tx);
+
+        // consumer is paused while more events than channel capacity are published
+        let (events_tx, events_rx) = broadcast::channel(2);
+        for i in 0..5 {
+            events_tx
+                .send(GatewayEvent::NetworkDeleted(
+                    network_id + 1,
+                    format!("other{i}"),
+                ))
+                .unwrap();
+        }
+        drop(events_tx);
+
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut handler = GatewayUpdatesHandler::new(
+            network_id,
+            network.clone(),
+            "gw".into(),
+            events_rx,
+            tx,
+            pool,
+            Arc::clone(&state),
+            None,
+            true,
+        );
+        handler.run().await;
+        drop(handler);
+
+        // missed events are replaced with full configuration
+        let (config, peers) = recv_full_config(&mut rx).await;
+        assert_eq!(config.name, network.name);
+        assert_eq!(peers.len(), 2);
+        // remaining events were for other networks
+        assert!(rx.recv().await.is_none());
+
+        let gateways = state.lock().unwrap().get_network_gateway_status(network_id);
+        assert_eq!(gateways[0].lag_incidents, 1);
+        assert_eq!(gateways[0].missed_events, 3);
+        assert!(gateways[0].last_lag_at.is_some());
+    }
+
+    #[sqlx::test]
+    async fn test_resync_without_peer_batches(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.save(&pool).await.unwrap();
+        let mut user = User::new(
+            "testuser",
+            Some("hunter2"),
+            "Tester",
+            "Test",
+            "test@test.com",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        for i in 0..2 {
+            Device::new_with_ip(
+                &pool,
+                user.id.unwrap(),
+                format!("dev{i}"),
+                format!("key{i}"),
+                &network,
+            )
+            .await
+            .unwrap();
+        }
+
+        // gateways which didn't opt in to batches get peers in the network update
+        let (_events_tx, events_rx) = broadcast::channel(1);
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut handler = GatewayUpdatesHandler::new(
+            network.id.unwrap(),
+            network.clone(),
+            "gw".into(),
+            events_rx,
+            tx,
+            pool,
+            Arc::new(Mutex::new(GatewayMap::new())),
+            None,
+            false,
+        );
+        handler.resync().await.unwrap();
+        handler
+            .send_network_update(&network, Vec::new(), 1)
+            .await
+            .unwrap();
+        drop(handler);
+
+        let update = rx.recv().await.unwrap().unwrap();
+        assert_eq!(update.update_type, 1);
+        let Some(update::Update::Network(config)) = update.update else {
+            panic!("expected network update");
+        };
+        assert_eq!(config.name, network.name);
+        assert_eq!(config.peers.len(), 2);
+        let update = rx.recv().await.unwrap().unwrap();
+        let Some(update::Update::Network(config)) = update.update else {
+            panic!("expected network update");
+        };
+        assert!(config.peers.is_empty());
+        assert!(rx.recv().await.is_none());
+    }
+
+    // journal is written by a background task
+    async fn wait_for_journal(pool: &DbPool, seq: i64) {
+        for _ in 0..50 {
+            if journal_head(pool).await.unwrap() >= seq {
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        panic!("event {seq} was not journaled");
+    }
+
+    #[sqlx::test]
+    async fn test_catch_up_missed_events(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.save(&pool).await.unwrap();
+        let network_id = network.id.unwrap();
+        let mut user = User::new(
+            "testuser",
+            Some("hunter2"),
+            "Tester",
+            "Test",
+            "test@test.com",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+
+        let (mail_tx, _mail_rx) = mpsc::unbounded_channel();
+        let state = Arc::new(Mutex::new(GatewayMap::new()));
+        state
+            .lock()
+            .unwrap()
+            .add_gateway(network_id, &network.name, "gw".into(), None, mail_tx);
+        let (events_tx, events_rx) = broadcast::channel(16);
+        tokio::spawn(run_gateway_event_journal(
+            pool.clone(),
+            events_rx,
+            Arc::clone(&state),
+        ));
+
+        // device is added while no gateway is connected
+        let (device, network_device) = Device::new_with_ip(
+            &pool,
+            user.id.unwrap(),
+            "dev".into(),
+            "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=".into(),
+            &network,
+        )
+        .await
+        .unwrap();
+        let peer = PeerUpdate {
+            device,
+            network_info: DeviceNetworkInfo {
+                network_id,
+                device_wireguard_ip: network_device.wireguard_ip,
+                preshared_key: None,
+                is_authorized: false,
+            },
+        };
+        events_tx
+            .send(GatewayEvent::PeerAdded(peer.clone()))
+            .unwrap();
+        wait_for_journal(&pool, 1).await;
+        for _ in 0..50 {
+            if sync_pending_since(&pool, network_id)
+                .await
+                .unwrap()
+                .is_some()
+            {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert!(sync_pending_since(&pool, network_id)
+            .await
+            .unwrap()
+            .is_some());
+
+        // connecting gateway gets full configuration including the device
+        let (_live_tx, live_rx) = broadcast::channel(1);
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut handler = GatewayUpdatesHandler::new(
+            network_id,
+            network.clone(),
+            "gw".into(),
+            live_rx,
+            tx,
+            pool.clone(),
+            Arc::clone(&state),
+            None,
+            true,
+        );
+        handler.catch_up().await.unwrap();
+        let (_, peers) = recv_full_config(&mut rx).await;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].pubkey, peer.device.wireguard_pubkey);
+        assert!(sync_pending_since(&pool, network_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        // gateway disconnected briefly gets only events it missed
+        state
+            .lock()
+            .unwrap()
+            .connect_gateway(network_id, "gw")
+            .unwrap();
+        let since = journal_head(&pool).await.unwrap();
+        events_tx
+            .send(GatewayEvent::PeerRemoved(peer.clone()))
+            .unwrap();
+        wait_for_journal(&pool, since + 1).await;
+        let (_live_tx, live_rx) = broadcast::channel(1);
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut handler = GatewayUpdatesHandler::new(
+            network_id,
+            network.clone(),
+            "gw".into(),
+            live_rx,
+            tx,
+            pool.clone(),
+            Arc::clone(&state),
+            Some(since),
+            true,
+        );
+        handler.catch_up().await.unwrap();
+        drop(handler);
+        let update = rx.recv().await.unwrap().unwrap();
+        assert_eq!(update.update_type, 2);
+        let Some(update::Update::Peer(removed)) = update.update else {
+            panic!("expected peer update");
+        };
+        assert_eq!(removed.pubkey, peer.device.wireguard_pubkey);
+        assert!(rx.recv().await.is_none());
+        assert!(sync_pending_since(&pool, network_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        // sequence numbers unknown to the journal can't be replayed
+        let (_live_tx, live_rx) = broadcast::channel(1);
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut handler = GatewayUpdatesHandler::new(
+            network_id,
+            network,
+            "gw".into(),
+            live_rx,
+            tx,
+            pool,
+            state,
+            Some(since + 100),
+            true,
+        );
+        handler.catch_up().await.unwrap();
+        recv_full_config(&mut rx).await;
+    }
+
+    async fn attributed_gateway(pool: &DbPool, device_id: i64, network_id: i64) -> Option<String> {
+        WireguardNetworkDevice::find(pool, device_id, network_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .gateway_hostname
+    }
+
+    #[sqlx::test]
+    async fn test_client_gateway_attribution(pool: DbPool) {
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.save(&pool).await.unwrap();
+        let network_id = network.id.unwrap();
+        let mut user = User::new(
+            "testuser",
+            Some("hunter2"),
+            "Tester",
+            "Test",
+            "test@test.com",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let (device, _) = Device::new_with_ip(
+            &pool,
+            user.id.unwrap(),
+            "dev".into(),
+            "key".into(),
+            &network,
+        )
+        .await
+        .unwrap();
+        let device_id = device.id.unwrap();
+        let stats = |handshake_seconds_ago| WireguardPeerStats {
+            id: None,
+            device_id,
+            collected_at: Utc::now().naive_utc(),
+            network: network_id,
+            endpoint: Some("1.2.3.4:5678".into()),
+            upload: 0,
+            download: 0,
+            latest_handshake: (Utc::now() - ChronoDuration::seconds(handshake_seconds_ago))
+                .naive_utc(),
+            allowed_ips: Some("10.1.1.2/32".into()),
+        };
+        // events are published on a process-wide channel, so only this device is checked
+        let mut events = live_events::subscribe();
+        let mut client_events = move || {
+            let mut found = Vec::new();
+            while let Ok(event) = events.try_recv() {
+                match event {
+                    LiveEvent::ClientConnected {
+                        device_id: id,
+                        network_id: network,
+                        ..
+                    }
+                    | LiveEvent::ClientDisconnected {
+                        device_id: id,
+                        network_id: network,
+                    } if id == device_id && network == network_id => found.push(event),
+                    _ => (),
+                }
+            }
+            found
+        };
+
+        let mut first = GatewayClients::new(network_id, Some("gw1".into()));
+        let mut second = GatewayClients::new(network_id, Some("gw2".into()));
+        assert!(first.update(&pool, &stats(60)).await);
+        assert_eq!(
+            attributed_gateway(&pool, device_id, network_id)
+                .await
+                .as_deref(),
+            Some("gw1")
+        );
+        let connected = client_events();
+        assert_eq!(connected.len(), 1);
+        let LiveEvent::ClientConnected { gateway: name, .. } = &connected[0] else {
+            panic!("expected client connected event");
+        };
+        assert_eq!(name.as_deref(), Some("gw1"));
+
+        // client moved to another gateway, which isn't a new connection
+        assert!(!second.update(&pool, &stats(10)).await);
+        assert_eq!(
+            attributed_gateway(&pool, device_id, network_id)
+                .await
+                .as_deref(),
+            Some("gw2")
+        );
+        // stale handshake reported by the previous gateway doesn't take it back
+        assert!(!first.update(&pool, &stats(60)).await);
+        assert_eq!(
+            attributed_gateway(&pool, device_id, network_id)
+                .await
+                .as_deref(),
+            Some("gw2")
+        );
+        assert!(client_events().is_empty());
+
+        // handshake expiring on the previous gateway isn't a disconnect
+        assert!(!first.update(&pool, &stats(3600)).await);
+        assert!(client_events().is_empty());
+        assert!(!second.update(&pool, &stats(3600)).await);
+        assert_eq!(
+            client_events(),
+            [LiveEvent::ClientDisconnected {
+                device_id,
+                network_id
+            }]
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_client_routes_not_on_gateway(pool: DbPool) {
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.save(&pool).await.unwrap();
+        let mut user = User::new(
+            "testuser",
+            Some("hunter2"),
+            "Tester",
+            "Test",
+            "test@test.com",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        Device::new_with_ip(
+            &pool,
+            user.id.unwrap(),
+            "dev".into(),
+            "key".into(),
+            &network,
+        )
+        .await
+        .unwrap();
+
+        let config = gen_config(&network).encode_to_vec();
+
+        network.client_routes = vec!["172.16.5.0/24".parse().unwrap()];
+        network.save(&pool).await.unwrap();
+        let peers = network.get_peers(&pool).await.unwrap();
+        assert_eq!(peers[0].allowed_ips, ["10.1.1.2"]);
+        assert_eq!(gen_config(&network).encode_to_vec(), config);
+    }
+
+    #[test]
+    fn test_nat_config() {
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.1.1/24").unwrap();
+        let previous = network.clone();
+        network.masquerade_enabled = true;
+        network.nat_exempt_networks = vec![
+            "10.20.0.0/16".parse().unwrap(),
+            "fd00:20::/64".parse().unwrap(),
+        ];
+        // NAT changes are pushed to gateways with full configuration
+        assert!(network.requires_gateway_resync(&previous));
+
+        let encoded = gen_config(&network).encode_to_vec();
+        let config = Configuration::decode(encoded.as_slice()).unwrap();
+        assert!(config.masquerade_enabled);
+        assert_eq!(config.nat_exempt_networks, ["10.20.0.0/16", "fd00:20::/64"]);
+        let config =
+            Configuration::decode(gen_config(&previous).encode_to_vec().as_slice()).unwrap();
+        assert!(!config.masquerade_enabled);
+        assert!(config.nat_exempt_networks.is_empty());
+
+        let mut changed = network.clone();
+        changed.nat_exempt_networks.pop();
+        assert!(changed.requires_gateway_resync(&network));
+    }
+
+    // stream configuration the way connecting gateways receive it
+    async fn streamed_config(
+        pool: &DbPool,
+        network: &WireguardNetwork,
+    ) -> Vec<configuration_chunk::Payload> {
+        let (tx, mut rx) = mpsc::channel(4);
+        let (pool, network) = (pool.clone(), network.clone());
+        let task = tokio::spawn(async move { stream_config(&pool, &network, &tx).await });
+
+        // generation waits for the gateway instead of building the whole peer set
+        sleep(Duration::from_millis(200)).await;
+        assert!(!task.is_finished());
+
+        let mut chunks = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            chunks.push(chunk.unwrap().payload.unwrap());
+        }
+        task.await.unwrap().unwrap();
+        chunks
+    }
+
+    #[sqlx::test]
+    async fn test_config_streamed_in_batches(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let batch_size = server_config().gateway_peer_batch_size;
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.0.1/16").unwrap();
+        network.save(&pool).await.unwrap();
+        let mut user = User::new(
+            "testuser",
+            Some("hunter2"),
+            "Tester",
+            "Test",
+            "test@test.com",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+
+        // synthetic peers of a large location
+        let peer_count: i64 = 3000;
+        query!(
+            "INSERT INTO device (name, wireguard_pubkey, user_id, created) \
+            SELECT 'dev' || i, 'key' || i, $1, now() FROM generate_series(1, $2::bigint) i",
+            user.id,
+            peer_count
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        query!(
+            "INSERT INTO wireguard_network_device (device_id, wireguard_network_id, wireguard_ip) \
+            SELECT id, $1, '10.1.0.1'::inet + id FROM device WHERE user_id = $2",
+            network.id,
+            user.id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let pubkeys: Vec<String> = query!("SELECT wireguard_pubkey FROM device ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| row.wireguard_pubkey)
+            .collect();
+        assert_eq!(pubkeys.len(), peer_count as usize);
+
+        let chunks = streamed_config(&pool, &network).await;
+        let Some((configuration_chunk::Payload::Configuration(config), batches)) =
+            chunks.split_first()
+        else {
+            panic!("expected network configuration first");
+        };
+        assert_eq!(config.name, network.name);
+        assert!(config.peers.is_empty());
+
+        // bounded batches, only the last one marked
+        assert_eq!(batches.len(), pubkeys.len().div_ceil(batch_size));
+        let mut streamed = Vec::new();
+        for (index, batch) in batches.iter().enumerate() {
+            let configuration_chunk::Payload::Peers(batch) = batch else {
+                panic!("expected batch of peers");
+            };
+            assert!(batch.peers.len() <= batch_size);
+            assert_eq!(batch.last, index == batches.len() - 1);
+            for peer in &batch.peers {
+                assert!(peer.encoded_len() <= MAX_ENCODED_PEER_SIZE);
+            }
+            assert!(batch.encoded_len() <= server_config().grpc_max_message_size);
+            streamed.extend(batch.peers.iter().map(|peer| peer.pubkey.clone()));
+        }
+
+        // every peer exactly once, in the same order each time
+        assert_eq!(streamed, pubkeys);
+        assert_eq!(streamed_config(&pool, &network).await, chunks);
+    }
+
+    #[test]
+    fn test_peer_batches() {
+        let peer = |index| Peer {
+            pubkey: format!("key{index}"),
+            allowed_ips: Vec::new(),
+            preshared_key: None,
+            keepalive_interval: None,
+            upload_limit_kbps: None,
+            download_limit_kbps: None,
+        };
+        let batches = peer_batches((0..5).map(peer).collect(), 2);
+        assert_eq!(
+            batches
+                .iter()
+                .map(|batch| (batch.peers.len(), batch.last))
+                .collect::<Vec<_>>(),
+            [(2, false), (2, false), (1, true)]
+        );
+        let batches = peer_batches((0..4).map(peer).collect(), 2);
+        assert_eq!(batches.len(), 2);
+        assert!(batches[1].last);
+
+        // empty peer set is sent as well
+        let batches = peer_batches(Vec::new(), 2);
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].peers.is_empty() && batches[0].last);
     }
 }
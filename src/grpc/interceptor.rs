@@ -1,6 +1,11 @@
-use crate::auth::{Claims, ClaimsType};
 use tonic::{service::Interceptor, Status};
 
+use crate::{
+    auth::{Claims, ClaimsType},
+    headers::resolve_source_ip,
+    server_config,
+};
+
 /// Auth interceptor used by GRPC services. Verifies JWT token sent
 /// in GRPC metadata under "authorization" key.
 #[derive(Clone)]
@@ -24,6 +29,13 @@ impl Interceptor for JwtInterceptor {
             None => return Err(Status::unauthenticated("Missing authorization header")),
         };
         if let Ok(claims) = Claims::from_jwt(self.claims_type, token) {
+            let source_ip = resolve_source_ip(
+                req.remote_addr().map(|address| address.ip()),
+                req.metadata()
+                    .get("x-forwarded-for")
+                    .and_then(|value| value.to_str().ok()),
+                &server_config().grpc_trusted_proxies,
+            );
             let request_metadata = req.metadata_mut();
 
             if let ClaimsType::Gateway = self.claims_type {
@@ -34,6 +46,18 @@ impl Interceptor for JwtInterceptor {
                         .parse()
                         .map_err(|_| Status::unknown("Network ID parsing error"))?,
                 );
+                // checked against network's gateway allowlist by the gateway service;
+                // never trust a value sent by the client
+                request_metadata.remove("gateway_source_ip");
+                if let Some(source_ip) = source_ip {
+                    request_metadata.insert(
+                        "gateway_source_ip",
+                        source_ip
+                            .to_string()
+                            .parse()
+                            .map_err(|_| Status::unknown("Source address parsing error"))?,
+                    );
+                }
             }
 
             // FIXME: can we push whole Claims object into metadata?
@@ -51,3 +75,38 @@ impl Interceptor for JwtInterceptor {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tonic::Request;
+
+    use super::*;
+    use crate::{config::DefGuardConfig, SERVER_CONFIG};
+
+    #[test]
+    fn test_gateway_source_ip_not_taken_from_client() {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let token = Claims::new(
+            ClaimsType::Gateway,
+            "DEFGUARD-NETWORK-1".into(),
+            "1".into(),
+            u32::MAX.into(),
+        )
+        .to_jwt()
+        .unwrap();
+
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("authorization", token.parse().unwrap());
+        request
+            .metadata_mut()
+            .insert("gateway_source_ip", "192.168.4.14".parse().unwrap());
+        let request = JwtInterceptor::new(ClaimsType::Gateway)
+            .call(request)
+            .unwrap();
+        assert_eq!(request.metadata().get("gateway_network_id").unwrap(), "1");
+        // request has no remote address, so source is unknown
+        assert!(request.metadata().get("gateway_source_ip").is_none());
+    }
+}
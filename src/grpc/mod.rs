@@ -13,6 +13,7 @@ use chrono::{Duration as ChronoDuration, NaiveDateTime, Utc};
 use serde::Serialize;
 use thiserror::Error;
 use tokio::{
+    net::TcpListener,
     sync::{
         broadcast::Sender,
         mpsc::{self, UnboundedSender},
@@ -20,11 +21,14 @@ use tokio::{
     time::sleep,
 };
 use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
+#[cfg(feature = "wireguard")]
+use tonic::service::interceptor::InterceptedService;
 use tonic::{
     transport::{Certificate, ClientTlsConfig, Endpoint, Identity, Server, ServerTlsConfig},
     Status,
 };
 use uaparser::UserAgentParser;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 #[cfg(feature = "wireguard")]
@@ -34,6 +38,7 @@ use self::{
     desktop_client_mfa::ClientMfaServer,
     enrollment::EnrollmentServer,
     password_reset::PasswordResetServer,
+    polling::PollingServer,
     proto::core_response,
 };
 #[cfg(feature = "worker")]
@@ -41,15 +46,21 @@ use self::{
     interceptor::JwtInterceptor,
     worker::{worker_service_server::WorkerServiceServer, WorkerServer},
 };
-use crate::{
-    auth::failed_login::FailedLoginMap, db::AppEvent,
-    handlers::mail::send_gateway_disconnected_email, mail::Mail, server_config,
-};
 #[cfg(feature = "worker")]
 use crate::{
     auth::ClaimsType,
     db::{DbPool, GatewayEvent},
 };
+use crate::{
+    auth::{failed_login::FailedLoginMap, failed_token::FailedTokenMap},
+    db::AppEvent,
+    error::WebError,
+    handlers::mail::send_gateway_disconnected_notification,
+    live_events::{self, LiveEvent},
+    mail::Mail,
+    proxy_protocol::{proxied_incoming, ProxyProtocol},
+    server_config,
+};
 
 mod auth;
 mod desktop_client_mfa;
@@ -59,6 +70,9 @@ pub(crate) mod gateway;
 #[cfg(any(feature = "wireguard", feature = "worker"))]
 mod interceptor;
 pub mod password_reset;
+#[cfg(feature = "wireguard")]
+pub mod peer_stats;
+pub(crate) mod polling;
 #[cfg(feature = "worker")]
 pub mod worker;
 
@@ -178,6 +192,10 @@ impl GatewayMap {
             return Err(GatewayMapError::NetworkNotFound(network_id));
         };
         info!("Gateway {hostname} connected in network {network_id}");
+        live_events::publish(LiveEvent::GatewayConnected {
+            network_id,
+            hostname: hostname.into(),
+        });
         Ok(())
     }
 
@@ -199,6 +217,10 @@ impl GatewayMap {
                     state
                 );
                 info!("Gateway {hostname} disconnected in network {network_id}");
+                live_events::publish(LiveEvent::GatewayDisconnected {
+                    network_id,
+                    hostname,
+                });
                 return Ok(());
             };
         };
@@ -207,6 +229,21 @@ impl GatewayMap {
         Err(err)
     }
 
+    // record that gateway update stream lagged behind and missed some events
+    pub fn record_lag(&mut self, network_id: i64, hostname: &str, missed: u64) {
+        if let Some(state) = self
+            .0
+            .get_mut(&network_id)
+            .and_then(|network_gateway_map| network_gateway_map.get_mut(hostname))
+        {
+            state.lag_incidents += 1;
+            state.missed_events += missed;
+            state.last_lag_at = Some(Utc::now().naive_utc());
+        } else {
+            error!("Gateway {hostname} not found in gateway map for network {network_id}");
+        }
+    }
+
     // return `true` if at least one gateway in a given network is connected
     #[must_use]
     pub fn connected(&self, network_id: i64) -> bool {
@@ -249,7 +286,7 @@ impl Default for GatewayMap {
     }
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug, ToSchema)]
 pub struct GatewayState {
     pub uid: Uuid,
     pub connected: bool,
@@ -259,6 +296,10 @@ pub struct GatewayState {
     pub hostname: String,
     pub connected_at: Option<NaiveDateTime>,
     pub disconnected_at: Option<NaiveDateTime>,
+    // times the update stream fell behind the event channel and had to resync
+    pub lag_incidents: u64,
+    pub missed_events: u64,
+    pub last_lag_at: Option<NaiveDateTime>,
     #[serde(skip)]
     pub mail_tx: UnboundedSender<Mail>,
     #[serde(skip)]
@@ -283,6 +324,9 @@ impl GatewayState {
             hostname: hostname.into(),
             connected_at: None,
             disconnected_at: None,
+            lag_incidents: 0,
+            missed_events: 0,
+            last_lag_at: None,
             mail_tx,
             last_email_notification: None,
         }
@@ -312,13 +356,18 @@ impl GatewayState {
             // FIXME: Try to get rid of spawn and use something like block_on
             // To return result instead of logging
             tokio::spawn(async move {
-                if let Err(e) =
-                    send_gateway_disconnected_email(name, network_name, &hostname, &mail_tx, &pool)
-                        .await
+                if let Err(e) = send_gateway_disconnected_notification(
+                    name,
+                    network_name,
+                    &hostname,
+                    &mail_tx,
+                    &pool,
+                )
+                .await
                 {
                     error!("Failed to send gateway disconnect notification: {e}");
                 } else {
-                    info!("Gateway {hostname} disconnected. Email notification sent",);
+                    info!("Gateway {hostname} disconnected. Notification sent");
                 }
             });
         } else {
@@ -343,23 +392,43 @@ impl From<Status> for CoreError {
     }
 }
 
+/// Map password policy check error to gRPC status, listing failed requirements for the proxy.
+pub(crate) fn password_policy_status(err: WebError) -> Status {
+    match err {
+        WebError::PasswordPolicy(err) => {
+            let requirements: Vec<&str> = err.0.iter().map(|req| req.as_str()).collect();
+            Status::invalid_argument(format!(
+                "password not strong enough: {}",
+                requirements.join(", ")
+            ))
+        }
+        _ => Status::internal("unexpected error"),
+    }
+}
+
 /// Bi-directional gRPC stream for comminication with Defguard proxy.
 pub async fn run_grpc_bidi_stream(
     pool: DbPool,
     wireguard_tx: Sender<GatewayEvent>,
     mail_tx: UnboundedSender<Mail>,
     user_agent_parser: Arc<UserAgentParser>,
+    failed_logins: Arc<Mutex<FailedLoginMap>>,
 ) -> Result<(), anyhow::Error> {
     let config = server_config();
 
+    // invalid token attempts are tracked together for both services
+    let failed_tokens = Arc::new(Mutex::new(FailedTokenMap::new()));
     // TODO: merge the two
     let enrollment_server = EnrollmentServer::new(
         pool.clone(),
         wireguard_tx.clone(),
         mail_tx.clone(),
         user_agent_parser,
+        Arc::clone(&failed_tokens),
     );
-    let password_reset_server = PasswordResetServer::new(pool.clone(), mail_tx.clone());
+    let password_reset_server =
+        PasswordResetServer::new(pool.clone(), mail_tx.clone(), failed_logins, failed_tokens);
+    let polling_server = PollingServer::new(pool.clone());
     let mut client_mfa_server = ClientMfaServer::new(pool, mail_tx, wireguard_tx);
 
     let endpoint = Endpoint::from_shared(config.proxy_url.as_deref().unwrap())?;
@@ -393,7 +462,10 @@ pub async fn run_grpc_bidi_stream(
                     let payload = match received.payload {
                         // rpc StartEnrollment (EnrollmentStartRequest) returns (EnrollmentStartResponse)
                         Some(core_request::Payload::EnrollmentStart(request)) => {
-                            match enrollment_server.start_enrollment(request).await {
+                            match enrollment_server
+                                .start_enrollment(request, received.device_info)
+                                .await
+                            {
                                 Ok(response_payload) => {
                                     Some(core_response::Payload::EnrollmentStart(response_payload))
                                 }
@@ -458,7 +530,10 @@ pub async fn run_grpc_bidi_stream(
                         }
                         // rpc StartPasswordReset (PasswordResetStartRequest) returns (PasswordResetStartResponse)
                         Some(core_request::Payload::PasswordResetStart(request)) => {
-                            match password_reset_server.start_password_reset(request).await {
+                            match password_reset_server
+                                .start_password_reset(request, received.device_info)
+                                .await
+                            {
                                 Ok(response_payload) => Some(
                                     core_response::Payload::PasswordResetStart(response_payload),
                                 ),
@@ -505,6 +580,18 @@ pub async fn run_grpc_bidi_stream(
                                 }
                             }
                         }
+                        // rpc InstanceInfo (InstanceInfoRequest) returns (InstanceInfoResponse)
+                        Some(core_request::Payload::InstanceInfo(request)) => {
+                            match polling_server.info(request).await {
+                                Ok(response_payload) => {
+                                    Some(core_response::Payload::InstanceInfo(response_payload))
+                                }
+                                Err(err) => {
+                                    error!("instance info error {err}");
+                                    Some(core_response::Payload::CoreError(err.into()))
+                                }
+                            }
+                        }
                         // Reply without payload.
                         None => None,
                     };
@@ -529,6 +616,7 @@ pub async fn run_grpc_server(
     mail_tx: UnboundedSender<Mail>,
     grpc_cert: Option<String>,
     grpc_key: Option<String>,
+    grpc_client_ca: Option<String>,
     failed_logins: Arc<Mutex<FailedLoginMap>>,
 ) -> Result<(), anyhow::Error> {
     // Build gRPC services
@@ -538,9 +626,17 @@ pub async fn run_grpc_server(
         WorkerServer::new(pool.clone(), worker_state),
         JwtInterceptor::new(ClaimsType::YubiBridge),
     );
+    // full location configuration is sent in batches of peers fitting in the message size
     #[cfg(feature = "wireguard")]
-    let gateway_service = GatewayServiceServer::with_interceptor(
-        GatewayServer::new(pool, gateway_state, wireguard_tx, mail_tx),
+    let gateway_service = InterceptedService::new(
+        GatewayServiceServer::new(GatewayServer::new(
+            pool,
+            gateway_state,
+            wireguard_tx,
+            mail_tx,
+        ))
+        .max_decoding_message_size(server_config().grpc_max_message_size)
+        .max_encoding_message_size(server_config().grpc_max_message_size),
         JwtInterceptor::new(ClaimsType::Gateway),
     );
     // Run gRPC server
@@ -548,7 +644,12 @@ pub async fn run_grpc_server(
     debug!("Starting gRPC services");
     let builder = if let (Some(cert), Some(key)) = (grpc_cert, grpc_key) {
         let identity = Identity::from_pem(cert, key);
-        Server::builder().tls_config(ServerTlsConfig::new().identity(identity))?
+        let mut tls = ServerTlsConfig::new().identity(identity);
+        if let Some(ca) = grpc_client_ca {
+            info!("gRPC clients are required to present a certificate");
+            tls = tls.client_ca_root(Certificate::from_pem(ca));
+        }
+        Server::builder().tls_config(tls)?
     } else {
         Server::builder()
     };
@@ -560,7 +661,17 @@ pub async fn run_grpc_server(
     let router = router.add_service(gateway_service);
     #[cfg(feature = "worker")]
     let router = router.add_service(worker_service);
-    router.serve(addr).await?;
+    let proxy_protocol = ProxyProtocol::new(server_config().proxy_protocol_sources.clone());
+    if proxy_protocol.is_enabled() {
+        info!("gRPC server expects PROXY protocol headers from load balancers");
+        // TCP keepalive set on the builder only applies to connections it accepts itself
+        let listener = TcpListener::bind(addr).await?;
+        router
+            .serve_with_incoming(proxied_incoming(listener, proxy_protocol))
+            .await?;
+    } else {
+        router.serve(addr).await?;
+    }
     info!("gRPC server started on {addr}");
     Ok(())
 }
@@ -572,6 +683,8 @@ pub struct Job {
     last_name: String,
     email: String,
     username: String,
+    // handed out to the worker, which hasn't reported the result yet
+    claimed: bool,
 }
 
 #[cfg(feature = "worker")]
@@ -593,7 +706,6 @@ pub struct WorkerInfo {
 
 #[cfg(feature = "worker")]
 pub struct WorkerState {
-    current_job_id: u32,
     workers: HashMap<String, WorkerInfo>,
     job_status: HashMap<u32, JobResponse>,
     webhook_tx: UnboundedSender<AppEvent>,
@@ -1,11 +1,23 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex},
+};
+
 use tokio::sync::mpsc::UnboundedSender;
 use tonic::Status;
 
+use super::password_policy_status;
 use crate::{
+    auth::{
+        challenge::{check_challenge, ChallengeError},
+        failed_login::FailedLoginMap,
+        failed_token::FailedTokenMap,
+    },
     db::{
         models::enrollment::{Token, PASSWORD_RESET_TOKEN_TYPE},
         DbPool, User,
     },
+    error::WebError,
     handlers::{
         mail::{send_password_reset_email, send_password_reset_success_email},
         user::check_password_strength,
@@ -23,17 +35,26 @@ use super::proto::{
 pub(super) struct PasswordResetServer {
     pool: DbPool,
     mail_tx: UnboundedSender<Mail>,
+    failed_logins: Arc<Mutex<FailedLoginMap>>,
+    failed_tokens: Arc<Mutex<FailedTokenMap>>,
     // ldap_feature_active: bool,
 }
 
 impl PasswordResetServer {
     #[must_use]
-    pub fn new(pool: DbPool, mail_tx: UnboundedSender<Mail>) -> Self {
+    pub fn new(
+        pool: DbPool,
+        mail_tx: UnboundedSender<Mail>,
+        failed_logins: Arc<Mutex<FailedLoginMap>>,
+        failed_tokens: Arc<Mutex<FailedTokenMap>>,
+    ) -> Self {
         // FIXME: check if LDAP feature is enabled
         // let ldap_feature_active = true;
         Self {
             pool,
             mail_tx,
+            failed_logins,
+            failed_tokens,
             // ldap_feature_active,
         }
     }
@@ -61,6 +82,31 @@ impl PasswordResetServer {
         }
     }
 
+    // reject requests without a valid challenge solution, if one is required
+    async fn check_challenge(
+        &self,
+        challenge: Option<&str>,
+        ip_address: Option<IpAddr>,
+    ) -> Result<(), Status> {
+        let client_ip = ip_address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        check_challenge(
+            &self.pool,
+            &self.failed_logins,
+            &self.failed_tokens,
+            challenge,
+            client_ip,
+            None,
+        )
+        .await
+        .map_err(|err| match err {
+            WebError::Challenge(ChallengeError::ProviderUnavailable(_)) => {
+                Status::unavailable("challenge verification unavailable")
+            }
+            WebError::Challenge(err) => Status::permission_denied(err.to_string()),
+            _ => Status::internal("unexpected error"),
+        })
+    }
+
     pub async fn request_password_reset(
         &self,
         request: PasswordResetInitializeRequest,
@@ -78,6 +124,8 @@ impl PasswordResetServer {
             ip_address = String::new();
             user_agent = String::new();
         }
+        self.check_challenge(request.challenge.as_deref(), ip_address.parse().ok())
+            .await?;
 
         let email = request.email;
 
@@ -148,10 +196,23 @@ impl PasswordResetServer {
     pub async fn start_password_reset(
         &self,
         request: PasswordResetStartRequest,
+        req_device_info: Option<super::proto::DeviceInfo>,
     ) -> Result<PasswordResetStartResponse, Status> {
         debug!("Starting password reset session: {request:?}");
+        let ip_address = req_device_info
+            .and_then(|info| info.ip_address)
+            .and_then(|ip| ip.parse().ok());
+        self.check_challenge(request.challenge.as_deref(), ip_address)
+            .await?;
 
-        let mut enrollment = Token::find_by_id(&self.pool, &request.token).await?;
+        let mut enrollment = Token::find_guarded(
+            &self.pool,
+            &self.mail_tx,
+            &self.failed_tokens,
+            &request.token,
+            ip_address,
+        )
+        .await?;
 
         if enrollment.token_type != Some("PASSWORD_RESET".to_string()) {
             error!(
@@ -220,13 +281,16 @@ impl PasswordResetServer {
             user_agent = String::new();
         }
 
-        if let Err(err) = check_password_strength(&request.password) {
+        let mut user = enrollment.fetch_user(&self.pool).await?;
+
+        if let Err(err) =
+            check_password_strength(&self.pool, &request.password, &user.username, &user.email)
+                .await
+        {
             error!("Password not strong enough: {err}");
-            return Err(Status::invalid_argument("password not strong enough"));
+            return Err(password_policy_status(err));
         }
 
-        let mut user = enrollment.fetch_user(&self.pool).await?;
-
         if !user.is_active {
             error!(
                 "Can't reset password for a disabled user {}.",
@@ -266,3 +330,152 @@ impl PasswordResetServer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use tokio::sync::mpsc::unbounded_channel;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        auth::challenge::{solve_proof_of_work, ChallengePolicy},
+        config::DefGuardConfig,
+        db::{models::settings::AuthChallenge, Settings},
+        SERVER_CONFIG,
+    };
+
+    #[sqlx::test]
+    async fn test_reset_password_policy(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+
+        let mut settings = Settings::get_settings(&pool).await.unwrap();
+        settings.password_min_length = 14;
+        settings.password_require_special = false;
+        settings.password_disallow_user_data = true;
+        settings.save(&pool).await.unwrap();
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let mut token = Token::new(
+            user.id.unwrap(),
+            None,
+            Some(user.email.clone()),
+            3600,
+            Some(PASSWORD_RESET_TOKEN_TYPE.to_string()),
+        );
+        token.used_at = Some(Utc::now().naive_utc());
+        token
+            .save(&mut pool.acquire().await.unwrap())
+            .await
+            .unwrap();
+
+        let (mail_tx, mut mail_rx) = unbounded_channel();
+        let server =
+            PasswordResetServer::new(pool.clone(), mail_tx, Arc::default(), Arc::default());
+        let request = |password: &str| PasswordResetRequest {
+            password: password.into(),
+            token: Some(token.id.clone()),
+        };
+
+        // too short and without digits
+        let status = server
+            .reset_password(request("Short"), None)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "password not strong enough: min_length, digit"
+        );
+
+        // contains username
+        let status = server
+            .reset_password(request("Hpotter-Password-1"), None)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "password not strong enough: user_data");
+
+        // meets the policy
+        server
+            .reset_password(request("Quidditch Seeker 1991"), None)
+            .await
+            .unwrap();
+        let user = User::find_by_username(&pool, "hpotter")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(user.verify_password("Quidditch Seeker 1991").is_ok());
+        assert!(mail_rx.try_recv().is_ok());
+    }
+
+    #[sqlx::test]
+    async fn test_password_reset_challenge(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+
+        let mut settings = Settings::get_settings(&pool).await.unwrap();
+        settings.auth_challenge = AuthChallenge::ProofOfWork;
+        settings.auth_challenge_pow_difficulty = 4;
+        settings.save(&pool).await.unwrap();
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+
+        let (mail_tx, mut mail_rx) = unbounded_channel();
+        let server =
+            PasswordResetServer::new(pool.clone(), mail_tx, Arc::default(), Arc::default());
+        let request = |challenge: Option<String>| PasswordResetInitializeRequest {
+            email: user.email.clone(),
+            challenge,
+        };
+
+        // requests without a solution are rejected
+        let status = server
+            .request_password_reset(request(None), None)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        let status = server
+            .start_password_reset(
+                PasswordResetStartRequest {
+                    token: "token".into(),
+                    challenge: None,
+                },
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert!(mail_rx.try_recv().is_err());
+
+        // solved challenge lets the request through once
+        let policy = ChallengePolicy::load(&pool).await.unwrap();
+        let solution = solve_proof_of_work(&policy.info(true).nonce.unwrap(), 4);
+        server
+            .request_password_reset(request(Some(solution.clone())), None)
+            .await
+            .unwrap();
+        assert!(mail_rx.try_recv().is_ok());
+        let status = server
+            .request_password_reset(request(Some(solution)), None)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert!(mail_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//! Batched ingestion of peer stats reported by gateways.
+//!
+//! Stats received on a gateway stream are buffered in memory and written using
+//! a single multi-row insert once the buffer is full or the flush interval elapses.
+//! Consecutive updates of the same peer within one batch are coalesced,
+//! keeping only the most recent one. Peer counters are cumulative, so no data is lost.
+
+use std::{
+    collections::hash_map::{Entry, HashMap},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+    time::{interval, sleep, MissedTickBehavior},
+};
+use utoipa::ToSchema;
+
+use crate::db::{models::wireguard::WireguardPeerStats, DbPool};
+
+// attempts made to write buffered stats after the stream has been closed
+const FINAL_FLUSH_ATTEMPTS: u32 = 3;
+const FINAL_FLUSH_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+static METRICS: StatsIngestionMetrics = StatsIngestionMetrics::new();
+
+/// Counters describing peer stats ingestion since server start.
+struct StatsIngestionMetrics {
+    batches: AtomicU64,
+    rows: AtomicU64,
+    coalesced: AtomicU64,
+    failed_flushes: AtomicU64,
+    last_batch_size: AtomicU64,
+    max_batch_size: AtomicU64,
+    last_flush_latency_ms: AtomicU64,
+    total_flush_latency_ms: AtomicU64,
+}
+
+impl StatsIngestionMetrics {
+    const fn new() -> Self {
+        Self {
+            batches: AtomicU64::new(0),
+            rows: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+            failed_flushes: AtomicU64::new(0),
+            last_batch_size: AtomicU64::new(0),
+            max_batch_size: AtomicU64::new(0),
+            last_flush_latency_ms: AtomicU64::new(0),
+            total_flush_latency_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn record_flush(&self, size: usize, latency: Duration) {
+        let size = size as u64;
+        let latency = latency.as_millis() as u64;
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.rows.fetch_add(size, Ordering::Relaxed);
+        self.last_batch_size.store(size, Ordering::Relaxed);
+        self.max_batch_size.fetch_max(size, Ordering::Relaxed);
+        self.last_flush_latency_ms.store(latency, Ordering::Relaxed);
+        self.total_flush_latency_ms
+            .fetch_add(latency, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StatsIngestionSnapshot {
+    pub batches: u64,
+    pub rows: u64,
+    pub coalesced: u64,
+    pub failed_flushes: u64,
+    pub last_batch_size: u64,
+    pub max_batch_size: u64,
+    pub last_flush_latency_ms: u64,
+    pub avg_flush_latency_ms: u64,
+}
+
+/// Current peer stats ingestion metrics.
+#[must_use]
+pub fn ingestion_metrics() -> StatsIngestionSnapshot {
+    let batches = METRICS.batches.load(Ordering::Relaxed);
+    let total_latency = METRICS.total_flush_latency_ms.load(Ordering::Relaxed);
+    StatsIngestionSnapshot {
+        batches,
+        rows: METRICS.rows.load(Ordering::Relaxed),
+        coalesced: METRICS.coalesced.load(Ordering::Relaxed),
+        failed_flushes: METRICS.failed_flushes.load(Ordering::Relaxed),
+        last_batch_size: METRICS.last_batch_size.load(Ordering::Relaxed),
+        max_batch_size: METRICS.max_batch_size.load(Ordering::Relaxed),
+        last_flush_latency_ms: METRICS.last_flush_latency_ms.load(Ordering::Relaxed),
+        avg_flush_latency_ms: total_latency.checked_div(batches).unwrap_or_default(),
+    }
+}
+
+// (network id, device id)
+type PeerKey = (i64, i64);
+
+/// Stats waiting to be written, at most one row per peer.
+#[derive(Default)]
+struct StatsBuffer {
+    pending: HashMap<PeerKey, WireguardPeerStats>,
+}
+
+impl StatsBuffer {
+    fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn insert(&mut self, stats: WireguardPeerStats) {
+        match self.pending.entry((stats.network, stats.device_id)) {
+            Entry::Occupied(mut entry) => {
+                if stats.collected_at >= entry.get().collected_at {
+                    entry.insert(stats);
+                }
+                METRICS.coalesced.fetch_add(1, Ordering::Relaxed);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(stats);
+            }
+        }
+    }
+
+    /// Put back rows of a failed batch, without overwriting newer updates received meanwhile.
+    fn restore(&mut self, rows: Vec<WireguardPeerStats>) {
+        for stats in rows {
+            self.pending
+                .entry((stats.network, stats.device_id))
+                .or_insert(stats);
+        }
+    }
+
+    /// Write buffered stats to the database. Returns `false` if the write failed,
+    /// in which case stats are kept in the buffer for the next attempt.
+    async fn flush(&mut self, pool: &DbPool) -> bool {
+        if self.pending.is_empty() {
+            return true;
+        }
+        let rows: Vec<_> = self.pending.drain().map(|(_, stats)| stats).collect();
+        let start = Instant::now();
+        match WireguardPeerStats::save_batch(pool, &rows).await {
+            Ok(count) => {
+                let latency = start.elapsed();
+                METRICS.record_flush(rows.len(), latency);
+                debug!("Saved {count} WireGuard peer stats to db in {latency:?}");
+                true
+            }
+            Err(err) => {
+                error!(
+                    "Saving {} WireGuard peer stats to db failed: {err}",
+                    rows.len()
+                );
+                METRICS.failed_flushes.fetch_add(1, Ordering::Relaxed);
+                self.restore(rows);
+                false
+            }
+        }
+    }
+}
+
+/// Buffers peer stats of a single gateway stream and writes them in batches
+/// from a background task, so database writes never block the stream.
+///
+/// Buffered stats are flushed when the batcher is closed or dropped.
+pub(crate) struct PeerStatsBatcher {
+    tx: UnboundedSender<WireguardPeerStats>,
+    handle: JoinHandle<()>,
+}
+
+impl PeerStatsBatcher {
+    #[must_use]
+    pub(crate) fn spawn(pool: DbPool, batch_size: usize, flush_interval: Duration) -> Self {
+        let (tx, rx) = unbounded_channel();
+        let handle = tokio::spawn(ingest(pool, rx, batch_size.max(1), flush_interval));
+        Self { tx, handle }
+    }
+
+    pub(crate) fn push(&self, stats: WireguardPeerStats) {
+        if self.tx.send(stats).is_err() {
+            error!("Peer stats ingestion task is not running, dropping stats");
+        }
+    }
+
+    /// Stop accepting stats and wait until buffered ones are written.
+    pub(crate) async fn close(self) {
+        drop(self.tx);
+        if let Err(err) = self.handle.await {
+            error!("Peer stats ingestion task failed: {err}");
+        }
+    }
+}
+
+async fn ingest(
+    pool: DbPool,
+    mut rx: UnboundedReceiver<WireguardPeerStats>,
+    batch_size: usize,
+    flush_interval: Duration,
+) {
+    let mut buffer = StatsBuffer::default();
+    let mut ticker = interval(flush_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // first tick completes immediately
+    ticker.tick().await;
+    // after a failed write, wait for the next tick instead of retrying on every update
+    let mut backoff = false;
+
+    loop {
+        tokio::select! {
+            stats = rx.recv() => {
+                let Some(stats) = stats else {
+                    break;
+                };
+                buffer.insert(stats);
+                if !backoff && buffer.len() >= batch_size {
+                    backoff = !buffer.flush(&pool).await;
+                }
+            }
+            _ = ticker.tick() => {
+                backoff = !buffer.flush(&pool).await;
+            }
+        }
+    }
+
+    debug!(
+        "Peer stats stream closed, flushing {} buffered stats",
+        buffer.len()
+    );
+    for attempt in 1..=FINAL_FLUSH_ATTEMPTS {
+        if buffer.flush(&pool).await {
+            return;
+        }
+        sleep(FINAL_FLUSH_RETRY_DELAY * attempt).await;
+    }
+    error!(
+        "Dropping {} WireGuard peer stats which could not be saved",
+        buffer.len()
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration as ChronoDuration, NaiveDateTime, Utc};
+    use sqlx::query_scalar;
+
+    use super::*;
+    use crate::db::{Device, User, WireguardNetwork};
+
+    async fn setup(pool: &DbPool, devices: usize) -> (i64, Vec<i64>) {
+        let mut network = WireguardNetwork::default();
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.save(pool).await.unwrap();
+        let mut user = User::new(
+            "testuser",
+            Some("hunter2"),
+            "Tester",
+            "Test",
+            "test@test.com",
+            None,
+        );
+        user.save(pool).await.unwrap();
+        let mut device_ids = Vec::new();
+        for i in 0..devices {
+            let (device, _) = Device::new_with_ip(
+                pool,
+                user.id.unwrap(),
+                format!("dev{i}"),
+                format!("key{i}"),
+                &network,
+            )
+            .await
+            .unwrap();
+            device_ids.push(device.id.unwrap());
+        }
+        (network.id.unwrap(), device_ids)
+    }
+
+    fn stats(network: i64, device_id: i64, collected_at: NaiveDateTime) -> WireguardPeerStats {
+        WireguardPeerStats {
+            id: None,
+            device_id,
+            collected_at,
+            network,
+            endpoint: Some("10.10.10.10:7301".into()),
+            upload: 100,
+            download: 200,
+            latest_handshake: collected_at,
+            allowed_ips: Some("10.1.1.2/32".into()),
+        }
+    }
+
+    async fn row_count(pool: &DbPool) -> i64 {
+        query_scalar("SELECT count(*) FROM wireguard_peer_stats")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_batch_insert(pool: DbPool) {
+        let (network, devices) = setup(&pool, 5).await;
+        let now = Utc::now().naive_utc();
+        let rows: Vec<_> = devices
+            .iter()
+            .map(|device_id| stats(network, *device_id, now))
+            .collect();
+        let count = WireguardPeerStats::save_batch(&pool, &rows).await.unwrap();
+        assert_eq!(count, 5);
+        assert_eq!(row_count(&pool).await, 5);
+
+        // batch size threshold triggers a write without waiting for the interval
+        let batcher = PeerStatsBatcher::spawn(pool.clone(), 5, Duration::from_secs(3600));
+        for device_id in &devices {
+            batcher.push(stats(network, *device_id, now));
+        }
+        for _ in 0..50 {
+            if row_count(&pool).await == 10 {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(row_count(&pool).await, 10);
+        batcher.close().await;
+        assert_eq!(row_count(&pool).await, 10);
+    }
+
+    #[sqlx::test]
+    async fn test_updates_are_coalesced(pool: DbPool) {
+        let (network, devices) = setup(&pool, 2).await;
+        let now = Utc::now().naive_utc();
+
+        let batcher = PeerStatsBatcher::spawn(pool.clone(), 100, Duration::from_secs(3600));
+        for i in 0..10 {
+            for device_id in &devices {
+                let mut update = stats(network, *device_id, now + ChronoDuration::seconds(i));
+                update.upload = i * 10;
+                batcher.push(update);
+            }
+        }
+        batcher.close().await;
+
+        assert_eq!(row_count(&pool).await, 2);
+        let uploads: Vec<i64> =
+            query_scalar("SELECT upload FROM wireguard_peer_stats ORDER BY device_id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(uploads, vec![90, 90]);
+    }
+
+    #[sqlx::test]
+    async fn test_flush_on_drop(pool: DbPool) {
+        let (network, devices) = setup(&pool, 3).await;
+        let now = Utc::now().naive_utc();
+
+        let batcher = PeerStatsBatcher::spawn(pool.clone(), 100, Duration::from_secs(3600));
+        for device_id in &devices {
+            batcher.push(stats(network, *device_id, now));
+        }
+        // e.g. gateway stream ended with an error before calling `close()`
+        drop(batcher);
+
+        for _ in 0..50 {
+            if row_count(&pool).await == 3 {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(row_count(&pool).await, 3);
+    }
+}
@@ -0,0 +1,245 @@
+use tonic::Status;
+
+use super::{
+    enrollment::device_config_response,
+    proto::{InstanceInfoRequest, InstanceInfoResponse},
+};
+use crate::db::{
+    models::{device::DevicePlatform, polling_token::PollingToken},
+    DbPool, Device, User,
+};
+
+/// Serves desktop client requests for current instance and location configuration.
+pub(super) struct PollingServer {
+    pool: DbPool,
+}
+
+impl PollingServer {
+    #[must_use]
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool }
+    }
+
+    /// Get current configuration of all locations the device owner is entitled to.
+    pub async fn info(&self, request: InstanceInfoRequest) -> Result<InstanceInfoResponse, Status> {
+        debug!("Getting instance info for polling token");
+        let Some(token) = PollingToken::find(&self.pool, &request.token)
+            .await
+            .map_err(|err| {
+                error!("Failed to fetch polling token: {err}");
+                Status::internal("unexpected error")
+            })?
+        else {
+            warn!("Invalid polling token");
+            return Err(Status::permission_denied("invalid token"));
+        };
+
+        let Some(mut device) = Device::find_by_id(&self.pool, token.device_id)
+            .await
+            .map_err(|err| {
+                error!("Failed to fetch device {}: {err}", token.device_id);
+                Status::internal("unexpected error")
+            })?
+        else {
+            error!("Device {} not found", token.device_id);
+            return Err(Status::internal("device not found error"));
+        };
+
+        let user = User::find_by_id(&self.pool, device.user_id)
+            .await
+            .map_err(|err| {
+                error!("Failed to fetch user {}: {err}", device.user_id);
+                Status::internal("unexpected error")
+            })?;
+        if !user.is_some_and(|user| user.is_active) {
+            warn!(
+                "Denying instance info for device {}, owner is disabled",
+                device.name
+            );
+            return Err(Status::permission_denied("user is disabled"));
+        }
+
+        // clients report their platform on each poll, so it's kept up to date after upgrades
+        let platform = DevicePlatform::new(request.os, request.os_version, request.client_version);
+        match device.update_platform(&self.pool, platform).await {
+            Ok(true) => info!(
+                "Device {} reported platform {:?} {:?}, client version {:?}",
+                device.name, device.os, device.os_version, device.client_version
+            ),
+            Ok(false) => (),
+            // not worth failing the poll
+            Err(err) => error!("Failed to update platform of device {}: {err}", device.name),
+        }
+
+        let device_config = device_config_response(&self.pool, device, None).await?;
+        Ok(InstanceInfoResponse {
+            device_config: Some(device_config),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        config::DefGuardConfig,
+        db::{Group, WireguardNetwork},
+        SERVER_CONFIG,
+    };
+
+    #[sqlx::test]
+    async fn test_poll_location_entitlements(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let mut device = Device::new(
+            "laptop".into(),
+            "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=".into(),
+            user.id.unwrap(),
+        );
+        device.save(&pool).await.unwrap();
+        let mut token = PollingToken::new(device.id.unwrap());
+        token.save(&pool).await.unwrap();
+        let mut group = Group::new("contractors");
+        group.save(&pool).await.unwrap();
+        user.add_to_group(&pool, &group).await.unwrap();
+
+        let server = PollingServer::new(pool.clone());
+        let request = || InstanceInfoRequest {
+            token: token.token.clone(),
+            os: None,
+            os_version: None,
+            client_version: None,
+        };
+        let response = server.info(request()).await.unwrap();
+        assert!(response.device_config.unwrap().configs.is_empty());
+
+        // new location allowed for user's group shows up on next poll
+        let mut network = WireguardNetwork {
+            name: "build-farm".into(),
+            ..Default::default()
+        };
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.mtu = Some(1280);
+        network.save(&pool).await.unwrap();
+        let mut transaction = pool.begin().await.unwrap();
+        network
+            .set_allowed_groups(&mut transaction, vec!["contractors".into()])
+            .await
+            .unwrap();
+        network
+            .add_all_allowed_devices(&mut transaction)
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+
+        let response = server.info(request()).await.unwrap();
+        let configs = response.device_config.unwrap().configs;
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].network_id, network.id.unwrap());
+        assert_eq!(configs[0].network_name, "build-farm");
+        assert_eq!(configs[0].pubkey, network.pubkey);
+        assert_eq!(configs[0].mtu, Some(1280));
+        assert!(configs[0].config.contains("MTU = 1280"));
+
+        // location disappears once user loses access
+        user.remove_from_group(&pool, &group).await.unwrap();
+        let mut transaction = pool.begin().await.unwrap();
+        network
+            .sync_allowed_devices(&mut transaction, None)
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+
+        let response = server.info(request()).await.unwrap();
+        assert!(response.device_config.unwrap().configs.is_empty());
+
+        // unknown token
+        let status = server
+            .info(InstanceInfoRequest {
+                token: "invalid".into(),
+                os: None,
+                os_version: None,
+                client_version: None,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+
+    #[sqlx::test]
+    async fn test_poll_updates_platform(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let mut device = Device::new(
+            "laptop".into(),
+            "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=".into(),
+            user.id.unwrap(),
+        );
+        device.set_platform(DevicePlatform::new(
+            Some("Windows".into()),
+            Some("11".into()),
+            Some("0.9.2".into()),
+        ));
+        device.save(&pool).await.unwrap();
+        let mut token = PollingToken::new(device.id.unwrap());
+        token.save(&pool).await.unwrap();
+
+        let server = PollingServer::new(pool.clone());
+        let request = |client_version: Option<&str>| InstanceInfoRequest {
+            token: token.token.clone(),
+            os: client_version.map(|_| "windows".into()),
+            os_version: client_version.map(|_| "11".into()),
+            client_version: client_version.map(Into::into),
+        };
+
+        // older clients don't report platform, stored one is kept
+        server.info(request(None)).await.unwrap();
+        let stored = Device::find_by_id(&pool, device.id.unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.platform(), device.platform());
+
+        // upgraded client
+        server.info(request(Some(" 1.0.0 "))).await.unwrap();
+        let mut stored = Device::find_by_id(&pool, device.id.unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.os.as_deref(), Some("windows"));
+        assert_eq!(stored.os_version.as_deref(), Some("11"));
+        assert_eq!(stored.client_version.as_deref(), Some("1.0.0"));
+
+        // repeated polls with the same platform don't write anything
+        let row_version = || {
+            sqlx::query_scalar::<_, String>("SELECT xmin::text FROM device WHERE id = $1")
+                .bind(stored.id)
+                .fetch_one(&pool)
+        };
+        let version = row_version().await.unwrap();
+        server.info(request(Some("1.0.0"))).await.unwrap();
+        assert_eq!(row_version().await.unwrap(), version);
+        let platform = stored.platform();
+        assert!(!stored.update_platform(&pool, platform).await.unwrap());
+    }
+}
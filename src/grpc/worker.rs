@@ -1,21 +1,46 @@
 use super::{Job, JobResponse, WorkerDetail, WorkerInfo, WorkerState};
-use crate::db::{
-    models::authentication_key::{AuthenticationKey, AuthenticationKeyType},
-    AppEvent, DbPool, HWKeyUserData, User, YubiKey,
+use crate::{
+    db::{
+        models::{
+            authentication_key::{AuthenticationKey, AuthenticationKeyType},
+            worker_job::{WorkerJob, WorkerJobResult, WorkerJobStatus},
+        },
+        AppEvent, DbPool, HWKeyUserData, User, YubiKey,
+    },
+    jobs::{Job as BackgroundJob, JobSchedule},
 };
-use sqlx::query;
+use sqlx::{query, Error as SqlxError};
 use std::{
     collections::hash_map::{Entry, HashMap},
     env,
     net::{IpAddr, Ipv4Addr},
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc::UnboundedSender;
 use tonic::{Request, Response, Status};
 
 tonic::include_proto!("worker");
 
+// Claimed jobs not reported back within this time are dispatched again
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+// How often abandoned jobs are looked for
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(60);
+
+impl From<WorkerJob> for Job {
+    fn from(job: WorkerJob) -> Self {
+        Self {
+            // worker protocol uses 32-bit job IDs
+            id: job.id as u32,
+            first_name: job.first_name,
+            last_name: job.last_name,
+            email: job.email,
+            username: job.username,
+            claimed: job.status == WorkerJobStatus::Claimed,
+        }
+    }
+}
+
 impl WorkerInfo {
     /// Create new `Worker` instance.
     #[must_use]
@@ -38,10 +63,11 @@ impl WorkerInfo {
         self.last_seen.elapsed().as_secs() < 2
     }
 
-    /// Return first availale Job.
-    #[must_use]
-    pub fn get_job(&self) -> Option<&Job> {
-        self.jobs.first()
+    /// Claim first available Job.
+    pub fn get_job(&mut self) -> Option<&Job> {
+        let job = self.jobs.iter_mut().find(|job| !job.claimed)?;
+        job.claimed = true;
+        Some(job)
     }
 
     /// Set worker ip
@@ -75,7 +101,6 @@ impl WorkerState {
     #[must_use]
     pub fn new(webhook_tx: UnboundedSender<AppEvent>) -> Self {
         Self {
-            current_job_id: 1,
             workers: HashMap::new(),
             job_status: HashMap::new(),
             webhook_tx,
@@ -92,29 +117,42 @@ impl WorkerState {
         }
     }
 
-    /// Create a new job.
-    /// Return job id.
-    pub fn create_job(
-        &mut self,
-        worker_id: &str,
-        first_name: String,
-        last_name: String,
-        email: String,
-        username: String,
-    ) -> u32 {
-        if let Some(worker) = self.workers.get_mut(worker_id) {
-            let id = self.current_job_id;
-            self.current_job_id = id.wrapping_add(1);
-            worker.add_job(Job {
-                id,
-                first_name,
-                last_name,
-                email,
-                username,
-            });
-            id
+    #[must_use]
+    pub fn has_worker(&self, id: &str) -> bool {
+        self.workers.contains_key(id)
+    }
+
+    /// Queue a stored job for its worker.
+    /// Return `false` if the worker isn't registered.
+    pub fn create_job(&mut self, job: WorkerJob) -> bool {
+        if let Some(worker) = self.workers.get_mut(&job.worker_id) {
+            worker.add_job(job.into());
+            true
         } else {
-            0
+            false
+        }
+    }
+
+    /// Queue unfinished jobs stored before restart, registering their workers.
+    pub fn restore_jobs(&mut self, jobs: Vec<WorkerJob>) {
+        for job in jobs {
+            self.workers
+                .entry(job.worker_id.clone())
+                .or_default()
+                .add_job(job.into());
+        }
+    }
+
+    /// Make claimed jobs with given ids available to workers again.
+    pub fn release_jobs(&mut self, job_ids: &[u32]) {
+        for job in self
+            .workers
+            .values_mut()
+            .flat_map(|worker| worker.jobs.iter_mut())
+        {
+            if job_ids.contains(&job.id) {
+                job.claimed = false;
+            }
         }
     }
 
@@ -128,7 +166,7 @@ impl WorkerState {
         }
     }
 
-    /// Return the first available job.
+    /// Claim the first available job.
     pub fn get_job(&mut self, id: &str, ip: IpAddr) -> Option<&Job> {
         if let Some(worker) = self.workers.get_mut(id) {
             worker.refresh_status();
@@ -176,6 +214,41 @@ impl WorkerState {
     }
 }
 
+/// Return jobs abandoned by workers to pending, so they're dispatched again.
+/// Returns number of returned jobs.
+pub async fn reclaim_abandoned_jobs(
+    pool: &DbPool,
+    state: &Mutex<WorkerState>,
+) -> Result<usize, SqlxError> {
+    let job_ids: Vec<u32> = WorkerJob::reclaim_abandoned(pool, CLAIM_TIMEOUT)
+        .await?
+        .into_iter()
+        .map(|id| id as u32)
+        .collect();
+    if !job_ids.is_empty() {
+        warn!("Worker jobs {job_ids:?} not completed in time, dispatching them again");
+        state.lock().unwrap().release_jobs(&job_ids);
+    }
+    Ok(job_ids.len())
+}
+
+/// Background job dispatching abandoned worker jobs again.
+#[must_use]
+pub fn worker_job_reclaim_job(pool: DbPool, state: Arc<Mutex<WorkerState>>) -> BackgroundJob {
+    BackgroundJob::new(
+        "worker_job_reclaim",
+        JobSchedule::Interval(RECLAIM_INTERVAL),
+        move || {
+            let pool = pool.clone();
+            let state = Arc::clone(&state);
+            async move {
+                reclaim_abandoned_jobs(&pool, &state).await?;
+                Ok(())
+            }
+        },
+    )
+}
+
 pub struct WorkerServer {
     pool: DbPool,
     state: Arc<Mutex<WorkerState>>,
@@ -206,14 +279,21 @@ impl worker_service_server::WorkerService for WorkerServer {
             .remote_addr()
             .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip());
         let message = request.into_inner();
-        let mut state = self.state.lock().unwrap();
-        if let Some(job) = state.get_job(&message.id, ip) {
-            Ok(Response::new(GetJobResponse {
+        let response = {
+            let mut state = self.state.lock().unwrap();
+            state.get_job(&message.id, ip).map(|job| GetJobResponse {
                 first_name: job.first_name.clone(),
                 last_name: job.last_name.clone(),
                 email: job.email.clone(),
                 job_id: job.id,
-            }))
+            })
+        };
+        if let Some(response) = response {
+            WorkerJob::claim(&self.pool, response.job_id.into())
+                .await
+                .map_err(|_| Status::internal("Failed to claim job"))?;
+            debug!("Worker {} claimed job {}", message.id, response.job_id);
+            Ok(Response::new(response))
         } else {
             Err(Status::not_found("No more jobs"))
         }
@@ -251,6 +331,30 @@ impl worker_service_server::WorkerService for WorkerServer {
             }
         };
 
+        let stored = WorkerJob::finish(
+            &self.pool,
+            message.job_id.into(),
+            &WorkerJobResult {
+                success: message.success,
+                yubikey_serial: &message.yubikey_serial,
+                ssh_key: &message.ssh_key,
+                public_key: &message.public_key,
+                error: &message.error,
+            },
+        )
+        .await
+        .map_err(|_| Status::internal("Failed to store job result"))?;
+        if stored && !message.success {
+            error!(
+                worker_job_failed = true,
+                "Job {} on worker {} for user {} failed: {}",
+                message.job_id,
+                message.id,
+                username.as_deref().unwrap_or("unknown"),
+                message.error
+            );
+        }
+
         if let Some(username) = username {
             if message.success {
                 match User::find_by_username(&self.pool, &username).await {
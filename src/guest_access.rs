@@ -0,0 +1,177 @@
+//! Time-limited access of guests, e.g. visitors or auditors, to a single location.
+//!
+//! Guest user, enrollment token and membership in a group allowed in the location are
+//! created in one step and recorded along with the admin who sponsored the access.
+//! While the access is active, devices of the guest are only added to that location,
+//! including when other locations are open to all users.
+//! The sponsor is reminded before the access expires. At expiry a background job disables
+//! the guest, ends their sessions, removes them from the group (or removes the group, if it
+//! was created for the guest) and removes their devices from gateways. Access can also be
+//! revoked early.
+
+use std::time::Duration;
+
+use chrono::{Duration as ChronoDuration, Utc};
+use sqlx::{Error as SqlxError, PgConnection};
+use thiserror::Error;
+use tokio::sync::{broadcast::Sender, mpsc::UnboundedSender};
+
+use crate::{
+    db::{
+        models::{
+            enrollment::{Token, TokenError},
+            guest_access::GuestAccess,
+            wireguard::WireguardNetworkError,
+        },
+        DbPool, GatewayEvent, Group, User, WireguardNetwork,
+    },
+    handlers::mail::send_guest_access_expiring_email,
+    jobs::{Job, JobSchedule},
+    mail::Mail,
+    templates::TemplateError,
+};
+
+// How often guest access is checked for expiry
+const GUEST_ACCESS_INTERVAL: Duration = Duration::from_secs(60);
+// How long before expiry the sponsor is reminded
+const EXPIRY_NOTICE_HOURS: i64 = 24;
+
+#[derive(Debug, Error)]
+pub enum GuestAccessError {
+    #[error(transparent)]
+    DbError(#[from] SqlxError),
+    #[error(transparent)]
+    NetworkError(#[from] WireguardNetworkError),
+    #[error(transparent)]
+    TokenError(#[from] TokenError),
+    #[error(transparent)]
+    TemplateError(#[from] TemplateError),
+}
+
+// Disable the guest and take away access given by the group. Gateways have to be synced
+// afterwards.
+async fn end_access(
+    transaction: &mut PgConnection,
+    access: &mut GuestAccess,
+) -> Result<(), GuestAccessError> {
+    if let Some(mut user) = User::find_by_id(&mut *transaction, access.user_id).await? {
+        user.logout_all_sessions(&mut *transaction).await?;
+        Token::delete_unused_user_tokens(&mut *transaction, access.user_id).await?;
+        user.is_active = false;
+        user.save(&mut *transaction).await?;
+        if let Some(group) = Group::find_by_name(&mut *transaction, &access.group_name).await? {
+            if access.group_created {
+                group.delete(&mut *transaction).await?;
+            } else {
+                user.remove_from_group(&mut *transaction, &group).await?;
+            }
+        }
+        info!(
+            "Guest access of user {} to location {} ended",
+            user.username, access.network_id
+        );
+    }
+    access
+        .mark_ended(&mut *transaction, Utc::now().naive_utc())
+        .await?;
+    Ok(())
+}
+
+/// End guest access before it expires. Returns gateway events to send.
+pub async fn revoke_guest_access(
+    pool: &DbPool,
+    access: &mut GuestAccess,
+) -> Result<Vec<GatewayEvent>, GuestAccessError> {
+    let mut transaction = pool.begin().await?;
+    end_access(&mut transaction, access).await?;
+    let events = WireguardNetwork::sync_all_networks_consolidated(&mut transaction).await?;
+    transaction.commit().await?;
+    Ok(events)
+}
+
+/// End expired guest access and remove devices of guests from gateways.
+/// Returns number of ended accesses.
+pub async fn expire_guest_access(
+    pool: &DbPool,
+    wireguard_tx: &Sender<GatewayEvent>,
+) -> Result<usize, GuestAccessError> {
+    let mut transaction = pool.begin().await?;
+    let due = GuestAccess::expiring(&mut *transaction, Utc::now().naive_utc()).await?;
+    if due.is_empty() {
+        return Ok(0);
+    }
+    let count = due.len();
+    for mut access in due {
+        end_access(&mut transaction, &mut access).await?;
+    }
+    let events = WireguardNetwork::sync_all_networks_consolidated(&mut transaction).await?;
+    transaction.commit().await?;
+    for event in events {
+        if let Err(err) = wireguard_tx.send(event) {
+            error!("Failed to send gateway event: {err}");
+        }
+    }
+
+    Ok(count)
+}
+
+/// Remind sponsors of guest access expiring soon. Each sponsor is reminded once per access.
+/// Returns number of sent reminders.
+pub async fn notify_sponsors(
+    pool: &DbPool,
+    mail_tx: &UnboundedSender<Mail>,
+) -> Result<usize, GuestAccessError> {
+    let now = Utc::now().naive_utc();
+    let mut notified = 0;
+    for mut access in
+        GuestAccess::expiring(pool, now + ChronoDuration::hours(EXPIRY_NOTICE_HOURS)).await?
+    {
+        if access.notified_at.is_some() || access.expires_at <= now {
+            continue;
+        }
+        let Some(sponsor_id) = access.sponsor_id else {
+            continue;
+        };
+        let (Some(sponsor), Some(guest), Some(network)) = (
+            User::find_by_id(pool, sponsor_id).await?,
+            User::find_by_id(pool, access.user_id).await?,
+            WireguardNetwork::find_by_id(pool, access.network_id).await?,
+        ) else {
+            continue;
+        };
+        send_guest_access_expiring_email(
+            &guest.username,
+            &network.name,
+            &access.expires_at,
+            &sponsor.email,
+            mail_tx,
+        )?;
+        access.mark_notified(pool, now).await?;
+        notified += 1;
+    }
+
+    Ok(notified)
+}
+
+/// Background job reminding sponsors and ending guest access at expiry.
+#[must_use]
+pub fn guest_access_job(
+    pool: DbPool,
+    wireguard_tx: Sender<GatewayEvent>,
+    mail_tx: UnboundedSender<Mail>,
+) -> Job {
+    Job::new(
+        "guest_access",
+        JobSchedule::Interval(GUEST_ACCESS_INTERVAL),
+        move || {
+            let pool = pool.clone();
+            let wireguard_tx = wireguard_tx.clone();
+            let mail_tx = mail_tx.clone();
+            async move {
+                notify_sponsors(&pool, &mail_tx).await?;
+                expire_guest_access(&pool, &wireguard_tx).await?;
+                Ok(())
+            }
+        },
+    )
+}
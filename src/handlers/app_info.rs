@@ -17,7 +17,7 @@ pub(crate) async fn get_app_info(
     State(appstate): State<AppState>,
     _session: SessionInfo,
 ) -> ApiResult {
-    let networks = WireguardNetwork::all(&appstate.pool).await?;
+    let networks = WireguardNetwork::all_active(&appstate.pool).await?;
     let settings = Settings::get_settings(&appstate.pool).await?;
     let res = AppInfo {
         network_present: !networks.is_empty(),
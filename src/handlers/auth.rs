@@ -1,8 +1,7 @@
 use axum::{
     extract::{Json, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
 };
-use axum_client_ip::{InsecureClientIp, LeftmostXForwardedFor};
 use axum_extra::{
     extract::{
         cookie::{Cookie, CookieJar, SameSite},
@@ -19,36 +18,119 @@ use webauthn_rs_proto::options::CollectedClientData;
 
 use super::{
     ApiResponse, ApiResult, Auth, AuthCode, AuthResponse, AuthTotp, RecoveryCode, RecoveryCodes,
-    WalletAddress, WalletSignature, WebAuthnRegistration, SESSION_COOKIE_NAME,
+    RecoveryCodesStatus, WalletAddress, WalletSignature, Web3Challenge, WebAuthnRegistration,
+    SESSION_COOKIE_NAME,
 };
 use crate::{
     appstate::AppState,
     auth::{
+        challenge::{challenge_solution, check_challenge, under_pressure, ChallengePolicy},
         failed_login::{check_username, log_failed_login_attempt},
         SessionInfo,
     },
+    break_glass::{check_break_glass_login, notify_break_glass_login},
     db::{MFAInfo, MFAMethod, Session, SessionState, Settings, User, UserInfo, Wallet, WebAuthn},
     error::WebError,
+    expired_cleanup::remove_expired_sessions,
     handlers::{
         mail::{
             send_email_mfa_activation_email, send_email_mfa_code_email, send_mfa_configured_email,
         },
         SIGN_IN_COOKIE_NAME,
     },
-    headers::{check_new_device_login, get_user_agent_device, parse_user_agent},
+    headers::{check_new_device_login, get_user_agent_device, parse_user_agent, ClientIp},
     ldap::utils::user_from_ldap,
+    mfa_policy::{
+        ensure_mfa_method_allowed, ensure_mfa_method_usable, ensure_recovery_codes_allowed,
+    },
     server_config,
 };
 
+/// Build auth cookie for a given session
+pub(crate) fn session_cookie(session_id: String) -> Cookie<'static> {
+    let max_age = Duration::seconds(server_config().auth_cookie_timeout.as_secs() as i64);
+    let config = server_config();
+    Cookie::build((SESSION_COOKIE_NAME, session_id))
+        .domain(
+            config
+                .cookie_domain
+                .clone()
+                .expect("Cookie domain not found"),
+        )
+        .path(config.cookie_path())
+        .http_only(true)
+        .secure(!config.cookie_insecure)
+        .same_site(SameSite::Lax)
+        .max_age(max_age)
+        .build()
+}
+
+/// Cookie removing the one with a given name, set under the path defguard is served at.
+pub(crate) fn removal_cookie(name: &'static str) -> Cookie<'static> {
+    Cookie::build(name)
+        .path(server_config().cookie_path())
+        .build()
+}
+
+/// Impersonation sessions are read-only, they can't be used to pass MFA.
+fn ensure_not_impersonating(session: &Session) -> Result<(), WebError> {
+    if let Some(impersonator_id) = session.impersonator_id {
+        warn!(
+            "User {impersonator_id} tried to use MFA while impersonating user {}",
+            session.user_id
+        );
+        return Err(WebError::Forbidden(
+            "Changes are not allowed while impersonating a user".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Challenge to solve before logging in or starting enrollment, with a fresh nonce
+/// for proof of work. `required` is only set while the challenge is enforced.
+#[utoipa::path(
+    get,
+    path = "/api/v1/auth/challenge",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Challenge details", body = ChallengeInfo),
+    ),
+    security(())
+)]
+pub async fn auth_challenge(State(appstate): State<AppState>) -> ApiResult {
+    let policy = ChallengePolicy::load(&appstate.pool).await?;
+    let under_pressure = policy.on_pressure
+        && under_pressure(&appstate.failed_logins, &appstate.failed_tokens, None);
+    Ok(ApiResponse {
+        json: json!(policy.info(policy.is_required(under_pressure))),
+        status: StatusCode::OK,
+    })
+}
+
 /// For successful login, return:
 /// * 200 with MFA disabled
 /// * 201 with MFA enabled when additional authentication factor is required
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth",
+    tag = "auth",
+    request_body = Auth,
+    responses(
+        (status = 200, description = "Logged in", body = AuthResponse),
+        (status = 201, description = "Additional authentication factor required", body = MFAInfo),
+        (status = 401, description = "Invalid credentials", body = ApiError),
+        (status = 403, description = "Break-glass account can't be used while regular admins are active, or challenge solution is missing", body = ApiError),
+        (status = 429, description = "Too many login attempts", body = ApiError),
+        (status = 503, description = "CAPTCHA provider unavailable", body = ApiError),
+    ),
+    security(())
+)]
 pub async fn authenticate(
     cookies: CookieJar,
     private_cookies: PrivateCookieJar,
     user_agent: Option<TypedHeader<UserAgent>>,
-    forwarded_for_ip: Option<LeftmostXForwardedFor>,
-    InsecureClientIp(insecure_ip): InsecureClientIp,
+    headers: HeaderMap,
+    ClientIp(client_ip): ClientIp,
     State(appstate): State<AppState>,
     Json(data): Json<Auth>,
 ) -> Result<(CookieJar, PrivateCookieJar, ApiResponse), WebError> {
@@ -56,6 +138,15 @@ pub async fn authenticate(
     debug!("Authenticating user {username}");
     // check if user can proceed with login
     check_username(&appstate.failed_logins, &username)?;
+    check_challenge(
+        &appstate.pool,
+        &appstate.failed_logins,
+        &appstate.failed_tokens,
+        challenge_solution(&headers),
+        client_ip,
+        Some(&username),
+    )
+    .await?;
 
     let user = match User::find_by_username(&appstate.pool, &username).await {
         Ok(Some(user)) => match user.verify_password(&data.password) {
@@ -68,7 +159,7 @@ pub async fn authenticate(
                 }
             }
             Err(err) => {
-                info!("Failed to authenticate user {username}: {err}");
+                info!("Failed to authenticate user {username} from {client_ip}: {err}");
                 log_failed_login_attempt(&appstate.failed_logins, &username);
                 return Err(WebError::Authorization(err.to_string()));
             }
@@ -79,7 +170,7 @@ pub async fn authenticate(
             if let Ok(user) = user_from_ldap(&appstate.pool, &username, &data.password).await {
                 user
             } else {
-                info!("Failed to authenticate user {username} with LDAP");
+                info!("Failed to authenticate user {username} from {client_ip} with LDAP");
                 log_failed_login_attempt(&appstate.failed_logins, &username);
                 return Err(WebError::Authorization("user not found".into()));
             }
@@ -90,7 +181,11 @@ pub async fn authenticate(
         }
     };
 
-    let ip_address = forwarded_for_ip.map_or(insecure_ip, |v| v.0).to_string();
+    if user.break_glass {
+        check_break_glass_login(&appstate.pool, &user).await?;
+    }
+
+    let ip_address = client_ip.to_string();
     let user_agent_string = match user_agent {
         Some(value) => value.to_string(),
         None => String::new(),
@@ -99,40 +194,39 @@ pub async fn authenticate(
     let device_info = agent.clone().map(|v| get_user_agent_device(&v));
 
     debug!("Cleaning up expired sessions...");
-    Session::delete_expired(&appstate.pool).await?;
+    remove_expired_sessions(&appstate.pool).await?;
     debug!("Expired sessions cleaned up");
 
     debug!("Creating new session for user {username}");
-    let session = Session::new(
-        user.id.unwrap(),
-        SessionState::PasswordVerified,
-        ip_address.clone(),
-        device_info,
-    );
+    let session = if user.break_glass {
+        notify_break_glass_login(&appstate.pool, &appstate.mail_tx, &user, &ip_address)?;
+        Session::new_break_glass(
+            user.id.unwrap(),
+            SessionState::PasswordVerified,
+            ip_address.clone(),
+            device_info,
+        )
+    } else {
+        Session::new(
+            user.id.unwrap(),
+            SessionState::PasswordVerified,
+            ip_address.clone(),
+            device_info,
+        )
+    };
     session.save(&appstate.pool).await?;
     debug!("New session created for user {username}");
 
-    let max_age = Duration::seconds(server_config().auth_cookie_timeout.as_secs() as i64);
-    let config = server_config();
-    let auth_cookie = Cookie::build((SESSION_COOKIE_NAME, session.id.clone()))
-        .domain(
-            config
-                .cookie_domain
-                .clone()
-                .expect("Cookie domain not found"),
-        )
-        .path("/")
-        .http_only(true)
-        .secure(!config.cookie_insecure)
-        .same_site(SameSite::Lax)
-        .max_age(max_age);
-    let cookies = cookies.add(auth_cookie);
+    let cookies = cookies.add(session_cookie(session.id.clone()));
 
     let login_event_type = "AUTHENTICATION".to_string();
 
     info!("Authenticated user {username}");
     if user.mfa_enabled {
-        if let Some(mfa_info) = MFAInfo::for_user(&appstate.pool, &user).await? {
+        if let Some(mut mfa_info) = MFAInfo::for_user(&appstate.pool, &user).await? {
+            // don't offer methods disabled in settings
+            let settings = Settings::get_settings(&appstate.pool).await?;
+            mfa_info.restrict_to(&settings);
             check_new_device_login(
                 &appstate.pool,
                 &appstate.mail_tx,
@@ -156,6 +250,7 @@ pub async fn authenticate(
             Err(WebError::DbError("MFA info read error".into()))
         }
     } else {
+        User::record_login(&appstate.pool, session.user_id).await?;
         let user_info = UserInfo::from_user(&appstate.pool, &user).await?;
 
         check_new_device_login(
@@ -174,7 +269,7 @@ pub async fn authenticate(
             let redirect_url = openid_cookie.value().to_string();
             Ok((
                 cookies,
-                private_cookies.remove(openid_cookie),
+                private_cookies.remove(removal_cookie(SIGN_IN_COOKIE_NAME)),
                 ApiResponse {
                     json: json!(AuthResponse {
                         user: user_info,
@@ -201,19 +296,71 @@ pub async fn authenticate(
 }
 
 /// Logout - forget the session cookie.
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/logout",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Logged out"),
+    )
+)]
 pub async fn logout(
     cookies: CookieJar,
     session: Session,
     State(appstate): State<AppState>,
 ) -> Result<(CookieJar, ApiResponse), WebError> {
     // remove auth cookie
-    let cookies = cookies.remove(Cookie::from(SESSION_COOKIE_NAME));
+    let cookies = cookies.remove(removal_cookie(SESSION_COOKIE_NAME));
     // remove stored session
     session.delete(&appstate.pool).await?;
     Ok((cookies, ApiResponse::default()))
 }
 
+/// End impersonation and switch back to impersonating admin's session, if it's still valid.
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/impersonate/end",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Switched back to the admin session or logged out if it expired"),
+        (status = 400, description = "Not impersonating any user", body = ApiError),
+    )
+)]
+pub async fn end_impersonation(
+    cookies: CookieJar,
+    session: Session,
+    State(appstate): State<AppState>,
+) -> Result<(CookieJar, ApiResponse), WebError> {
+    let Some(admin_session_id) = session.impersonator_session_id.clone() else {
+        return Err(WebError::BadRequest("Not impersonating any user".into()));
+    };
+    debug!(
+        "Ending impersonation of user {} by user {:?}",
+        session.user_id, session.impersonator_id
+    );
+    session.delete(&appstate.pool).await?;
+    let cookies = match Session::find_by_id(&appstate.pool, &admin_session_id).await? {
+        Some(admin_session) if !admin_session.expired() => {
+            cookies.add(session_cookie(admin_session.id))
+        }
+        _ => {
+            info!("Admin session expired during impersonation, logging out");
+            cookies.remove(removal_cookie(SESSION_COOKIE_NAME))
+        }
+    };
+    Ok((cookies, ApiResponse::default()))
+}
+
 /// Enable MFA
+#[utoipa::path(
+    put,
+    path = "/api/v1/auth/mfa",
+    tag = "auth",
+    responses(
+        (status = 200, description = "MFA enabled, all sessions of the user are logged out"),
+        (status = 304, description = "No MFA method configured"),
+    )
+)]
 pub async fn mfa_enable(
     cookies: CookieJar,
     _session: Session,
@@ -225,7 +372,7 @@ pub async fn mfa_enable(
     user.enable_mfa(&appstate.pool).await?;
     if user.mfa_enabled {
         info!("Enabled MFA for user {}", user.username);
-        let cookies = cookies.remove(Cookie::from("defguard_sesssion"));
+        let cookies = cookies.remove(removal_cookie(SESSION_COOKIE_NAME));
         user.logout_all_sessions(&appstate.pool).await?;
         debug!(
             "Removed auth sessions for user {} after enabling MFA",
@@ -239,6 +386,15 @@ pub async fn mfa_enable(
 }
 
 /// Disable MFA
+#[utoipa::path(
+    delete,
+    path = "/api/v1/auth/mfa",
+    tag = "auth",
+    responses(
+        (status = 200, description = "MFA disabled"),
+        (status = 401, description = "Not logged in", body = ApiError),
+    )
+)]
 pub async fn mfa_disable(session_info: SessionInfo, State(appstate): State<AppState>) -> ApiResult {
     let mut user = session_info.user;
     debug!("Disabling MFA for user {}", user.username);
@@ -248,6 +404,16 @@ pub async fn mfa_disable(session_info: SessionInfo, State(appstate): State<AppSt
 }
 
 /// Initialize WebAuthn registration
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/webauthn/init",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Passkey registration challenge as defined by the WebAuthn specification", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ApiError),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+    )
+)]
 pub async fn webauthn_init(
     mut session_info: SessionInfo,
     State(appstate): State<AppState>,
@@ -257,6 +423,7 @@ pub async fn webauthn_init(
         "Initializing WebAuthn registration for user {}",
         user.username
     );
+    ensure_mfa_method_allowed(&appstate.pool, &MFAMethod::Webauthn).await?;
     // passkeys to exclude
     let passkeys =
         WebAuthn::passkeys_for_user(&appstate.pool, user.id.expect("User ID missing")).await?;
@@ -285,6 +452,17 @@ pub async fn webauthn_init(
 }
 
 /// Finish WebAuthn registration
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/webauthn/finish",
+    tag = "auth",
+    request_body(content = serde_json::Value, description = "Security key name and registration response as defined by the WebAuthn specification"),
+    responses(
+        (status = 200, description = "Security key registered", body = RecoveryCodes),
+        (status = 401, description = "Not logged in", body = ApiError),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+    )
+)]
 pub async fn webauthn_finish(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -294,6 +472,7 @@ pub async fn webauthn_finish(
         "Finishing WebAuthn registration for user {}",
         session.user.username
     );
+    ensure_mfa_method_allowed(&appstate.pool, &MFAMethod::Webauthn).await?;
     let passkey_reg =
         session
             .session
@@ -352,7 +531,19 @@ pub async fn webauthn_finish(
 }
 
 /// Start WebAuthn authentication
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/webauthn/start",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Authentication challenge as defined by the WebAuthn specification", body = serde_json::Value),
+        (status = 400, description = "No security keys registered"),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+    )
+)]
 pub async fn webauthn_start(mut session: Session, State(appstate): State<AppState>) -> ApiResult {
+    ensure_not_impersonating(&session)?;
+    ensure_mfa_method_usable(&appstate.pool, &MFAMethod::Webauthn).await?;
     let passkeys = WebAuthn::passkeys_for_user(&appstate.pool, session.user_id).await?;
 
     match appstate.webauthn.start_passkey_authentication(&passkeys) {
@@ -370,12 +561,25 @@ pub async fn webauthn_start(mut session: Session, State(appstate): State<AppStat
 }
 
 /// Finish WebAuthn authentication
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/webauthn",
+    tag = "auth",
+    request_body(content = serde_json::Value, description = "WebAuthn credential as defined by the WebAuthn specification"),
+    responses(
+        (status = 200, description = "Logged in", body = AuthResponse),
+        (status = 400, description = "Authentication failed"),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+    )
+)]
 pub async fn webauthn_end(
     private_cookies: PrivateCookieJar,
     mut session: Session,
     State(appstate): State<AppState>,
     Json(pubkey): Json<PublicKeyCredential>,
 ) -> Result<(PrivateCookieJar, ApiResponse), WebError> {
+    ensure_not_impersonating(&session)?;
+    ensure_mfa_method_usable(&appstate.pool, &MFAMethod::Webauthn).await?;
     if let Some(passkey_auth) = session.get_passkey_authentication() {
         if let Ok(auth_result) = appstate
             .webauthn
@@ -397,7 +601,8 @@ pub async fn webauthn_end(
                 if let Some(openid_cookie) = private_cookies.get(SIGN_IN_COOKIE_NAME) {
                     debug!("Found OpenID session cookie.");
                     let redirect_url = openid_cookie.value().to_string();
-                    let private_cookies = private_cookies.remove(openid_cookie);
+                    let private_cookies =
+                        private_cookies.remove(removal_cookie(SIGN_IN_COOKIE_NAME));
                     Ok((
                         private_cookies,
                         ApiResponse {
@@ -429,9 +634,20 @@ pub async fn webauthn_end(
 }
 
 /// Generate new TOTP secret
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/totp/init",
+    tag = "auth",
+    responses(
+        (status = 200, description = "New TOTP secret", body = AuthTotp),
+        (status = 401, description = "Not logged in", body = ApiError),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+    )
+)]
 pub async fn totp_secret(session: SessionInfo, State(appstate): State<AppState>) -> ApiResult {
     let mut user = session.user;
     debug!("Generating new TOTP secret for user {}", user.username);
+    ensure_mfa_method_allowed(&appstate.pool, &MFAMethod::OneTimePassword).await?;
 
     let secret = user.new_totp_secret(&appstate.pool).await?;
     info!("Generated new TOTP secret for user {}", user.username);
@@ -442,6 +658,17 @@ pub async fn totp_secret(session: SessionInfo, State(appstate): State<AppState>)
 }
 
 /// Enable TOTP
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/totp",
+    tag = "auth",
+    request_body = AuthCode,
+    responses(
+        (status = 200, description = "TOTP enabled", body = RecoveryCodes),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+        (status = 404, description = "Invalid TOTP code", body = ApiError),
+    )
+)]
 pub async fn totp_enable(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -449,6 +676,7 @@ pub async fn totp_enable(
 ) -> ApiResult {
     let mut user = session.user;
     debug!("Enabling TOTP for user {}", user.username);
+    ensure_mfa_method_allowed(&appstate.pool, &MFAMethod::OneTimePassword).await?;
     if user.verify_totp_code(data.code) {
         let recovery_codes = RecoveryCodes::new(user.get_recovery_codes(&appstate.pool).await?);
         user.enable_totp(&appstate.pool).await?;
@@ -474,6 +702,15 @@ pub async fn totp_enable(
 }
 
 /// Disable TOTP
+#[utoipa::path(
+    delete,
+    path = "/api/v1/auth/totp",
+    tag = "auth",
+    responses(
+        (status = 200, description = "TOTP disabled"),
+        (status = 401, description = "Not logged in", body = ApiError),
+    )
+)]
 pub async fn totp_disable(session: SessionInfo, State(appstate): State<AppState>) -> ApiResult {
     let mut user = session.user;
     debug!("Disabling TOTP for user {}", user.username);
@@ -484,15 +721,28 @@ pub async fn totp_disable(session: SessionInfo, State(appstate): State<AppState>
 }
 
 /// Validate one-time passcode
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/totp/verify",
+    tag = "auth",
+    request_body = AuthCode,
+    responses(
+        (status = 200, description = "Logged in", body = AuthResponse),
+        (status = 401, description = "Invalid TOTP code", body = ApiError),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+    )
+)]
 pub async fn totp_code(
     private_cookies: PrivateCookieJar,
     mut session: Session,
     State(appstate): State<AppState>,
     Json(data): Json<AuthCode>,
 ) -> Result<(PrivateCookieJar, ApiResponse), WebError> {
+    ensure_not_impersonating(&session)?;
     if let Some(user) = User::find_by_id(&appstate.pool, session.user_id).await? {
         let username = user.username.clone();
         debug!("Verifying TOTP for user {}", username);
+        ensure_mfa_method_usable(&appstate.pool, &MFAMethod::OneTimePassword).await?;
         if user.totp_enabled && user.verify_totp_code(data.code) {
             session
                 .set_state(&appstate.pool, SessionState::MultiFactorVerified)
@@ -502,7 +752,7 @@ pub async fn totp_code(
             if let Some(openid_cookie) = private_cookies.get(SIGN_IN_COOKIE_NAME) {
                 debug!("Found openid session cookie.");
                 let redirect_url = openid_cookie.value().to_string();
-                let private_cookies = private_cookies.remove(openid_cookie);
+                let private_cookies = private_cookies.remove(removal_cookie(SIGN_IN_COOKIE_NAME));
                 Ok((
                     private_cookies,
                     ApiResponse {
@@ -534,7 +784,18 @@ pub async fn totp_code(
 }
 
 /// Initialize email MFA setup
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/email/init",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Activation code sent to the user email"),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+        (status = 500, description = "SMTP not configured", body = ApiError),
+    )
+)]
 pub async fn email_mfa_init(session: SessionInfo, State(appstate): State<AppState>) -> ApiResult {
+    ensure_mfa_method_allowed(&appstate.pool, &MFAMethod::Email).await?;
     // check if SMTP is configured
     let settings = Settings::get_settings(&appstate.pool).await?;
     if !settings.smtp_configured() {
@@ -555,6 +816,17 @@ pub async fn email_mfa_init(session: SessionInfo, State(appstate): State<AppStat
 }
 
 /// Enable email MFA
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/email",
+    tag = "auth",
+    request_body = AuthCode,
+    responses(
+        (status = 200, description = "Email MFA enabled", body = RecoveryCodes),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+        (status = 404, description = "Invalid email code", body = ApiError),
+    )
+)]
 pub async fn email_mfa_enable(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -562,6 +834,7 @@ pub async fn email_mfa_enable(
 ) -> ApiResult {
     let mut user = session.user;
     debug!("Enabling email MFA for user {}", user.username);
+    ensure_mfa_method_allowed(&appstate.pool, &MFAMethod::Email).await?;
     if user.verify_email_mfa_code(data.code) {
         let recovery_codes = RecoveryCodes::new(user.get_recovery_codes(&appstate.pool).await?);
         user.enable_email_mfa(&appstate.pool).await?;
@@ -587,6 +860,15 @@ pub async fn email_mfa_enable(
 }
 
 /// Disable email MFA
+#[utoipa::path(
+    delete,
+    path = "/api/v1/auth/email",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Email MFA disabled"),
+        (status = 401, description = "Not logged in", body = ApiError),
+    )
+)]
 pub async fn email_mfa_disable(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -600,12 +882,24 @@ pub async fn email_mfa_disable(
 }
 
 /// Send email code to user
+#[utoipa::path(
+    get,
+    path = "/api/v1/auth/email",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Code sent to the user email"),
+        (status = 401, description = "Email MFA not enabled", body = ApiError),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+    )
+)]
 pub async fn request_email_mfa_code(
     session: Session,
     State(appstate): State<AppState>,
 ) -> ApiResult {
+    ensure_not_impersonating(&session)?;
     if let Some(user) = User::find_by_id(&appstate.pool, session.user_id).await? {
         debug!("Sending email MFA code for user {}", user.username);
+        ensure_mfa_method_usable(&appstate.pool, &MFAMethod::Email).await?;
         if user.email_mfa_enabled {
             send_email_mfa_code_email(&user, &appstate.mail_tx, Some(&session))?;
             info!("Sent email MFA code for user {}", user.username);
@@ -619,15 +913,28 @@ pub async fn request_email_mfa_code(
 }
 
 /// Validate email MFA code
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/email/verify",
+    tag = "auth",
+    request_body = AuthCode,
+    responses(
+        (status = 200, description = "Logged in", body = AuthResponse),
+        (status = 401, description = "Invalid email MFA code", body = ApiError),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+    )
+)]
 pub async fn email_mfa_code(
     private_cookies: PrivateCookieJar,
     mut session: Session,
     State(appstate): State<AppState>,
     Json(data): Json<AuthCode>,
 ) -> Result<(PrivateCookieJar, ApiResponse), WebError> {
+    ensure_not_impersonating(&session)?;
     if let Some(user) = User::find_by_id(&appstate.pool, session.user_id).await? {
         let username = user.username.clone();
         debug!("Verifying email MFA code for user {}", username);
+        ensure_mfa_method_usable(&appstate.pool, &MFAMethod::Email).await?;
         if user.email_mfa_enabled && user.verify_email_mfa_code(data.code) {
             session
                 .set_state(&appstate.pool, SessionState::MultiFactorVerified)
@@ -637,7 +944,7 @@ pub async fn email_mfa_code(
             if let Some(openid_cookie) = private_cookies.get(SIGN_IN_COOKIE_NAME) {
                 debug!("Found openid session cookie.");
                 let redirect_url = openid_cookie.value().to_string();
-                let private_cookies = private_cookies.remove(openid_cookie);
+                let private_cookies = private_cookies.remove(removal_cookie(SIGN_IN_COOKIE_NAME));
                 Ok((
                     private_cookies,
                     ApiResponse {
@@ -669,12 +976,24 @@ pub async fn email_mfa_code(
 }
 
 /// Start Web3 authentication
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/web3/start",
+    tag = "auth",
+    request_body = WalletAddress,
+    responses(
+        (status = 200, description = "Message to be signed with the wallet", body = Web3Challenge),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+    )
+)]
 pub async fn web3auth_start(
     mut session: Session,
     State(appstate): State<AppState>,
     Json(data): Json<WalletAddress>,
 ) -> ApiResult {
+    ensure_not_impersonating(&session)?;
     debug!("Starting web3 authentication for wallet {}", data.address);
+    ensure_mfa_method_usable(&appstate.pool, &MFAMethod::Web3).await?;
     match Settings::find_by_id(&appstate.pool, 1).await? {
         Some(settings) => {
             let challenge = Wallet::format_challenge(&data.address, &settings.challenge_template);
@@ -683,7 +1002,7 @@ pub async fn web3auth_start(
                 .await?;
             info!("Started web3 authentication for wallet {}", data.address);
             Ok(ApiResponse {
-                json: json!({ "challenge": challenge }),
+                json: json!(Web3Challenge { challenge }),
                 status: StatusCode::OK,
             })
         }
@@ -692,16 +1011,30 @@ pub async fn web3auth_start(
 }
 
 /// Finish Web3 authentication
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/web3",
+    tag = "auth",
+    request_body = WalletSignature,
+    responses(
+        (status = 200, description = "Logged in", body = AuthResponse),
+        (status = 400, description = "Wallet not found"),
+        (status = 401, description = "Signature not verified", body = ApiError),
+        (status = 403, description = "Method disabled in settings", body = ApiError),
+    )
+)]
 pub async fn web3auth_end(
     private_cookies: PrivateCookieJar,
     mut session: Session,
     State(appstate): State<AppState>,
     Json(signature): Json<WalletSignature>,
 ) -> Result<(PrivateCookieJar, ApiResponse), WebError> {
+    ensure_not_impersonating(&session)?;
     debug!(
         "Finishing web3 authentication for wallet {}",
         signature.address
     );
+    ensure_mfa_method_usable(&appstate.pool, &MFAMethod::Web3).await?;
     if let Some(ref challenge) = session.web3_challenge {
         if let Some(wallet) =
             Wallet::find_by_user_and_address(&appstate.pool, session.user_id, &signature.address)
@@ -725,7 +1058,8 @@ pub async fn web3auth_end(
                             if let Some(openid_cookie) = private_cookies.get(SIGN_IN_COOKIE_NAME) {
                                 debug!("Found openid session cookie.");
                                 let redirect_url = openid_cookie.value().to_string();
-                                let private_cookies = private_cookies.remove(openid_cookie);
+                                let private_cookies =
+                                    private_cookies.remove(removal_cookie(SIGN_IN_COOKIE_NAME));
                                 Ok((
                                     private_cookies,
                                     ApiResponse {
@@ -761,15 +1095,28 @@ pub async fn web3auth_end(
 }
 
 /// Authenticate with a recovery code.
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/recovery",
+    tag = "auth",
+    request_body = RecoveryCode,
+    responses(
+        (status = 200, description = "Logged in", body = AuthResponse),
+        (status = 401, description = "Invalid recovery code"),
+        (status = 403, description = "Recovery codes disabled in settings", body = ApiError),
+    )
+)]
 pub async fn recovery_code(
     private_cookies: PrivateCookieJar,
     mut session: Session,
     State(appstate): State<AppState>,
     Json(recovery_code): Json<RecoveryCode>,
 ) -> Result<(PrivateCookieJar, ApiResponse), WebError> {
+    ensure_not_impersonating(&session)?;
     if let Some(mut user) = User::find_by_id(&appstate.pool, session.user_id).await? {
         let username = user.username.clone();
         debug!("Authenticating user {} with recovery code", username);
+        ensure_recovery_codes_allowed(&appstate.pool).await?;
         if user
             .verify_recovery_code(&appstate.pool, &recovery_code.code)
             .await?
@@ -782,7 +1129,7 @@ pub async fn recovery_code(
             if let Some(openid_cookie) = private_cookies.get(SIGN_IN_COOKIE_NAME) {
                 debug!("Found OpenID session cookie.");
                 let redirect_url = openid_cookie.value().to_string();
-                let private_cookies = private_cookies.remove(openid_cookie);
+                let private_cookies = private_cookies.remove(removal_cookie(SIGN_IN_COOKIE_NAME));
                 return Ok((
                     private_cookies,
                     ApiResponse {
@@ -809,3 +1156,75 @@ pub async fn recovery_code(
     }
     Err(WebError::Http(StatusCode::UNAUTHORIZED))
 }
+
+/// Return the number of unused recovery codes and when the used ones were consumed.
+#[utoipa::path(
+    get,
+    path = "/api/v1/me/mfa/recovery",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Recovery codes status", body = RecoveryCodesStatus),
+        (status = 401, description = "Not logged in", body = ApiError),
+    )
+)]
+pub async fn recovery_codes_status(
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+) -> ApiResult {
+    let user = session.user;
+    debug!("Fetching recovery codes status for user {}", user.username);
+    let status = RecoveryCodesStatus {
+        remaining: user.recovery_codes.len(),
+        used_at: user.recovery_codes_used_at(&appstate.pool).await?,
+    };
+    Ok(ApiResponse {
+        json: json!(status),
+        status: StatusCode::OK,
+    })
+}
+
+/// Replace recovery codes with a fresh set. Requires a current TOTP or email MFA code.
+/// New codes are returned only in this response.
+#[utoipa::path(
+    post,
+    path = "/api/v1/me/mfa/recovery/regenerate",
+    tag = "auth",
+    request_body = AuthCode,
+    responses(
+        (status = 200, description = "New recovery codes", body = RecoveryCodes),
+        (status = 400, description = "MFA is not enabled", body = ApiError),
+        (status = 401, description = "Invalid MFA code", body = ApiError),
+        (status = 403, description = "Recovery codes disabled in settings", body = ApiError),
+    )
+)]
+pub async fn regenerate_recovery_codes(
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Json(data): Json<AuthCode>,
+) -> ApiResult {
+    let mut user = session.user;
+    let username = user.username.clone();
+    debug!("Regenerating recovery codes for user {username}");
+    if !user.mfa_enabled {
+        return Err(WebError::BadRequest("MFA is not enabled".into()));
+    }
+    ensure_recovery_codes_allowed(&appstate.pool).await?;
+    let settings = Settings::get_settings(&appstate.pool).await?;
+    let verified = (user.totp_enabled
+        && settings.mfa_method_usable(&MFAMethod::OneTimePassword)
+        && user.verify_totp_code(data.code))
+        || (user.email_mfa_enabled
+            && settings.mfa_method_usable(&MFAMethod::Email)
+            && user.verify_email_mfa_code(data.code));
+    if !verified {
+        warn!("Failed to regenerate recovery codes for user {username}: invalid MFA code");
+        return Err(WebError::Authorization("Invalid MFA code".into()));
+    }
+
+    let recovery_codes = user.regenerate_recovery_codes(&appstate.pool).await?;
+    info!("Regenerated recovery codes for user {username}");
+    Ok(ApiResponse {
+        json: json!(RecoveryCodes::new(Some(recovery_codes))),
+        status: StatusCode::OK,
+    })
+}
@@ -0,0 +1,178 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+    Extension,
+};
+use serde_json::json;
+
+use super::{ApiResponse, ApiResult};
+use crate::{
+    appstate::AppState,
+    auth::{AdminRole, SessionInfo},
+    consistency::run_consistency_checks,
+    db::{Settings, User},
+    diagnostics::{probe_proof, run_diagnostics, DiagnosticsOptions},
+    error::WebError,
+    expired_cleanup::cleanup_metrics,
+    grpc::GatewayMap,
+};
+
+// longer nonces are rejected, the checker sends much shorter ones
+const MAX_NONCE_LENGTH: usize = 64;
+
+#[derive(Deserialize)]
+pub struct ProbeQuery {
+    nonce: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConsistencyQuery {
+    #[serde(default)]
+    repair: bool,
+}
+
+#[derive(Deserialize)]
+pub struct AuthzDenialsQuery {
+    username: String,
+}
+
+/// Run configuration and connectivity checks.
+#[utoipa::path(
+    post,
+    path = "/api/v1/system/diagnostics",
+    tag = "settings",
+    request_body(content = Option<DiagnosticsOptions>, description = "Optional test mail recipient"),
+    responses(
+        (status = 200, description = "Diagnostics report", body = DiagnosticsReport),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
+pub async fn diagnostics(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Extension(gateway_state): Extension<Arc<Mutex<GatewayMap>>>,
+    options: Option<Json<DiagnosticsOptions>>,
+) -> ApiResult {
+    debug!("User {} running diagnostics", session.user.username);
+    let options = options.map(|Json(options)| options).unwrap_or_default();
+    let report = run_diagnostics(&appstate.pool, Some(&gateway_state), &options).await;
+    Ok(ApiResponse {
+        json: json!(report),
+        status: StatusCode::OK,
+    })
+}
+
+/// Detect orphaned and inconsistent database relations, optionally removing the safe cases.
+#[utoipa::path(
+    get,
+    path = "/api/v1/system/consistency",
+    tag = "settings",
+    params(("repair" = Option<bool>, Query, description = "Remove inconsistencies which can be repaired safely")),
+    responses(
+        (status = 200, description = "Consistency report", body = ConsistencyReport),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
+pub async fn consistency(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Query(query): Query<ConsistencyQuery>,
+) -> ApiResult {
+    debug!(
+        "User {} running database consistency checks, repair: {}",
+        session.user.username, query.repair
+    );
+    let report =
+        run_consistency_checks(&appstate.pool, query.repair, &session.user.username).await?;
+    Ok(ApiResponse {
+        json: json!(report),
+        status: StatusCode::OK,
+    })
+}
+
+/// Latest authorization denials of a user, newest first, for troubleshooting 403 responses.
+/// Trace IDs match ones returned in response bodies.
+#[utoipa::path(
+    get,
+    path = "/api/v1/system/authz_denials",
+    tag = "settings",
+    params(("username" = String, Query, description = "User whose requests were denied")),
+    responses(
+        (status = 200, description = "Denials kept in memory since server start", body = [AuthzDenial]),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+        (status = 404, description = "User not found", body = ApiError),
+    )
+)]
+pub async fn authz_denials(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Query(query): Query<AuthzDenialsQuery>,
+) -> ApiResult {
+    debug!(
+        "User {} listing authorization denials of user {}",
+        session.user.username, query.username
+    );
+    let Some(user_id) = User::find_by_username(&appstate.pool, &query.username)
+        .await?
+        .and_then(|user| user.id)
+    else {
+        return Err(WebError::ObjectNotFound(format!(
+            "user {} not found",
+            query.username
+        )));
+    };
+    let denials = appstate
+        .authz_denials
+        .lock()
+        .expect("Failed to lock authorization denials")
+        .for_user(user_id);
+    Ok(ApiResponse {
+        json: json!(denials),
+        status: StatusCode::OK,
+    })
+}
+
+/// Rows removed by cleanup of expired sessions and tokens since server start.
+#[utoipa::path(
+    get,
+    path = "/api/v1/system/cleanup",
+    tag = "settings",
+    responses(
+        (status = 200, description = "Expired sessions and tokens cleanup metrics", body = CleanupSnapshot),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
+pub async fn cleanup_stats(_admin: AdminRole) -> ApiResult {
+    Ok(ApiResponse {
+        json: json!(cleanup_metrics()),
+        status: StatusCode::OK,
+    })
+}
+
+/// Answer public URL check of diagnostics, proving this is the expected instance.
+#[utoipa::path(
+    get,
+    path = "/api/v1/system/probe",
+    tag = "settings",
+    params(("nonce" = String, Query, description = "Random value sent by the checking instance")),
+    responses(
+        (status = 200, description = "Proof derived from the nonce"),
+        (status = 400, description = "Nonce too long", body = ApiError),
+    ),
+    security(())
+)]
+pub async fn probe(State(appstate): State<AppState>, Query(query): Query<ProbeQuery>) -> ApiResult {
+    if query.nonce.len() > MAX_NONCE_LENGTH {
+        return Err(WebError::BadRequest("nonce too long".into()));
+    }
+    let settings = Settings::get_settings(&appstate.pool).await?;
+    Ok(ApiResponse {
+        json: json!({"proof": probe_proof(&settings.uuid, &query.nonce)}),
+        status: StatusCode::OK,
+    })
+}
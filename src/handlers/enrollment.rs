@@ -0,0 +1,361 @@
+//! Browser based enrollment, offered as a fallback for users
+//! who don't have the desktop client installed yet.
+//!
+//! Token validation, session handling and user activation are shared
+//! with the desktop client enrollment gRPC service.
+
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Json, State},
+    http::{HeaderMap, StatusCode},
+};
+use axum_extra::{headers::UserAgent, TypedHeader};
+use serde_json::json;
+use tokio::time::sleep;
+
+use super::{
+    mail::{send_mfa_configured_email, send_new_device_added_email},
+    user::check_password_strength,
+    ApiResponse, ApiResult, AuthTotp, RecoveryCodes,
+};
+use crate::{
+    appstate::AppState,
+    auth::{
+        challenge::{challenge_solution, check_challenge},
+        failed_token::LOCKOUT_RESPONSE_DELAY,
+    },
+    db::{
+        models::{
+            device::{Device, DevicePlatform, MachineHints, PRIVATE_KEY_PLACEHOLDER},
+            enrollment::{Token, TokenError, ENROLLMENT_TOKEN_TYPE},
+        },
+        MFAMethod, Settings, User, WireguardNetwork,
+    },
+    error::WebError,
+    headers::{get_device_info, ClientIp},
+    mfa_policy::ensure_mfa_method_allowed,
+    server_config,
+    templates::TemplateLocation,
+};
+
+#[derive(Deserialize)]
+pub struct WebEnrollmentToken {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct WebEnrollmentActivation {
+    pub token: String,
+    pub password: String,
+    pub phone_number: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct WebEnrollmentCode {
+    pub token: String,
+    pub code: u32,
+}
+
+#[derive(Deserialize)]
+pub struct WebEnrollmentDevice {
+    pub token: String,
+    pub name: String,
+}
+
+async fn ensure_web_enrollment_enabled(appstate: &AppState) -> Result<(), WebError> {
+    let settings = Settings::get_settings(&appstate.pool).await?;
+    if settings.enrollment_web_fallback_enabled {
+        Ok(())
+    } else {
+        Err(WebError::Forbidden("web enrollment is disabled".into()))
+    }
+}
+
+/// Fetch enrollment token with a valid session and the user being enrolled.
+async fn enrollment_session(appstate: &AppState, token: &str) -> Result<(Token, User), WebError> {
+    ensure_web_enrollment_enabled(appstate).await?;
+    let enrollment = Token::find_session(&appstate.pool, token).await?;
+    if enrollment.token_type.as_deref() != Some(ENROLLMENT_TOKEN_TYPE) {
+        return Err(TokenError::NotFound.into());
+    }
+    let user = enrollment.fetch_user(&appstate.pool).await?;
+    if !user.is_active {
+        warn!(
+            "Can't continue enrollment for disabled user {}",
+            user.username
+        );
+        return Err(TokenError::UserDisabled.into());
+    }
+    Ok((enrollment, user))
+}
+
+/// Make sure user has set a password before configuring MFA or devices.
+fn ensure_activated(user: &User) -> Result<(), WebError> {
+    if user.has_password() {
+        Ok(())
+    } else {
+        Err(WebError::BadRequest("user account is not activated".into()))
+    }
+}
+
+fn client_info(
+    appstate: &AppState,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    client_ip: IpAddr,
+) -> (String, Option<String>) {
+    let ip_address = client_ip.to_string();
+    let device_info = user_agent
+        .and_then(|value| get_device_info(&appstate.user_agent_parser, &value.to_string()));
+    (ip_address, device_info)
+}
+
+pub async fn start_web_enrollment(
+    State(appstate): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(data): Json<WebEnrollmentToken>,
+) -> ApiResult {
+    debug!("Starting web enrollment session");
+    ensure_web_enrollment_enabled(&appstate).await?;
+    check_challenge(
+        &appstate.pool,
+        &appstate.failed_logins,
+        &appstate.failed_tokens,
+        challenge_solution(&headers),
+        client_ip,
+        None,
+    )
+    .await?;
+
+    let mut enrollment = match Token::find_guarded(
+        &appstate.pool,
+        &appstate.mail_tx,
+        &appstate.failed_tokens,
+        &data.token,
+        Some(client_ip),
+    )
+    .await
+    {
+        Ok(enrollment) => enrollment,
+        Err(err @ TokenError::TooManyAttempts(_)) => {
+            // slow down automated guessing
+            sleep(LOCKOUT_RESPONSE_DELAY).await;
+            return Err(err.into());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if enrollment.token_type.as_deref() != Some(ENROLLMENT_TOKEN_TYPE) {
+        error!("Invalid token type used while trying to start web enrollment");
+        return Err(TokenError::NotFound.into());
+    }
+    let user = enrollment.fetch_user(&appstate.pool).await?;
+    if !user.is_active {
+        warn!("Can't start enrollment for disabled user {}", user.username);
+        return Err(TokenError::UserDisabled.into());
+    }
+    if user.has_password() {
+        error!("User {} already activated", user.username);
+        return Err(TokenError::AlreadyActive.into());
+    }
+
+    let mut transaction = appstate.pool.begin().await?;
+    let session_deadline = enrollment
+        .start_session(
+            &mut transaction,
+            server_config().enrollment_session_timeout.as_secs(),
+        )
+        .await?;
+    let final_page_content = enrollment
+        .get_welcome_page_content(&mut transaction)
+        .await?;
+    transaction.commit().await?;
+    info!("Web enrollment session started for user {}", user.username);
+
+    Ok(ApiResponse {
+        json: json!({
+            "user": {
+                "username": user.username,
+                "first_name": user.first_name,
+                "last_name": user.last_name,
+                "email": user.email,
+            },
+            "deadline_timestamp": session_deadline.and_utc().timestamp(),
+            "final_page_content": final_page_content,
+        }),
+        status: StatusCode::OK,
+    })
+}
+
+pub async fn activate_web_enrollment(
+    user_agent: Option<TypedHeader<UserAgent>>,
+    ClientIp(client_ip): ClientIp,
+    State(appstate): State<AppState>,
+    Json(data): Json<WebEnrollmentActivation>,
+) -> ApiResult {
+    let (enrollment, user) = enrollment_session(&appstate, &data.token).await?;
+    debug!("Activating user {} using web enrollment", user.username);
+    check_password_strength(&appstate.pool, &data.password, &user.username, &user.email).await?;
+
+    let (ip_address, device_info) = client_info(&appstate, user_agent, client_ip);
+    enrollment
+        .activate_user(
+            &appstate.pool,
+            &appstate.mail_tx,
+            &data.password,
+            data.phone_number,
+            &ip_address,
+            device_info.as_deref(),
+        )
+        .await?;
+
+    Ok(ApiResponse::default())
+}
+
+pub async fn web_enrollment_totp_secret(
+    State(appstate): State<AppState>,
+    Json(data): Json<WebEnrollmentToken>,
+) -> ApiResult {
+    let (_, mut user) = enrollment_session(&appstate, &data.token).await?;
+    ensure_activated(&user)?;
+    if user.totp_enabled {
+        return Err(WebError::BadRequest("TOTP is already enabled".into()));
+    }
+    debug!("Generating new TOTP secret for user {}", user.username);
+    ensure_mfa_method_allowed(&appstate.pool, &MFAMethod::OneTimePassword).await?;
+
+    let secret = user.new_totp_secret(&appstate.pool).await?;
+    info!("Generated new TOTP secret for user {}", user.username);
+    Ok(ApiResponse {
+        json: json!(AuthTotp::new(secret)),
+        status: StatusCode::OK,
+    })
+}
+
+pub async fn web_enrollment_totp_enable(
+    State(appstate): State<AppState>,
+    Json(data): Json<WebEnrollmentCode>,
+) -> ApiResult {
+    let (_, mut user) = enrollment_session(&appstate, &data.token).await?;
+    ensure_activated(&user)?;
+    debug!("Enabling TOTP for user {}", user.username);
+    ensure_mfa_method_allowed(&appstate.pool, &MFAMethod::OneTimePassword).await?;
+
+    if !user.verify_totp_code(data.code) {
+        info!("Invalid TOTP code for user {}", user.username);
+        return Err(WebError::BadRequest("invalid TOTP code".into()));
+    }
+    let recovery_codes = RecoveryCodes::new(user.get_recovery_codes(&appstate.pool).await?);
+    user.enable_totp(&appstate.pool).await?;
+    if user.mfa_method == MFAMethod::None {
+        send_mfa_configured_email(None, &user, &MFAMethod::OneTimePassword, &appstate.mail_tx)?;
+        user.set_mfa_method(&appstate.pool, MFAMethod::OneTimePassword)
+            .await?;
+    }
+    // there is no separate step enabling MFA in this flow
+    user.enable_mfa(&appstate.pool).await?;
+    info!("Enabled TOTP for user {}", user.username);
+
+    Ok(ApiResponse {
+        json: json!(recovery_codes),
+        status: StatusCode::OK,
+    })
+}
+
+/// Create the first device of enrolled user and return its WireGuard configs.
+///
+/// Key pair is generated server-side as the browser has no WireGuard client;
+/// the private key is only included in the returned configs and is not stored.
+pub async fn web_enrollment_device(
+    user_agent: Option<TypedHeader<UserAgent>>,
+    ClientIp(client_ip): ClientIp,
+    State(appstate): State<AppState>,
+    Json(data): Json<WebEnrollmentDevice>,
+) -> ApiResult {
+    let (enrollment, user) = enrollment_session(&appstate, &data.token).await?;
+    ensure_activated(&user)?;
+    debug!(
+        "Adding device {} for user {} using web enrollment",
+        data.name, user.username
+    );
+
+    // lock the user, so concurrent requests can't both add the "first" device
+    let mut transaction = appstate.pool.begin().await?;
+    User::lock(&mut transaction, enrollment.user_id).await?;
+    let (count, _) = Device::user_device_usage(&mut *transaction, enrollment.user_id).await?;
+    if count > 0 {
+        error!(
+            "User {} already has a device, web enrollment can add only the first one",
+            user.username
+        );
+        return Err(WebError::BadRequest("user already has a device".into()));
+    }
+
+    let key = WireguardNetwork::genkey();
+    let (device, configs) = enrollment
+        .add_device(
+            &mut transaction,
+            &appstate.wireguard_tx,
+            data.name,
+            key.public,
+            DevicePlatform::browser(),
+            MachineHints::default(),
+        )
+        .await?;
+    transaction.commit().await?;
+
+    let (ip_address, device_info) = client_info(&appstate, user_agent, client_ip);
+    let template_locations: Vec<TemplateLocation> = configs
+        .iter()
+        .map(|c| TemplateLocation {
+            name: c.network_name.clone(),
+            assigned_ip: c.address.to_string(),
+        })
+        .collect();
+    send_new_device_added_email(
+        &device.name,
+        &device.wireguard_pubkey,
+        &template_locations,
+        &user.email,
+        &appstate.mail_tx,
+        Some(&ip_address),
+        device_info.as_deref(),
+    )?;
+    info!(
+        "Device {} assigned to user {} and added to all networks.",
+        device.name, user.username
+    );
+
+    // complete configs can be fetched once as QR codes for mobile WireGuard apps
+    let device_id = device.get_id()?;
+    let configs: Vec<_> = {
+        let mut qr_links = appstate
+            .config_qr_links
+            .lock()
+            .expect("Failed to lock config QR links");
+        configs
+            .into_iter()
+            .map(|config| {
+                let network_id = config.network_id;
+                let content = config.config.replace(PRIVATE_KEY_PLACEHOLDER, &key.private);
+                let token = qr_links.insert(device_id, network_id, content.clone());
+                json!({
+                    "network_id": network_id,
+                    "network_name": config.network_name,
+                    "config": content,
+                    "qr_url": format!(
+                        "{}/api/v1/device/{device_id}/config/{network_id}/qr?token={token}",
+                        server_config().base_path()
+                    ),
+                })
+            })
+            .collect()
+    };
+    Ok(ApiResponse {
+        json: json!({
+            "device": device,
+            "configs": configs,
+        }),
+        status: StatusCode::CREATED,
+    })
+}
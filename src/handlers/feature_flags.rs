@@ -0,0 +1,112 @@
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+};
+use serde_json::json;
+use utoipa::ToSchema;
+
+use super::{ApiResponse, ApiResult};
+use crate::{
+    appstate::AppState,
+    auth::{AdminRole, SessionInfo},
+    db::WireguardNetwork,
+    error::WebError,
+    feature_flags::{self, FeatureFlag},
+};
+
+#[derive(Deserialize)]
+pub struct FeatureFlagQuery {
+    network_id: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct FeatureFlagChange {
+    pub name: FeatureFlag,
+    /// Override the flag for this location only
+    #[serde(default)]
+    pub network_id: Option<i64>,
+    /// `null` removes the override
+    pub enabled: Option<bool>,
+}
+
+/// Feature flags with their defaults and overrides.
+#[utoipa::path(
+    get,
+    path = "/api/v1/system/features",
+    tag = "settings",
+    params(("network_id" = Option<i64>, Query, description = "Resolve flags for this location")),
+    responses(
+        (status = 200, description = "Feature flags", body = [FeatureFlagInfo]),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
+pub async fn list_feature_flags(
+    _admin: AdminRole,
+    Query(query): Query<FeatureFlagQuery>,
+) -> ApiResult {
+    Ok(ApiResponse {
+        json: json!(feature_flags::list(query.network_id)),
+        status: StatusCode::OK,
+    })
+}
+
+/// Override a feature flag for the whole instance or a single location.
+#[utoipa::path(
+    put,
+    path = "/api/v1/system/features",
+    tag = "settings",
+    request_body = FeatureFlagChange,
+    responses(
+        (status = 200, description = "Changed feature flag", body = FeatureFlagInfo),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+        (status = 404, description = "Location not found", body = ApiError),
+    )
+)]
+pub async fn set_feature_flag(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Json(change): Json<FeatureFlagChange>,
+) -> ApiResult {
+    let flag = change.name;
+    debug!(
+        "User {} changing feature flag {} for location {:?} to {:?}",
+        session.user.username,
+        flag.name(),
+        change.network_id,
+        change.enabled
+    );
+    if let Some(network_id) = change.network_id {
+        if WireguardNetwork::find_by_id(&appstate.pool, network_id)
+            .await?
+            .is_none()
+        {
+            return Err(WebError::ObjectNotFound(format!(
+                "Location {network_id} not found"
+            )));
+        }
+    }
+    feature_flags::set_override(
+        &appstate.pool,
+        flag,
+        change.network_id,
+        change.enabled,
+        session.user.id,
+    )
+    .await?;
+    info!(
+        feature_flag = flag.name(),
+        network_id = ?change.network_id,
+        enabled = ?change.enabled,
+        "User {} changed feature flag {}",
+        session.user.username,
+        flag.name()
+    );
+    let info = feature_flags::list(change.network_id)
+        .into_iter()
+        .find(|info| info.name == flag);
+    Ok(ApiResponse {
+        json: json!(info),
+        status: StatusCode::OK,
+    })
+}
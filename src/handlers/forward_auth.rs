@@ -8,7 +8,10 @@ use axum_extra::extract::cookie::CookieJar;
 use reqwest::Url;
 
 use super::SESSION_COOKIE_NAME;
-use crate::{appstate::AppState, db::Session, error::WebError, server_config};
+use crate::{
+    appstate::AppState, db::Session, error::WebError, expired_cleanup::remove_stale_session,
+    server_config,
+};
 
 // Header names
 static FORWARDED_HOST: &str = "x-forwarded-host";
@@ -75,7 +78,12 @@ pub async fn forward_auth(
                     "Session {} for user id {} has expired, redirecting to login",
                     session.id, session.user_id
                 );
-                let _result = session.delete(&appstate.pool).await;
+                remove_stale_session(&appstate.pool, session).await;
+            } else if session.is_impersonation() {
+                info!(
+                    "Session {} is an impersonation session, redirecting to login",
+                    session.id
+                );
             } else {
                 // If session is verified return 200 response
                 return Ok(ForwardAuthResponse::Accept);
@@ -89,7 +97,7 @@ pub async fn forward_auth(
 
 async fn login_redirect(headers: ForwardAuthHeaders) -> Result<ForwardAuthResponse, WebError> {
     let server_url = &server_config().url; // prepare redirect URL for login page
-    let mut location = server_url.join("/auth/login").map_err(|err| {
+    let mut location = server_url.join("auth/login").map_err(|err| {
         error!("Failed to prepare redirect URL: {err}");
         WebError::Http(StatusCode::INTERNAL_SERVER_ERROR)
     })?;
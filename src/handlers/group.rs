@@ -2,16 +2,18 @@ use axum::{
     extract::{Json, Path, State},
     http::StatusCode,
 };
+use chrono::{NaiveDateTime, Utc};
 use serde_json::json;
 use sqlx::query_as;
 
 use super::{ApiResponse, EditGroupInfo, GroupInfo, Username};
 use crate::{
     appstate::AppState,
-    auth::{SessionInfo, UserAdminRole},
-    db::{Group, User, WireguardNetwork},
+    auth::{AdminRole, SessionInfo, UserAdminRole},
+    db::{DbPool, Group, User, WireguardNetwork},
     error::WebError,
     server_config,
+    user_suspension::suspend_group_members,
     // ldap::utils::{ldap_add_user_to_group, ldap_modify_group, ldap_remove_user_from_group},
 };
 
@@ -47,7 +49,7 @@ pub(crate) async fn bulk_assign_to_groups(
         User,
         "SELECT id \"id?\", username, password_hash, last_name, first_name, email, \
             phone, mfa_enabled, totp_enabled, email_mfa_enabled, \
-            totp_secret, email_mfa_secret, mfa_method \"mfa_method: _\", recovery_codes, is_active \
+            totp_secret, email_mfa_secret, mfa_method \"mfa_method: _\", recovery_codes, is_active, break_glass, max_devices, merged_into, new_country_alert_opt_out \
             FROM \"user\" WHERE id = ANY($1)",
         &data.users
     )
@@ -95,17 +97,30 @@ pub(crate) async fn list_groups_info(
     State(appstate): State<AppState>,
 ) -> Result<ApiResponse, WebError> {
     debug!("Listing groups info");
+    // closure of group hierarchy resolves effective members in a single query
     let q_result = query_as!(
         GroupInfo,
-        "SELECT g.name as name, \
+        "WITH RECURSIVE closure AS ( \
+            SELECT id ancestor, id descendant FROM \"group\" \
+            UNION \
+            SELECT c.ancestor, g.id FROM closure c JOIN \"group\" g ON g.parent_id = c.descendant \
+        ) \
+        SELECT g.name as name, p.name as \"parent?\", \
         COALESCE(ARRAY_AGG(DISTINCT u.username) FILTER (WHERE u.username IS NOT NULL), '{}') as \"members!\", \
-        COALESCE(ARRAY_AGG(DISTINCT wn.name) FILTER (WHERE wn.name IS NOT NULL), '{}') as \"vpn_locations!\" \
+        COALESCE(ARRAY_AGG(DISTINCT wn.name) FILTER (WHERE wn.name IS NOT NULL), '{}') as \"vpn_locations!\", \
+        ARRAY( \
+            SELECT DISTINCT eu.username FROM closure c \
+            JOIN \"group_user\" egu ON egu.group_id = c.descendant \
+            JOIN \"user\" eu ON eu.id = egu.user_id \
+            WHERE c.ancestor = g.id ORDER BY eu.username \
+        ) as \"effective_members!\" \
         FROM \"group\" g \
+        LEFT JOIN \"group\" p ON p.id = g.parent_id \
         LEFT JOIN \"group_user\" gu ON gu.group_id = g.id \
         LEFT JOIN \"user\" u ON u.id = gu.user_id \
         LEFT JOIN \"wireguard_network_allowed_group\" wnag ON wnag.group_id = g.id \
         LEFT JOIN \"wireguard_network\" wn ON wn.id = wnag.network_id \
-        GROUP BY g.name"
+        GROUP BY g.id, g.name, p.name"
     )
     .fetch_all(&appstate.pool)
     .await?;
@@ -143,9 +158,12 @@ pub(crate) async fn get_group(
     if let Some(group) = Group::find_by_name(&appstate.pool, &name).await? {
         let members = group.member_usernames(&appstate.pool).await?;
         let vpn_locations = group.allowed_vpn_locations(&appstate.pool).await?;
-        info!("Retrieved group {name}");
+        let mut group_info = GroupInfo::new(name, members, vpn_locations);
+        group_info.parent = group.parent_name(&appstate.pool).await?;
+        group_info.effective_members = group.effective_member_usernames(&appstate.pool).await?;
+        info!("Retrieved group {}", group_info.name);
         Ok(ApiResponse {
-            json: json!(GroupInfo::new(name, members, vpn_locations)),
+            json: json!(group_info),
             status: StatusCode::OK,
         })
     } else {
@@ -155,6 +173,23 @@ pub(crate) async fn get_group(
     }
 }
 
+async fn find_parent(pool: &DbPool, name: &str) -> Result<Group, WebError> {
+    Group::find_by_name(pool, name).await?.ok_or_else(|| {
+        let msg = format!("Parent group {name} not found");
+        error!(msg);
+        WebError::BadRequest(msg)
+    })
+}
+
+fn cycle_response(group: &str, parent: &str) -> ApiResponse {
+    let msg = format!("Group {parent} can't be a parent of {group}, because it is its subgroup");
+    error!(msg);
+    ApiResponse {
+        json: json!({ "msg": msg }),
+        status: StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
 /// POST: Create group with a given name and member list.
 pub(crate) async fn create_group(
     _role: UserAdminRole,
@@ -167,6 +202,12 @@ pub(crate) async fn create_group(
     let mut transaction = appstate.pool.begin().await?;
 
     let mut group = Group::new(&group_info.name);
+    if let Some(parent) = &group_info.parent {
+        if *parent == group_info.name {
+            return Ok(cycle_response(&group_info.name, parent));
+        }
+        group.parent_id = find_parent(&appstate.pool, parent).await?.id;
+    }
     // FIXME: conflicts must not return internal server error (500).
     group.save(&appstate.pool).await?;
     // TODO: create group in LDAP
@@ -206,12 +247,27 @@ pub(crate) async fn modify_group(
         return Err(WebError::ObjectNotFound(msg));
     };
 
+    let parent_id = match &group_info.parent {
+        Some(parent) => {
+            let parent_group = find_parent(&appstate.pool, parent).await?;
+            let Some(parent_id) = parent_group.id else {
+                return Err(WebError::ModelError("Group ID is missing".into()));
+            };
+            if group.creates_cycle(&appstate.pool, parent_id).await? {
+                return Ok(cycle_response(&group.name, parent));
+            }
+            Some(parent_id)
+        }
+        None => None,
+    };
+
     // FIXME: LDAP operations are not reverted.
     let mut transaction = appstate.pool.begin().await?;
 
-    // Rename only when needed.
-    if group.name != group_info.name {
+    // Rename or move only when needed.
+    if group.name != group_info.name || group.parent_id != parent_id {
         group.name = group_info.name;
+        group.parent_id = parent_id;
         group.save(&mut *transaction).await?;
         // TODO: update LDAP
     }
@@ -249,6 +305,200 @@ pub(crate) async fn modify_group(
     Ok(ApiResponse::default())
 }
 
+#[derive(Deserialize)]
+pub(crate) struct GroupMembers {
+    members: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GroupMembersPatch {
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+/// Find users by username, failing if any of them doesn't exist.
+async fn find_members(pool: &DbPool, usernames: &[String]) -> Result<Vec<User>, WebError> {
+    let users = query_as!(
+        User,
+        "SELECT id \"id?\", username, password_hash, last_name, first_name, email, \
+            phone, mfa_enabled, totp_enabled, email_mfa_enabled, \
+            totp_secret, email_mfa_secret, mfa_method \"mfa_method: _\", recovery_codes, is_active, break_glass, max_devices, merged_into, new_country_alert_opt_out \
+            FROM \"user\" WHERE username = ANY($1)",
+        usernames
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut unknown: Vec<&str> = usernames
+        .iter()
+        .filter(|username| !users.iter().any(|user| &user.username == *username))
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort_unstable();
+        unknown.dedup();
+        let msg = format!("Users not found: {}", unknown.join(", "));
+        error!(msg);
+        return Err(WebError::BadRequest(msg));
+    }
+    Ok(users)
+}
+
+/// Apply membership changes to `group` in a single transaction, and update gateways
+/// with at most one event per location once it's committed.
+async fn change_group_members(
+    appstate: &AppState,
+    group: &Group,
+    add: &[User],
+    remove: &[User],
+) -> Result<ApiResponse, WebError> {
+    let mut transaction = appstate.pool.begin().await?;
+    let current: Vec<String> = group.member_usernames(&mut *transaction).await?;
+    let mut added = Vec::new();
+    for user in add {
+        if !current.contains(&user.username) && !added.contains(&user.username) {
+            user.add_to_group(&mut *transaction, group).await?;
+            added.push(user.username.clone());
+        }
+    }
+    let mut removed = Vec::new();
+    for user in remove {
+        if current.contains(&user.username) && !removed.contains(&user.username) {
+            user.remove_from_group(&mut *transaction, group).await?;
+            removed.push(user.username.clone());
+        }
+    }
+    // TODO: update LDAP
+    added.sort_unstable();
+    removed.sort_unstable();
+    let events = if added.is_empty() && removed.is_empty() {
+        Vec::new()
+    } else {
+        WireguardNetwork::sync_all_networks_consolidated(&mut transaction).await?
+    };
+    transaction.commit().await?;
+    appstate.send_multiple_wireguard_events(events);
+
+    info!(
+        added = ?added,
+        removed = ?removed,
+        "Changed members of group {}: {} added, {} removed",
+        group.name,
+        added.len(),
+        removed.len()
+    );
+    Ok(ApiResponse {
+        json: json!({"added": added, "removed": removed}),
+        status: StatusCode::OK,
+    })
+}
+
+async fn find_group(pool: &DbPool, name: &str) -> Result<Group, WebError> {
+    Group::find_by_name(pool, name).await?.ok_or_else(|| {
+        let msg = format!("Group {name} not found");
+        error!(msg);
+        WebError::ObjectNotFound(msg)
+    })
+}
+
+/// PUT: Replace members of group with `name`.
+pub(crate) async fn set_group_members(
+    _role: UserAdminRole,
+    State(appstate): State<AppState>,
+    Path(name): Path<String>,
+    Json(data): Json<GroupMembers>,
+) -> Result<ApiResponse, WebError> {
+    debug!("Setting members of group {name}");
+    let group = find_group(&appstate.pool, &name).await?;
+    let members = find_members(&appstate.pool, &data.members).await?;
+    let outstanding: Vec<User> = group
+        .members(&appstate.pool)
+        .await?
+        .into_iter()
+        .filter(|user| !data.members.contains(&user.username))
+        .collect();
+    change_group_members(&appstate, &group, &members, &outstanding).await
+}
+
+/// PATCH: Add and remove members of group with `name`.
+pub(crate) async fn patch_group_members(
+    _role: UserAdminRole,
+    State(appstate): State<AppState>,
+    Path(name): Path<String>,
+    Json(data): Json<GroupMembersPatch>,
+) -> Result<ApiResponse, WebError> {
+    debug!("Changing members of group {name}");
+    if let Some(username) = data
+        .add
+        .iter()
+        .find(|username| data.remove.contains(username))
+    {
+        return Err(WebError::BadRequest(format!(
+            "User {username} can't be both added and removed"
+        )));
+    }
+    let group = find_group(&appstate.pool, &name).await?;
+    let usernames = [data.add.as_slice(), data.remove.as_slice()].concat();
+    let (add, remove): (Vec<User>, Vec<User>) = find_members(&appstate.pool, &usernames)
+        .await?
+        .into_iter()
+        .partition(|user| data.add.contains(&user.username));
+    change_group_members(&appstate, &group, &add, &remove).await
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SuspendGroupRequest {
+    #[serde(default)]
+    reactivate_at: Option<NaiveDateTime>,
+    // usernames of members to leave active
+    #[serde(default)]
+    exempt: Vec<String>,
+}
+
+/// POST: Suspend active members of group with `name`, optionally until `reactivate_at`.
+pub(crate) async fn suspend_group(
+    _role: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(name): Path<String>,
+    Json(data): Json<SuspendGroupRequest>,
+) -> Result<ApiResponse, WebError> {
+    debug!(
+        "User {} suspending members of group {name}",
+        session.user.username
+    );
+    let group = find_group(&appstate.pool, &name).await?;
+    if data
+        .reactivate_at
+        .is_some_and(|reactivate_at| reactivate_at <= Utc::now().naive_utc())
+    {
+        return Err(WebError::BadRequest(
+            "Reactivation time must be in the future".into(),
+        ));
+    }
+    find_members(&appstate.pool, &data.exempt).await?;
+    let (batch, events) = suspend_group_members(
+        &appstate.pool,
+        &group,
+        &data.exempt,
+        data.reactivate_at,
+        &session.user,
+    )
+    .await?;
+    appstate.send_multiple_wireguard_events(events);
+    warn!(
+        batch_id = %batch.batch_id,
+        "User {} suspended {} members of group {name}",
+        session.user.username,
+        batch.suspended.len()
+    );
+    Ok(ApiResponse {
+        json: json!(batch),
+        status: StatusCode::OK,
+    })
+}
+
 /// DELETE: Remove group with `name`.
 pub(crate) async fn delete_group(
     _session: SessionInfo,
@@ -0,0 +1,288 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{NaiveDateTime, Utc};
+use serde_json::json;
+use utoipa::ToSchema;
+
+use super::{user::check_username, ApiResponse, ApiResult};
+use crate::{
+    appstate::AppState,
+    auth::{AdminRole, SessionInfo},
+    db::{
+        models::guest_access::{GuestAccess, GuestAccessDetails},
+        AppEvent, Group, User, UserInfo, WireguardNetwork,
+    },
+    error::WebError,
+    guest_access::revoke_guest_access,
+    server_config,
+};
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct GuestAccessRequest {
+    username: String,
+    first_name: String,
+    last_name: String,
+    email: String,
+    #[serde(default)]
+    phone: Option<String>,
+    /// Location the guest gets access to.
+    network_id: i64,
+    /// Existing group allowed in the location; a group is created for the guest if not set.
+    #[serde(default)]
+    group: Option<String>,
+    /// Time at which the access ends, in UTC.
+    expires_at: NaiveDateTime,
+    /// Send the enrollment token to the guest's email.
+    #[serde(default)]
+    send_enrollment_notification: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedGuestAccess {
+    #[serde(flatten)]
+    access: GuestAccess,
+    enrollment_token: String,
+    enrollment_url: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GuestAccessInfo {
+    #[serde(flatten)]
+    details: GuestAccessDetails,
+    /// Seconds left until the access expires.
+    time_remaining: i64,
+}
+
+/// Create a guest user with access to a single location until `expires_at`.
+///
+/// The user, its enrollment token and membership in a group allowed in the location are
+/// created together. The location has to be limited to allowed groups, otherwise group
+/// membership wouldn't limit the guest to it.
+#[utoipa::path(
+    post,
+    path = "/api/v1/guest_access",
+    tag = "guest_access",
+    request_body = GuestAccessRequest,
+    responses(
+        (status = 201, description = "Guest access created", body = CreatedGuestAccess),
+        (status = 400, description = "Invalid username, expiry time, location or group", body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 404, description = "Location or group not found", body = ApiError),
+        (status = 409, description = "User or group already exists", body = ApiError),
+    )
+)]
+pub async fn create_guest_access(
+    _role: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Json(data): Json<GuestAccessRequest>,
+) -> ApiResult {
+    let username = data.username.clone();
+    debug!(
+        "User {} creating guest access for user {username}",
+        session.user.username
+    );
+    if let Err(err) = check_username(&username) {
+        debug!("Username {username} rejected: {err}");
+        return Err(WebError::BadRequest(format!("Invalid username {username}")));
+    }
+    let now = Utc::now().naive_utc();
+    if data.expires_at <= now {
+        return Err(WebError::BadRequest(
+            "Expiry time must be in the future".into(),
+        ));
+    }
+    if User::find_by_username(&appstate.pool, &username)
+        .await?
+        .is_some()
+    {
+        return Err(WebError::Conflict(format!(
+            "User {username} already exists"
+        )));
+    }
+    let Some(network) = WireguardNetwork::find_by_id(&appstate.pool, data.network_id).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "Location {} not found",
+            data.network_id
+        )));
+    };
+
+    let mut transaction = appstate.pool.begin().await?;
+    let allowed_groups = network.fetch_allowed_groups(&mut *transaction).await?;
+    if allowed_groups.is_empty() {
+        return Err(WebError::BadRequest(format!(
+            "Location {} isn't limited to allowed groups",
+            network.name
+        )));
+    }
+    let (group, group_created) = match data.group {
+        Some(name) => {
+            if name == server_config().admin_groupname {
+                return Err(WebError::BadRequest(
+                    "Guests can't be members of the admin group".into(),
+                ));
+            }
+            if !allowed_groups.contains(&name) {
+                return Err(WebError::BadRequest(format!(
+                    "Group {name} isn't allowed in location {}",
+                    network.name
+                )));
+            }
+            let Some(group) = Group::find_by_name(&mut *transaction, &name).await? else {
+                return Err(WebError::ObjectNotFound(format!("Group {name} not found")));
+            };
+            (group, false)
+        }
+        None => {
+            let name = format!("guest-{username}");
+            if Group::find_by_name(&mut *transaction, &name)
+                .await?
+                .is_some()
+            {
+                return Err(WebError::Conflict(format!("Group {name} already exists")));
+            }
+            let mut group = Group::new(name);
+            group.save(&mut *transaction).await?;
+            network.add_to_group(&mut transaction, &group.name).await?;
+            (group, true)
+        }
+    };
+
+    let mut user = User::new(
+        username.clone(),
+        None,
+        data.last_name,
+        data.first_name,
+        data.email.clone(),
+        data.phone,
+    );
+    user.save(&mut *transaction).await?;
+    user.add_to_group(&mut *transaction, &group).await?;
+    let Some(user_id) = user.id else {
+        error!("Model returned user ({username}) without ID");
+        return Err(WebError::ModelError(
+            "Model returned user without ID".into(),
+        ));
+    };
+
+    // the token is of no use once the access ends
+    let config = server_config();
+    let token_timeout = config
+        .enrollment_token_timeout
+        .as_secs()
+        .min((data.expires_at - now).num_seconds().max(1) as u64);
+    let enrollment_token = user
+        .start_enrollment(
+            &mut transaction,
+            &session.user,
+            Some(data.email),
+            token_timeout,
+            config.enrollment_url.clone(),
+            data.send_enrollment_notification,
+            appstate.mail_tx.clone(),
+        )
+        .await?;
+
+    let mut access = GuestAccess {
+        id: None,
+        user_id,
+        network_id: data.network_id,
+        group_name: group.name,
+        group_created,
+        sponsor_id: session.user.id,
+        created_at: now,
+        expires_at: data.expires_at,
+        notified_at: None,
+        ended_at: None,
+    };
+    access.save(&mut *transaction).await?;
+    transaction.commit().await?;
+
+    let user_info = UserInfo::from_user(&appstate.pool, &user).await?;
+    appstate.trigger_action(AppEvent::UserCreated(user_info));
+    info!(
+        "User {} created guest access for user {username} to location {} until {}",
+        session.user.username, network.name, access.expires_at
+    );
+
+    Ok(ApiResponse {
+        json: json!(CreatedGuestAccess {
+            access,
+            enrollment_token,
+            enrollment_url: config.enrollment_url.to_string(),
+        }),
+        status: StatusCode::CREATED,
+    })
+}
+
+/// Guest access which hasn't ended yet, soonest to expire first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/guest_access",
+    tag = "guest_access",
+    responses(
+        (status = 200, description = "Active guest access", body = [GuestAccessInfo]),
+        (status = 403, description = "Not an admin", body = ApiError),
+    )
+)]
+pub async fn list_guest_access(_role: AdminRole, State(appstate): State<AppState>) -> ApiResult {
+    let now = Utc::now().naive_utc();
+    let accesses: Vec<GuestAccessInfo> = GuestAccess::all_active(&appstate.pool)
+        .await?
+        .into_iter()
+        .map(|details| GuestAccessInfo {
+            time_remaining: (details.access.expires_at - now).num_seconds().max(0),
+            details,
+        })
+        .collect();
+    Ok(ApiResponse {
+        json: json!(accesses),
+        status: StatusCode::OK,
+    })
+}
+
+/// End guest access before it expires: the guest is disabled and their devices disconnected.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/guest_access/{id}",
+    tag = "guest_access",
+    params(
+        ("id" = i64, Path, description = "Guest access ID")
+    ),
+    responses(
+        (status = 200, description = "Guest access ended", body = GuestAccess),
+        (status = 400, description = "Guest access has already ended", body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 404, description = "Guest access not found", body = ApiError),
+    )
+)]
+pub async fn delete_guest_access(
+    _role: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(id): Path<i64>,
+) -> ApiResult {
+    let Some(mut access) = GuestAccess::find_by_id(&appstate.pool, id).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "Guest access {id} not found"
+        )));
+    };
+    if access.ended_at.is_some() {
+        return Err(WebError::BadRequest(format!(
+            "Guest access {id} has already ended"
+        )));
+    }
+    let events = revoke_guest_access(&appstate.pool, &mut access).await?;
+    appstate.send_multiple_wireguard_events(events);
+    info!(
+        "User {} revoked guest access {id} of user {}",
+        session.user.username, access.user_id
+    );
+    Ok(ApiResponse {
+        json: json!(access),
+        status: StatusCode::OK,
+    })
+}
@@ -0,0 +1,41 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde_json::json;
+
+use super::{ApiResponse, ApiResult};
+use crate::{
+    auth::{AdminRole, SessionInfo},
+    AppState,
+};
+
+pub async fn list_jobs(_admin: AdminRole, State(appstate): State<AppState>) -> ApiResult {
+    debug!("Listing background jobs");
+    let jobs = appstate.job_runner.status().await?;
+    Ok(ApiResponse {
+        json: json!(jobs),
+        status: StatusCode::OK,
+    })
+}
+
+pub async fn run_job(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(name): Path<String>,
+) -> ApiResult {
+    debug!(
+        "User {} triggering background job {name}",
+        session.user.username
+    );
+    appstate.job_runner.trigger(&name)?;
+    info!(
+        "User {} triggered background job {name}",
+        session.user.username
+    );
+    Ok(ApiResponse {
+        json: json!({}),
+        status: StatusCode::ACCEPTED,
+    })
+}
@@ -0,0 +1,27 @@
+use axum::{
+    extract::{ws::WebSocketUpgrade, State},
+    response::Response,
+};
+
+use crate::{
+    appstate::AppState,
+    auth::{AdminRole, SessionInfo},
+    live_events::{serve_live_events, subscribe},
+};
+
+/// Upgrade to a WebSocket pushing live events to the admin dashboard.
+pub async fn connect_live_events(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    debug!("User {} connecting to live events", session.user.username);
+    let events_rx = subscribe();
+    let gateway_events_rx = appstate.wireguard_tx.subscribe();
+    let pool = appstate.pool.clone();
+    let session_id = session.session.id;
+    ws.on_upgrade(move |socket| {
+        serve_live_events(socket, events_rx, gateway_events_rx, pool, session_id)
+    })
+}
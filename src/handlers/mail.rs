@@ -20,6 +20,7 @@ use crate::{
     db::{models::enrollment::TokenError, MFAMethod, Session, User},
     error::WebError,
     mail::{Attachment, Mail},
+    notifications::{notify_admins, AdminNotification, NotificationCategory},
     server_config,
     support::dump_config,
     templates::{self, support_data_mail, TemplateError, TemplateLocation},
@@ -32,6 +33,13 @@ static SUPPORT_EMAIL_SUBJECT: &str = "Defguard support data";
 
 static NEW_DEVICE_ADDED_EMAIL_SUBJECT: &str = "Defguard: new device added to your account";
 static NEW_DEVICE_LOGIN_EMAIL_SUBJECT: &str = "Defguard: new device logged in to your account";
+static DEVICE_TRANSFERRED_EMAIL_SUBJECT: &str = "Defguard: device ownership changed";
+static PSK_ROTATION_EMAIL_SUBJECT: &str = "Defguard: device preshared key rotation";
+static GUEST_ACCESS_EXPIRING_EMAIL_SUBJECT: &str = "Defguard: guest access is about to expire";
+static NEW_COUNTRY_CONNECTION_EMAIL_SUBJECT: &str = "Defguard: device connected from a new country";
+static SHARED_CONFIG_DOWNLOADED_EMAIL_SUBJECT: &str = "Defguard: device configuration downloaded";
+static MFA_METHODS_DISALLOWED_EMAIL_SUBJECT: &str =
+    "Defguard: multi-factor authentication methods no longer allowed";
 
 static EMAIL_MFA_ACTIVATION_EMAIL_SUBJECT: &str = "Your Multi-Factor Authentication Activation";
 static EMAIL_MFA_CODE_EMAIL_SUBJECT: &str = "Your Multi-Factor Authentication Code for Login";
@@ -208,44 +216,221 @@ pub fn send_new_device_added_email(
     }
 }
 
-pub async fn send_gateway_disconnected_email(
-    gateway_name: Option<String>,
-    network_name: String,
-    gateway_adress: &str,
+/// Ask device owner to switch to a new preshared key before `deadline`.
+pub fn send_psk_rotation_email(
+    device_name: &str,
+    location_name: &str,
+    fingerprint: &str,
+    deadline: &NaiveDateTime,
+    user_email: &str,
     mail_tx: &UnboundedSender<Mail>,
-    pool: &DbPool,
-) -> Result<(), WebError> {
-    debug!("Sending gateway disconnected mail to all admin users");
-    let admin_users = User::find_by_group_name(pool, &server_config().admin_groupname).await?;
-    let gateway_name = gateway_name.unwrap_or_default();
-    for user in admin_users {
+) -> Result<(), TemplateError> {
+    debug!("Sending preshared key rotation notification for device {device_name} to {user_email}");
+
+    let mail = Mail {
+        to: user_email.to_string(),
+        subject: PSK_ROTATION_EMAIL_SUBJECT.to_string(),
+        content: templates::psk_rotation_mail(device_name, location_name, fingerprint, deadline)?,
+        attachments: Vec::new(),
+        result_tx: None,
+    };
+    let to = mail.to.clone();
+    match mail_tx.send(mail) {
+        Ok(()) => info!("Sent preshared key rotation notification to {to}"),
+        Err(err) => {
+            error!("Sending preshared key rotation notification to {to} failed with error:\n{err}");
+        }
+    }
+    Ok(())
+}
+
+/// Remind the sponsor of guest access that it's going to end at `expires_at`.
+pub fn send_guest_access_expiring_email(
+    username: &str,
+    location_name: &str,
+    expires_at: &NaiveDateTime,
+    sponsor_email: &str,
+    mail_tx: &UnboundedSender<Mail>,
+) -> Result<(), TemplateError> {
+    debug!("Sending guest access expiry notification for user {username} to {sponsor_email}");
+
+    let mail = Mail {
+        to: sponsor_email.to_string(),
+        subject: GUEST_ACCESS_EXPIRING_EMAIL_SUBJECT.to_string(),
+        content: templates::guest_access_expiring_mail(username, location_name, expires_at)?,
+        attachments: Vec::new(),
+        result_tx: None,
+    };
+    let to = mail.to.clone();
+    match mail_tx.send(mail) {
+        Ok(()) => info!("Sent guest access expiry notification to {to}"),
+        Err(err) => {
+            error!("Sending guest access expiry notification to {to} failed with error:\n{err}");
+        }
+    }
+    Ok(())
+}
+
+/// Warn device owner about a VPN connection from a country the device hasn't been seen in.
+pub fn send_new_country_connection_email(
+    device_name: &str,
+    location_name: &str,
+    country: &str,
+    endpoint_ip: &str,
+    connected_at: &NaiveDateTime,
+    user_email: &str,
+    mail_tx: &UnboundedSender<Mail>,
+) -> Result<(), TemplateError> {
+    debug!("Sending new country connection notification for device {device_name} to {user_email}");
+
+    let mail = Mail {
+        to: user_email.to_string(),
+        subject: NEW_COUNTRY_CONNECTION_EMAIL_SUBJECT.to_string(),
+        content: templates::new_country_connection_mail(
+            device_name,
+            location_name,
+            country,
+            endpoint_ip,
+            connected_at,
+        )?,
+        attachments: Vec::new(),
+        result_tx: None,
+    };
+    let to = mail.to.clone();
+    match mail_tx.send(mail) {
+        Ok(()) => info!("Sent new country connection notification to {to}"),
+        Err(err) => {
+            error!("Sending new country connection notification to {to} failed with error:\n{err}");
+        }
+    }
+    Ok(())
+}
+
+/// Tell device owner that the device config has been downloaded using a one-time link.
+pub fn send_shared_config_downloaded_email(
+    device_name: &str,
+    location_name: &str,
+    ip_address: &str,
+    downloaded_at: &NaiveDateTime,
+    user_email: &str,
+    mail_tx: &UnboundedSender<Mail>,
+) -> Result<(), TemplateError> {
+    debug!("Sending shared config download notification for device {device_name} to {user_email}");
+
+    let mail = Mail {
+        to: user_email.to_string(),
+        subject: SHARED_CONFIG_DOWNLOADED_EMAIL_SUBJECT.to_string(),
+        content: templates::shared_config_downloaded_mail(
+            device_name,
+            location_name,
+            ip_address,
+            downloaded_at,
+        )?,
+        attachments: Vec::new(),
+        result_tx: None,
+    };
+    let to = mail.to.clone();
+    match mail_tx.send(mail) {
+        Ok(()) => info!("Sent shared config download notification to {to}"),
+        Err(err) => {
+            error!("Sending shared config download notification to {to} failed with error:\n{err}");
+        }
+    }
+    Ok(())
+}
+
+/// Ask a user to switch from disallowed MFA methods, or tell them these have been removed.
+pub fn send_mfa_methods_disallowed_email(
+    user_email: &str,
+    methods: &[MFAMethod],
+    allowed_methods: &[MFAMethod],
+    deadline: Option<&NaiveDateTime>,
+    fallback: &str,
+    mail_tx: &UnboundedSender<Mail>,
+) -> Result<(), TemplateError> {
+    debug!("Sending disallowed MFA methods notification to {user_email}");
+
+    let mail = Mail {
+        to: user_email.to_string(),
+        subject: MFA_METHODS_DISALLOWED_EMAIL_SUBJECT.to_string(),
+        content: templates::mfa_methods_disallowed_mail(
+            methods,
+            allowed_methods,
+            deadline,
+            fallback,
+        )?,
+        attachments: Vec::new(),
+        result_tx: None,
+    };
+    let to = mail.to.clone();
+    match mail_tx.send(mail) {
+        Ok(()) => info!("Sent disallowed MFA methods notification to {to}"),
+        Err(err) => {
+            error!("Sending disallowed MFA methods notification to {to} failed with error:\n{err}");
+        }
+    }
+    Ok(())
+}
+
+/// Notify both previous and new owner about device transfer.
+pub fn send_device_transferred_email(
+    device_name: &str,
+    public_key: &str,
+    previous_owner: &User,
+    new_owner: &User,
+    mail_tx: &UnboundedSender<Mail>,
+) -> Result<(), TemplateError> {
+    debug!(
+        "Sending device {device_name} transfer notification to users {} and {}",
+        previous_owner.username, new_owner.username
+    );
+
+    for (user, received) in [(previous_owner, false), (new_owner, true)] {
         let mail = Mail {
-            to: user.email,
-            subject: GATEWAY_DISCONNECTED.to_string(),
-            content: templates::gateway_disconnected_mail(
-                &gateway_name,
-                gateway_adress,
-                &network_name,
+            to: user.email.clone(),
+            subject: DEVICE_TRANSFERRED_EMAIL_SUBJECT.to_string(),
+            content: templates::device_transferred_mail(
+                device_name,
+                public_key,
+                &previous_owner.username,
+                &new_owner.username,
+                received,
             )?,
             attachments: Vec::new(),
             result_tx: None,
         };
         let to = mail.to.clone();
-
         match mail_tx.send(mail) {
-            Ok(()) => {
-                info!("Sent gateway disconnected notification to {to}");
-            }
+            Ok(()) => info!("Sent device transfer notification to {to}"),
             Err(err) => {
-                error!(
-                    "Sending gateway disconnected notification to {to} failed with error:\n{err}"
-                );
+                error!("Sending device transfer notification to {to} failed with error:\n{err}");
             }
         }
     }
     Ok(())
 }
 
+pub async fn send_gateway_disconnected_notification(
+    gateway_name: Option<String>,
+    network_name: String,
+    gateway_adress: &str,
+    mail_tx: &UnboundedSender<Mail>,
+    pool: &DbPool,
+) -> Result<(), WebError> {
+    debug!("Sending gateway disconnected notification to admins");
+    let gateway_name = gateway_name.unwrap_or_default();
+    let notification = AdminNotification {
+        category: NotificationCategory::Gateway,
+        subject: GATEWAY_DISCONNECTED.to_string(),
+        message: format!(
+            "Gateway {gateway_name} ({gateway_adress}) in location {network_name} disconnected"
+        ),
+        html: templates::gateway_disconnected_mail(&gateway_name, gateway_adress, &network_name)?,
+    };
+    notify_admins(pool, mail_tx, &notification).await?;
+    Ok(())
+}
+
 pub async fn send_new_device_login_email(
     user_email: &str,
     mail_tx: &UnboundedSender<Mail>,
@@ -3,13 +3,15 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
+use chrono::NaiveDateTime;
 use serde_json::{json, Value};
+use utoipa::ToSchema;
 use webauthn_rs::prelude::RegisterPublicKeyCredential;
 
 #[cfg(feature = "wireguard")]
 use crate::db::Device;
 use crate::{
-    auth::SessionInfo,
+    auth::{challenge::ChallengeError, SessionInfo},
     db::{DbPool, User, UserInfo},
     error::WebError,
     VERSION,
@@ -17,17 +19,28 @@ use crate::{
 
 pub(crate) mod app_info;
 pub(crate) mod auth;
+pub(crate) mod diagnostics;
+pub(crate) mod enrollment;
+pub(crate) mod feature_flags;
 pub(crate) mod forward_auth;
 pub(crate) mod group;
+pub(crate) mod guest_access;
+pub(crate) mod jobs;
+pub(crate) mod live_events;
 pub(crate) mod mail;
+pub mod openapi;
 #[cfg(feature = "openid")]
 pub(crate) mod openid_clients;
 #[cfg(feature = "openid")]
 pub mod openid_flow;
 pub(crate) mod settings;
+#[cfg(feature = "wireguard")]
+pub(crate) mod shared_config;
 pub(crate) mod ssh_authorized_keys;
+pub(crate) mod ssh_ca;
 pub(crate) mod support;
 pub(crate) mod user;
+pub(crate) mod user_fields;
 pub(crate) mod webhooks;
 #[cfg(feature = "wireguard")]
 pub mod wireguard;
@@ -37,6 +50,8 @@ pub(crate) mod yubikey;
 
 pub(crate) static SESSION_COOKIE_NAME: &str = "defguard_session";
 static SIGN_IN_COOKIE_NAME: &str = "defguard_sign_in";
+static SESSION_IDLE_ERROR_CODE: &str = "session_idle";
+static CHALLENGE_REQUIRED_ERROR_CODE: &str = "challenge_required";
 
 #[derive(Default)]
 pub struct ApiResponse {
@@ -61,10 +76,23 @@ impl From<WebError> for ApiResponse {
                 error!(msg);
                 ApiResponse::new(json!({ "msg": msg }), StatusCode::UNAUTHORIZED)
             }
+            // distinguished so that the frontend can tell users why they were logged out
+            WebError::SessionIdle => ApiResponse::new(
+                json!({ "msg": web_error.to_string(), "code": SESSION_IDLE_ERROR_CODE }),
+                StatusCode::UNAUTHORIZED,
+            ),
             WebError::Forbidden(msg) => {
                 error!(msg);
                 ApiResponse::new(json!({ "msg": msg }), StatusCode::FORBIDDEN)
             }
+            // trace ID lets admins find out which check denied access
+            WebError::AccessDenied { msg, trace_id } => {
+                info!("{msg}, authorization trace {trace_id}");
+                ApiResponse::new(
+                    json!({ "msg": msg, "trace_id": trace_id }),
+                    StatusCode::FORBIDDEN,
+                )
+            }
             WebError::DbError(_)
             | WebError::Grpc(_)
             | WebError::Ldap(_)
@@ -90,13 +118,64 @@ impl From<WebError> for ApiResponse {
                 json!({ "msg": "Too many login attempts" }),
                 StatusCode::TOO_MANY_REQUESTS,
             ),
-            WebError::IncorrectUsername(msg)
-            | WebError::PubkeyValidation(msg)
-            | WebError::PubkeyExists(msg)
-            | WebError::BadRequest(msg) => {
+            WebError::Challenge(ChallengeError::ProviderUnavailable(_)) => ApiResponse::new(
+                json!({ "msg": "Challenge verification unavailable" }),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            // tells clients to fetch a challenge and retry with its solution
+            WebError::Challenge(err) => ApiResponse::new(
+                json!({ "msg": err.to_string(), "code": CHALLENGE_REQUIRED_ERROR_CODE }),
+                StatusCode::FORBIDDEN,
+            ),
+            WebError::IncorrectUsername(msg) | WebError::BadRequest(msg) => {
                 error!(msg);
                 ApiResponse::new(json!({ "msg": msg }), StatusCode::BAD_REQUEST)
             }
+            WebError::PubkeyValidation(msg) => {
+                error!(msg);
+                ApiResponse::new(json!({ "msg": msg }), StatusCode::UNPROCESSABLE_ENTITY)
+            }
+            WebError::PlatformNotAllowed(msg) => {
+                warn!(msg);
+                ApiResponse::new(
+                    json!({ "msg": msg, "policy": "allowed_platforms" }),
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                )
+            }
+            WebError::InvalidAddresses(ref errors) => {
+                debug!("{web_error}");
+                ApiResponse::new(
+                    json!({ "msg": "Invalid addresses", "errors": errors }),
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                )
+            }
+            WebError::PubkeyExists(msg) | WebError::Conflict(msg) => {
+                error!(msg);
+                ApiResponse::new(json!({ "msg": msg }), StatusCode::CONFLICT)
+            }
+            // count and limit let clients tell users how many devices they have to remove
+            WebError::DeviceLimitExceeded { count, limit } => {
+                info!("{web_error}");
+                ApiResponse::new(
+                    json!({ "msg": web_error.to_string(), "count": count, "limit": limit }),
+                    StatusCode::CONFLICT,
+                )
+            }
+            // suggested name is free, clients can offer it to users
+            WebError::DeviceNameExists { ref suggestion, .. } => {
+                info!("{web_error}");
+                ApiResponse::new(
+                    json!({ "msg": web_error.to_string(), "suggestion": suggestion }),
+                    StatusCode::CONFLICT,
+                )
+            }
+            WebError::PasswordPolicy(err) => {
+                debug!("{err}");
+                ApiResponse::new(
+                    json!({ "msg": "Password does not meet requirements", "requirements": err.0 }),
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                )
+            }
             WebError::TemplateError(err) => {
                 error!("Template error: {err}");
                 ApiResponse::new(
@@ -129,7 +208,7 @@ impl IntoResponse for ApiResponse {
 
 pub type ApiResult = Result<ApiResponse, WebError>;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct Auth {
     username: String,
     password: String,
@@ -145,7 +224,7 @@ impl Auth {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct AuthTotp {
     pub secret: String,
 }
@@ -159,7 +238,7 @@ impl AuthTotp {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct AuthCode {
     code: u32,
 }
@@ -174,8 +253,14 @@ impl AuthCode {
 #[derive(Deserialize, Serialize)]
 pub struct GroupInfo {
     pub name: String,
+    // direct members
     pub members: Vec<String>,
     pub vpn_locations: Vec<String>,
+    #[serde(default)]
+    pub parent: Option<String>,
+    // direct members and members of all subgroups
+    #[serde(default)]
+    pub effective_members: Vec<String>,
 }
 
 impl GroupInfo {
@@ -185,6 +270,8 @@ impl GroupInfo {
             name: name.into(),
             members,
             vpn_locations,
+            parent: None,
+            effective_members: Vec::new(),
         }
     }
 }
@@ -194,14 +281,23 @@ impl GroupInfo {
 pub struct EditGroupInfo {
     pub name: String,
     pub members: Vec<String>,
+    #[serde(default)]
+    pub parent: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct Username {
     pub username: String,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, ToSchema)]
+pub struct DisconnectUser {
+    /// Keep devices from connecting again until an admin unblocks them
+    #[serde(default)]
+    pub block_devices: bool,
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct AddUserData {
     pub username: String,
     pub last_name: String,
@@ -211,37 +307,37 @@ pub struct AddUserData {
     pub password: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct StartEnrollmentRequest {
     #[serde(default)]
     pub send_enrollment_notification: bool,
     pub email: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct PasswordChangeSelf {
     pub old_password: String,
     pub new_password: String,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct PasswordChange {
     pub new_password: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct WalletSignature {
     pub address: String,
     pub signature: String,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct WalletChallenge {
     pub id: i64,
     pub message: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct WalletChange {
     pub use_for_mfa: bool,
 }
@@ -252,17 +348,17 @@ pub struct WebAuthnRegistration {
     pub rpkc: RegisterPublicKeyCredential,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct RecoveryCode {
     code: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct WalletAddress {
     address: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct RecoveryCodes {
     codes: Option<Vec<String>>,
 }
@@ -274,14 +370,40 @@ impl RecoveryCodes {
     }
 }
 
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct RecoveryCodesStatus {
+    pub remaining: usize,
+    pub used_at: Vec<NaiveDateTime>,
+}
+
+/// Current user info with the name of impersonating admin, if any
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SessionUserInfo {
+    #[serde(flatten)]
+    pub user: UserInfo,
+    pub impersonator: Option<String>,
+}
+
 /// Return type needed to know if user came from openid flow
 /// with optional url to redirect him later if yes
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct AuthResponse {
     pub user: UserInfo,
     pub url: Option<String>,
 }
 
+/// Enrollment token together with the URL of enrollment service it's meant for.
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct EnrollmentTokenInfo {
+    pub enrollment_token: String,
+    pub enrollment_url: String,
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct Web3Challenge {
+    pub challenge: String,
+}
+
 /// Try to fetch [`User`] if the username is of the currently logged in user, or
 /// the logged in user is an admin.
 pub async fn user_for_admin_or_self(
@@ -0,0 +1,375 @@
+//! OpenAPI description of the REST API.
+//!
+//! The spec is generated from handler annotations and served as JSON. Swagger UI for browsing
+//! it is optional: it has to be enabled in settings and is only served to admins.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, SecurityScheme},
+        OpenApi as OpenApiSpec,
+    },
+    Modify, OpenApi, ToSchema,
+};
+use utoipa_swagger_ui::Config;
+
+use super::{
+    auth, guest_access, settings, ssh_authorized_keys, ssh_ca, user, user_fields, yubikey,
+    ApiResponse, ApiResult, SESSION_COOKIE_NAME,
+};
+use crate::{
+    appstate::AppState,
+    auth::AdminRole,
+    db::{models, Settings},
+    dns,
+    error::WebError,
+    handlers, notifications, server_config,
+};
+
+pub(crate) static SPEC_PATH: &str = "/api/v1/openapi.json";
+
+/// Body of error responses.
+#[derive(Serialize, ToSchema)]
+pub struct ApiError {
+    msg: String,
+}
+
+struct SessionCookie;
+
+impl Modify for SessionCookie {
+    fn modify(&self, openapi: &mut OpenApiSpec) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "session",
+                SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new(SESSION_COOKIE_NAME))),
+            );
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Defguard", description = "Defguard core REST API"),
+    paths(
+        user::list_users,
+        user::get_user,
+        user::add_user,
+        user::start_enrollment,
+        user::start_remote_desktop_configuration,
+        user::username_available,
+        user::modify_user,
+        user::delete_user,
+        user::impersonate_user,
+        user::merge_user,
+        user::disconnect_user,
+        user::user_timeline,
+        user::change_self_password,
+        user::change_password,
+        user::reset_password,
+        user::wallet_challenge,
+        user::set_wallet,
+        user::update_wallet,
+        user::delete_wallet,
+        user::delete_security_key,
+        user::me,
+        user::delete_authorized_app,
+        user_fields::list_user_fields,
+        user_fields::add_user_field,
+        user_fields::modify_user_field,
+        user_fields::delete_user_field,
+        user_fields::get_user_field_values,
+        user_fields::set_user_field_values,
+        ssh_authorized_keys::add_authentication_key,
+        ssh_authorized_keys::fetch_authentication_keys,
+        ssh_authorized_keys::delete_authentication_key,
+        ssh_authorized_keys::rename_authentication_key,
+        ssh_ca::sign_ssh_key,
+        ssh_ca::ssh_ca_public_key,
+        ssh_ca::ssh_krl,
+        ssh_ca::list_ssh_certificates,
+        ssh_ca::revoke_ssh_certificate,
+        guest_access::create_guest_access,
+        guest_access::list_guest_access,
+        guest_access::delete_guest_access,
+        yubikey::delete_yubikey,
+        yubikey::rename_yubikey,
+        auth::auth_challenge,
+        auth::authenticate,
+        auth::logout,
+        auth::end_impersonation,
+        auth::mfa_enable,
+        auth::mfa_disable,
+        auth::webauthn_init,
+        auth::webauthn_finish,
+        auth::webauthn_start,
+        auth::webauthn_end,
+        auth::totp_secret,
+        auth::totp_enable,
+        auth::totp_disable,
+        auth::totp_code,
+        auth::email_mfa_init,
+        auth::email_mfa_enable,
+        auth::email_mfa_disable,
+        auth::request_email_mfa_code,
+        auth::email_mfa_code,
+        auth::web3auth_start,
+        auth::web3auth_end,
+        auth::recovery_code,
+        auth::recovery_codes_status,
+        auth::regenerate_recovery_codes,
+        settings::get_settings,
+        settings::update_settings,
+        settings::patch_settings,
+        settings::get_settings_essentials,
+        settings::set_default_branding,
+        settings::test_ldap_settings,
+        settings::get_notification_recipients,
+        settings::update_notification_recipients,
+        settings::test_notifications,
+        handlers::diagnostics::probe,
+        handlers::diagnostics::consistency,
+        handlers::diagnostics::authz_denials,
+        handlers::diagnostics::cleanup_stats,
+        handlers::feature_flags::list_feature_flags,
+        handlers::feature_flags::set_feature_flag,
+        handlers::support::config_schema,
+    ),
+    components(schemas(
+        ApiError,
+        handlers::AddUserData,
+        handlers::Auth,
+        handlers::AuthCode,
+        handlers::AuthResponse,
+        handlers::AuthTotp,
+        handlers::EnrollmentTokenInfo,
+        handlers::PasswordChange,
+        handlers::PasswordChangeSelf,
+        handlers::RecoveryCode,
+        handlers::RecoveryCodes,
+        handlers::RecoveryCodesStatus,
+        handlers::SessionUserInfo,
+        handlers::StartEnrollmentRequest,
+        handlers::Username,
+        handlers::DisconnectUser,
+        user::UserTimeline,
+        handlers::WalletAddress,
+        handlers::WalletChallenge,
+        handlers::WalletChange,
+        handlers::WalletSignature,
+        handlers::Web3Challenge,
+        ssh_authorized_keys::AddAuthenticationKeyData,
+        ssh_authorized_keys::AuthenticationKeyInfo,
+        ssh_authorized_keys::RenameRequest,
+        ssh_ca::SignSshKeyRequest,
+        ssh_ca::SignedSshKey,
+        guest_access::GuestAccessRequest,
+        guest_access::CreatedGuestAccess,
+        guest_access::GuestAccessInfo,
+        models::MFAInfo,
+        models::OAuth2AuthorizedAppInfo,
+        models::SecurityKey,
+        models::UserDetails,
+        models::UserInfo,
+        models::WalletInfo,
+        models::authentication_key::AuthenticationKeyType,
+        models::device::Device,
+        models::device::UserDevice,
+        models::device::UserDeviceNetworkInfo,
+        models::notification_recipient::NotificationChannel,
+        models::notification_recipient::NotificationRecipient,
+        models::settings::AuthChallenge,
+        models::settings::DisallowedMfaPolicy,
+        models::settings::DeviceNamePolicy,
+        models::settings::DnsProvider,
+        models::settings::Settings,
+        models::settings::SettingsEssentials,
+        models::settings::SmtpEncryption,
+        models::ssh_certificate::SshCertificate,
+        models::guest_access::GuestAccess,
+        models::guest_access::GuestAccessDetails,
+        models::user::DisconnectedDevice,
+        models::user::DisconnectedLocation,
+        models::user::MFAMethod,
+        models::user::MergeSummary,
+        models::user_field::UserFieldDefinition,
+        models::user_field::UserFieldType,
+        models::user_field::UserFieldValue,
+        models::yubikey::YubiKey,
+        notifications::DeliveryResult,
+        dns::DeviceDnsStatus,
+        dns::DnsRecordState,
+        crate::consistency::ConsistencyCheckResult,
+        crate::consistency::ConsistencyReport,
+        crate::auth::authz_trace::AuthzDenial,
+        crate::auth::authz_trace::AuthzCheck,
+        crate::diagnostics::CheckResult,
+        crate::diagnostics::CheckStatus,
+        crate::diagnostics::DiagnosticsOptions,
+        crate::diagnostics::DiagnosticsReport,
+        crate::auth::challenge::ChallengeInfo,
+        crate::config_schema::ConfigOption,
+        crate::user_timeline::TimelineEvent,
+        crate::expired_cleanup::CleanupSnapshot,
+        crate::feature_flags::FeatureFlag,
+        crate::feature_flags::FeatureFlagInfo,
+        crate::feature_flags::LocationFeatureFlag,
+        handlers::feature_flags::FeatureFlagChange,
+    )),
+    modifiers(&SessionCookie),
+    security(("session" = [])),
+    tags(
+        (name = "user", description = "Users, their keys, wallets and custom fields"),
+        (name = "auth", description = "Logging in and multi-factor authentication"),
+        (name = "settings", description = "Instance settings and admin notifications"),
+        (name = "ssh", description = "SSH certificate authority"),
+        (name = "guest_access", description = "Time-limited access of guests to a location"),
+    )
+)]
+struct CoreApi;
+
+#[cfg(feature = "wireguard")]
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        handlers::wireguard::add_device,
+        handlers::wireguard::modify_device,
+        handlers::wireguard::get_device,
+        handlers::wireguard::delete_device,
+        handlers::wireguard::transfer_device,
+        handlers::wireguard::rotate_device_psk,
+        handlers::wireguard::rotate_device_key,
+        handlers::wireguard::unblock_device,
+        handlers::wireguard::confirm_device_psk,
+        handlers::wireguard::pending_device_psk,
+        handlers::wireguard::device_config_qr,
+        handlers::shared_config::share_device_config,
+        handlers::shared_config::list_shared_configs,
+        handlers::shared_config::revoke_shared_config,
+        handlers::shared_config::download_shared_config,
+        handlers::wireguard::device_effective_config,
+        handlers::wireguard::list_devices,
+        handlers::wireguard::list_invalid_pubkeys,
+        handlers::wireguard::list_user_devices,
+        handlers::wireguard::download_config,
+        handlers::wireguard::set_device_bandwidth_limits,
+        handlers::wireguard::pin_device_psk,
+        handlers::wireguard::unpin_device_psk,
+        handlers::wireguard::create_network,
+        handlers::wireguard::modify_network,
+        handlers::wireguard::delete_network,
+        handlers::wireguard::list_networks,
+        handlers::wireguard::network_details,
+        handlers::wireguard::gateway_status,
+        handlers::wireguard::remove_gateway,
+        handlers::wireguard::resync_gateways,
+        handlers::wireguard::import_network,
+        handlers::wireguard::list_archived_networks,
+        handlers::wireguard::network_overlaps,
+        handlers::wireguard::archive_network,
+        handlers::wireguard::unarchive_network,
+        handlers::wireguard::add_user_devices,
+        handlers::wireguard::create_network_token,
+        handlers::wireguard::user_stats,
+        handlers::wireguard::network_stats,
+        handlers::wireguard::connected_clients,
+        handlers::wireguard::stats_ingestion,
+        handlers::diagnostics::diagnostics,
+    ),
+    components(schemas(
+        handlers::shared_config::ShareConfigRequest,
+        handlers::shared_config::SharedConfigLink,
+        handlers::wireguard::AddDeviceResult,
+        handlers::wireguard::ConnectedClients,
+        handlers::wireguard::DeviceBandwidthLimits,
+        handlers::wireguard::DeviceDetails,
+        handlers::wireguard::DeviceGateway,
+        handlers::wireguard::DeviceTransfer,
+        handlers::wireguard::PinnedPresharedKey,
+        handlers::wireguard::PinnedPresharedKeyInfo,
+        handlers::wireguard::ImportNetworkData,
+        handlers::wireguard::ImportedNetworkData,
+        handlers::wireguard::ListedDevice,
+        handlers::wireguard::MappedDevices,
+        handlers::wireguard::NetworkToken,
+        handlers::wireguard::PskRotation,
+        handlers::wireguard::PendingPsk,
+        handlers::wireguard::WireguardNetworkData,
+        models::device::AddDevice,
+        models::device::DeviceConfig,
+        models::device::EffectiveDeviceConfig,
+        models::device::EffectivePeerConfig,
+        models::device::InvalidPubkeyDevice,
+        models::device::ModifyDevice,
+        models::shared_config::SharedConfig,
+        models::wireguard::ConnectedClient,
+        models::wireguard::MappedDevice,
+        models::wireguard::NetworkOverlap,
+        models::wireguard::WireguardDeviceStatsRow,
+        models::wireguard::WireguardDeviceTransferRow,
+        models::wireguard::WireguardNetwork,
+        models::wireguard::WireguardNetworkInfo,
+        models::wireguard::WireguardNetworkStats,
+        models::wireguard::WireguardStatsRow,
+        models::wireguard::WireguardUserStatsRow,
+        crate::grpc::GatewayState,
+        handlers::wireguard::GatewayResync,
+        handlers::wireguard::GatewayResyncStatus,
+        crate::grpc::peer_stats::StatsIngestionSnapshot,
+        crate::wg_config::ImportedDevice,
+    )),
+    tags(
+        (name = "device", description = "WireGuard devices and their configs"),
+        (name = "network", description = "WireGuard locations, their gateways and stats"),
+    )
+)]
+struct WireguardApi;
+
+/// OpenAPI description of the REST API, covering endpoints of enabled features.
+#[must_use]
+pub fn openapi() -> OpenApiSpec {
+    #[allow(unused_mut)]
+    let mut spec = CoreApi::openapi();
+    #[cfg(feature = "wireguard")]
+    spec.merge(WireguardApi::openapi());
+    spec
+}
+
+pub async fn openapi_spec() -> ApiResult {
+    Ok(ApiResponse {
+        json: json!(openapi()),
+        status: StatusCode::OK,
+    })
+}
+
+/// Serve Swagger UI files. The UI loads the spec from [`SPEC_PATH`], under the base path.
+pub async fn swagger_ui(
+    _admin: AdminRole,
+    State(appstate): State<AppState>,
+    path: Option<Path<String>>,
+) -> Result<Response, WebError> {
+    let settings = Settings::get_settings(&appstate.pool).await?;
+    if !settings.openapi_ui_enabled {
+        return Err(WebError::ObjectNotFound("Swagger UI is disabled".into()));
+    }
+    let path = path.map(|Path(path)| path).unwrap_or_default();
+    let spec_path = format!("{}{SPEC_PATH}", server_config().base_path());
+    match utoipa_swagger_ui::serve(&path, Arc::new(Config::from(spec_path.as_str()))) {
+        Ok(Some(file)) => Ok((
+            [(header::CONTENT_TYPE, file.content_type)],
+            file.bytes.into_owned(),
+        )
+            .into_response()),
+        Ok(None) => Err(WebError::ObjectNotFound(format!("{path} not found"))),
+        Err(err) => {
+            error!("Failed to serve Swagger UI file {path}: {err}");
+            Err(WebError::Http(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
@@ -2,6 +2,7 @@ use axum::{
     extract::{Json, Path, State},
     http::StatusCode,
 };
+use reqwest::Url;
 use serde_json::json;
 
 use super::{webhooks::ChangeStateData, ApiResponse, ApiResult};
@@ -9,35 +10,76 @@ use crate::{
     appstate::AppState,
     auth::{AdminRole, SessionInfo},
     db::models::{
-        oauth2client::{OAuth2Client, OAuth2ClientSafe},
+        oauth2client::{OAuth2Client, OAuth2ClientInfo, OAuth2ClientSafe},
+        oauth2session::OAuth2Session,
         NewOpenIDClient,
     },
+    error::WebError,
 };
 
+/// Fail with bad request if some of the allowed groups don't exist.
+fn check_missing_groups(missing: &[String]) -> Result<(), WebError> {
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(WebError::BadRequest(format!(
+            "Groups not found: {}",
+            missing.join(", ")
+        )))
+    }
+}
+
+// number of recent back-channel logouts shown to admins
+const LOGOUT_STATUS_LIMIT: i64 = 50;
+
+fn validate_backchannel_logout_uri(data: &NewOpenIDClient) -> Result<(), WebError> {
+    match &data.backchannel_logout_uri {
+        Some(uri) if Url::parse(uri).is_err() => Err(WebError::BadRequest(format!(
+            "Invalid back-channel logout URI: {uri}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
 pub async fn add_openid_client(
     _admin: AdminRole,
     session: SessionInfo,
     State(appstate): State<AppState>,
     Json(data): Json<NewOpenIDClient>,
 ) -> ApiResult {
+    validate_backchannel_logout_uri(&data)?;
+    let allowed_groups = data.allowed_groups.clone();
     let mut client = OAuth2Client::from_new(data);
     debug!(
         "User {} adding OpenID client {}",
         session.user.username, client.name
     );
-    client.save(&appstate.pool).await?;
+    let mut transaction = appstate.pool.begin().await?;
+    client.save(&mut *transaction).await?;
+    check_missing_groups(
+        &client
+            .set_allowed_groups(&mut transaction, &allowed_groups)
+            .await?,
+    )?;
+    transaction.commit().await?;
     info!(
-        "User {} added OpenID client {}",
+        "User {} added OpenID client {} allowed for groups {allowed_groups:?}",
         session.user.username, client.name
     );
     Ok(ApiResponse {
-        json: json!(client),
+        json: json!(OAuth2ClientInfo {
+            client,
+            allowed_groups
+        }),
         status: StatusCode::CREATED,
     })
 }
 
 pub async fn list_openid_clients(_admin: AdminRole, State(appstate): State<AppState>) -> ApiResult {
-    let openid_clients = OAuth2Client::all(&appstate.pool).await?;
+    let mut openid_clients = Vec::new();
+    for client in OAuth2Client::all(&appstate.pool).await? {
+        openid_clients.push(OAuth2ClientInfo::from_client(&appstate.pool, client).await?);
+    }
     Ok(ApiResponse {
         json: json!(openid_clients),
         status: StatusCode::OK,
@@ -52,6 +94,8 @@ pub async fn get_openid_client(
     match OAuth2Client::find_by_client_id(&appstate.pool, &client_id).await? {
         Some(openid_client) => {
             if session.is_admin {
+                let openid_client =
+                    OAuth2ClientInfo::from_client(&appstate.pool, openid_client).await?;
                 Ok(ApiResponse {
                     json: json!(openid_client),
                     status: StatusCode::OK,
@@ -81,16 +125,25 @@ pub async fn change_openid_client(
         "User {} updating OpenID client {client_id}",
         session.user.username
     );
+    validate_backchannel_logout_uri(&data)?;
     let status = match OAuth2Client::find_by_client_id(&appstate.pool, &client_id).await? {
         Some(mut openid_client) => {
             openid_client.name = data.name;
             openid_client.redirect_uri = data.redirect_uri;
             openid_client.enabled = data.enabled;
             openid_client.scope = data.scope;
-            openid_client.save(&appstate.pool).await?;
+            openid_client.backchannel_logout_uri = data.backchannel_logout_uri;
+            let mut transaction = appstate.pool.begin().await?;
+            openid_client.save(&mut *transaction).await?;
+            check_missing_groups(
+                &openid_client
+                    .set_allowed_groups(&mut transaction, &data.allowed_groups)
+                    .await?,
+            )?;
+            transaction.commit().await?;
             info!(
-                "User {} updated OpenID client {client_id} ({})",
-                session.user.username, openid_client.name
+                "User {} updated OpenID client {client_id} ({}) allowed for groups {:?}",
+                session.user.username, openid_client.name, data.allowed_groups
             );
             StatusCode::OK
         }
@@ -157,3 +210,25 @@ pub async fn delete_openid_client(
         status,
     })
 }
+
+/// Delivery status of recent back-channel logouts sent to the client.
+pub async fn openid_client_logout_status(
+    _admin: AdminRole,
+    State(appstate): State<AppState>,
+    Path(client_id): Path<String>,
+) -> ApiResult {
+    let Some(openid_client) = OAuth2Client::find_by_client_id(&appstate.pool, &client_id).await?
+    else {
+        return Err(WebError::ObjectNotFound(format!(
+            "OpenID client {client_id} not found"
+        )));
+    };
+    let Some(id) = openid_client.id else {
+        return Err(WebError::ModelError("OpenID client has no id".into()));
+    };
+    let status = OAuth2Session::delivery_status(&appstate.pool, id, LOGOUT_STATUS_LIMIT).await?;
+    Ok(ApiResponse {
+        json: json!(status),
+        status: StatusCode::OK,
+    })
+}
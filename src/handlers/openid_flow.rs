@@ -39,11 +39,12 @@ use crate::{
     appstate::AppState,
     auth::{AccessUserInfo, SessionInfo},
     db::{
-        models::{auth_code::AuthCode, oauth2client::OAuth2Client},
+        models::{auth_code::AuthCode, oauth2client::OAuth2Client, oauth2session::OAuth2Session},
         DbPool, OAuth2AuthorizedApp, OAuth2Token, Session, SessionState, User,
     },
     error::WebError,
-    handlers::{mail::send_new_device_ocid_login_email, SIGN_IN_COOKIE_NAME},
+    expired_cleanup::remove_stale_session,
+    handlers::{auth::removal_cookie, mail::send_new_device_ocid_login_email, SIGN_IN_COOKIE_NAME},
     server_config,
 };
 
@@ -293,17 +294,18 @@ impl AuthenticationRequest {
 async fn generate_auth_code_redirect(
     appstate: AppState,
     data: AuthenticationRequest,
-    user_id: Option<i64>,
+    session: &Session,
 ) -> Result<String, WebError> {
     let mut url =
         Url::parse(&data.redirect_uri).map_err(|_| WebError::Http(StatusCode::BAD_REQUEST))?;
     let mut auth_code = AuthCode::new(
-        user_id.unwrap(),
+        session.user_id,
         data.client_id,
         data.redirect_uri,
         data.scope,
         data.nonce,
         data.code_challenge,
+        Some(session.id.clone()),
     );
     auth_code.save(&appstate.pool).await?;
 
@@ -332,6 +334,36 @@ fn redirect_to<T: AsRef<str>>(
     (StatusCode::FOUND, headers, private_cookies)
 }
 
+/// Helper function to redirect user who isn't allowed to use the client back to it
+/// with `access_denied` error.
+fn access_denied_redirect(
+    data: AuthenticationRequest,
+    oauth2client: &OAuth2Client,
+    private_cookies: PrivateCookieJar,
+) -> Result<(StatusCode, HeaderMap, PrivateCookieJar), WebError> {
+    let mut url =
+        Url::parse(&data.redirect_uri).map_err(|_| WebError::Http(StatusCode::BAD_REQUEST))?;
+    {
+        let mut query_pairs = url.query_pairs_mut();
+        query_pairs.append_pair("error", CoreAuthErrorResponseType::AccessDenied.as_ref());
+        query_pairs.append_pair(
+            "error_description",
+            &format!(
+                "You are not allowed to use {}, ask your administrator for access",
+                oauth2client.name
+            ),
+        );
+        if let Some(state) = data.state {
+            query_pairs.append_pair("state", &state);
+        };
+    };
+
+    Ok(redirect_to(
+        url,
+        private_cookies.remove(removal_cookie(SIGN_IN_COOKIE_NAME)),
+    ))
+}
+
 /// Helper function to redirect unauthorized user to login page
 /// and store information about OpenID authorize url in cookie to redirect later
 async fn login_redirect(
@@ -353,12 +385,15 @@ async fn login_redirect(
             .clone()
             .expect("Cookie domain not found"),
     )
-    .path("/")
+    .path(config.cookie_path())
     .secure(!config.cookie_insecure)
     .same_site(SameSite::Lax)
     .http_only(true)
     .max_age(Duration::minutes(10));
-    Ok(redirect_to("/login", private_cookies.add(cookie)))
+    Ok(redirect_to(
+        format!("{}/login", config.base_path()),
+        private_cookies.add(cookie),
+    ))
 }
 
 /// Authorization Endpoint
@@ -383,7 +418,11 @@ pub async fn authorization(
                         );
                         // FIXME: do not panic
                         return Ok(redirect_to(
-                            format!("/consent?{}", serde_urlencoded::to_string(data).unwrap(),),
+                            format!(
+                                "{}/consent?{}",
+                                server_config().base_path(),
+                                serde_urlencoded::to_string(data).unwrap(),
+                            ),
                             private_cookies,
                         ));
                     }
@@ -399,7 +438,11 @@ pub async fn authorization(
                                 // If session expired return login
                                 if session.expired() {
                                     info!("Session {} for user id {} has expired, redirecting to login", session.id, session.user_id);
-                                    let _result = session.delete(&appstate.pool).await;
+                                    remove_stale_session(&appstate.pool, session).await;
+                                    login_redirect(&data, private_cookies).await
+                                } else if session.is_impersonation() {
+                                    // impersonation is read-only, it can't be used to authorize apps
+                                    warn!("Session {} is an impersonation session, redirecting to login", session.id);
                                     login_redirect(&data, private_cookies).await
                                 } else {
                                     let user = User::find_by_id(&appstate.pool, session.user_id)
@@ -418,6 +461,19 @@ pub async fn authorization(
                                         return login_redirect(&data, private_cookies).await;
                                     }
 
+                                    // Check if user is allowed to use the app before asking for consent.
+                                    if !oauth2client.is_allowed_for(&appstate.pool, &user).await? {
+                                        warn!(
+                                            "User {} is not allowed to use OAuth client {}, denying access",
+                                            user.username, oauth2client.name
+                                        );
+                                        return access_denied_redirect(
+                                            data,
+                                            &oauth2client,
+                                            private_cookies,
+                                        );
+                                    }
+
                                     // If session is present check if app is in user authorized apps.
                                     // If yes return auth code and state else redirect to consent form.
                                     if let Some(app) =
@@ -434,12 +490,9 @@ pub async fn authorization(
                                         );
                                         let private_cookies = private_cookies
                                             .remove(Cookie::from(SIGN_IN_COOKIE_NAME));
-                                        let location = generate_auth_code_redirect(
-                                            appstate,
-                                            data,
-                                            Some(session.user_id),
-                                        )
-                                        .await?;
+                                        let location =
+                                            generate_auth_code_redirect(appstate, data, &session)
+                                                .await?;
                                         Ok(redirect_to(location, private_cookies))
                                     } else {
                                         // If authorized app not found redirect to consent form
@@ -504,14 +557,18 @@ pub async fn authorization(
 pub struct GroupClaims {
     #[serde(skip_serializing_if = "Option::is_none")]
     groups: Option<Vec<String>>,
+    // session ID, used by clients to match back-channel logout requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sid: Option<String>,
 }
 
 impl AdditionalClaims for GroupClaims {}
 
 pub async fn get_group_claims(pool: &DbPool, user: &User) -> Result<GroupClaims, WebError> {
-    let groups = user.member_of_names(pool).await?;
+    let groups = user.effective_member_of_names(pool).await?;
     Ok(GroupClaims {
         groups: Some(groups),
+        sid: None,
     })
 }
 
@@ -531,6 +588,16 @@ pub async fn secure_authorization(
         {
             match data.validate_for_client(&oauth2client) {
                 Ok(()) => {
+                    if !oauth2client
+                        .is_allowed_for(&appstate.pool, &session_info.user)
+                        .await?
+                    {
+                        warn!(
+                            "User {} is not allowed to use OAuth client {}, denying access",
+                            session_info.user.username, oauth2client.name
+                        );
+                        return access_denied_redirect(data, &oauth2client, private_cookies);
+                    }
                     if OAuth2AuthorizedApp::find_by_user_and_oauth2client_id(
                         &appstate.pool,
                         session_info.user.id.unwrap(),
@@ -559,7 +626,7 @@ pub async fn secure_authorization(
                     );
                     let private_cookies = private_cookies.remove(SIGN_IN_COOKIE_NAME);
                     let location =
-                        generate_auth_code_redirect(appstate, data, session_info.user.id).await?;
+                        generate_auth_code_redirect(appstate, data, &session_info.session).await?;
                     info!(
                         "Redirecting user {} to {location}",
                         session_info.user.username
@@ -752,6 +819,29 @@ impl TokenRequest {
     }
 }
 
+/// Check if the user who authorized the app the token was issued for can still use it.
+async fn token_user_allowed(pool: &DbPool, token: &OAuth2Token) -> Result<bool, WebError> {
+    let Some(app) = OAuth2AuthorizedApp::find_by_id(pool, token.oauth2authorizedapp_id).await?
+    else {
+        return Ok(false);
+    };
+    let (Some(client), Some(user)) = (
+        OAuth2Client::find_by_id(pool, app.oauth2client_id).await?,
+        User::find_by_id(pool, app.user_id).await?,
+    ) else {
+        return Ok(false);
+    };
+    if client.is_allowed_for(pool, &user).await? {
+        Ok(true)
+    } else {
+        warn!(
+            "User {} is no longer allowed to use OAuth client {}, refusing to refresh token",
+            user.username, client.name
+        );
+        Ok(false)
+    }
+}
+
 /// Token Endpoint
 /// https://openid.net/specs/openid-connect-core-1_0.html#TokenEndpoint
 /// https://openid.net/specs/openid-connect-core-1_0.html#RefreshTokens
@@ -811,11 +901,20 @@ pub async fn token(
                                     auth_code.redirect_uri.clone(),
                                     auth_code.scope.clone(),
                                 );
-                                let group_claims = if auth_code.scope.contains("groups") {
+                                let mut group_claims = if auth_code.scope.contains("groups") {
                                     get_group_claims(&appstate.pool, &user).await?
                                 } else {
-                                    GroupClaims { groups: None }
+                                    GroupClaims::default()
                                 };
+                                // link session for back-channel logout
+                                if let Some(session_id) = &auth_code.session_id {
+                                    group_claims.sid = OAuth2Session::link(
+                                        &appstate.pool,
+                                        client.id.unwrap(),
+                                        session_id,
+                                    )
+                                    .await?;
+                                }
                                 let config = server_config();
                                 match form.authorization_code_flow(
                                     &auth_code,
@@ -828,6 +927,9 @@ pub async fn token(
                                 ) {
                                     Ok(response) => {
                                         token.save(&appstate.pool).await?;
+                                        token
+                                            .record_issuance(&appstate.pool, "authorization_code")
+                                            .await?;
                                         info!(
                                             "Issued new token for user {} client {}",
                                             user.username, client.name
@@ -873,9 +975,25 @@ pub async fn token(
                 if let Ok(Some(mut token)) =
                     OAuth2Token::find_refresh_token(&appstate.pool, &refresh_token).await
                 {
+                    // access could have been restricted since the token was issued
+                    if !token_user_allowed(&appstate.pool, &token).await? {
+                        token.delete(&appstate.pool).await?;
+                        let response = StandardErrorResponse::<CoreErrorResponseType>::new(
+                            CoreErrorResponseType::InvalidGrant,
+                            Some("User is not allowed to use this application".into()),
+                            None,
+                        );
+                        return Ok(ApiResponse {
+                            json: json!(response),
+                            status: StatusCode::BAD_REQUEST,
+                        });
+                    }
                     token.refresh_and_save(&appstate.pool).await?;
                     let response = TokenRequest::refresh_token_flow(&token);
                     token.save(&appstate.pool).await?;
+                    token
+                        .record_issuance(&appstate.pool, "refresh_token")
+                        .await?;
                     return Ok(ApiResponse {
                         json: json!(response),
                         status: StatusCode::OK,
@@ -902,7 +1020,7 @@ pub async fn userinfo(user_info: AccessUserInfo) -> ApiResult {
     })
 }
 
-// Must be served under /.well-known/openid-configuration
+// Must be served under /.well-known/openid-configuration, relative to the issuer URL
 pub async fn openid_configuration() -> ApiResult {
     let config = server_config();
     let provider_metadata = CoreProviderMetadata::new(
@@ -947,9 +1065,13 @@ pub async fn openid_configuration() -> ApiResult {
     .set_userinfo_endpoint(Some(UserInfoUrl::from_url(
         config.url.join("api/v1/oauth/userinfo").unwrap(),
     )));
+    // https://openid.net/specs/openid-connect-backchannel-1_0.html#BCSupport
+    let mut provider_metadata = json!(provider_metadata);
+    provider_metadata["backchannel_logout_supported"] = json!(true);
+    provider_metadata["backchannel_logout_session_supported"] = json!(true);
 
     Ok(ApiResponse {
-        json: json!(provider_metadata),
+        json: provider_metadata,
         status: StatusCode::OK,
     })
 }
@@ -7,19 +7,53 @@ use struct_patch::Patch;
 
 use super::{ApiResponse, ApiResult};
 use crate::{
-    auth::{AdminRole, SessionInfo},
+    auth::{challenge::ChallengePolicy, AdminRole, SessionInfo},
     db::{
-        models::settings::{SettingsEssentials, SettingsPatch},
-        Settings,
+        models::{
+            notification_recipient::NotificationRecipient,
+            settings::{SettingsEssentials, SettingsPatch},
+        },
+        Device, Session, Settings,
     },
+    dns,
     error::WebError,
     ldap::LDAPConnection,
-    AppState,
+    mfa_policy, new_country_alert,
+    notifications::{deliver, AdminNotification, NotificationCategory},
+    password_policy::PasswordPolicy,
+    ssh_ca, templates, AppState,
 };
 
+static TEST_NOTIFICATION_SUBJECT: &str = "Defguard notification test";
+static TEST_NOTIFICATION_MESSAGE: &str = "This is a test notification sent from Defguard settings";
+
 static DEFAULT_NAV_LOGO_URL: &str = "/svg/defguard-nav-logo.svg";
 static DEFAULT_MAIN_LOGO_URL: &str = "/svg/logo-defguard-white.svg";
 
+// Handle users affected by MFA method changes. Settings are already saved at this point,
+// so an error means only some of the users have been handled.
+async fn apply_mfa_settings_change(
+    appstate: &AppState,
+    previous: &Settings,
+    settings: &Settings,
+) -> Result<(), WebError> {
+    mfa_policy::apply_settings_change(&appstate.pool, &appstate.mail_tx, previous, settings)
+        .await
+        .map_err(|err| {
+            error!("Failed to apply MFA settings change: {err}");
+            WebError::Http(StatusCode::INTERNAL_SERVER_ERROR)
+        })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/settings",
+    tag = "settings",
+    responses(
+        (status = 200, description = "Instance settings", body = Settings),
+    ),
+    security(())
+)]
 pub async fn get_settings(State(appstate): State<AppState>) -> ApiResult {
     debug!("Retrieving settings");
     if let Some(mut settings) = Settings::find_by_id(&appstate.pool, 1).await? {
@@ -41,6 +75,17 @@ pub async fn get_settings(State(appstate): State<AppState>) -> ApiResult {
     })
 }
 
+#[utoipa::path(
+    put,
+    path = "/api/v1/settings",
+    tag = "settings",
+    request_body = Settings,
+    responses(
+        (status = 200, description = "Settings updated"),
+        (status = 400, description = "Invalid password policy, MFA policy or DNS settings", body = ApiError),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
 pub async fn update_settings(
     _admin: AdminRole,
     session: SessionInfo,
@@ -49,11 +94,32 @@ pub async fn update_settings(
 ) -> ApiResult {
     debug!("User {} updating settings", session.user.username);
     data.id = Some(1);
+    PasswordPolicy::validate_settings(&data).map_err(WebError::BadRequest)?;
+    mfa_policy::validate_settings(&data).map_err(WebError::BadRequest)?;
+    dns::validate_settings(&data).map_err(WebError::BadRequest)?;
+    Session::validate_settings(&data).map_err(WebError::BadRequest)?;
+    Device::validate_settings(&data).map_err(WebError::BadRequest)?;
+    new_country_alert::validate_settings(&data).map_err(WebError::BadRequest)?;
+    ChallengePolicy::validate_settings(&data).map_err(WebError::BadRequest)?;
+    ssh_ca::validate_settings(&data).map_err(WebError::BadRequest)?;
+    let previous = Settings::get_settings(&appstate.pool).await?;
+    mfa_policy::update_grace_period(&previous, &mut data);
     data.save(&appstate.pool).await?;
     info!("User {} updated settings", session.user.username);
+    dns::request_sync();
+    apply_mfa_settings_change(&appstate, &previous, &data).await?;
     Ok(ApiResponse::default())
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/settings_essentials",
+    tag = "settings",
+    responses(
+        (status = 200, description = "Settings needed before logging in", body = SettingsEssentials),
+    ),
+    security(())
+)]
 pub async fn get_settings_essentials(State(appstate): State<AppState>) -> ApiResult {
     debug!("Retrieving essential settings");
     let mut settings = SettingsEssentials::get_settings_essentials(&appstate.pool).await?;
@@ -70,6 +136,16 @@ pub async fn get_settings_essentials(State(appstate): State<AppState>) -> ApiRes
     })
 }
 
+#[utoipa::path(
+    put,
+    path = "/api/v1/settings/{id}",
+    tag = "settings",
+    params(("id" = i64, Path, description = "Settings ID")),
+    responses(
+        (status = 200, description = "Default branding restored", body = Settings),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
 pub async fn set_default_branding(
     _admin: AdminRole,
     State(appstate): State<AppState>,
@@ -100,6 +176,17 @@ pub async fn set_default_branding(
     }
 }
 
+#[utoipa::path(
+    patch,
+    path = "/api/v1/settings",
+    tag = "settings",
+    request_body(content = Settings, description = "Any subset of settings fields"),
+    responses(
+        (status = 200, description = "Settings updated"),
+        (status = 400, description = "Invalid password policy, MFA policy or DNS settings", body = ApiError),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
 pub async fn patch_settings(
     _admin: AdminRole,
     State(appstate): State<AppState>,
@@ -107,13 +194,35 @@ pub async fn patch_settings(
     Json(data): Json<SettingsPatch>,
 ) -> ApiResult {
     debug!("Admin {} patching settings.", &session.user.username);
-    let mut settings = Settings::get_settings(&appstate.pool).await?;
+    let previous = Settings::get_settings(&appstate.pool).await?;
+    let mut settings = previous.clone();
     settings.apply(data);
+    PasswordPolicy::validate_settings(&settings).map_err(WebError::BadRequest)?;
+    mfa_policy::validate_settings(&settings).map_err(WebError::BadRequest)?;
+    dns::validate_settings(&settings).map_err(WebError::BadRequest)?;
+    Session::validate_settings(&settings).map_err(WebError::BadRequest)?;
+    Device::validate_settings(&settings).map_err(WebError::BadRequest)?;
+    new_country_alert::validate_settings(&settings).map_err(WebError::BadRequest)?;
+    ChallengePolicy::validate_settings(&settings).map_err(WebError::BadRequest)?;
+    ssh_ca::validate_settings(&settings).map_err(WebError::BadRequest)?;
+    mfa_policy::update_grace_period(&previous, &mut settings);
     settings.save(&appstate.pool).await?;
     info!("Admin {} patched settings.", &session.user.username);
+    dns::request_sync();
+    apply_mfa_settings_change(&appstate, &previous, &settings).await?;
     Ok(ApiResponse::default())
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/ldap/test",
+    tag = "settings",
+    responses(
+        (status = 200, description = "Connected to LDAP"),
+        (status = 400, description = "LDAP connection failed"),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
 pub async fn test_ldap_settings(_admin: AdminRole, State(appstate): State<AppState>) -> ApiResult {
     debug!("Testing LDAP connection");
     if LDAPConnection::create(&appstate.pool).await.is_ok() {
@@ -130,3 +239,104 @@ pub async fn test_ldap_settings(_admin: AdminRole, State(appstate): State<AppSta
         })
     }
 }
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/settings/notifications",
+    tag = "settings",
+    responses(
+        (status = 200, description = "Admin notification recipients", body = [NotificationRecipient]),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
+pub async fn get_notification_recipients(
+    _admin: AdminRole,
+    State(appstate): State<AppState>,
+) -> ApiResult {
+    debug!("Retrieving notification recipients");
+    let recipients = NotificationRecipient::all(&appstate.pool).await?;
+    info!("Retrieved notification recipients");
+    Ok(ApiResponse {
+        json: json!(recipients),
+        status: StatusCode::OK,
+    })
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/settings/notifications",
+    tag = "settings",
+    request_body = [NotificationRecipient],
+    responses(
+        (status = 200, description = "Recipients replaced", body = [NotificationRecipient]),
+        (status = 400, description = "Invalid recipient", body = ApiError),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
+pub async fn update_notification_recipients(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Json(mut data): Json<Vec<NotificationRecipient>>,
+) -> ApiResult {
+    debug!(
+        "User {} updating notification recipients",
+        session.user.username
+    );
+    for recipient in &data {
+        recipient.validate().map_err(WebError::BadRequest)?;
+    }
+    let mut transaction = appstate.pool.begin().await?;
+    NotificationRecipient::delete_all(&mut *transaction).await?;
+    for recipient in &mut data {
+        recipient.id = None;
+        recipient.save(&mut *transaction).await?;
+    }
+    transaction.commit().await?;
+    info!(
+        "User {} updated notification recipients",
+        session.user.username
+    );
+    Ok(ApiResponse {
+        json: json!(data),
+        status: StatusCode::OK,
+    })
+}
+
+/// Send a test notification to every configured recipient and report per-recipient results.
+#[utoipa::path(
+    post,
+    path = "/api/v1/settings/notifications/test",
+    tag = "settings",
+    responses(
+        (status = 200, description = "Delivery result for every recipient", body = [DeliveryResult]),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
+pub async fn test_notifications(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+) -> ApiResult {
+    debug!(
+        "User {} sending test notification to all recipients",
+        session.user.username
+    );
+    let recipients = NotificationRecipient::all(&appstate.pool).await?;
+    let notification = AdminNotification {
+        category: NotificationCategory::System,
+        subject: TEST_NOTIFICATION_SUBJECT.into(),
+        message: TEST_NOTIFICATION_MESSAGE.into(),
+        html: templates::test_mail(Some(&session.session))?,
+    };
+    let results = deliver(&recipients, &appstate.mail_tx, &notification).await;
+    info!(
+        "User {} sent test notification to {} recipients",
+        session.user.username,
+        results.len()
+    );
+    Ok(ApiResponse {
+        json: json!(results),
+        status: StatusCode::OK,
+    })
+}
@@ -0,0 +1,286 @@
+//! One-time links to device configs, for handing configs of devices created on behalf
+//! of users over to them without pasting keys into chat.
+
+use axum::{
+    extract::{Json, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{Duration as ChronoDuration, Utc};
+use serde_json::json;
+use tokio::time::sleep;
+use utoipa::ToSchema;
+
+use super::{
+    device_for_admin_or_self,
+    mail::send_shared_config_downloaded_email,
+    wireguard::{ensure_not_archived, find_network},
+    ApiResponse, ApiResult,
+};
+use crate::{
+    appstate::AppState,
+    auth::{
+        failed_token::{check_token_attempt, log_failed_token_attempt, LOCKOUT_RESPONSE_DELAY},
+        SessionInfo,
+    },
+    db::{
+        models::{device::WireguardNetworkDevice, shared_config::SharedConfig},
+        Device, User, WireguardNetwork,
+    },
+    error::WebError,
+    headers::ClientIp,
+    server_config,
+};
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct ShareConfigRequest {
+    // configs include preshared keys held by the server, sharing them has to be confirmed
+    #[serde(default)]
+    pub confirm_secrets: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SharedConfigLink {
+    #[serde(flatten)]
+    shared_config: SharedConfig,
+    url: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/device/{device_id}/config/{network_id}/share",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID"), ("network_id" = i64, Path, description = "Network ID")),
+    request_body(content = Option<ShareConfigRequest>, description = "Confirmation of sharing server-held keys"),
+    responses(
+        (status = 201, description = "One-time link to the device config", body = SharedConfigLink),
+        (status = 400, description = "Config contains server-held keys and sharing them wasn't confirmed", body = ApiError),
+        (status = 404, description = "Device not found or not assigned to the network", body = ApiError),
+        (status = 409, description = "Network is archived", body = ApiError),
+    )
+)]
+pub async fn share_device_config(
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path((device_id, network_id)): Path<(i64, i64)>,
+    request: Option<Json<ShareConfigRequest>>,
+) -> ApiResult {
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let device = device_for_admin_or_self(&appstate.pool, &session, device_id).await?;
+    let network = find_network(network_id, &appstate.pool).await?;
+    ensure_not_archived(&network)?;
+    let Some(network_device) =
+        WireguardNetworkDevice::find(&appstate.pool, device_id, network_id).await?
+    else {
+        return Err(WebError::ObjectNotFound(format!(
+            "device {} is not assigned to network {}",
+            device.name, network.name
+        )));
+    };
+    if network_device.preshared_key.is_some() && !request.confirm_secrets {
+        return Err(WebError::BadRequest(format!(
+            "Config of device {} contains a preshared key held by the server, \
+            set confirm_secrets to share it",
+            device.name
+        )));
+    }
+
+    let expires_at = Utc::now().naive_utc()
+        + ChronoDuration::from_std(*server_config().shared_config_timeout)
+            .expect("Failed to parse duration");
+    let mut shared_config = SharedConfig::new(device_id, network_id, session.user.id, expires_at);
+    shared_config.save(&appstate.pool).await?;
+    info!(
+        device_id,
+        "User {} shared config of device {} in network {} until {expires_at}",
+        session.user.username,
+        device.name,
+        network.name
+    );
+    let url = format!(
+        "{}api/v1/shared_config/{}",
+        server_config().url,
+        shared_config.token
+    );
+
+    Ok(ApiResponse {
+        json: json!(SharedConfigLink { shared_config, url }),
+        status: StatusCode::CREATED,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/device/{device_id}/shared_config",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID")),
+    responses(
+        (status = 200, description = "Unused one-time links to device configs", body = [SharedConfig]),
+        (status = 404, description = "Device not found", body = ApiError),
+    )
+)]
+pub async fn list_shared_configs(
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(device_id): Path<i64>,
+) -> ApiResult {
+    device_for_admin_or_self(&appstate.pool, &session, device_id).await?;
+    let shared_configs = SharedConfig::active_for_device(&appstate.pool, device_id).await?;
+    Ok(ApiResponse {
+        json: json!(shared_configs),
+        status: StatusCode::OK,
+    })
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/device/{device_id}/shared_config/{id}",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID"), ("id" = i64, Path, description = "Shared config ID")),
+    responses(
+        (status = 200, description = "Link revoked"),
+        (status = 404, description = "Device or link not found", body = ApiError),
+    )
+)]
+pub async fn revoke_shared_config(
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path((device_id, id)): Path<(i64, i64)>,
+) -> ApiResult {
+    let device = device_for_admin_or_self(&appstate.pool, &session, device_id).await?;
+    let mut shared_config = SharedConfig::find_by_id(&appstate.pool, id)
+        .await?
+        .filter(|shared_config| shared_config.device_id == device_id)
+        .ok_or_else(|| WebError::ObjectNotFound(format!("shared config {id} not found")))?;
+    shared_config.revoke(&appstate.pool).await?;
+    info!(
+        device_id,
+        "User {} revoked shared config {id} of device {}", session.user.username, device.name
+    );
+    Ok(ApiResponse::default())
+}
+
+/// Rejection of a link which has been used, revoked or has expired.
+fn rejected(shared_config: &SharedConfig, ip_address: &str) -> WebError {
+    warn!(
+        device_id = shared_config.device_id,
+        "Rejected download of used, revoked or expired shared config {:?} from {ip_address}",
+        shared_config.id
+    );
+    WebError::Http(StatusCode::GONE)
+}
+
+/// Download device config using a one-time link. The link can't be used again afterwards,
+/// and the device owner is notified.
+#[utoipa::path(
+    get,
+    path = "/api/v1/shared_config/{token}",
+    tag = "device",
+    params(("token" = String, Path, description = "One-time link token")),
+    responses(
+        (status = 200, description = "WireGuard config of the device", body = String, content_type = "text/plain"),
+        (status = 404, description = "Link not found", body = ApiError),
+        (status = 410, description = "Link has been used, revoked or has expired", body = ApiError),
+        (status = 429, description = "Too many invalid attempts", body = ApiError),
+    ),
+    security(())
+)]
+pub async fn download_shared_config(
+    State(appstate): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Path(token): Path<String>,
+) -> Result<Response, WebError> {
+    if check_token_attempt(&appstate.failed_tokens, Some(client_ip)).is_err() {
+        // slow down automated guessing
+        sleep(LOCKOUT_RESPONSE_DELAY).await;
+        return Err(WebError::Http(StatusCode::TOO_MANY_REQUESTS));
+    }
+    let Some(mut shared_config) = SharedConfig::find_by_token(&appstate.pool, &token).await? else {
+        if let Some(prefix) =
+            log_failed_token_attempt(&appstate.failed_tokens, Some(client_ip), &token)
+        {
+            let revoked = SharedConfig::revoke_by_prefix(&appstate.pool, &prefix).await?;
+            warn!("Repeated attempts to guess a shared config link, revoked {revoked} links");
+        }
+        return Err(WebError::ObjectNotFound("shared config not found".into()));
+    };
+    let ip_address = client_ip.to_string();
+    if !shared_config.is_active() {
+        return Err(rejected(&shared_config, &ip_address));
+    }
+
+    // Render the config before the link is used up, so errors don't burn it.
+    let mut transaction = appstate.pool.begin().await?;
+    let device = Device::find_by_id(&mut *transaction, shared_config.device_id)
+        .await?
+        .ok_or_else(|| WebError::ObjectNotFound("device not found".into()))?;
+    let network = WireguardNetwork::find_by_id(&mut *transaction, shared_config.network_id)
+        .await?
+        .ok_or_else(|| WebError::ObjectNotFound("network not found".into()))?;
+    let network_device = WireguardNetworkDevice::find(
+        &mut *transaction,
+        shared_config.device_id,
+        shared_config.network_id,
+    )
+    .await?
+    .ok_or_else(|| {
+        WebError::ObjectNotFound(format!(
+            "device {} is not assigned to network {}",
+            device.name, network.name
+        ))
+    })?;
+    let config = device.create_config(&network, &network_device);
+    if !shared_config
+        .consume(&mut *transaction, &ip_address)
+        .await?
+    {
+        return Err(rejected(&shared_config, &ip_address));
+    }
+    transaction.commit().await?;
+    info!(
+        device_id = shared_config.device_id,
+        "Shared config of device {} in network {} downloaded from {ip_address}",
+        device.name,
+        network.name
+    );
+
+    // the config has been handed over, a failed notification must not fail the download
+    match User::find_by_id(&appstate.pool, device.user_id).await {
+        Ok(Some(owner)) => {
+            if let Err(err) = send_shared_config_downloaded_email(
+                &device.name,
+                &network.name,
+                &ip_address,
+                &shared_config.used_at.unwrap_or_default(),
+                &owner.email,
+                &appstate.mail_tx,
+            ) {
+                error!(
+                    "Failed to notify owner of device {} about shared config download: {err}",
+                    device.name
+                );
+            }
+        }
+        Ok(None) => {}
+        Err(err) => error!(
+            "Failed to find owner of device {} to notify about shared config download: {err}",
+            device.name
+        ),
+    }
+
+    let disposition = format!(
+        "attachment; filename=\"{}\"",
+        device.config_file_name(&network)
+    );
+    // configs contain keys, they must not be stored by browsers or proxies
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/plain"),
+            (header::CONTENT_DISPOSITION, disposition.as_str()),
+            (header::CACHE_CONTROL, "no-store"),
+            (header::PRAGMA, "no-cache"),
+        ],
+        config,
+    )
+        .into_response())
+}
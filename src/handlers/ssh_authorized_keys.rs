@@ -6,6 +6,7 @@ use axum::{
 use serde_json::json;
 use sqlx::{query, Error as SqlxError, PgExecutor};
 use ssh_key::PublicKey;
+use utoipa::ToSchema;
 
 use super::{user_for_admin_or_self, ApiResponse, ApiResult};
 use crate::{
@@ -18,7 +19,7 @@ use crate::{
     error::WebError,
 };
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub(crate) struct AuthenticationKeyInfo {
     id: i64,
     name: Option<String>,
@@ -111,7 +112,7 @@ pub async fn get_authorized_keys(
                         if let Some(user) = User::find_by_username(&appstate.pool, username).await?
                         {
                             // check if user belongs to specified group
-                            let members = group.member_usernames(&appstate.pool).await?;
+                            let members = group.effective_member_usernames(&appstate.pool).await?;
                             if members.contains(&user.username) {
                                 add_user_ssh_keys_to_list(&appstate.pool, &user, &mut ssh_keys)
                                     .await;
@@ -125,7 +126,7 @@ pub async fn get_authorized_keys(
                     None => {
                         debug!("Fetching SSH keys for all users in group {group_name}");
                         // fetch all users in group
-                        let users = group.members(&appstate.pool).await?;
+                        let users = group.effective_members(&appstate.pool).await?;
                         for user in users {
                             add_user_ssh_keys_to_list(&appstate.pool, &user, &mut ssh_keys).await;
                         }
@@ -153,13 +154,25 @@ pub async fn get_authorized_keys(
     Ok(ssh_keys.join("\n"))
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
 pub struct AddAuthenticationKeyData {
     key: String,
     name: String,
     key_type: AuthenticationKeyType,
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{username}/auth_key",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    request_body = AddAuthenticationKeyData,
+    responses(
+        (status = 201, description = "Key added"),
+        (status = 400, description = "Invalid key or key already exists", body = ApiError),
+        (status = 403, description = "Not an admin or the user itself", body = ApiError),
+    )
+)]
 pub async fn add_authentication_key(
     State(appstate): State<AppState>,
     session: SessionInfo,
@@ -225,6 +238,16 @@ pub async fn add_authentication_key(
 }
 
 // GET on user, returns AuthenticationKeyInfo vector in JSON
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/{username}/auth_key",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    responses(
+        (status = 200, description = "SSH and GPG keys of the user", body = [AuthenticationKeyInfo]),
+        (status = 403, description = "Not an admin or the user itself", body = ApiError),
+    )
+)]
 pub async fn fetch_authentication_keys(
     State(appstate): State<AppState>,
     Path(username): Path<String>,
@@ -246,6 +269,17 @@ pub async fn fetch_authentication_keys(
     })
 }
 
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/{username}/auth_key/{key_id}",
+    tag = "user",
+    params(("username" = String, Path, description = "Username"), ("key_id" = i64, Path, description = "Key ID")),
+    responses(
+        (status = 200, description = "Key deleted"),
+        (status = 400, description = "Key not found", body = ApiError),
+        (status = 403, description = "Not an admin or the key owner", body = ApiError),
+    )
+)]
 pub async fn delete_authentication_key(
     State(appstate): State<AppState>,
     session: SessionInfo,
@@ -270,11 +304,24 @@ pub async fn delete_authentication_key(
     })
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, ToSchema)]
 pub struct RenameRequest {
     name: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{username}/auth_key/{key_id}/rename",
+    tag = "user",
+    params(("username" = String, Path, description = "Username"), ("key_id" = i64, Path, description = "Key ID")),
+    request_body = RenameRequest,
+    responses(
+        (status = 200, description = "Key renamed"),
+        (status = 400, description = "YubiKey keys are renamed with the YubiKey", body = ApiError),
+        (status = 403, description = "Not an admin or the key owner", body = ApiError),
+        (status = 404, description = "Key not found", body = ApiError),
+    )
+)]
 pub async fn rename_authentication_key(
     State(appstate): State<AppState>,
     session: SessionInfo,
@@ -0,0 +1,264 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde_json::json;
+use ssh_key::PublicKey;
+use utoipa::ToSchema;
+
+use super::{ApiResponse, ApiResult};
+use crate::{
+    appstate::AppState,
+    auth::{AdminRole, SessionInfo},
+    db::{
+        models::{
+            authentication_key::{AuthenticationKey, AuthenticationKeyType},
+            ssh_certificate::SshCertificate,
+        },
+        Settings,
+    },
+    error::WebError,
+    ssh_ca::{build_krl, ca_key, issue_certificate, user_principals},
+};
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SignSshKeyRequest {
+    /// One of the user's registered SSH keys, in OpenSSH format.
+    public_key: String,
+    /// Validity in minutes, limited by settings; the maximum if not set.
+    #[serde(default)]
+    validity_minutes: Option<i32>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SignedSshKey {
+    /// Certificate in OpenSSH format.
+    certificate: String,
+    serial: i64,
+    key_id: String,
+    principals: Vec<String>,
+    valid_after: NaiveDateTime,
+    valid_before: NaiveDateTime,
+}
+
+/// Sign one of the user's registered SSH keys with the SSH certificate authority.
+///
+/// Principals of the certificate are the username and ones mapped from the user's groups.
+#[utoipa::path(
+    post,
+    path = "/api/v1/me/ssh/sign",
+    tag = "ssh",
+    request_body = SignSshKeyRequest,
+    responses(
+        (status = 201, description = "Signed certificate", body = SignedSshKey),
+        (status = 400, description = "Invalid key or validity, or SSH CA is disabled", body = ApiError),
+        (status = 403, description = "Key not registered for the user, user disabled or impersonated", body = ApiError),
+    )
+)]
+pub async fn sign_ssh_key(
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Json(data): Json<SignSshKeyRequest>,
+) -> ApiResult {
+    let user = session.user;
+    let username = user.username.clone();
+    debug!("User {username} requested an SSH certificate");
+    let settings = Settings::get_settings(&appstate.pool).await?;
+    if !settings.ssh_ca_enabled {
+        return Err(WebError::BadRequest(
+            "SSH certificate authority is disabled".into(),
+        ));
+    }
+    // certificates would be attributed to the impersonated user
+    if session.impersonator.is_some() {
+        return Err(WebError::Forbidden(
+            "SSH certificates can't be issued while impersonating".into(),
+        ));
+    }
+    if !user.is_active {
+        warn!("Refused SSH certificate for disabled user {username}");
+        return Err(WebError::Forbidden("User is disabled".into()));
+    }
+    let Some(user_id) = user.id else {
+        error!("Model returned user ({username}) without ID");
+        return Err(WebError::ModelError(
+            "Model returned user without ID".into(),
+        ));
+    };
+    let public_key = data
+        .public_key
+        .trim()
+        .parse::<PublicKey>()
+        .map_err(|_| WebError::BadRequest("Invalid SSH public key".into()))?;
+
+    // keys are compared without comments
+    let registered = AuthenticationKey::find_by_user_id(
+        &appstate.pool,
+        user_id,
+        Some(AuthenticationKeyType::Ssh),
+    )
+    .await?
+    .iter()
+    .filter_map(|key| key.key.parse::<PublicKey>().ok())
+    .any(|key| key.key_data() == public_key.key_data());
+    if !registered {
+        warn!(
+            security_action = "ssh_certificate_refused",
+            user_id,
+            "User {username} requested an SSH certificate for a key not registered for them"
+        );
+        return Err(WebError::Forbidden(
+            "SSH key is not registered for the user".into(),
+        ));
+    }
+
+    let max_validity = settings.ssh_ca_max_validity_minutes;
+    let validity_minutes = match data.validity_minutes {
+        Some(minutes) if minutes < 1 => {
+            return Err(WebError::BadRequest(
+                "Validity must be at least one minute".into(),
+            ))
+        }
+        Some(minutes) => minutes.min(max_validity),
+        None => max_validity,
+    };
+    let ca_key = ca_key(&appstate.pool, true)
+        .await?
+        .ok_or(WebError::Http(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let groups = user.member_of_names(&appstate.pool).await?;
+    let principals = user_principals(
+        &username,
+        &groups,
+        settings.ssh_ca_principal_mapping.as_deref(),
+    );
+    let (record, certificate) = issue_certificate(
+        &appstate.pool,
+        &ca_key,
+        user_id,
+        &username,
+        &public_key,
+        principals,
+        Duration::minutes(validity_minutes.into()),
+    )
+    .await?;
+    info!(
+        "Issued SSH certificate {} for user {username} with principals {}, valid until {}",
+        record.key_id,
+        record.principals.join(", "),
+        record.valid_before
+    );
+
+    Ok(ApiResponse {
+        json: json!(SignedSshKey {
+            certificate,
+            serial: record.id.unwrap_or_default(),
+            key_id: record.key_id,
+            principals: record.principals,
+            valid_after: record.valid_after,
+            valid_before: record.valid_before,
+        }),
+        status: StatusCode::CREATED,
+    })
+}
+
+/// Public key of the SSH certificate authority, for `TrustedUserCAKeys` option of `sshd`.
+#[utoipa::path(
+    get,
+    path = "/api/v1/ssh/ca",
+    tag = "ssh",
+    responses(
+        (status = 200, description = "CA public key in OpenSSH format", body = String, content_type = "text/plain"),
+        (status = 404, description = "No CA key yet", body = ApiError),
+    ),
+    security(())
+)]
+pub async fn ssh_ca_public_key(State(appstate): State<AppState>) -> Result<String, WebError> {
+    let ca_key = ca_key(&appstate.pool, false)
+        .await?
+        .ok_or_else(|| WebError::ObjectNotFound("SSH CA key not found".into()))?;
+    ca_key
+        .public_key()
+        .to_openssh()
+        .map_err(|_| WebError::Http(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// Key revocation list of revoked certificates, for `RevokedKeys` option of `sshd`.
+#[utoipa::path(
+    get,
+    path = "/api/v1/ssh/krl",
+    tag = "ssh",
+    responses(
+        (status = 200, description = "OpenSSH key revocation list", body = Vec<u8>, content_type = "application/octet-stream"),
+    ),
+    security(())
+)]
+pub async fn ssh_krl(State(appstate): State<AppState>) -> Result<Response, WebError> {
+    let now = Utc::now().naive_utc();
+    let ca_key = ca_key(&appstate.pool, false).await?;
+    let serials = SshCertificate::revoked_serials(&appstate.pool, now).await?;
+    let version = SshCertificate::last_revoked_at(&appstate.pool)
+        .await?
+        .map_or(0, |revoked_at| {
+            revoked_at.and_utc().timestamp().max(0) as u64
+        });
+    let krl = build_krl(
+        ca_key.as_ref().map(|key| key.public_key()),
+        &serials,
+        version,
+        now,
+    )?;
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], krl).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/ssh/certificate",
+    tag = "ssh",
+    responses(
+        (status = 200, description = "Issued SSH certificates, newest first", body = [SshCertificate]),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
+pub async fn list_ssh_certificates(
+    _admin: AdminRole,
+    State(appstate): State<AppState>,
+) -> ApiResult {
+    let certificates = SshCertificate::all(&appstate.pool).await?;
+    Ok(ApiResponse {
+        json: json!(certificates),
+        status: StatusCode::OK,
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/ssh/certificate/{serial}/revoke",
+    tag = "ssh",
+    params(("serial" = i64, Path, description = "Certificate serial number")),
+    responses(
+        (status = 200, description = "Revoked certificate", body = SshCertificate),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+        (status = 404, description = "Certificate not found", body = ApiError),
+    )
+)]
+pub async fn revoke_ssh_certificate(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(serial): Path<i64>,
+) -> ApiResult {
+    let certificate = SshCertificate::revoke(&appstate.pool, serial, Utc::now().naive_utc())
+        .await?
+        .ok_or_else(|| WebError::ObjectNotFound("SSH certificate not found".into()))?;
+    warn!(
+        security_action = "revoke_ssh_certificate",
+        serial, "User {} revoked SSH certificate {}", session.user.username, certificate.key_id
+    );
+    Ok(ApiResponse {
+        json: json!(certificate),
+        status: StatusCode::OK,
+    })
+}
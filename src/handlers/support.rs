@@ -1,8 +1,10 @@
 use axum::{extract::State, http::StatusCode};
+use serde_json::json;
 
 use super::{ApiResponse, ApiResult};
 use crate::{
     auth::{AdminRole, SessionInfo},
+    config_schema::effective_config,
     error::WebError,
     server_config,
     support::dump_config,
@@ -43,3 +45,24 @@ pub async fn logs(_admin: AdminRole, session: SessionInfo) -> Result<String, Web
         Ok("Log file not configured".to_string())
     }
 }
+
+/// Reference of configuration options with their effective values. Secrets are masked.
+#[utoipa::path(
+    get,
+    path = "/api/v1/system/config_schema",
+    tag = "settings",
+    responses(
+        (status = 200, description = "Configuration options", body = [ConfigOption]),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
+pub async fn config_schema(_admin: AdminRole, session: SessionInfo) -> ApiResult {
+    debug!(
+        "User {} listing configuration options",
+        session.user.username
+    );
+    Ok(ApiResponse {
+        json: json!(effective_config(server_config())),
+        status: StatusCode::OK,
+    })
+}
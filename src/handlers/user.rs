@@ -2,28 +2,40 @@ use axum::{
     extract::{Json, Path, Query, State},
     http::StatusCode,
 };
+use axum_extra::extract::cookie::CookieJar;
+use chrono::{Duration, NaiveDateTime, Utc};
 use serde_json::json;
+use utoipa::ToSchema;
 
 use super::{
+    auth::session_cookie,
     mail::{send_mfa_configured_email, EMAIL_PASSOWRD_RESET_START_SUBJECT},
-    user_for_admin_or_self, AddUserData, ApiResponse, ApiResult, PasswordChange,
-    PasswordChangeSelf, RecoveryCodes, StartEnrollmentRequest, Username, WalletChallenge,
-    WalletChange, WalletSignature,
+    user_for_admin_or_self, AddUserData, ApiResponse, ApiResult, DisconnectUser,
+    EnrollmentTokenInfo, PasswordChange, PasswordChangeSelf, RecoveryCodes, SessionUserInfo,
+    StartEnrollmentRequest, Username, WalletChallenge, WalletChange, WalletSignature,
 };
 use crate::{
     appstate::AppState,
-    auth::{SessionInfo, UserAdminRole},
+    auth::{AdminRole, SessionInfo, UserAdminRole},
     db::{
         models::enrollment::{Token, PASSWORD_RESET_TOKEN_TYPE},
-        AppEvent, MFAMethod, OAuth2AuthorizedApp, Settings, User, UserDetails, UserInfo, Wallet,
-        WebAuthn, WireguardNetwork,
+        AppEvent, DbPool, MFAMethod, OAuth2AuthorizedApp, ReadPool, Session, Settings, User,
+        UserDetails, UserInfo, Wallet, WebAuthn, WireguardNetwork,
     },
     error::WebError,
+    idempotency::IdempotentTx,
     ldap::utils::{ldap_add_user, ldap_change_password, ldap_delete_user, ldap_modify_user},
     mail::Mail,
+    mfa_policy::ensure_mfa_method_allowed,
+    password_policy::PasswordPolicy,
     server_config, templates,
+    user_timeline::{user_timeline as fetch_user_timeline, TimelineCursor, TimelineEvent},
 };
 
+const DEFAULT_TIMELINE_DAYS: i64 = 30;
+const DEFAULT_TIMELINE_LIMIT: i64 = 100;
+const MAX_TIMELINE_LIMIT: i64 = 1000;
+
 /// Verify the given username
 ///
 /// To enable LDAP sync usernames need to avoid reserved characters.
@@ -34,7 +46,7 @@ use crate::{
 /// - starts with non-special character
 /// - special characters: . - _
 /// - no whitespaces
-fn check_username(username: &str) -> Result<(), WebError> {
+pub(crate) fn check_username(username: &str) -> Result<(), WebError> {
     // check length
     let length = username.len();
     if !(3..64).contains(&length) {
@@ -65,36 +77,292 @@ fn check_username(username: &str) -> Result<(), WebError> {
     Ok(())
 }
 
-pub(crate) fn check_password_strength(password: &str) -> Result<(), WebError> {
-    if !(8..=128).contains(&password.len()) {
-        return Err(WebError::Serialization("Incorrect password length".into()));
+/// Verify the given password against password policy configured in settings
+///
+/// `username` and `email` are used by the policy to reject passwords containing user data.
+pub(crate) async fn check_password_strength(
+    pool: &DbPool,
+    password: &str,
+    username: &str,
+    email: &str,
+) -> Result<(), WebError> {
+    let policy = PasswordPolicy::load(pool).await?;
+    policy.validate(password, &[username, email]).await?;
+    Ok(())
+}
+
+/// Merge another account into this one: devices, authentication keys, group memberships and
+/// external identities are moved here and the other account is disabled. Irreversible.
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{username}/merge",
+    tag = "user",
+    params(("username" = String, Path, description = "Surviving account")),
+    request_body(content = Username, description = "Account merged into the surviving one"),
+    responses(
+        (status = 200, description = "Accounts merged", body = MergeSummary),
+        (status = 400, description = "Accounts can't be merged", body = ApiError),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+        (status = 404, description = "User not found", body = ApiError),
+        (status = 409, description = "Both accounts have devices with the same names", body = ApiError),
+    )
+)]
+pub async fn merge_user(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(username): Path<String>,
+    Json(data): Json<Username>,
+) -> ApiResult {
+    debug!(
+        "User {} merging user {} into {username}",
+        session.user.username, data.username
+    );
+    let mut transaction = appstate.pool.begin().await?;
+    let Some(target) = User::find_by_username(&mut *transaction, &username).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "User {username} not found"
+        )));
+    };
+    let Some(merged) = User::find_by_username(&mut *transaction, &data.username).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "User {} not found",
+            data.username
+        )));
+    };
+    let (Some(target_id), Some(merged_id)) = (target.id, merged.id) else {
+        return Err(WebError::ModelError("User has no id".into()));
+    };
+    if target_id == merged_id {
+        return Err(WebError::BadRequest("Can't merge user into itself".into()));
     }
-    if !password.chars().any(|c| c.is_ascii_punctuation()) {
-        return Err(WebError::Serialization(
-            "No special characters in password".into(),
-        ));
+    // Lock both users in a fixed order and read them again, so a concurrent merge or devices
+    // added in the meantime can't slip in between the checks and the merge.
+    User::lock(&mut transaction, target_id.min(merged_id)).await?;
+    User::lock(&mut transaction, target_id.max(merged_id)).await?;
+    let (Some(target), Some(mut merged)) = (
+        User::find_by_id(&mut *transaction, target_id).await?,
+        User::find_by_id(&mut *transaction, merged_id).await?,
+    ) else {
+        return Err(WebError::ObjectNotFound("User not found".into()));
+    };
+    if target.merged_into.is_some() || merged.merged_into.is_some() {
+        return Err(WebError::BadRequest("User has already been merged".into()));
     }
-    if !password.chars().any(|c| c.is_ascii_digit()) {
-        return Err(WebError::Serialization("No numbers in password".into()));
+    if target.break_glass || merged.break_glass {
+        return Err(WebError::BadRequest(
+            "Break-glass accounts can't be merged".into(),
+        ));
     }
-    if !password.chars().any(|c| c.is_ascii_lowercase()) {
-        return Err(WebError::Serialization(
-            "No lowercase characters in password".into(),
+    if merged.id == session.user.id {
+        return Err(WebError::BadRequest(
+            "Can't merge own account into another one".into(),
         ));
     }
-    if !password.chars().any(|c| c.is_ascii_uppercase()) {
-        return Err(WebError::Serialization(
-            "No uppercase characters in password".into(),
+    let conflicts = merged
+        .conflicting_device_names(&mut *transaction, target_id)
+        .await?;
+    if !conflicts.is_empty() {
+        return Err(WebError::Conflict(format!(
+            "Users {} and {} both have devices named {}, rename them first",
+            merged.username,
+            target.username,
+            conflicts.join(", ")
+        )));
+    }
+
+    let summary = merged.merge_into(&mut transaction, &target).await?;
+    // moved devices are now allowed in networks according to the groups of surviving account
+    let mut events = Vec::new();
+    for network in WireguardNetwork::all_active(&mut *transaction).await? {
+        events.extend(network.sync_allowed_devices(&mut transaction, None).await?);
+    }
+    transaction.commit().await?;
+    appstate.send_multiple_wireguard_events(events);
+
+    let _result = ldap_delete_user(&appstate.pool, &merged.username).await;
+    appstate.trigger_action(AppEvent::UserDeleted(merged.username.clone()));
+    warn!(
+        merged_user_id = merged_id,
+        surviving_user_id = target_id,
+        devices = summary.devices,
+        authentication_keys = summary.authentication_keys,
+        groups = summary.groups,
+        external_identities = summary.external_identities,
+        "User {} merged user {} into {}",
+        session.user.username,
+        merged.username,
+        target.username
+    );
+
+    Ok(ApiResponse {
+        json: json!(summary),
+        status: StatusCode::OK,
+    })
+}
+
+/// Cut all VPN connections of a user at once, e.g. when the account is known to be compromised.
+/// Disabled users are handled the same, as their devices may still be connected.
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{username}/disconnect",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    request_body = DisconnectUser,
+    responses(
+        (status = 200, description = "Devices disconnected, with locations they were removed from", body = [DisconnectedDevice]),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+        (status = 404, description = "User not found", body = ApiError),
+    )
+)]
+pub async fn disconnect_user(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(username): Path<String>,
+    Json(data): Json<DisconnectUser>,
+) -> ApiResult {
+    debug!(
+        "User {} disconnecting all devices of user {username}",
+        session.user.username
+    );
+    let Some(user) = User::find_by_username(&appstate.pool, &username).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "User {username} not found"
+        )));
+    };
+
+    let mut transaction = appstate.pool.begin().await?;
+    let (devices, events) = user
+        .disconnect_devices(&mut transaction, data.block_devices)
+        .await?;
+    transaction.commit().await?;
+    let peers = events.len();
+    appstate.send_multiple_wireguard_events(events);
+
+    warn!(
+        security_action = "disconnect_user",
+        user_id = user.id,
+        blocked = data.block_devices,
+        devices = devices.len(),
+        peers,
+        "User {} disconnected all devices of user {username}",
+        session.user.username
+    );
+
+    Ok(ApiResponse {
+        json: json!(devices),
+        status: StatusCode::OK,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimelineQuery {
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserTimeline {
+    events: Vec<TimelineEvent>,
+    /// Cursor for the next page, if there may be more events.
+    next_cursor: Option<String>,
+}
+
+/// Activity of a user merged into a single timeline: logins from new devices, tokens issued
+/// to OpenID clients and VPN connection sessions, oldest first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/{username}/timeline",
+    tag = "user",
+    params(
+        ("username" = String, Path, description = "Username"),
+        ("from" = Option<NaiveDateTime>, Query, description = "Start of the time range, 30 days before its end by default"),
+        ("to" = Option<NaiveDateTime>, Query, description = "End of the time range, now by default"),
+        ("cursor" = Option<String>, Query, description = "Cursor returned with the previous page"),
+        ("limit" = Option<i64>, Query, description = "Maximum number of events, 100 by default"),
+    ),
+    responses(
+        (status = 200, description = "Page of user activity", body = UserTimeline),
+        (status = 400, description = "Invalid time range, cursor or limit", body = ApiError),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+        (status = 404, description = "User not found", body = ApiError),
+    )
+)]
+pub async fn user_timeline(
+    _admin: AdminRole,
+    read_pool: ReadPool,
+    Path(username): Path<String>,
+    Query(query): Query<TimelineQuery>,
+) -> ApiResult {
+    let pool = read_pool.pool();
+    let Some(user_id) = User::find_by_username(pool, &username)
+        .await?
+        .and_then(|user| user.id)
+    else {
+        return Err(WebError::ObjectNotFound(format!(
+            "User {username} not found"
+        )));
+    };
+    let to = query.to.unwrap_or_else(|| Utc::now().naive_utc());
+    let from = query
+        .from
+        .unwrap_or_else(|| to - Duration::days(DEFAULT_TIMELINE_DAYS));
+    if from >= to {
+        return Err(WebError::BadRequest(
+            "Start of the time range must be before its end".into(),
         ));
     }
-    Ok(())
+    let limit = query.limit.unwrap_or(DEFAULT_TIMELINE_LIMIT);
+    if !(1..=MAX_TIMELINE_LIMIT).contains(&limit) {
+        return Err(WebError::BadRequest(format!(
+            "Limit must be between 1 and {MAX_TIMELINE_LIMIT}"
+        )));
+    }
+    let cursor = match &query.cursor {
+        Some(cursor) => Some(
+            TimelineCursor::decode(cursor)
+                .ok_or_else(|| WebError::BadRequest("Invalid cursor".into()))?,
+        ),
+        None => None,
+    };
+
+    let (events, last) =
+        fetch_user_timeline(pool, user_id, from, to, cursor.as_ref(), limit).await?;
+    // a full page may be followed by more events
+    let next_cursor = if events.len() as i64 == limit {
+        last.map(|cursor| cursor.encode())
+    } else {
+        None
+    };
+    Ok(ApiResponse {
+        json: json!(UserTimeline {
+            events,
+            next_cursor
+        }),
+        status: StatusCode::OK,
+    })
 }
 
-pub async fn list_users(_role: UserAdminRole, State(appstate): State<AppState>) -> ApiResult {
-    let all_users = User::all(&appstate.pool).await?;
+#[utoipa::path(
+    get,
+    path = "/api/v1/user",
+    tag = "user",
+    responses(
+        (status = 200, description = "List of all users", body = [UserInfo]),
+        (status = 401, description = "Not logged in", body = ApiError),
+        (status = 403, description = "Requires user management permissions", body = ApiError),
+    )
+)]
+pub async fn list_users(_role: UserAdminRole, read_pool: ReadPool) -> ApiResult {
+    let pool = read_pool.pool();
+    let all_users = User::all(pool).await?;
     let mut users: Vec<UserInfo> = Vec::with_capacity(all_users.len());
-    for user in all_users {
-        users.push(UserInfo::from_user(&appstate.pool, &user).await?);
+    // accounts merged into other ones are kept only for references
+    for user in all_users.iter().filter(|user| user.merged_into.is_none()) {
+        users.push(UserInfo::from_user(pool, user).await?);
     }
     Ok(ApiResponse {
         json: json!(users),
@@ -102,6 +370,17 @@ pub async fn list_users(_role: UserAdminRole, State(appstate): State<AppState>)
     })
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/{username}",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    responses(
+        (status = 200, description = "User details", body = UserDetails),
+        (status = 403, description = "Not an admin or the user itself", body = ApiError),
+        (status = 404, description = "User not found", body = ApiError),
+    )
+)]
 pub async fn get_user(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -115,10 +394,24 @@ pub async fn get_user(
     })
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/user",
+    tag = "user",
+    params(("Idempotency-Key" = Option<String>, Header, description = "Replay the stored response if the same request was already made with this key")),
+    request_body = AddUserData,
+    responses(
+        (status = 201, description = "User created", body = UserInfo),
+        (status = 400, description = "Invalid username"),
+        (status = 403, description = "Requires user management permissions", body = ApiError),
+        (status = 422, description = "Password does not meet requirements", body = ApiError),
+    )
+)]
 pub async fn add_user(
     _role: UserAdminRole,
     session: SessionInfo,
     State(appstate): State<AppState>,
+    mut transaction: IdempotentTx,
     Json(user_data): Json<AddUserData>,
 ) -> ApiResult {
     let username = user_data.username.clone();
@@ -135,12 +428,11 @@ pub async fn add_user(
     let password = match &user_data.password {
         Some(password) => {
             // check password strength
-            if let Err(err) = check_password_strength(password) {
-                debug!("Password not strong enough: {err}");
-                return Ok(ApiResponse {
-                    json: json!({}),
-                    status: StatusCode::BAD_REQUEST,
-                });
+            if let Err(err) =
+                check_password_strength(&appstate.pool, password, &username, &user_data.email).await
+            {
+                debug!("Password for user {username} not strong enough: {err}");
+                return Err(err);
             }
             Some(password.as_str())
         }
@@ -156,25 +448,39 @@ pub async fn add_user(
         user_data.email,
         user_data.phone,
     );
-    user.save(&appstate.pool).await?;
+    user.save(&mut *transaction).await?;
+    let user_info = UserInfo::from_user_in(&mut transaction, &user).await?;
+    let response = ApiResponse {
+        json: json!(&user_info),
+        status: StatusCode::CREATED,
+    };
+    transaction.commit(&response).await?;
 
     if let Some(password) = user_data.password {
         let _result = ldap_add_user(&appstate.pool, &user, &password).await;
     }
 
-    let user_info = UserInfo::from_user(&appstate.pool, &user).await?;
     appstate.trigger_action(AppEvent::UserCreated(user_info.clone()));
     info!("User {} added user {username}", session.user.username);
     if !user_info.enrolled {
         warn!("User {username} hasn't been enrolled yet. Please proceed with enrollment.");
     };
-    Ok(ApiResponse {
-        json: json!(&user_info),
-        status: StatusCode::CREATED,
-    })
+    Ok(response)
 }
 
 // Trigger enrollment process manually
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{username}/start_enrollment",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    request_body = StartEnrollmentRequest,
+    responses(
+        (status = 201, description = "Enrollment started", body = EnrollmentTokenInfo),
+        (status = 400, description = "Missing email for enrollment notification", body = ApiError),
+        (status = 404, description = "User not found", body = ApiError),
+    )
+)]
 pub async fn start_enrollment(
     _role: UserAdminRole,
     session: SessionInfo,
@@ -228,11 +534,26 @@ pub async fn start_enrollment(
     );
 
     Ok(ApiResponse {
-        json: json!({"enrollment_token": enrollment_token, "enrollment_url":  config.enrollment_url.to_string()}),
+        json: json!(EnrollmentTokenInfo {
+            enrollment_token,
+            enrollment_url: config.enrollment_url.to_string(),
+        }),
         status: StatusCode::CREATED,
     })
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{username}/start_desktop",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    request_body = StartEnrollmentRequest,
+    responses(
+        (status = 201, description = "Desktop client configuration started", body = EnrollmentTokenInfo),
+        (status = 403, description = "Not an admin or the user itself", body = ApiError),
+        (status = 404, description = "User not found", body = ApiError),
+    )
+)]
 pub async fn start_remote_desktop_configuration(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -275,11 +596,24 @@ pub async fn start_remote_desktop_configuration(
     );
 
     Ok(ApiResponse {
-        json: json!({"enrollment_token": enrollment_token, "enrollment_url":  config.enrollment_url.to_string()}),
+        json: json!(EnrollmentTokenInfo {
+            enrollment_token,
+            enrollment_url: config.enrollment_url.to_string(),
+        }),
         status: StatusCode::CREATED,
     })
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/available",
+    tag = "user",
+    request_body = Username,
+    responses(
+        (status = 200, description = "Username is available"),
+        (status = 400, description = "Username is invalid or taken"),
+    )
+)]
 pub async fn username_available(
     _role: UserAdminRole,
     State(appstate): State<AppState>,
@@ -305,6 +639,18 @@ pub async fn username_available(
     })
 }
 
+#[utoipa::path(
+    put,
+    path = "/api/v1/user/{username}",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    request_body = UserInfo,
+    responses(
+        (status = 200, description = "User modified"),
+        (status = 400, description = "Invalid username or admin disabling themselves"),
+        (status = 403, description = "Not an admin or the user itself", body = ApiError),
+    )
+)]
 pub async fn modify_user(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -320,6 +666,16 @@ pub async fn modify_user(
             status: StatusCode::BAD_REQUEST,
         });
     }
+    if user.merged_into.is_some() && user_info.is_active {
+        return Err(WebError::BadRequest(
+            "Merged account can't be enabled".into(),
+        ));
+    }
+    if user_info.max_devices.is_some_and(|limit| limit < 0) {
+        return Err(WebError::BadRequest(
+            "Device limit can't be negative".into(),
+        ));
+    }
 
     let mut transaction = appstate.pool.begin().await?;
 
@@ -361,7 +717,7 @@ pub async fn modify_user(
                 "User {} changed {username} groups or status, syncing allowed network devices",
                 session.user.username
             );
-            let networks = WireguardNetwork::all(&mut *transaction).await?;
+            let networks = WireguardNetwork::all_active(&mut *transaction).await?;
             for network in networks {
                 let gateway_events = network.sync_allowed_devices(&mut transaction, None).await?;
                 appstate.send_multiple_wireguard_events(gateway_events);
@@ -375,7 +731,10 @@ pub async fn modify_user(
     user.save(&mut *transaction).await?;
 
     // TODO: Reflect user status (active/disabled) modification in ldap
-    let _result = ldap_modify_user(&appstate.pool, &username, &user).await;
+    // break-glass accounts are local only
+    if !user.break_glass {
+        let _result = ldap_modify_user(&appstate.pool, &username, &user).await;
+    }
     let user_info = UserInfo::from_user(&appstate.pool, &user).await?;
     appstate.trigger_action(AppEvent::UserModified(user_info));
 
@@ -385,6 +744,17 @@ pub async fn modify_user(
     Ok(ApiResponse::default())
 }
 
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/{username}",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    responses(
+        (status = 200, description = "User deleted"),
+        (status = 400, description = "Users can't delete themselves"),
+        (status = 404, description = "User not found", body = ApiError),
+    )
+)]
 pub async fn delete_user(
     _role: UserAdminRole,
     State(appstate): State<AppState>,
@@ -400,8 +770,11 @@ pub async fn delete_user(
         });
     }
     if let Some(user) = User::find_by_username(&appstate.pool, &username).await? {
+        let break_glass = user.break_glass;
         user.delete(&appstate.pool).await?;
-        let _result = ldap_delete_user(&appstate.pool, &username).await;
+        if !break_glass {
+            let _result = ldap_delete_user(&appstate.pool, &username).await;
+        }
         appstate.trigger_action(AppEvent::UserDeleted(username.clone()));
         info!("User {} deleted user {}", session.user.username, &username);
         Ok(ApiResponse::default())
@@ -413,6 +786,81 @@ pub async fn delete_user(
     }
 }
 
+/// Start read-only session acting as a given user. Impersonating admins is not allowed.
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{username}/impersonate",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    responses(
+        (status = 201, description = "Impersonation session started", body = UserInfo),
+        (status = 400, description = "Admins can't impersonate themselves", body = ApiError),
+        (status = 403, description = "Admins can't be impersonated", body = ApiError),
+        (status = 404, description = "User not found", body = ApiError),
+    )
+)]
+pub async fn impersonate_user(
+    _role: AdminRole,
+    cookies: CookieJar,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(username): Path<String>,
+) -> Result<(CookieJar, ApiResponse), WebError> {
+    debug!(
+        "User {} starting impersonation of user {username}",
+        session.user.username
+    );
+    if session.user.username == username {
+        return Err(WebError::BadRequest("Cannot impersonate yourself".into()));
+    }
+    let Some(user) = User::find_by_username(&appstate.pool, &username).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "User {username} not found"
+        )));
+    };
+    let admin_groupname = &server_config().admin_groupname;
+    if user
+        .effective_member_of_names(&appstate.pool)
+        .await?
+        .contains(admin_groupname)
+    {
+        warn!(
+            "User {} tried to impersonate admin {username}",
+            session.user.username
+        );
+        return Err(WebError::Forbidden("Cannot impersonate an admin".into()));
+    }
+
+    let Some(user_id) = user.id else {
+        return Err(WebError::ModelError("User has no id".into()));
+    };
+    let impersonation_session = Session::new_impersonation(user_id, &session.session);
+    impersonation_session.save(&appstate.pool).await?;
+    let user_info = UserInfo::from_user(&appstate.pool, &user).await?;
+    info!(
+        "User {} started impersonating user {username}, session expires at {}",
+        session.user.username, impersonation_session.expires
+    );
+    Ok((
+        cookies.add(session_cookie(impersonation_session.id)),
+        ApiResponse {
+            json: json!(user_info),
+            status: StatusCode::CREATED,
+        },
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/user/change_password",
+    tag = "user",
+    request_body = PasswordChangeSelf,
+    responses(
+        (status = 200, description = "Password changed"),
+        (status = 400, description = "Wrong current password"),
+        (status = 422, description = "Password does not meet requirements", body = ApiError),
+    )
+)]
 pub async fn change_self_password(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -427,18 +875,24 @@ pub async fn change_self_password(
         });
     }
 
-    if let Err(err) = check_password_strength(&data.new_password) {
+    if let Err(err) = check_password_strength(
+        &appstate.pool,
+        &data.new_password,
+        &user.username,
+        &user.email,
+    )
+    .await
+    {
         debug!("User {} password change failed: {err}", user.username);
-        return Ok(ApiResponse {
-            json: json!({}),
-            status: StatusCode::BAD_REQUEST,
-        });
+        return Err(err);
     }
 
     user.set_password(&data.new_password);
     user.save(&appstate.pool).await?;
 
-    let _ = ldap_change_password(&appstate.pool, &user.username, &data.new_password).await;
+    if !user.break_glass {
+        let _ = ldap_change_password(&appstate.pool, &user.username, &data.new_password).await;
+    }
 
     info!("User {} changed his password.", &user.username);
 
@@ -448,6 +902,19 @@ pub async fn change_self_password(
     })
 }
 
+#[utoipa::path(
+    put,
+    path = "/api/v1/user/{username}/password",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    request_body = PasswordChange,
+    responses(
+        (status = 200, description = "Password changed"),
+        (status = 400, description = "Admins have to change their own password with the current one"),
+        (status = 404, description = "User not found"),
+        (status = 422, description = "Password does not meet requirements", body = ApiError),
+    )
+)]
 pub async fn change_password(
     _role: UserAdminRole,
     session: SessionInfo,
@@ -468,13 +935,6 @@ pub async fn change_password(
         });
     }
 
-    if let Err(err) = check_password_strength(&data.new_password) {
-        debug!("Password for user {username} not strong enough: {err}");
-        return Ok(ApiResponse {
-            json: json!({}),
-            status: StatusCode::BAD_REQUEST,
-        });
-    }
     if let Err(err) = check_username(&username) {
         debug!("Invalid username ({username}): {err}");
         return Ok(ApiResponse {
@@ -486,9 +946,22 @@ pub async fn change_password(
     let user = User::find_by_username(&appstate.pool, &username).await?;
 
     if let Some(mut user) = user {
+        if let Err(err) = check_password_strength(
+            &appstate.pool,
+            &data.new_password,
+            &user.username,
+            &user.email,
+        )
+        .await
+        {
+            debug!("Password for user {username} not strong enough: {err}");
+            return Err(err);
+        }
         user.set_password(&data.new_password);
         user.save(&appstate.pool).await?;
-        let _ = ldap_change_password(&appstate.pool, &username, &data.new_password).await;
+        if !user.break_glass {
+            let _ = ldap_change_password(&appstate.pool, &username, &data.new_password).await;
+        }
         info!(
             "Admin {} changed password for user {username}",
             session.user.username
@@ -503,6 +976,17 @@ pub async fn change_password(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{username}/reset_password",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    responses(
+        (status = 200, description = "Password reset started"),
+        (status = 400, description = "Admins can't reset their own password"),
+        (status = 404, description = "User not found"),
+    )
+)]
 pub async fn reset_password(
     _role: UserAdminRole,
     session: SessionInfo,
@@ -597,6 +1081,21 @@ pub struct WalletInfoShort {
     pub chain_id: i64,
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/{username}/challenge",
+    tag = "user",
+    params(
+        ("username" = String, Path, description = "Username"),
+        ("address" = String, Query, description = "Wallet address"),
+        ("name" = String, Query, description = "Wallet name"),
+        ("chain_id" = i64, Query, description = "Chain ID"),
+    ),
+    responses(
+        (status = 200, description = "Message to be signed with the wallet", body = WalletChallenge),
+        (status = 404, description = "Wallet already validated", body = ApiError),
+    )
+)]
 pub async fn wallet_challenge(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -654,6 +1153,17 @@ pub async fn wallet_challenge(
     })
 }
 
+#[utoipa::path(
+    put,
+    path = "/api/v1/user/{username}/wallet",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    request_body = WalletSignature,
+    responses(
+        (status = 200, description = "Wallet validated"),
+        (status = 404, description = "Wallet not found or invalid signature", body = ApiError),
+    )
+)]
 pub async fn set_wallet(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -696,6 +1206,18 @@ pub async fn set_wallet(
 
 /// Change wallet.
 /// Currently only `use_for_mfa` flag can be set or unset.
+#[utoipa::path(
+    put,
+    path = "/api/v1/user/{username}/wallet/{address}",
+    tag = "user",
+    params(("username" = String, Path, description = "Username"), ("address" = String, Path, description = "Wallet address")),
+    request_body = WalletChange,
+    responses(
+        (status = 200, description = "Wallet changed, recovery codes are returned if it enabled MFA", body = RecoveryCodes),
+        (status = 403, description = "Web3 MFA disabled in settings", body = ApiError),
+        (status = 404, description = "Wallet not found", body = ApiError),
+    )
+)]
 pub async fn update_wallet(
     session: SessionInfo,
     Path((username, address)): Path<(String, String)>,
@@ -712,6 +1234,9 @@ pub async fn update_wallet(
     {
         if Some(wallet.user_id) == user.id {
             let mfa_change = wallet.use_for_mfa != data.use_for_mfa;
+            if mfa_change && data.use_for_mfa {
+                ensure_mfa_method_allowed(&appstate.pool, &MFAMethod::Web3).await?;
+            }
             wallet.use_for_mfa = data.use_for_mfa;
             wallet.save(&appstate.pool).await?;
             if mfa_change {
@@ -764,6 +1289,16 @@ pub async fn update_wallet(
 }
 
 /// Delete wallet.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/{username}/wallet/{address}",
+    tag = "user",
+    params(("username" = String, Path, description = "Username"), ("address" = String, Path, description = "Wallet address")),
+    responses(
+        (status = 200, description = "Wallet deleted"),
+        (status = 404, description = "Wallet not found", body = ApiError),
+    )
+)]
 pub async fn delete_wallet(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -801,6 +1336,16 @@ pub async fn delete_wallet(
     }
 }
 
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/{username}/security_key/{id}",
+    tag = "user",
+    params(("username" = String, Path, description = "Username"), ("id" = i64, Path, description = "Security key ID")),
+    responses(
+        (status = 200, description = "Security key deleted"),
+        (status = 404, description = "Security key not found", body = ApiError),
+    )
+)]
 pub async fn delete_security_key(
     session: SessionInfo,
     State(appstate): State<AppState>,
@@ -836,15 +1381,37 @@ pub async fn delete_security_key(
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/me",
+    tag = "user",
+    responses(
+        (status = 200, description = "Currently logged in user", body = SessionUserInfo),
+        (status = 401, description = "Not logged in", body = ApiError),
+    )
+)]
 pub async fn me(session: SessionInfo, State(appstate): State<AppState>) -> ApiResult {
     let user_info = UserInfo::from_user(&appstate.pool, &session.user).await?;
     Ok(ApiResponse {
-        json: json!(user_info),
+        json: json!(SessionUserInfo {
+            user: user_info,
+            impersonator: session.impersonator,
+        }),
         status: StatusCode::OK,
     })
 }
 
 /// Delete Oauth token.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/{username}/oauth_app/{oauth2client_id}",
+    tag = "user",
+    params(("username" = String, Path, description = "Username"), ("oauth2client_id" = i64, Path, description = "OAuth2 client ID")),
+    responses(
+        (status = 200, description = "Authorized app removed"),
+        (status = 404, description = "Authorized app not found", body = ApiError),
+    )
+)]
 pub async fn delete_authorized_app(
     session: SessionInfo,
     State(appstate): State<AppState>,
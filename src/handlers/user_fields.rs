@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+};
+use serde_json::json;
+
+use super::{user_for_admin_or_self, ApiResponse, ApiResult};
+use crate::{
+    appstate::AppState,
+    auth::{AdminRole, SessionInfo, UserAdminRole},
+    db::{
+        models::user_field::{UserFieldDefinition, UserFieldValue},
+        DbPool, User,
+    },
+    error::WebError,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteFieldQuery {
+    #[serde(default)]
+    confirm: bool,
+}
+
+async fn find_definition(pool: &DbPool, id: i64) -> Result<UserFieldDefinition, WebError> {
+    UserFieldDefinition::find_by_id(pool, id)
+        .await?
+        .ok_or_else(|| WebError::ObjectNotFound(format!("user field {id} not found")))
+}
+
+async fn ensure_name_available(pool: &DbPool, name: &str, id: Option<i64>) -> Result<(), WebError> {
+    match UserFieldDefinition::find_by_name(pool, name).await? {
+        Some(existing) if existing.id != id => Err(WebError::Conflict(format!(
+            "User field {name} already exists"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/user_field",
+    tag = "user",
+    responses(
+        (status = 200, description = "Custom user field definitions", body = [UserFieldDefinition]),
+        (status = 403, description = "Requires user management permissions", body = ApiError),
+    )
+)]
+pub async fn list_user_fields(_role: UserAdminRole, State(appstate): State<AppState>) -> ApiResult {
+    let mut definitions = UserFieldDefinition::all(&appstate.pool).await?;
+    definitions.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(ApiResponse {
+        json: json!(definitions),
+        status: StatusCode::OK,
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/user_field",
+    tag = "user",
+    request_body = UserFieldDefinition,
+    responses(
+        (status = 201, description = "Field added", body = UserFieldDefinition),
+        (status = 400, description = "Invalid definition", body = ApiError),
+        (status = 409, description = "Field with this name already exists", body = ApiError),
+    )
+)]
+pub async fn add_user_field(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Json(mut definition): Json<UserFieldDefinition>,
+) -> ApiResult {
+    debug!(
+        "User {} adding user field {}",
+        session.user.username, definition.name
+    );
+    definition.validate().map_err(WebError::BadRequest)?;
+    ensure_name_available(&appstate.pool, &definition.name, None).await?;
+    definition.id = None;
+    definition.save(&appstate.pool).await?;
+    info!(
+        "User {} added user field {}",
+        session.user.username, definition.name
+    );
+    Ok(ApiResponse {
+        json: json!(definition),
+        status: StatusCode::CREATED,
+    })
+}
+
+/// Modify field definition. Values already set must remain valid, so e.g. an option
+/// which is still in use can't be removed.
+#[utoipa::path(
+    put,
+    path = "/api/v1/user_field/{id}",
+    tag = "user",
+    params(("id" = i64, Path, description = "Field ID")),
+    request_body = UserFieldDefinition,
+    responses(
+        (status = 200, description = "Field modified", body = UserFieldDefinition),
+        (status = 400, description = "Invalid definition or existing values no longer valid", body = ApiError),
+        (status = 404, description = "Field not found", body = ApiError),
+        (status = 409, description = "Field with this name already exists", body = ApiError),
+    )
+)]
+pub async fn modify_user_field(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(id): Path<i64>,
+    Json(mut data): Json<UserFieldDefinition>,
+) -> ApiResult {
+    debug!("User {} modifying user field {id}", session.user.username);
+    let definition = find_definition(&appstate.pool, id).await?;
+    data.id = definition.id;
+    data.validate().map_err(WebError::BadRequest)?;
+    ensure_name_available(&appstate.pool, &data.name, data.id).await?;
+    for value in data.values(&appstate.pool).await? {
+        data.validate_value(&value).map_err(|err| {
+            WebError::BadRequest(format!("Existing value {value} is no longer valid: {err}"))
+        })?;
+    }
+    data.save(&appstate.pool).await?;
+    info!(
+        "User {} modified user field {}",
+        session.user.username, data.name
+    );
+    Ok(ApiResponse {
+        json: json!(data),
+        status: StatusCode::OK,
+    })
+}
+
+/// Delete field definition together with all its values.
+/// Requires `confirm=true` query parameter if any user has a value set.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user_field/{id}",
+    tag = "user",
+    params(("id" = i64, Path, description = "Field ID"), ("confirm" = Option<bool>, Query, description = "Confirm removing values set for users")),
+    responses(
+        (status = 200, description = "Field deleted"),
+        (status = 404, description = "Field not found", body = ApiError),
+        (status = 409, description = "Field has values set and deletion wasn't confirmed", body = ApiError),
+    )
+)]
+pub async fn delete_user_field(
+    _admin: AdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(id): Path<i64>,
+    Query(query): Query<DeleteFieldQuery>,
+) -> ApiResult {
+    debug!("User {} deleting user field {id}", session.user.username);
+    let definition = find_definition(&appstate.pool, id).await?;
+    let values = definition.value_count(&appstate.pool).await?;
+    if values > 0 && !query.confirm {
+        return Ok(ApiResponse {
+            json: json!({
+                "msg": format!(
+                    "Deleting user field {} removes its value from {values} users, confirm to proceed",
+                    definition.name
+                ),
+                "values": values,
+            }),
+            status: StatusCode::CONFLICT,
+        });
+    }
+    let name = definition.name.clone();
+    definition.delete(&appstate.pool).await?;
+    info!(
+        "User {} deleted user field {name} and {values} values",
+        session.user.username
+    );
+    Ok(ApiResponse::default())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/{username}/fields",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    responses(
+        (status = 200, description = "Custom field values of the user", body = [UserFieldValue]),
+        (status = 403, description = "Not an admin or the user itself", body = ApiError),
+    )
+)]
+pub async fn get_user_field_values(
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(username): Path<String>,
+) -> ApiResult {
+    let user = user_for_admin_or_self(&appstate.pool, &session, &username).await?;
+    let values = UserFieldValue::all_for_user(&appstate.pool, user.id.unwrap()).await?;
+    Ok(ApiResponse {
+        json: json!(values),
+        status: StatusCode::OK,
+    })
+}
+
+/// Set custom field values of a user, keyed by field name.
+/// `null` or empty string removes the value. Fields not included are left unchanged.
+#[utoipa::path(
+    put,
+    path = "/api/v1/user/{username}/fields",
+    tag = "user",
+    params(("username" = String, Path, description = "Username")),
+    request_body(content = HashMap<String, Option<String>>, description = "Field values by field name"),
+    responses(
+        (status = 200, description = "Custom field values of the user", body = [UserFieldValue]),
+        (status = 400, description = "Invalid value, unknown field or missing required field", body = ApiError),
+        (status = 404, description = "User not found", body = ApiError),
+    )
+)]
+pub async fn set_user_field_values(
+    _role: UserAdminRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path(username): Path<String>,
+    Json(data): Json<HashMap<String, Option<String>>>,
+) -> ApiResult {
+    debug!(
+        "User {} setting custom fields of user {username}",
+        session.user.username
+    );
+    let Some(user) = User::find_by_username(&appstate.pool, &username).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "user {username} not found"
+        )));
+    };
+    let user_id = user.id.unwrap();
+
+    let mut transaction = appstate.pool.begin().await?;
+    for (name, value) in data {
+        let Some(definition) = UserFieldDefinition::find_by_name(&mut *transaction, &name).await?
+        else {
+            return Err(WebError::BadRequest(format!("Unknown user field {name}")));
+        };
+        let definition_id = definition.id.unwrap();
+        match value.as_deref().map(str::trim) {
+            Some(value) if !value.is_empty() => {
+                definition
+                    .validate_value(value)
+                    .map_err(WebError::BadRequest)?;
+                UserFieldValue::set(&mut *transaction, user_id, definition_id, value).await?;
+            }
+            _ => UserFieldValue::remove(&mut *transaction, user_id, definition_id).await?,
+        }
+    }
+    let missing = UserFieldDefinition::missing_required(&mut *transaction, user_id).await?;
+    if !missing.is_empty() {
+        return Err(WebError::BadRequest(format!(
+            "Missing required user fields: {}",
+            missing.join(", ")
+        )));
+    }
+    transaction.commit().await?;
+    info!(
+        "User {} set custom fields of user {username}",
+        session.user.username
+    );
+
+    let values = UserFieldValue::all_for_user(&appstate.pool, user_id).await?;
+    Ok(ApiResponse {
+        json: json!(values),
+        status: StatusCode::OK,
+    })
+}
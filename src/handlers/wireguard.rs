@@ -1,42 +1,86 @@
 use std::{
+    cmp::Reverse,
+    collections::HashMap,
     net::IpAddr,
     str::FromStr,
     sync::{Arc, Mutex},
+    time::{Duration as StdDuration, Instant},
 };
 
 use axum::{
     extract::{Json, Path, Query, State},
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     Extension,
 };
 use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use ipnetwork::IpNetwork;
 use serde_json::{json, Value};
+use sqlx::PgExecutor;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 use super::{device_for_admin_or_self, user_for_admin_or_self, ApiResponse, ApiResult, WebError};
 use crate::{
     appstate::AppState,
-    auth::{Claims, ClaimsType, SessionInfo, VpnRole},
+    auth::{AdminRole, Claims, ClaimsType, SessionInfo, VpnRole},
     db::{
         models::{
             device::{
-                DeviceConfig, DeviceInfo, DeviceNetworkInfo, ModifyDevice, WireguardNetworkDevice,
+                compare_versions, parse_preshared_key, DeviceConfig, DeviceError, DeviceInfo,
+                DeviceNetworkInfo, ModifyDevice, WireguardNetworkDevice, WireguardPubkey,
+                MAX_PLATFORM_FIELD_LENGTH, PRIVATE_KEY_PLACEHOLDER,
+            },
+            wireguard::{
+                canonical_networks, parse_networks, ConnectedClient, DateTimeAggregation,
+                MappedDevice, NetworkOverlap, PeerUpdate, WireguardNetworkInfo, MAX_MTU,
+                MIN_MTU_IPV4, MIN_MTU_IPV6,
             },
-            wireguard::{DateTimeAggregation, MappedDevice, WireguardNetworkInfo},
         },
-        AddDevice, DbPool, Device, GatewayEvent, WireguardNetwork,
+        AddDevice, DbPool, Device, GatewayEvent, ReadPool, User, WireguardNetwork,
     },
-    grpc::GatewayMap,
-    handlers::mail::send_new_device_added_email,
+    dns::{self, DeviceDnsStatus},
+    grpc::{peer_stats::ingestion_metrics, GatewayMap},
+    handlers::mail::{send_device_transferred_email, send_new_device_added_email},
+    idempotency::IdempotentTx,
+    key_escrow::{self, KeyEncryptionKey},
     server_config,
     templates::TemplateLocation,
-    wg_config::{parse_wireguard_config, ImportedDevice},
+    wg_config::{parse_wireguard_config, ImportedDevice, WireguardConfigParseError},
+    wireguard_config_qr::{render_config_qr, QrFormat},
+    wireguard_psk_rotation::{
+        grace_period_deadline, key_fingerprint, promote_psk, stage_psk_rotation,
+    },
 };
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
+#[schema(example = json!({
+    "name": "office",
+    "address": "10.10.10.1/24",
+    "endpoint": "vpn.example.com",
+    "port": 51820,
+    "allowed_ips": "10.10.10.0/24, 192.168.1.0/24",
+    "dns": "10.10.10.1",
+    "allowed_groups": ["admin"],
+    "mfa_enabled": false,
+    "keepalive_interval": 25,
+    "peer_disconnect_threshold": 180,
+    "psk_rotation_days": 90,
+    "gateway_allowed_ips": null,
+    "mtu": null,
+    "dns_zone": "office.vpn.example.com",
+    "upload_limit_kbps": null,
+    "download_limit_kbps": 100000,
+    "allowed_platforms": ["linux", "windows"],
+    "deny_unknown_platform": true,
+    "client_routes": "172.16.5.0/24",
+    "masquerade_enabled": true,
+    "nat_exempt_networks": "10.20.0.0/16",
+    "server_managed_keys": false
+}))]
 pub struct WireguardNetworkData {
     pub name: String,
+    #[schema(value_type = String)]
     pub address: IpNetwork,
     pub endpoint: String,
     pub port: i32,
@@ -46,20 +90,165 @@ pub struct WireguardNetworkData {
     pub mfa_enabled: bool,
     pub keepalive_interval: i32,
     pub peer_disconnect_threshold: i32,
+    #[serde(default)]
+    pub psk_rotation_days: Option<i32>,
+    #[serde(default)]
+    pub gateway_allowed_ips: Option<String>,
+    #[serde(default)]
+    pub mtu: Option<i32>,
+    #[serde(default)]
+    pub dns_zone: Option<String>,
+    #[serde(default)]
+    pub upload_limit_kbps: Option<i32>,
+    #[serde(default)]
+    pub download_limit_kbps: Option<i32>,
+    #[serde(default)]
+    pub allowed_platforms: Vec<String>,
+    #[serde(default)]
+    pub deny_unknown_platform: bool,
+    #[serde(default)]
+    pub client_routes: Option<String>,
+    #[serde(default)]
+    pub masquerade_enabled: bool,
+    #[serde(default)]
+    pub nat_exempt_networks: Option<String>,
+    #[serde(default)]
+    pub server_managed_keys: bool,
+}
+
+/// Limits have to be positive and can't exceed the configured maximum.
+fn validate_bandwidth_limit(limit: Option<i32>, direction: &str) -> Result<(), WebError> {
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    let max_limit = server_config().max_bandwidth_limit_kbps;
+    if (1..=max_limit).contains(&limit) {
+        Ok(())
+    } else {
+        Err(WebError::BadRequest(format!(
+            "{direction} limit must be between 1 and {max_limit} kbps"
+        )))
+    }
 }
 
 impl WireguardNetworkData {
-    pub(crate) fn parse_allowed_ips(&self) -> Vec<IpNetwork> {
-        self.allowed_ips.as_ref().map_or(Vec::new(), |ips| {
-            ips.split(',')
-                .filter_map(|ip| ip.trim().parse().ok())
-                .collect()
-        })
+    /// Parse routed networks into canonical form, rejecting invalid entries.
+    pub(crate) fn parse_allowed_ips(&self) -> Result<Vec<IpNetwork>, WebError> {
+        let networks = parse_networks(self.allowed_ips.as_deref().unwrap_or_default())
+            .map_err(WebError::InvalidAddresses)?;
+        Ok(canonical_networks(&networks))
+    }
+
+    /// Parse additional client routes the same way as `allowed_ips`.
+    pub(crate) fn parse_client_routes(&self) -> Result<Vec<IpNetwork>, WebError> {
+        let networks = parse_networks(self.client_routes.as_deref().unwrap_or_default())
+            .map_err(WebError::InvalidAddresses)?;
+        Ok(canonical_networks(&networks))
+    }
+
+    /// Invalid entries are rejected, as skipping them would silently loosen the restriction.
+    pub(crate) fn parse_gateway_allowed_ips(&self) -> Result<Vec<IpNetwork>, WebError> {
+        self.gateway_allowed_ips
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .map(|ip| {
+                ip.parse().map_err(|_| {
+                    WebError::BadRequest(format!("invalid gateway allowed address {ip}"))
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn validate_psk_rotation_days(&self) -> Result<(), WebError> {
+        match self.psk_rotation_days {
+            Some(days) if days < 1 => Err(WebError::BadRequest(
+                "preshared key rotation period must be at least one day".into(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// IPv6 requires links to carry at least 1280 bytes, so the lower bound depends on
+    /// the address family of the tunnel.
+    pub(crate) fn validate_mtu(&self) -> Result<(), WebError> {
+        let Some(mtu) = self.mtu else {
+            return Ok(());
+        };
+        let min_mtu = if self.address.is_ipv4() {
+            MIN_MTU_IPV4
+        } else {
+            MIN_MTU_IPV6
+        };
+        if (min_mtu..=MAX_MTU).contains(&mtu) {
+            Ok(())
+        } else {
+            Err(WebError::BadRequest(format!(
+                "MTU must be between {min_mtu} and {MAX_MTU}"
+            )))
+        }
+    }
+
+    pub(crate) fn validate_bandwidth_limits(&self) -> Result<(), WebError> {
+        validate_bandwidth_limit(self.upload_limit_kbps, "upload")?;
+        validate_bandwidth_limit(self.download_limit_kbps, "download")
+    }
+
+    /// Platforms are compared with operating systems reported by clients, which are
+    /// stored lowercase.
+    pub(crate) fn parse_allowed_platforms(&self) -> Result<Vec<String>, WebError> {
+        let mut platforms: Vec<String> = Vec::new();
+        for platform in &self.allowed_platforms {
+            let platform = platform.trim().to_lowercase();
+            if platform.is_empty() || platform.chars().count() > MAX_PLATFORM_FIELD_LENGTH {
+                return Err(WebError::BadRequest(format!(
+                    "invalid allowed platform \"{platform}\""
+                )));
+            }
+            if !platforms.contains(&platform) {
+                platforms.push(platform);
+            }
+        }
+        Ok(platforms)
+    }
+
+    /// Destinations gateways route without masquerading; only allowed with masquerade enabled.
+    pub(crate) fn parse_nat_exempt_networks(&self) -> Result<Vec<IpNetwork>, WebError> {
+        let networks = parse_networks(self.nat_exempt_networks.as_deref().unwrap_or_default())
+            .map_err(WebError::InvalidAddresses)?;
+        if !networks.is_empty() && !self.masquerade_enabled {
+            return Err(WebError::BadRequest(
+                "NAT exempt networks require masquerade to be enabled".into(),
+            ));
+        }
+        Ok(canonical_networks(&networks))
+    }
+
+    /// Escrowed private keys are encrypted, so a key-encryption key has to be configured.
+    pub(crate) fn validate_server_managed_keys(&self) -> Result<(), WebError> {
+        if self.server_managed_keys && server_config().key_encryption_key.is_none() {
+            return Err(WebError::BadRequest(
+                "server managed keys require a key-encryption key in configuration".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Normalized DNS zone, empty means none.
+    pub(crate) fn parse_dns_zone(&self) -> Result<Option<String>, WebError> {
+        self.dns_zone
+            .as_deref()
+            .map(str::trim)
+            .filter(|zone| !zone.is_empty())
+            .map(|zone| dns::normalize_name(zone).map_err(WebError::BadRequest))
+            .transpose()
     }
 }
 
 // Used in process of importing network from WireGuard config
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct MappedDevices {
     pub devices: Vec<MappedDevice>,
 }
@@ -69,7 +258,7 @@ struct ConnectionInfo {
     connected: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct ImportNetworkData {
     pub name: String,
     pub endpoint: String,
@@ -77,16 +266,58 @@ pub struct ImportNetworkData {
     pub allowed_groups: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct ImportedNetworkData {
     pub network: WireguardNetwork,
     pub devices: Vec<ImportedDevice>,
 }
 
+#[derive(Deserialize)]
+pub struct OverlapQuery {
+    // some deployments use overlapping ranges on purpose
+    #[serde(default)]
+    allow_overlap: bool,
+}
+
+/// Response listing address ranges conflicting with other locations.
+fn overlap_conflict(overlaps: &[NetworkOverlap]) -> ApiResponse {
+    let msg = overlaps
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    ApiResponse {
+        json: json!({
+            "msg": format!("Network address ranges overlap with other locations: {msg}"),
+            "overlaps": overlaps,
+        }),
+        status: StatusCode::CONFLICT,
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/network",
+    tag = "network",
+    params(
+        ("allow_overlap" = Option<bool>, Query, description = "Allow address ranges overlapping with other locations"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the stored response if the same request was already made with this key"),
+    ),
+    request_body = WireguardNetworkData,
+    responses(
+        (status = 201, description = "Network created", body = WireguardNetwork),
+        (status = 400, description = "Invalid network parameters", body = ApiError),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 409, description = "Address ranges overlap with other locations", body = ApiError),
+        (status = 422, description = "Invalid allowed IPs entries", body = ApiError),
+    )
+)]
 pub async fn create_network(
     _role: VpnRole,
     State(appstate): State<AppState>,
     session: SessionInfo,
+    Query(query): Query<OverlapQuery>,
+    mut transaction: IdempotentTx,
     Json(data): Json<WireguardNetworkData>,
 ) -> ApiResult {
     let network_name = data.name.clone();
@@ -94,7 +325,16 @@ pub async fn create_network(
         "User {} creating WireGuard network {network_name}",
         session.user.username
     );
-    let allowed_ips = data.parse_allowed_ips();
+    data.validate_psk_rotation_days()?;
+    data.validate_mtu()?;
+    data.validate_bandwidth_limits()?;
+    data.validate_server_managed_keys()?;
+    let gateway_allowed_ips = data.parse_gateway_allowed_ips()?;
+    let dns_zone = data.parse_dns_zone()?;
+    let allowed_platforms = data.parse_allowed_platforms()?;
+    let allowed_ips = data.parse_allowed_ips()?;
+    let client_routes = data.parse_client_routes()?;
+    let nat_exempt_networks = data.parse_nat_exempt_networks()?;
     let mut network = WireguardNetwork::new(
         data.name,
         data.address,
@@ -107,8 +347,23 @@ pub async fn create_network(
         data.peer_disconnect_threshold,
     )
     .map_err(|_| WebError::Serialization("Invalid network address".into()))?;
-
-    let mut transaction = appstate.pool.begin().await?;
+    network.psk_rotation_days = data.psk_rotation_days;
+    network.gateway_allowed_ips = gateway_allowed_ips;
+    network.mtu = data.mtu;
+    network.dns_zone = dns_zone;
+    network.upload_limit_kbps = data.upload_limit_kbps;
+    network.download_limit_kbps = data.download_limit_kbps;
+    network.allowed_platforms = allowed_platforms;
+    network.deny_unknown_platform = data.deny_unknown_platform;
+    network.client_routes = client_routes;
+    network.masquerade_enabled = data.masquerade_enabled;
+    network.nat_exempt_networks = nat_exempt_networks;
+    network.server_managed_keys = data.server_managed_keys;
+
+    if let Some(response) = check_overlaps(&mut *transaction, &network, query.allow_overlap).await?
+    {
+        return Ok(response);
+    }
     network.save(&mut *transaction).await?;
     network
         .set_allowed_groups(&mut transaction, data.allowed_groups)
@@ -132,29 +387,103 @@ pub async fn create_network(
         }
     }
 
-    transaction.commit().await?;
+    let response = network_response(&network, StatusCode::CREATED);
+    transaction.commit(&response).await?;
 
     info!(
         "User {} created WireGuard network {network_name}",
         session.user.username
     );
-    Ok(ApiResponse {
-        json: json!(network),
-        status: StatusCode::CREATED,
-    })
+    Ok(response)
 }
 
-async fn find_network(id: i64, pool: &DbPool) -> Result<WireguardNetwork, WebError> {
+pub(crate) async fn find_network(id: i64, pool: &DbPool) -> Result<WireguardNetwork, WebError> {
     WireguardNetwork::find_by_id(pool, id)
         .await?
         .ok_or_else(|| WebError::ObjectNotFound(format!("Network {id} not found")))
 }
 
+/// Network along with warnings about settings which are accepted, but likely wrong.
+fn network_response(network: &WireguardNetwork, status: StatusCode) -> ApiResponse {
+    let warnings: Vec<String> = network
+        .client_route_overlaps()
+        .iter()
+        .map(|route| {
+            format!(
+                "Client route {route} overlaps with network address {}",
+                network.address
+            )
+        })
+        .collect();
+    let mut json = json!(network);
+    if !warnings.is_empty() {
+        warn!("Network {}: {}", network.name, warnings.join(", "));
+        json["warnings"] = json!(warnings);
+    }
+    ApiResponse { json, status }
+}
+
+/// Return conflict response if network ranges overlap with other locations,
+/// unless overlapping is explicitly allowed.
+async fn check_overlaps<'e, E>(
+    executor: E,
+    network: &WireguardNetwork,
+    allow_overlap: bool,
+) -> Result<Option<ApiResponse>, WebError>
+where
+    E: PgExecutor<'e>,
+{
+    let overlaps = network.find_overlaps(executor).await?;
+    if overlaps.is_empty() {
+        return Ok(None);
+    }
+    if allow_overlap {
+        warn!(
+            "Network {} address ranges overlap with other locations",
+            network.name
+        );
+        Ok(None)
+    } else {
+        debug!(
+            "Rejecting network {}, address ranges overlap with other locations",
+            network.name
+        );
+        Ok(Some(overlap_conflict(&overlaps)))
+    }
+}
+
+/// Archived networks have to be restored before they can be changed.
+pub(crate) fn ensure_not_archived(network: &WireguardNetwork) -> Result<(), WebError> {
+    if network.archived {
+        Err(WebError::Conflict(format!(
+            "Network {} is archived",
+            network.name
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/network/{network_id}",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID"), ("allow_overlap" = Option<bool>, Query, description = "Allow address ranges overlapping with other locations")),
+    request_body = WireguardNetworkData,
+    responses(
+        (status = 200, description = "Network modified", body = WireguardNetwork),
+        (status = 400, description = "Invalid network parameters", body = ApiError),
+        (status = 404, description = "Network not found", body = ApiError),
+        (status = 409, description = "Network is archived or address ranges overlap with other locations", body = ApiError),
+        (status = 422, description = "Invalid allowed IPs entries", body = ApiError),
+    )
+)]
 pub async fn modify_network(
     _role: VpnRole,
     Path(network_id): Path<i64>,
     State(appstate): State<AppState>,
     session: SessionInfo,
+    Query(query): Query<OverlapQuery>,
     Json(data): Json<WireguardNetworkData>,
 ) -> ApiResult {
     debug!(
@@ -162,7 +491,19 @@ pub async fn modify_network(
         session.user.username
     );
     let mut network = find_network(network_id, &appstate.pool).await?;
-    network.allowed_ips = data.parse_allowed_ips();
+    ensure_not_archived(&network)?;
+    data.validate_psk_rotation_days()?;
+    data.validate_mtu()?;
+    data.validate_bandwidth_limits()?;
+    data.validate_server_managed_keys()?;
+    let gateway_allowed_ips = data.parse_gateway_allowed_ips()?;
+    let dns_zone = data.parse_dns_zone()?;
+    let allowed_platforms = data.parse_allowed_platforms()?;
+    let allowed_ips = data.parse_allowed_ips()?;
+    let client_routes = data.parse_client_routes()?;
+    let nat_exempt_networks = data.parse_nat_exempt_networks()?;
+    let previous_network = network.clone();
+    network.allowed_ips = allowed_ips;
     network.name = data.name;
 
     // initialize DB transaction
@@ -175,43 +516,74 @@ pub async fn modify_network(
     network.mfa_enabled = data.mfa_enabled;
     network.keepalive_interval = data.keepalive_interval;
     network.peer_disconnect_threshold = data.peer_disconnect_threshold;
+    network.psk_rotation_days = data.psk_rotation_days;
+    network.gateway_allowed_ips = gateway_allowed_ips;
+    network.mtu = data.mtu;
+    network.dns_zone = dns_zone;
+    network.upload_limit_kbps = data.upload_limit_kbps;
+    network.download_limit_kbps = data.download_limit_kbps;
+    network.allowed_platforms = allowed_platforms;
+    network.deny_unknown_platform = data.deny_unknown_platform;
+    network.client_routes = client_routes;
+    network.masquerade_enabled = data.masquerade_enabled;
+    network.nat_exempt_networks = nat_exempt_networks;
+    network.server_managed_keys = data.server_managed_keys;
+    if let Some(response) = check_overlaps(&appstate.pool, &network, query.allow_overlap).await? {
+        return Ok(response);
+    }
 
     network.save(&mut *transaction).await?;
     network
         .set_allowed_groups(&mut transaction, data.allowed_groups)
         .await?;
-    let _events = network.sync_allowed_devices(&mut transaction, None).await?;
-
-    match &network.id {
-        Some(network_id) => {
-            let peers = network.get_peers(&mut *transaction).await?;
-            appstate.send_wireguard_event(GatewayEvent::NetworkModified(
-                *network_id,
-                network.clone(),
-                peers,
-            ));
-        }
-        &None => {
-            error!(
-                "Network {} id not found, gateway update not sent!",
-                network.name
-            );
+    let events = network.sync_allowed_devices(&mut transaction, None).await?;
+
+    // interface changes require a full resync, otherwise only changed peers are sent
+    if network.requires_gateway_resync(&previous_network) {
+        match &network.id {
+            Some(network_id) => {
+                let peers = network.get_peers(&mut *transaction).await?;
+                appstate.send_wireguard_event(GatewayEvent::NetworkModified(
+                    *network_id,
+                    network.clone(),
+                    peers,
+                ));
+            }
+            &None => {
+                error!(
+                    "Network {} id not found, gateway update not sent!",
+                    network.name
+                );
+            }
         }
+    } else {
+        appstate.send_multiple_wireguard_events(events);
     }
 
     // commit DB transaction
     transaction.commit().await?;
+    if network.dns_zone != previous_network.dns_zone {
+        dns::request_sync();
+    }
 
     info!(
         "User {} updated WireGuard network {network_id}",
         session.user.username,
     );
-    Ok(ApiResponse {
-        json: json!(network),
-        status: StatusCode::OK,
-    })
+    Ok(network_response(&network, StatusCode::OK))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/api/v1/network/{network_id}",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID")),
+    responses(
+        (status = 200, description = "Network deleted"),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 404, description = "Network not found", body = ApiError),
+    )
+)]
 pub async fn delete_network(
     _role: VpnRole,
     Path(network_id): Path<i64>,
@@ -233,18 +605,15 @@ pub async fn delete_network(
     Ok(ApiResponse::default())
 }
 
-pub async fn list_networks(
-    _role: VpnRole,
-    State(appstate): State<AppState>,
-    Extension(gateway_state): Extension<Arc<Mutex<GatewayMap>>>,
-) -> ApiResult {
-    debug!("Listing WireGuard networks");
+async fn networks_info(
+    pool: &DbPool,
+    gateway_state: &Mutex<GatewayMap>,
+    networks: Vec<WireguardNetwork>,
+) -> Result<Vec<WireguardNetworkInfo>, WebError> {
     let mut network_info = Vec::new();
-    let networks = WireguardNetwork::all(&appstate.pool).await?;
-
     for network in networks {
         let network_id = network.id.expect("Network does not have an ID");
-        let allowed_groups = network.fetch_allowed_groups(&appstate.pool).await?;
+        let allowed_groups = network.fetch_allowed_groups(pool).await?;
         {
             let gateway_state = gateway_state
                 .lock()
@@ -257,6 +626,26 @@ pub async fn list_networks(
             });
         }
     }
+    Ok(network_info)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/network",
+    tag = "network",
+    responses(
+        (status = 200, description = "Active networks", body = [WireguardNetworkInfo]),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+    )
+)]
+pub async fn list_networks(
+    _role: VpnRole,
+    State(appstate): State<AppState>,
+    Extension(gateway_state): Extension<Arc<Mutex<GatewayMap>>>,
+) -> ApiResult {
+    debug!("Listing WireGuard networks");
+    let networks = WireguardNetwork::all_active(&appstate.pool).await?;
+    let network_info = networks_info(&appstate.pool, &gateway_state, networks).await?;
     debug!("Listed WireGuard networks");
 
     Ok(ApiResponse {
@@ -265,6 +654,159 @@ pub async fn list_networks(
     })
 }
 
+/// Report address ranges overlapping between non-archived locations.
+#[utoipa::path(
+    get,
+    path = "/api/v1/network/overlaps",
+    tag = "network",
+    responses(
+        (status = 200, description = "Address ranges overlapping between active networks", body = [NetworkOverlap]),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+    )
+)]
+pub async fn network_overlaps(_role: VpnRole, State(appstate): State<AppState>) -> ApiResult {
+    let overlaps = WireguardNetwork::all_overlaps(&appstate.pool).await?;
+    Ok(ApiResponse {
+        json: json!(overlaps),
+        status: StatusCode::OK,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/network/archived",
+    tag = "network",
+    responses(
+        (status = 200, description = "Archived networks", body = [WireguardNetworkInfo]),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+    )
+)]
+pub async fn list_archived_networks(
+    _role: VpnRole,
+    State(appstate): State<AppState>,
+    Extension(gateway_state): Extension<Arc<Mutex<GatewayMap>>>,
+) -> ApiResult {
+    debug!("Listing archived WireGuard networks");
+    let networks = WireguardNetwork::all_archived(&appstate.pool).await?;
+    let network_info = networks_info(&appstate.pool, &gateway_state, networks).await?;
+    debug!("Listed archived WireGuard networks");
+
+    Ok(ApiResponse {
+        json: json!(network_info),
+        status: StatusCode::OK,
+    })
+}
+
+/// Archive network, keeping its devices, stats and configuration.
+///
+/// Archived network is removed from gateways and isn't served to clients until it's restored.
+#[utoipa::path(
+    post,
+    path = "/api/v1/network/{network_id}/archive",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID")),
+    responses(
+        (status = 200, description = "Network archived", body = WireguardNetwork),
+        (status = 404, description = "Network not found", body = ApiError),
+        (status = 409, description = "Network is already archived", body = ApiError),
+    )
+)]
+pub async fn archive_network(
+    _role: VpnRole,
+    Path(network_id): Path<i64>,
+    State(appstate): State<AppState>,
+    session: SessionInfo,
+) -> ApiResult {
+    debug!(
+        "User {} archiving WireGuard network {network_id}",
+        session.user.username
+    );
+    let mut network = find_network(network_id, &appstate.pool).await?;
+    if network.archived {
+        return Err(WebError::Conflict(format!(
+            "Network {network_id} is already archived"
+        )));
+    }
+    network.archived = true;
+    network.save(&appstate.pool).await?;
+    appstate.send_wireguard_event(GatewayEvent::NetworkDeleted(
+        network_id,
+        network.name.clone(),
+    ));
+    info!(
+        "User {} archived WireGuard network {network}",
+        session.user.username
+    );
+
+    Ok(ApiResponse {
+        json: json!(network),
+        status: StatusCode::OK,
+    })
+}
+
+/// Restore archived network and send its full configuration to gateways.
+#[utoipa::path(
+    post,
+    path = "/api/v1/network/{network_id}/unarchive",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID")),
+    responses(
+        (status = 200, description = "Network restored", body = WireguardNetwork),
+        (status = 404, description = "Network not found", body = ApiError),
+        (status = 409, description = "Network is not archived", body = ApiError),
+    )
+)]
+pub async fn unarchive_network(
+    _role: VpnRole,
+    Path(network_id): Path<i64>,
+    State(appstate): State<AppState>,
+    session: SessionInfo,
+) -> ApiResult {
+    debug!(
+        "User {} restoring archived WireGuard network {network_id}",
+        session.user.username
+    );
+    let mut network = find_network(network_id, &appstate.pool).await?;
+    if !network.archived {
+        return Err(WebError::Conflict(format!(
+            "Network {network_id} is not archived"
+        )));
+    }
+    let mut transaction = appstate.pool.begin().await?;
+    network.archived = false;
+    network.save(&mut *transaction).await?;
+    // users and groups may have changed while network was archived,
+    // peer changes are covered by the full configuration sent below
+    network.sync_allowed_devices(&mut transaction, None).await?;
+    let peers = network.get_peers(&mut *transaction).await?;
+    transaction.commit().await?;
+    appstate.send_wireguard_event(GatewayEvent::NetworkModified(
+        network_id,
+        network.clone(),
+        peers,
+    ));
+    info!(
+        "User {} restored archived WireGuard network {network}",
+        session.user.username
+    );
+
+    Ok(ApiResponse {
+        json: json!(network),
+        status: StatusCode::OK,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/network/{network_id}",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID")),
+    responses(
+        (status = 200, description = "Network details", body = WireguardNetworkInfo),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 404, description = "Network not found"),
+    )
+)]
 pub async fn network_details(
     Path(network_id): Path<i64>,
     _role: VpnRole,
@@ -300,6 +842,16 @@ pub async fn network_details(
     Ok(response)
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/network/{network_id}/gateways",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID")),
+    responses(
+        (status = 200, description = "Gateways of the network", body = [GatewayState]),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+    )
+)]
 pub async fn gateway_status(
     Path(network_id): Path<i64>,
     _role: VpnRole,
@@ -317,6 +869,16 @@ pub async fn gateway_status(
     })
 }
 
+#[utoipa::path(
+    delete,
+    path = "/api/v1/network/{network_id}/gateways/{gateway_id}",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID"), ("gateway_id" = String, Path, description = "Gateway UUID")),
+    responses(
+        (status = 200, description = "Disconnected gateway removed"),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+    )
+)]
 pub async fn remove_gateway(
     Path((network_id, gateway_id)): Path<(i64, String)>,
     _role: VpnRole,
@@ -341,6 +903,113 @@ pub async fn remove_gateway(
     })
 }
 
+// full resyncs replace whole gateway configuration, so they're limited per network
+const GATEWAY_RESYNC_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+/// Gateway which was connected, and so received full configuration, at the time of resync.
+#[derive(Serialize, ToSchema)]
+pub struct GatewayResyncStatus {
+    pub hostname: String,
+    pub name: Option<String>,
+    pub connected: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct GatewayResync {
+    pub peers: usize,
+    pub gateways: Vec<GatewayResyncStatus>,
+}
+
+/// Push freshly generated configuration with the complete peer list to all gateways
+/// of the network, e.g. when they kept stale peers after a network partition.
+/// Gateways don't acknowledge updates, so only their connection state is reported.
+#[utoipa::path(
+    post,
+    path = "/api/v1/network/{network_id}/gateways/resync",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID")),
+    responses(
+        (status = 200, description = "Full configuration sent to connected gateways", body = GatewayResync),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 404, description = "Network not found", body = ApiError),
+        (status = 409, description = "Network is archived", body = ApiError),
+        (status = 429, description = "Network was resynced less than a minute ago", body = ApiError),
+    )
+)]
+pub async fn resync_gateways(
+    Path(network_id): Path<i64>,
+    _role: VpnRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Extension(gateway_state): Extension<Arc<Mutex<GatewayMap>>>,
+) -> ApiResult {
+    debug!("Resyncing gateways of network {network_id}");
+    let network = find_network(network_id, &appstate.pool).await?;
+    ensure_not_archived(&network)?;
+    let peers = network.get_peers(&appstate.pool).await?;
+    {
+        let mut resyncs = appstate
+            .gateway_resyncs
+            .lock()
+            .expect("Failed to acquire gateway resync lock");
+        if let Some(last_resync) = resyncs.get(&network_id) {
+            if last_resync.elapsed() < GATEWAY_RESYNC_INTERVAL {
+                warn!(
+                    "User {} requested resync of gateways of network {network}, \
+                    which was resynced {}s ago",
+                    session.user.username,
+                    last_resync.elapsed().as_secs()
+                );
+                return Err(WebError::Http(StatusCode::TOO_MANY_REQUESTS));
+            }
+        }
+        resyncs.insert(network_id, Instant::now());
+    }
+
+    let gateways: Vec<GatewayResyncStatus> = gateway_state
+        .lock()
+        .expect("Failed to acquire gateway state lock")
+        .get_network_gateway_status(network_id)
+        .into_iter()
+        .map(|gateway| GatewayResyncStatus {
+            hostname: gateway.hostname,
+            name: gateway.name,
+            connected: gateway.connected,
+        })
+        .collect();
+    let resync = GatewayResync {
+        peers: peers.len(),
+        gateways,
+    };
+    appstate.send_wireguard_event(GatewayEvent::FullResync(network_id, network.clone(), peers));
+    info!(
+        "User {} resynced {} gateways of network {network} with {} peers",
+        session.user.username,
+        resync
+            .gateways
+            .iter()
+            .filter(|gateway| gateway.connected)
+            .count(),
+        resync.peers
+    );
+
+    Ok(ApiResponse {
+        json: json!(resync),
+        status: StatusCode::OK,
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/network/import",
+    tag = "network",
+    request_body = ImportNetworkData,
+    responses(
+        (status = 201, description = "Network imported", body = ImportedNetworkData),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 422, description = "Invalid WireGuard config"),
+    )
+)]
 pub async fn import_network(
     _role: VpnRole,
     State(appstate): State<AppState>,
@@ -350,7 +1019,10 @@ pub async fn import_network(
     let (mut network, imported_devices) =
         parse_wireguard_config(&data.config).map_err(|error| {
             error!("{error}");
-            WebError::Http(StatusCode::UNPROCESSABLE_ENTITY)
+            match error {
+                WireguardConfigParseError::InvalidKey(msg) => WebError::PubkeyValidation(msg),
+                _ => WebError::Http(StatusCode::UNPROCESSABLE_ENTITY),
+            }
         })?;
     network.name = data.name;
     network.endpoint = data.endpoint;
@@ -400,6 +1072,20 @@ pub async fn import_network(
 }
 
 // This is used exclusively for the wizard to map imported devices to users.
+#[utoipa::path(
+    post,
+    path = "/api/v1/network/{network_id}/devices",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID")),
+    request_body = MappedDevices,
+    responses(
+        (status = 201, description = "Imported devices assigned to users"),
+        (status = 204, description = "No devices provided"),
+        (status = 404, description = "Network not found", body = ApiError),
+        (status = 409, description = "Network is archived or public key used by another device", body = ApiError),
+        (status = 422, description = "Invalid public key or unknown platform not allowed in the network", body = ApiError),
+    )
+)]
 pub async fn add_user_devices(
     _role: VpnRole,
     session: SessionInfo,
@@ -427,6 +1113,7 @@ pub async fn add_user_devices(
 
     match WireguardNetwork::find_by_id(&appstate.pool, network_id).await? {
         Some(network) => {
+            ensure_not_archived(&network)?;
             // wrap loop in transaction to abort if a device is invalid
             let mut transaction = appstate.pool.begin().await?;
             let events = network
@@ -454,11 +1141,100 @@ pub async fn add_user_devices(
     }
 }
 
+/// Reject public key already used by another device, which would break routing on gateways.
+async fn ensure_unique_pubkey<'e, E>(
+    executor: E,
+    device_name: &str,
+    pubkey: &WireguardPubkey,
+    device_id: Option<i64>,
+) -> Result<(), WebError>
+where
+    E: PgExecutor<'e>,
+{
+    match Device::find_duplicate_pubkey(executor, pubkey, device_id).await? {
+        Some(existing) => Err(WebError::PubkeyExists(format!(
+            "Device {device_name} can't use pubkey {pubkey}, it's already used by device {}",
+            existing.name
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AddDeviceResult {
+    configs: Vec<DeviceConfig>,
+    device: Device,
+    // the server generated the keypair and holds the private key
+    key_escrow: bool,
+}
+
+/// Gateway a device was last seen through in a location.
+#[derive(Serialize, ToSchema)]
+pub struct DeviceGateway {
+    network_id: i64,
+    hostname: String,
+    latest_handshake: Option<NaiveDateTime>,
+}
+
+/// Device with state of its DNS records and gateways it was last seen through.
+#[derive(Serialize, ToSchema)]
+pub struct DeviceDetails {
+    #[serde(flatten)]
+    device: Device,
+    dns_status: Vec<DeviceDnsStatus>,
+    gateways: Vec<DeviceGateway>,
+    // the server holds the private key
+    key_escrow: bool,
+}
+
+#[derive(Deserialize)]
+pub struct AddDeviceQuery {
+    #[serde(default)]
+    override_limit: bool,
+    #[serde(default)]
+    generate_keys: bool,
+}
+
+/// Active locations refuse escrowed keys unless they use server managed keys.
+fn ensure_server_managed_keys(networks: &[WireguardNetwork]) -> Result<(), WebError> {
+    match networks
+        .iter()
+        .find(|network| !network.archived && !network.server_managed_keys)
+    {
+        Some(network) => Err(WebError::BadRequest(format!(
+            "location {} doesn't use server managed keys",
+            network.name
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/device/{username}",
+    tag = "device",
+    params(
+        ("username" = String, Path, description = "Owner username"),
+        ("override_limit" = Option<bool>, Query, description = "Add the device even if the user has reached the device limit, admins only"),
+        ("generate_keys" = Option<bool>, Query, description = "Generate the keypair on the server, which escrows the private key; admins only, all locations must use server managed keys"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the stored response if the same request was already made with this key; not allowed with generate_keys"),
+    ),
+    request_body = AddDevice,
+    responses(
+        (status = 201, description = "Device added with its config for every network; name may be sanitized. Configs include a generated private key only for admins with key escrow permission", body = AddDeviceResult),
+        (status = 400, description = "No networks, invalid name, name can't be used as DNS label, keys requested from a location without server managed keys, or keys requested with an idempotency key", body = ApiError),
+        (status = 403, description = "Not an admin or the user itself, user is disabled, or limit override or key generation requested by non-admin", body = ApiError),
+        (status = 409, description = "Public key or DNS name used by another device, name used by another device of the user, or device limit reached", body = ApiError),
+        (status = 422, description = "Invalid public key", body = ApiError),
+    )
+)]
 pub async fn add_device(
     session: SessionInfo,
     State(appstate): State<AppState>,
     // Alias, because otherwise `axum` reports conflicting routes.
     Path(username): Path<String>,
+    Query(query): Query<AddDeviceQuery>,
+    mut transaction: IdempotentTx,
     Json(add_device): Json<AddDevice>,
 ) -> ApiResult {
     let device_name = add_device.name.clone();
@@ -467,6 +1243,31 @@ pub async fn add_device(
         session.user.username,
     );
 
+    if query.override_limit && !session.is_admin {
+        info!(
+            "User {} tried to override device limit of user {username}",
+            session.user.username
+        );
+        return Err(WebError::Forbidden(
+            "Only admins can override device limit.".into(),
+        ));
+    }
+    if query.generate_keys && !session.is_admin {
+        info!(
+            "User {} tried to generate keys of a device for user {username}",
+            session.user.username
+        );
+        return Err(WebError::Forbidden(
+            "Only admins can create devices with server managed keys.".into(),
+        ));
+    }
+    // stored responses must not contain private keys
+    if query.generate_keys && transaction.has_key() {
+        return Err(WebError::BadRequest(
+            "Idempotency key can't be used when keys are generated by the server".into(),
+        ));
+    }
+
     let user = user_for_admin_or_self(&appstate.pool, &session, &username).await?;
 
     // Let admins manage devices for disabled users
@@ -479,7 +1280,7 @@ pub async fn add_device(
         return Err(WebError::Forbidden("User is disabled.".into()));
     }
 
-    let networks = WireguardNetwork::all(&appstate.pool).await?;
+    let networks = WireguardNetwork::all(&mut *transaction).await?;
     if networks.is_empty() {
         error!("Failed to add device {device_name}, no networks found");
         return Ok(ApiResponse {
@@ -488,18 +1289,25 @@ pub async fn add_device(
         });
     }
 
-    Device::validate_pubkey(&add_device.wireguard_pubkey).map_err(WebError::PubkeyValidation)?;
-
-    // Make sure there is no device with the same pubkey, such state may lead to unexpected issues
-    if Device::find_by_pubkey(&appstate.pool, &add_device.wireguard_pubkey)
-        .await?
-        .is_some()
-    {
-        return Err(WebError::PubkeyExists(format!(
-            "Failed to add device {device_name}, identical pubkey ({}) already exists",
-            add_device.wireguard_pubkey
-        )));
-    }
+    // the private key is escrowed along with the device
+    let generated = if query.generate_keys {
+        ensure_server_managed_keys(&networks)?;
+        if !add_device.wireguard_pubkey.is_empty() {
+            return Err(WebError::BadRequest(
+                "public key can't be provided when keys are generated by the server".into(),
+            ));
+        }
+        Some((KeyEncryptionKey::from_config()?, WireguardNetwork::genkey()))
+    } else {
+        None
+    };
+    let pubkey = WireguardPubkey::parse(
+        generated
+            .as_ref()
+            .map_or(&add_device.wireguard_pubkey, |(_, key)| &key.public),
+    )
+    .map_err(|err| WebError::PubkeyValidation(err.to_string()))?;
+    ensure_unique_pubkey(&mut *transaction, &device_name, &pubkey, None).await?;
 
     // save device
     let Some(user_id) = user.id else {
@@ -509,32 +1317,73 @@ pub async fn add_device(
         );
         return Err(WebError::ModelError("User has no id".to_string()));
     };
-    let mut device = Device::new(add_device.name, add_device.wireguard_pubkey, user_id);
 
-    let mut transaction = appstate.pool.begin().await?;
+    match Device::check_limit(&mut transaction, user_id).await {
+        Ok(()) => (),
+        Err(DeviceError::LimitExceeded { count, limit }) if query.override_limit => {
+            warn!(
+                device_limit_override = true,
+                "Admin {} added device {device_name} for user {username} over the device limit \
+                ({count} of {limit} devices)",
+                session.user.username
+            );
+        }
+        Err(err) => return Err(err.into()),
+    }
+    // name may be sanitized, DNS records are published under the saved one
+    let name = Device::check_name(&mut transaction, user_id, &add_device.name, None).await?;
+    dns::ensure_publishable_name(&appstate.pool, &name, None).await?;
+    let mut device = Device::new(name, pubkey.into(), user_id);
     device.save(&mut *transaction).await?;
+    if let Some((key_encryption_key, key)) = &generated {
+        key_escrow::store(
+            &mut *transaction,
+            key_encryption_key,
+            device.get_id()?,
+            &key.private,
+        )
+        .await?;
+        warn!(
+            key_escrow = true,
+            "User {} created device {device_name} for user {username} with server managed keys, \
+            private key is escrowed",
+            session.user.username
+        );
+    }
 
     // assign IPs and generate configs for each network
-    #[derive(Serialize)]
-    struct AddDeviceResult {
-        configs: Vec<DeviceConfig>,
-        device: Device,
+    let (network_info, mut configs) = device.add_to_all_networks(&mut transaction).await?;
+    if let Some((_, key)) = &generated {
+        if session.can_access_escrowed_keys() {
+            key_escrow::record_access(
+                &mut *transaction,
+                device.get_id()?,
+                None,
+                session.user.id,
+                "device_created",
+            )
+            .await?;
+            warn!(
+                key_escrow_access = true,
+                "User {} received escrowed private key of new device {device_name}",
+                session.user.username
+            );
+            for config in &mut configs {
+                config.config = config.config.replace(PRIVATE_KEY_PLACEHOLDER, &key.private);
+            }
+        }
     }
 
-    let (network_info, configs) = device.add_to_all_networks(&mut transaction).await?;
-
     let mut network_ips: Vec<String> = Vec::new();
     for network_info_item in network_info.clone() {
         network_ips.push(network_info_item.device_wireguard_ip.to_string());
     }
 
-    appstate.send_wireguard_event(GatewayEvent::DeviceCreated(DeviceInfo {
+    appstate.send_multiple_wireguard_events(GatewayEvent::peers_added(DeviceInfo {
         device: device.clone(),
         network_info: network_info.clone(),
     }));
 
-    transaction.commit().await?;
-
     let template_locations: Vec<TemplateLocation> = configs
         .iter()
         .map(|c| TemplateLocation {
@@ -542,6 +1391,16 @@ pub async fn add_device(
             assigned_ip: c.address.to_string(),
         })
         .collect();
+    let result = AddDeviceResult {
+        configs,
+        device: device.clone(),
+        key_escrow: generated.is_some(),
+    };
+    let response = ApiResponse {
+        json: json!(result),
+        status: StatusCode::CREATED,
+    };
+    transaction.commit(&response).await?;
 
     // hide session info if triggered by admin for other user
     let (session_ip, session_device_info) = if session.is_admin && session.user != user {
@@ -567,14 +1426,23 @@ pub async fn add_device(
         session.user.username
     );
 
-    let result = AddDeviceResult { configs, device };
-
-    Ok(ApiResponse {
-        json: json!(result),
-        status: StatusCode::CREATED,
-    })
+    Ok(response)
 }
 
+#[utoipa::path(
+    put,
+    path = "/api/v1/device/{device_id}",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID")),
+    request_body = ModifyDevice,
+    responses(
+        (status = 200, description = "Device modified; name may be sanitized. Escrowed private key is removed if the public key changes", body = Device),
+        (status = 400, description = "Public key of a network, no networks, invalid name, or name can't be used as DNS label", body = ApiError),
+        (status = 404, description = "Device not found", body = ApiError),
+        (status = 409, description = "Public key or DNS name used by another device, or name used by another device of the user", body = ApiError),
+        (status = 422, description = "Invalid public key", body = ApiError),
+    )
+)]
 pub async fn modify_device(
     session: SessionInfo,
     Path(device_id): Path<i64>,
@@ -594,8 +1462,10 @@ pub async fn modify_device(
     }
 
     // check pubkeys
+    let pubkey = WireguardPubkey::parse(&data.wireguard_pubkey)
+        .map_err(|err| WebError::PubkeyValidation(err.to_string()))?;
     for network in &networks {
-        if network.pubkey == data.wireguard_pubkey {
+        if network.pubkey == pubkey.as_str() {
             error!("Failed to update device {device_id}, device's pubkey must be different from server's pubkey");
             return Ok(ApiResponse {
                 json: json!({"msg": "device's pubkey must be different from server's pubkey"}),
@@ -603,34 +1473,71 @@ pub async fn modify_device(
             });
         }
     }
+    ensure_unique_pubkey(&appstate.pool, &device.name, &pubkey, device.id).await?;
+    // names saved before a device name policy was set are kept unless changed
+    let mut transaction = appstate.pool.begin().await?;
+    let renamed = device.name != data.name;
+    let name = if renamed {
+        let name =
+            Device::check_name(&mut transaction, device.user_id, &data.name, device.id).await?;
+        dns::ensure_publishable_name(&appstate.pool, &name, device.id).await?;
+        name
+    } else {
+        data.name
+    };
 
     // update device info
-    device.update_from(data);
-    device.save(&appstate.pool).await?;
+    let previous_pubkey = device.wireguard_pubkey.clone();
+    device.update_from(ModifyDevice {
+        name,
+        wireguard_pubkey: pubkey.into(),
+    });
+    device.save(&mut *transaction).await?;
+    device.clear_pubkey_issue(&mut *transaction).await?;
+    // the device uses a key of its own from now on
+    if device.wireguard_pubkey != previous_pubkey
+        && key_escrow::remove(&mut *transaction, device_id).await?
+    {
+        warn!(
+            key_escrow = true,
+            "User {} replaced server managed key of device {device_id}, escrowed private key removed",
+            session.user.username
+        );
+    }
+    transaction.commit().await?;
 
-    // send update to gateway's
-    let mut network_info = Vec::new();
-    for network in &networks {
-        if let Some(network_id) = network.id {
-            if let Some(device_id) = device.id {
-                let wireguard_network_device =
-                    WireguardNetworkDevice::find(&appstate.pool, device_id, network_id).await?;
-                if let Some(wireguard_network_device) = wireguard_network_device {
-                    let device_network_info = DeviceNetworkInfo {
-                        network_id,
-                        device_wireguard_ip: wireguard_network_device.wireguard_ip,
-                        preshared_key: wireguard_network_device.preshared_key,
-                        is_authorized: wireguard_network_device.is_authorized,
-                    };
-                    network_info.push(device_network_info);
+    // gateways only need to know about key changes
+    if device.wireguard_pubkey != previous_pubkey {
+        let mut network_info = Vec::new();
+        for network in networks.iter().filter(|network| !network.archived) {
+            if let Some(network_id) = network.id {
+                if let Some(device_id) = device.id {
+                    let wireguard_network_device =
+                        WireguardNetworkDevice::find(&appstate.pool, device_id, network_id).await?;
+                    if let Some(wireguard_network_device) = wireguard_network_device {
+                        let device_network_info = DeviceNetworkInfo {
+                            network_id,
+                            device_wireguard_ip: wireguard_network_device.wireguard_ip,
+                            preshared_key: wireguard_network_device.preshared_key,
+                            is_authorized: wireguard_network_device.is_authorized,
+                        };
+                        network_info.push(device_network_info);
+                    }
                 }
             }
         }
+        appstate.send_multiple_wireguard_events(GatewayEvent::peers_modified(
+            DeviceInfo {
+                device: device.clone(),
+                network_info,
+            },
+            Some(previous_pubkey),
+        ));
+    }
+    // DNS records follow device names
+    if renamed {
+        dns::request_sync();
     }
-    appstate.send_wireguard_event(GatewayEvent::DeviceModified(DeviceInfo {
-        device: device.clone(),
-        network_info,
-    }));
 
     info!("User {} updated device {device_id}", session.user.username);
     Ok(ApiResponse {
@@ -639,6 +1546,16 @@ pub async fn modify_device(
     })
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/device/{device_id}",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID")),
+    responses(
+        (status = 200, description = "Device with state of its DNS records and whether the server holds its private key", body = DeviceDetails),
+        (status = 404, description = "Device not found", body = ApiError),
+    )
+)]
 pub async fn get_device(
     session: SessionInfo,
     Path(device_id): Path<i64>,
@@ -646,13 +1563,44 @@ pub async fn get_device(
 ) -> ApiResult {
     debug!("Retrieving device with id: {device_id}");
     let device = device_for_admin_or_self(&appstate.pool, &session, device_id).await?;
+    let dns_status = DeviceDnsStatus::for_device(&appstate.pool, device_id).await?;
+    let gateways = WireguardNetworkDevice::find_by_device(&appstate.pool, device_id)
+        .await?
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|network_device| {
+            network_device
+                .gateway_hostname
+                .map(|hostname| DeviceGateway {
+                    network_id: network_device.wireguard_network_id,
+                    hostname,
+                    latest_handshake: network_device.gateway_handshake,
+                })
+        })
+        .collect();
+    let key_escrow = key_escrow::is_escrowed(&appstate.pool, device_id).await?;
     debug!("Retrieved device with id: {device_id}");
     Ok(ApiResponse {
-        json: json!(device),
+        json: json!(DeviceDetails {
+            device,
+            dns_status,
+            gateways,
+            key_escrow,
+        }),
         status: StatusCode::OK,
     })
 }
 
+#[utoipa::path(
+    delete,
+    path = "/api/v1/device/{device_id}",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID")),
+    responses(
+        (status = 200, description = "Device deleted"),
+        (status = 404, description = "Device not found", body = ApiError),
+    )
+)]
 pub async fn delete_device(
     session: SessionInfo,
     Path(device_id): Path<i64>,
@@ -660,7 +1608,7 @@ pub async fn delete_device(
 ) -> ApiResult {
     debug!("User {} deleting device {device_id}", session.user.username);
     let device = device_for_admin_or_self(&appstate.pool, &session, device_id).await?;
-    appstate.send_wireguard_event(GatewayEvent::DeviceDeleted(
+    appstate.send_multiple_wireguard_events(GatewayEvent::peers_removed(
         DeviceInfo::from_device(&appstate.pool, device.clone()).await?,
     ));
     device.delete(&appstate.pool).await?;
@@ -668,9 +1616,227 @@ pub async fn delete_device(
     Ok(ApiResponse::default())
 }
 
-pub async fn list_devices(_role: VpnRole, State(appstate): State<AppState>) -> ApiResult {
+#[derive(Deserialize, ToSchema)]
+pub struct DeviceTransfer {
+    pub username: String,
+}
+
+/// Assign device to another user, keeping its network IPs and authorization state.
+///
+/// Network access is re-evaluated for the new owner, so the device may be removed
+/// from (or added to) locations restricted to specific groups.
+#[utoipa::path(
+    post,
+    path = "/api/v1/device/{device_id}/transfer",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID")),
+    request_body = DeviceTransfer,
+    responses(
+        (status = 200, description = "Device transferred", body = Device),
+        (status = 400, description = "Device already belongs to the user", body = ApiError),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+        (status = 404, description = "Device or user not found", body = ApiError),
+    )
+)]
+pub async fn transfer_device(
+    _admin: AdminRole,
+    session: SessionInfo,
+    Path(device_id): Path<i64>,
+    State(appstate): State<AppState>,
+    Json(data): Json<DeviceTransfer>,
+) -> ApiResult {
+    debug!(
+        "User {} transferring device {device_id} to user {}",
+        session.user.username, data.username
+    );
+    let Some(mut device) = Device::find_by_id(&appstate.pool, device_id).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "device id {device_id} not found"
+        )));
+    };
+    let Some(new_owner) = User::find_by_username(&appstate.pool, &data.username).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "user {} not found",
+            data.username
+        )));
+    };
+    let Some(previous_owner) = User::find_by_id(&appstate.pool, device.user_id).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "owner of device {device_id} not found"
+        )));
+    };
+    let Some(new_owner_id) = new_owner.id else {
+        return Err(WebError::ModelError("User has no id".into()));
+    };
+    if device.user_id == new_owner_id {
+        return Err(WebError::BadRequest(format!(
+            "device {device_id} already belongs to user {}",
+            new_owner.username
+        )));
+    }
+
+    let mut transaction = appstate.pool.begin().await?;
+    device.user_id = new_owner_id;
+    device.save(&mut *transaction).await?;
+    // allowed groups of the new owner may differ
+    let mut events = Vec::new();
+    for network in WireguardNetwork::all_active(&mut *transaction).await? {
+        events.extend(network.sync_allowed_devices(&mut transaction, None).await?);
+    }
+    transaction.commit().await?;
+    appstate.send_multiple_wireguard_events(events);
+
+    send_device_transferred_email(
+        &device.name,
+        &device.wireguard_pubkey,
+        &previous_owner,
+        &new_owner,
+        &appstate.mail_tx,
+    )?;
+    info!(
+        "User {} transferred device {} from user {} to user {}",
+        session.user.username, device.name, previous_owner.username, new_owner.username
+    );
+
+    Ok(ApiResponse {
+        json: json!(device),
+        status: StatusCode::OK,
+    })
+}
+
+/// Allow a device blocked by disconnecting its owner to connect again.
+#[utoipa::path(
+    post,
+    path = "/api/v1/device/{device_id}/unblock",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID")),
+    responses(
+        (status = 200, description = "Device unblocked", body = Device),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+        (status = 404, description = "Device not found", body = ApiError),
+    )
+)]
+pub async fn unblock_device(
+    _admin: AdminRole,
+    session: SessionInfo,
+    Path(device_id): Path<i64>,
+    State(appstate): State<AppState>,
+) -> ApiResult {
+    debug!(
+        "User {} unblocking device {device_id}",
+        session.user.username
+    );
+    let Some(mut device) = Device::find_by_id(&appstate.pool, device_id).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "device id {device_id} not found"
+        )));
+    };
+    if device.blocked {
+        device.set_blocked(&appstate.pool, false).await?;
+        let device_info = DeviceInfo::from_device(&appstate.pool, device.clone()).await?;
+        appstate.send_multiple_wireguard_events(GatewayEvent::peers_added(device_info));
+        warn!(
+            security_action = "unblock_device",
+            device_id, "User {} unblocked device {device}", session.user.username
+        );
+    }
+
+    Ok(ApiResponse {
+        json: json!(device),
+        status: StatusCode::OK,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct DeviceQuery {
+    os: Option<String>,
+    client_version_lt: Option<String>,
+}
+
+impl DeviceQuery {
+    /// Devices which haven't reported their platform don't match any filter.
+    fn matches(&self, device: &Device) -> bool {
+        if let Some(os) = &self.os {
+            if !device
+                .os
+                .as_ref()
+                .is_some_and(|device_os| device_os.eq_ignore_ascii_case(os.trim()))
+            {
+                return false;
+            }
+        }
+        if let Some(bound) = &self.client_version_lt {
+            if !device
+                .client_version
+                .as_ref()
+                .is_some_and(|version| compare_versions(version, bound) == std::cmp::Ordering::Less)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Device flagged with other devices of its owner which appear to run on the same machine,
+/// usually left behind by enrolling it again after OS reinstall.
+#[derive(Serialize, ToSchema)]
+pub struct ListedDevice {
+    #[serde(flatten)]
+    device: Device,
+    likely_duplicates: Vec<i64>,
+}
+
+impl ListedDevice {
+    /// Devices are only compared with other devices of the same user.
+    fn flag_duplicates(devices: Vec<Device>) -> Vec<Self> {
+        let mut by_user: HashMap<i64, Vec<&Device>> = HashMap::new();
+        for device in &devices {
+            by_user.entry(device.user_id).or_default().push(device);
+        }
+        let duplicates: Vec<Vec<i64>> = devices
+            .iter()
+            .map(|device| {
+                let hints = device.machine_hints();
+                by_user[&device.user_id]
+                    .iter()
+                    .filter(|other| other.id != device.id && other.same_machine(&hints))
+                    .filter_map(|other| other.id)
+                    .collect()
+            })
+            .collect();
+        devices
+            .into_iter()
+            .zip(duplicates)
+            .map(|(device, likely_duplicates)| Self {
+                device,
+                likely_duplicates,
+            })
+            .collect()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/device",
+    tag = "device",
+    params(
+        ("os" = Option<String>, Query, description = "Only devices running given operating system, e.g. windows"),
+        ("client_version_lt" = Option<String>, Query, description = "Only devices with client older than given version"),
+    ),
+    responses(
+        (status = 200, description = "All devices, with likely duplicates of each", body = [ListedDevice]),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+    )
+)]
+pub async fn list_devices(
+    _role: VpnRole,
+    read_pool: ReadPool,
+    Query(query): Query<DeviceQuery>,
+) -> ApiResult {
     debug!("Listing devices");
-    let devices = Device::all(&appstate.pool).await?;
+    let mut devices = ListedDevice::flag_duplicates(Device::all(read_pool.pool()).await?);
+    devices.retain(|listed| query.matches(&listed.device));
     info!("Listed {} devices", devices.len());
 
     Ok(ApiResponse {
@@ -679,9 +1845,46 @@ pub async fn list_devices(_role: VpnRole, State(appstate): State<AppState>) -> A
     })
 }
 
+/// Devices with public keys gateways can't use, flagged when upgrading. Keys are never fixed
+/// automatically; a device leaves the report once its key is updated to a valid, unique one.
+#[utoipa::path(
+    get,
+    path = "/api/v1/device/invalid_pubkeys",
+    tag = "device",
+    responses(
+        (status = 200, description = "Devices with invalid or duplicate public keys", body = [InvalidPubkeyDevice]),
+        (status = 403, description = "Not an admin", body = ApiError),
+    )
+)]
+pub async fn list_invalid_pubkeys(
+    _admin: AdminRole,
+    State(appstate): State<AppState>,
+) -> ApiResult {
+    debug!("Listing devices with invalid public keys");
+    let devices = Device::invalid_pubkeys(&appstate.pool).await?;
+    if !devices.is_empty() {
+        warn!("{} devices have invalid public keys", devices.len());
+    }
+
+    Ok(ApiResponse {
+        json: json!(devices),
+        status: StatusCode::OK,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/device/user/{username}",
+    tag = "device",
+    params(("username" = String, Path, description = "Username")),
+    responses(
+        (status = 200, description = "Devices of the user", body = [Device]),
+        (status = 403, description = "Not an admin or the user itself", body = ApiError),
+    )
+)]
 pub async fn list_user_devices(
     session: SessionInfo,
-    State(appstate): State<AppState>,
+    read_pool: ReadPool,
     Path(username): Path<String>,
 ) -> ApiResult {
     // only allow for admin or user themselves
@@ -693,7 +1896,7 @@ pub async fn list_user_devices(
         return Err(WebError::Forbidden("Admin access required".into()));
     };
     debug!("Listing devices for user: {username}");
-    let devices = Device::all_for_username(&appstate.pool, &username).await?;
+    let devices = Device::all_for_username(read_pool.pool(), &username).await?;
     info!("Listed {} devices for user: {username}", devices.len());
 
     Ok(ApiResponse {
@@ -702,19 +1905,111 @@ pub async fn list_user_devices(
     })
 }
 
+/// Escrowed private key of a device, read for its config in given network.
+/// Only admins with key escrow permission can read keys, in locations with server managed keys.
+async fn escrowed_private_key(
+    appstate: &AppState,
+    session: &SessionInfo,
+    device: &Device,
+    network: &WireguardNetwork,
+    purpose: &str,
+) -> Result<String, WebError> {
+    if !session.can_access_escrowed_keys() {
+        warn!(
+            key_escrow_access = true,
+            "User {} was denied escrowed private key of device {device} in network {network}",
+            session.user.username
+        );
+        return Err(WebError::Forbidden(
+            "Reading escrowed private keys requires key escrow permission.".into(),
+        ));
+    }
+    if !network.server_managed_keys {
+        return Err(WebError::BadRequest(format!(
+            "location {} doesn't use server managed keys",
+            network.name
+        )));
+    }
+    let Some(private_key) = key_escrow::read_private_key(
+        &appstate.pool,
+        device.get_id()?,
+        network.id,
+        session.user.id,
+        purpose,
+    )
+    .await?
+    else {
+        return Err(WebError::ObjectNotFound(format!(
+            "private key of device {} is not escrowed",
+            device.name
+        )));
+    };
+    warn!(
+        key_escrow_access = true,
+        "User {} read escrowed private key of device {device} in network {network} for {purpose}",
+        session.user.username
+    );
+    Ok(private_key)
+}
+
+#[derive(Deserialize)]
+pub struct ConfigQuery {
+    // include escrowed private key of the device
+    #[serde(default)]
+    private_key: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/network/{network_id}/device/{device_id}/config",
+    tag = "device",
+    params(("network_id" = i64, Path, description = "Network ID"), ("device_id" = i64, Path, description = "Device ID"), ("private_key" = Option<bool>, Query, description = "Include escrowed private key, requires key escrow permission")),
+    responses(
+        (status = 200, description = "WireGuard config of the device, without its private key unless requested, as an attachment named after the device and the network", body = String, content_type = "text/plain"),
+        (status = 400, description = "Private key requested in a location without server managed keys", body = ApiError),
+        (status = 403, description = "Private key requested without key escrow permission", body = ApiError),
+        (status = 404, description = "Device has no address in the network, or its private key is not escrowed", body = ApiError),
+    )
+)]
 pub async fn download_config(
     session: SessionInfo,
     State(appstate): State<AppState>,
     Path((network_id, device_id)): Path<(i64, i64)>,
-) -> Result<String, WebError> {
+    Query(query): Query<ConfigQuery>,
+) -> Result<Response, WebError> {
     debug!("Creating config for device {device_id} in network {network_id}");
     let network = find_network(network_id, &appstate.pool).await?;
     let device = device_for_admin_or_self(&appstate.pool, &session, device_id).await?;
     let wireguard_network_device =
         WireguardNetworkDevice::find(&appstate.pool, device_id, network_id).await?;
     if let Some(wireguard_network_device) = wireguard_network_device {
+        let mut config = device.create_config(&network, &wireguard_network_device);
+        if query.private_key {
+            let private_key =
+                escrowed_private_key(&appstate, &session, &device, &network, "config_download")
+                    .await?;
+            config = config.replace(PRIVATE_KEY_PLACEHOLDER, &private_key);
+        }
         info!("Created config for device {}({device_id})", device.name);
-        Ok(device.create_config(&network, &wireguard_network_device))
+        let disposition = format!(
+            "attachment; filename=\"{}\"",
+            device.config_file_name(&network)
+        );
+        let mut response = (
+            [
+                (header::CONTENT_TYPE, "text/plain"),
+                (header::CONTENT_DISPOSITION, disposition.as_str()),
+            ],
+            config,
+        )
+            .into_response();
+        // configs with private keys must not be stored by browsers or proxies
+        if query.private_key {
+            response
+                .headers_mut()
+                .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        }
+        Ok(response)
     } else {
         let device_id = if let Some(id) = device.id {
             id.to_string()
@@ -732,6 +2027,628 @@ pub async fn download_config(
     }
 }
 
+/// Per-device bandwidth limits, overriding location defaults. Empty means location default.
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct DeviceBandwidthLimits {
+    #[serde(default)]
+    pub upload_limit_kbps: Option<i32>,
+    #[serde(default)]
+    pub download_limit_kbps: Option<i32>,
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/network/{network_id}/device/{device_id}/limits",
+    tag = "device",
+    params(("network_id" = i64, Path, description = "Network ID"), ("device_id" = i64, Path, description = "Device ID")),
+    request_body = DeviceBandwidthLimits,
+    responses(
+        (status = 200, description = "Device bandwidth limits changed", body = DeviceBandwidthLimits),
+        (status = 400, description = "Invalid limits", body = ApiError),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 404, description = "Device not assigned to the network", body = ApiError),
+        (status = 409, description = "Network is archived", body = ApiError),
+    )
+)]
+pub async fn set_device_bandwidth_limits(
+    _role: VpnRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path((network_id, device_id)): Path<(i64, i64)>,
+    Json(data): Json<DeviceBandwidthLimits>,
+) -> ApiResult {
+    debug!(
+        "User {} changing bandwidth limits of device {device_id} in network {network_id}",
+        session.user.username
+    );
+    validate_bandwidth_limit(data.upload_limit_kbps, "upload")?;
+    validate_bandwidth_limit(data.download_limit_kbps, "download")?;
+    let network = find_network(network_id, &appstate.pool).await?;
+    ensure_not_archived(&network)?;
+    let device = Device::find_by_id(&appstate.pool, device_id)
+        .await?
+        .ok_or_else(|| WebError::ObjectNotFound(format!("device {device_id} not found")))?;
+    let mut network_device = WireguardNetworkDevice::find(&appstate.pool, device_id, network_id)
+        .await?
+        .ok_or_else(|| {
+            WebError::ObjectNotFound(format!(
+                "device {} is not assigned to network {}",
+                device.name, network.name
+            ))
+        })?;
+    network_device.upload_limit_kbps = data.upload_limit_kbps;
+    network_device.download_limit_kbps = data.download_limit_kbps;
+    network_device
+        .update_bandwidth_limits(&appstate.pool)
+        .await?;
+
+    // gateways read current limits when applying peer updates
+    appstate.send_wireguard_event(GatewayEvent::PeerModified(
+        PeerUpdate {
+            network_info: DeviceNetworkInfo {
+                network_id,
+                device_wireguard_ip: network_device.wireguard_ip,
+                preshared_key: network_device.preshared_key,
+                is_authorized: network_device.is_authorized,
+            },
+            device,
+        },
+        None,
+    ));
+    info!(
+        device_id,
+        "User {} set bandwidth limits of device {device_id} in network {network}: upload {:?} kbps, download {:?} kbps",
+        session.user.username,
+        data.upload_limit_kbps,
+        data.download_limit_kbps
+    );
+
+    Ok(ApiResponse {
+        json: json!(data),
+        status: StatusCode::OK,
+    })
+}
+
+/// Preshared key provided by the operator, e.g. one built into appliance firmware.
+#[derive(Deserialize, ToSchema)]
+pub struct PinnedPresharedKey {
+    /// 32 bytes of base64, as generated by `wg genpsk`.
+    preshared_key: String,
+}
+
+/// Pinned preshared key; the key itself is never returned.
+#[derive(Serialize, ToSchema)]
+pub struct PinnedPresharedKeyInfo {
+    fingerprint: String,
+    pinned: bool,
+}
+
+/// Fetch device assignment to a network whose preshared key can be pinned.
+async fn pinned_psk_target(
+    appstate: &AppState,
+    network_id: i64,
+    device_id: i64,
+) -> Result<(Device, WireguardNetwork, WireguardNetworkDevice), WebError> {
+    let network = find_network(network_id, &appstate.pool).await?;
+    ensure_not_archived(&network)?;
+    if network.mfa_enabled {
+        return Err(WebError::BadRequest(format!(
+            "preshared keys in MFA-protected network {} are renewed on every login",
+            network.name
+        )));
+    }
+    let device = Device::find_by_id(&appstate.pool, device_id)
+        .await?
+        .ok_or_else(|| WebError::ObjectNotFound(format!("device {device_id} not found")))?;
+    let network_device = WireguardNetworkDevice::find(&appstate.pool, device_id, network_id)
+        .await?
+        .ok_or_else(|| {
+            WebError::ObjectNotFound(format!(
+                "device {} is not assigned to network {}",
+                device.name, network.name
+            ))
+        })?;
+    Ok((device, network, network_device))
+}
+
+/// Use preshared key provided by the operator for device in given network.
+///
+/// The key is excluded from rotation until unpinned. It can't be read back,
+/// only its fingerprint is returned.
+#[utoipa::path(
+    put,
+    path = "/api/v1/network/{network_id}/device/{device_id}/preshared_key",
+    tag = "device",
+    params(("network_id" = i64, Path, description = "Network ID"), ("device_id" = i64, Path, description = "Device ID")),
+    request_body = PinnedPresharedKey,
+    responses(
+        (status = 200, description = "Preshared key pinned", body = PinnedPresharedKeyInfo),
+        (status = 400, description = "Invalid key, or preshared keys in MFA-protected networks are renewed on login", body = ApiError),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 404, description = "Device not assigned to the network", body = ApiError),
+        (status = 409, description = "Network is archived", body = ApiError),
+    )
+)]
+pub async fn pin_device_psk(
+    _role: VpnRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path((network_id, device_id)): Path<(i64, i64)>,
+    Json(data): Json<PinnedPresharedKey>,
+) -> ApiResult {
+    debug!(
+        "User {} pinning preshared key of device {device_id} in network {network_id}",
+        session.user.username
+    );
+    let preshared_key = parse_preshared_key(&data.preshared_key)
+        .ok_or_else(|| WebError::BadRequest("preshared key must be 32 bytes of base64".into()))?;
+    let (device, network, mut network_device) =
+        pinned_psk_target(&appstate, network_id, device_id).await?;
+    let fingerprint = key_fingerprint(&preshared_key);
+    network_device
+        .pin_preshared_key(&appstate.pool, preshared_key)
+        .await?;
+
+    appstate.send_wireguard_event(GatewayEvent::PeerModified(
+        PeerUpdate {
+            network_info: DeviceNetworkInfo {
+                network_id,
+                device_wireguard_ip: network_device.wireguard_ip,
+                preshared_key: network_device.preshared_key,
+                is_authorized: network_device.is_authorized,
+            },
+            device,
+        },
+        None,
+    ));
+    info!(
+        device_id,
+        "User {} pinned preshared key {fingerprint} of device {device_id} in network {network}",
+        session.user.username
+    );
+
+    Ok(ApiResponse {
+        json: json!(PinnedPresharedKeyInfo {
+            fingerprint,
+            pinned: true,
+        }),
+        status: StatusCode::OK,
+    })
+}
+
+/// Let rotation policy of the network replace pinned preshared key of a device.
+/// The pinned key stays in use until then.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/network/{network_id}/device/{device_id}/preshared_key",
+    tag = "device",
+    params(("network_id" = i64, Path, description = "Network ID"), ("device_id" = i64, Path, description = "Device ID")),
+    responses(
+        (status = 200, description = "Preshared key unpinned"),
+        (status = 400, description = "Preshared keys in MFA-protected networks are renewed on login", body = ApiError),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 404, description = "Device not assigned to the network", body = ApiError),
+        (status = 409, description = "Network is archived", body = ApiError),
+    )
+)]
+pub async fn unpin_device_psk(
+    _role: VpnRole,
+    session: SessionInfo,
+    State(appstate): State<AppState>,
+    Path((network_id, device_id)): Path<(i64, i64)>,
+) -> ApiResult {
+    let (_, network, mut network_device) =
+        pinned_psk_target(&appstate, network_id, device_id).await?;
+    network_device.unpin_preshared_key(&appstate.pool).await?;
+    info!(
+        device_id,
+        "User {} unpinned preshared key of device {device_id} in network {network}",
+        session.user.username
+    );
+
+    Ok(ApiResponse::default())
+}
+
+#[derive(Deserialize)]
+pub struct ConfigQrQuery {
+    #[serde(default)]
+    format: QrFormat,
+    // one-time link token handed out by web enrollment
+    token: Option<String>,
+    // include escrowed private key of the device
+    #[serde(default)]
+    private_key: bool,
+}
+
+/// QR code of device config, for mobile WireGuard apps.
+///
+/// The server doesn't store device private keys, so configs rendered from the database
+/// can't be turned into a working QR code. Complete configs are only available through
+/// one-time links returned by web enrollment, which don't require a session, or for devices
+/// with server managed keys to admins with key escrow permission.
+#[utoipa::path(
+    get,
+    path = "/api/v1/device/{device_id}/config/{network_id}/qr",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID"), ("network_id" = i64, Path, description = "Network ID"), ("format" = Option<String>, Query, description = "Image format, `svg` (default) or `png`"), ("token" = Option<String>, Query, description = "One-time link token returned by web enrollment"), ("private_key" = Option<bool>, Query, description = "Include escrowed private key, requires key escrow permission")),
+    responses(
+        (status = 200, description = "QR code image of the device config", body = Vec<u8>, content_type = ["image/svg+xml", "image/png"]),
+        (status = 400, description = "Private key requested in a location without server managed keys", body = ApiError),
+        (status = 401, description = "Neither session nor link token provided", body = ApiError),
+        (status = 403, description = "Private key requested without key escrow permission", body = ApiError),
+        (status = 404, description = "Device not found, link token invalid, or private key not escrowed", body = ApiError),
+        (status = 409, description = "Stored config lacks the device private key", body = ApiError),
+    )
+)]
+pub async fn device_config_qr(
+    session: Option<SessionInfo>,
+    Path((device_id, network_id)): Path<(i64, i64)>,
+    Query(query): Query<ConfigQrQuery>,
+    State(appstate): State<AppState>,
+) -> Result<Response, WebError> {
+    let config = if let Some(token) = query.token {
+        appstate
+            .config_qr_links
+            .lock()
+            .expect("Failed to lock config QR links")
+            .take(&token, device_id, network_id)
+            .ok_or_else(|| WebError::ObjectNotFound("QR code link is invalid or expired".into()))?
+    } else {
+        let Some(session) = session else {
+            return Err(WebError::Authorization("Session is required".into()));
+        };
+        let device = device_for_admin_or_self(&appstate.pool, &session, device_id).await?;
+        let network = find_network(network_id, &appstate.pool).await?;
+        let Some(network_device) =
+            WireguardNetworkDevice::find(&appstate.pool, device_id, network_id).await?
+        else {
+            return Err(WebError::ObjectNotFound(format!(
+                "device {} is not assigned to network {}",
+                device.name, network.name
+            )));
+        };
+        let mut config = device.create_config(&network, &network_device);
+        if query.private_key {
+            let private_key =
+                escrowed_private_key(&appstate, &session, &device, &network, "config_qr").await?;
+            config = config.replace(PRIVATE_KEY_PLACEHOLDER, &private_key);
+        }
+        if config.contains(PRIVATE_KEY_PLACEHOLDER) {
+            return Err(WebError::Conflict(format!(
+                "Private key of device {} is not stored on the server, QR code would contain \
+                an incomplete config. Import the config and set the private key in the app instead.",
+                device.name
+            )));
+        }
+        config
+    };
+    debug!("Rendering config QR code of device {device_id} in network {network_id}");
+    let image = render_config_qr(&config, query.format)?;
+
+    // configs contain keys, they must not be stored by browsers or proxies
+    Ok((
+        [
+            (header::CONTENT_TYPE, query.format.content_type()),
+            (header::CACHE_CONTROL, "no-store"),
+            (header::PRAGMA, "no-cache"),
+        ],
+        image,
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct NetworkQuery {
+    network_id: i64,
+}
+
+/// Fetch device config in a location whose preshared key can be rotated.
+async fn psk_rotation_target(
+    appstate: &AppState,
+    session: &SessionInfo,
+    device_id: i64,
+    network_id: i64,
+) -> Result<(Device, WireguardNetwork, WireguardNetworkDevice), WebError> {
+    let device = device_for_admin_or_self(&appstate.pool, session, device_id).await?;
+    let network = find_network(network_id, &appstate.pool).await?;
+    ensure_not_archived(&network)?;
+    if network.mfa_enabled {
+        return Err(WebError::BadRequest(format!(
+            "preshared keys in MFA-protected network {} are renewed on every login",
+            network.name
+        )));
+    }
+    let Some(network_device) =
+        WireguardNetworkDevice::find(&appstate.pool, device_id, network_id).await?
+    else {
+        return Err(WebError::ObjectNotFound(format!(
+            "device {} is not assigned to network {}",
+            device.name, network.name
+        )));
+    };
+    Ok((device, network, network_device))
+}
+
+/// Peer parameters the client and gateways use for device in given network.
+#[utoipa::path(
+    get,
+    path = "/api/v1/device/{device_id}/effective_config",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID"), ("network_id" = i64, Query, description = "Network ID")),
+    responses(
+        (status = 200, description = "Effective peer config of the device", body = EffectiveDeviceConfig),
+        (status = 404, description = "Device not found", body = ApiError),
+    )
+)]
+pub async fn device_effective_config(
+    session: SessionInfo,
+    Path(device_id): Path<i64>,
+    Query(query): Query<NetworkQuery>,
+    State(appstate): State<AppState>,
+) -> ApiResult {
+    debug!(
+        "User {} fetching effective config of device {device_id} in network {}",
+        session.user.username, query.network_id
+    );
+    let device = device_for_admin_or_self(&appstate.pool, &session, device_id).await?;
+    let network = find_network(query.network_id, &appstate.pool).await?;
+    let mut transaction = appstate.pool.begin().await?;
+    let config = device.effective_config(&mut transaction, &network).await?;
+    transaction.commit().await?;
+
+    Ok(ApiResponse {
+        json: json!(config),
+        status: StatusCode::OK,
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PskRotation {
+    // fingerprint of the staged key, included in the notification email
+    fingerprint: String,
+    // when the staged key replaces the current one if not confirmed earlier
+    deadline: Option<NaiveDateTime>,
+}
+
+/// Stage a new preshared key for device in given network.
+/// It replaces the current key once confirmed or after the grace period.
+#[utoipa::path(
+    post,
+    path = "/api/v1/device/{device_id}/rotate_psk",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID"), ("network_id" = i64, Query, description = "Network ID")),
+    responses(
+        (status = 200, description = "New preshared key staged", body = PskRotation),
+        (status = 400, description = "Preshared key is pinned, or preshared keys in MFA-protected networks are renewed on login", body = ApiError),
+        (status = 404, description = "Device not found or not assigned to the network", body = ApiError),
+        (status = 409, description = "Network is archived", body = ApiError),
+    )
+)]
+pub async fn rotate_device_psk(
+    session: SessionInfo,
+    Path(device_id): Path<i64>,
+    Query(query): Query<NetworkQuery>,
+    State(appstate): State<AppState>,
+) -> ApiResult {
+    debug!(
+        "User {} rotating preshared key of device {device_id} in network {}",
+        session.user.username, query.network_id
+    );
+    let (device, network, mut network_device) =
+        psk_rotation_target(&appstate, &session, device_id, query.network_id).await?;
+    if network_device.preshared_key_pinned {
+        return Err(WebError::BadRequest(format!(
+            "preshared key of device {} in network {} is pinned by an operator",
+            device.name, network.name
+        )));
+    }
+    let fingerprint = stage_psk_rotation(
+        &appstate.pool,
+        &appstate.mail_tx,
+        &device,
+        &network,
+        &mut network_device,
+    )
+    .await?;
+    info!(
+        "User {} rotated preshared key of device {device} in network {network}, new key fingerprint {fingerprint}",
+        session.user.username
+    );
+
+    Ok(ApiResponse {
+        json: json!(PskRotation {
+            fingerprint,
+            deadline: network_device
+                .pending_preshared_key_created
+                .map(grace_period_deadline),
+        }),
+        status: StatusCode::OK,
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PendingPsk {
+    preshared_key: String,
+    fingerprint: String,
+    // when the key replaces the current one if not confirmed earlier
+    deadline: Option<NaiveDateTime>,
+}
+
+/// Preshared key staged for device in given network. Generated configs contain the active
+/// key until this one is confirmed, clients applying keys themselves can switch right after
+/// confirming.
+#[utoipa::path(
+    get,
+    path = "/api/v1/device/{device_id}/pending_psk",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID"), ("network_id" = i64, Query, description = "Network ID")),
+    responses(
+        (status = 200, description = "Pending preshared key", body = PendingPsk),
+        (status = 400, description = "Preshared keys in MFA-protected networks are renewed on login", body = ApiError),
+        (status = 404, description = "Device not found, not assigned to the network, or no key pending", body = ApiError),
+        (status = 409, description = "Network is archived", body = ApiError),
+    )
+)]
+pub async fn pending_device_psk(
+    session: SessionInfo,
+    Path(device_id): Path<i64>,
+    Query(query): Query<NetworkQuery>,
+    State(appstate): State<AppState>,
+) -> ApiResult {
+    debug!(
+        "User {} fetching pending preshared key of device {device_id} in network {}",
+        session.user.username, query.network_id
+    );
+    let (device, network, network_device) =
+        psk_rotation_target(&appstate, &session, device_id, query.network_id).await?;
+    let Some(preshared_key) = network_device.pending_preshared_key else {
+        return Err(WebError::ObjectNotFound(format!(
+            "device {} has no pending preshared key in network {}",
+            device.name, network.name
+        )));
+    };
+    let fingerprint = key_fingerprint(&preshared_key);
+    info!(
+        "User {} fetched pending preshared key {fingerprint} of device {device} in network {network}",
+        session.user.username
+    );
+
+    Ok(ApiResponse {
+        json: json!(PendingPsk {
+            preshared_key,
+            fingerprint,
+            deadline: network_device
+                .pending_preshared_key_created
+                .map(grace_period_deadline),
+        }),
+        status: StatusCode::OK,
+    })
+}
+
+/// Switch device to its pending preshared key right away. Configs generated afterwards
+/// contain the new key.
+#[utoipa::path(
+    post,
+    path = "/api/v1/device/{device_id}/confirm_psk",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID"), ("network_id" = i64, Query, description = "Network ID")),
+    responses(
+        (status = 200, description = "Pending preshared key promoted"),
+        (status = 400, description = "No pending preshared key", body = ApiError),
+        (status = 404, description = "Device not found or not assigned to the network", body = ApiError),
+        (status = 409, description = "Network is archived", body = ApiError),
+    )
+)]
+pub async fn confirm_device_psk(
+    session: SessionInfo,
+    Path(device_id): Path<i64>,
+    Query(query): Query<NetworkQuery>,
+    State(appstate): State<AppState>,
+) -> ApiResult {
+    debug!(
+        "User {} confirming preshared key of device {device_id} in network {}",
+        session.user.username, query.network_id
+    );
+    let (device, network, mut network_device) =
+        psk_rotation_target(&appstate, &session, device_id, query.network_id).await?;
+    if !promote_psk(&appstate.pool, &appstate.wireguard_tx, &mut network_device).await? {
+        return Err(WebError::BadRequest(format!(
+            "device {} has no pending preshared key in network {}",
+            device.name, network.name
+        )));
+    }
+    info!(
+        "User {} confirmed preshared key of device {device} in network {network}",
+        session.user.username
+    );
+
+    Ok(ApiResponse::default())
+}
+
+/// Replace keypair of a device with server managed keys; the new private key is escrowed,
+/// encrypted with the current key-encryption key.
+#[utoipa::path(
+    post,
+    path = "/api/v1/device/{device_id}/rotate_key",
+    tag = "device",
+    params(("device_id" = i64, Path, description = "Device ID")),
+    responses(
+        (status = 200, description = "Keypair replaced; configs have to be issued again", body = Device),
+        (status = 400, description = "Device doesn't have server managed keys, or a location doesn't use them", body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 404, description = "Device not found", body = ApiError),
+    )
+)]
+pub async fn rotate_device_key(
+    _role: AdminRole,
+    session: SessionInfo,
+    Path(device_id): Path<i64>,
+    State(appstate): State<AppState>,
+) -> ApiResult {
+    debug!(
+        "User {} rotating keypair of device {device_id}",
+        session.user.username
+    );
+    let Some(mut device) = Device::find_by_id(&appstate.pool, device_id).await? else {
+        return Err(WebError::ObjectNotFound(format!(
+            "device {device_id} not found"
+        )));
+    };
+    if !key_escrow::is_escrowed(&appstate.pool, device_id).await? {
+        return Err(WebError::BadRequest(format!(
+            "device {} doesn't have server managed keys",
+            device.name
+        )));
+    }
+    ensure_server_managed_keys(&WireguardNetwork::all(&appstate.pool).await?)?;
+    let key_encryption_key = KeyEncryptionKey::from_config()?;
+    let key = WireguardNetwork::genkey();
+
+    let mut transaction = appstate.pool.begin().await?;
+    let previous_pubkey = std::mem::replace(&mut device.wireguard_pubkey, key.public);
+    device.save(&mut *transaction).await?;
+    key_escrow::store(
+        &mut *transaction,
+        &key_encryption_key,
+        device_id,
+        &key.private,
+    )
+    .await?;
+    transaction.commit().await?;
+
+    appstate.send_multiple_wireguard_events(GatewayEvent::peers_modified(
+        DeviceInfo::from_device(&appstate.pool, device.clone()).await?,
+        Some(previous_pubkey),
+    ));
+    warn!(
+        key_escrow = true,
+        "User {} rotated server managed keys of device {device}", session.user.username
+    );
+
+    Ok(ApiResponse {
+        json: json!(device),
+        status: StatusCode::OK,
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct NetworkToken {
+    token: String,
+    grpc_url: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/network/{network_id}/token",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID")),
+    responses(
+        (status = 200, description = "Gateway token for the network", body = NetworkToken),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 404, description = "Network not found", body = ApiError),
+        (status = 409, description = "Network is archived", body = ApiError),
+    )
+)]
 pub async fn create_network_token(
     _role: VpnRole,
     State(appstate): State<AppState>,
@@ -739,6 +2656,7 @@ pub async fn create_network_token(
 ) -> ApiResult {
     debug!("Generating a new token for network ID {network_id}");
     let network = find_network(network_id, &appstate.pool).await?;
+    ensure_not_archived(&network)?;
     let token = Claims::new(
         ClaimsType::Gateway,
         format!("DEFGUARD-NETWORK-{network_id}"),
@@ -755,7 +2673,10 @@ pub async fn create_network_token(
     })?;
     info!("Generated a new token for network ID {network_id}");
     Ok(ApiResponse {
-        json: json!({"token": token, "grpc_url": server_config().grpc_url.to_string()}),
+        json: json!(NetworkToken {
+            token,
+            grpc_url: server_config().grpc_url.to_string(),
+        }),
         status: StatusCode::OK,
     })
 }
@@ -788,14 +2709,26 @@ impl QueryFrom {
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/network/{network_id}/stats/users",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID"), ("from" = Option<String>, Query, description = "RFC 3339 timestamp stats are collected from, an hour ago by default")),
+    responses(
+        (status = 200, description = "Transfer stats of network users and their devices", body = [WireguardUserStatsRow]),
+        (status = 400, description = "Invalid timestamp"),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 404, description = "Network not found", body = ApiError),
+    )
+)]
 pub async fn user_stats(
     _role: VpnRole,
-    State(appstate): State<AppState>,
+    read_pool: ReadPool,
     Path(network_id): Path<i64>,
     Query(query_from): Query<QueryFrom>,
 ) -> ApiResult {
     debug!("Displaying WireGuard user stats for network {network_id}");
-    let Some(network) = WireguardNetwork::find_by_id(&appstate.pool, network_id).await? else {
+    let Some(network) = WireguardNetwork::find_by_id(read_pool.pool(), network_id).await? else {
         return Err(WebError::ObjectNotFound(format!(
             "Requested network ({network_id}) not found",
         )));
@@ -803,7 +2736,7 @@ pub async fn user_stats(
     let from = query_from.parse_timestamp()?.naive_utc();
     let aggregation = get_aggregation(from)?;
     let stats = network
-        .user_stats(&appstate.pool, &from, &aggregation)
+        .user_stats(read_pool.pool(), &from, &aggregation)
         .await?;
     debug!("Displayed WireGuard user stats for network {network_id}");
 
@@ -813,14 +2746,26 @@ pub async fn user_stats(
     })
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/network/{network_id}/stats",
+    tag = "network",
+    params(("network_id" = i64, Path, description = "Network ID"), ("from" = Option<String>, Query, description = "RFC 3339 timestamp stats are collected from, an hour ago by default")),
+    responses(
+        (status = 200, description = "Network transfer stats", body = WireguardNetworkStats),
+        (status = 400, description = "Invalid timestamp"),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 404, description = "Network not found", body = ApiError),
+    )
+)]
 pub async fn network_stats(
     _role: VpnRole,
-    State(appstate): State<AppState>,
+    read_pool: ReadPool,
     Path(network_id): Path<i64>,
     Query(query_from): Query<QueryFrom>,
 ) -> ApiResult {
     debug!("Displaying WireGuard network stats for network {network_id}");
-    let Some(network) = WireguardNetwork::find_by_id(&appstate.pool, network_id).await? else {
+    let Some(network) = WireguardNetwork::find_by_id(read_pool.pool(), network_id).await? else {
         return Err(WebError::ObjectNotFound(format!(
             "Requested network ({network_id}) not found"
         )));
@@ -828,7 +2773,7 @@ pub async fn network_stats(
     let from = query_from.parse_timestamp()?.naive_utc();
     let aggregation = get_aggregation(from)?;
     let stats = network
-        .network_stats(&appstate.pool, &from, &aggregation)
+        .network_stats(read_pool.pool(), &from, &aggregation)
         .await?;
     debug!("Displayed WireGuard network stats for network {network_id}");
 
@@ -837,3 +2782,122 @@ pub async fn network_stats(
         status: StatusCode::OK,
     })
 }
+
+// maximum number of connected clients returned at once
+const CONNECTED_CLIENTS_MAX_LIMIT: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectedClientsSort {
+    // longest connected first
+    #[default]
+    ConnectedSince,
+    // largest session transfer first
+    Transfer,
+}
+
+#[derive(Deserialize)]
+pub struct ConnectedClientsQuery {
+    #[serde(default)]
+    sort: ConnectedClientsSort,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_connected_clients_limit")]
+    limit: usize,
+    username: Option<String>,
+}
+
+fn default_connected_clients_limit() -> usize {
+    100
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ConnectedClients {
+    // number of connected clients matching the filter, regardless of pagination
+    total: usize,
+    clients: Vec<ConnectedClient>,
+}
+
+/// Devices currently connected to a location, with details of their sessions.
+///
+/// Devices are considered connected as long as they aren't disconnected for inactivity,
+/// i.e. their latest handshake is within the location's peer disconnect threshold.
+#[utoipa::path(
+    get,
+    path = "/api/v1/network/{network_id}/connected",
+    tag = "network",
+    params(
+        ("network_id" = i64, Path, description = "Network ID"),
+        ("sort" = Option<String>, Query, description = "`connected_since` (default) for longest connected first, or `transfer` for largest session transfer first"),
+        ("offset" = Option<usize>, Query, description = "Number of clients to skip"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of clients returned, 100 by default"),
+        ("username" = Option<String>, Query, description = "Only list devices of this user"),
+    ),
+    responses(
+        (status = 200, description = "Connected clients", body = ConnectedClients),
+        (status = 400, description = "Invalid limit", body = ApiError),
+        (status = 403, description = "Requires VPN management permissions", body = ApiError),
+        (status = 404, description = "Network not found", body = ApiError),
+    )
+)]
+pub async fn connected_clients(
+    _role: VpnRole,
+    read_pool: ReadPool,
+    Path(network_id): Path<i64>,
+    Query(query): Query<ConnectedClientsQuery>,
+) -> ApiResult {
+    debug!("Listing clients connected to network {network_id}");
+    if !(1..=CONNECTED_CLIENTS_MAX_LIMIT).contains(&query.limit) {
+        return Err(WebError::BadRequest(format!(
+            "limit must be between 1 and {CONNECTED_CLIENTS_MAX_LIMIT}"
+        )));
+    }
+    let network = find_network(network_id, read_pool.pool()).await?;
+    let mut clients = network
+        .connected_clients(
+            read_pool.pool(),
+            Utc::now().naive_utc(),
+            query.username.as_deref(),
+        )
+        .await?;
+    // device ID keeps order stable between pages
+    match query.sort {
+        ConnectedClientsSort::ConnectedSince => clients.sort_by_key(|client| {
+            (
+                client.connected_since.is_none(),
+                client.connected_since,
+                client.device_id,
+            )
+        }),
+        ConnectedClientsSort::Transfer => clients
+            .sort_by_key(|client| (Reverse(client.upload + client.download), client.device_id)),
+    }
+    let total = clients.len();
+    let clients = clients
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit)
+        .collect();
+
+    Ok(ApiResponse {
+        json: json!(ConnectedClients { total, clients }),
+        status: StatusCode::OK,
+    })
+}
+
+/// Batch sizes and flush latency of peer stats received from gateways.
+#[utoipa::path(
+    get,
+    path = "/api/v1/system/stats_ingestion",
+    tag = "network",
+    responses(
+        (status = 200, description = "Peer stats ingestion metrics", body = StatsIngestionSnapshot),
+        (status = 403, description = "Requires admin permissions", body = ApiError),
+    )
+)]
+pub async fn stats_ingestion(_admin: AdminRole) -> ApiResult {
+    Ok(ApiResponse {
+        json: json!(ingestion_metrics()),
+        status: StatusCode::OK,
+    })
+}
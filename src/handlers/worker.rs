@@ -10,11 +10,14 @@ use super::{ApiResponse, ApiResult};
 use crate::{
     appstate::AppState,
     auth::{AdminRole, Claims, ClaimsType, SessionInfo},
-    db::User,
+    db::{models::worker_job::WorkerJob, User},
     error::WebError,
     grpc::WorkerState,
 };
 
+// number of recent jobs shown to admins
+const RECENT_JOBS_LIMIT: i64 = 100;
+
 #[derive(Deserialize, Serialize)]
 pub struct JobData {
     pub username: String,
@@ -55,15 +58,19 @@ pub async fn create_job(
                 ));
             };
 
-            let mut state = worker_state.lock().unwrap();
+            if !worker_state.lock().unwrap().has_worker(&worker) {
+                error!("Failed to create job, worker {worker} not found");
+                return Err(WebError::ObjectNotFound(format!(
+                    "worker_id {worker} not found"
+                )));
+            }
             debug!("Creating job");
-            let id = state.create_job(
-                &job_data.worker,
-                user.first_name.clone(),
-                user.last_name.clone(),
-                user.email,
-                job_data.username,
-            );
+            let job = WorkerJob::create(&appstate.pool, &worker, &user).await?;
+            // worker protocol uses 32-bit job IDs
+            let id = job.id as u32;
+            if !worker_state.lock().unwrap().create_job(job) {
+                warn!("Worker {worker} removed while creating job {id}, job will be dispatched after restart");
+            }
             info!(
                 "User {} created a worker job (ID {id}) for worker {worker} and user {username}",
                 session.user.username,
@@ -113,6 +120,15 @@ pub async fn list_workers(
     })
 }
 
+/// Recent jobs with their status and results, including ones from before restart.
+pub async fn list_worker_jobs(_admin: AdminRole, State(appstate): State<AppState>) -> ApiResult {
+    let jobs = WorkerJob::recent(&appstate.pool, RECENT_JOBS_LIMIT).await?;
+    Ok(ApiResponse {
+        json: json!(jobs),
+        status: StatusCode::OK,
+    })
+}
+
 pub async fn remove_worker(
     _admin: AdminRole,
     session: SessionInfo,
@@ -8,6 +8,17 @@ use serde_json::json;
 use super::{user_for_admin_or_self, ApiResponse, ApiResult};
 use crate::{appstate::AppState, auth::SessionInfo, db::YubiKey, error::WebError};
 
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/{username}/yubikey/{key_id}",
+    tag = "user",
+    params(("username" = String, Path, description = "Username"), ("key_id" = i64, Path, description = "YubiKey ID")),
+    responses(
+        (status = 200, description = "YubiKey deleted"),
+        (status = 403, description = "Not an admin or the YubiKey owner", body = ApiError),
+        (status = 404, description = "YubiKey not found", body = ApiError),
+    )
+)]
 pub async fn delete_yubikey(
     State(appstate): State<AppState>,
     session: SessionInfo,
@@ -42,6 +53,18 @@ pub struct RenameRequest {
     name: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{username}/yubikey/{key_id}/rename",
+    tag = "user",
+    params(("username" = String, Path, description = "Username"), ("key_id" = i64, Path, description = "YubiKey ID")),
+    request_body = RenameRequest,
+    responses(
+        (status = 200, description = "YubiKey renamed", body = YubiKey),
+        (status = 403, description = "Not an admin or the YubiKey owner", body = ApiError),
+        (status = 404, description = "YubiKey not found", body = ApiError),
+    )
+)]
 pub async fn rename_yubikey(
     State(appstate): State<AppState>,
     session: SessionInfo,
@@ -1,15 +1,113 @@
-use std::{borrow::Borrow, sync::Arc};
+use std::{
+    borrow::Borrow,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, StatusCode},
+};
+use ipnetwork::IpNetwork;
 use tokio::sync::mpsc::UnboundedSender;
 use uaparser::{Client, Parser, UserAgentParser};
 
 use crate::{
     db::{models::device_login::DeviceLoginEvent, DbPool, Session, User},
+    error::WebError,
     handlers::mail::send_new_device_login_email,
     mail::Mail,
+    server_config,
     templates::TemplateError,
 };
 
+static X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+// Parse single `x-forwarded-for` entry. Some proxies include the port,
+// so "203.0.113.7:4321" and "[2001:db8::7]:443" are accepted as well.
+fn parse_forwarded_address(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim().trim_matches('"');
+    if let Ok(address) = entry.parse::<IpAddr>() {
+        return Some(address);
+    }
+    if let Ok(address) = entry.parse::<SocketAddr>() {
+        return Some(address.ip());
+    }
+    entry
+        .strip_prefix('[')
+        .and_then(|entry| entry.strip_suffix(']'))
+        .and_then(|entry| entry.parse().ok())
+}
+
+/// Address of the client which sent the request. If the request was passed by a trusted proxy,
+/// the rightmost untrusted address from `x-forwarded-for` is used instead.
+/// Returns `None` if the header can't be parsed up to the first untrusted entry.
+pub(crate) fn resolve_source_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpNetwork],
+) -> Option<IpAddr> {
+    let is_trusted = |address: &IpAddr| {
+        trusted_proxies
+            .iter()
+            .any(|network| network.contains(*address))
+    };
+    let peer = peer?.to_canonical();
+    if !is_trusted(&peer) {
+        return Some(peer);
+    }
+    let Some(forwarded_for) = forwarded_for else {
+        return Some(peer);
+    };
+    let mut source = peer;
+    for entry in forwarded_for.rsplit(',') {
+        // unparsable entry can't be trusted, so neither can anything left of it
+        source = parse_forwarded_address(entry)?.to_canonical();
+        if !is_trusted(&source) {
+            break;
+        }
+    }
+    Some(source)
+}
+
+/// Client address of a web request, taking trusted reverse proxies into account.
+/// `x-forwarded-for` sent by anyone else is ignored and the peer address is used.
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = WebError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(ConnectInfo(peer)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() else {
+            error!("Peer address missing, server has to be run with connect info");
+            return Err(WebError::Http(StatusCode::INTERNAL_SERVER_ERROR));
+        };
+        let peer = peer.ip();
+        // proxies may append separate header lines instead of extending the existing one
+        let forwarded_for = parts
+            .headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .map(|value| value.to_str().ok())
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(","));
+        // non-ASCII value can't be parsed, fall back to the peer
+        let source = forwarded_for.and_then(|forwarded_for| {
+            resolve_source_ip(
+                Some(peer),
+                Some(forwarded_for.as_str()).filter(|value| !value.is_empty()),
+                &server_config().trusted_proxies,
+            )
+        });
+        Ok(Self(source.unwrap_or_else(|| peer.to_canonical())))
+    }
+}
+
 #[must_use]
 pub fn create_user_agent_parser() -> Arc<UserAgentParser> {
     let regexes = include_bytes!("../user_agent_header_regexes.yaml");
@@ -148,3 +246,78 @@ pub async fn check_new_device_login(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_source_ip() {
+        let proxies: Vec<IpNetwork> = vec![
+            "10.0.0.0/24".parse().unwrap(),
+            "fd00:1::/64".parse().unwrap(),
+        ];
+        let gateway: IpAddr = "203.0.113.7".parse().unwrap();
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+
+        // direct connection, forwarded address can't be trusted
+        assert_eq!(
+            resolve_source_ip(Some(gateway), Some("192.168.4.14"), &proxies),
+            Some(gateway)
+        );
+        assert_eq!(resolve_source_ip(Some(gateway), None, &[]), Some(gateway));
+        assert_eq!(
+            resolve_source_ip(None, Some("192.168.4.14"), &proxies),
+            None
+        );
+
+        // through trusted proxies, spoofed leftmost entries are ignored
+        assert_eq!(
+            resolve_source_ip(Some(proxy), Some("192.168.4.14, 203.0.113.7"), &proxies),
+            Some(gateway)
+        );
+        assert_eq!(
+            resolve_source_ip(Some(proxy), Some("203.0.113.7, 10.0.0.3"), &proxies),
+            Some(gateway)
+        );
+        assert_eq!(resolve_source_ip(Some(proxy), None, &proxies), Some(proxy));
+        assert_eq!(
+            resolve_source_ip(
+                Some("fd00:1::2".parse().unwrap()),
+                Some("2001:db8::7"),
+                &proxies
+            ),
+            Some("2001:db8::7".parse().unwrap())
+        );
+        assert_eq!(
+            resolve_source_ip(
+                Some("::ffff:10.0.0.2".parse().unwrap()),
+                Some("203.0.113.7"),
+                &proxies
+            ),
+            Some(gateway)
+        );
+        assert_eq!(
+            resolve_source_ip(Some(proxy), Some("203.0.113.7, unknown"), &proxies),
+            None
+        );
+
+        // entries with ports and bracketed IPv6 addresses
+        assert_eq!(
+            resolve_source_ip(Some(proxy), Some("203.0.113.7:4321, 10.0.0.3"), &proxies),
+            Some(gateway)
+        );
+        assert_eq!(
+            resolve_source_ip(Some(proxy), Some("[2001:db8::7]:443"), &proxies),
+            Some("2001:db8::7".parse().unwrap())
+        );
+        assert_eq!(
+            resolve_source_ip(
+                Some(proxy),
+                Some("\"[2001:db8::7]\", [fd00:1::3]"),
+                &proxies
+            ),
+            Some("2001:db8::7".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! Idempotency keys making retried `POST` requests safe.
+//!
+//! Automation retrying a request whose response was lost would otherwise create objects twice.
+//! A client can send an `Idempotency-Key` header: the first request with a key is handled
+//! normally and its response is stored, later requests of the same user with the same key
+//! get the stored response back instead of being handled again. Reusing a key for a different
+//! request is rejected.
+//!
+//! Only endpoints creating networks, devices and users accept the header; responses containing
+//! secrets must never be stored. [`idempotency`] middleware validates the key and hashes the
+//! request. Handlers opt in by taking an [`IdempotentTx`] which reserves the key when extracted
+//! and stores the response when committed, along with the changes. A retry sent while the first
+//! request is still in progress waits for it, and a request which fails leaves no record, so it
+//! can be retried. Records expire after a configured period and are then removed by the cleanup
+//! job.
+
+use std::ops::{Deref, DerefMut};
+
+use axum::{
+    async_trait,
+    body::{to_bytes, Body},
+    extract::{FromRef, FromRequestParts, Request},
+    http::{request::Parts, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{Duration as ChronoDuration, NaiveDateTime, Utc};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, Error as SqlxError, PgConnection, PgExecutor, Postgres, Transaction};
+
+use crate::{
+    appstate::AppState, db::Session, error::WebError, handlers::ApiResponse, server_config,
+};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+// set on responses replayed from a stored record
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+const MAX_KEY_LENGTH: usize = 255;
+// same as the default limit of request body extractors
+const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+struct IdempotencyRecord {
+    request_hash: Vec<u8>,
+    response_status: Option<i32>,
+    response_body: Option<Vec<u8>>,
+    expires_at: NaiveDateTime,
+}
+
+/// Idempotency key of a request, added to request extensions by [`idempotency`] middleware.
+#[derive(Clone)]
+struct IdempotencyKey {
+    key: String,
+    request_hash: Vec<u8>,
+}
+
+impl IdempotencyKey {
+    /// Reserve the key for the request in the transaction making its changes.
+    ///
+    /// Returns the response to send instead of handling the request if the user already used
+    /// the key. A concurrent request with the same key blocks until the transaction holding
+    /// the reservation finishes.
+    async fn reserve(
+        &self,
+        transaction: &mut PgConnection,
+        user_id: i64,
+    ) -> Result<Option<Response>, SqlxError> {
+        let now = Utc::now().naive_utc();
+        let ttl = ChronoDuration::from_std(*server_config().idempotency_key_ttl)
+            .expect("Failed to parse duration");
+        loop {
+            if reserve(
+                &mut *transaction,
+                user_id,
+                &self.key,
+                &self.request_hash,
+                now + ttl,
+            )
+            .await?
+            {
+                return Ok(None);
+            }
+            let Some(record) = find(&mut *transaction, user_id, &self.key).await? else {
+                // request holding the reservation failed in the meantime
+                continue;
+            };
+            // expired records may be reused before the cleanup job removes them
+            if record.expires_at < now {
+                query!(
+                    "DELETE FROM idempotency_record \
+                    WHERE user_id = $1 AND key = $2 AND expires_at < $3",
+                    user_id,
+                    self.key,
+                    now
+                )
+                .execute(&mut *transaction)
+                .await?;
+                continue;
+            }
+            if record.request_hash != self.request_hash {
+                warn!(
+                    "User {user_id} reused idempotency key {} for a different request",
+                    self.key
+                );
+                return Ok(Some(
+                    ApiResponse::new(
+                        json!({ "msg": "Idempotency key was already used for a different request" }),
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                    )
+                    .into_response(),
+                ));
+            }
+            debug!(
+                "Replaying response for idempotency key {} of user {user_id}",
+                self.key
+            );
+            let status = record
+                .response_status
+                .and_then(|status| u16::try_from(status).ok())
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .unwrap_or(StatusCode::OK);
+            let json = record
+                .response_body
+                .and_then(|body| serde_json::from_slice(&body).ok())
+                .unwrap_or_default();
+            let mut response = ApiResponse::new(json, status).into_response();
+            response
+                .headers_mut()
+                .insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+            return Ok(Some(response));
+        }
+    }
+
+    /// Store the response for the reserved key, before the transaction is committed.
+    async fn store(
+        &self,
+        transaction: &mut PgConnection,
+        user_id: i64,
+        response: &ApiResponse,
+    ) -> Result<(), SqlxError> {
+        let body = response.json.to_string();
+        query!(
+            "UPDATE idempotency_record SET response_status = $3, response_body = $4 \
+            WHERE user_id = $1 AND key = $2",
+            user_id,
+            self.key,
+            i32::from(response.status.as_u16()),
+            body.as_bytes()
+        )
+        .execute(transaction)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Transaction of a handler accepting `Idempotency-Key` header.
+///
+/// Extracting it begins a transaction and reserves the key of the request, if it has one.
+/// Requests with a key the user already used get the stored response, the handler doesn't run.
+/// [`IdempotentTx::commit`] stores the handler's response in the same transaction as its changes.
+pub struct IdempotentTx {
+    transaction: Transaction<'static, Postgres>,
+    // reserved key along with the id of the user who sent it
+    key: Option<(IdempotencyKey, i64)>,
+}
+
+impl IdempotentTx {
+    /// Whether the request has an idempotency key, so its response will be stored.
+    #[must_use]
+    pub fn has_key(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Store the response for the reserved key, if any, and commit the transaction.
+    pub async fn commit(mut self, response: &ApiResponse) -> Result<(), SqlxError> {
+        if let Some((key, user_id)) = &self.key {
+            key.store(&mut self.transaction, *user_id, response).await?;
+        }
+        self.transaction.commit().await
+    }
+}
+
+impl Deref for IdempotentTx {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.transaction
+    }
+}
+
+impl DerefMut for IdempotentTx {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.transaction
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IdempotentTx
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let appstate = AppState::from_ref(state);
+        let mut transaction = appstate
+            .pool
+            .begin()
+            .await
+            .map_err(|err| WebError::from(err).into_response())?;
+        let Some(key) = parts.extensions.get::<IdempotencyKey>().cloned() else {
+            return Ok(Self {
+                transaction,
+                key: None,
+            });
+        };
+        let session = Session::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if let Some(response) = key
+            .reserve(&mut transaction, session.user_id)
+            .await
+            .map_err(|err| WebError::from(err).into_response())?
+        {
+            return Err(response);
+        }
+        Ok(Self {
+            transaction,
+            key: Some((key, session.user_id)),
+        })
+    }
+}
+
+// hash of everything which makes a request different, apart from headers
+fn request_hash(method: &Method, path: &str, body: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_str());
+    hasher.update([0]);
+    hasher.update(path);
+    hasher.update([0]);
+    hasher.update(body);
+    hasher.finalize().to_vec()
+}
+
+/// Reserve a key for a request. Returns `false` if the user already used it.
+async fn reserve<'e, E>(
+    executor: E,
+    user_id: i64,
+    key: &str,
+    request_hash: &[u8],
+    expires_at: NaiveDateTime,
+) -> Result<bool, SqlxError>
+where
+    E: PgExecutor<'e>,
+{
+    let result = query!(
+        "INSERT INTO idempotency_record (user_id, key, request_hash, created_at, expires_at) \
+        VALUES ($1, $2, $3, $4, $5) ON CONFLICT (user_id, key) DO NOTHING",
+        user_id,
+        key,
+        request_hash,
+        Utc::now().naive_utc(),
+        expires_at
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+async fn find<'e, E>(
+    executor: E,
+    user_id: i64,
+    key: &str,
+) -> Result<Option<IdempotencyRecord>, SqlxError>
+where
+    E: PgExecutor<'e>,
+{
+    query_as!(
+        IdempotencyRecord,
+        "SELECT request_hash, response_status, response_body, expires_at \
+        FROM idempotency_record WHERE user_id = $1 AND key = $2",
+        user_id,
+        key
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+/// Delete a batch of expired records. Returns the number of deleted rows.
+pub async fn delete_expired<'e, E>(
+    executor: E,
+    now: NaiveDateTime,
+    limit: i64,
+) -> Result<u64, SqlxError>
+where
+    E: PgExecutor<'e>,
+{
+    let result = query!(
+        "DELETE FROM idempotency_record WHERE (user_id, key) IN \
+        (SELECT user_id, key FROM idempotency_record WHERE expires_at < $1 LIMIT $2)",
+        now,
+        limit
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Middleware handling `Idempotency-Key` header, layered only on routes which support it.
+///
+/// Requests with a valid key get `IdempotencyKey` extension, which [`IdempotentTx`] reserves.
+pub async fn idempotency(request: Request, next: Next) -> Response {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_string(),
+        _ => {
+            return WebError::BadRequest(format!(
+                "Idempotency key must be 1 to {MAX_KEY_LENGTH} visible ASCII characters"
+            ))
+            .into_response()
+        }
+    };
+
+    let (mut parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, MAX_BODY_SIZE).await else {
+        return WebError::Http(StatusCode::PAYLOAD_TOO_LARGE).into_response();
+    };
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or(parts.uri.path(), |path| path.as_str());
+    let key = IdempotencyKey {
+        key,
+        request_hash: request_hash(&parts.method, path, &body),
+    };
+    parts.extensions.insert(key);
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
@@ -0,0 +1,682 @@
+//! Runner for periodic background jobs.
+//!
+//! Each job has a name, a schedule and an async function to run.
+//! Job status (last run, its duration and outcome, next scheduled run) is stored
+//! in `background_job_status` table, so it can be inspected by admins and survives restarts.
+//! Failed or panicked jobs are retried with exponential backoff.
+//!
+//! When multiple instances share a database, only one of them runs scheduled jobs.
+//! The leader holds a Postgres advisory lock on a dedicated connection; other instances
+//! periodically try to take it and take over once the leader is gone.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter, Result as FmtResult},
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDateTime, Utc};
+use cron::Schedule;
+use humantime::format_duration;
+use sqlx::{query, query_as, query_scalar, Error as SqlxError, PgConnection};
+use thiserror::Error;
+use tokio::{
+    task::{spawn, JoinSet},
+    time::sleep,
+};
+
+use crate::db::DbPool;
+
+// Delay before retrying a failed job, doubled after every consecutive failure.
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(3600);
+// Advisory lock held by the instance running scheduled jobs ("defguard" in ASCII)
+const LEADER_LOCK_ID: i64 = 0x6465_6667_7561_7264;
+// How often standby instances try to take over, and the leader checks its lock connection
+const DEFAULT_ELECTION_INTERVAL: Duration = Duration::from_secs(10);
+
+pub type JobResult = Result<(), anyhow::Error>;
+type JobFn = Box<dyn Fn() -> Pin<Box<dyn Future<Output = JobResult> + Send>> + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("Background job {0} not found")]
+    NotFound(String),
+    #[error("Background job {0} is already running")]
+    AlreadyRunning(String),
+    #[error("Background job {0} failed: {1}")]
+    Failed(String, String),
+    #[error(transparent)]
+    DbError(#[from] SqlxError),
+}
+
+/// When a job should be executed.
+#[derive(Clone, Debug)]
+pub enum JobSchedule {
+    /// Run every given interval, counting from the start of previous run.
+    Interval(Duration),
+    /// Run according to a cron expression (with seconds), in UTC.
+    Cron {
+        expression: String,
+        schedule: Box<Schedule>,
+    },
+}
+
+impl JobSchedule {
+    pub fn cron(expression: &str) -> Result<Self, cron::error::Error> {
+        let schedule = Schedule::from_str(expression)?;
+        Ok(Self::Cron {
+            expression: expression.into(),
+            schedule: Box::new(schedule),
+        })
+    }
+
+    /// Time of next run, given the start of last run. `None` if there are no more runs.
+    #[must_use]
+    pub fn next_run(&self, last_run: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Interval(interval) => Some(match last_run {
+                Some(last_run) => {
+                    last_run
+                        + ChronoDuration::from_std(*interval).expect("Failed to parse duration")
+                }
+                None => Utc::now(),
+            }),
+            Self::Cron { schedule, .. } => schedule.after(&Utc::now()).next(),
+        }
+    }
+}
+
+impl Display for JobSchedule {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::Interval(interval) => write!(f, "every {}", format_duration(*interval)),
+            Self::Cron { expression, .. } => write!(f, "cron {expression}"),
+        }
+    }
+}
+
+pub struct Job {
+    name: String,
+    schedule: JobSchedule,
+    run: JobFn,
+}
+
+impl Job {
+    #[must_use]
+    pub fn new<S, F, Fut>(name: S, schedule: JobSchedule, run: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = JobResult> + Send + 'static,
+    {
+        Self {
+            name: name.into(),
+            schedule,
+            run: Box::new(move || Box::pin(run())),
+        }
+    }
+}
+
+struct JobEntry {
+    job: Job,
+    running: AtomicBool,
+    failures: AtomicU32,
+}
+
+// Clears a flag once job execution or leadership is over, also if it was cancelled.
+struct RunningGuard<'a>(&'a AtomicBool);
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Last recorded run of a job.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BackgroundJobStatus {
+    pub last_started_at: Option<NaiveDateTime>,
+    pub last_finished_at: Option<NaiveDateTime>,
+    pub last_duration_ms: Option<i64>,
+    pub success: Option<bool>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: i32,
+    pub next_run_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JobInfo {
+    pub name: String,
+    pub schedule: String,
+    pub running: bool,
+    pub status: Option<BackgroundJobStatus>,
+}
+
+pub struct JobRunner {
+    pool: DbPool,
+    jobs: HashMap<String, Arc<JobEntry>>,
+    retry_backoff: Duration,
+    election_interval: Duration,
+    leader: AtomicBool,
+}
+
+impl JobRunner {
+    #[must_use]
+    pub fn new(pool: DbPool) -> Self {
+        Self {
+            pool,
+            jobs: HashMap::new(),
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            election_interval: DEFAULT_ELECTION_INTERVAL,
+            leader: AtomicBool::new(false),
+        }
+    }
+
+    #[must_use]
+    pub fn with_retry_backoff(mut self, retry_backoff: Duration) -> Self {
+        self.retry_backoff = retry_backoff;
+        self
+    }
+
+    #[must_use]
+    pub fn with_election_interval(mut self, election_interval: Duration) -> Self {
+        self.election_interval = election_interval;
+        self
+    }
+
+    /// Whether this instance currently runs scheduled jobs.
+    #[must_use]
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::SeqCst)
+    }
+
+    pub fn register(&mut self, job: Job) {
+        info!(
+            "Registering background job {} running {}",
+            job.name, job.schedule
+        );
+        self.jobs.insert(
+            job.name.clone(),
+            Arc::new(JobEntry {
+                job,
+                running: AtomicBool::new(false),
+                failures: AtomicU32::new(0),
+            }),
+        );
+    }
+
+    /// List registered jobs with their last recorded status.
+    pub async fn status(&self) -> Result<Vec<JobInfo>, SqlxError> {
+        let mut jobs = Vec::with_capacity(self.jobs.len());
+        for (name, entry) in &self.jobs {
+            let status = query_as!(
+                BackgroundJobStatus,
+                "SELECT last_started_at, last_finished_at, last_duration_ms, success, last_error, \
+                consecutive_failures, next_run_at \
+                FROM background_job_status WHERE name = $1",
+                name
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+            jobs.push(JobInfo {
+                name: name.clone(),
+                schedule: entry.job.schedule.to_string(),
+                running: entry.running.load(Ordering::SeqCst),
+                status,
+            });
+        }
+        jobs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(jobs)
+    }
+
+    /// Run a job immediately and wait for it to finish.
+    pub async fn run_job(&self, name: &str) -> Result<(), JobError> {
+        let entry = self
+            .jobs
+            .get(name)
+            .ok_or_else(|| JobError::NotFound(name.into()))?;
+        self.execute(entry).await
+    }
+
+    /// Trigger a job outside of its schedule without waiting for it to finish.
+    pub fn trigger(self: &Arc<Self>, name: &str) -> Result<(), JobError> {
+        let entry = self
+            .jobs
+            .get(name)
+            .ok_or_else(|| JobError::NotFound(name.into()))?;
+        if entry.running.load(Ordering::SeqCst) {
+            return Err(JobError::AlreadyRunning(name.into()));
+        }
+        let runner = Arc::clone(self);
+        let name = name.to_string();
+        spawn(async move {
+            if let Err(err) = runner.run_job(&name).await {
+                warn!("Manually triggered background job failed: {err}");
+            }
+        });
+        Ok(())
+    }
+
+    /// Run all registered jobs according to their schedules, once this instance
+    /// becomes the leader. Scheduled runs stop if leadership is lost.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let mut connection = self.acquire_leadership().await;
+            self.leader.store(true, Ordering::SeqCst);
+            let _guard = RunningGuard(&self.leader);
+
+            info!("Starting {} background jobs", self.jobs.len());
+            let mut tasks = JoinSet::new();
+            for entry in self.jobs.values() {
+                tasks.spawn(Arc::clone(&self).schedule_job(Arc::clone(entry)));
+            }
+            loop {
+                tokio::select! {
+                    Some(result) = tasks.join_next() => {
+                        if let Err(err) = result {
+                            error!("Background job scheduler task failed: {err}");
+                        }
+                        if tasks.is_empty() {
+                            // jobs can still be triggered manually
+                            debug!("No more scheduled background jobs");
+                        }
+                    }
+                    () = sleep(self.election_interval) => {
+                        // lock is released by the server once its connection is gone
+                        let result = query!("SELECT 1 \"one!\"").fetch_one(&mut connection).await;
+                        if let Err(err) = result {
+                            error!("Lost connection holding background job leadership: {err}");
+                            break;
+                        }
+                    }
+                }
+            }
+            // dropping the set cancels scheduled runs
+            drop(tasks);
+            warn!("No longer running scheduled background jobs, waiting to take over again");
+        }
+    }
+
+    // Wait until this instance acquires the leader lock. The lock is held as long as
+    // the returned connection stays open.
+    async fn acquire_leadership(&self) -> PgConnection {
+        let mut waiting = false;
+        loop {
+            match self.try_lock_leader().await {
+                Ok(Some(connection)) => {
+                    info!("Acquired background job leadership");
+                    return connection;
+                }
+                Ok(None) => {
+                    if !waiting {
+                        info!("Background jobs are run by another instance, standing by");
+                        waiting = true;
+                    }
+                }
+                Err(err) => error!("Failed to acquire background job leadership: {err}"),
+            }
+            sleep(self.election_interval).await;
+        }
+    }
+
+    async fn try_lock_leader(&self) -> Result<Option<PgConnection>, SqlxError> {
+        let mut connection = self.pool.acquire().await?;
+        let locked = query_scalar!(
+            "SELECT pg_try_advisory_lock($1) \"locked!\"",
+            LEADER_LOCK_ID
+        )
+        .fetch_one(&mut *connection)
+        .await?;
+        // detached from the pool, so that the lock is released once the connection is dropped
+        Ok(locked.then(|| connection.detach()))
+    }
+
+    async fn schedule_job(self: Arc<Self>, entry: Arc<JobEntry>) {
+        let name = &entry.job.name;
+        // continue schedule from last recorded run, so that restarts don't trigger all jobs
+        let mut last_run = match self.last_started_at(name).await {
+            Ok(last_run) => last_run,
+            Err(err) => {
+                error!("Failed to fetch last run of background job {name}: {err}");
+                None
+            }
+        };
+        loop {
+            let Some(mut next_run) = entry.job.schedule.next_run(last_run) else {
+                warn!("Background job {name} has no more scheduled runs");
+                break;
+            };
+            let failures = entry.failures.load(Ordering::SeqCst);
+            if failures > 0 {
+                let retry_at = Utc::now()
+                    + ChronoDuration::from_std(self.retry_delay(failures))
+                        .expect("Failed to parse duration");
+                next_run = next_run.min(retry_at);
+            }
+            if let Err(err) = self.record_next_run(name, next_run).await {
+                error!("Failed to record next run of background job {name}: {err}");
+            }
+            debug!("Next run of background job {name} scheduled at {next_run}");
+            sleep((next_run - Utc::now()).to_std().unwrap_or_default()).await;
+
+            last_run = Some(Utc::now());
+            match self.execute(&entry).await {
+                Ok(()) | Err(JobError::Failed(..)) => (),
+                Err(JobError::AlreadyRunning(_)) => {
+                    debug!("Background job {name} is already running, skipping scheduled run");
+                }
+                Err(err) => error!("Failed to run background job {name}: {err}"),
+            }
+        }
+    }
+
+    fn retry_delay(&self, failures: u32) -> Duration {
+        let multiplier = 2_u32.saturating_pow(failures.saturating_sub(1));
+        self.retry_backoff
+            .saturating_mul(multiplier)
+            .min(MAX_RETRY_BACKOFF)
+    }
+
+    async fn execute(&self, entry: &JobEntry) -> Result<(), JobError> {
+        let name = &entry.job.name;
+        if entry
+            .running
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(JobError::AlreadyRunning(name.clone()));
+        }
+        let _guard = RunningGuard(&entry.running);
+
+        info!("Starting background job {name}");
+        let started_at = Utc::now();
+        self.record_start(name, started_at).await?;
+
+        // run in a separate task to catch panics
+        let result = match spawn((entry.job.run)()).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(err) if err.is_panic() => Err("job panicked".to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        let finished_at = Utc::now();
+        let failures = if result.is_ok() {
+            entry.failures.store(0, Ordering::SeqCst);
+            0
+        } else {
+            entry.failures.fetch_add(1, Ordering::SeqCst) + 1
+        };
+        self.record_finish(name, started_at, finished_at, &result, failures)
+            .await?;
+
+        match result {
+            Ok(()) => {
+                info!(
+                    "Background job {name} finished in {}ms",
+                    (finished_at - started_at).num_milliseconds()
+                );
+                Ok(())
+            }
+            Err(err) => {
+                error!("Background job {name} failed ({failures} times in a row): {err}");
+                Err(JobError::Failed(name.clone(), err))
+            }
+        }
+    }
+
+    async fn last_started_at(&self, name: &str) -> Result<Option<DateTime<Utc>>, SqlxError> {
+        let last_started_at = query_scalar!(
+            "SELECT last_started_at FROM background_job_status WHERE name = $1",
+            name
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(last_started_at
+            .flatten()
+            .map(|timestamp| timestamp.and_utc()))
+    }
+
+    async fn record_start(&self, name: &str, started_at: DateTime<Utc>) -> Result<(), SqlxError> {
+        query!(
+            "INSERT INTO background_job_status (name, last_started_at) VALUES ($1, $2) \
+            ON CONFLICT (name) DO UPDATE SET last_started_at = $2",
+            name,
+            started_at.naive_utc()
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn record_finish(
+        &self,
+        name: &str,
+        started_at: DateTime<Utc>,
+        finished_at: DateTime<Utc>,
+        result: &Result<(), String>,
+        failures: u32,
+    ) -> Result<(), SqlxError> {
+        query!(
+            "UPDATE background_job_status SET last_finished_at = $2, last_duration_ms = $3, \
+            success = $4, last_error = $5, consecutive_failures = $6 WHERE name = $1",
+            name,
+            finished_at.naive_utc(),
+            (finished_at - started_at).num_milliseconds(),
+            result.is_ok(),
+            result.as_ref().err().map(String::as_str),
+            i32::try_from(failures).unwrap_or(i32::MAX)
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn record_next_run(&self, name: &str, next_run: DateTime<Utc>) -> Result<(), SqlxError> {
+        query!(
+            "INSERT INTO background_job_status (name, next_run_at) VALUES ($1, $2) \
+            ON CONFLICT (name) DO UPDATE SET next_run_at = $2",
+            name,
+            next_run.naive_utc()
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+    use tokio::{sync::Notify, time::timeout};
+
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn counting_job(name: &str, counter: Arc<AtomicU32>, fail_first: bool) -> Job {
+        Job::new(name, JobSchedule::Interval(HOUR), move || {
+            let counter = Arc::clone(&counter);
+            async move {
+                let run = counter.fetch_add(1, Ordering::SeqCst);
+                if fail_first && run == 0 {
+                    Err(anyhow!("boom"))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    async fn wait_for_runs(counter: &AtomicU32, runs: u32) {
+        timeout(Duration::from_secs(5), async {
+            while counter.load(Ordering::SeqCst) < runs {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn job_status(runner: &JobRunner, name: &str) -> JobInfo {
+        runner
+            .status()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|job| job.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule = JobSchedule::Interval(HOUR);
+        let last_run = Utc::now() - ChronoDuration::minutes(10);
+        assert_eq!(
+            schedule.next_run(Some(last_run)),
+            Some(last_run + ChronoDuration::hours(1))
+        );
+        assert!(schedule.next_run(None).unwrap() <= Utc::now());
+        assert_eq!(schedule.to_string(), "every 1h");
+
+        let schedule = JobSchedule::cron("0 30 2 * * *").unwrap();
+        let next_run = schedule.next_run(None).unwrap();
+        assert!(next_run > Utc::now());
+        assert_eq!(next_run.format("%H:%M:%S").to_string(), "02:30:00");
+        assert!(JobSchedule::cron("not a cron").is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_failed_job_is_retried(pool: DbPool) {
+        let counter = Arc::new(AtomicU32::new(0));
+        let mut runner = JobRunner::new(pool).with_retry_backoff(Duration::from_millis(50));
+        runner.register(counting_job("flaky", Arc::clone(&counter), true));
+        let runner = Arc::new(runner);
+
+        let err = runner.run_job("flaky").await.unwrap_err();
+        assert!(matches!(err, JobError::Failed(..)));
+        let status = job_status(&runner, "flaky").await.status.unwrap();
+        assert_eq!(status.success, Some(false));
+        assert_eq!(status.last_error.as_deref(), Some("boom"));
+        assert_eq!(status.consecutive_failures, 1);
+
+        // retried after backoff instead of waiting for the next regular run
+        spawn(Arc::clone(&runner).run());
+        wait_for_runs(&counter, 2).await;
+        timeout(Duration::from_secs(5), async {
+            while job_status(&runner, "flaky").await.running {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let status = job_status(&runner, "flaky").await.status.unwrap();
+        assert_eq!(status.success, Some(true));
+        assert_eq!(status.last_error, None);
+        assert_eq!(status.consecutive_failures, 0);
+        assert!(status.next_run_at.is_some());
+    }
+
+    #[sqlx::test]
+    async fn test_manual_trigger(pool: DbPool) {
+        let counter = Arc::new(AtomicU32::new(0));
+        let mut runner = JobRunner::new(pool);
+        runner.register(counting_job("hourly", Arc::clone(&counter), false));
+        let runner = Arc::new(runner);
+
+        // scheduler runs the job once, next run is an hour away
+        spawn(Arc::clone(&runner).run());
+        wait_for_runs(&counter, 1).await;
+
+        runner.trigger("hourly").unwrap();
+        wait_for_runs(&counter, 2).await;
+
+        assert!(matches!(
+            runner.trigger("unknown"),
+            Err(JobError::NotFound(_))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn test_no_overlapping_runs(pool: DbPool) {
+        let counter = Arc::new(AtomicU32::new(0));
+        let release = Arc::new(Notify::new());
+        let mut runner = JobRunner::new(pool);
+        let job_counter = Arc::clone(&counter);
+        let job_release = Arc::clone(&release);
+        runner.register(Job::new("slow", JobSchedule::Interval(HOUR), move || {
+            let counter = Arc::clone(&job_counter);
+            let release = Arc::clone(&job_release);
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                release.notified().await;
+                Ok(())
+            }
+        }));
+        let runner = Arc::new(runner);
+
+        let first_run = spawn({
+            let runner = Arc::clone(&runner);
+            async move { runner.run_job("slow").await }
+        });
+        wait_for_runs(&counter, 1).await;
+        assert!(job_status(&runner, "slow").await.running);
+
+        assert!(matches!(
+            runner.run_job("slow").await,
+            Err(JobError::AlreadyRunning(_))
+        ));
+        assert!(matches!(
+            runner.trigger("slow"),
+            Err(JobError::AlreadyRunning(_))
+        ));
+
+        release.notify_one();
+        first_run.await.unwrap().unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert!(!job_status(&runner, "slow").await.running);
+    }
+
+    #[sqlx::test]
+    async fn test_single_leader(pool: DbPool) {
+        let interval = Duration::from_millis(50);
+        let first_counter = Arc::new(AtomicU32::new(0));
+        let second_counter = Arc::new(AtomicU32::new(0));
+        let mut first = JobRunner::new(pool.clone()).with_election_interval(interval);
+        first.register(counting_job("hourly", Arc::clone(&first_counter), false));
+        let first = Arc::new(first);
+        let mut second = JobRunner::new(pool).with_election_interval(interval);
+        second.register(counting_job("hourly", Arc::clone(&second_counter), false));
+        let second = Arc::new(second);
+
+        let first_task = spawn(Arc::clone(&first).run());
+        wait_for_runs(&first_counter, 1).await;
+        assert!(first.is_leader());
+
+        // second instance stands by
+        spawn(Arc::clone(&second).run());
+        sleep(interval * 5).await;
+        assert!(!second.is_leader());
+        assert_eq!(second_counter.load(Ordering::SeqCst), 0);
+
+        // and takes over once the leader is gone
+        first_task.abort();
+        timeout(Duration::from_secs(5), async {
+            while !second.is_leader() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!first.is_leader());
+        // schedule continues from the last recorded run
+        assert_eq!(second_counter.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,251 @@
+//! Escrow of device private keys generated by the server.
+//!
+//! Locations with server managed keys let admins create devices without a keypair: the server
+//! generates one and keeps the private key, so that configs can be issued again without
+//! touching the device. This is opt-in per location, as whoever can read the database and
+//! configuration can impersonate such devices.
+//!
+//! Private keys are encrypted with AES-256-GCM, using a key derived from the key-encryption key
+//! set in configuration, and bound to the device they belong to. Ciphertext is stored as base64
+//! of the nonce followed by the encrypted key. Every read of an escrowed key is recorded in
+//! `device_key_escrow_access`.
+//!
+//! The same key encrypts the SSH CA key generated by the server, see `crate::ssh_ca`.
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_scalar, Error as SqlxError, PgExecutor};
+use thiserror::Error;
+
+use crate::{db::DbPool, server_config};
+
+const NONCE_LENGTH: usize = 12;
+
+#[derive(Debug, Error)]
+pub enum KeyEscrowError {
+    #[error(transparent)]
+    DbError(#[from] SqlxError),
+    #[error("Key-encryption key is not configured")]
+    NotConfigured,
+    #[error("Failed to encrypt private key")]
+    Encryption,
+    #[error("Failed to decrypt private key, key-encryption key may have changed")]
+    Decryption,
+}
+
+/// Key used to encrypt private keys held by the server.
+pub struct KeyEncryptionKey(Aes256Gcm);
+
+impl KeyEncryptionKey {
+    #[must_use]
+    pub fn new(secret: &str) -> Self {
+        let key = Sha256::digest(secret.as_bytes());
+        Self(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    }
+
+    /// Key set in configuration.
+    pub fn from_config() -> Result<Self, KeyEscrowError> {
+        server_config()
+            .key_encryption_key
+            .as_ref()
+            .map(|secret| Self::new(secret.expose_secret()))
+            .ok_or(KeyEscrowError::NotConfigured)
+    }
+
+    /// Encrypt private key of a device.
+    pub fn encrypt(&self, device_id: i64, private_key: &str) -> Result<String, KeyEscrowError> {
+        self.encrypt_for(&device_id.to_be_bytes(), private_key)
+    }
+
+    /// Decrypt private key of a device; fails for keys of other devices.
+    pub fn decrypt(&self, device_id: i64, encrypted: &str) -> Result<String, KeyEscrowError> {
+        self.decrypt_for(&device_id.to_be_bytes(), encrypted)
+    }
+
+    /// Encrypt a secret bound to `context`, so it can't be decrypted as another one.
+    pub fn encrypt_for(&self, context: &[u8], secret: &str) -> Result<String, KeyEscrowError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: secret.as_bytes(),
+                    aad: context,
+                },
+            )
+            .map_err(|_| KeyEscrowError::Encryption)?;
+        let mut data = nonce.to_vec();
+        data.extend(ciphertext);
+        Ok(BASE64_STANDARD.encode(data))
+    }
+
+    /// Decrypt a secret encrypted for `context`.
+    pub fn decrypt_for(&self, context: &[u8], encrypted: &str) -> Result<String, KeyEscrowError> {
+        let data = BASE64_STANDARD
+            .decode(encrypted)
+            .map_err(|_| KeyEscrowError::Decryption)?;
+        if data.len() < NONCE_LENGTH {
+            return Err(KeyEscrowError::Decryption);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        let secret = self
+            .0
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: context,
+                },
+            )
+            .map_err(|_| KeyEscrowError::Decryption)?;
+        String::from_utf8(secret).map_err(|_| KeyEscrowError::Decryption)
+    }
+}
+
+/// Store private key of a device, replacing the previous one.
+pub async fn store<'e, E>(
+    executor: E,
+    key: &KeyEncryptionKey,
+    device_id: i64,
+    private_key: &str,
+) -> Result<(), KeyEscrowError>
+where
+    E: PgExecutor<'e>,
+{
+    let encrypted = key.encrypt(device_id, private_key)?;
+    query!(
+        "INSERT INTO device_key_escrow (device_id, encrypted_private_key) VALUES ($1, $2) \
+        ON CONFLICT (device_id) DO UPDATE \
+        SET encrypted_private_key = EXCLUDED.encrypted_private_key, rotated_at = now()",
+        device_id,
+        encrypted
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Whether the server holds private key of a device.
+pub async fn is_escrowed<'e, E>(executor: E, device_id: i64) -> Result<bool, SqlxError>
+where
+    E: PgExecutor<'e>,
+{
+    query_scalar!(
+        "SELECT EXISTS (SELECT 1 FROM device_key_escrow WHERE device_id = $1) \"exists!\"",
+        device_id
+    )
+    .fetch_one(executor)
+    .await
+}
+
+/// Forget private key of a device, e.g. once it uses a key of its own.
+/// Returns whether there was one.
+pub async fn remove<'e, E>(executor: E, device_id: i64) -> Result<bool, SqlxError>
+where
+    E: PgExecutor<'e>,
+{
+    let result = query!(
+        "DELETE FROM device_key_escrow WHERE device_id = $1",
+        device_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Record that a user was given private key of a device.
+pub async fn record_access<'e, E>(
+    executor: E,
+    device_id: i64,
+    network_id: Option<i64>,
+    user_id: Option<i64>,
+    purpose: &str,
+) -> Result<(), SqlxError>
+where
+    E: PgExecutor<'e>,
+{
+    query!(
+        "INSERT INTO device_key_escrow_access (device_id, network_id, user_id, purpose) \
+        VALUES ($1, $2, $3, $4)",
+        device_id,
+        network_id,
+        user_id,
+        purpose
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Read escrowed private key of a device on behalf of a user, recording the access.
+/// Returns `None` if the server doesn't hold the key.
+pub async fn read_private_key(
+    pool: &DbPool,
+    device_id: i64,
+    network_id: Option<i64>,
+    user_id: Option<i64>,
+    purpose: &str,
+) -> Result<Option<String>, KeyEscrowError> {
+    let Some(encrypted) = query_scalar!(
+        "SELECT encrypted_private_key FROM device_key_escrow WHERE device_id = $1",
+        device_id
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    // recorded even if decryption fails
+    record_access(pool, device_id, network_id, user_id, purpose).await?;
+    KeyEncryptionKey::from_config()?
+        .decrypt(device_id, &encrypted)
+        .map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = KeyEncryptionKey::new("correct horse battery staple, twice over");
+        let private_key = "GAA2X3DW0WakGVx+DsGjhDpTgg50s1MlmrLf24Psrlg=";
+        let encrypted = key.encrypt(1, private_key).unwrap();
+        assert!(!encrypted.contains(private_key));
+        assert_eq!(key.decrypt(1, &encrypted).unwrap(), private_key);
+        // nonces are random
+        assert_ne!(key.encrypt(1, private_key).unwrap(), encrypted);
+
+        // bound to the device and the key-encryption key
+        assert!(matches!(
+            key.decrypt(2, &encrypted),
+            Err(KeyEscrowError::Decryption)
+        ));
+        let other_key = KeyEncryptionKey::new("another key-encryption key, also long");
+        assert!(matches!(
+            other_key.decrypt(1, &encrypted),
+            Err(KeyEscrowError::Decryption)
+        ));
+        assert!(matches!(
+            key.decrypt(1, "c2hvcnQ="),
+            Err(KeyEscrowError::Decryption)
+        ));
+
+        // other secrets are bound to their context
+        let encrypted = key.encrypt_for(b"ssh-ca-key", private_key).unwrap();
+        assert_eq!(
+            key.decrypt_for(b"ssh-ca-key", &encrypted).unwrap(),
+            private_key
+        );
+        assert!(matches!(
+            key.decrypt(1, &encrypted),
+            Err(KeyEscrowError::Decryption)
+        ));
+    }
+}
@@ -203,9 +203,8 @@ impl LDAPConnection {
         }
     }
 
-    /// Retrieves user with given username from LDAP.
-    /// TODO: Password must agree with the password stored in LDAP.
-    pub async fn get_user(&mut self, username: &str, password: &str) -> Result<User, LdapError> {
+    /// Retrieves LDAP entry of user with given username.
+    pub async fn get_user_entry(&mut self, username: &str) -> Result<SearchEntry, LdapError> {
         debug!("Performing LDAP user search: {username}");
         let mut entries = self
             .search_users(&format!(
@@ -215,7 +214,7 @@ impl LDAPConnection {
             .await?;
         if let Some(entry) = entries.pop() {
             info!("Performed LDAP user search: {username}");
-            Ok(User::from_searchentry(&entry, username, password))
+            Ok(entry)
         } else {
             Err(LdapError::ObjectNotFound(format!(
                 "User {username} not found",
@@ -223,6 +222,13 @@ impl LDAPConnection {
         }
     }
 
+    /// Retrieves user with given username from LDAP.
+    /// TODO: Password must agree with the password stored in LDAP.
+    pub async fn get_user(&mut self, username: &str, password: &str) -> Result<User, LdapError> {
+        let entry = self.get_user_entry(username).await?;
+        Ok(User::from_searchentry(&entry, username, password))
+    }
+
     /// Adds user to LDAP.
     pub async fn add_user(&mut self, user: &User, password: &str) -> Result<(), LdapError> {
         debug!("Adding LDAP user {}", user.username);
@@ -3,7 +3,10 @@ use std::collections::HashSet;
 use ldap3::{Mod, SearchEntry};
 
 use super::LDAPConfig;
-use crate::{db::User, hashset};
+use crate::{
+    db::{models::user_field::UserFieldDefinition, User},
+    hashset,
+};
 
 impl User {
     #[must_use]
@@ -90,6 +93,22 @@ impl User {
 //     }
 // }
 
+/// Values of LDAP attributes mapped to custom user fields.
+#[must_use]
+pub fn user_field_values<'a>(
+    entry: &'a SearchEntry,
+    definitions: &'a [UserFieldDefinition],
+) -> Vec<(&'a UserFieldDefinition, &'a str)> {
+    definitions
+        .iter()
+        .filter_map(|definition| {
+            let attr = definition.ldap_attr.as_deref()?;
+            let value = entry.attrs.get(attr)?.first()?;
+            Some((definition, value.as_str()))
+        })
+        .collect()
+}
+
 fn get_value_or_default(entry: &SearchEntry, key: &str) -> String {
     match entry.attrs.get(key) {
         Some(values) if !values.is_empty() => values[0].clone(),
@@ -1,7 +1,11 @@
+use ldap3::SearchEntry;
 use sqlx::PgExecutor;
 
-use super::{error::LdapError, LDAPConnection};
-use crate::db::{DbPool, Group, User};
+use super::{error::LdapError, model::user_field_values, LDAPConnection};
+use crate::db::{
+    models::user_field::{UserFieldDefinition, UserFieldValue},
+    DbPool, Group, User,
+};
 
 pub async fn user_from_ldap(
     pool: &DbPool,
@@ -9,11 +13,37 @@ pub async fn user_from_ldap(
     password: &str,
 ) -> Result<User, LdapError> {
     let mut ldap_connection = LDAPConnection::create(pool).await?;
-    let mut user = ldap_connection.get_user(username, password).await?;
+    let entry = ldap_connection.get_user_entry(username).await?;
+    let mut user = User::from_searchentry(&entry, username, password);
     let _result = user.save(pool).await; // FIXME: do not ignore errors
+    if let Some(user_id) = user.id {
+        import_user_fields(pool, user_id, &entry).await;
+    }
     Ok(user)
 }
 
+/// Copy LDAP attributes mapped to custom user fields. Values not matching field type are skipped.
+async fn import_user_fields(pool: &DbPool, user_id: i64, entry: &SearchEntry) {
+    let definitions = match UserFieldDefinition::ldap_mapped(pool).await {
+        Ok(definitions) => definitions,
+        Err(err) => {
+            error!("Failed to load LDAP mapped user fields: {err}");
+            return;
+        }
+    };
+    for (definition, value) in user_field_values(entry, &definitions) {
+        if let Err(err) = definition.validate_value(value) {
+            warn!("Skipping LDAP value of user field: {err}");
+            continue;
+        }
+        if let Some(definition_id) = definition.id {
+            if let Err(err) = UserFieldValue::set(pool, user_id, definition_id, value).await {
+                error!("Failed to save user field {}: {err}", definition.name);
+            }
+        }
+    }
+}
+
 pub async fn ldap_add_user<'e, E>(executor: E, user: &User, password: &str) -> Result<(), LdapError>
 where
     E: PgExecutor<'e>,
@@ -7,6 +7,7 @@ use std::{
 use anyhow::anyhow;
 use axum::{
     http::{Request, StatusCode},
+    middleware,
     routing::{delete, get, patch, post, put},
     serve, Extension, Router,
 };
@@ -39,58 +40,92 @@ use self::{
     auth::{Claims, ClaimsType},
     config::{DefGuardConfig, InitVpnLocationArgs},
     db::{
-        init_db,
+        init_db_from_config,
         models::wireguard::{DEFAULT_DISCONNECT_THRESHOLD, DEFAULT_KEEPALIVE_INTERVAL},
-        AppEvent, DbPool, Device, GatewayEvent, User, WireguardNetwork,
+        read_pool::replica_lag_header,
+        AppEvent, DbPool, Device, GatewayEvent, ReadPool, User, WireguardNetwork,
     },
     handlers::{
         auth::{
-            authenticate, email_mfa_code, email_mfa_disable, email_mfa_enable, email_mfa_init,
-            logout, mfa_disable, mfa_enable, recovery_code, request_email_mfa_code, totp_code,
+            auth_challenge, authenticate, email_mfa_code, email_mfa_disable, email_mfa_enable,
+            email_mfa_init, end_impersonation, logout, mfa_disable, mfa_enable, recovery_code,
+            recovery_codes_status, regenerate_recovery_codes, request_email_mfa_code, totp_code,
             totp_disable, totp_enable, totp_secret, web3auth_end, web3auth_start, webauthn_end,
             webauthn_finish, webauthn_init, webauthn_start,
         },
+        diagnostics::{authz_denials, cleanup_stats, consistency, probe},
+        enrollment::{
+            activate_web_enrollment, start_web_enrollment, web_enrollment_device,
+            web_enrollment_totp_enable, web_enrollment_totp_secret,
+        },
+        feature_flags::{list_feature_flags, set_feature_flag},
         forward_auth::forward_auth,
         group::{
             add_group_member, create_group, delete_group, get_group, list_groups, modify_group,
-            remove_group_member,
+            patch_group_members, remove_group_member, set_group_members, suspend_group,
         },
+        guest_access::{create_guest_access, delete_guest_access, list_guest_access},
+        jobs::{list_jobs, run_job},
+        live_events::connect_live_events,
         mail::{send_support_data, test_mail},
+        openapi::{openapi_spec, swagger_ui},
         settings::{
-            get_settings, get_settings_essentials, patch_settings, set_default_branding,
-            test_ldap_settings, update_settings,
+            get_notification_recipients, get_settings, get_settings_essentials, patch_settings,
+            set_default_branding, test_ldap_settings, test_notifications,
+            update_notification_recipients, update_settings,
         },
         ssh_authorized_keys::get_authorized_keys,
-        support::{configuration, logs},
+        ssh_ca::{
+            list_ssh_certificates, revoke_ssh_certificate, sign_ssh_key, ssh_ca_public_key, ssh_krl,
+        },
+        support::{config_schema, configuration, logs},
         user::{
             add_user, change_password, change_self_password, delete_authorized_app,
-            delete_security_key, delete_user, delete_wallet, get_user, list_users, me, modify_user,
-            reset_password, set_wallet, start_enrollment, start_remote_desktop_configuration,
-            update_wallet, username_available, wallet_challenge,
+            delete_security_key, delete_user, delete_wallet, disconnect_user, get_user,
+            impersonate_user, list_users, me, merge_user, modify_user, reset_password, set_wallet,
+            start_enrollment, start_remote_desktop_configuration, update_wallet, user_timeline,
+            username_available, wallet_challenge,
+        },
+        user_fields::{
+            add_user_field, delete_user_field, get_user_field_values, list_user_fields,
+            modify_user_field, set_user_field_values,
         },
         webhooks::{
             add_webhook, change_enabled, change_webhook, delete_webhook, get_webhook, list_webhooks,
         },
     },
+    idempotency::idempotency,
+    jobs::JobRunner,
     mail::Mail,
+    proxy_protocol::ProxyProtocol,
 };
 
+#[cfg(feature = "wireguard")]
+use self::handlers::diagnostics::diagnostics;
+#[cfg(feature = "wireguard")]
+use self::handlers::shared_config::{
+    download_shared_config, list_shared_configs, revoke_shared_config, share_device_config,
+};
 #[cfg(feature = "wireguard")]
 use self::handlers::wireguard::{
-    add_device, add_user_devices, create_network, create_network_token, delete_device,
-    delete_network, download_config, gateway_status, get_device, import_network, list_devices,
-    list_networks, list_user_devices, modify_device, modify_network, network_details,
-    network_stats, remove_gateway, user_stats,
+    add_device, add_user_devices, archive_network, confirm_device_psk, connected_clients,
+    create_network, create_network_token, delete_device, delete_network, device_config_qr,
+    device_effective_config, download_config, gateway_status, get_device, import_network,
+    list_archived_networks, list_devices, list_invalid_pubkeys, list_networks, list_user_devices,
+    modify_device, modify_network, network_details, network_overlaps, network_stats,
+    pending_device_psk, pin_device_psk, remove_gateway, resync_gateways, rotate_device_key,
+    rotate_device_psk, set_device_bandwidth_limits, stats_ingestion, transfer_device,
+    unarchive_network, unblock_device, unpin_device_psk, user_stats,
 };
 #[cfg(feature = "worker")]
 use self::handlers::worker::{
-    create_job, create_worker_token, job_status, list_workers, remove_worker,
+    create_job, create_worker_token, job_status, list_worker_jobs, list_workers, remove_worker,
 };
 #[cfg(feature = "openid")]
 use self::handlers::{
     openid_clients::{
         add_openid_client, change_openid_client, change_openid_client_state, delete_openid_client,
-        get_openid_client, list_openid_clients,
+        get_openid_client, list_openid_clients, openid_client_logout_status,
     },
     openid_flow::{
         authorization, discovery_keys, openid_configuration, secure_authorization, token, userinfo,
@@ -107,21 +142,52 @@ use self::{
 pub mod appstate;
 pub mod assets;
 pub mod auth;
+pub mod break_glass;
+pub mod cli;
 pub mod config;
+pub mod config_schema;
+pub mod consistency;
 pub mod db;
+pub mod diagnostics;
+pub mod dns;
 mod error;
+pub mod expired_cleanup;
+pub mod feature_flags;
+#[cfg(feature = "wireguard")]
+pub mod gateway_event_journal;
+#[cfg(feature = "wireguard")]
+pub mod gateway_event_relay;
+pub mod geoip;
 pub mod grpc;
+pub mod guest_access;
 pub mod handlers;
 pub mod headers;
 pub mod hex;
+pub mod idempotency;
+pub mod jobs;
+pub mod key_escrow;
 pub mod ldap;
+pub mod live_events;
 pub mod mail;
+pub mod mfa_policy;
+pub mod new_country_alert;
+pub mod notifications;
+#[cfg(feature = "openid")]
+pub mod openid_backchannel_logout;
+pub mod password_policy;
+pub mod proxy_protocol;
 pub(crate) mod random;
 pub mod secret;
+pub mod ssh_ca;
 pub mod support;
 pub mod templates;
+pub mod tls;
+pub mod user_suspension;
+pub mod user_timeline;
 pub mod wg_config;
+pub mod wireguard_config_qr;
 pub mod wireguard_peer_disconnect;
+pub mod wireguard_psk_rotation;
 pub mod wireguard_stats_purge;
 
 #[macro_use]
@@ -159,8 +225,10 @@ pub fn build_webapp(
     worker_state: Arc<Mutex<WorkerState>>,
     gateway_state: Arc<Mutex<GatewayMap>>,
     pool: DbPool,
+    read_pool: ReadPool,
     user_agent_parser: Arc<UserAgentParser>,
     failed_logins: Arc<Mutex<FailedLoginMap>>,
+    job_runner: Arc<JobRunner>,
 ) -> Router {
     let webapp: Router<AppState> = Router::new()
         .route("/", get(index))
@@ -176,8 +244,17 @@ pub fn build_webapp(
             .route("/health", get(health_check))
             .route("/info", get(get_app_info))
             .route("/ssh_authorized_keys", get(get_authorized_keys))
+            // SSH certificate authority
+            .route("/ssh/ca", get(ssh_ca_public_key))
+            .route("/ssh/krl", get(ssh_krl))
+            .route("/ssh/certificate", get(list_ssh_certificates))
+            .route(
+                "/ssh/certificate/:serial/revoke",
+                post(revoke_ssh_certificate),
+            )
             // /auth
             .route("/auth", post(authenticate))
+            .route("/auth/challenge", get(auth_challenge))
             .route("/auth/logout", post(logout))
             .route("/auth/mfa", put(mfa_enable))
             .route("/auth/mfa", delete(mfa_disable))
@@ -197,10 +274,20 @@ pub fn build_webapp(
             .route("/auth/web3/start", post(web3auth_start))
             .route("/auth/web3", post(web3auth_end))
             .route("/auth/recovery", post(recovery_code))
+            .route("/auth/impersonate/end", post(end_impersonation))
+            // web enrollment fallback
+            .route("/enrollment/start", post(start_web_enrollment))
+            .route("/enrollment/activate", post(activate_web_enrollment))
+            .route("/enrollment/totp/init", post(web_enrollment_totp_secret))
+            .route("/enrollment/totp", post(web_enrollment_totp_enable))
+            .route("/enrollment/device", post(web_enrollment_device))
             // /user
             .route("/user", get(list_users))
             .route("/user/:username", get(get_user))
-            .route("/user", post(add_user))
+            .route(
+                "/user",
+                post(add_user).layer(middleware::from_fn(idempotency)),
+            )
             .route("/user/:username/start_enrollment", post(start_enrollment))
             .route(
                 "/user/:username/start_desktop",
@@ -214,6 +301,10 @@ pub fn build_webapp(
             .route("/user/:username/password", put(change_password))
             .route("/user/:username/reset_password", post(reset_password))
             .route("/user/:username/challenge", get(wallet_challenge))
+            .route("/user/:username/impersonate", post(impersonate_user))
+            .route("/user/:username/merge", post(merge_user))
+            .route("/user/:username/disconnect", post(disconnect_user))
+            .route("/user/:username/timeline", get(user_timeline))
             // auth keys
             .route("/user/:username/auth_key", get(fetch_authentication_keys))
             .route("/user/:username/auth_key", post(add_authentication_key))
@@ -239,10 +330,23 @@ pub fn build_webapp(
                 delete(delete_security_key),
             )
             .route("/me", get(me))
+            .route("/me/mfa/recovery", get(recovery_codes_status))
+            .route(
+                "/me/mfa/recovery/regenerate",
+                post(regenerate_recovery_codes),
+            )
+            .route("/me/ssh/sign", post(sign_ssh_key))
             .route(
                 "/user/:username/oauth_app/:oauth2client_id",
                 delete(delete_authorized_app),
             )
+            .route("/user/:username/fields", get(get_user_field_values))
+            .route("/user/:username/fields", put(set_user_field_values))
+            // custom user fields
+            .route("/user_field", get(list_user_fields))
+            .route("/user_field", post(add_user_field))
+            .route("/user_field/:id", put(modify_user_field))
+            .route("/user_field/:id", delete(delete_user_field))
             // forward_auth
             .route("/forward_auth", get(forward_auth))
             // group
@@ -253,8 +357,15 @@ pub fn build_webapp(
             .route("/group/:name", delete(delete_group))
             .route("/group/:name", post(add_group_member))
             .route("/group/:name/user/:username", delete(remove_group_member))
+            .route("/group/:name/members", put(set_group_members))
+            .route("/group/:name/members", patch(patch_group_members))
+            .route("/group/:name/suspend", post(suspend_group))
             .route("/group-info", get(list_groups_info))
             .route("/groups-assign", post(bulk_assign_to_groups))
+            // guest access
+            .route("/guest_access", get(list_guest_access))
+            .route("/guest_access", post(create_guest_access))
+            .route("/guest_access/:id", delete(delete_guest_access))
             // mail
             .route("/mail/test", post(test_mail))
             .route("/mail/support", post(send_support_data))
@@ -263,11 +374,29 @@ pub fn build_webapp(
             .route("/settings", put(update_settings))
             .route("/settings", patch(patch_settings))
             .route("/settings/:id", put(set_default_branding))
+            .route("/settings/notifications", get(get_notification_recipients))
+            .route(
+                "/settings/notifications",
+                put(update_notification_recipients),
+            )
+            .route("/settings/notifications/test", post(test_notifications))
             // settings for frontend
             .route("/settings_essentials", get(get_settings_essentials))
             // support
             .route("/support/configuration", get(configuration))
             .route("/support/logs", get(logs))
+            // background jobs
+            .route("/system/jobs", get(list_jobs))
+            .route("/system/jobs/:name/run", post(run_job))
+            .route("/system/probe", get(probe))
+            .route("/system/consistency", get(consistency))
+            .route("/system/authz_denials", get(authz_denials))
+            .route("/system/cleanup", get(cleanup_stats))
+            .route("/system/features", get(list_feature_flags))
+            .route("/system/features", put(set_feature_flag))
+            .route("/system/config_schema", get(config_schema))
+            // live events for the admin dashboard
+            .route("/ws/events", get(connect_live_events))
             // webhooks
             .route("/webhook", post(add_webhook))
             .route("/webhook", get(list_webhooks))
@@ -276,7 +405,11 @@ pub fn build_webapp(
             .route("/webhook/:id", delete(delete_webhook))
             .route("/webhook/:id", post(change_enabled))
             // ldap
-            .route("/ldap/test", get(test_ldap_settings)),
+            .route("/ldap/test", get(test_ldap_settings))
+            // REST API description
+            .route("/openapi.json", get(openapi_spec))
+            .route("/swagger-ui/", get(swagger_ui))
+            .route("/swagger-ui/*tail", get(swagger_ui)),
     );
 
     #[cfg(feature = "openid")]
@@ -291,6 +424,10 @@ pub fn build_webapp(
                 .route("/:client_id", put(change_openid_client))
                 .route("/:client_id", post(change_openid_client_state))
                 .route("/:client_id", delete(delete_openid_client))
+                .route(
+                    "/:client_id/logout_status",
+                    get(openid_client_logout_status),
+                )
                 .route("/authorize", get(authorization))
                 .route("/authorize", post(secure_authorization))
                 .route("/token", post(token))
@@ -305,31 +442,85 @@ pub fn build_webapp(
     let webapp = webapp.nest(
         "/api/v1",
         Router::new()
-            .route("/device/:device_id", post(add_device))
+            .route(
+                "/device/:device_id",
+                post(add_device).layer(middleware::from_fn(idempotency)),
+            )
             .route("/device/:device_id", put(modify_device))
             .route("/device/:device_id", get(get_device))
             .route("/device/:device_id", delete(delete_device))
+            .route("/device/:device_id/transfer", post(transfer_device))
+            .route("/device/:device_id/rotate_psk", post(rotate_device_psk))
+            .route("/device/:device_id/rotate_key", post(rotate_device_key))
+            .route("/device/:device_id/confirm_psk", post(confirm_device_psk))
+            .route("/device/:device_id/pending_psk", get(pending_device_psk))
+            .route("/device/:device_id/unblock", post(unblock_device))
+            .route(
+                "/device/:device_id/config/:network_id/qr",
+                get(device_config_qr),
+            )
+            .route(
+                "/device/:device_id/config/:network_id/share",
+                post(share_device_config),
+            )
+            .route("/device/:device_id/shared_config", get(list_shared_configs))
+            .route(
+                "/device/:device_id/shared_config/:id",
+                delete(revoke_shared_config),
+            )
+            .route("/shared_config/:token", get(download_shared_config))
+            .route(
+                "/device/:device_id/effective_config",
+                get(device_effective_config),
+            )
             .route("/device", get(list_devices))
+            .route("/device/invalid_pubkeys", get(list_invalid_pubkeys))
             .route("/device/user/:username", get(list_user_devices))
-            .route("/network", post(create_network))
+            .route(
+                "/network",
+                post(create_network).layer(middleware::from_fn(idempotency)),
+            )
             .route("/network/:network_id", put(modify_network))
             .route("/network/:network_id", delete(delete_network))
             .route("/network", get(list_networks))
             .route("/network/:network_id", get(network_details))
             .route("/network/:network_id/gateways", get(gateway_status))
+            .route(
+                "/network/:network_id/gateways/resync",
+                post(resync_gateways),
+            )
             .route(
                 "/network/:network_id/gateways/:gateway_id",
                 delete(remove_gateway),
             )
             .route("/network/import", post(import_network))
+            .route("/network/archived", get(list_archived_networks))
+            .route("/network/overlaps", get(network_overlaps))
+            .route("/network/:network_id/archive", post(archive_network))
+            .route("/network/:network_id/unarchive", post(unarchive_network))
             .route("/network/:network_id/devices", post(add_user_devices))
             .route(
                 "/network/:network_id/device/:device_id/config",
                 get(download_config),
             )
+            .route(
+                "/network/:network_id/device/:device_id/limits",
+                put(set_device_bandwidth_limits),
+            )
+            .route(
+                "/network/:network_id/device/:device_id/preshared_key",
+                put(pin_device_psk),
+            )
+            .route(
+                "/network/:network_id/device/:device_id/preshared_key",
+                delete(unpin_device_psk),
+            )
             .route("/network/:network_id/token", get(create_network_token))
             .route("/network/:network_id/stats/users", get(user_stats))
             .route("/network/:network_id/stats", get(network_stats))
+            .route("/network/:network_id/connected", get(connected_clients))
+            .route("/system/stats_ingestion", get(stats_ingestion))
+            .route("/system/diagnostics", post(diagnostics))
             .layer(Extension(gateway_state)),
     );
 
@@ -338,6 +529,7 @@ pub fn build_webapp(
         "/api/v1/worker",
         Router::new()
             .route("/job", post(create_job))
+            .route("/jobs", get(list_worker_jobs))
             .route("/token", get(create_worker_token))
             .route("/", get(list_workers))
             .route("/:id", delete(remove_worker))
@@ -345,16 +537,37 @@ pub fn build_webapp(
             .layer(Extension(worker_state)),
     );
 
+    // serve everything under the path of the public URL, e.g. behind a reverse proxy
+    let base_path = server_config().base_path();
+    let webapp = if base_path.is_empty() {
+        webapp
+    } else {
+        let webapp = Router::new()
+            .route(&format!("{base_path}/"), get(index))
+            .nest(base_path, webapp);
+        // discovery is also looked up at the root of the host
+        #[cfg(feature = "openid")]
+        let webapp = webapp.route(
+            "/.well-known/openid-configuration",
+            get(openid_configuration),
+        );
+        webapp
+    };
+
+    let appstate = AppState::new(
+        pool,
+        read_pool,
+        webhook_tx,
+        webhook_rx,
+        wireguard_tx,
+        mail_tx,
+        user_agent_parser,
+        failed_logins,
+        job_runner,
+    );
     webapp
-        .with_state(AppState::new(
-            pool,
-            webhook_tx,
-            webhook_rx,
-            wireguard_tx,
-            mail_tx,
-            user_agent_parser,
-            failed_logins,
-        ))
+        .with_state(appstate)
+        .layer(middleware::from_fn(replica_lag_header))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &Request<_>| {
@@ -377,8 +590,10 @@ pub async fn run_web_server(
     wireguard_tx: Sender<GatewayEvent>,
     mail_tx: UnboundedSender<Mail>,
     pool: DbPool,
+    read_pool: ReadPool,
     user_agent_parser: Arc<UserAgentParser>,
     failed_logins: Arc<Mutex<FailedLoginMap>>,
+    job_runner: Arc<JobRunner>,
 ) -> Result<(), anyhow::Error> {
     let webapp = build_webapp(
         webhook_tx,
@@ -388,11 +603,38 @@ pub async fn run_web_server(
         worker_state,
         gateway_state,
         pool,
+        read_pool,
         user_agent_parser,
         failed_logins,
+        job_runner,
     );
     info!("Started web services");
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), server_config().http_port);
+    let config = server_config();
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), config.http_port);
+    let proxy_protocol = ProxyProtocol::new(config.proxy_protocol_sources.clone());
+    if let (Some(cert), Some(key)) = (&config.http_tls_cert, &config.http_tls_key) {
+        let listener = std::net::TcpListener::bind(addr)?;
+        return tls::serve_tls(
+            listener,
+            webapp,
+            cert.clone(),
+            key.clone(),
+            *config.http_tls_reload_interval,
+            proxy_protocol,
+        )
+        .await
+        .map_err(|err| anyhow!("Web server can't be started {err}"));
+    }
+    if proxy_protocol.is_enabled() {
+        info!("Web server expects PROXY protocol headers from load balancers");
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        return axum_server::from_tcp(listener)
+            .acceptor(proxy_protocol)
+            .serve(webapp.into_make_service())
+            .await
+            .map_err(|err| anyhow!("Web server can't be started {err}"));
+    }
     let listener = TcpListener::bind(&addr).await?;
     serve(
         listener,
@@ -411,14 +653,7 @@ pub async fn run_web_server(
 /// Private: wGS1qdJfYbWJsOUuP1IDgaJYpR+VaKZPVZvdmLjsH2Y=
 pub async fn init_dev_env(config: &DefGuardConfig) {
     info!("Initializing dev environment");
-    let pool = init_db(
-        &config.database_host,
-        config.database_port,
-        &config.database_name,
-        &config.database_user,
-        config.database_password.expose_secret(),
-    )
-    .await;
+    let pool = init_db_from_config(config).await;
 
     // initialize admin user
     User::init_admin_user(&pool, config.default_admin_password.expose_secret())
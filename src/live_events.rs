@@ -0,0 +1,370 @@
+//! Live events pushed to the admin dashboard over WebSocket.
+//!
+//! Gateway and VPN client connection changes, and connection attempts refused by location
+//! policies, are published on a process-wide broadcast channel. Device changes are taken
+//! from the gateway event channel. Every WebSocket connection subscribes to both, so each
+//! one has its own bounded queue; connections which fall behind are closed instead of
+//! slowing down the publishers.
+
+use std::{
+    collections::HashSet,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use chrono::{Duration as ChronoDuration, Utc};
+use serde_json::json;
+use tokio::{
+    sync::broadcast::{self, error::RecvError, Receiver},
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::{
+    db::{
+        models::wireguard::{PeerUpdate, WireguardPeerStats, WIREGUARD_MAX_HANDSHAKE_MINUTES},
+        DbPool, GatewayEvent, Session, User,
+    },
+    server_config,
+};
+
+// Events buffered for each connection before it's considered too slow
+const LIVE_EVENTS_CAPACITY: usize = 256;
+// How often connections are pinged to keep proxies from closing them
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+// Connections silent for this long are considered dead
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+// How often the session of a connection is checked for logout, expiry or lost admin rights
+const AUTHORIZATION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+static LIVE_EVENTS: OnceLock<broadcast::Sender<LiveEvent>> = OnceLock::new();
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LiveEvent {
+    DeviceAdded {
+        device_id: i64,
+        device_name: String,
+        user_id: i64,
+        network_id: i64,
+    },
+    DeviceRemoved {
+        device_id: i64,
+        device_name: String,
+        user_id: i64,
+        network_id: i64,
+    },
+    GatewayConnected {
+        network_id: i64,
+        hostname: String,
+    },
+    GatewayDisconnected {
+        network_id: i64,
+        hostname: String,
+    },
+    ClientConnected {
+        device_id: i64,
+        network_id: i64,
+        endpoint: Option<String>,
+        // hostname of the gateway the client connected through
+        gateway: Option<String>,
+    },
+    ClientDisconnected {
+        device_id: i64,
+        network_id: i64,
+    },
+    // connection attempt refused by location policy
+    ClientRejected {
+        device_id: i64,
+        network_id: i64,
+        reason: String,
+    },
+}
+
+/// Event types clients can subscribe to.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LiveEventKind {
+    DeviceAdded,
+    DeviceRemoved,
+    GatewayConnected,
+    GatewayDisconnected,
+    ClientConnected,
+    ClientDisconnected,
+    ClientRejected,
+}
+
+impl LiveEvent {
+    #[must_use]
+    pub fn kind(&self) -> LiveEventKind {
+        match self {
+            Self::DeviceAdded { .. } => LiveEventKind::DeviceAdded,
+            Self::DeviceRemoved { .. } => LiveEventKind::DeviceRemoved,
+            Self::GatewayConnected { .. } => LiveEventKind::GatewayConnected,
+            Self::GatewayDisconnected { .. } => LiveEventKind::GatewayDisconnected,
+            Self::ClientConnected { .. } => LiveEventKind::ClientConnected,
+            Self::ClientDisconnected { .. } => LiveEventKind::ClientDisconnected,
+            Self::ClientRejected { .. } => LiveEventKind::ClientRejected,
+        }
+    }
+
+    /// Device changes are published to gateways, other gateway events aren't of interest.
+    #[must_use]
+    pub fn from_gateway_event(event: &GatewayEvent) -> Option<Self> {
+        let device = |update: &PeerUpdate| {
+            update
+                .device
+                .id
+                .map(|device_id| (device_id, update.device.name.clone(), update.device.user_id))
+        };
+        match event {
+            GatewayEvent::PeerAdded(update) => {
+                device(update).map(|(device_id, device_name, user_id)| Self::DeviceAdded {
+                    device_id,
+                    device_name,
+                    user_id,
+                    network_id: update.network_id(),
+                })
+            }
+            GatewayEvent::PeerRemoved(update) => {
+                device(update).map(|(device_id, device_name, user_id)| Self::DeviceRemoved {
+                    device_id,
+                    device_name,
+                    user_id,
+                    network_id: update.network_id(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn sender() -> &'static broadcast::Sender<LiveEvent> {
+    LIVE_EVENTS.get_or_init(|| broadcast::channel(LIVE_EVENTS_CAPACITY).0)
+}
+
+/// Publish event to all connected dashboards.
+pub fn publish(event: LiveEvent) {
+    // sending only fails if nobody is listening
+    let _ = sender().send(event);
+}
+
+#[must_use]
+pub fn subscribe() -> Receiver<LiveEvent> {
+    sender().subscribe()
+}
+
+/// Change of VPN client connection state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionChange {
+    Connected,
+    Disconnected,
+}
+
+impl ConnectionChange {
+    /// Live event of the change reported in `stats` by gateway `gateway`.
+    #[must_use]
+    pub fn into_event(self, stats: &WireguardPeerStats, gateway: Option<String>) -> LiveEvent {
+        match self {
+            Self::Connected => LiveEvent::ClientConnected {
+                device_id: stats.device_id,
+                network_id: stats.network,
+                endpoint: stats.endpoint.clone(),
+                gateway,
+            },
+            Self::Disconnected => LiveEvent::ClientDisconnected {
+                device_id: stats.device_id,
+                network_id: stats.network,
+            },
+        }
+    }
+}
+
+/// Detects VPN clients connecting and disconnecting from peer stats of a single gateway.
+/// A client is connected as long as its latest handshake is recent enough.
+#[derive(Default)]
+pub struct ClientConnectionTracker {
+    connected: HashSet<i64>,
+}
+
+impl ClientConnectionTracker {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns connection change of the client, if there is any.
+    /// Publishing the change is up to the caller.
+    pub fn update(&mut self, stats: &WireguardPeerStats) -> Option<ConnectionChange> {
+        let threshold = Utc::now() - ChronoDuration::minutes(WIREGUARD_MAX_HANDSHAKE_MINUTES);
+        let active = stats.latest_handshake >= threshold.naive_utc();
+        if active && self.connected.insert(stats.device_id) {
+            Some(ConnectionChange::Connected)
+        } else if !active && self.connected.remove(&stats.device_id) {
+            Some(ConnectionChange::Disconnected)
+        } else {
+            None
+        }
+    }
+
+    #[must_use]
+    pub fn is_connected(&self, device_id: i64) -> bool {
+        self.connected.contains(&device_id)
+    }
+}
+
+#[derive(Deserialize)]
+struct Subscription {
+    subscribe: HashSet<LiveEventKind>,
+}
+
+/// Whether `user_id` is still an active admin.
+async fn is_active_admin(pool: &DbPool, user_id: i64) -> bool {
+    match User::find_by_id(pool, user_id).await {
+        Ok(Some(user)) if user.is_active => user
+            .effective_member_of_names(pool)
+            .await
+            .is_ok_and(|groups| groups.contains(&server_config().admin_groupname)),
+        Ok(_) => false,
+        Err(err) => {
+            error!("Failed to check admin rights of user {user_id} for live events: {err}");
+            false
+        }
+    }
+}
+
+/// Whether the session a connection was opened with is still valid and belongs to an admin.
+/// Admins impersonating someone have to keep their own admin rights as well.
+async fn session_authorized(pool: &DbPool, session_id: &str) -> bool {
+    let session = match Session::find_by_id(pool, session_id).await {
+        Ok(Some(session)) if !session.expired() => session,
+        Ok(_) => return false,
+        Err(err) => {
+            error!("Failed to check session for live events: {err}");
+            return false;
+        }
+    };
+    if let Some(impersonator_id) = session.impersonator_id {
+        if !is_active_admin(pool, impersonator_id).await {
+            return false;
+        }
+    }
+    is_active_admin(pool, session.user_id).await
+}
+
+/// Push subscribed events to a WebSocket until it's closed or falls behind.
+/// Nothing is sent until the client subscribes by sending `{"subscribe": [...]}`,
+/// which can be repeated to change the filter.
+///
+/// The connection is closed once its session `session_id` is logged out or expires,
+/// or its user loses admin rights. This is checked periodically and on every subscription.
+pub async fn serve_live_events(
+    mut socket: WebSocket,
+    mut events_rx: Receiver<LiveEvent>,
+    mut gateway_events_rx: Receiver<GatewayEvent>,
+    pool: DbPool,
+    session_id: String,
+) {
+    let mut filter = HashSet::new();
+    let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut authorization_check = interval(AUTHORIZATION_CHECK_INTERVAL);
+    authorization_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_seen = Instant::now();
+    loop {
+        let event = tokio::select! {
+            message = socket.recv() => {
+                let Some(Ok(message)) = message else {
+                    debug!("Live events connection closed by client");
+                    return;
+                };
+                last_seen = Instant::now();
+                match message {
+                    Message::Text(text) => {
+                        if !session_authorized(&pool, &session_id).await {
+                            close_unauthorized(&mut socket).await;
+                            return;
+                        }
+                        let reply = match serde_json::from_str::<Subscription>(&text) {
+                            Ok(subscription) => {
+                                filter = subscription.subscribe;
+                                debug!("Live events connection subscribed to {filter:?}");
+                                json!({"subscribed": filter})
+                            }
+                            Err(err) => json!({"error": format!("Invalid subscription: {err}")}),
+                        };
+                        if socket.send(Message::Text(reply.to_string())).await.is_err() {
+                            return;
+                        }
+                    }
+                    Message::Close(_) => return,
+                    _ => (),
+                }
+                continue;
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > HEARTBEAT_TIMEOUT {
+                    info!("Closing unresponsive live events connection");
+                    return;
+                }
+                if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            _ = authorization_check.tick() => {
+                if !session_authorized(&pool, &session_id).await {
+                    close_unauthorized(&mut socket).await;
+                    return;
+                }
+                continue;
+            }
+            event = events_rx.recv() => event,
+            event = gateway_events_rx.recv() => match event {
+                Ok(event) => match LiveEvent::from_gateway_event(&event) {
+                    Some(event) => Ok(event),
+                    None => continue,
+                },
+                Err(err) => Err(err),
+            },
+        };
+        match event {
+            Ok(event) => {
+                if !filter.contains(&event.kind()) {
+                    continue;
+                }
+                let Ok(text) = serde_json::to_string(&event) else {
+                    error!("Failed to serialize live event {event:?}");
+                    continue;
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Closing live events connection which missed {missed} events");
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::AGAIN,
+                        reason: "Too slow to receive events".into(),
+                    })))
+                    .await;
+                return;
+            }
+            Err(RecvError::Closed) => {
+                error!("Live events channel closed");
+                return;
+            }
+        }
+    }
+}
+
+async fn close_unauthorized(socket: &mut WebSocket) {
+    info!("Closing live events connection of a session which is no longer authorized");
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: "Session is no longer authorized".into(),
+        })))
+        .await;
+}
@@ -235,6 +235,20 @@ impl MailHandler {
     }
 }
 
+/// Check connection to configured SMTP server without sending anything.
+pub async fn test_smtp_connection(settings: Settings) -> Result<bool, MailError> {
+    let mailer = MailHandler::mailer(SmtpSettings::from_settings(settings)?)?;
+    Ok(mailer.test_connection().await?)
+}
+
+/// Send mail right away instead of queueing it for the mail handler,
+/// which isn't running e.g. in command-line utilities.
+pub async fn send_mail_now(settings: Settings, mail: Mail) -> Result<Response, MailError> {
+    let settings = SmtpSettings::from_settings(settings)?;
+    let message = mail.into_message(&settings.sender)?;
+    Ok(MailHandler::mailer(settings)?.send(message).await?)
+}
+
 /// Builds MailHandler and runs it.
 pub async fn run_mail_handler(rx: UnboundedReceiver<Mail>, db: Pool<Postgres>) {
     MailHandler::new(rx, db).run().await;
@@ -0,0 +1,314 @@
+//! Instance-wide restrictions of MFA methods.
+//!
+//! Admins can disallow MFA methods in settings. Disallowed methods can't be set up anymore.
+//! Users who already have one are handled according to [`DisallowedMfaPolicy`]:
+//! either the method keeps working until the end of a grace period and users are reminded
+//! daily to switch, or it's removed from their accounts right away. Methods left after
+//! the grace period are removed by a background job.
+//!
+//! Users left without an allowed method keep their recovery codes, if these are allowed,
+//! so they can log in and set up a new method. Otherwise MFA gets disabled for them.
+
+use std::time::Duration;
+
+use chrono::{Duration as ChronoDuration, Utc};
+use sqlx::Error as SqlxError;
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    db::{models::settings::DisallowedMfaPolicy, DbPool, MFAInfo, MFAMethod, Settings, User},
+    error::WebError,
+    handlers::mail::send_mfa_methods_disallowed_email,
+    jobs::{Job, JobSchedule},
+    mail::Mail,
+    templates::TemplateError,
+};
+
+// How often users are reminded to switch from disallowed methods
+const MFA_POLICY_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Error)]
+pub enum MfaPolicyError {
+    #[error(transparent)]
+    DbError(#[from] SqlxError),
+    #[error(transparent)]
+    TemplateError(#[from] TemplateError),
+    #[error(transparent)]
+    WebError(#[from] WebError),
+}
+
+/// What users whose disallowed methods were removed are left with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MfaFallback {
+    /// Other, allowed methods.
+    Methods,
+    /// Recovery codes only, which have to be used to log in and set up a new method.
+    RecoveryCodes,
+    /// Nothing, MFA is disabled.
+    Disabled,
+}
+
+impl MfaFallback {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Methods => "methods",
+            Self::RecoveryCodes => "recovery_codes",
+            Self::Disabled => "disabled",
+        }
+    }
+}
+
+/// Check if MFA settings make sense.
+pub fn validate_settings(settings: &Settings) -> Result<(), String> {
+    if settings.allowed_mfa_methods().is_empty() {
+        return Err("At least one MFA method has to be allowed".into());
+    }
+    if settings.mfa_grace_period_days < 1 {
+        return Err("MFA grace period must be at least one day".into());
+    }
+    Ok(())
+}
+
+/// Reject setting up an MFA method disallowed in settings.
+pub async fn ensure_mfa_method_allowed(pool: &DbPool, method: &MFAMethod) -> Result<(), WebError> {
+    let settings = Settings::get_settings(pool).await?;
+    if settings.mfa_method_allowed(method) {
+        Ok(())
+    } else {
+        warn!("Refusing to set up disallowed MFA method {method:?}");
+        Err(WebError::Forbidden(format!(
+            "MFA method {} is disabled",
+            method.to_string()
+        )))
+    }
+}
+
+/// Reject logging in with an MFA method which can't be used anymore.
+pub async fn ensure_mfa_method_usable(pool: &DbPool, method: &MFAMethod) -> Result<(), WebError> {
+    let settings = Settings::get_settings(pool).await?;
+    if settings.mfa_method_usable(method) {
+        Ok(())
+    } else {
+        warn!("Refusing to log in with disallowed MFA method {method:?}");
+        Err(WebError::Forbidden(format!(
+            "MFA method {} is disabled",
+            method.to_string()
+        )))
+    }
+}
+
+/// Reject logging in with recovery codes if these are disallowed.
+pub async fn ensure_recovery_codes_allowed(pool: &DbPool) -> Result<(), WebError> {
+    let settings = Settings::get_settings(pool).await?;
+    if settings.mfa_recovery_codes_allowed {
+        Ok(())
+    } else {
+        warn!("Refusing to use disallowed recovery codes");
+        Err(WebError::Forbidden("Recovery codes are disabled".into()))
+    }
+}
+
+/// Update grace period of settings changed from `previous`, before they're saved.
+/// Newly disallowed methods start a new grace period, which also applies to methods
+/// disallowed earlier. The end can't be set directly.
+pub fn update_grace_period(previous: &Settings, settings: &mut Settings) {
+    let disallowed = settings.disallowed_mfa_methods();
+    settings.mfa_grace_period_end = if disallowed.is_empty()
+        || settings.mfa_disallowed_policy != DisallowedMfaPolicy::GracePeriod
+    {
+        None
+    } else if newly_disallowed(previous, settings) {
+        Some(Utc::now().naive_utc() + ChronoDuration::days(settings.mfa_grace_period_days.into()))
+    } else {
+        previous.mfa_grace_period_end
+    };
+}
+
+fn newly_disallowed(previous: &Settings, settings: &Settings) -> bool {
+    settings
+        .disallowed_mfa_methods()
+        .iter()
+        .any(|method| previous.mfa_method_allowed(method))
+}
+
+/// Handle users affected by changed MFA settings, once they're saved.
+pub async fn apply_settings_change(
+    pool: &DbPool,
+    mail_tx: &UnboundedSender<Mail>,
+    previous: &Settings,
+    settings: &Settings,
+) -> Result<(), MfaPolicyError> {
+    let disallowed = settings.disallowed_mfa_methods();
+    if disallowed != previous.disallowed_mfa_methods()
+        || settings.mfa_disallowed_policy != previous.mfa_disallowed_policy
+        || settings.mfa_recovery_codes_allowed != previous.mfa_recovery_codes_allowed
+    {
+        info!(
+            "MFA policy changed: disallowed methods {disallowed:?}, policy {:?}, recovery codes {}",
+            settings.mfa_disallowed_policy,
+            if settings.mfa_recovery_codes_allowed {
+                "allowed"
+            } else {
+                "disallowed"
+            }
+        );
+    }
+    if disallowed.is_empty() {
+        return Ok(());
+    }
+    match settings.mfa_disallowed_policy {
+        DisallowedMfaPolicy::Invalidate => {
+            if newly_disallowed(previous, settings)
+                || previous.mfa_disallowed_policy != DisallowedMfaPolicy::Invalidate
+            {
+                remove_disallowed_methods(pool, mail_tx, settings).await?;
+            }
+        }
+        DisallowedMfaPolicy::GracePeriod => {
+            if settings.mfa_grace_period_end != previous.mfa_grace_period_end {
+                send_reminders(pool, mail_tx, settings).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Users with disallowed MFA methods set up, together with these methods.
+async fn users_with_disallowed_methods(
+    pool: &DbPool,
+    settings: &Settings,
+) -> Result<Vec<(User, Vec<MFAMethod>)>, SqlxError> {
+    let mut affected = Vec::new();
+    for user in User::all(pool).await? {
+        if !user.mfa_enabled {
+            continue;
+        }
+        let Some(info) = MFAInfo::for_user(pool, &user).await? else {
+            continue;
+        };
+        let methods: Vec<MFAMethod> = info
+            .list_available_methods()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|method| !settings.mfa_method_allowed(method))
+            .collect();
+        if !methods.is_empty() {
+            affected.push((user, methods));
+        }
+    }
+    Ok(affected)
+}
+
+async fn send_reminders(
+    pool: &DbPool,
+    mail_tx: &UnboundedSender<Mail>,
+    settings: &Settings,
+) -> Result<(), MfaPolicyError> {
+    let allowed = settings.allowed_mfa_methods();
+    for (user, methods) in users_with_disallowed_methods(pool, settings).await? {
+        debug!(
+            "Reminding user {} to switch from disallowed MFA methods {methods:?}",
+            user.username
+        );
+        send_mfa_methods_disallowed_email(
+            &user.email,
+            &methods,
+            &allowed,
+            settings.mfa_grace_period_end.as_ref(),
+            MfaFallback::Methods.as_str(),
+            mail_tx,
+        )?;
+    }
+    Ok(())
+}
+
+/// Remove disallowed methods of a single user and fix up their MFA state.
+async fn remove_user_methods(
+    pool: &DbPool,
+    settings: &Settings,
+    user: &mut User,
+    methods: &[MFAMethod],
+) -> Result<MfaFallback, MfaPolicyError> {
+    for method in methods {
+        user.remove_mfa_method(pool, method).await?;
+    }
+    let factors_left = MFAInfo::for_user(pool, user)
+        .await?
+        .is_some_and(|info| info.mfa_available());
+    let fallback = if factors_left {
+        // switches current method to a remaining one
+        user.verify_mfa_state(pool).await?;
+        MfaFallback::Methods
+    } else if settings.mfa_recovery_codes_allowed && !user.recovery_codes.is_empty() {
+        user.set_mfa_method(pool, MFAMethod::None).await?;
+        MfaFallback::RecoveryCodes
+    } else {
+        user.disable_mfa(pool).await?;
+        MfaFallback::Disabled
+    };
+    warn!(
+        "Removed disallowed MFA methods {methods:?} of user {}, user is left with {}",
+        user.username,
+        fallback.as_str()
+    );
+    Ok(fallback)
+}
+
+async fn remove_disallowed_methods(
+    pool: &DbPool,
+    mail_tx: &UnboundedSender<Mail>,
+    settings: &Settings,
+) -> Result<(), MfaPolicyError> {
+    let allowed = settings.allowed_mfa_methods();
+    for (mut user, methods) in users_with_disallowed_methods(pool, settings).await? {
+        let fallback = remove_user_methods(pool, settings, &mut user, &methods).await?;
+        send_mfa_methods_disallowed_email(
+            &user.email,
+            &methods,
+            &allowed,
+            None,
+            fallback.as_str(),
+            mail_tx,
+        )?;
+    }
+    Ok(())
+}
+
+/// Remind users to switch from disallowed methods during grace period,
+/// remove these methods once it's over.
+pub async fn enforce_mfa_policy(
+    pool: &DbPool,
+    mail_tx: &UnboundedSender<Mail>,
+) -> Result<(), MfaPolicyError> {
+    let settings = Settings::get_settings(pool).await?;
+    if settings.mfa_grace_period_end.is_none() {
+        return Ok(());
+    }
+    if settings.mfa_grace_period_active() {
+        send_reminders(pool, mail_tx, &settings).await
+    } else {
+        info!("MFA grace period is over, removing disallowed methods");
+        remove_disallowed_methods(pool, mail_tx, &settings).await?;
+        Settings::end_mfa_grace_period(pool).await?;
+        Ok(())
+    }
+}
+
+/// Background job enforcing MFA method restrictions.
+#[must_use]
+pub fn mfa_policy_job(pool: DbPool, mail_tx: UnboundedSender<Mail>) -> Job {
+    Job::new(
+        "mfa_policy",
+        JobSchedule::Interval(MFA_POLICY_INTERVAL),
+        move || {
+            let pool = pool.clone();
+            let mail_tx = mail_tx.clone();
+            async move {
+                enforce_mfa_policy(&pool, &mail_tx).await?;
+                Ok(())
+            }
+        },
+    )
+}
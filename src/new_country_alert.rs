@@ -0,0 +1,258 @@
+//! Notifications about VPN connections from new countries.
+//!
+//! Countries are resolved from peer endpoints reported by gateways with [`crate::geoip`],
+//! and remembered per device. When a device connects from a country it hasn't been seen in
+//! during the retention period, its owner gets an email. The first known country of a device
+//! is only remembered, as there's nothing to compare it with. Countries are tracked even
+//! if alerts are disabled in settings, so enabling them doesn't alert about every device.
+
+use chrono::Utc;
+use sqlx::{query, query_scalar, Error as SqlxError};
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    db::{DbPool, Device, Settings, User, WireguardNetwork},
+    geoip::{endpoint_ip, GeoIpDatabase},
+    handlers::mail::send_new_country_connection_email,
+    mail::Mail,
+    templates::TemplateError,
+};
+
+#[derive(Debug, Error)]
+pub enum NewCountryAlertError {
+    #[error(transparent)]
+    DbError(#[from] SqlxError),
+    #[error(transparent)]
+    TemplateError(#[from] TemplateError),
+}
+
+/// Check if retention period of known countries is valid.
+pub fn validate_settings(settings: &Settings) -> Result<(), String> {
+    if settings.known_country_retention_months < 1 {
+        return Err("Known country retention period must be at least one month".into());
+    }
+    Ok(())
+}
+
+/// Remember that a device connected from `country`, forgetting countries not seen within
+/// `retention_months`. Returns `true` if the country is new, and the device was already
+/// known to connect from other countries.
+pub async fn record_country(
+    pool: &DbPool,
+    device_id: i64,
+    country: &str,
+    retention_months: i32,
+) -> Result<bool, SqlxError> {
+    let mut transaction = pool.begin().await?;
+    query!(
+        "DELETE FROM device_country WHERE device_id = $1 \
+        AND last_seen < now() - make_interval(months => $2)",
+        device_id,
+        retention_months
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let known = query_scalar!(
+        "SELECT EXISTS (SELECT 1 FROM device_country WHERE device_id = $1) \"exists!\"",
+        device_id
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    // both timestamps default to transaction start, so they're only equal for new rows
+    let inserted = query_scalar!(
+        "INSERT INTO device_country (device_id, country) VALUES ($1, $2) \
+        ON CONFLICT (device_id, country) DO UPDATE SET last_seen = now() \
+        RETURNING first_seen = last_seen \"inserted!\"",
+        device_id,
+        country
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(known && inserted)
+}
+
+/// Check the country of a new VPN connection and notify the device owner if it's new.
+/// Connections which can't be located are ignored. Returns `true` if the owner was notified.
+pub async fn check_connection_country(
+    pool: &DbPool,
+    mail_tx: &UnboundedSender<Mail>,
+    geoip: Option<&GeoIpDatabase>,
+    device_id: i64,
+    network_id: i64,
+    endpoint: &str,
+) -> Result<bool, NewCountryAlertError> {
+    let Some(ip) = endpoint_ip(endpoint) else {
+        return Ok(false);
+    };
+    let Some(country) = geoip.and_then(|geoip| geoip.country(ip)) else {
+        debug!("Country of device {device_id} endpoint {endpoint} unknown, skipping");
+        return Ok(false);
+    };
+    let settings = Settings::get_settings(pool).await?;
+    if !record_country(
+        pool,
+        device_id,
+        country,
+        settings.known_country_retention_months,
+    )
+    .await?
+    {
+        return Ok(false);
+    }
+    if !settings.new_country_alert_enabled {
+        return Ok(false);
+    }
+
+    let Some(device) = Device::find_by_id(pool, device_id).await? else {
+        return Ok(false);
+    };
+    let Some(user) = User::find_by_id(pool, device.user_id).await? else {
+        return Ok(false);
+    };
+    warn!(
+        new_country = true,
+        device_id,
+        user_id = device.user_id,
+        "Device {} of user {} connected to location {network_id} from new country {country}, address {ip}",
+        device.name,
+        user.username
+    );
+    if user.new_country_alert_opt_out {
+        debug!(
+            "User {} opted out of new country alerts, not notifying",
+            user.username
+        );
+        return Ok(false);
+    }
+    let location_name = WireguardNetwork::find_by_id(pool, network_id)
+        .await?
+        .map_or_else(|| network_id.to_string(), |network| network.name);
+    send_new_country_connection_email(
+        &device.name,
+        &location_name,
+        country,
+        &ip.to_string(),
+        &Utc::now().naive_utc(),
+        &user.email,
+        mail_tx,
+    )?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+    use crate::{config::DefGuardConfig, SERVER_CONFIG};
+
+    async fn setup(pool: &DbPool, enabled: bool) -> (User, Device) {
+        let mut settings = Settings::get_settings(pool).await.unwrap();
+        settings.new_country_alert_enabled = enabled;
+        settings.save(pool).await.unwrap();
+        let mut user = User::new(
+            "hpotter",
+            Some("pass123"),
+            "Potter",
+            "Harry",
+            "h.potter@hogwart.edu.uk",
+            None,
+        );
+        user.save(pool).await.unwrap();
+        let mut device = Device::new("laptop".into(), "key".into(), user.id.unwrap());
+        device.save(pool).await.unwrap();
+        (user, device)
+    }
+
+    fn geoip() -> GeoIpDatabase {
+        GeoIpDatabase::parse("2.16.0.0,2.16.255.255,PL\n3.0.0.0,3.0.255.255,US\n").unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_new_country_alert(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let (_user, device) = setup(&pool, true).await;
+        let device_id = device.id.unwrap();
+        let geoip = &geoip();
+        let (mail_tx, mut mail_rx) = unbounded_channel();
+        let (pool_ref, mail_tx_ref) = (&pool, &mail_tx);
+        let check = move |endpoint: &'static str| {
+            check_connection_country(pool_ref, mail_tx_ref, Some(geoip), device_id, 1, endpoint)
+        };
+
+        // first known country isn't alerted about
+        assert!(!check("2.16.0.1:51820").await.unwrap());
+        // connections which can't be located are ignored
+        assert!(!check("10.0.0.1:51820").await.unwrap());
+        assert!(!check("").await.unwrap());
+        assert!(mail_rx.try_recv().is_err());
+
+        assert!(check("3.0.0.1:51820").await.unwrap());
+        let mail = mail_rx.try_recv().unwrap();
+        assert_eq!(mail.to, "h.potter@hogwart.edu.uk");
+        assert!(mail.content.contains("3.0.0.1"));
+        assert!(mail.content.contains("US"));
+        assert!(mail_rx.try_recv().is_err());
+
+        // known countries aren't alerted about again
+        assert!(!check("3.0.0.2:51820").await.unwrap());
+        assert!(!check("2.16.0.1:51820").await.unwrap());
+        assert!(mail_rx.try_recv().is_err());
+
+        // countries not seen for longer than retention period are forgotten
+        query!(
+            "UPDATE device_country SET last_seen = now() - interval '13 months' \
+            WHERE device_id = $1 AND country = 'US'",
+            device_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert!(check("3.0.0.1:51820").await.unwrap());
+        assert!(mail_rx.try_recv().is_ok());
+
+        // without GeoIP database nothing is located
+        assert!(
+            !check_connection_country(&pool, &mail_tx, None, device_id, 1, "2.16.0.1:51820")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_new_country_alert_disabled(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let (mut user, device) = setup(&pool, true).await;
+        let device_id = device.id.unwrap();
+        let geoip = &geoip();
+        let (mail_tx, mut mail_rx) = unbounded_channel();
+        let (pool_ref, mail_tx_ref) = (&pool, &mail_tx);
+        let check = move |endpoint: &'static str| {
+            check_connection_country(pool_ref, mail_tx_ref, Some(geoip), device_id, 1, endpoint)
+        };
+        assert!(!check("2.16.0.1:51820").await.unwrap());
+
+        // users can opt out
+        user.new_country_alert_opt_out = true;
+        user.save(&pool).await.unwrap();
+        assert!(!check("3.0.0.1:51820").await.unwrap());
+        assert!(mail_rx.try_recv().is_err());
+
+        // countries are still tracked with alerts disabled
+        user.new_country_alert_opt_out = false;
+        user.save(&pool).await.unwrap();
+        let mut settings = Settings::get_settings(&pool).await.unwrap();
+        settings.new_country_alert_enabled = false;
+        settings.save(&pool).await.unwrap();
+        assert!(!check("3.0.0.1:51820").await.unwrap());
+        assert!(!check("4.0.0.1:51820").await.unwrap());
+        settings.new_country_alert_enabled = true;
+        settings.save(&pool).await.unwrap();
+        assert!(!check("3.0.0.1:51820").await.unwrap());
+        assert!(mail_rx.try_recv().is_err());
+    }
+}
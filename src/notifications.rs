@@ -0,0 +1,310 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use sha2::Sha256;
+use sqlx::Error as SqlxError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use utoipa::ToSchema;
+
+use crate::{
+    db::{
+        models::notification_recipient::{NotificationChannel, NotificationRecipient},
+        DbPool, User,
+    },
+    hex::to_lower_hex,
+    mail::Mail,
+    server_config,
+};
+
+/// Header containing HMAC-SHA256 signature of webhook notification body.
+pub const SIGNATURE_HEADER: &str = "x-defguard-signature";
+/// Header containing notification category of webhook notification.
+pub const CATEGORY_HEADER: &str = "x-defguard-notification";
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Categories of admin notifications. Recipients subscribe to a subset of them.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationCategory {
+    Gateway,
+    Security,
+    Licensing,
+    System,
+}
+
+impl NotificationCategory {
+    pub const ALL: [Self; 4] = [Self::Gateway, Self::Security, Self::Licensing, Self::System];
+
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gateway => "gateway",
+            Self::Security => "security",
+            Self::Licensing => "licensing",
+            Self::System => "system",
+        }
+    }
+}
+
+impl Display for NotificationCategory {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for NotificationCategory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|category| category.as_str() == s)
+            .ok_or_else(|| format!("Unknown notification category: {s}"))
+    }
+}
+
+/// Notification addressed to instance administrators.
+#[derive(Clone, Debug)]
+pub struct AdminNotification {
+    pub category: NotificationCategory,
+    pub subject: String,
+    /// Plain text message, used for webhooks.
+    pub message: String,
+    /// Rendered HTML message, used for emails.
+    pub html: String,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    category: NotificationCategory,
+    subject: &'a str,
+    message: &'a str,
+    timestamp: String,
+}
+
+/// Outcome of delivering a notification to a single recipient.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeliveryResult {
+    pub recipient: String,
+    pub channel: NotificationChannel,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+impl DeliveryResult {
+    fn new(recipient: String, channel: NotificationChannel, result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => {
+                info!("Sent admin notification to {recipient}");
+                Self {
+                    recipient,
+                    channel,
+                    success: true,
+                    error: None,
+                }
+            }
+            Err(err) => {
+                error!("Failed to send admin notification to {recipient}: {err}");
+                Self {
+                    recipient,
+                    channel,
+                    success: false,
+                    error: Some(err),
+                }
+            }
+        }
+    }
+}
+
+/// Compute value of the signature header for given webhook secret and request body.
+#[must_use]
+pub fn sign_payload(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", to_lower_hex(&mac.finalize().into_bytes()))
+}
+
+/// Send notification to all recipients subscribed to its category.
+///
+/// If no recipients are configured, the notification is emailed to members of the admin group.
+pub async fn notify_admins(
+    pool: &DbPool,
+    mail_tx: &UnboundedSender<Mail>,
+    notification: &AdminNotification,
+) -> Result<Vec<DeliveryResult>, SqlxError> {
+    debug!(
+        "Sending {} admin notification: {}",
+        notification.category, notification.subject
+    );
+    let recipients = NotificationRecipient::all(pool).await?;
+    if recipients.is_empty() {
+        let admins = User::find_by_group_name(pool, &server_config().admin_groupname).await?;
+        let mut results = Vec::with_capacity(admins.len());
+        for admin in admins {
+            let result = send_email(&admin.email, mail_tx, notification).await;
+            results.push(DeliveryResult::new(
+                admin.email,
+                NotificationChannel::Email,
+                result,
+            ));
+        }
+        return Ok(results);
+    }
+
+    let recipients: Vec<_> = recipients
+        .into_iter()
+        .filter(|recipient| recipient.accepts(notification.category))
+        .collect();
+    Ok(deliver(&recipients, mail_tx, notification).await)
+}
+
+/// Send notification to given recipients, regardless of their category filters.
+pub async fn deliver(
+    recipients: &[NotificationRecipient],
+    mail_tx: &UnboundedSender<Mail>,
+    notification: &AdminNotification,
+) -> Vec<DeliveryResult> {
+    let client = Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .expect("Failed to build HTTP client");
+    let mut results = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        let result = match recipient.channel {
+            NotificationChannel::Email => match &recipient.email {
+                Some(email) => send_email(email, mail_tx, notification).await,
+                None => Err("email address not configured".into()),
+            },
+            NotificationChannel::Webhook => send_webhook(&client, recipient, notification).await,
+        };
+        results.push(DeliveryResult::new(
+            recipient.name.clone(),
+            recipient.channel,
+            result,
+        ));
+    }
+    results
+}
+
+async fn send_email(
+    to: &str,
+    mail_tx: &UnboundedSender<Mail>,
+    notification: &AdminNotification,
+) -> Result<(), String> {
+    let (tx, mut rx) = unbounded_channel();
+    let mail = Mail {
+        to: to.to_string(),
+        subject: notification.subject.clone(),
+        content: notification.html.clone(),
+        attachments: Vec::new(),
+        result_tx: Some(tx),
+    };
+    mail_tx.send(mail).map_err(|err| err.to_string())?;
+    match rx.recv().await {
+        Some(Ok(_)) => Ok(()),
+        Some(Err(err)) => Err(err.to_string()),
+        None => Err("mail handler did not report delivery result".into()),
+    }
+}
+
+async fn send_webhook(
+    client: &Client,
+    recipient: &NotificationRecipient,
+    notification: &AdminNotification,
+) -> Result<(), String> {
+    let Some(url) = &recipient.webhook_url else {
+        return Err("webhook URL not configured".into());
+    };
+    let payload = WebhookPayload {
+        category: notification.category,
+        subject: &notification.subject,
+        message: &notification.message,
+        timestamp: Utc::now().to_rfc3339(),
+    };
+    let body = serde_json::to_vec(&payload).map_err(|err| err.to_string())?;
+    let mut request = client
+        .post(url)
+        .header(CATEGORY_HEADER, notification.category.as_str())
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+    if let Some(secret) = &recipient.webhook_secret {
+        request = request.header(
+            SIGNATURE_HEADER,
+            sign_payload(secret.expose_secret(), &body),
+        );
+    }
+    let response = request
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "webhook responded with status {}",
+            response.status()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn recipient(categories: &[&str]) -> NotificationRecipient {
+        NotificationRecipient {
+            id: None,
+            name: "ops".into(),
+            channel: NotificationChannel::Webhook,
+            email: None,
+            webhook_url: Some("http://localhost/hook".into()),
+            webhook_secret: None,
+            categories: categories.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn test_category_filtering() {
+        let recipient = recipient(&["gateway", "security"]);
+        assert!(recipient.accepts(NotificationCategory::Gateway));
+        assert!(recipient.accepts(NotificationCategory::Security));
+        assert!(!recipient.accepts(NotificationCategory::Licensing));
+        assert!(!recipient.accepts(NotificationCategory::System));
+        assert!(recipient.validate().is_ok());
+
+        let recipient = self::recipient(&[]);
+        for category in NotificationCategory::ALL {
+            assert!(!recipient.accepts(category));
+        }
+
+        assert!(self::recipient(&["billing"]).validate().is_err());
+    }
+
+    #[test]
+    fn test_category_names() {
+        for category in NotificationCategory::ALL {
+            assert_eq!(category.as_str().parse(), Ok(category));
+            assert_eq!(
+                serde_json::to_value(category).unwrap(),
+                serde_json::json!(category.as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn test_sign_payload() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign_payload("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
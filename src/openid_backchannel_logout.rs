@@ -0,0 +1,186 @@
+//! OpenID Connect Back-Channel Logout
+//! https://openid.net/specs/openid-connect-backchannel-1_0.html
+//!
+//! When a defguard session ends (logout, all sessions being invalidated, user disabled),
+//! sessions of OpenID clients it authenticated are marked as ended.
+//! A background job then sends a signed logout token to each client with
+//! a back-channel logout URI configured. Delivery never blocks the logout itself.
+
+use std::time::Duration;
+
+use chrono::{Duration as ChronoDuration, Utc};
+use jsonwebtoken::{
+    encode,
+    errors::{Error as JWTError, ErrorKind},
+    Algorithm, EncodingKey, Header,
+};
+use reqwest::Client;
+use rsa::{pkcs1::EncodeRsaPrivateKey, pkcs8::LineEnding, traits::PublicKeyParts};
+use serde_json::{json, Value};
+use sqlx::Error as SqlxError;
+use tokio::time::sleep;
+
+use crate::{
+    db::{
+        models::oauth2session::{OAuth2Session, PendingLogout},
+        DbPool,
+    },
+    jobs::{Job, JobSchedule},
+    random::gen_alphanumeric,
+    server_config,
+};
+
+pub const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+const BACKCHANNEL_LOGOUT_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// attempts made in a single run, with increasing delay
+const ATTEMPTS_PER_RUN: i32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+// delivery is abandoned after this many attempts in total
+const MAX_ATTEMPTS: i32 = 3 * ATTEMPTS_PER_RUN;
+const LOGOUT_TOKEN_VALIDITY_SECONDS: i64 = 120;
+// ended sessions are kept for delivery status
+const STATUS_RETENTION_DAYS: i64 = 7;
+
+/// https://openid.net/specs/openid-connect-backchannel-1_0.html#LogoutToken
+#[derive(Serialize)]
+struct LogoutTokenClaims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+    jti: String,
+    sid: &'a str,
+    events: Value,
+}
+
+/// Build logout token, signed the same way as ID tokens: with RSA key if configured,
+/// client secret otherwise.
+fn logout_token(logout: &PendingLogout) -> Result<String, JWTError> {
+    let config = server_config();
+    let now = Utc::now().timestamp();
+    let claims = LogoutTokenClaims {
+        iss: config.url.as_str(),
+        sub: &logout.username,
+        aud: &logout.client_id,
+        iat: now,
+        exp: now + LOGOUT_TOKEN_VALIDITY_SECONDS,
+        jti: gen_alphanumeric(24),
+        sid: &logout.sid,
+        events: json!({ BACKCHANNEL_LOGOUT_EVENT: {} }),
+    };
+    match &config.openid_signing_key {
+        Some(key) => {
+            let pem = key
+                .to_pkcs1_pem(LineEnding::default())
+                .map_err(|err| ErrorKind::InvalidRsaKey(err.to_string()))?;
+            let mut header = Header::new(Algorithm::RS256);
+            header.typ = Some("logout+jwt".into());
+            // same key ID as published in JWKS
+            header.kid = Some(key.n().to_str_radix(36));
+            encode(
+                &header,
+                &claims,
+                &EncodingKey::from_rsa_pem(pem.as_bytes())?,
+            )
+        }
+        None => {
+            let mut header = Header::new(Algorithm::HS256);
+            header.typ = Some("logout+jwt".into());
+            encode(
+                &header,
+                &claims,
+                &EncodingKey::from_secret(logout.client_secret.as_bytes()),
+            )
+        }
+    }
+}
+
+async fn post_logout_token(client: &Client, uri: &str, token: &str) -> Result<(), String> {
+    let response = client
+        .post(uri)
+        .form(&[("logout_token", token)])
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "logout endpoint responded with status {}",
+            response.status()
+        ))
+    }
+}
+
+/// Deliver logout token, retrying a few times. Returns number of attempts made and the outcome.
+async fn deliver(client: &Client, logout: &PendingLogout) -> (i32, Result<(), String>) {
+    let token = match logout_token(logout) {
+        Ok(token) => token,
+        Err(err) => return (1, Err(format!("failed to create logout token: {err}"))),
+    };
+    let attempts = ATTEMPTS_PER_RUN.min(MAX_ATTEMPTS - logout.logout_attempts);
+    let mut result = Ok(());
+    for attempt in 1..=attempts {
+        result = post_logout_token(client, &logout.backchannel_logout_uri, &token).await;
+        if result.is_ok() {
+            return (attempt, result);
+        }
+        if attempt < attempts {
+            sleep(RETRY_DELAY * attempt as u32).await;
+        }
+    }
+    (attempts, result)
+}
+
+/// Send logout tokens for all ended sessions not delivered yet.
+pub async fn send_backchannel_logouts(pool: &DbPool) -> Result<(), SqlxError> {
+    let threshold = (Utc::now() - ChronoDuration::days(STATUS_RETENTION_DAYS)).naive_utc();
+    let purged = OAuth2Session::purge_ended(pool, threshold).await?;
+    if purged > 0 {
+        debug!("Removed {purged} old OpenID client sessions");
+    }
+
+    let pending = OAuth2Session::pending_logouts(pool, MAX_ATTEMPTS).await?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    debug!("Sending {} back-channel logout tokens", pending.len());
+    let client = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to build HTTP client");
+    for logout in pending {
+        let (attempts, result) = deliver(&client, &logout).await;
+        match &result {
+            Ok(()) => info!(
+                "Sent back-channel logout of user {} to OpenID client {}",
+                logout.username, logout.client_name
+            ),
+            Err(err) => warn!(
+                "Back-channel logout of user {} to OpenID client {} failed: {err}",
+                logout.username, logout.client_name
+            ),
+        }
+        OAuth2Session::record_delivery(pool, logout.id, attempts, result.err().as_deref()).await?;
+    }
+    Ok(())
+}
+
+/// Background job delivering back-channel logout tokens.
+#[must_use]
+pub fn backchannel_logout_job(pool: DbPool) -> Job {
+    Job::new(
+        "backchannel_logout",
+        JobSchedule::Interval(BACKCHANNEL_LOGOUT_INTERVAL),
+        move || {
+            let pool = pool.clone();
+            async move {
+                send_backchannel_logouts(&pool).await?;
+                Ok(())
+            }
+        },
+    )
+}
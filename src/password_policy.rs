@@ -0,0 +1,430 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    time::Duration,
+};
+
+use reqwest::{Client, Url};
+use sha1::{Digest, Sha1};
+use sqlx::{Error as SqlxError, PgExecutor};
+use thiserror::Error;
+use zxcvbn::zxcvbn;
+
+use crate::{db::Settings, hex::to_lower_hex, server_config};
+
+/// Passwords longer than this are always rejected.
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+// shorter user data (e.g. 2-letter usernames) would reject too many passwords
+const MIN_USER_DATA_LENGTH: usize = 3;
+
+/// Single password policy requirement which a password failed to meet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordRequirement {
+    MinLength,
+    MaxLength,
+    Lowercase,
+    Uppercase,
+    Digit,
+    Special,
+    UserData,
+    Score,
+    Breached,
+}
+
+impl PasswordRequirement {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MinLength => "min_length",
+            Self::MaxLength => "max_length",
+            Self::Lowercase => "lowercase",
+            Self::Uppercase => "uppercase",
+            Self::Digit => "digit",
+            Self::Special => "special",
+            Self::UserData => "user_data",
+            Self::Score => "score",
+            Self::Breached => "breached",
+        }
+    }
+}
+
+impl Display for PasswordRequirement {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Password does not meet requirements: {}", join_requirements(.0))]
+pub struct PasswordPolicyError(pub Vec<PasswordRequirement>);
+
+fn join_requirements(requirements: &[PasswordRequirement]) -> String {
+    requirements
+        .iter()
+        .map(PasswordRequirement::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Password requirements configured in [`Settings`].
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_special: bool,
+    pub disallow_user_data: bool,
+    pub min_score: Option<u8>,
+    pub breach_check: bool,
+    pub breach_check_url: Url,
+    pub breach_check_timeout: Duration,
+}
+
+impl PasswordPolicy {
+    #[must_use]
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            min_length: usize::try_from(settings.password_min_length).unwrap_or_default(),
+            require_lowercase: settings.password_require_lowercase,
+            require_uppercase: settings.password_require_uppercase,
+            require_digit: settings.password_require_digit,
+            require_special: settings.password_require_special,
+            disallow_user_data: settings.password_disallow_user_data,
+            min_score: settings
+                .password_min_score
+                .and_then(|score| u8::try_from(score).ok()),
+            breach_check: settings.password_breach_check,
+            breach_check_url: server_config().pwned_passwords_url.clone(),
+            breach_check_timeout: Duration::from_millis(
+                u64::try_from(settings.password_breach_check_timeout).unwrap_or_default(),
+            ),
+        }
+    }
+
+    pub async fn load<'e, E>(executor: E) -> Result<Self, SqlxError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let settings = Settings::get_settings(executor).await?;
+        Ok(Self::from_settings(&settings))
+    }
+
+    /// Check if policy values stored in settings make sense.
+    pub fn validate_settings(settings: &Settings) -> Result<(), String> {
+        let max_length = i32::try_from(MAX_PASSWORD_LENGTH).unwrap_or(i32::MAX);
+        if !(1..=max_length).contains(&settings.password_min_length) {
+            return Err(format!(
+                "Minimum password length must be between 1 and {MAX_PASSWORD_LENGTH}"
+            ));
+        }
+        if let Some(score) = settings.password_min_score {
+            if !(0..=4).contains(&score) {
+                return Err("Minimum password score must be between 0 and 4".into());
+            }
+        }
+        if settings.password_breach_check_timeout <= 0 {
+            return Err("Password breach check timeout must be positive".into());
+        }
+        Ok(())
+    }
+
+    /// Return requirements the password fails to meet, without contacting external services.
+    ///
+    /// `user_inputs` should contain user data (username, email) which can't be part of a password.
+    #[must_use]
+    pub fn failed_requirements(
+        &self,
+        password: &str,
+        user_inputs: &[&str],
+    ) -> Vec<PasswordRequirement> {
+        let mut failed = Vec::new();
+        let length = password.chars().count();
+        if length < self.min_length {
+            failed.push(PasswordRequirement::MinLength);
+        }
+        if length > MAX_PASSWORD_LENGTH {
+            failed.push(PasswordRequirement::MaxLength);
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            failed.push(PasswordRequirement::Lowercase);
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            failed.push(PasswordRequirement::Uppercase);
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            failed.push(PasswordRequirement::Digit);
+        }
+        if self.require_special && !password.chars().any(|c| c.is_ascii_punctuation()) {
+            failed.push(PasswordRequirement::Special);
+        }
+        if self.disallow_user_data && contains_user_data(password, user_inputs) {
+            failed.push(PasswordRequirement::UserData);
+        }
+        if let Some(min_score) = self.min_score {
+            // zxcvbn fails on blank passwords which are too weak anyway
+            let score = zxcvbn(password, user_inputs).map_or(0, |entropy| entropy.score());
+            if score < min_score {
+                failed.push(PasswordRequirement::Score);
+            }
+        }
+        failed
+    }
+
+    /// Check the password against all requirements, including the optional breach check.
+    ///
+    /// The breach check fails open: if the Pwned Passwords API can't be reached
+    /// the password is accepted and a warning is logged.
+    pub async fn validate(
+        &self,
+        password: &str,
+        user_inputs: &[&str],
+    ) -> Result<(), PasswordPolicyError> {
+        let mut failed = self.failed_requirements(password, user_inputs);
+        if self.breach_check {
+            match self.is_breached(password).await {
+                Ok(true) => failed.push(PasswordRequirement::Breached),
+                Ok(false) => (),
+                Err(err) => warn!("Password breach check failed, skipping it: {err}"),
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(PasswordPolicyError(failed))
+        }
+    }
+
+    // Query Pwned Passwords range API, which only receives the first 5 characters of SHA-1 hash.
+    async fn is_breached(&self, password: &str) -> Result<bool, reqwest::Error> {
+        let hash = to_lower_hex(&Sha1::digest(password.as_bytes())).to_uppercase();
+        let (prefix, suffix) = hash.split_at(5);
+        let Ok(url) = self.breach_check_url.join(&format!("range/{prefix}")) else {
+            warn!(
+                "Invalid Pwned Passwords API URL {}, skipping breach check",
+                self.breach_check_url
+            );
+            return Ok(false);
+        };
+        debug!("Checking password hash prefix {prefix} in Pwned Passwords API");
+        let response = Client::builder()
+            .timeout(self.breach_check_timeout)
+            .build()?
+            .get(url)
+            .header("Add-Padding", "true")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        // padding entries have count 0
+        Ok(response.lines().any(|line| {
+            line.split_once(':').is_some_and(|(hash_suffix, count)| {
+                hash_suffix.eq_ignore_ascii_case(suffix) && count.trim() != "0"
+            })
+        }))
+    }
+}
+
+// Check if password contains any of user data, case-insensitive.
+// For email addresses the local part is checked too.
+fn contains_user_data(password: &str, user_inputs: &[&str]) -> bool {
+    let password = password.to_lowercase();
+    user_inputs
+        .iter()
+        .flat_map(|input| {
+            let local_part = input.split_once('@').map(|(local_part, _)| local_part);
+            [Some(*input), local_part]
+        })
+        .flatten()
+        .filter(|input| input.chars().count() >= MIN_USER_DATA_LENGTH)
+        .any(|input| password.contains(&input.to_lowercase()))
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{extract::Path, routing::get, serve, Router};
+    use claims::{assert_err, assert_ok};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn test_policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_special: false,
+            disallow_user_data: false,
+            min_score: None,
+            breach_check: false,
+            breach_check_url: Url::parse("http://127.0.0.1:1").unwrap(),
+            breach_check_timeout: Duration::from_millis(500),
+        }
+    }
+
+    #[test]
+    fn test_length() {
+        let mut policy = test_policy();
+        policy.min_length = 14;
+        assert_eq!(
+            policy.failed_requirements("short", &[]),
+            [PasswordRequirement::MinLength]
+        );
+        assert!(policy
+            .failed_requirements("long enough words", &[])
+            .is_empty());
+        assert_eq!(
+            policy.failed_requirements(&"a".repeat(MAX_PASSWORD_LENGTH + 1), &[]),
+            [PasswordRequirement::MaxLength]
+        );
+    }
+
+    #[test]
+    fn test_character_classes() {
+        let mut policy = test_policy();
+        policy.require_lowercase = true;
+        assert_eq!(
+            policy.failed_requirements("UPPERCASE1!", &[]),
+            [PasswordRequirement::Lowercase]
+        );
+
+        let mut policy = test_policy();
+        policy.require_uppercase = true;
+        assert_eq!(
+            policy.failed_requirements("lowercase1!", &[]),
+            [PasswordRequirement::Uppercase]
+        );
+
+        let mut policy = test_policy();
+        policy.require_digit = true;
+        assert_eq!(
+            policy.failed_requirements("NoDigitsHere!", &[]),
+            [PasswordRequirement::Digit]
+        );
+
+        let mut policy = test_policy();
+        policy.require_special = true;
+        assert_eq!(
+            policy.failed_requirements("NoSpecials123", &[]),
+            [PasswordRequirement::Special]
+        );
+
+        let mut policy = test_policy();
+        policy.require_lowercase = true;
+        policy.require_uppercase = true;
+        policy.require_digit = true;
+        policy.require_special = true;
+        assert!(policy
+            .failed_requirements("strongPass1234$!", &[])
+            .is_empty());
+        assert_eq!(
+            policy.failed_requirements("12345678", &[]),
+            [
+                PasswordRequirement::Lowercase,
+                PasswordRequirement::Uppercase,
+                PasswordRequirement::Special
+            ]
+        );
+    }
+
+    #[test]
+    fn test_user_data() {
+        let mut policy = test_policy();
+        let user_inputs = ["hpotter", "harry@hogwart.edu.uk"];
+        assert!(policy
+            .failed_requirements("xHPotter-2024", &user_inputs)
+            .is_empty());
+
+        policy.disallow_user_data = true;
+        assert_eq!(
+            policy.failed_requirements("xHPotter-2024", &user_inputs),
+            [PasswordRequirement::UserData]
+        );
+        assert_eq!(
+            policy.failed_requirements("my-Harry-2024", &user_inputs),
+            [PasswordRequirement::UserData]
+        );
+        assert!(policy
+            .failed_requirements("Quidditch-2024", &user_inputs)
+            .is_empty());
+        // too short to matter
+        assert!(policy
+            .failed_requirements("Quidditch-2024", &["it"])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_score() {
+        let mut policy = test_policy();
+        policy.min_score = Some(3);
+        assert_eq!(
+            policy.failed_requirements("password1", &[]),
+            [PasswordRequirement::Score]
+        );
+        assert_eq!(
+            policy.failed_requirements("", &[]),
+            [PasswordRequirement::MinLength, PasswordRequirement::Score]
+        );
+        assert!(policy
+            .failed_requirements("correct horse battery staple", &[])
+            .is_empty());
+    }
+
+    // Serve a fake Pwned Passwords range API containing given hashes.
+    async fn mock_pwned_passwords(passwords: &[&str]) -> Url {
+        let hashes: Vec<String> = passwords
+            .iter()
+            .map(|password| to_lower_hex(&Sha1::digest(password.as_bytes())).to_uppercase())
+            .collect();
+        let app = Router::new().route(
+            "/range/:prefix",
+            get(|Path(prefix): Path<String>| async move {
+                let mut lines: Vec<String> = hashes
+                    .iter()
+                    .filter_map(|hash| hash.strip_prefix(&prefix))
+                    .map(|suffix| format!("{suffix}:42"))
+                    .collect();
+                // padding entry
+                lines.push("0000000000000000000000000000000000A:0".into());
+                lines.join("\r\n")
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { serve(listener, app).await.unwrap() });
+        Url::parse(&format!("http://{addr}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_breach_check() {
+        let mut policy = test_policy();
+        policy.breach_check = true;
+        policy.breach_check_url = mock_pwned_passwords(&["Password123!"]).await;
+
+        let err = policy.validate("Password123!", &[]).await.unwrap_err();
+        assert_eq!(err.0, [PasswordRequirement::Breached]);
+        assert_ok!(policy.validate("Unique-Password-42", &[]).await);
+
+        // breach check is skipped when disabled
+        policy.breach_check = false;
+        assert_ok!(policy.validate("Password123!", &[]).await);
+    }
+
+    #[tokio::test]
+    async fn test_breach_check_fails_open() {
+        let mut policy = test_policy();
+        policy.breach_check = true;
+
+        // nothing listens on this port
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        policy.breach_check_url = Url::parse(&format!("http://{addr}")).unwrap();
+        assert_ok!(policy.validate("Password123!", &[]).await);
+
+        // other requirements are still enforced
+        assert_err!(policy.validate("short", &[]).await);
+    }
+}
@@ -0,0 +1,454 @@
+//! PROXY protocol v2 support for web and gRPC servers behind L4 load balancers.
+//!
+//! Load balancers which don't terminate connections make all clients appear to connect
+//! from their address. With PROXY protocol they send a binary header with the original
+//! client address before any connection data, which is then used in place of the peer
+//! address. Headers are only accepted from configured load balancer addresses, which
+//! have to send them. A header from anyone else, or a malformed one, drops the connection.
+
+use std::{
+    future::Future,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::extract::ConnectInfo;
+use axum_server::accept::Accept;
+use ipnetwork::IpNetwork;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tower_http::add_extension::AddExtension;
+
+/// Every v2 header starts with this signature.
+pub const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// signature, version and command, address family and protocol, length of addresses
+const HEADER_LENGTH: usize = 16;
+// How long peers have to send the header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+// How long to wait for more data when it looks like a partial signature
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+// Accepted connections waiting to be picked up by gRPC server
+const INCOMING_BUFFER: usize = 64;
+// Delay after failing to accept a connection, e.g. when out of file descriptors
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+const COMMAND_LOCAL: u8 = 0x0;
+const COMMAND_PROXY: u8 = 0x1;
+const FAMILY_UNSPEC: u8 = 0x0;
+const FAMILY_INET: u8 = 0x1;
+const FAMILY_INET6: u8 = 0x2;
+const FAMILY_UNIX: u8 = 0x3;
+
+#[derive(Debug, Error)]
+pub enum ProxyProtocolError {
+    #[error("Malformed PROXY protocol header: {0}")]
+    Malformed(&'static str),
+    #[error("PROXY protocol header missing")]
+    Missing,
+    #[error("PROXY protocol header sent by untrusted peer")]
+    Untrusted,
+    #[error("Timed out waiting for PROXY protocol header")]
+    Timeout,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Parse source address from the header. `None` means the peer address should be used:
+/// LOCAL command is sent by load balancers for their own connections, e.g. health checks.
+pub fn parse_header(
+    header: &[u8; HEADER_LENGTH],
+    addresses: &[u8],
+) -> Result<Option<SocketAddr>, ProxyProtocolError> {
+    if header[..SIGNATURE.len()] != SIGNATURE {
+        return Err(ProxyProtocolError::Missing);
+    }
+    if header[12] >> 4 != 2 {
+        return Err(ProxyProtocolError::Malformed("unsupported version"));
+    }
+    match header[12] & 0x0f {
+        COMMAND_LOCAL => return Ok(None),
+        COMMAND_PROXY => (),
+        _ => return Err(ProxyProtocolError::Malformed("unknown command")),
+    }
+    let port = |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+    match header[13] >> 4 {
+        FAMILY_INET => {
+            if addresses.len() < 12 {
+                return Err(ProxyProtocolError::Malformed("IPv4 addresses too short"));
+            }
+            let ip: [u8; 4] = addresses[..4].try_into().expect("slice has 4 bytes");
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        FAMILY_INET6 => {
+            if addresses.len() < 36 {
+                return Err(ProxyProtocolError::Malformed("IPv6 addresses too short"));
+            }
+            let ip: [u8; 16] = addresses[..16].try_into().expect("slice has 16 bytes");
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        FAMILY_UNSPEC | FAMILY_UNIX => Ok(None),
+        _ => Err(ProxyProtocolError::Malformed("unknown address family")),
+    }
+}
+
+/// Resolves client addresses of accepted connections, reading PROXY protocol headers
+/// from configured load balancers. Disabled if there are none.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyProtocol {
+    sources: Arc<Vec<IpNetwork>>,
+}
+
+impl ProxyProtocol {
+    #[must_use]
+    pub fn new(sources: Vec<IpNetwork>) -> Self {
+        Self {
+            sources: Arc::new(sources),
+        }
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        !self.sources.is_empty()
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.sources.iter().any(|network| network.contains(ip))
+    }
+
+    /// Client address of a connection from given peer. Header sent by a trusted peer
+    /// is consumed, so that only connection data remains in the stream.
+    pub async fn client_addr(
+        &self,
+        stream: &mut TcpStream,
+        peer: SocketAddr,
+    ) -> Result<SocketAddr, ProxyProtocolError> {
+        if !self.is_enabled() {
+            return Ok(peer);
+        }
+        timeout(HEADER_TIMEOUT, self.read_header(stream, peer))
+            .await
+            .map_err(|_| ProxyProtocolError::Timeout)?
+    }
+
+    async fn read_header(
+        &self,
+        stream: &mut TcpStream,
+        peer: SocketAddr,
+    ) -> Result<SocketAddr, ProxyProtocolError> {
+        if !self.is_trusted(peer.ip()) {
+            return if starts_with_signature(stream).await? {
+                Err(ProxyProtocolError::Untrusted)
+            } else {
+                Ok(peer)
+            };
+        }
+        let mut header = [0; HEADER_LENGTH];
+        stream.read_exact(&mut header).await?;
+        if header[..SIGNATURE.len()] != SIGNATURE {
+            return Err(ProxyProtocolError::Missing);
+        }
+        let length = usize::from(u16::from_be_bytes([header[14], header[15]]));
+        let mut addresses = vec![0; length];
+        stream.read_exact(&mut addresses).await?;
+        Ok(parse_header(&header, &addresses)?.unwrap_or(peer))
+    }
+}
+
+// Peek at connection data until it's clear whether it starts with the signature.
+async fn starts_with_signature(stream: &TcpStream) -> io::Result<bool> {
+    let mut buf = [0; SIGNATURE.len()];
+    loop {
+        let read = stream.peek(&mut buf).await?;
+        if read == 0 || buf[..read] != SIGNATURE[..read] {
+            return Ok(false);
+        }
+        if read == SIGNATURE.len() {
+            return Ok(true);
+        }
+        sleep(PEEK_INTERVAL).await;
+    }
+}
+
+/// Web server acceptor passing client address to handlers as [`ConnectInfo`].
+impl<S> Accept<TcpStream, S> for ProxyProtocol
+where
+    S: Send + 'static,
+{
+    type Stream = TcpStream;
+    type Service = AddExtension<S, ConnectInfo<SocketAddr>>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, mut stream: TcpStream, service: S) -> Self::Future {
+        let proxy_protocol = self.clone();
+        Box::pin(async move {
+            let peer = stream.peer_addr()?;
+            match proxy_protocol.client_addr(&mut stream, peer).await {
+                Ok(addr) => Ok((stream, AddExtension::new(service, ConnectInfo(addr)))),
+                Err(err) => {
+                    warn!("Dropping web connection from {peer}: {err}");
+                    Err(io::Error::new(ErrorKind::InvalidData, err))
+                }
+            }
+        })
+    }
+}
+
+/// Connection accepted by gRPC server, reporting client address as its remote address.
+pub struct ProxiedStream {
+    inner: TcpStream,
+    remote_addr: SocketAddr,
+}
+
+impl Connected for ProxiedStream {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        TcpConnectInfo {
+            local_addr: self.inner.local_addr().ok(),
+            remote_addr: Some(self.remote_addr),
+        }
+    }
+}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Accept gRPC connections, reading headers in the background so that a slow peer
+/// doesn't hold up others.
+pub fn proxied_incoming(
+    listener: TcpListener,
+    proxy_protocol: ProxyProtocol,
+) -> ReceiverStream<io::Result<ProxiedStream>> {
+    let (tx, rx) = mpsc::channel(INCOMING_BUFFER);
+    tokio::spawn(async move {
+        while !tx.is_closed() {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("Failed to accept gRPC connection: {err}");
+                    sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            let tx = tx.clone();
+            let proxy_protocol = proxy_protocol.clone();
+            tokio::spawn(async move {
+                match proxy_protocol.client_addr(&mut stream, peer).await {
+                    Ok(remote_addr) => {
+                        let stream = ProxiedStream {
+                            inner: stream,
+                            remote_addr,
+                        };
+                        // server is shutting down otherwise
+                        let _ = tx.send(Ok(stream)).await;
+                    }
+                    Err(err) => warn!("Dropping gRPC connection from {peer}: {err}"),
+                }
+            });
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{routing::get, Router};
+    use tokio::io::AsyncWriteExt;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    fn header(source: SocketAddr) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.push(0x20 | COMMAND_PROXY);
+        match source {
+            SocketAddr::V4(source) => {
+                header.push((FAMILY_INET << 4) | 0x1);
+                header.extend_from_slice(&12_u16.to_be_bytes());
+                header.extend_from_slice(&source.ip().octets());
+                header.extend_from_slice(&[10, 0, 0, 1]);
+                header.extend_from_slice(&source.port().to_be_bytes());
+                header.extend_from_slice(&443_u16.to_be_bytes());
+            }
+            SocketAddr::V6(source) => {
+                header.push((FAMILY_INET6 << 4) | 0x1);
+                header.extend_from_slice(&36_u16.to_be_bytes());
+                header.extend_from_slice(&source.ip().octets());
+                header.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+                header.extend_from_slice(&source.port().to_be_bytes());
+                header.extend_from_slice(&443_u16.to_be_bytes());
+            }
+        }
+        header
+    }
+
+    fn split(header: &[u8]) -> ([u8; HEADER_LENGTH], &[u8]) {
+        (
+            header[..HEADER_LENGTH].try_into().unwrap(),
+            &header[HEADER_LENGTH..],
+        )
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let source: SocketAddr = "203.0.113.7:4242".parse().unwrap();
+        let encoded = header(source);
+        let (fixed, addresses) = split(&encoded);
+        assert_eq!(parse_header(&fixed, addresses).unwrap(), Some(source));
+
+        let source: SocketAddr = "[2001:db8::7]:4242".parse().unwrap();
+        let encoded = header(source);
+        let (fixed, addresses) = split(&encoded);
+        assert_eq!(parse_header(&fixed, addresses).unwrap(), Some(source));
+
+        // health checks of load balancer itself
+        let mut local = fixed;
+        local[12] = 0x20 | COMMAND_LOCAL;
+        assert_eq!(parse_header(&local, &[]).unwrap(), None);
+
+        let mut version_one = fixed;
+        version_one[12] = 0x10 | COMMAND_PROXY;
+        assert!(matches!(
+            parse_header(&version_one, addresses),
+            Err(ProxyProtocolError::Malformed(_))
+        ));
+        assert!(matches!(
+            parse_header(&fixed, &addresses[..20]),
+            Err(ProxyProtocolError::Malformed(_))
+        ));
+        let mut unsigned = fixed;
+        unsigned[0] = b'G';
+        assert!(matches!(
+            parse_header(&unsigned, addresses),
+            Err(ProxyProtocolError::Missing)
+        ));
+    }
+
+    // Serve web app echoing client address seen by handlers.
+    async fn echo_server(proxy_protocol: ProxyProtocol) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/",
+            get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.to_string() }),
+        );
+        tokio::spawn(
+            axum_server::from_tcp(listener)
+                .acceptor(proxy_protocol)
+                .serve(app.into_make_service()),
+        );
+        addr
+    }
+
+    // Send a request, optionally preceded by a header, and return the response.
+    async fn request(addr: SocketAddr, header: Option<Vec<u8>>) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        if let Some(header) = header {
+            stream.write_all(&header).await.unwrap();
+        }
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        // connection reset counts as closed
+        let _ = stream.read_to_string(&mut response).await;
+        response
+    }
+
+    #[tokio::test]
+    async fn test_trusted_load_balancer() {
+        let addr = echo_server(ProxyProtocol::new(vec!["127.0.0.0/8".parse().unwrap()])).await;
+        let source: SocketAddr = "203.0.113.7:4242".parse().unwrap();
+        let response = request(addr, Some(header(source))).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("203.0.113.7:4242"));
+
+        // load balancer has to send the header
+        assert_eq!(request(addr, None).await, "");
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_peer() {
+        let addr = echo_server(ProxyProtocol::new(vec!["10.0.0.0/8".parse().unwrap()])).await;
+        let source: SocketAddr = "203.0.113.7:4242".parse().unwrap();
+        assert_eq!(request(addr, Some(header(source))).await, "");
+
+        // connections without the header are served as usual
+        let response = request(addr, None).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("127.0.0.1:"));
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let addr = echo_server(ProxyProtocol::default()).await;
+        let response = request(addr, None).await;
+        assert!(response.contains("127.0.0.1:"));
+    }
+
+    #[tokio::test]
+    async fn test_grpc_incoming() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = proxied_incoming(
+            listener,
+            ProxyProtocol::new(vec!["127.0.0.1/32".parse().unwrap()]),
+        );
+
+        // malformed header is dropped, next connection goes through
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"PROXY TCP4 v1 is not supported\r\n")
+            .await
+            .unwrap();
+        let source: SocketAddr = "[2001:db8::7]:4242".parse().unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&header(source)).await.unwrap();
+        stream.write_all(b"data").await.unwrap();
+
+        let mut accepted = incoming.next().await.unwrap().unwrap();
+        assert_eq!(accepted.connect_info().remote_addr, Some(source));
+        let mut data = [0; 4];
+        accepted.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"data");
+    }
+}
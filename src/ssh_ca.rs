@@ -0,0 +1,320 @@
+//! SSH certificate authority issuing short-lived user certificates.
+//!
+//! Users get certificates for SSH keys registered as their authentication keys. Principals
+//! are the username and ones mapped from group memberships in settings. Issued
+//! certificates are recorded for audit; revoked ones are published as an OpenSSH key
+//! revocation list (KRL), in the format described in `PROTOCOL.krl` of OpenSSH sources.
+//!
+//! The CA key is read from the file set in configuration. Otherwise an Ed25519 key
+//! is generated on first use and stored in the `ssh_ca_key` table, encrypted with
+//! the key-encryption key from configuration.
+
+use std::{collections::HashMap, fs::read_to_string, path::Path};
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand_core::OsRng;
+use sqlx::{query, query_scalar, Error as SqlxError};
+use ssh_key::{
+    certificate::{Builder, CertType},
+    Algorithm, HashAlg, LineEnding, PrivateKey, PublicKey,
+};
+use thiserror::Error;
+
+use crate::{
+    db::{models::ssh_certificate::SshCertificate, DbPool, Settings},
+    key_escrow::{KeyEncryptionKey, KeyEscrowError},
+    server_config,
+};
+
+// extensions `ssh-keygen` grants by default
+const EXTENSIONS: [&str; 5] = [
+    "permit-X11-forwarding",
+    "permit-agent-forwarding",
+    "permit-port-forwarding",
+    "permit-pty",
+    "permit-user-rc",
+];
+// KRL format constants, see `PROTOCOL.krl`
+const KRL_MAGIC: &[u8; 8] = b"SSHKRL\n\0";
+const KRL_FORMAT_VERSION: u32 = 1;
+const KRL_SECTION_CERTIFICATES: u8 = 1;
+const KRL_SECTION_CERT_SERIAL_LIST: u8 = 0x20;
+// binds the encrypted CA key to its purpose
+const CA_KEY_ENCRYPTION_CONTEXT: &[u8] = b"ssh-ca-key";
+
+#[derive(Debug, Error)]
+pub enum SshCaError {
+    #[error(transparent)]
+    DbError(#[from] SqlxError),
+    #[error("SSH key error: {0}")]
+    KeyError(#[from] ssh_key::Error),
+    #[error("Failed to read SSH CA key file: {0}")]
+    KeyFileError(#[from] std::io::Error),
+    #[error("SSH CA key file is encrypted")]
+    EncryptedKey,
+    #[error(transparent)]
+    KeyEncryptionError(#[from] KeyEscrowError),
+}
+
+/// Check certificate validity bound and principal mapping.
+pub fn validate_settings(settings: &Settings) -> Result<(), String> {
+    if settings.ssh_ca_max_validity_minutes < 1 {
+        return Err("SSH certificate validity must be at least one minute".into());
+    }
+    if let Some(mapping) = &settings.ssh_ca_principal_mapping {
+        parse_principal_mapping(mapping)?;
+    }
+    Ok(())
+}
+
+/// Parse group to principals mapping, one `group: principal, principal` entry per line.
+pub fn parse_principal_mapping(mapping: &str) -> Result<HashMap<&str, Vec<&str>>, String> {
+    let mut groups: HashMap<&str, Vec<&str>> = HashMap::new();
+    for line in mapping
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        let Some((group, principals)) = line.split_once(':') else {
+            return Err(format!(
+                "Invalid SSH principal mapping entry \"{line}\", expected \"group: principal, ...\""
+            ));
+        };
+        let group = group.trim();
+        if group.is_empty() {
+            return Err(format!(
+                "Missing group name in SSH principal mapping entry \"{line}\""
+            ));
+        }
+        let entry = groups.entry(group).or_default();
+        for principal in principals.split(',').map(str::trim) {
+            if principal.is_empty() || principal.contains(char::is_whitespace) {
+                return Err(format!(
+                    "Invalid principal in SSH principal mapping entry \"{line}\""
+                ));
+            }
+            entry.push(principal);
+        }
+    }
+    Ok(groups)
+}
+
+/// Principals of a user: the username, followed by principals mapped from their groups.
+#[must_use]
+pub fn user_principals(username: &str, groups: &[String], mapping: Option<&str>) -> Vec<String> {
+    let mapping = mapping
+        .and_then(|mapping| parse_principal_mapping(mapping).ok())
+        .unwrap_or_default();
+    let mut principals = vec![username.to_string()];
+    for principal in groups
+        .iter()
+        .filter_map(|group| mapping.get(group.as_str()))
+        .flatten()
+    {
+        if !principals.iter().any(|known| known == principal) {
+            principals.push((*principal).to_string());
+        }
+    }
+    principals
+}
+
+/// Read CA key from an unencrypted OpenSSH private key file.
+pub fn read_ca_key_file(path: &Path) -> Result<PrivateKey, SshCaError> {
+    let key = PrivateKey::from_openssh(read_to_string(path)?)?;
+    if key.is_encrypted() {
+        return Err(SshCaError::EncryptedKey);
+    }
+    Ok(key)
+}
+
+/// CA key set in configuration, or the stored one. If `generate` is set, a key is generated
+/// when there's none yet; otherwise `None` is returned.
+pub async fn ca_key(pool: &DbPool, generate: bool) -> Result<Option<PrivateKey>, SshCaError> {
+    if let Some(path) = &server_config().ssh_ca_key {
+        return read_ca_key_file(path).map(Some);
+    }
+    let stored = query_scalar!("SELECT encrypted_private_key FROM ssh_ca_key")
+        .fetch_optional(pool)
+        .await?;
+    let (stored, generated) = match stored {
+        Some(key) => (key, false),
+        None if generate => {
+            let key_encryption_key = KeyEncryptionKey::from_config()?;
+            let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)?;
+            let encrypted = key_encryption_key
+                .encrypt_for(CA_KEY_ENCRYPTION_CONTEXT, &key.to_openssh(LineEnding::LF)?)?;
+            // other instances may be doing the same, the first stored key is used
+            query!(
+                "INSERT INTO ssh_ca_key (encrypted_private_key) VALUES ($1) \
+                ON CONFLICT (id) DO NOTHING",
+                encrypted
+            )
+            .execute(pool)
+            .await?;
+            let stored = query_scalar!("SELECT encrypted_private_key FROM ssh_ca_key")
+                .fetch_one(pool)
+                .await?;
+            (stored, true)
+        }
+        None => return Ok(None),
+    };
+    let key = KeyEncryptionKey::from_config()?.decrypt_for(CA_KEY_ENCRYPTION_CONTEXT, &stored)?;
+    let key = PrivateKey::from_openssh(key)?;
+    if generated {
+        info!(
+            "Generated SSH certificate authority key {}",
+            key.public_key().fingerprint(HashAlg::Sha256)
+        );
+    }
+    Ok(Some(key))
+}
+
+/// Sign a user certificate for `public_key`, valid from now for `validity`,
+/// and record it. Returns the record and the certificate in OpenSSH format.
+pub async fn issue_certificate(
+    pool: &DbPool,
+    ca_key: &PrivateKey,
+    user_id: i64,
+    username: &str,
+    public_key: &PublicKey,
+    principals: Vec<String>,
+    validity: Duration,
+) -> Result<(SshCertificate, String), SshCaError> {
+    let now = Utc::now().naive_utc();
+    let valid_before = now + validity;
+    let mut transaction = pool.begin().await?;
+    let serial = SshCertificate::next_serial(&mut *transaction).await?;
+    let key_id = format!("{username}-{serial}");
+
+    let mut builder = Builder::new_with_random_nonce(
+        &mut OsRng,
+        public_key.key_data().clone(),
+        unix_timestamp(now),
+        unix_timestamp(valid_before),
+    )?;
+    builder
+        .serial(serial as u64)?
+        .key_id(&key_id)?
+        .cert_type(CertType::User)?;
+    for principal in &principals {
+        builder.valid_principal(principal)?;
+    }
+    for extension in EXTENSIONS {
+        builder.extension(extension, "")?;
+    }
+    let certificate = builder.sign(ca_key)?.to_openssh()?;
+
+    let record = SshCertificate {
+        id: Some(serial),
+        user_id: Some(user_id),
+        key_id,
+        principals,
+        public_key: public_key.to_openssh()?,
+        valid_after: now,
+        valid_before,
+        issued_at: now,
+        revoked_at: None,
+    };
+    record.save(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok((record, certificate))
+}
+
+fn unix_timestamp(time: NaiveDateTime) -> u64 {
+    time.and_utc().timestamp().max(0) as u64
+}
+
+// SSH wire format string: length followed by data
+fn put_string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// Build OpenSSH key revocation list revoking given certificate serial numbers.
+/// Without a CA key the list is empty.
+pub fn build_krl(
+    ca_key: Option<&PublicKey>,
+    serials: &[i64],
+    version: u64,
+    generated_at: NaiveDateTime,
+) -> Result<Vec<u8>, SshCaError> {
+    let mut krl = Vec::new();
+    krl.extend_from_slice(KRL_MAGIC);
+    krl.extend_from_slice(&KRL_FORMAT_VERSION.to_be_bytes());
+    krl.extend_from_slice(&version.to_be_bytes());
+    krl.extend_from_slice(&unix_timestamp(generated_at).to_be_bytes());
+    // flags
+    krl.extend_from_slice(&0u64.to_be_bytes());
+    // reserved
+    put_string(&mut krl, &[]);
+    // comment
+    put_string(&mut krl, b"defguard");
+
+    if let (Some(ca_key), false) = (ca_key, serials.is_empty()) {
+        let mut serial_list = Vec::with_capacity(serials.len() * 8);
+        for serial in serials {
+            serial_list.extend_from_slice(&(*serial as u64).to_be_bytes());
+        }
+        let mut section = Vec::new();
+        put_string(&mut section, &ca_key.to_bytes()?);
+        // reserved
+        put_string(&mut section, &[]);
+        section.push(KRL_SECTION_CERT_SERIAL_LIST);
+        put_string(&mut section, &serial_list);
+
+        krl.push(KRL_SECTION_CERTIFICATES);
+        put_string(&mut krl, &section);
+    }
+    Ok(krl)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_principal_mapping() {
+        let mapping = "admin: root, ops\n\n  developers : deploy\nops: ops";
+        let parsed = parse_principal_mapping(mapping).unwrap();
+        assert_eq!(parsed["admin"], ["root", "ops"]);
+        assert_eq!(parsed["developers"], ["deploy"]);
+
+        let principals = user_principals(
+            "hpotter",
+            &["admin".into(), "ops".into(), "other".into()],
+            Some(mapping),
+        );
+        assert_eq!(principals, ["hpotter", "root", "ops"]);
+        assert_eq!(user_principals("hpotter", &[], None), ["hpotter"]);
+
+        assert!(parse_principal_mapping("admin root").is_err());
+        assert!(parse_principal_mapping(": root").is_err());
+        assert!(parse_principal_mapping("admin: root,").is_err());
+        assert!(parse_principal_mapping("admin: root user").is_err());
+    }
+
+    #[test]
+    fn test_krl_format() {
+        let ca_key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let now = Utc::now().naive_utc();
+
+        let empty = build_krl(Some(ca_key.public_key()), &[], 1, now).unwrap();
+        assert!(empty.starts_with(KRL_MAGIC));
+        // header only: magic, version, krl version, date, flags, reserved, comment
+        assert_eq!(empty.len(), 8 + 4 + 8 + 8 + 8 + 4 + 4 + 8);
+
+        let krl = build_krl(Some(ca_key.public_key()), &[3, 7], 2, now).unwrap();
+        let key_blob = ca_key.public_key().to_bytes().unwrap();
+        let section = &krl[empty.len()..];
+        assert_eq!(section[0], KRL_SECTION_CERTIFICATES);
+        let section_len = u32::from_be_bytes(section[1..5].try_into().unwrap()) as usize;
+        assert_eq!(section.len(), 5 + section_len);
+        let section = &section[5..];
+        assert_eq!(&section[4..4 + key_blob.len()], key_blob.as_slice());
+        let serials = &section[4 + key_blob.len() + 4..];
+        assert_eq!(serials[0], KRL_SECTION_CERT_SERIAL_LIST);
+        assert_eq!(&serials[1..5], &16u32.to_be_bytes());
+        assert_eq!(&serials[5..13], &3u64.to_be_bytes());
+        assert_eq!(&serials[13..], &7u64.to_be_bytes());
+    }
+}
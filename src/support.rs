@@ -22,6 +22,8 @@ pub async fn dump_config(db: &DbPool) -> Value {
     let settings = match Settings::find_by_id(db, 1).await {
         Ok(Some(mut settings)) => {
             settings.smtp_password = None;
+            settings.dns_tsig_secret = None;
+            settings.dns_webhook_secret = None;
             json!(settings)
         }
         Ok(None) => json!({"error": "Settings not found"}),
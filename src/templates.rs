@@ -18,6 +18,12 @@ static MAIL_ENROLLMENT_ADMIN_NOTIFICATION: &str =
     include_str!("../templates/mail_enrollment_admin_notification.tera");
 static MAIL_SUPPORT_DATA: &str = include_str!("../templates/mail_support_data.tera");
 static MAIL_NEW_DEVICE_ADDED: &str = include_str!("../templates/mail_new_device_added.tera");
+static MAIL_DEVICE_TRANSFERRED: &str = include_str!("../templates/mail_device_transferred.tera");
+static MAIL_PSK_ROTATION: &str = include_str!("../templates/mail_psk_rotation.tera");
+static MAIL_NEW_COUNTRY_CONNECTION: &str =
+    include_str!("../templates/mail_new_country_connection.tera");
+static MAIL_SHARED_CONFIG_DOWNLOADED: &str =
+    include_str!("../templates/mail_shared_config_downloaded.tera");
 static MAIL_GATEWAY_DISCONNECTED: &str =
     include_str!("../templates/mail_gateway_disconnected.tera");
 static MAIL_MFA_CONFIGURED: &str = include_str!("../templates/mail_mfa_configured.tera");
@@ -31,6 +37,12 @@ static MAIL_PASSWORD_RESET_START: &str =
     include_str!("../templates/mail_password_reset_start.tera");
 static MAIL_PASSWORD_RESET_SUCCESS: &str =
     include_str!("../templates/mail_password_reset_success.tera");
+static MAIL_TOKEN_LOCKED: &str = include_str!("../templates/mail_token_locked.tera");
+static MAIL_MFA_METHODS_DISALLOWED: &str =
+    include_str!("../templates/mail_mfa_methods_disallowed.tera");
+static MAIL_BREAK_GLASS_LOGIN: &str = include_str!("../templates/mail_break_glass_login.tera");
+static MAIL_GUEST_ACCESS_EXPIRING: &str =
+    include_str!("../templates/mail_guest_access_expiring.tera");
 
 #[allow(dead_code)]
 static MAIL_DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:00Z";
@@ -193,6 +205,136 @@ pub fn new_device_added_mail(
     Ok(tera.render("mail_new_device_added", &context)?)
 }
 
+/// Notify a device owner about device being transferred from or to their account.
+pub fn device_transferred_mail(
+    device_name: &str,
+    public_key: &str,
+    previous_owner: &str,
+    new_owner: &str,
+    received: bool,
+) -> Result<String, TemplateError> {
+    let (mut tera, mut context) = get_base_tera(None, None, None, None)?;
+    context.insert("device_name", device_name);
+    context.insert("public_key", public_key);
+    context.insert("previous_owner", previous_owner);
+    context.insert("new_owner", new_owner);
+    context.insert("received", &received);
+
+    tera.add_raw_template("mail_device_transferred", MAIL_DEVICE_TRANSFERRED)?;
+    Ok(tera.render("mail_device_transferred", &context)?)
+}
+
+/// Notify token owner or issuing admin that the token was invalidated because of guessing attempts.
+pub fn token_locked_mail(
+    username: &str,
+    token_kind: &str,
+    to_admin: bool,
+    ip_address: Option<&str>,
+) -> Result<String, TemplateError> {
+    let (mut tera, mut context) = get_base_tera(None, None, ip_address, None)?;
+    context.insert("username", username);
+    context.insert("token_kind", token_kind);
+    context.insert("to_admin", &to_admin);
+
+    tera.add_raw_template("mail_token_locked", MAIL_TOKEN_LOCKED)?;
+    Ok(tera.render("mail_token_locked", &context)?)
+}
+
+/// Ask a device owner to switch to a new preshared key.
+pub fn psk_rotation_mail(
+    device_name: &str,
+    location_name: &str,
+    fingerprint: &str,
+    deadline: &NaiveDateTime,
+) -> Result<String, TemplateError> {
+    let (mut tera, mut context) = get_base_tera(None, None, None, None)?;
+    context.insert("device_name", device_name);
+    context.insert("location_name", location_name);
+    context.insert("fingerprint", fingerprint);
+    context.insert(
+        "deadline",
+        &deadline.format("%Y-%m-%d %H:%M UTC").to_string(),
+    );
+
+    tera.add_raw_template("mail_psk_rotation", MAIL_PSK_ROTATION)?;
+    Ok(tera.render("mail_psk_rotation", &context)?)
+}
+
+/// Warn a device owner about the device connecting from a country it hasn't been seen in.
+pub fn new_country_connection_mail(
+    device_name: &str,
+    location_name: &str,
+    country: &str,
+    endpoint_ip: &str,
+    connected_at: &NaiveDateTime,
+) -> Result<String, TemplateError> {
+    let (mut tera, mut context) = get_base_tera(None, None, None, None)?;
+    context.insert("device_name", device_name);
+    context.insert("location_name", location_name);
+    context.insert("country", country);
+    context.insert("endpoint_ip", endpoint_ip);
+    context.insert(
+        "connected_at",
+        &connected_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+    );
+
+    tera.add_raw_template("mail_new_country_connection", MAIL_NEW_COUNTRY_CONNECTION)?;
+    Ok(tera.render("mail_new_country_connection", &context)?)
+}
+
+/// Tell a device owner that the device config has been downloaded using a one-time link.
+pub fn shared_config_downloaded_mail(
+    device_name: &str,
+    location_name: &str,
+    ip_address: &str,
+    downloaded_at: &NaiveDateTime,
+) -> Result<String, TemplateError> {
+    let (mut tera, mut context) = get_base_tera(None, None, None, None)?;
+    context.insert("device_name", device_name);
+    context.insert("location_name", location_name);
+    context.insert("ip_address", ip_address);
+    context.insert(
+        "downloaded_at",
+        &downloaded_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+    );
+
+    tera.add_raw_template(
+        "mail_shared_config_downloaded",
+        MAIL_SHARED_CONFIG_DOWNLOADED,
+    )?;
+    Ok(tera.render("mail_shared_config_downloaded", &context)?)
+}
+
+fn join_mfa_methods(methods: &[MFAMethod]) -> String {
+    methods
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Ask a user to switch from MFA methods which are no longer allowed before `deadline`.
+/// Without a deadline, tell the user these methods have been removed and what's left,
+/// see the template for `fallback` values.
+pub fn mfa_methods_disallowed_mail(
+    methods: &[MFAMethod],
+    allowed_methods: &[MFAMethod],
+    deadline: Option<&NaiveDateTime>,
+    fallback: &str,
+) -> Result<String, TemplateError> {
+    let (mut tera, mut context) = get_base_tera(None, None, None, None)?;
+    context.insert("methods", &join_mfa_methods(methods));
+    context.insert("allowed_methods", &join_mfa_methods(allowed_methods));
+    context.insert(
+        "deadline",
+        &deadline.map(|deadline| deadline.format("%Y-%m-%d %H:%M UTC").to_string()),
+    );
+    context.insert("fallback", fallback);
+
+    tera.add_raw_template("mail_mfa_methods_disallowed", MAIL_MFA_METHODS_DISALLOWED)?;
+    Ok(tera.render("mail_mfa_methods_disallowed", &context)?)
+}
+
 pub fn mfa_configured_mail(
     session: Option<&Session>,
     method: &MFAMethod,
@@ -249,6 +391,31 @@ pub fn gateway_disconnected_mail(
     Ok(tera.render("mail_gateway_disconnected", &context)?)
 }
 
+pub fn break_glass_login_mail(username: &str, ip_address: &str) -> Result<String, TemplateError> {
+    let (mut tera, mut context) = get_base_tera(None, None, None, None)?;
+    context.insert("username", username);
+    context.insert("ip_address", ip_address);
+    tera.add_raw_template("mail_break_glass_login", MAIL_BREAK_GLASS_LOGIN)?;
+    Ok(tera.render("mail_break_glass_login", &context)?)
+}
+
+/// Remind a sponsor that guest access they created is about to expire.
+pub fn guest_access_expiring_mail(
+    username: &str,
+    location_name: &str,
+    expires_at: &NaiveDateTime,
+) -> Result<String, TemplateError> {
+    let (mut tera, mut context) = get_base_tera(None, None, None, None)?;
+    context.insert("username", username);
+    context.insert("location_name", location_name);
+    context.insert(
+        "expires_at",
+        &expires_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+    );
+    tera.add_raw_template("mail_guest_access_expiring", MAIL_GUEST_ACCESS_EXPIRING)?;
+    Ok(tera.render("mail_guest_access_expiring", &context)?)
+}
+
 pub fn email_mfa_activation_mail(code: u32, session: &Session) -> Result<String, TemplateError> {
     let (mut tera, mut context) = get_base_tera(None, Some(session), None, None)?;
     let timeout = server_config().mfa_code_timeout;
@@ -351,7 +518,7 @@ mod test {
 
     #[test]
     fn test_enrollment_start_mail() {
-        SERVER_CONFIG.set(DefGuardConfig::default()).unwrap();
+        let _ = SERVER_CONFIG.set(DefGuardConfig::default());
         assert_ok!(enrollment_start_mail(
             Context::new(),
             Url::parse("http://localhost:8080").unwrap(),
@@ -396,6 +563,87 @@ mod test {
             None,
         ));
     }
+
+    #[test]
+    fn test_device_transferred_mail() {
+        let mail = device_transferred_mail("Test device", "TestKey", "alice", "bob", true).unwrap();
+        assert!(mail.contains("transferred to your account"));
+        let mail =
+            device_transferred_mail("Test device", "TestKey", "alice", "bob", false).unwrap();
+        assert!(mail.contains("transferred from your account"));
+    }
+
+    #[test]
+    fn test_new_country_connection_mail() {
+        let connected_at = NaiveDateTime::default();
+        let mail =
+            new_country_connection_mail("Test device", "office", "PL", "2.16.0.1", &connected_at)
+                .unwrap();
+        assert!(mail.contains("hasn't connected from before"));
+        assert!(mail.contains("2.16.0.1"));
+        assert!(mail.contains("1970-01-01 00:00 UTC"));
+    }
+
+    #[test]
+    fn test_shared_config_downloaded_mail() {
+        let downloaded_at = NaiveDateTime::default();
+        let mail =
+            shared_config_downloaded_mail("Test device", "office", "10.0.0.1", &downloaded_at)
+                .unwrap();
+        assert!(mail.contains("downloaded using a one-time link"));
+        assert!(mail.contains("10.0.0.1"));
+        assert!(mail.contains("1970-01-01 00:00 UTC"));
+    }
+
+    #[test]
+    fn test_token_locked_mail() {
+        let mail = token_locked_mail("hpotter", "enrollment", false, Some("10.0.0.1")).unwrap();
+        assert!(mail.contains("Your enrollment token has been invalidated"));
+        assert!(mail.contains("10.0.0.1"));
+        let mail = token_locked_mail("hpotter", "password reset", true, None).unwrap();
+        assert!(mail.contains("password reset token you issued for user hpotter"));
+    }
+
+    #[test]
+    fn test_mfa_methods_disallowed_mail() {
+        let deadline = NaiveDateTime::default();
+        let mail = mfa_methods_disallowed_mail(
+            &[MFAMethod::Email],
+            &[MFAMethod::OneTimePassword, MFAMethod::Webauthn],
+            Some(&deadline),
+            "methods",
+        )
+        .unwrap();
+        assert!(mail.contains("which you have set up: Email"));
+        assert!(mail.contains("TOTP, WebAuthn"));
+        assert!(mail.contains("1970-01-01 00:00 UTC"));
+        let mail = mfa_methods_disallowed_mail(
+            &[MFAMethod::Email],
+            &[MFAMethod::OneTimePassword],
+            None,
+            "recovery_codes",
+        )
+        .unwrap();
+        assert!(mail.contains("have been removed from your account"));
+        assert!(mail.contains("Log in with one of your recovery codes"));
+    }
+
+    #[test]
+    fn test_break_glass_login_mail() {
+        let mail = break_glass_login_mail("breakglass", "10.0.0.1").unwrap();
+        assert!(mail.contains("breakglass"));
+        assert!(mail.contains("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_guest_access_expiring_mail() {
+        let expires_at = NaiveDateTime::default();
+        let mail = guest_access_expiring_mail("guest", "office", &expires_at).unwrap();
+        assert!(mail.contains("Guest access you sponsored is about to expire"));
+        assert!(mail.contains("office"));
+        assert!(mail.contains("1970-01-01 00:00 UTC"));
+    }
+
     #[test]
     fn test_gateway_disconnected() {
         assert_ok!(gateway_disconnected_mail(
@@ -0,0 +1,337 @@
+//! TLS termination of the web server, for installs exposed without a reverse proxy.
+//!
+//! Certificate and key files are checked for changes periodically, and a changed pair
+//! replaces the server configuration without a restart. Established connections keep
+//! the certificate they were accepted with; only new connections get the new one.
+//! If the new pair is invalid, e.g. the key hasn't been written yet, the previous one
+//! stays in use until the next change.
+
+use std::{
+    fs::read,
+    io::Error as IoError,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::Router;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use chrono::{DateTime, NaiveDateTime};
+use rustls::{
+    crypto::{ring::default_provider, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer},
+    sign::CertifiedKey,
+    Error as RustlsError, ServerConfig,
+};
+use thiserror::Error;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::proxy_protocol::ProxyProtocol;
+
+#[derive(Debug, Error)]
+pub enum TlsConfigError {
+    #[error("Failed to read {0}: {1}")]
+    Read(PathBuf, IoError),
+    #[error("No PEM encoded certificate found in {0}")]
+    NoCertificate(PathBuf),
+    #[error("No PEM encoded private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("Certificate in {0} can't be parsed")]
+    InvalidCertificate(PathBuf),
+    #[error("Private key in {key} doesn't match certificate in {cert}")]
+    KeyMismatch { cert: PathBuf, key: PathBuf },
+    #[error("Invalid TLS configuration: {0}")]
+    Rustls(#[from] RustlsError),
+}
+
+/// Certificate chain and private key read from PEM files.
+pub struct TlsIdentity {
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    /// Expiration of the leaf certificate.
+    pub not_after: NaiveDateTime,
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, TlsConfigError> {
+    read(path).map_err(|err| TlsConfigError::Read(path.into(), err))
+}
+
+impl TlsIdentity {
+    /// Parse certificate chain and key from PEM encoded file contents, checking that
+    /// the key belongs to the leaf certificate. Paths are used in errors only.
+    pub fn from_pem(
+        cert_path: &Path,
+        cert_pem: &[u8],
+        key_path: &Path,
+        key_pem: &[u8],
+    ) -> Result<Self, TlsConfigError> {
+        let certs = rustls_pemfile::certs(&mut &cert_pem[..])
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| TlsConfigError::Read(cert_path.into(), err))?;
+        let Some(leaf) = certs.first() else {
+            return Err(TlsConfigError::NoCertificate(cert_path.into()));
+        };
+        let not_after = x509_parser::parse_x509_certificate(leaf)
+            .ok()
+            .and_then(|(_, cert)| {
+                DateTime::from_timestamp(cert.validity().not_after.timestamp(), 0)
+            })
+            .ok_or_else(|| TlsConfigError::InvalidCertificate(cert_path.into()))?
+            .naive_utc();
+        let key = rustls_pemfile::private_key(&mut &key_pem[..])
+            .map_err(|err| TlsConfigError::Read(key_path.into(), err))?
+            .ok_or_else(|| TlsConfigError::NoPrivateKey(key_path.into()))?;
+
+        let signing_key = provider().key_provider.load_private_key(key.clone_key())?;
+        if CertifiedKey::new(certs.clone(), signing_key)
+            .keys_match()
+            .is_err()
+        {
+            return Err(TlsConfigError::KeyMismatch {
+                cert: cert_path.into(),
+                key: key_path.into(),
+            });
+        }
+
+        Ok(Self {
+            certs,
+            key,
+            not_after,
+        })
+    }
+
+    /// Read certificate chain and key from PEM files.
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, TlsConfigError> {
+        Self::from_pem(
+            cert_path,
+            &read_file(cert_path)?,
+            key_path,
+            &read_file(key_path)?,
+        )
+    }
+
+    fn server_config(self) -> Result<ServerConfig, TlsConfigError> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(self.certs, self.key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn provider() -> CryptoProvider {
+    default_provider()
+}
+
+/// Periodically compare certificate and key files with the ones in use,
+/// and replace server configuration if they changed.
+async fn reload_on_change(
+    config: RustlsConfig,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    mut current: (Vec<u8>, Vec<u8>),
+    reload_interval: Duration,
+) {
+    let mut ticker = interval(reload_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // first tick completes immediately
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let files = match (read_file(&cert_path), read_file(&key_path)) {
+            (Ok(cert), Ok(key)) => (cert, key),
+            (Err(err), _) | (_, Err(err)) => {
+                warn!("Keeping current TLS certificate: {err}");
+                continue;
+            }
+        };
+        if files == current {
+            continue;
+        }
+        let result =
+            TlsIdentity::from_pem(&cert_path, &files.0, &key_path, &files.1).and_then(|identity| {
+                let not_after = identity.not_after;
+                identity.server_config().map(|server| (server, not_after))
+            });
+        match result {
+            Ok((server, not_after)) => {
+                config.reload_from_config(Arc::new(server));
+                info!(
+                    "Reloaded TLS certificate from {}, valid until {not_after}",
+                    cert_path.display()
+                );
+            }
+            Err(err) => error!("Failed to reload TLS certificate, keeping current one: {err}"),
+        }
+        // invalid pair is retried once any of the files changes again
+        current = files;
+    }
+}
+
+/// Serve web app over HTTPS, reloading certificate and key when the files change.
+/// Fails right away if they can't be loaded. PROXY protocol header precedes TLS handshake.
+pub async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    reload_interval: Duration,
+    proxy_protocol: ProxyProtocol,
+) -> Result<(), anyhow::Error> {
+    let files = (read_file(&cert_path)?, read_file(&key_path)?);
+    let identity = TlsIdentity::from_pem(&cert_path, &files.0, &key_path, &files.1)?;
+    info!(
+        "Serving HTTPS with certificate from {}, valid until {}",
+        cert_path.display(),
+        identity.not_after
+    );
+    let config = RustlsConfig::from_config(Arc::new(identity.server_config()?));
+    tokio::spawn(reload_on_change(
+        config.clone(),
+        cert_path,
+        key_path,
+        files,
+        reload_interval,
+    ));
+    listener.set_nonblocking(true)?;
+    axum_server::from_tcp(listener)
+        .acceptor(RustlsAcceptor::new(config).acceptor(proxy_protocol))
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env::temp_dir, fs::write, net::SocketAddr};
+
+    use axum::routing::get;
+    use rcgen::generate_simple_self_signed;
+    use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::sleep,
+    };
+    use tokio_rustls::{client::TlsStream, TlsConnector};
+
+    use super::*;
+    use crate::random::gen_alphanumeric;
+
+    const RELOAD_INTERVAL: Duration = Duration::from_millis(50);
+
+    fn self_signed() -> (CertificateDer<'static>, String, String) {
+        let generated = generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        (
+            generated.cert.der().clone(),
+            generated.cert.pem(),
+            generated.key_pair.serialize_pem(),
+        )
+    }
+
+    // connect trusting only given certificate
+    async fn connect(
+        addr: SocketAddr,
+        trusted: &CertificateDer<'static>,
+    ) -> Result<TlsStream<TcpStream>, IoError> {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.clone()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await?;
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+    }
+
+    async fn get_status_line(stream: &mut TlsStream<TcpStream>) -> String {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = vec![0; 1024];
+        let len = stream.read(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response[..len])
+            .lines()
+            .next()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_identity_validation() {
+        let (_, cert, key) = self_signed();
+        let (_, _, other_key) = self_signed();
+        let (cert_path, key_path) = (Path::new("cert.pem"), Path::new("key.pem"));
+        assert!(
+            TlsIdentity::from_pem(cert_path, cert.as_bytes(), key_path, key.as_bytes()).is_ok()
+        );
+        assert!(matches!(
+            TlsIdentity::from_pem(cert_path, cert.as_bytes(), key_path, other_key.as_bytes()),
+            Err(TlsConfigError::KeyMismatch { .. })
+        ));
+        assert!(matches!(
+            TlsIdentity::from_pem(cert_path, key.as_bytes(), key_path, key.as_bytes()),
+            Err(TlsConfigError::NoCertificate(_))
+        ));
+        assert!(matches!(
+            TlsIdentity::from_pem(cert_path, cert.as_bytes(), key_path, cert.as_bytes()),
+            Err(TlsConfigError::NoPrivateKey(_))
+        ));
+        assert!(matches!(
+            TlsIdentity::load(Path::new("/nonexistent/cert.pem"), key_path),
+            Err(TlsConfigError::Read(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_certificate_reload() {
+        let dir = temp_dir().join(format!("defguard-tls-{}", gen_alphanumeric(8)));
+        std::fs::create_dir(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let (first, cert, key) = self_signed();
+        write(&cert_path, cert).unwrap();
+        write(&key_path, key).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        let server = tokio::spawn(serve_tls(
+            listener,
+            app,
+            cert_path.clone(),
+            key_path.clone(),
+            RELOAD_INTERVAL,
+            ProxyProtocol::default(),
+        ));
+
+        let mut established = connect(addr, &first).await.unwrap();
+        assert_eq!(get_status_line(&mut established).await, "HTTP/1.1 200 OK");
+
+        // new pair is picked up by new connections
+        let (second, cert, key) = self_signed();
+        write(&cert_path, cert).unwrap();
+        write(&key_path, key).unwrap();
+        sleep(RELOAD_INTERVAL * 4).await;
+        assert!(connect(addr, &first).await.is_err());
+        let mut stream = connect(addr, &second).await.unwrap();
+        assert_eq!(get_status_line(&mut stream).await, "HTTP/1.1 200 OK");
+        // established connections are kept
+        assert_eq!(get_status_line(&mut established).await, "HTTP/1.1 200 OK");
+
+        // invalid pair is ignored
+        let (_, _, other_key) = self_signed();
+        write(&key_path, other_key).unwrap();
+        sleep(RELOAD_INTERVAL * 4).await;
+        assert!(connect(addr, &second).await.is_ok());
+
+        assert!(!server.is_finished());
+        server.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
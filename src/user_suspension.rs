@@ -0,0 +1,160 @@
+//! Suspension of group members, e.g. seasonal contractors between engagements.
+//!
+//! All active members of a group are disabled at once, their sessions are invalidated and
+//! their devices removed from gateways. Unlike plain disabling, each suspension is recorded
+//! with the batch it belongs to, and can be lifted automatically by a background job.
+//! Users disabled before the suspension are left alone, so they're never reactivated.
+
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use sqlx::Error as SqlxError;
+use thiserror::Error;
+use tokio::sync::broadcast::Sender;
+use uuid::Uuid;
+
+use crate::{
+    db::{
+        models::{user_suspension::UserSuspension, wireguard::WireguardNetworkError},
+        DbPool, GatewayEvent, Group, User, WireguardNetwork,
+    },
+    jobs::{Job, JobSchedule},
+};
+
+// How often suspensions are checked for scheduled reactivation
+const REACTIVATION_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum SuspensionError {
+    #[error(transparent)]
+    DbError(#[from] SqlxError),
+    #[error(transparent)]
+    NetworkError(#[from] WireguardNetworkError),
+}
+
+/// Users suspended in a single batch.
+#[derive(Debug, Serialize)]
+pub struct SuspensionBatch {
+    pub batch_id: Uuid,
+    pub suspended: Vec<String>,
+}
+
+/// Suspend active members of `group`, except `exempt` users, break-glass accounts
+/// and `suspended_by` itself. Returns the batch and gateway events to send.
+pub async fn suspend_group_members(
+    pool: &DbPool,
+    group: &Group,
+    exempt: &[String],
+    reactivate_at: Option<NaiveDateTime>,
+    suspended_by: &User,
+) -> Result<(SuspensionBatch, Vec<GatewayEvent>), SuspensionError> {
+    let batch_id = Uuid::new_v4();
+    let suspended_at = Utc::now().naive_utc();
+    let mut suspended = Vec::new();
+    let mut transaction = pool.begin().await?;
+    for mut user in group.members(&mut *transaction).await? {
+        let Some(user_id) = user.id else {
+            continue;
+        };
+        if !user.is_active
+            || user.break_glass
+            || user.id == suspended_by.id
+            || exempt.contains(&user.username)
+        {
+            continue;
+        }
+        user.logout_all_sessions(&mut *transaction).await?;
+        user.is_active = false;
+        user.save(&mut *transaction).await?;
+        UserSuspension {
+            user_id,
+            batch_id,
+            group_name: group.name.clone(),
+            suspended_by: suspended_by.id,
+            suspended_at,
+            reactivate_at,
+        }
+        .save(&mut *transaction)
+        .await?;
+        info!(
+            %batch_id,
+            user_id,
+            "User {} suspended user {} with other members of group {}",
+            suspended_by.username,
+            user.username,
+            group.name
+        );
+        suspended.push(user.username);
+    }
+    let events = if suspended.is_empty() {
+        Vec::new()
+    } else {
+        WireguardNetwork::sync_all_networks_consolidated(&mut transaction).await?
+    };
+    transaction.commit().await?;
+
+    Ok((
+        SuspensionBatch {
+            batch_id,
+            suspended,
+        },
+        events,
+    ))
+}
+
+/// Reactivate users whose suspension ended, and restore their devices on gateways.
+/// Returns number of reactivated users.
+pub async fn reactivate_suspended_users(
+    pool: &DbPool,
+    wireguard_tx: &Sender<GatewayEvent>,
+) -> Result<usize, SuspensionError> {
+    let mut transaction = pool.begin().await?;
+    let due = UserSuspension::due(&mut *transaction, Utc::now().naive_utc()).await?;
+    if due.is_empty() {
+        return Ok(0);
+    }
+    for suspension in &due {
+        UserSuspension::delete_for_user(&mut *transaction, suspension.user_id).await?;
+        let Some(mut user) = User::find_by_id(&mut *transaction, suspension.user_id).await? else {
+            continue;
+        };
+        // accounts merged in the meantime stay disabled
+        if user.merged_into.is_none() {
+            user.is_active = true;
+            user.save(&mut *transaction).await?;
+        }
+        info!(
+            batch_id = %suspension.batch_id,
+            user_id = suspension.user_id,
+            "Reactivated user {} suspended with other members of group {}",
+            user.username,
+            suspension.group_name
+        );
+    }
+    let events = WireguardNetwork::sync_all_networks_consolidated(&mut transaction).await?;
+    transaction.commit().await?;
+    for event in events {
+        if let Err(err) = wireguard_tx.send(event) {
+            error!("Failed to send gateway event: {err}");
+        }
+    }
+
+    Ok(due.len())
+}
+
+/// Background job lifting suspensions at their scheduled time.
+#[must_use]
+pub fn user_reactivation_job(pool: DbPool, wireguard_tx: Sender<GatewayEvent>) -> Job {
+    Job::new(
+        "user_reactivation",
+        JobSchedule::Interval(REACTIVATION_INTERVAL),
+        move || {
+            let pool = pool.clone();
+            let wireguard_tx = wireguard_tx.clone();
+            async move {
+                reactivate_suspended_users(&pool, &wireguard_tx).await?;
+                Ok(())
+            }
+        },
+    )
+}
@@ -0,0 +1,210 @@
+//! Timeline of a user's activity for incident response.
+//!
+//! Events of different kinds are merged by a single query, in timestamp order:
+//! - first logins from new devices (browsers), as recorded for new device notifications,
+//! - tokens issued to OpenID clients,
+//! - VPN connection sessions, derived from peer handshakes collected from gateways. A session
+//!   ends when the gap between handshakes exceeds disconnect threshold of the location.
+//!
+//! Pages are chained with an opaque cursor pointing at the last returned event, so pages are
+//! stable while new events are being recorded.
+
+use chrono::{DateTime, NaiveDateTime};
+use sqlx::{query, Error as SqlxError, PgExecutor};
+use utoipa::ToSchema;
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TimelineEvent {
+    /// First login from a device (browser) the user hasn't used before.
+    DeviceLogin {
+        id: i64,
+        occurred_at: NaiveDateTime,
+        ip_address: Option<String>,
+        browser: Option<String>,
+        os_family: Option<String>,
+    },
+    /// Token issued to an OpenID client.
+    OidcToken {
+        id: i64,
+        occurred_at: NaiveDateTime,
+        client_id: Option<String>,
+        app_name: Option<String>,
+        grant_type: Option<String>,
+    },
+    /// VPN connection of a device to a location, from the first to the last handshake.
+    VpnSession {
+        id: i64,
+        occurred_at: NaiveDateTime,
+        ended_at: Option<NaiveDateTime>,
+        duration_secs: i64,
+        network_id: Option<i64>,
+        network_name: Option<String>,
+        device_id: Option<i64>,
+        device_name: Option<String>,
+        endpoint: Option<String>,
+    },
+}
+
+/// Position after an event, for fetching the next page.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimelineCursor {
+    occurred_at: NaiveDateTime,
+    kind: String,
+    id: i64,
+}
+
+impl TimelineCursor {
+    #[must_use]
+    pub fn encode(&self) -> String {
+        format!(
+            "{}.{}.{}",
+            self.occurred_at.and_utc().timestamp_micros(),
+            self.kind,
+            self.id
+        )
+    }
+
+    #[must_use]
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let mut parts = cursor.splitn(3, '.');
+        let micros = parts.next()?.parse().ok()?;
+        let kind = parts.next()?.to_string();
+        let id = parts.next()?.parse().ok()?;
+        Some(Self {
+            occurred_at: DateTime::from_timestamp_micros(micros)?.naive_utc(),
+            kind,
+            id,
+        })
+    }
+}
+
+/// Events of a user which occurred in `[from, to)` after `cursor`, at most `limit` of them.
+/// Returns the events and cursor of the last one.
+pub async fn user_timeline<'e, E>(
+    executor: E,
+    user_id: i64,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    cursor: Option<&TimelineCursor>,
+    limit: i64,
+) -> Result<(Vec<TimelineEvent>, Option<TimelineCursor>), SqlxError>
+where
+    E: PgExecutor<'e>,
+{
+    let rows = query!(
+        "WITH handshake AS ( \
+            SELECT DISTINCT ON (s.device_id, s.network, s.latest_handshake) \
+            s.id, s.device_id, s.network, s.latest_handshake, s.endpoint \
+            FROM wireguard_peer_stats s JOIN device d ON d.id = s.device_id \
+            WHERE d.user_id = $1 AND s.latest_handshake > 'epoch' \
+            AND s.latest_handshake >= $2 AND s.latest_handshake < $3 \
+            ORDER BY s.device_id, s.network, s.latest_handshake, s.id \
+        ), marked AS ( \
+            SELECT h.*, n.name network_name, \
+            CASE WHEN h.latest_handshake - lag(h.latest_handshake) OVER w \
+            <= make_interval(secs => n.peer_disconnect_threshold) THEN 0 ELSE 1 END session_start \
+            FROM handshake h JOIN wireguard_network n ON n.id = h.network \
+            WINDOW w AS (PARTITION BY h.device_id, h.network ORDER BY h.latest_handshake) \
+        ), numbered AS ( \
+            SELECT marked.*, sum(session_start) OVER \
+            (PARTITION BY device_id, network ORDER BY latest_handshake) session \
+            FROM marked \
+        ), vpn_session AS ( \
+            SELECT min(id) id, device_id, network, min(network_name) network_name, \
+            min(latest_handshake) started_at, max(latest_handshake) ended_at, \
+            (array_agg(endpoint ORDER BY latest_handshake))[1] endpoint \
+            FROM numbered GROUP BY device_id, network, session \
+        ), event AS ( \
+            SELECT 'device_login' kind, e.id, e.created occurred_at, NULL::timestamp ended_at, \
+            e.ip_address, e.browser, e.os_family, NULL::text client_id, NULL::text app_name, \
+            NULL::text grant_type, NULL::bigint network_id, NULL::text network_name, \
+            NULL::bigint device_id, NULL::text device_name \
+            FROM device_login_event e \
+            WHERE e.user_id = $1 AND e.created >= $2 AND e.created < $3 \
+            UNION ALL \
+            SELECT 'oidc_token', i.id, i.issued_at, NULL, NULL, NULL, NULL, c.client_id, c.name, \
+            i.grant_type, NULL, NULL, NULL, NULL \
+            FROM oauth2_token_issuance i JOIN oauth2client c ON c.id = i.oauth2client_id \
+            WHERE i.user_id = $1 AND i.issued_at >= $2 AND i.issued_at < $3 \
+            UNION ALL \
+            SELECT 'vpn_session', s.id, s.started_at, s.ended_at, s.endpoint, NULL, NULL, NULL, \
+            NULL, NULL, s.network, s.network_name, s.device_id, d.name \
+            FROM vpn_session s JOIN device d ON d.id = s.device_id \
+        ) \
+        SELECT kind \"kind!\", id \"id!\", occurred_at \"occurred_at!\", ended_at, ip_address, \
+        browser, os_family, client_id, app_name, grant_type, network_id, network_name, \
+        device_id, device_name FROM event \
+        WHERE $4::timestamp IS NULL OR (occurred_at, kind, id) > ($4, $5, $6) \
+        ORDER BY occurred_at, kind, id LIMIT $7",
+        user_id,
+        from,
+        to,
+        cursor.map(|cursor| cursor.occurred_at),
+        cursor.map(|cursor| cursor.kind.as_str()),
+        cursor.map(|cursor| cursor.id),
+        limit
+    )
+    .fetch_all(executor)
+    .await?;
+
+    let last = rows.last().map(|row| TimelineCursor {
+        occurred_at: row.occurred_at,
+        kind: row.kind.clone(),
+        id: row.id,
+    });
+    let events = rows
+        .into_iter()
+        .filter_map(|row| match row.kind.as_str() {
+            "device_login" => Some(TimelineEvent::DeviceLogin {
+                id: row.id,
+                occurred_at: row.occurred_at,
+                ip_address: row.ip_address,
+                browser: row.browser,
+                os_family: row.os_family,
+            }),
+            "oidc_token" => Some(TimelineEvent::OidcToken {
+                id: row.id,
+                occurred_at: row.occurred_at,
+                client_id: row.client_id,
+                app_name: row.app_name,
+                grant_type: row.grant_type,
+            }),
+            "vpn_session" => Some(TimelineEvent::VpnSession {
+                id: row.id,
+                occurred_at: row.occurred_at,
+                ended_at: row.ended_at,
+                duration_secs: row
+                    .ended_at
+                    .map_or(0, |ended_at| (ended_at - row.occurred_at).num_seconds()),
+                network_id: row.network_id,
+                network_name: row.network_name,
+                device_id: row.device_id,
+                device_name: row.device_name,
+                // shares the column with IP address of logins
+                endpoint: row.ip_address,
+            }),
+            _ => None,
+        })
+        .collect();
+    Ok((events, last))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cursor() {
+        let cursor = TimelineCursor {
+            occurred_at: DateTime::from_timestamp_micros(1_723_456_789_123_456)
+                .unwrap()
+                .naive_utc(),
+            kind: "vpn_session".into(),
+            id: 42,
+        };
+        assert_eq!(TimelineCursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(TimelineCursor::decode("1.device_login"), None);
+        assert_eq!(TimelineCursor::decode("x.device_login.1"), None);
+    }
+}
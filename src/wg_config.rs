@@ -3,23 +3,28 @@ use std::{array::TryFromSliceError, net::IpAddr};
 use base64::{prelude::BASE64_STANDARD, DecodeError, Engine};
 use ipnetwork::{IpNetwork, IpNetworkError};
 use thiserror::Error;
+use utoipa::ToSchema;
 use x25519_dalek::{PublicKey, StaticSecret};
 
 use crate::{
     db::{
-        models::wireguard::{
-            WireguardNetworkError, DEFAULT_DISCONNECT_THRESHOLD, DEFAULT_KEEPALIVE_INTERVAL,
+        models::{
+            device::WireguardPubkey,
+            wireguard::{
+                WireguardNetworkError, DEFAULT_DISCONNECT_THRESHOLD, DEFAULT_KEEPALIVE_INTERVAL,
+            },
         },
-        Device, WireguardNetwork,
+        WireguardNetwork,
     },
     KEY_LENGTH,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct ImportedDevice {
     pub user_id: Option<i64>,
     pub name: String,
     pub wireguard_pubkey: String,
+    #[schema(value_type = String)]
     pub wireguard_ip: IpAddr,
 }
 
@@ -120,7 +125,9 @@ pub fn parse_wireguard_config(
         let pubkey = peer
             .get("PublicKey")
             .ok_or_else(|| WireguardConfigParseError::KeyNotFound("PublicKey"))?;
-        Device::validate_pubkey(pubkey).map_err(WireguardConfigParseError::InvalidKey)?;
+        let pubkey = WireguardPubkey::parse(pubkey)
+            .map_err(|err| WireguardConfigParseError::InvalidKey(err.to_string()))?;
+        let pubkey = pubkey.as_str();
 
         // check if device pubkey collides with network pubkey
         if pubkey == network.pubkey {
@@ -128,6 +135,14 @@ pub fn parse_wireguard_config(
                 "Device pubkey is the same as network pubkey {pubkey}"
             )));
         }
+        if devices
+            .iter()
+            .any(|device: &ImportedDevice| device.wireguard_pubkey == pubkey)
+        {
+            return Err(WireguardConfigParseError::InvalidKey(format!(
+                "Pubkey {pubkey} is used by more than one peer"
+            )));
+        }
 
         devices.push(ImportedDevice {
             user_id: None,
@@ -201,4 +216,26 @@ mod test {
         );
         assert_eq!(device2.wireguard_ip.to_string(), "10.0.0.11");
     }
+
+    #[test]
+    fn test_parse_config_duplicate_peer() {
+        let config = "
+            [Interface]
+            PrivateKey = GAA2X3DW0WakGVx+DsGjhDpTgg50s1MlmrLf24Psrlg=
+            Address = 10.0.0.1/24
+            ListenPort = 55055
+
+            [Peer]
+            PublicKey = 2LYRr2HgSSpGCdXKDDAlcFe0Uuc6RR8TFgSquNc9VAE=
+            AllowedIPs = 10.0.0.10/24
+
+            [Peer]
+            PublicKey = 2LYRr2HgSSpGCdXKDDAlcFe0Uuc6RR8TFgSquNc9VAE
+            AllowedIPs = 10.0.0.11/24
+        ";
+        assert!(matches!(
+            parse_wireguard_config(config),
+            Err(WireguardConfigParseError::InvalidKey(_))
+        ));
+    }
 }
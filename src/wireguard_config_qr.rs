@@ -0,0 +1,142 @@
+//! QR codes of WireGuard client configs, scanned by mobile WireGuard apps.
+//!
+//! Codes are generated in-process, so configs never leave the server.
+//! Configs stored in the database don't include device private keys; those are only
+//! known right after web enrollment, which hands out one-time QR code links for them.
+
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    time::{Duration, Instant},
+};
+
+use image::{ImageError, ImageFormat, Luma};
+use qrcode::{render::svg, types::QrError, QrCode};
+use thiserror::Error;
+
+use crate::random::gen_alphanumeric;
+
+// one-time links are meant to be opened right after enrollment
+const QR_LINK_VALIDITY: Duration = Duration::from_secs(15 * 60);
+const QR_TOKEN_LENGTH: usize = 32;
+// minimal image size in pixels, large enough for phone cameras
+const QR_MIN_SIZE: u32 = 320;
+
+#[derive(Debug, Error)]
+pub enum ConfigQrError {
+    #[error("Config can't be encoded as QR code: {0}")]
+    Encode(#[from] QrError),
+    #[error("Failed to render QR code image: {0}")]
+    Image(#[from] ImageError),
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QrFormat {
+    #[default]
+    Svg,
+    Png,
+}
+
+impl QrFormat {
+    #[must_use]
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Svg => "image/svg+xml",
+            Self::Png => "image/png",
+        }
+    }
+}
+
+/// Render config as QR code image in given format.
+pub fn render_config_qr(config: &str, format: QrFormat) -> Result<Vec<u8>, ConfigQrError> {
+    let code = QrCode::new(config.as_bytes())?;
+    match format {
+        QrFormat::Svg => Ok(code
+            .render::<svg::Color>()
+            .min_dimensions(QR_MIN_SIZE, QR_MIN_SIZE)
+            .build()
+            .into_bytes()),
+        QrFormat::Png => {
+            let image = code
+                .render::<Luma<u8>>()
+                .min_dimensions(QR_MIN_SIZE, QR_MIN_SIZE)
+                .build();
+            let mut png = Vec::new();
+            image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+            Ok(png)
+        }
+    }
+}
+
+struct QrLink {
+    device_id: i64,
+    network_id: i64,
+    config: String,
+    created: Instant,
+}
+
+/// Complete configs waiting to be fetched once as QR codes, by link token.
+#[derive(Default)]
+pub struct ConfigQrLinks(HashMap<String, QrLink>);
+
+impl ConfigQrLinks {
+    /// Store config of device in given network and return one-time link token.
+    pub fn insert(&mut self, device_id: i64, network_id: i64, config: String) -> String {
+        self.purge_expired();
+        let token = gen_alphanumeric(QR_TOKEN_LENGTH);
+        self.0.insert(
+            token.clone(),
+            QrLink {
+                device_id,
+                network_id,
+                config,
+                created: Instant::now(),
+            },
+        );
+        token
+    }
+
+    /// Remove and return config for given token, if it's valid for the device and network.
+    pub fn take(&mut self, token: &str, device_id: i64, network_id: i64) -> Option<String> {
+        self.purge_expired();
+        match self.0.get(token) {
+            Some(link) if link.device_id == device_id && link.network_id == network_id => {
+                self.0.remove(token).map(|link| link.config)
+            }
+            _ => None,
+        }
+    }
+
+    fn purge_expired(&mut self) {
+        self.0
+            .retain(|_, link| link.created.elapsed() < QR_LINK_VALIDITY);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_qr_links_are_single_use() {
+        let mut links = ConfigQrLinks::default();
+        let token = links.insert(1, 2, "config".into());
+        // token is bound to device and network
+        assert_eq!(links.take(&token, 1, 3), None);
+        assert_eq!(links.take(&token, 3, 2), None);
+        assert_eq!(links.take("other", 1, 2), None);
+        assert_eq!(links.take(&token, 1, 2).as_deref(), Some("config"));
+        assert_eq!(links.take(&token, 1, 2), None);
+    }
+
+    #[test]
+    fn test_render_config_qr() {
+        let svg = render_config_qr("[Interface]", QrFormat::Svg).unwrap();
+        assert!(String::from_utf8(svg).unwrap().contains("<svg"));
+        let png = render_config_qr("[Interface]", QrFormat::Png).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+        // beyond QR code capacity
+        assert!(render_config_qr(&"x".repeat(8000), QrFormat::Svg).is_err());
+    }
+}
@@ -4,21 +4,25 @@
 //! it should be removed from gateway configuration and marked as "not allowed",
 //! which enforces an authentication requirement to connect again.
 
-use crate::db::{
-    models::{
-        device::{DeviceInfo, DeviceNetworkInfo, WireguardNetworkDevice},
-        error::ModelError,
-        wireguard::WireguardNetworkError,
+use crate::{
+    db::{
+        models::{
+            device::{DeviceNetworkInfo, WireguardNetworkDevice},
+            error::ModelError,
+            wireguard::{PeerUpdate, WireguardNetworkError},
+        },
+        DbPool, Device, GatewayEvent, WireguardNetwork,
     },
-    DbPool, Device, GatewayEvent, WireguardNetwork,
+    jobs::{Job, JobSchedule},
 };
+use chrono::Utc;
 use sqlx::{query_as, Error as SqlxError};
 use std::time::Duration;
 use thiserror::Error;
-use tokio::{sync::broadcast::Sender, time::sleep};
+use tokio::sync::broadcast::Sender;
 
-// How long to sleep between loop iterations
-const DISCONNECT_LOOP_SLEEP_SECONDS: u64 = 60; // 1 minute
+// How often inactive peers are disconnected
+const DISCONNECT_INTERVAL_SECONDS: u64 = 60; // 1 minute
 
 #[derive(Debug, Error)]
 pub enum PeerDisconnectError {
@@ -32,33 +36,50 @@ pub enum PeerDisconnectError {
     EventError(String),
 }
 
-/// Run periodic disconnect task
-///
-/// Run with a specified frequency and disconnect all inactive peers in MFA-protected locations.
-pub async fn run_periodic_peer_disconnect(
-    pool: DbPool,
-    wireguard_tx: Sender<GatewayEvent>,
+/// Background job disconnecting inactive peers in MFA-protected locations.
+#[must_use]
+pub fn peer_disconnect_job(pool: DbPool, wireguard_tx: Sender<GatewayEvent>) -> Job {
+    Job::new(
+        "peer_disconnect",
+        JobSchedule::Interval(Duration::from_secs(DISCONNECT_INTERVAL_SECONDS)),
+        move || {
+            let pool = pool.clone();
+            let wireguard_tx = wireguard_tx.clone();
+            async move {
+                disconnect_inactive_peers(&pool, &wireguard_tx).await?;
+                Ok(())
+            }
+        },
+    )
+}
+
+/// Disconnect all inactive peers in MFA-protected locations.
+pub async fn disconnect_inactive_peers(
+    pool: &DbPool,
+    wireguard_tx: &Sender<GatewayEvent>,
 ) -> Result<(), PeerDisconnectError> {
-    info!("Starting periodic disconnect of inactive devices in MFA-protected locations");
-    loop {
-        debug!("Starting periodic inactive device disconnect");
+    debug!("Starting inactive device disconnect");
 
-        // get all MFA-protected locations
-        let locations = query_as!(
-            WireguardNetwork,
-            "SELECT \
-                id as \"id?\", name, address, port, pubkey, prvkey, endpoint, dns, allowed_ips, \
-                connected_at, mfa_enabled, keepalive_interval, peer_disconnect_threshold \
-            FROM wireguard_network WHERE mfa_enabled = true",
-        )
-        .fetch_all(&pool)
-        .await?;
+    // get all active MFA-protected locations
+    let locations = query_as!(
+        WireguardNetwork,
+        "SELECT \
+            id as \"id?\", name, address, port, pubkey, prvkey, endpoint, dns, allowed_ips, \
+            connected_at, mfa_enabled, keepalive_interval, peer_disconnect_threshold, archived, \
+            psk_rotation_days, gateway_allowed_ips, mtu, dns_zone, upload_limit_kbps, download_limit_kbps, \
+            allowed_platforms, deny_unknown_platform, client_routes, masquerade_enabled, \
+            nat_exempt_networks, server_managed_keys \
+        FROM wireguard_network WHERE mfa_enabled = true AND NOT archived",
+    )
+    .fetch_all(pool)
+    .await?;
 
-        // loop over all locations
-        for location in locations {
-            debug!("Fetching inactive devices for location {location}");
-            let location_id = location.get_id()?;
-            let devices = query_as!(
+    // loop over all locations
+    for location in locations {
+        debug!("Fetching inactive devices for location {location}");
+        let location_id = location.get_id()?;
+        // inactive devices are exactly the authorized ones `connected_clients()` doesn't list
+        let devices = query_as!(
             Device,
             "WITH stats AS ( \
                     SELECT DISTINCT ON (device_id) device_id, endpoint, latest_handshake \
@@ -66,64 +87,62 @@ pub async fn run_periodic_peer_disconnect(
                     WHERE network = $1 \
                     ORDER BY device_id, collected_at DESC \
                 ) \
-            SELECT d.id as \"id?\", d.name, d.wireguard_pubkey, d.user_id, d.created \
+            SELECT d.id as \"id?\", d.name, d.wireguard_pubkey, d.user_id, d.created, d.os, d.os_version, d.client_version, d.hostname, d.machine_hash, d.blocked \
             FROM device d \
             JOIN wireguard_network_device wnd ON wnd.device_id = d.id \
             LEFT JOIN stats on d.id = stats.device_id \
             WHERE wnd.wireguard_network_id = $1 AND wnd.is_authorized = true AND \
-            (wnd.authorized_at IS NULL OR (NOW() - wnd.authorized_at) > $2 * interval '1 second') AND \
-            (stats.latest_handshake IS NULL OR (NOW() - stats.latest_handshake) > $2 * interval '1 second')",
+            (wnd.authorized_at IS NULL OR wnd.authorized_at < $2) AND \
+            (stats.latest_handshake IS NULL OR stats.latest_handshake < $2)",
             location_id,
-                location.peer_disconnect_threshold as f64
+            location.peer_active_since(Utc::now().naive_utc())
         )
-                .fetch_all(&pool)
-                .await?;
-
-            for device in devices {
-                debug!("Processing inactive device {device}");
-                let device_id = device.get_id()?;
+        .fetch_all(pool)
+        .await?;
 
-                // start transaction
-                let mut transaction = pool.begin().await?;
+        for device in devices {
+            debug!("Processing inactive device {device}");
+            let device_id = device.get_id()?;
 
-                // get network config for device
-                if let Some(mut device_network_config) =
-                    WireguardNetworkDevice::find(&mut *transaction, device_id, location_id).await?
-                {
-                    info!("Marking device {device} as not authorized to connect to location {location}");
-                    // change `is_authorized` value for device
-                    device_network_config.is_authorized = false;
-                    // clear `preshared_key` value
-                    device_network_config.preshared_key = None;
-                    device_network_config.update(&mut *transaction).await?;
+            // start transaction
+            let mut transaction = pool.begin().await?;
 
-                    debug!("Sending `peer_delete` message to gateway");
-                    let device_info = DeviceInfo {
-                        device,
-                        network_info: vec![DeviceNetworkInfo {
-                            network_id: location_id,
-                            device_wireguard_ip: device_network_config.wireguard_ip,
-                            preshared_key: device_network_config.preshared_key,
-                            is_authorized: device_network_config.is_authorized,
-                        }],
-                    };
-                    let event = GatewayEvent::DeviceDeleted(device_info);
-                    wireguard_tx.send(event).map_err(|err| {
-                        error!("Error sending WireGuard event: {err}");
-                        PeerDisconnectError::EventError(err.to_string())
-                    })?;
-                } else {
-                    error!("Network config for device {device} in location {location} not found. Skipping device...");
-                    continue;
-                }
+            // get network config for device
+            if let Some(mut device_network_config) =
+                WireguardNetworkDevice::find(&mut *transaction, device_id, location_id).await?
+            {
+                info!(
+                    "Marking device {device} as not authorized to connect to location {location}"
+                );
+                // change `is_authorized` value for device
+                device_network_config.is_authorized = false;
+                // clear `preshared_key` value
+                device_network_config.preshared_key = None;
+                device_network_config.update(&mut *transaction).await?;
 
-                // commit transaction
-                transaction.commit().await?;
+                debug!("Sending `peer_delete` message to gateway");
+                let event = GatewayEvent::PeerRemoved(PeerUpdate {
+                    device,
+                    network_info: DeviceNetworkInfo {
+                        network_id: location_id,
+                        device_wireguard_ip: device_network_config.wireguard_ip,
+                        preshared_key: device_network_config.preshared_key,
+                        is_authorized: device_network_config.is_authorized,
+                    },
+                });
+                wireguard_tx.send(event).map_err(|err| {
+                    error!("Error sending WireGuard event: {err}");
+                    PeerDisconnectError::EventError(err.to_string())
+                })?;
+            } else {
+                error!("Network config for device {device} in location {location} not found. Skipping device...");
+                continue;
             }
-        }
 
-        // wait till next iteration
-        debug!("Sleeping until next iteration");
-        sleep(Duration::from_secs(DISCONNECT_LOOP_SLEEP_SECONDS)).await;
+            // commit transaction
+            transaction.commit().await?;
+        }
     }
+
+    Ok(())
 }
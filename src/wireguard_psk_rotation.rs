@@ -0,0 +1,444 @@
+//! Rotation of WireGuard preshared keys in locations with a rotation policy.
+//!
+//! A new key is first staged as pending and the device owner is asked by email
+//! to confirm the change. Gateways keep using the active key until the owner confirms
+//! or the grace period ends, whichever comes first. Generated client configs carry the
+//! active key only, so clients switch along with gateways; the pending key is available
+//! on its own for clients which apply it themselves once the change is confirmed.
+//! MFA-protected locations are skipped, as their keys are renewed on every login.
+//! Keys are never logged, only their fingerprints.
+
+use std::time::Duration;
+
+use chrono::{Duration as ChronoDuration, NaiveDateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::Error as SqlxError;
+use thiserror::Error;
+use tokio::sync::{broadcast::Sender, mpsc::UnboundedSender};
+
+use crate::{
+    db::{
+        models::{
+            device::{DeviceNetworkInfo, WireguardNetworkDevice},
+            wireguard::PeerUpdate,
+        },
+        DbPool, Device, GatewayEvent, User, WireguardNetwork,
+    },
+    handlers::mail::send_psk_rotation_email,
+    hex::to_lower_hex,
+    jobs::{Job, JobSchedule},
+    mail::Mail,
+    server_config,
+    templates::TemplateError,
+};
+
+// How often locations are checked for keys due for rotation
+const PSK_ROTATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Error)]
+pub enum PskRotationError {
+    #[error(transparent)]
+    DbError(#[from] SqlxError),
+    #[error(transparent)]
+    TemplateError(#[from] TemplateError),
+    #[error("Owner of device {0} not found")]
+    OwnerNotFound(String),
+    #[error("Device {0} not found")]
+    DeviceNotFound(i64),
+    #[error("Failed to send gateway event: {0}")]
+    EventError(String),
+}
+
+/// Identifies a preshared key in logs and notifications without revealing it.
+#[must_use]
+pub fn key_fingerprint(key: &str) -> String {
+    to_lower_hex(&Sha256::digest(key.as_bytes())[..8])
+}
+
+/// Time after which a key staged at `created` replaces the active one.
+#[must_use]
+pub fn grace_period_deadline(created: NaiveDateTime) -> NaiveDateTime {
+    created
+        + ChronoDuration::from_std(*server_config().psk_rotation_grace_period)
+            .expect("Failed to parse duration")
+}
+
+/// Stage a new preshared key for a device and ask its owner to switch to it.
+/// Returns fingerprint of the new key.
+pub async fn stage_psk_rotation(
+    pool: &DbPool,
+    mail_tx: &UnboundedSender<Mail>,
+    device: &Device,
+    network: &WireguardNetwork,
+    network_device: &mut WireguardNetworkDevice,
+) -> Result<String, PskRotationError> {
+    let Some(user) = User::find_by_id(pool, device.user_id).await? else {
+        return Err(PskRotationError::OwnerNotFound(device.name.clone()));
+    };
+    let key = WireguardNetwork::genkey().public;
+    let fingerprint = key_fingerprint(&key);
+    network_device.stage_preshared_key(pool, key).await?;
+    let created = network_device
+        .pending_preshared_key_created
+        .unwrap_or_else(|| Utc::now().naive_utc());
+    info!(
+        "Staged preshared key {fingerprint} for device {} in location {}",
+        device.name, network.name
+    );
+
+    send_psk_rotation_email(
+        &device.name,
+        &network.name,
+        &fingerprint,
+        &grace_period_deadline(created),
+        &user.email,
+        mail_tx,
+    )?;
+    Ok(fingerprint)
+}
+
+/// Switch a device to its pending preshared key and update gateways.
+/// Returns `false` if there was no key pending.
+pub async fn promote_psk(
+    pool: &DbPool,
+    wireguard_tx: &Sender<GatewayEvent>,
+    network_device: &mut WireguardNetworkDevice,
+) -> Result<bool, PskRotationError> {
+    let previous = network_device.preshared_key.as_deref().map(key_fingerprint);
+    if !network_device.promote_preshared_key(pool).await? {
+        return Ok(false);
+    }
+    let Some(device) = Device::find_by_id(pool, network_device.device_id).await? else {
+        return Err(PskRotationError::DeviceNotFound(network_device.device_id));
+    };
+    info!(
+        "Preshared key of device {device} in location {} changed from {} to {}",
+        network_device.wireguard_network_id,
+        previous.as_deref().unwrap_or("none"),
+        network_device
+            .preshared_key
+            .as_deref()
+            .map(key_fingerprint)
+            .unwrap_or_default(),
+    );
+
+    debug!("Sending `peer_modified` message to gateway");
+    let event = GatewayEvent::PeerModified(
+        PeerUpdate {
+            device,
+            network_info: DeviceNetworkInfo {
+                network_id: network_device.wireguard_network_id,
+                device_wireguard_ip: network_device.wireguard_ip,
+                preshared_key: network_device.preshared_key.clone(),
+                is_authorized: network_device.is_authorized,
+            },
+        },
+        None,
+    );
+    wireguard_tx.send(event).map_err(|err| {
+        error!("Error sending WireGuard event: {err}");
+        PskRotationError::EventError(err.to_string())
+    })?;
+    Ok(true)
+}
+
+/// Promote pending keys past their grace period, then stage new keys
+/// for devices whose keys are older than rotation period of their location.
+pub async fn rotate_preshared_keys(
+    pool: &DbPool,
+    wireguard_tx: &Sender<GatewayEvent>,
+    mail_tx: &UnboundedSender<Mail>,
+) -> Result<(), PskRotationError> {
+    debug!("Starting preshared key rotation");
+    let now = Utc::now().naive_utc();
+    let grace_period = ChronoDuration::from_std(*server_config().psk_rotation_grace_period)
+        .expect("Failed to parse duration");
+    for mut network_device in
+        WireguardNetworkDevice::pending_psk_expired(pool, now - grace_period).await?
+    {
+        promote_psk(pool, wireguard_tx, &mut network_device).await?;
+    }
+
+    for network in WireguardNetwork::all_active(pool).await? {
+        let (Some(network_id), Some(rotation_days)) = (network.id, network.psk_rotation_days)
+        else {
+            continue;
+        };
+        if network.mfa_enabled {
+            continue;
+        }
+        let rotated_before = now - ChronoDuration::days(rotation_days.into());
+        let due =
+            WireguardNetworkDevice::due_for_psk_rotation(pool, network_id, rotated_before).await?;
+        if !due.is_empty() {
+            info!(
+                "Rotating preshared keys of {} devices in location {network}",
+                due.len()
+            );
+        }
+        for mut network_device in due {
+            let Some(device) = Device::find_by_id(pool, network_device.device_id).await? else {
+                continue;
+            };
+            stage_psk_rotation(pool, mail_tx, &device, &network, &mut network_device).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Background job rotating preshared keys.
+#[must_use]
+pub fn psk_rotation_job(
+    pool: DbPool,
+    wireguard_tx: Sender<GatewayEvent>,
+    mail_tx: UnboundedSender<Mail>,
+) -> Job {
+    Job::new(
+        "psk_rotation",
+        JobSchedule::Interval(PSK_ROTATION_INTERVAL),
+        move || {
+            let pool = pool.clone();
+            let wireguard_tx = wireguard_tx.clone();
+            let mail_tx = mail_tx.clone();
+            async move {
+                rotate_preshared_keys(&pool, &wireguard_tx, &mail_tx).await?;
+                Ok(())
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use claims::{assert_err, assert_matches};
+    use sqlx::query;
+    use tokio::sync::{broadcast, mpsc::unbounded_channel};
+
+    use super::*;
+    use crate::{config::DefGuardConfig, SERVER_CONFIG};
+
+    async fn create_network(
+        pool: &DbPool,
+        name: &str,
+        rotation_days: Option<i32>,
+    ) -> WireguardNetwork {
+        let mut network = WireguardNetwork {
+            name: name.into(),
+            ..Default::default()
+        };
+        network.try_set_address("10.1.1.1/24").unwrap();
+        network.psk_rotation_days = rotation_days;
+        network.save(pool).await.unwrap();
+        network
+    }
+
+    async fn create_user(pool: &DbPool, username: &str, is_active: bool) -> i64 {
+        let mut user = User::new(
+            username,
+            Some("pass123"),
+            "Tester",
+            "Test",
+            &format!("{username}@test.com"),
+            None,
+        );
+        user.is_active = is_active;
+        user.save(pool).await.unwrap();
+        user.id.unwrap()
+    }
+
+    async fn add_device(
+        pool: &DbPool,
+        user_id: i64,
+        name: &str,
+        network: &WireguardNetwork,
+    ) -> WireguardNetworkDevice {
+        let (_, network_device) =
+            Device::new_with_ip(pool, user_id, name.into(), format!("key-{name}"), network)
+                .await
+                .unwrap();
+        network_device
+    }
+
+    async fn set_rotated(pool: &DbPool, network_device: &WireguardNetworkDevice, days_ago: i64) {
+        query(
+            "UPDATE wireguard_network_device SET preshared_key = 'old', preshared_key_rotated = $3 \
+            WHERE device_id = $1 AND wireguard_network_id = $2",
+        )
+        .bind(network_device.device_id)
+        .bind(network_device.wireguard_network_id)
+        .bind(Utc::now().naive_utc() - ChronoDuration::days(days_ago))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn set_pending_created(
+        pool: &DbPool,
+        network_device: &WireguardNetworkDevice,
+        days_ago: i64,
+    ) {
+        query(
+            "UPDATE wireguard_network_device SET pending_preshared_key_created = $3 \
+            WHERE device_id = $1 AND wireguard_network_id = $2",
+        )
+        .bind(network_device.device_id)
+        .bind(network_device.wireguard_network_id)
+        .bind(Utc::now().naive_utc() - ChronoDuration::days(days_ago))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn reload(
+        pool: &DbPool,
+        network_device: &WireguardNetworkDevice,
+    ) -> WireguardNetworkDevice {
+        WireguardNetworkDevice::find(
+            pool,
+            network_device.device_id,
+            network_device.wireguard_network_id,
+        )
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_rotation_selects_due_devices(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let (wireguard_tx, mut wireguard_rx) = broadcast::channel(16);
+        let (mail_tx, mut mail_rx) = unbounded_channel();
+
+        let rotated = create_network(&pool, "rotated", Some(30)).await;
+        let no_policy = create_network(&pool, "no-policy", None).await;
+        let mut mfa = create_network(&pool, "mfa", Some(30)).await;
+        mfa.mfa_enabled = true;
+        mfa.save(&pool).await.unwrap();
+        let user_id = create_user(&pool, "active", true).await;
+        let disabled_user_id = create_user(&pool, "disabled", false).await;
+
+        let recent = add_device(&pool, user_id, "recent", &rotated).await;
+        set_rotated(&pool, &recent, 1).await;
+        let old = add_device(&pool, user_id, "old", &rotated).await;
+        set_rotated(&pool, &old, 40).await;
+        let never = add_device(&pool, user_id, "never", &rotated).await;
+        let disabled = add_device(&pool, disabled_user_id, "disabled", &rotated).await;
+        let unmanaged = add_device(&pool, user_id, "unmanaged", &no_policy).await;
+        let mfa_device = add_device(&pool, user_id, "mfa", &mfa).await;
+        let mut pinned = add_device(&pool, user_id, "pinned", &rotated).await;
+        pinned
+            .pin_preshared_key(&pool, "operator".into())
+            .await
+            .unwrap();
+        set_rotated(&pool, &pinned, 40).await;
+        let mut pending = add_device(&pool, user_id, "pending", &rotated).await;
+        pending
+            .stage_preshared_key(&pool, "staged".into())
+            .await
+            .unwrap();
+
+        rotate_preshared_keys(&pool, &wireguard_tx, &mail_tx)
+            .await
+            .unwrap();
+
+        for network_device in [&old, &never] {
+            let network_device = reload(&pool, network_device).await;
+            let key = network_device.pending_preshared_key.unwrap();
+            assert_ne!(Some(key), network_device.preshared_key);
+            assert!(network_device.pending_preshared_key_created.is_some());
+        }
+        for network_device in [&recent, &disabled, &unmanaged, &mfa_device, &pinned] {
+            assert!(reload(&pool, network_device)
+                .await
+                .pending_preshared_key
+                .is_none());
+        }
+        // already pending keys are left alone
+        assert_eq!(
+            reload(&pool, &pending).await.pending_preshared_key,
+            Some("staged".into())
+        );
+
+        // owners are notified, gateways are not updated yet
+        assert_eq!(mail_rx.try_recv().unwrap().to, "active@test.com");
+        assert_eq!(mail_rx.try_recv().unwrap().to, "active@test.com");
+        assert_err!(mail_rx.try_recv());
+        assert_err!(wireguard_rx.try_recv());
+    }
+
+    #[sqlx::test]
+    async fn test_staged_key_promoted_after_grace_period(pool: DbPool) {
+        let _ = SERVER_CONFIG.set(DefGuardConfig::new_test_config());
+        let (wireguard_tx, mut wireguard_rx) = broadcast::channel(16);
+        let (mail_tx, mut mail_rx) = unbounded_channel();
+
+        let network = create_network(&pool, "rotated", Some(30)).await;
+        let user_id = create_user(&pool, "hpotter", true).await;
+        let first = add_device(&pool, user_id, "first", &network).await;
+        let second = add_device(&pool, user_id, "second", &network).await;
+
+        rotate_preshared_keys(&pool, &wireguard_tx, &mail_tx)
+            .await
+            .unwrap();
+        assert_err!(wireguard_rx.try_recv());
+        let first = reload(&pool, &first).await;
+        let second = reload(&pool, &second).await;
+        let first_key = first.pending_preshared_key.clone().unwrap();
+        let second_key = second.pending_preshared_key.clone().unwrap();
+        assert!(first.preshared_key.is_none());
+
+        // configuration keeps the key gateways use until promotion
+        let device = Device::find_by_id(&pool, first.device_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!device.create_config(&network, &first).contains(&first_key));
+
+        // grace period not over yet
+        rotate_preshared_keys(&pool, &wireguard_tx, &mail_tx)
+            .await
+            .unwrap();
+        assert_err!(wireguard_rx.try_recv());
+
+        // keys staged earlier are promoted first
+        set_pending_created(&pool, &first, 4).await;
+        set_pending_created(&pool, &second, 5).await;
+        rotate_preshared_keys(&pool, &wireguard_tx, &mail_tx)
+            .await
+            .unwrap();
+        for (network_device, key) in [(&second, &second_key), (&first, &first_key)] {
+            let event = wireguard_rx.try_recv().unwrap();
+            assert_matches!(
+                event,
+                GatewayEvent::PeerModified(ref update, None)
+                    if update.device.id == Some(network_device.device_id)
+                        && update.network_info.preshared_key.as_ref() == Some(key)
+            );
+        }
+        assert_err!(wireguard_rx.try_recv());
+
+        for (network_device, key) in [(&first, first_key), (&second, second_key)] {
+            let network_device = reload(&pool, network_device).await;
+            assert_eq!(network_device.preshared_key, Some(key));
+            assert!(network_device.pending_preshared_key.is_none());
+            assert!(network_device.preshared_key_rotated.is_some());
+        }
+
+        // promoted key makes it into the configuration
+        let first = reload(&pool, &first).await;
+        let promoted = first.preshared_key.clone().unwrap();
+        assert!(device
+            .create_config(&network, &first)
+            .contains(&format!("PresharedKey = {promoted}")));
+
+        // freshly rotated keys are not staged again
+        rotate_preshared_keys(&pool, &wireguard_tx, &mail_tx)
+            .await
+            .unwrap();
+        assert_err!(wireguard_rx.try_recv());
+        assert!(mail_rx.try_recv().is_ok());
+        assert!(mail_rx.try_recv().is_ok());
+        assert_err!(mail_rx.try_recv());
+    }
+}
@@ -1,12 +1,11 @@
-use crate::db::{DbPool, WireguardPeerStats};
+use crate::{
+    db::{DbPool, WireguardPeerStats},
+    jobs::{Job, JobSchedule},
+};
 use chrono::{DateTime, Duration as ChronoDuration, NaiveDateTime, Utc};
 use humantime::format_duration;
 use sqlx::{query, query_scalar, Error as SqlxError, PgExecutor};
 use std::time::Duration;
-use tokio::time::sleep;
-
-// How long to sleep between loop iterations
-const PURGE_LOOP_SLEEP_SECONDS: u64 = 300; // 5 minutes
 
 impl WireguardPeerStats {
     /// Delete stats older than a configured threshold.
@@ -91,45 +90,27 @@ impl WireguardPeerStats {
     }
 }
 
-pub async fn run_periodic_stats_purge(
+/// Background job periodically purging stats older than configured threshold.
+#[must_use]
+pub fn stats_purge_job(
     pool: DbPool,
     stats_purge_frequency: Duration,
     stats_purge_threshold: Duration,
-) -> Result<(), SqlxError> {
+) -> Job {
     info!(
-        "Starting periodic purge of stats older than {} every {}",
+        "Scheduling purge of stats older than {} every {}",
         format_duration(stats_purge_threshold),
         format_duration(stats_purge_frequency)
     );
-
-    loop {
-        debug!("Checking if stats purge should be executed");
-        // check time elapsed since last purge
-        let time_since_last_purge = WireguardPeerStats::time_since_last_purge(&pool).await?;
-        if match time_since_last_purge {
-            Some(time_since) => time_since >= stats_purge_frequency,
-            None => true,
-        } {
-            // perform purge
-            info!("Executing stats purge");
-            match WireguardPeerStats::purge_old_stats(&pool, stats_purge_threshold).await {
-                Ok(()) => {
-                    let next_purge_timestamp = (Utc::now()
-                        + ChronoDuration::from_std(stats_purge_frequency)
-                            .expect("Failed to parse duration"))
-                    .naive_utc();
-                    info!(
-                        "Stats purge successful. Next purge will be executed at {next_purge_timestamp}"
-                    );
-                }
-                Err(err) => {
-                    error!("Error while purging stats: {err}");
-                }
+    Job::new(
+        "stats_purge",
+        JobSchedule::Interval(stats_purge_frequency),
+        move || {
+            let pool = pool.clone();
+            async move {
+                WireguardPeerStats::purge_old_stats(&pool, stats_purge_threshold).await?;
+                Ok(())
             }
-        }
-
-        // wait till next iteration
-        debug!("Sleeping until next iteration");
-        sleep(Duration::from_secs(PURGE_LOOP_SLEEP_SECONDS)).await;
-    }
+        },
+    )
 }
@@ -2,7 +2,7 @@ mod common;
 
 use std::{str::FromStr, time::SystemTime};
 
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime, Utc};
 use claims::assert_err;
 use common::fetch_user_details;
 use defguard::{
@@ -19,8 +19,8 @@ use otpauth::TOTP;
 use reqwest::{header::USER_AGENT, StatusCode};
 use secp256k1::{rand::rngs::OsRng, All, Message, Secp256k1, SecretKey};
 use serde::Deserialize;
-use serde_json::json;
-use sqlx::query;
+use serde_json::{json, Value};
+use sqlx::{query, query_scalar};
 use webauthn_authenticator_rs::{prelude::Url, softpasskey::SoftPasskey, WebauthnAuthenticator};
 use webauthn_rs::prelude::{CreationChallengeResponse, RequestChallengeResponse};
 
@@ -37,7 +37,7 @@ async fn make_client() -> TestClient {
     let (client, client_state) = make_test_client().await;
 
     let mut wallet = Wallet::new_for_user(
-        client_state.test_user.id.unwrap(),
+        client_state.test_user().id.unwrap(),
         "0x4aF8803CBAD86BA65ED347a3fbB3fb50e96eDD3e",
         "test",
         5,
@@ -52,7 +52,7 @@ async fn make_client_with_db() -> (TestClient, DbPool) {
     let (client, client_state) = make_test_client().await;
 
     let mut wallet = Wallet::new_for_user(
-        client_state.test_user.id.unwrap(),
+        client_state.test_user().id.unwrap(),
         "0x4aF8803CBAD86BA65ED347a3fbB3fb50e96eDD3e",
         "test",
         5,
@@ -67,7 +67,7 @@ async fn make_client_with_state() -> (TestClient, ClientState) {
     let (client, client_state) = make_test_client().await;
 
     let mut wallet = Wallet::new_for_user(
-        client_state.test_user.id.unwrap(),
+        client_state.test_user().id.unwrap(),
         "0x4aF8803CBAD86BA65ED347a3fbB3fb50e96eDD3e",
         "test",
         5,
@@ -82,7 +82,7 @@ async fn make_client_with_wallet(address: &str) -> TestClient {
     let (client, client_state) = make_test_client().await;
 
     let mut wallet =
-        Wallet::new_for_user(client_state.test_user.id.unwrap(), address, "test", 5, "");
+        Wallet::new_for_user(client_state.test_user().id.unwrap(), address, "test", 5, "");
     wallet.save(&client_state.pool).await.unwrap();
 
     client
@@ -305,6 +305,94 @@ async fn test_totp() {
     assert_eq!(response.status(), StatusCode::OK);
 }
 
+#[derive(Deserialize)]
+pub struct RecoveryCodesStatus {
+    remaining: usize,
+    used_at: Vec<NaiveDateTime>,
+}
+
+#[tokio::test]
+async fn test_recovery_codes_regeneration() {
+    let client = make_client().await;
+
+    // login
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // enable TOTP and MFA
+    let response = client.post("/api/v1/auth/totp/init").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth_totp: AuthTotp = response.json().await;
+    let code = totp_code(&auth_totp);
+    let response = client.post("/api/v1/auth/totp").json(&code).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let old_codes = response.json::<RecoveryCodes>().await.codes.unwrap();
+    let response = client.put("/api/v1/auth/mfa").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // login with recovery code
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/auth/recovery")
+        .json(&json!({ "code": old_codes[0] }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // count decremented and usage recorded
+    let response = client.get("/api/v1/me/mfa/recovery").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let status: RecoveryCodesStatus = response.json().await;
+    assert_eq!(status.remaining, 7);
+    assert_eq!(status.used_at.len(), 1);
+    let user_details = fetch_user_details(&client, "hpotter").await;
+    assert_eq!(user_details.recovery_codes_remaining, 7);
+
+    // regeneration requires a valid MFA code
+    let response = client
+        .post("/api/v1/me/mfa/recovery/regenerate")
+        .json(&AuthCode::new(0))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = client
+        .post("/api/v1/me/mfa/recovery/regenerate")
+        .json(&totp_code(&auth_totp))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let new_codes = response.json::<RecoveryCodes>().await.codes.unwrap();
+    assert_eq!(new_codes.len(), 8); // RECOVERY_CODES_COUNT
+
+    let response = client.get("/api/v1/me/mfa/recovery").send().await;
+    let status: RecoveryCodesStatus = response.json().await;
+    assert_eq!(status.remaining, 8);
+    assert!(status.used_at.is_empty());
+
+    // old code no longer works
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/auth/recovery")
+        .json(&json!({ "code": old_codes[1] }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // new one does
+    let response = client
+        .post("/api/v1/auth/recovery")
+        .json(&json!({ "code": new_codes[0] }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
 static EMAIL_CODE_REGEX: &str = r"<b>(?<code>\d{6})</b>";
 fn extract_email_code(content: &str) -> u32 {
     let re = regex::Regex::new(EMAIL_CODE_REGEX).unwrap();
@@ -1095,7 +1183,8 @@ async fn test_login_ip_headers() {
     let mut mail_rx = state.mail_rx;
     let user_agent_header_iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Mobile/15E148 Safari/604.1";
 
-    // Works with X-Forwarded-For header
+    // Works with X-Forwarded-For header, test clients connect through a trusted proxy.
+    // Leftmost entry is set by the client and can't be trusted.
     let auth = Auth::new("hpotter", "pass123");
     let response = client
         .post("/api/v1/auth")
@@ -1112,7 +1201,7 @@ async fn test_login_ip_headers() {
         mail.subject,
         "Defguard: new device logged in to your account"
     );
-    assert!(mail.content.contains("IP Address:</span> 10.0.0.20"));
+    assert!(mail.content.contains("IP Address:</span> 10.1.1.10"));
 }
 
 #[tokio::test]
@@ -1147,6 +1236,75 @@ async fn test_session_cookie() {
     assert!(auth_cookie.is_none());
 }
 
+#[tokio::test]
+async fn test_session_idle_timeout() {
+    let (client, pool) = make_client_with_db().await;
+
+    query!("UPDATE settings SET session_idle_timeout = 30")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let session_id = response
+        .cookies()
+        .find(|c| c.name() == SESSION_COOKIE_NAME)
+        .unwrap()
+        .value()
+        .to_string();
+    let row_version = || {
+        query_scalar::<_, String>("SELECT xmin::text FROM session WHERE id = $1")
+            .bind(&session_id)
+            .fetch_one(&pool)
+    };
+
+    // activity is written at most once a minute
+    let version = row_version().await.unwrap();
+    for _ in 0..3 {
+        let response = client.get("/api/v1/me").send().await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    assert_eq!(row_version().await.unwrap(), version);
+
+    query!(
+        "UPDATE session SET last_activity = now() - interval '2 minutes' WHERE id = $1",
+        session_id
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let response = client.get("/api/v1/me").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let last_activity: NaiveDateTime =
+        query_scalar("SELECT last_activity FROM session WHERE id = $1")
+            .bind(&session_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert!(last_activity > Utc::now().naive_utc() - Duration::minutes(1));
+
+    // inactive for longer than the idle timeout, though far from absolute expiry
+    query!(
+        "UPDATE session SET last_activity = now() - interval '31 minutes' WHERE id = $1",
+        session_id
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let response = client.get("/api/v1/me").send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let error: Value = response.json().await;
+    assert_eq!(error["code"], "session_idle");
+
+    // session is gone
+    let response = client.get("/api/v1/me").send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let error: Value = response.json().await;
+    assert_eq!(error["code"], Value::Null);
+}
+
 #[tokio::test]
 async fn test_all_session_logout() {
     let (client, pool) = make_client_with_db().await;
@@ -0,0 +1,156 @@
+mod common;
+
+use defguard::{
+    auth::challenge::CHALLENGE_HEADER, db::models::settings::AuthChallenge, handlers::Auth,
+};
+use reqwest::{header::HeaderName, StatusCode};
+use serde_json::{json, Value};
+
+use self::common::{client::TestClient, make_test_client, ClientState, TestServerBuilder};
+
+async fn make_client(on_pressure: bool) -> (TestClient, ClientState) {
+    TestServerBuilder::new()
+        .with_settings(move |settings| {
+            settings.auth_challenge = AuthChallenge::ProofOfWork;
+            settings.auth_challenge_on_pressure = on_pressure;
+            settings.auth_challenge_pow_difficulty = 8;
+            settings.enrollment_web_fallback_enabled = true;
+        })
+        .build()
+        .await
+}
+
+fn challenge_header() -> HeaderName {
+    HeaderName::from_static(CHALLENGE_HEADER)
+}
+
+#[tokio::test]
+async fn test_challenge_disabled() {
+    let (client, _) = make_test_client().await;
+
+    let response = client.get("/api/v1/auth/challenge").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let info: Value = response.json().await;
+    assert_eq!(info, json!({"kind": "none", "required": false}));
+
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_proof_of_work() {
+    let (mut client, _) = make_client(false).await;
+
+    let response = client.get("/api/v1/auth/challenge").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let info: Value = response.json().await;
+    assert_eq!(info["kind"], "proof_of_work");
+    assert_eq!(info["required"], true);
+    assert_eq!(info["difficulty"], 8);
+
+    // requests without a solution are rejected
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let error: Value = response.json().await;
+    assert_eq!(error["code"], "challenge_required");
+    let response = client
+        .post("/api/v1/enrollment/start")
+        .json(&json!({"token": "unknown"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = client
+        .post("/api/v1/auth")
+        .json(&auth)
+        .header(challenge_header(), "garbage:1")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // solved challenge can be used once
+    let solution = client.challenge_solution().await;
+    let response = client
+        .post("/api/v1/auth")
+        .json(&auth)
+        .header(challenge_header(), &solution)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/auth")
+        .json(&auth)
+        .header(challenge_header(), &solution)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // client solving challenges on its own works as usual
+    client.solve_challenges(true);
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/enrollment/start")
+        .json(&json!({"token": "unknown"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_challenge_on_pressure() {
+    let (client, _) = make_client(true).await;
+
+    let response = client.get("/api/v1/auth/challenge").send().await;
+    let info: Value = response.json().await;
+    assert_eq!(info["required"], false);
+    assert!(info["nonce"].is_string());
+
+    // not required without failed attempts
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // required after failed login of the same user
+    let wrong_auth = Auth::new("hpotter", "wrong");
+    let response = client.post("/api/v1/auth").json(&wrong_auth).send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let solution = client.challenge_solution().await;
+    let response = client
+        .post("/api/v1/auth")
+        .json(&auth)
+        .header(challenge_header(), &solution)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // other users aren't affected by a single failure
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // invalid settings are rejected
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"auth_challenge_pow_difficulty": 64}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"auth_challenge": "turnstile"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
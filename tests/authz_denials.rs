@@ -0,0 +1,103 @@
+mod common;
+
+use defguard::handlers::Auth;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use self::common::{
+    client::{TestClient, TestResponse},
+    make_test_client,
+};
+
+async fn login(client: &TestClient, username: &str) {
+    let auth = Auth::new(username, "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn logout(client: &TestClient) {
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+/// Trace ID of a denied request.
+async fn trace_id(response: TestResponse) -> String {
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let body: Value = response.json().await;
+    body["trace_id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_authz_denials() {
+    let (client, _) = make_test_client().await;
+
+    // denied by different role extractors
+    login(&client, "hpotter").await;
+    let consistency_trace_id =
+        trace_id(client.get("/api/v1/system/consistency").send().await).await;
+    let users_trace_id = trace_id(client.get("/api/v1/user").send().await).await;
+    assert_ne!(consistency_trace_id, users_trace_id);
+    let response = client
+        .get("/api/v1/system/authz_denials?username=hpotter")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    logout(&client).await;
+
+    // denied while impersonating
+    login(&client, "admin").await;
+    let response = client.post("/api/v1/user/hpotter/impersonate").send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let impersonation_trace_id = trace_id(
+        client
+            .put("/api/v1/user/hpotter")
+            .json(&json!({"first_name": "Secret"}))
+            .send()
+            .await,
+    )
+    .await;
+    let response = client.post("/api/v1/auth/impersonate/end").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // newest first, the impersonation denial was recorded for the admin
+    let response = client
+        .get("/api/v1/system/authz_denials?username=hpotter")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let denials: Vec<Value> = response.json().await;
+    assert_eq!(denials.len(), 3);
+    assert_eq!(denials[0]["route"], "/api/v1/system/authz_denials");
+    assert_eq!(denials[1]["trace_id"], users_trace_id);
+    assert_eq!(denials[1]["method"], "GET");
+    assert_eq!(denials[1]["route"], "/api/v1/user");
+    assert_eq!(denials[1]["passed"], json!(["session"]));
+    assert_eq!(denials[1]["denied_by"], "group_membership");
+    assert_eq!(denials[1]["required_groups"], json!(["admin", "useradmin"]));
+    assert_eq!(denials[2]["trace_id"], consistency_trace_id);
+    assert_eq!(denials[2]["route"], "/api/v1/system/consistency");
+    assert_eq!(denials[2]["denied_by"], "group_membership");
+    assert_eq!(denials[2]["required_groups"], json!(["admin"]));
+
+    let response = client
+        .get("/api/v1/system/authz_denials?username=admin")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let denials: Vec<Value> = response.json().await;
+    assert_eq!(denials.len(), 1);
+    assert_eq!(denials[0]["trace_id"], impersonation_trace_id);
+    assert_eq!(denials[0]["method"], "PUT");
+    // path parameters and request body aren't recorded
+    assert_eq!(denials[0]["route"], "/api/v1/user/:username");
+    assert_eq!(denials[0]["passed"], json!(["session"]));
+    assert_eq!(denials[0]["denied_by"], "impersonation");
+    assert_eq!(denials[0]["required_groups"], json!([]));
+    assert!(!denials[0].to_string().contains("Secret"));
+
+    let response = client
+        .get("/api/v1/system/authz_denials?username=nobody")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
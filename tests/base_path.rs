@@ -0,0 +1,101 @@
+mod common;
+
+use defguard::handlers::{AddUserData, Auth};
+use reqwest::{header::SET_COOKIE, StatusCode, Url};
+use serde_json::{json, Value};
+
+use self::common::{client::TestResponse, TestServerBuilder};
+
+static BASE_URL: &str = "http://localhost:8000/defguard/";
+
+/// Session cookie set by a response.
+fn session_cookie(response: &TestResponse) -> String {
+    response
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .map(|value| value.to_str().unwrap().to_string())
+        .find(|cookie| cookie.starts_with("defguard_session="))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_served_under_base_path() {
+    let (client, mut client_state) = TestServerBuilder::new()
+        .with_config(|config| config.url = Url::parse(BASE_URL).unwrap())
+        .build()
+        .await;
+
+    // API is only served under the base path
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = client
+        .post("/defguard/api/v1/auth")
+        .json(&auth)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(session_cookie(&response).contains("Path=/defguard;"));
+    let response = client.get("/defguard/api/v1/me").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // enrollment email links to the Web UI under the base path
+    let new_user = AddUserData {
+        username: "adumbledore".into(),
+        last_name: "Dumbledore".into(),
+        first_name: "Albus".into(),
+        email: "a.dumbledore@hogwart.edu.uk".into(),
+        phone: None,
+        password: None,
+    };
+    let response = client
+        .post("/defguard/api/v1/user")
+        .json(&new_user)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/defguard/api/v1/user/adumbledore/start_enrollment")
+        .json(&json!({
+            "email": "a.dumbledore@hogwart.edu.uk",
+            "send_enrollment_notification": true,
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let mail = client_state.mail_rx.try_recv().unwrap();
+    assert!(mail.content.contains(BASE_URL));
+
+    // discovery is served under the base path and at the root, advertising prefixed endpoints
+    for path in [
+        "/defguard/.well-known/openid-configuration",
+        "/.well-known/openid-configuration",
+    ] {
+        let response = client.get(path).send().await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let discovery: Value = response.json().await;
+        assert_eq!(discovery["issuer"], BASE_URL);
+        for endpoint in [
+            "authorization_endpoint",
+            "token_endpoint",
+            "userinfo_endpoint",
+            "jwks_uri",
+        ] {
+            assert!(
+                discovery[endpoint]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("http://localhost:8000/defguard/api/v1/oauth/"),
+                "{endpoint} isn't under the base path"
+            );
+        }
+    }
+
+    // logout removes the cookie set under the base path
+    let response = client.post("/defguard/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(session_cookie(&response).contains("Path=/defguard;"));
+    let response = client.get("/defguard/api/v1/me").send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use defguard::{
+    break_glass::{create_break_glass_account, BreakGlassError},
+    db::{DbPool, User},
+    handlers::Auth,
+    mail::Mail,
+};
+use reqwest::StatusCode;
+use sqlx::query_scalar;
+use tokio::{sync::mpsc::UnboundedReceiver, time::timeout};
+
+use self::common::{make_test_client, ClientState};
+
+static BREAK_GLASS_PASSWORD: &str = "Emergency!Acc3ss";
+static BREAK_GLASS_LOGIN_SUBJECT: &str = "Defguard: break-glass account used";
+
+async fn create_account(pool: &DbPool) -> User {
+    create_break_glass_account(
+        pool,
+        "breakglass",
+        "breakglass@defguard",
+        BREAK_GLASS_PASSWORD,
+    )
+    .await
+    .unwrap()
+}
+
+/// Collect notification mails sent in the background.
+async fn notification_recipients(mail_rx: &mut UnboundedReceiver<Mail>) -> Vec<String> {
+    let mut recipients = Vec::new();
+    while let Ok(Some(mail)) = timeout(Duration::from_millis(500), mail_rx.recv()).await {
+        if mail.subject == BREAK_GLASS_LOGIN_SUBJECT {
+            recipients.push(mail.to);
+        }
+    }
+    recipients
+}
+
+#[tokio::test]
+async fn test_break_glass_account() {
+    let (client, state) = make_test_client().await;
+    let ClientState {
+        pool, mut mail_rx, ..
+    } = state;
+    let user = create_account(&pool).await;
+    assert!(user.break_glass);
+
+    // can't take over existing users
+    assert!(matches!(
+        create_break_glass_account(&pool, "hpotter", "h@defguard", BREAK_GLASS_PASSWORD).await,
+        Err(BreakGlassError::UserExists(_))
+    ));
+    // password policy applies
+    assert!(matches!(
+        create_break_glass_account(&pool, "breakglass2", "b@defguard", "short").await,
+        Err(BreakGlassError::PasswordPolicy(_))
+    ));
+
+    // break-glass account is an admin
+    let auth = Auth::new("breakglass", BREAK_GLASS_PASSWORD);
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/user").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // admins are notified
+    let recipients = notification_recipients(&mut mail_rx).await;
+    assert!(recipients.contains(&"admin@defguard".to_string()));
+
+    // session is short-lived
+    let expires: NaiveDateTime =
+        query_scalar("SELECT max(expires) FROM session WHERE user_id = $1")
+            .bind(user.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert!(expires <= Utc::now().naive_utc() + chrono::Duration::minutes(30));
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_break_glass_blocked_by_active_admin() {
+    let (client, state) = make_test_client().await;
+    let ClientState {
+        pool, mut mail_rx, ..
+    } = state;
+    create_account(&pool).await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // a regular admin has just logged in
+    let auth = Auth::new("breakglass", BREAK_GLASS_PASSWORD);
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(notification_recipients(&mut mail_rx).await.is_empty());
+
+    // wrong password is still rejected as usual
+    let auth = Auth::new("breakglass", "wrong");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
@@ -2,18 +2,29 @@ use std::{net::SocketAddr, sync::Arc};
 
 use axum::{serve, Router};
 use bytes::Bytes;
+use defguard::auth::challenge::{solve_proof_of_work, CHALLENGE_HEADER};
 use reqwest::{
-    cookie::{Cookie, Jar},
+    cookie::{Cookie, CookieStore, Jar},
     header::{HeaderMap, HeaderName},
     redirect::Policy,
     Body, Client, StatusCode, Url,
 };
-use tokio::net::TcpListener;
+use serde_json::Value;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{header::COOKIE, HeaderValue},
+    },
+    MaybeTlsStream, WebSocketStream,
+};
 
 pub struct TestClient {
     client: Client,
     jar: Arc<Jar>,
     port: u16,
+    solve_challenges: bool,
 }
 
 #[allow(dead_code)]
@@ -41,7 +52,12 @@ impl TestClient {
             .build()
             .unwrap();
 
-        TestClient { client, jar, port }
+        TestClient {
+            client,
+            jar,
+            port,
+            solve_challenges: false,
+        }
     }
 
     pub fn set_cookie(&mut self, cookie: &Cookie) {
@@ -54,17 +70,32 @@ impl TestClient {
     ///
     /// this is useful when trying to check if Location headers in responses
     /// are generated correctly as Location contains an absolute URL
-    fn base_url(&self) -> String {
+    pub fn base_url(&self) -> String {
         let mut s = String::from("http://localhost:");
         s.push_str(&self.port.to_string());
         s
     }
 
+    /// Solve proof of work challenge before each POST request, so that tests
+    /// keep working with authentication challenges enabled.
+    pub fn solve_challenges(&mut self, enabled: bool) {
+        self.solve_challenges = enabled;
+    }
+
+    /// Fetch a proof of work challenge and return its solution,
+    /// to be sent in the challenge header.
+    pub async fn challenge_solution(&self) -> String {
+        let info: Value = self.get("/api/v1/auth/challenge").send().await.json().await;
+        solve(&info).expect("Proof of work challenge is not enabled")
+    }
+
     pub fn get<T: AsRef<str>>(&self, url: T) -> RequestBuilder {
         let mut full_url = self.base_url();
         full_url.push_str(url.as_ref());
         RequestBuilder {
             builder: self.client.get(full_url),
+            challenge_url: None,
+            client: self.client.clone(),
         }
     }
 
@@ -73,6 +104,8 @@ impl TestClient {
         full_url.push_str(url.as_ref());
         RequestBuilder {
             builder: self.client.head(full_url),
+            challenge_url: None,
+            client: self.client.clone(),
         }
     }
 
@@ -81,6 +114,10 @@ impl TestClient {
         full_url.push_str(url.as_ref());
         RequestBuilder {
             builder: self.client.post(full_url),
+            challenge_url: self
+                .solve_challenges
+                .then(|| format!("{}/api/v1/auth/challenge", self.base_url())),
+            client: self.client.clone(),
         }
     }
 
@@ -89,6 +126,8 @@ impl TestClient {
         full_url.push_str(url.as_ref());
         RequestBuilder {
             builder: self.client.put(full_url),
+            challenge_url: None,
+            client: self.client.clone(),
         }
     }
 
@@ -97,6 +136,8 @@ impl TestClient {
         full_url.push_str(url.as_ref());
         RequestBuilder {
             builder: self.client.patch(full_url),
+            challenge_url: None,
+            client: self.client.clone(),
         }
     }
 
@@ -105,17 +146,61 @@ impl TestClient {
         full_url.push_str(url.as_ref());
         RequestBuilder {
             builder: self.client.delete(full_url),
+            challenge_url: None,
+            client: self.client.clone(),
+        }
+    }
+
+    /// Open a WebSocket connection, sending cookies of this client.
+    pub async fn websocket<T: AsRef<str>>(
+        &self,
+        url: T,
+    ) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
+        let mut request = format!("ws://localhost:{}{}", self.port, url.as_ref())
+            .into_client_request()
+            .unwrap();
+        let base_url = Url::parse(&self.base_url()).unwrap();
+        if let Some(cookies) = self.jar.cookies(&base_url) {
+            request
+                .headers_mut()
+                .insert(COOKIE, HeaderValue::from_bytes(cookies.as_bytes()).unwrap());
         }
+        let (stream, _) = connect_async(request).await.unwrap();
+        stream
     }
 }
 
+// Solve proof of work challenge described by challenge endpoint response, if there is one.
+fn solve(info: &Value) -> Option<String> {
+    let nonce = info["nonce"].as_str()?;
+    let difficulty = info["difficulty"].as_u64()?;
+    Some(solve_proof_of_work(nonce, difficulty as u8))
+}
+
 pub struct RequestBuilder {
     builder: reqwest::RequestBuilder,
+    // fetch and solve a challenge before sending the request
+    challenge_url: Option<String>,
+    client: Client,
 }
 
 #[allow(dead_code)]
 impl RequestBuilder {
-    pub async fn send(self) -> TestResponse {
+    pub async fn send(mut self) -> TestResponse {
+        if let Some(url) = self.challenge_url.take() {
+            let info: Value = self
+                .client
+                .get(url)
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            if let Some(solution) = solve(&info) {
+                self.builder = self.builder.header(CHALLENGE_HEADER, solution);
+            }
+        }
         TestResponse {
             response: self.builder.send().await.unwrap(),
         }
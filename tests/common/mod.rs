@@ -6,15 +6,20 @@ use defguard::{
     auth::failed_login::FailedLoginMap,
     build_webapp,
     config::DefGuardConfig,
-    db::{init_db, AppEvent, DbPool, GatewayEvent, User, UserDetails},
+    db::{init_db, AppEvent, DbPool, GatewayEvent, ReadPool, Settings, User, UserDetails},
     grpc::{GatewayMap, WorkerState},
     headers::create_user_agent_parser,
+    jobs::JobRunner,
     mail::Mail,
     SERVER_CONFIG,
 };
 use reqwest::{header::HeaderName, StatusCode};
 use secrecy::ExposeSecret;
-use sqlx::{postgres::PgConnectOptions, query, types::Uuid};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    query,
+    types::Uuid,
+};
 use tokio::sync::{
     broadcast::{self, Receiver},
     mpsc::{unbounded_channel, UnboundedReceiver},
@@ -29,9 +34,25 @@ pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for
 #[allow(dead_code, clippy::declare_interior_mutable_const)]
 pub const X_FORWARDED_URI: HeaderName = HeaderName::from_static("x-forwarded-uri");
 
-pub async fn init_test_db() -> (DbPool, DefGuardConfig) {
-    let config = DefGuardConfig::new_test_config();
-    let _ = SERVER_CONFIG.set(config.clone());
+/// Set process-wide server config.
+///
+/// `SERVER_CONFIG` can be set only once and each file in `tests/` is built into a separate
+/// test binary, so all tests in a single file must use the same configuration.
+/// Tests running in parallel with a different configuration would silently use the wrong one,
+/// hence the panic.
+fn set_server_config(config: &DefGuardConfig) {
+    if SERVER_CONFIG.set(config.clone()).is_err() {
+        let current = SERVER_CONFIG.get().expect("Server config not set");
+        assert_eq!(
+            format!("{current:?}"),
+            format!("{config:?}"),
+            "Server config was already set to a different value by another test, \
+            tests with config overrides have to be moved to a separate file"
+        );
+    }
+}
+
+async fn create_test_db(config: &DefGuardConfig) -> DbPool {
     let opts = PgConnectOptions::new()
         .host(&config.database_host)
         .port(config.database_port)
@@ -46,15 +67,21 @@ pub async fn init_test_db() -> (DbPool, DefGuardConfig) {
         .execute(&pool)
         .await
         .expect("Failed to create test database");
-    let pool = init_db(
+    init_db(
         &config.database_host,
         config.database_port,
         &db_name,
         &config.database_user,
         config.database_password.expose_secret(),
     )
-    .await;
+    .await
+}
 
+#[allow(dead_code)]
+pub async fn init_test_db() -> (DbPool, DefGuardConfig) {
+    let config = DefGuardConfig::new_test_config();
+    set_server_config(&config);
+    let pool = create_test_db(&config).await;
     initialize_users(&pool, config.clone()).await;
 
     (pool, config)
@@ -82,20 +109,28 @@ pub struct ClientState {
     pub wireguard_rx: Receiver<GatewayEvent>,
     pub mail_rx: UnboundedReceiver<Mail>,
     pub failed_logins: Arc<Mutex<FailedLoginMap>>,
-    pub test_user: User,
+    test_user: Option<User>,
     pub config: DefGuardConfig,
+    pub read_pool: ReadPool,
+}
+
+/// Database pools the test server was built with.
+pub struct TestPools {
+    pub pool: DbPool,
+    pub read_pool: ReadPool,
 }
 
 impl ClientState {
     pub fn new(
-        pool: DbPool,
+        pools: TestPools,
         worker_state: Arc<Mutex<WorkerState>>,
         wireguard_rx: Receiver<GatewayEvent>,
         mail_rx: UnboundedReceiver<Mail>,
         failed_logins: Arc<Mutex<FailedLoginMap>>,
-        test_user: User,
+        test_user: Option<User>,
         config: DefGuardConfig,
     ) -> Self {
+        let TestPools { pool, read_pool } = pools;
         Self {
             pool,
             worker_state,
@@ -104,11 +139,28 @@ impl ClientState {
             failed_logins,
             test_user,
             config,
+            read_pool,
         }
     }
+
+    /// Standard (non-admin) user seeded in the test database.
+    #[allow(dead_code)]
+    pub fn test_user(&self) -> &User {
+        self.test_user.as_ref().expect("Test users were not seeded")
+    }
 }
 
+#[allow(dead_code)]
 pub async fn make_base_client(pool: DbPool, config: DefGuardConfig) -> (TestClient, ClientState) {
+    let read_pool = ReadPool::primary(pool.clone());
+    make_client_with_read_pool(pool, read_pool, config).await
+}
+
+async fn make_client_with_read_pool(
+    pool: DbPool,
+    read_pool: ReadPool,
+    config: DefGuardConfig,
+) -> (TestClient, ClientState) {
     let (tx, rx) = unbounded_channel::<AppEvent>();
     let worker_state = Arc::new(Mutex::new(WorkerState::new(tx.clone())));
     let (wg_tx, wg_rx) = broadcast::channel::<GatewayEvent>(16);
@@ -119,17 +171,18 @@ pub async fn make_base_client(pool: DbPool, config: DefGuardConfig) -> (TestClie
     let failed_logins = Arc::new(Mutex::new(failed_logins));
 
     let user_agent_parser = create_user_agent_parser();
+    let job_runner = Arc::new(JobRunner::new(pool.clone()));
 
     let client_state = ClientState::new(
-        pool.clone(),
+        TestPools {
+            pool: pool.clone(),
+            read_pool: read_pool.clone(),
+        },
         worker_state.clone(),
         wg_rx,
         mail_rx,
         failed_logins.clone(),
-        User::find_by_username(&pool, "hpotter")
-            .await
-            .unwrap()
-            .unwrap(),
+        User::find_by_username(&pool, "hpotter").await.unwrap(),
         config.clone(),
     );
 
@@ -152,16 +205,112 @@ pub async fn make_base_client(pool: DbPool, config: DefGuardConfig) -> (TestClie
         worker_state,
         gateway_state,
         pool,
+        read_pool,
         user_agent_parser,
         failed_logins,
+        job_runner,
     );
     (TestClient::new(webapp).await, client_state)
 }
 
+type SettingsOverride = Box<dyn FnOnce(&mut Settings)>;
+
+/// Builder for test server instances with customized configuration and seed data.
+///
+/// ```ignore
+/// let (client, client_state) = TestServerBuilder::new()
+///     .with_config(|config| config.enrollment_token_timeout = Duration::from_secs(60))
+///     .with_settings(|settings| settings.smtp_server = Some("localhost".into()))
+///     .build()
+///     .await;
+/// ```
+///
+/// Config overrides are process-wide, see [`set_server_config`].
+pub struct TestServerBuilder {
+    config: DefGuardConfig,
+    settings: Vec<SettingsOverride>,
+    seed_users: bool,
+    read_replica: bool,
+}
+
+#[allow(dead_code)]
+impl TestServerBuilder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            config: DefGuardConfig::new_test_config(),
+            settings: Vec::new(),
+            seed_users: true,
+            read_replica: false,
+        }
+    }
+
+    /// Modify server configuration before it's set as `SERVER_CONFIG`.
+    #[must_use]
+    pub fn with_config<F: FnOnce(&mut DefGuardConfig)>(mut self, f: F) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    /// Modify settings stored in the test database, applied after default settings.
+    #[must_use]
+    pub fn with_settings<F: FnOnce(&mut Settings) + 'static>(mut self, f: F) -> Self {
+        self.settings.push(Box::new(f));
+        self
+    }
+
+    /// Don't create default admin and test users.
+    #[must_use]
+    pub fn without_users(mut self) -> Self {
+        self.seed_users = false;
+        self
+    }
+
+    /// Serve read-only endpoints from a separate read-only pool connected to the test
+    /// database, standing in for a read replica.
+    #[must_use]
+    pub fn with_read_replica(mut self) -> Self {
+        self.read_replica = true;
+        self
+    }
+
+    pub async fn build(self) -> (TestClient, ClientState) {
+        set_server_config(&self.config);
+        let pool = create_test_db(&self.config).await;
+
+        // same as on server startup
+        Settings::init_defaults(&pool).await.unwrap();
+        if !self.settings.is_empty() {
+            let mut settings = Settings::get_settings(&pool).await.unwrap();
+            for f in self.settings {
+                f(&mut settings);
+            }
+            settings.save(&pool).await.unwrap();
+        }
+
+        if self.seed_users {
+            initialize_users(&pool, self.config.clone()).await;
+        }
+
+        let read_pool = if self.read_replica {
+            let options = pool.connect_options().as_ref().clone();
+            ReadPool::replica(options, PgPoolOptions::new())
+        } else {
+            ReadPool::primary(pool.clone())
+        };
+        make_client_with_read_pool(pool, read_pool, self.config).await
+    }
+}
+
+impl Default for TestServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[allow(dead_code)]
 pub async fn make_test_client() -> (TestClient, ClientState) {
-    let (pool, config) = init_test_db().await;
-    make_base_client(pool, config).await
+    TestServerBuilder::new().build().await
 }
 
 #[allow(dead_code)]
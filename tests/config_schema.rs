@@ -0,0 +1,50 @@
+mod common;
+
+use defguard::handlers::Auth;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use self::common::make_test_client;
+
+#[tokio::test]
+async fn test_config_schema_api() {
+    let (client, _) = make_test_client().await;
+
+    // normal user can't see configuration
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/system/config_schema").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/system/config_schema").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let options: Vec<Value> = response.json().await;
+    let option = |name: &str| {
+        options
+            .iter()
+            .find(|option| option["name"] == name)
+            .unwrap()
+            .clone()
+    };
+
+    let secret_key = option("secret_key");
+    assert_eq!(secret_key["env"], "DEFGUARD_SECRET_KEY");
+    assert_eq!(secret_key["secret"], true);
+    assert_eq!(secret_key["value"], json!("********"));
+    let admin_password = option("default_admin_password");
+    assert_eq!(admin_password["value"], json!("********"));
+    assert!(options
+        .iter()
+        .filter(|option| option["secret"] == true)
+        .all(|option| option["value"].is_null() || option["value"] == "********"));
+
+    let http_port = option("http_port");
+    assert_eq!(http_port["flag"], "--http-port");
+    assert_eq!(http_port["default"], "8000");
+    assert_eq!(http_port["value"], 8000);
+    assert_eq!(option("admin_groupname")["value"], "admin");
+}
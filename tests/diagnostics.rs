@@ -0,0 +1,110 @@
+mod common;
+
+use defguard::{
+    consistency::ConsistencyReport,
+    db::Settings,
+    diagnostics::{check_public_url, CheckStatus, DiagnosticsReport},
+    handlers::Auth,
+};
+use reqwest::{StatusCode, Url};
+use serde_json::json;
+use uuid::Uuid;
+
+use self::common::make_test_client;
+
+#[tokio::test]
+async fn test_diagnostics_requires_admin() {
+    let (client, _) = make_test_client().await;
+
+    let response = client.post("/api/v1/system/diagnostics").send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post("/api/v1/system/diagnostics").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/system/diagnostics")
+        .json(&json!({}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report: DiagnosticsReport = response.json().await;
+    let status = |name: &str| {
+        report
+            .checks
+            .iter()
+            .find(|check| check.name == name)
+            .map(|check| check.status)
+    };
+    assert_eq!(status("database"), Some(CheckStatus::Pass));
+    assert_eq!(status("smtp"), Some(CheckStatus::Warn));
+    assert_eq!(status("ldap"), Some(CheckStatus::Pass));
+    assert!(status("public_url").is_some());
+}
+
+#[tokio::test]
+async fn test_public_url_probe() {
+    let (client, client_state) = make_test_client().await;
+    let settings = Settings::get_settings(&client_state.pool).await.unwrap();
+    let url = Url::parse(&client.base_url()).unwrap();
+
+    let results = check_public_url(&url, &settings.uuid).await;
+    assert_eq!(results[0].status, CheckStatus::Pass);
+
+    // URL leads to another instance
+    let results = check_public_url(&url, &Uuid::new_v4()).await;
+    assert_eq!(results[0].status, CheckStatus::Fail);
+    assert!(results[0].hint.is_some());
+
+    // nonces are short
+    let response = client
+        .get(format!("/api/v1/system/probe?nonce={}", "x".repeat(100)))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_consistency_check() {
+    let (client, client_state) = make_test_client().await;
+
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/system/consistency").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    sqlx::query("INSERT INTO oauth2authorizedapp (oauth2client_id, user_id) VALUES (12345, 12345)")
+        .execute(&client_state.pool)
+        .await
+        .unwrap();
+
+    let response = client.get("/api/v1/system/consistency").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report: ConsistencyReport = response.json().await;
+    assert!(report.has_issues());
+
+    let response = client
+        .get("/api/v1/system/consistency?repair=true")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report: ConsistencyReport = response.json().await;
+    assert!(!report.has_issues());
+    let response = client.get("/api/v1/system/consistency").send().await;
+    let report: ConsistencyReport = response.json().await;
+    assert!(report.checks.iter().all(|check| check.count == 0));
+}
@@ -1,15 +1,25 @@
 mod common;
 
+use std::time::SystemTime;
+
 use common::fetch_user_details;
 use defguard::{
-    db::{models::enrollment::Token, DbPool},
-    handlers::{AddUserData, Auth},
+    auth::TOTP_CODE_VALIDITY_PERIOD,
+    db::{
+        models::{
+            enrollment::Token,
+            wireguard::{DEFAULT_DISCONNECT_THRESHOLD, DEFAULT_KEEPALIVE_INTERVAL},
+        },
+        DbPool, MFAMethod, User, UserInfo, WireguardNetwork,
+    },
+    handlers::{AddUserData, Auth, AuthTotp},
 };
+use otpauth::TOTP;
 use reqwest::StatusCode;
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 
-use self::common::{client::TestClient, make_test_client};
+use self::common::{client::TestClient, make_test_client, X_FORWARDED_FOR};
 
 async fn make_client() -> (TestClient, DbPool) {
     let (client, client_state) = make_test_client().await;
@@ -123,3 +133,352 @@ async fn test_enroll_disabled_user() {
         .await;
     assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 }
+
+fn totp_code(auth_totp: &AuthTotp) -> u32 {
+    let auth = TOTP::from_base32(auth_totp.secret.clone()).unwrap();
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    auth.generate(TOTP_CODE_VALIDITY_PERIOD, timestamp)
+}
+
+#[tokio::test]
+async fn test_web_enrollment() {
+    let (client, mut client_state) = make_test_client().await;
+    let pool = client_state.pool.clone();
+
+    let mut network = WireguardNetwork::new(
+        "network".into(),
+        "10.1.1.1/24".parse().unwrap(),
+        55555,
+        "192.168.4.14".into(),
+        None,
+        vec![],
+        false,
+        DEFAULT_KEEPALIVE_INTERVAL,
+        DEFAULT_DISCONNECT_THRESHOLD,
+    )
+    .unwrap();
+    network.save(&pool).await.unwrap();
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let new_user = AddUserData {
+        username: "adumbledore".into(),
+        last_name: "Dumbledore".into(),
+        first_name: "Albus".into(),
+        email: "a.dumbledore@hogwart.edu.uk".into(),
+        phone: None,
+        password: None,
+    };
+    let response = client.post("/api/v1/user").json(&new_user).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    #[derive(Deserialize)]
+    struct StartEnrollmentResponse {
+        enrollment_token: String,
+    }
+    let start_enrollment = json!({
+        "email": "a.dumbledore@hogwart.edu.uk",
+        "send_enrollment_notification": true,
+    });
+
+    // web enrollment is disabled by default
+    let response = client
+        .post("/api/v1/user/adumbledore/start_enrollment")
+        .json(&start_enrollment)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let token = response
+        .json::<StartEnrollmentResponse>()
+        .await
+        .enrollment_token;
+    let mail = client_state.mail_rx.try_recv().unwrap();
+    assert!(!mail.content.contains("/enroll?token="));
+    let response = client
+        .post("/api/v1/enrollment/start")
+        .json(&json!({"token": token}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"enrollment_web_fallback_enabled": true}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // enrollment email doesn't link to a page core doesn't serve
+    let response = client
+        .post("/api/v1/user/adumbledore/start_enrollment")
+        .json(&start_enrollment)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let token = response
+        .json::<StartEnrollmentResponse>()
+        .await
+        .enrollment_token;
+    let mail = client_state.mail_rx.try_recv().unwrap();
+    assert!(!mail.content.contains("/enroll?token="));
+
+    // web enrollment doesn't require a session
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // steps require a started session
+    let response = client
+        .post("/api/v1/enrollment/activate")
+        .json(&json!({"token": token, "password": "Alohomora!12345"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = client
+        .post("/api/v1/enrollment/start")
+        .json(&json!({"token": token}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let session: Value = response.json().await;
+    assert_eq!(session["user"]["username"], "adumbledore");
+
+    // device can't be added before setting a password
+    let response = client
+        .post("/api/v1/enrollment/device")
+        .json(&json!({"token": token, "name": "laptop"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = client
+        .post("/api/v1/enrollment/activate")
+        .json(&json!({"token": token, "password": "weak"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // concurrent activations using the same token, only one can succeed
+    let activation = json!({"token": token, "password": "Alohomora!12345"});
+    let (first, second) = tokio::join!(
+        client
+            .post("/api/v1/enrollment/activate")
+            .json(&activation)
+            .send(),
+        client
+            .post("/api/v1/enrollment/activate")
+            .json(&activation)
+            .send(),
+    );
+    let mut statuses = [first.status(), second.status()];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::BAD_REQUEST]);
+
+    // token can't be reused
+    let response = client
+        .post("/api/v1/enrollment/activate")
+        .json(&activation)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .post("/api/v1/enrollment/start")
+        .json(&json!({"token": token}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // enroll TOTP
+    let response = client
+        .post("/api/v1/enrollment/totp/init")
+        .json(&json!({"token": token}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth_totp: AuthTotp = response.json().await;
+    let response = client
+        .post("/api/v1/enrollment/totp")
+        .json(&json!({"token": token, "code": totp_code(&auth_totp)}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let user = User::find_by_username(&pool, "adumbledore")
+        .await
+        .unwrap()
+        .unwrap();
+    let user_info = UserInfo::from_user(&pool, &user).await.unwrap();
+    assert!(user_info.totp_enabled);
+    assert_eq!(user_info.mfa_method, MFAMethod::OneTimePassword);
+
+    // download config for the first device
+    let response = client
+        .post("/api/v1/enrollment/device")
+        .json(&json!({"token": token, "name": "laptop"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    assert_eq!(device["device"]["name"], "laptop");
+    assert_eq!(device["device"]["os"], "browser");
+    let configs = device["configs"].as_array().unwrap();
+    assert_eq!(configs.len(), 1);
+    let config = configs[0]["config"].as_str().unwrap();
+    assert!(!config.contains("YOUR_PRIVATE_KEY"));
+    assert!(config.contains("PrivateKey = "));
+
+    // one-time QR code link of the complete config
+    let qr_url = configs[0]["qr_url"].as_str().unwrap();
+    let response = client.get(format!("{qr_url}&format=png")).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "image/png");
+    assert_eq!(response.headers()["cache-control"], "no-store");
+    let image = image::load_from_memory(&response.bytes().await)
+        .unwrap()
+        .to_luma8();
+    let mut image = rqrr::PreparedImage::prepare_from_greyscale(
+        image.width() as usize,
+        image.height() as usize,
+        |x, y| image.get_pixel(x as u32, y as u32)[0],
+    );
+    let grids = image.detect_grids();
+    assert_eq!(grids.len(), 1);
+    let (_, content) = grids[0].decode().unwrap();
+    assert_eq!(content, config);
+    let response = client.get(qr_url).send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // stored configs lack private keys, so they require a session and can't be rendered
+    let device_id = device["device"]["id"].as_i64().unwrap();
+    let network_id = configs[0]["network_id"].as_i64().unwrap();
+    let stored_qr_url = format!("/api/v1/device/{device_id}/config/{network_id}/qr");
+    let response = client.get(&stored_qr_url).send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // only the first device can be added
+    let response = client
+        .post("/api/v1/enrollment/device")
+        .json(&json!({"token": token, "name": "phone"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // user can log in with the new password
+    let auth = Auth::new("adumbledore", "Alohomora!12345");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get(&stored_qr_url).send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get(&stored_qr_url).send().await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn test_enrollment_token_lockout() {
+    let (client, mut client_state) = make_test_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"enrollment_web_fallback_enabled": true}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let new_user = AddUserData {
+        username: "adumbledore".into(),
+        last_name: "Dumbledore".into(),
+        first_name: "Albus".into(),
+        email: "a.dumbledore@hogwart.edu.uk".into(),
+        phone: None,
+        password: None,
+    };
+    let response = client.post("/api/v1/user").json(&new_user).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/user/adumbledore/start_enrollment")
+        .json(&json!({
+            "email": "a.dumbledore@hogwart.edu.uk",
+            "send_enrollment_notification": false,
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let token: Value = response.json().await;
+    let token = token["enrollment_token"].as_str().unwrap().to_string();
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // repeated guesses sharing a prefix with the token, from different addresses
+    let guess = format!("{}{}", &token[..8], "x".repeat(24));
+    for index in 1..=5 {
+        let response = client
+            .post("/api/v1/enrollment/start")
+            .header(X_FORWARDED_FOR, &format!("10.0.1.{index}"))
+            .json(&json!({"token": guess}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    // token got invalidated, user and issuing admin are notified
+    let response = client
+        .post("/api/v1/enrollment/start")
+        .json(&json!({"token": token}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let mail = client_state.mail_rx.try_recv().unwrap();
+    assert_eq!(mail.to, "a.dumbledore@hogwart.edu.uk");
+    assert!(mail
+        .content
+        .contains("Your enrollment token has been invalidated"));
+    assert!(mail.content.contains("10.0.1.5"));
+    let mail = client_state.mail_rx.try_recv().unwrap();
+    assert_eq!(mail.to, "admin@defguard");
+    assert!(mail
+        .content
+        .contains("token you issued for user adumbledore"));
+    assert!(client_state.mail_rx.try_recv().is_err());
+
+    // too many invalid attempts from one address
+    for index in 0..10 {
+        let response = client
+            .post("/api/v1/enrollment/start")
+            .header(X_FORWARDED_FOR, "10.0.2.1")
+            .json(&json!({"token": format!("{index:0>8}guess")}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+    let response = client
+        .post("/api/v1/enrollment/start")
+        .header(X_FORWARDED_FOR, "10.0.2.1")
+        .json(&json!({"token": "guess"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // other addresses are not affected
+    let response = client
+        .post("/api/v1/enrollment/start")
+        .header(X_FORWARDED_FOR, "10.0.2.2")
+        .json(&json!({"token": "guess"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,161 @@
+mod common;
+
+use defguard::{
+    db::{models::external_identity::ExternalIdentity, Device, GatewayEvent, Group, User},
+    handlers::Auth,
+};
+use matches::assert_matches;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use self::common::make_test_client;
+
+#[tokio::test]
+async fn test_merge_users() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+    let mut wg_rx = client_state.wireguard_rx;
+
+    let mut group = Group::new("allowed group");
+    group.save(&pool).await.unwrap();
+    let hpotter = User::find_by_username(&pool, "hpotter")
+        .await
+        .unwrap()
+        .unwrap();
+    hpotter.add_to_group(&pool, &group).await.unwrap();
+
+    // duplicate account, created when logging in with another provider
+    let mut duplicate = User::new(
+        "harry",
+        Some("pass123"),
+        "Potter",
+        "Harry",
+        "harry@hogwart.edu.uk",
+        None,
+    );
+    duplicate.save(&pool).await.unwrap();
+    let mut device = Device::new(
+        "laptop".into(),
+        "wYOt6ImBaQ3BEMQ3Xf5P5fTnbqwOvjcqYkkSBt+1xOg=".into(),
+        duplicate.id.unwrap(),
+    );
+    device.save(&pool).await.unwrap();
+    ExternalIdentity::new(
+        "google",
+        "sub1",
+        "harry@hogwart.edu.uk",
+        duplicate.id.unwrap(),
+    )
+    .save(&pool)
+    .await
+    .unwrap();
+    let mut conflicting = Device::new(
+        "laptop".into(),
+        "v2U14sjNN4tOYD3P15z0WkjriKY9Hl85I3vIEPomrYs=".into(),
+        hpotter.id.unwrap(),
+    );
+    conflicting.save(&pool).await.unwrap();
+
+    // only admins can merge accounts
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/user/hpotter/merge")
+        .json(&json!({"username": "harry"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // network allowed only for the surviving account's group
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": "network",
+            "address": "10.1.1.1/24",
+            "port": 55555,
+            "endpoint": "192.168.4.14",
+            "allowed_ips": "10.1.1.0/24",
+            "dns": "1.1.1.1",
+            "allowed_groups": ["allowed group"],
+            "mfa_enabled": false,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    while wg_rx.try_recv().is_ok() {}
+
+    let response = client
+        .post("/api/v1/user/hpotter/merge")
+        .json(&json!({"username": "hpotter"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .post("/api/v1/user/hpotter/merge")
+        .json(&json!({"username": "nobody"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // device names have to be unique for the surviving account
+    let response = client
+        .post("/api/v1/user/hpotter/merge")
+        .json(&json!({"username": "harry"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    conflicting.name = "desktop".into();
+    conflicting.save(&pool).await.unwrap();
+
+    let response = client
+        .post("/api/v1/user/hpotter/merge")
+        .json(&json!({"username": "harry"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let summary: Value = response.json().await;
+    assert_eq!(summary["devices"], 1);
+    assert_eq!(summary["external_identities"], 1);
+
+    // moved device joined the network
+    let event = wg_rx.try_recv().unwrap();
+    assert_matches!(event, GatewayEvent::PeerAdded(peer) if peer.device.id == device.id);
+    assert!(wg_rx.try_recv().is_err());
+
+    let device = Device::find_by_id(&pool, device.id.unwrap())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(device.user_id, hpotter.id.unwrap());
+    let identity = ExternalIdentity::find_by_subject(&pool, "google", "sub1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(identity.user_id, hpotter.id.unwrap());
+
+    // merged account is disabled and hidden
+    let merged = User::find_by_username(&pool, "harry")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!merged.is_active);
+    assert_eq!(merged.merged_into, hpotter.id);
+    let response = client.get("/api/v1/user").send().await;
+    let users: Vec<Value> = response.json().await;
+    assert!(users.iter().all(|user| user["username"] != "harry"));
+
+    // merging twice is rejected
+    let response = client
+        .post("/api/v1/user/hpotter/merge")
+        .json(&json!({"username": "harry"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
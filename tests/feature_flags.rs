@@ -0,0 +1,156 @@
+mod common;
+
+use defguard::{
+    feature_flags::{self, FeatureFlag},
+    handlers::Auth,
+};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use sqlx::query;
+
+use self::common::{client::TestClient, make_test_client};
+
+async fn flag(client: &TestClient, name: &str, network_id: Option<i64>) -> Value {
+    let url = match network_id {
+        Some(network_id) => format!("/api/v1/system/features?network_id={network_id}"),
+        None => "/api/v1/system/features".into(),
+    };
+    let response = client.get(url).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let flags: Vec<Value> = response.json().await;
+    flags.into_iter().find(|flag| flag["name"] == name).unwrap()
+}
+
+#[tokio::test]
+async fn test_feature_flags() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/system/features").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": "network",
+            "address": "10.1.1.1/24",
+            "port": 55555,
+            "endpoint": "192.168.4.14",
+            "allowed_ips": "10.1.1.0/24",
+            "dns": "1.1.1.1",
+            "allowed_groups": [],
+            "mfa_enabled": false,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: Value = response.json().await;
+    let network_id = network["id"].as_i64().unwrap();
+
+    // defaults
+    let diffs = flag(&client, "gateway_peer_diffs", None).await;
+    assert_eq!(diffs["default"], true);
+    assert_eq!(diffs["enabled"], true);
+    assert!(feature_flags::is_enabled(
+        FeatureFlag::GatewayPeerDiffs,
+        Some(network_id)
+    ));
+
+    // instance-wide override is visible right away
+    let response = client
+        .put("/api/v1/system/features")
+        .json(&json!({"name": "gateway_peer_diffs", "enabled": false}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let diffs = flag(&client, "gateway_peer_diffs", None).await;
+    assert_eq!(diffs["enabled"], false);
+    assert_eq!(diffs["instance_override"], false);
+    assert!(!feature_flags::is_enabled(
+        FeatureFlag::GatewayPeerDiffs,
+        Some(network_id)
+    ));
+
+    // location override wins for that location only
+    let response = client
+        .put("/api/v1/system/features")
+        .json(&json!({"name": "gateway_peer_diffs", "network_id": network_id, "enabled": true}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let diffs: Value = response.json().await;
+    assert_eq!(diffs["enabled"], true);
+    assert_eq!(
+        flag(&client, "gateway_peer_diffs", Some(network_id)).await["enabled"],
+        true
+    );
+    assert_eq!(
+        flag(&client, "gateway_peer_diffs", Some(network_id + 1)).await["enabled"],
+        false
+    );
+    assert_eq!(
+        flag(&client, "gateway_lag_resync", Some(network_id)).await["enabled"],
+        true
+    );
+    assert!(feature_flags::is_enabled(
+        FeatureFlag::GatewayPeerDiffs,
+        Some(network_id)
+    ));
+
+    // overrides are stored, so other instances get the same values
+    feature_flags::reload(&pool).await.unwrap();
+    let diffs = flag(&client, "gateway_peer_diffs", None).await;
+    assert_eq!(diffs["enabled"], false);
+    assert_eq!(
+        diffs["locations"],
+        json!([{"network_id": network_id, "enabled": true}])
+    );
+
+    // removing overrides restores the default
+    let response = client
+        .put("/api/v1/system/features")
+        .json(&json!({"name": "gateway_peer_diffs", "enabled": null}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        flag(&client, "gateway_peer_diffs", Some(network_id + 1)).await["enabled"],
+        true
+    );
+
+    // location overrides are removed with the location
+    query("DELETE FROM wireguard_network WHERE id = $1")
+        .bind(network_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    feature_flags::reload(&pool).await.unwrap();
+    assert_eq!(
+        flag(&client, "gateway_peer_diffs", None).await["locations"],
+        json!([])
+    );
+
+    // unknown flags and locations are rejected
+    let response = client
+        .put("/api/v1/system/features")
+        .json(&json!({"name": "unknown", "enabled": true}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let response = client
+        .put("/api/v1/system/features")
+        .json(&json!({"name": "gateway_peer_diffs", "network_id": network_id, "enabled": true}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -1,15 +1,21 @@
 mod common;
 
-use defguard::{db::Wallet, handlers::Auth, SERVER_CONFIG};
-use reqwest::StatusCode;
+use defguard::{db::Wallet, handlers::Auth};
+use reqwest::{StatusCode, Url};
 
-use self::common::{client::TestClient, make_test_client, X_FORWARDED_HOST, X_FORWARDED_URI};
+use self::common::{client::TestClient, TestServerBuilder, X_FORWARDED_HOST, X_FORWARDED_URI};
 
 async fn make_client() -> TestClient {
-    let (client, client_state) = make_test_client().await;
+    let (client, client_state) = TestServerBuilder::new()
+        .with_config(|config| {
+            config.url = Url::parse("https://defguard.example.com").unwrap();
+            config.webauthn_rp_id = Some("defguard.example.com".into());
+        })
+        .build()
+        .await;
 
     let mut wallet = Wallet::new_for_user(
-        client_state.test_user.id.unwrap(),
+        client_state.test_user().id.unwrap(),
         "0x4aF8803CBAD86BA65ED347a3fbB3fb50e96eDD3e",
         "test",
         5,
@@ -35,11 +41,7 @@ async fn test_forward_auth() {
     let headers = response.headers();
     assert_eq!(
         headers.get("location").unwrap().to_str().unwrap(),
-        format!(
-            "{}auth/login?r={}",
-            SERVER_CONFIG.get().unwrap().url,
-            "http://app.example.com/test"
-        )
+        "https://defguard.example.com/auth/login?r=http://app.example.com/test"
     );
 
     // login
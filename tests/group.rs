@@ -1,10 +1,18 @@
 mod common;
 
-use defguard::handlers::{Auth, GroupInfo};
+use chrono::{Duration, Utc};
+use defguard::{
+    db::{Device, GatewayEvent, User, WireguardNetwork},
+    handlers::{Auth, GroupInfo},
+    user_suspension::reactivate_suspended_users,
+};
+use matches::assert_matches;
 use reqwest::StatusCode;
-use serde_json::json;
+use serde_json::{json, Value};
+use sqlx::query;
+use tokio::sync::broadcast;
 
-use self::common::make_test_client;
+use self::common::{fetch_user_details, make_test_client};
 
 #[tokio::test]
 async fn test_create_group() {
@@ -148,3 +156,405 @@ async fn test_modify_group_no_locations_in_request() {
     assert_eq!(group_info.name, "gryffindor");
     assert_eq!(group_info.members, vec!["hpotter"]);
 }
+
+#[tokio::test]
+async fn test_nested_groups() {
+    let (client, _) = make_test_client().await;
+
+    // Authorize as an administrator.
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Create group hierarchy.
+    let response = client
+        .post("/api/v1/group")
+        .json(&json!({"name": "engineering", "members": []}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/group")
+        .json(&json!({"name": "backend", "members": ["hpotter"], "parent": "engineering"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/group")
+        .json(&json!({"name": "platform", "members": [], "parent": "nonexistent"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Parent group lists members of subgroups as effective members.
+    let response = client.get("/api/v1/group/engineering").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let group_info: GroupInfo = response.json().await;
+    assert!(group_info.members.is_empty());
+    assert_eq!(group_info.effective_members, vec!["hpotter"]);
+    let response = client.get("/api/v1/group/backend").send().await;
+    let group_info: GroupInfo = response.json().await;
+    assert_eq!(group_info.parent.as_deref(), Some("engineering"));
+
+    let response = client.get("/api/v1/group-info").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let groups: Vec<GroupInfo> = response.json().await;
+    let engineering = groups
+        .iter()
+        .find(|group| group.name == "engineering")
+        .unwrap();
+    assert_eq!(engineering.effective_members, vec!["hpotter"]);
+
+    // Group can't become its own ancestor.
+    let response = client
+        .put("/api/v1/group/engineering")
+        .json(&json!({"name": "engineering", "members": [], "parent": "backend"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let response = client
+        .put("/api/v1/group/engineering")
+        .json(&json!({"name": "engineering", "members": [], "parent": "engineering"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Members of admin subgroups are administrators.
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/user").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .put("/api/v1/group/engineering")
+        .json(&json!({"name": "engineering", "members": [], "parent": "admin"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/user").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_batch_group_members() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+    let mut wg_rx = client_state.wireguard_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let data = GroupInfo::new("team", Vec::new(), Vec::new());
+    let response = client.post("/api/v1/group").json(&data).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": "network",
+            "address": "10.1.1.1/24",
+            "port": 55555,
+            "endpoint": "192.168.4.14",
+            "allowed_ips": "10.1.1.0/24",
+            "dns": "1.1.1.1",
+            "allowed_groups": ["team"],
+            "mfa_enabled": false,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: WireguardNetwork = response.json().await;
+
+    let mut usernames = Vec::new();
+    for index in 0..10 {
+        let username = format!("student{index}");
+        let email = format!("{username}@hogwart.edu.uk");
+        let mut user = User::new(
+            username.as_str(),
+            Some("pass123"),
+            "Student",
+            "Hogwarts",
+            email.as_str(),
+            None,
+        );
+        user.save(&pool).await.unwrap();
+        let mut device = Device::new(
+            format!("{username} laptop"),
+            WireguardNetwork::genkey().public,
+            user.id.unwrap(),
+        );
+        device.save(&pool).await.unwrap();
+        usernames.push(username);
+    }
+    while wg_rx.try_recv().is_ok() {}
+
+    // unknown users fail the whole batch
+    let mut members = usernames.clone();
+    members.extend(["ghost".to_string(), "dementor".to_string()]);
+    let response = client
+        .put("/api/v1/group/team/members")
+        .json(&json!({ "members": members }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: Value = response.json().await;
+    assert_eq!(body["msg"], "Users not found: dementor, ghost");
+    let response = client.get("/api/v1/group/team").send().await;
+    let group: GroupInfo = response.json().await;
+    assert!(group.members.is_empty());
+    assert!(wg_rx.try_recv().is_err());
+
+    // adding 10 users sends single update to the location
+    let response = client
+        .put("/api/v1/group/team/members")
+        .json(&json!({ "members": usernames }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let delta: Value = response.json().await;
+    assert_eq!(delta["added"].as_array().unwrap().len(), 10);
+    assert_eq!(delta["removed"], json!([]));
+    let event = wg_rx.try_recv().unwrap();
+    assert_matches!(
+        event,
+        GatewayEvent::NetworkModified(network_id, _, peers)
+            if Some(network_id) == network.id && peers.len() == 10
+    );
+    assert!(wg_rx.try_recv().is_err());
+
+    // setting the same members changes nothing
+    let response = client
+        .put("/api/v1/group/team/members")
+        .json(&json!({ "members": usernames }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let delta: Value = response.json().await;
+    assert_eq!(delta, json!({"added": [], "removed": []}));
+    assert!(wg_rx.try_recv().is_err());
+
+    // single change is sent as peer update
+    let response = client
+        .patch("/api/v1/group/team/members")
+        .json(&json!({ "remove": ["student0"] }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let delta: Value = response.json().await;
+    assert_eq!(delta, json!({"added": [], "removed": ["student0"]}));
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerRemoved(_));
+    assert!(wg_rx.try_recv().is_err());
+
+    let response = client
+        .patch("/api/v1/group/team/members")
+        .json(&json!({ "add": ["student0", "hpotter"], "remove": ["student1", "student2"] }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let delta: Value = response.json().await;
+    assert_eq!(
+        delta,
+        json!({"added": ["hpotter", "student0"], "removed": ["student1", "student2"]})
+    );
+    assert_matches!(
+        wg_rx.try_recv().unwrap(),
+        GatewayEvent::NetworkModified(_, _, peers) if peers.len() == 8
+    );
+    assert!(wg_rx.try_recv().is_err());
+
+    let response = client
+        .patch("/api/v1/group/team/members")
+        .json(&json!({ "add": ["student1"], "remove": ["student1"] }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .patch("/api/v1/group/team/members")
+        .json(&json!({ "add": ["ghost"] }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .put("/api/v1/group/nobody/members")
+        .json(&json!({ "members": [] }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = client.get("/api/v1/group/team").send().await;
+    let group: GroupInfo = response.json().await;
+    assert_eq!(group.members.len(), 9);
+    assert!(group.members.contains(&"hpotter".to_string()));
+    assert!(!group.members.contains(&"student1".to_string()));
+}
+
+#[tokio::test]
+async fn test_suspend_group() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+    let mut wg_rx = client_state.wireguard_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for (username, email) in [
+        ("ssnape", "s.snape@hogwart.edu.uk"),
+        ("dobby", "dobby@hogwart.edu.uk"),
+    ] {
+        let mut user = User::new(username, Some("pass123"), "Test", "Test", email, None);
+        user.save(&pool).await.unwrap();
+    }
+    for username in ["hpotter", "ssnape", "dobby"] {
+        let user = User::find_by_username(&pool, username)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut device = Device::new(
+            format!("{username} laptop"),
+            WireguardNetwork::genkey().public,
+            user.id.unwrap(),
+        );
+        device.save(&pool).await.unwrap();
+    }
+    let data = GroupInfo::new(
+        "contractors",
+        vec!["hpotter".into(), "ssnape".into(), "dobby".into()],
+        Vec::new(),
+    );
+    let response = client.post("/api/v1/group").json(&data).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": "network",
+            "address": "10.1.1.1/24",
+            "port": 55555,
+            "endpoint": "192.168.4.14",
+            "allowed_ips": "10.1.1.0/24",
+            "dns": "1.1.1.1",
+            "allowed_groups": ["contractors"],
+            "mfa_enabled": false,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    while wg_rx.try_recv().is_ok() {}
+
+    // reactivation has to be scheduled in the future
+    let response = client
+        .post("/api/v1/group/contractors/suspend")
+        .json(&json!({"reactivate_at": Utc::now().naive_utc() - Duration::hours(1)}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = client
+        .post("/api/v1/group/contractors/suspend")
+        .json(&json!({
+            "reactivate_at": Utc::now().naive_utc() + Duration::days(30),
+            "exempt": ["dobby"]
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let batch: Value = response.json().await;
+    let mut suspended: Vec<String> = serde_json::from_value(batch["suspended"].clone()).unwrap();
+    suspended.sort();
+    assert_eq!(suspended, ["hpotter", "ssnape"]);
+
+    // devices of suspended users are removed from the location
+    assert_matches!(
+        wg_rx.try_recv().unwrap(),
+        GatewayEvent::NetworkModified(_, _, peers) if peers.len() == 1
+    );
+    assert!(wg_rx.try_recv().is_err());
+
+    let details = fetch_user_details(&client, "hpotter").await;
+    assert!(!details.user.is_active);
+    let suspension = details.user.suspension.unwrap();
+    assert_eq!(suspension.group_name, "contractors");
+    assert_eq!(suspension.batch_id.to_string(), batch["batch_id"]);
+    let details = fetch_user_details(&client, "dobby").await;
+    assert!(details.user.is_active);
+    assert!(details.user.suspension.is_none());
+
+    // suspended users can't log in
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // nothing to reactivate yet
+    let (wireguard_tx, mut wireguard_rx) = broadcast::channel(16);
+    assert_eq!(
+        reactivate_suspended_users(&pool, &wireguard_tx)
+            .await
+            .unwrap(),
+        0
+    );
+
+    // enabling by hand lifts the suspension
+    let mut details = fetch_user_details(&client, "ssnape").await;
+    details.user.is_active = true;
+    let response = client
+        .put("/api/v1/user/ssnape")
+        .json(&details.user)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let details = fetch_user_details(&client, "ssnape").await;
+    assert!(details.user.is_active);
+    assert!(details.user.suspension.is_none());
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(_));
+
+    // scheduled reactivation restores devices
+    query!("UPDATE user_suspension SET reactivate_at = now() - interval '1 minute'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        reactivate_suspended_users(&pool, &wireguard_tx)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_matches!(wireguard_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(_));
+    assert!(wireguard_rx.try_recv().is_err());
+    let details = fetch_user_details(&client, "hpotter").await;
+    assert!(details.user.is_active);
+    assert!(details.user.suspension.is_none());
+
+    // users disabled before suspension are never reactivated
+    let mut details = fetch_user_details(&client, "dobby").await;
+    details.user.is_active = false;
+    let response = client
+        .put("/api/v1/user/dobby")
+        .json(&details.user)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/group/contractors/suspend")
+        .json(&json!({}))
+        .send()
+        .await;
+    let batch: Value = response.json().await;
+    let mut suspended: Vec<String> = serde_json::from_value(batch["suspended"].clone()).unwrap();
+    suspended.sort();
+    assert_eq!(suspended, ["hpotter", "ssnape"]);
+}
@@ -0,0 +1,250 @@
+mod common;
+
+use chrono::{Duration, Utc};
+use defguard::{
+    guest_access::{expire_guest_access, notify_sponsors},
+    handlers::{Auth, GroupInfo},
+};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use sqlx::query;
+use tokio::sync::{broadcast, mpsc::unbounded_channel};
+
+use self::common::{fetch_user_details, make_test_client};
+
+fn make_guest(username: &str, network_id: i64, group: Option<&str>) -> Value {
+    json!({
+        "username": username,
+        "first_name": "Guest",
+        "last_name": "User",
+        "email": format!("{username}@example.com"),
+        "network_id": network_id,
+        "group": group,
+        "expires_at": Utc::now().naive_utc() + Duration::days(7),
+    })
+}
+
+#[tokio::test]
+async fn test_guest_access() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let data = GroupInfo::new("staff", Vec::new(), Vec::new());
+    let response = client.post("/api/v1/group").json(&data).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let mut network_ids = Vec::new();
+    for (name, address, allowed_ips, allowed_groups) in [
+        ("office", "10.1.1.1/24", "10.1.1.0/24", vec!["staff"]),
+        ("open", "10.2.2.1/24", "10.2.2.0/24", Vec::new()),
+    ] {
+        let response = client
+            .post("/api/v1/network")
+            .json(&json!({
+                "name": name,
+                "address": address,
+                "port": 55555,
+                "endpoint": "192.168.4.14",
+                "allowed_ips": allowed_ips,
+                "dns": "1.1.1.1",
+                "allowed_groups": allowed_groups,
+                "mfa_enabled": false,
+                "keepalive_interval": 25,
+                "peer_disconnect_threshold": 180
+            }))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let network: Value = response.json().await;
+        network_ids.push(network["id"].as_i64().unwrap());
+    }
+    let (office_id, open_id) = (network_ids[0], network_ids[1]);
+
+    // invalid requests
+    let mut guest = make_guest("visitor", office_id, None);
+    guest["expires_at"] = json!(Utc::now().naive_utc() - Duration::hours(1));
+    let response = client
+        .post("/api/v1/guest_access")
+        .json(&guest)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let guest = make_guest("visitor", open_id, None);
+    let response = client
+        .post("/api/v1/guest_access")
+        .json(&guest)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    for group in ["admin", "other"] {
+        let guest = make_guest("visitor", office_id, Some(group));
+        let response = client
+            .post("/api/v1/guest_access")
+            .json(&guest)
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    let guest = make_guest("hpotter", office_id, None);
+    let response = client
+        .post("/api/v1/guest_access")
+        .json(&guest)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // group is created for the guest and allowed in the location
+    let guest = make_guest("visitor", office_id, None);
+    let response = client
+        .post("/api/v1/guest_access")
+        .json(&guest)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: Value = response.json().await;
+    assert_eq!(created["group_name"], "guest-visitor");
+    assert_eq!(created["group_created"], true);
+    assert!(!created["enrollment_token"].as_str().unwrap().is_empty());
+    let details = fetch_user_details(&client, "visitor").await;
+    assert!(details.user.is_active);
+    assert_eq!(details.user.groups, ["guest-visitor"]);
+    let response = client
+        .get(format!("/api/v1/network/{office_id}"))
+        .send()
+        .await;
+    let network: Value = response.json().await;
+    let mut allowed_groups: Vec<String> =
+        serde_json::from_value(network["allowed_groups"].clone()).unwrap();
+    allowed_groups.sort();
+    assert_eq!(allowed_groups, ["guest-visitor", "staff"]);
+
+    // guest devices are only added to the location of the access, even if others are open
+    let device = json!({
+        "name": "laptop",
+        "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+    });
+    let response = client
+        .post("/api/v1/device/visitor")
+        .json(&device)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let added: Value = response.json().await;
+    let configs = added["configs"].as_array().unwrap();
+    assert_eq!(configs.len(), 1);
+    assert_eq!(configs[0]["network_id"], office_id);
+    let device_id = added["device"]["id"].as_i64().unwrap();
+    let response = client
+        .get(format!(
+            "/api/v1/network/{office_id}/device/{device_id}/config"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .get(format!(
+            "/api/v1/network/{open_id}/device/{device_id}/config"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // existing group
+    let guest = make_guest("auditor", office_id, Some("staff"));
+    let response = client
+        .post("/api/v1/guest_access")
+        .json(&guest)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let auditor: Value = response.json().await;
+    assert_eq!(auditor["group_created"], false);
+
+    // only admins manage guest access
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/guest_access").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.get("/api/v1/guest_access").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let accesses: Vec<Value> = response.json().await;
+    assert_eq!(accesses.len(), 2);
+    assert_eq!(accesses[0]["username"], "visitor");
+    assert_eq!(accesses[0]["network_name"], "office");
+    assert_eq!(accesses[0]["sponsor"], "admin");
+    assert!(accesses[0]["time_remaining"].as_i64().unwrap() > 6 * 24 * 3600);
+
+    // sponsor is reminded once before expiry
+    let (mail_tx, mut mail_rx) = unbounded_channel();
+    assert_eq!(notify_sponsors(&pool, &mail_tx).await.unwrap(), 0);
+    query!(
+        "UPDATE guest_access SET expires_at = now() + interval '1 hour' WHERE id = $1",
+        created["id"].as_i64().unwrap()
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    assert_eq!(notify_sponsors(&pool, &mail_tx).await.unwrap(), 1);
+    let mail = mail_rx.try_recv().unwrap();
+    assert_eq!(mail.to, "admin@defguard");
+    assert_eq!(mail.subject, "Defguard: guest access is about to expire");
+    assert_eq!(notify_sponsors(&pool, &mail_tx).await.unwrap(), 0);
+
+    // expired access is torn down along with the created group
+    let (wireguard_tx, _wireguard_rx) = broadcast::channel(16);
+    assert_eq!(expire_guest_access(&pool, &wireguard_tx).await.unwrap(), 0);
+    query!(
+        "UPDATE guest_access SET expires_at = now() - interval '1 minute' WHERE id = $1",
+        created["id"].as_i64().unwrap()
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    assert_eq!(expire_guest_access(&pool, &wireguard_tx).await.unwrap(), 1);
+    let details = fetch_user_details(&client, "visitor").await;
+    assert!(!details.user.is_active);
+    assert!(details.user.groups.is_empty());
+    let response = client.get("/api/v1/group/guest-visitor").send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = client.get("/api/v1/guest_access").send().await;
+    let accesses: Vec<Value> = response.json().await;
+    assert_eq!(accesses.len(), 1);
+    assert_eq!(accesses[0]["username"], "auditor");
+
+    // early revocation keeps the existing group
+    let auditor_id = auditor["id"].as_i64().unwrap();
+    let response = client
+        .delete(format!("/api/v1/guest_access/{auditor_id}"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let revoked: Value = response.json().await;
+    assert!(!revoked["ended_at"].is_null());
+    let details = fetch_user_details(&client, "auditor").await;
+    assert!(!details.user.is_active);
+    assert!(details.user.groups.is_empty());
+    let response = client.get("/api/v1/group/staff").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .delete(format!("/api/v1/guest_access/{auditor_id}"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client.delete("/api/v1/guest_access/1000").send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = client.get("/api/v1/guest_access").send().await;
+    let accesses: Vec<Value> = response.json().await;
+    assert!(accesses.is_empty());
+}
@@ -0,0 +1,231 @@
+mod common;
+
+use defguard::{db::WireguardNetwork, handlers::Auth, idempotency::IDEMPOTENCY_KEY_HEADER};
+use reqwest::{header::HeaderName, StatusCode};
+use serde_json::{json, Value};
+use sqlx::{query, query_scalar};
+
+use self::common::make_test_client;
+
+fn make_network(name: &str, subnet: u8) -> Value {
+    json!({
+        "name": name,
+        "address": format!("10.1.{subnet}.1/24"),
+        "port": 55555,
+        "endpoint": "192.168.4.14",
+        "allowed_ips": format!("10.1.{subnet}.0/24"),
+        "dns": "1.1.1.1",
+        "allowed_groups": [],
+        "mfa_enabled": false,
+        "keepalive_interval": 25,
+        "peer_disconnect_threshold": 180
+    })
+}
+
+fn idempotency_key() -> HeaderName {
+    HeaderName::from_static(IDEMPOTENCY_KEY_HEADER)
+}
+
+#[tokio::test]
+async fn test_idempotency_key() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // first request is handled, the retry gets the same response
+    let response = client
+        .post("/api/v1/network")
+        .header(idempotency_key(), "create-network-1")
+        .json(&make_network("network", 1))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert!(response.headers().get("idempotent-replayed").is_none());
+    let created: Value = response.json().await;
+    let response = client
+        .post("/api/v1/network")
+        .header(idempotency_key(), "create-network-1")
+        .json(&make_network("network", 1))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()["idempotent-replayed"], "true");
+    let replayed: Value = response.json().await;
+    assert_eq!(replayed, created);
+    let response = client.get("/api/v1/network").send().await;
+    let networks: Vec<WireguardNetwork> = response.json().await;
+    assert_eq!(networks.len(), 1);
+
+    // the key can't be reused for a different request
+    let response = client
+        .post("/api/v1/network")
+        .header(idempotency_key(), "create-network-1")
+        .json(&make_network("other", 2))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // requests without a key, or with another one, are handled as usual
+    for (key, subnet) in [(None, 2), (Some("create-network-2"), 3)] {
+        let mut request = client
+            .post("/api/v1/network")
+            .json(&make_network("network", subnet));
+        if let Some(key) = key {
+            request = request.header(idempotency_key(), key);
+        }
+        let response = request.send().await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+    let response = client.get("/api/v1/network").send().await;
+    let networks: Vec<WireguardNetwork> = response.json().await;
+    assert_eq!(networks.len(), 3);
+
+    // keys are per user
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/network")
+        .header(idempotency_key(), "create-network-1")
+        .json(&make_network("network", 1))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(response.headers().get("idempotent-replayed").is_none());
+
+    // expired key can be used again
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    query!("UPDATE idempotency_record SET expires_at = now() - interval '1 minute'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let response = client
+        .post("/api/v1/network")
+        .header(idempotency_key(), "create-network-1")
+        .json(&make_network("other", 4))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert!(response.headers().get("idempotent-replayed").is_none());
+    let network: WireguardNetwork = response.json().await;
+    assert_eq!(network.name, "other");
+
+    // invalid key
+    let response = client
+        .post("/api/v1/network")
+        .header(idempotency_key(), &"a".repeat(256))
+        .json(&make_network("network", 1))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_idempotency_key_routes() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/network")
+        .json(&make_network("network", 1))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // devices and users can be created with a key
+    let device = json!({
+        "name": "laptop",
+        "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+    });
+    let user = json!({
+        "username": "adumbledore",
+        "last_name": "Dumbledore",
+        "first_name": "Albus",
+        "email": "a.dumbledore@hogwart.edu.uk",
+        "password": "Alohomora!12",
+    });
+    for (url, key, body) in [
+        ("/api/v1/device/hpotter", "create-device", &device),
+        ("/api/v1/user", "create-user", &user),
+    ] {
+        let response = client
+            .post(url)
+            .header(idempotency_key(), key)
+            .json(body)
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let created: Value = response.json().await;
+        let response = client
+            .post(url)
+            .header(idempotency_key(), key)
+            .json(body)
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["idempotent-replayed"], "true");
+        let replayed: Value = response.json().await;
+        assert_eq!(replayed, created);
+    }
+    let response = client.get("/api/v1/device/user/hpotter").send().await;
+    let devices: Vec<Value> = response.json().await;
+    assert_eq!(devices.len(), 1);
+
+    // responses with private keys are never stored
+    let response = client
+        .post("/api/v1/device/hpotter?generate_keys=true")
+        .header(idempotency_key(), "generate")
+        .json(&json!({"name": "phone", "wireguard_pubkey": ""}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // failed requests leave no record and can be retried
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .header(idempotency_key(), "duplicate")
+        .json(&device)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .header(idempotency_key(), "duplicate")
+        .json(&json!({
+            "name": "tablet",
+            "wireguard_pubkey": "v2U14sjNN4tOYD3P15z0WkjriKY9Hl85I3vIEPomrYs=",
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert!(response.headers().get("idempotent-replayed").is_none());
+
+    // other routes ignore the header
+    for _ in 0..2 {
+        let response = client
+            .post("/api/v1/auth")
+            .header(idempotency_key(), "login")
+            .json(&auth)
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("idempotent-replayed").is_none());
+    }
+    let keys: Vec<String> = query_scalar!("SELECT key FROM idempotency_record ORDER BY key")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(keys, ["create-device", "create-user", "duplicate"]);
+}
@@ -0,0 +1,181 @@
+mod common;
+
+use defguard::handlers::Auth;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use sqlx::{query, query_scalar};
+
+use self::common::make_test_client;
+
+#[tokio::test]
+async fn test_impersonation() {
+    let (client, client_state) = make_test_client().await;
+
+    // normal user can't impersonate
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post("/api/v1/user/admin/impersonate").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // admins can't be impersonated
+    let response = client
+        .post("/api/v1/user")
+        .json(&json!({
+            "username": "adumbledore",
+            "last_name": "Dumbledore",
+            "first_name": "Albus",
+            "email": "a.dumbledore@hogwart.edu.uk",
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/group/admin")
+        .json(&json!({"username": "adumbledore"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/user/adumbledore/impersonate")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    // neither can oneself
+    let response = client.post("/api/v1/user/admin/impersonate").send().await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client.post("/api/v1/user/nobody/impersonate").send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = client.post("/api/v1/user/hpotter/impersonate").send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = client.get("/api/v1/me").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let me: Value = response.json().await;
+    assert_eq!(me["username"], "hpotter");
+    assert_eq!(me["impersonator"], "admin");
+
+    // read-only access
+    let response = client.get("/api/v1/user/hpotter").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .put("/api/v1/user/hpotter")
+        .json(&json!({"first_name": "Changed"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // impersonation sessions can't be used to pass MFA
+    let response = client
+        .post("/api/v1/auth/totp/verify")
+        .json(&json!({"code": 0}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = client.get("/api/v1/auth/email").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // end impersonation and return to admin session
+    let response = client.post("/api/v1/auth/impersonate/end").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/me").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let me: Value = response.json().await;
+    assert_eq!(me["username"], "admin");
+    assert_eq!(me["impersonator"], Value::Null);
+
+    // not impersonating anymore
+    let response = client.post("/api/v1/auth/impersonate/end").send().await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // impersonation sessions expire
+    let response = client.post("/api/v1/user/hpotter/impersonate").send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    sqlx::query(
+        "UPDATE session SET expires = now() - interval '1 minute' \
+        WHERE impersonator_id IS NOT NULL",
+    )
+    .execute(&client_state.pool)
+    .await
+    .unwrap();
+    let response = client.get("/api/v1/me").send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_impersonation_ends_with_admin_rights() {
+    let (mut client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let admin_cookie = response
+        .cookies()
+        .find(|cookie| cookie.name() == "defguard_session")
+        .unwrap();
+
+    // impersonator removed from admin group
+    let response = client.post("/api/v1/user/hpotter/impersonate").send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client.get("/api/v1/me").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let admin_group = query!(
+        "DELETE FROM group_user WHERE user_id = (SELECT id FROM \"user\" WHERE username = 'admin') \
+        RETURNING group_id"
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap()
+    .group_id;
+    let response = client.get("/api/v1/me").send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let sessions =
+        query_scalar!("SELECT count(*) \"count!\" FROM session WHERE impersonator_id IS NOT NULL")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(sessions, 0);
+    query!(
+        "INSERT INTO group_user (group_id, user_id) \
+        SELECT $1, id FROM \"user\" WHERE username = 'admin'",
+        admin_group
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    // disabled impersonator
+    client.set_cookie(&admin_cookie);
+    let response = client.post("/api/v1/user/hpotter/impersonate").send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    query!("UPDATE \"user\" SET is_active = false WHERE username = 'admin'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let response = client.get("/api/v1/me").send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // impersonation sessions are removed along with the admin session
+    query!("UPDATE \"user\" SET is_active = true WHERE username = 'admin'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    client.set_cookie(&admin_cookie);
+    let response = client.post("/api/v1/user/hpotter/impersonate").send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    query!("DELETE FROM session WHERE impersonator_id IS NULL")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let sessions = query_scalar!("SELECT count(*) \"count!\" FROM session")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(sessions, 0);
+}
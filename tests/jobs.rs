@@ -0,0 +1,29 @@
+mod common;
+
+use defguard::{handlers::Auth, jobs::JobInfo};
+use reqwest::StatusCode;
+
+use self::common::make_test_client;
+
+#[tokio::test]
+async fn test_background_jobs_api() {
+    let (client, _) = make_test_client().await;
+
+    // normal user can't list jobs
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/system/jobs").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/system/jobs").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let jobs: Vec<JobInfo> = response.json().await;
+    assert!(jobs.is_empty());
+
+    let response = client.post("/api/v1/system/jobs/unknown/run").send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
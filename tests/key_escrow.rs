@@ -0,0 +1,289 @@
+mod common;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use defguard::{
+    db::{Group, User},
+    handlers::Auth,
+};
+use reqwest::{header::CACHE_CONTROL, StatusCode};
+use secrecy::Secret;
+use serde_json::{json, Value};
+use sqlx::{query, query_scalar, PgPool};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use self::common::{client::TestClient, TestServerBuilder};
+
+fn network_data(server_managed_keys: bool) -> Value {
+    json!({
+        "name": "managed",
+        "address": "10.1.1.1/24",
+        "port": 55555,
+        "endpoint": "192.168.4.14",
+        "allowed_ips": "10.1.1.0/24",
+        "dns": "1.1.1.1",
+        "allowed_groups": [],
+        "mfa_enabled": false,
+        "keepalive_interval": 25,
+        "peer_disconnect_threshold": 180,
+        "server_managed_keys": server_managed_keys
+    })
+}
+
+async fn login(client: &TestClient, username: &str) {
+    let auth = Auth::new(username, "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+/// Private key from rendered config, checked against the public key.
+fn private_key_of(config: &str, pubkey: &str) -> String {
+    let private_key = config
+        .lines()
+        .find_map(|line| line.strip_prefix("PrivateKey = "))
+        .unwrap()
+        .to_string();
+    let bytes: [u8; 32] = BASE64_STANDARD
+        .decode(&private_key)
+        .unwrap()
+        .try_into()
+        .unwrap();
+    let public = PublicKey::from(&StaticSecret::from(bytes));
+    assert_eq!(BASE64_STANDARD.encode(public.as_bytes()), pubkey);
+    private_key
+}
+
+async fn accesses(pool: &PgPool, device_id: i64) -> Vec<(Option<i64>, Option<i64>, String)> {
+    query!(
+        "SELECT user_id, network_id, purpose FROM device_key_escrow_access \
+        WHERE device_id = $1 ORDER BY id",
+        device_id
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| (row.user_id, row.network_id, row.purpose))
+    .collect()
+}
+
+#[tokio::test]
+async fn test_server_managed_keys() {
+    let (client, client_state) = TestServerBuilder::new()
+        .with_config(|config| {
+            config.key_encryption_key = Some(Secret::new("k".repeat(32)));
+        })
+        .build()
+        .await;
+    let pool = client_state.pool;
+
+    login(&client, "admin").await;
+    let response = client
+        .post("/api/v1/network")
+        .json(&network_data(false))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: Value = response.json().await;
+    let network_id = network["id"].as_i64().unwrap();
+    assert_eq!(network["server_managed_keys"], false);
+
+    // locations without server managed keys refuse key generation
+    let response = client
+        .post("/api/v1/device/hpotter?generate_keys=true")
+        .json(&json!({"name": "kiosk"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network_data(true))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // keys are generated either by the server or by the device
+    let response = client
+        .post("/api/v1/device/hpotter?generate_keys=true")
+        .json(&json!({
+            "name": "kiosk",
+            "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU="
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // without key escrow permission configs lack the generated key
+    let response = client
+        .post("/api/v1/device/hpotter?generate_keys=true")
+        .json(&json!({"name": "kiosk"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let result: Value = response.json().await;
+    assert_eq!(result["key_escrow"], true);
+    let device_id = result["device"]["id"].as_i64().unwrap();
+    let pubkey = result["device"]["wireguard_pubkey"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let config = result["configs"][0]["config"].as_str().unwrap();
+    assert!(config.contains("YOUR_PRIVATE_KEY"));
+    assert!(accesses(&pool, device_id).await.is_empty());
+
+    let response = client
+        .get(format!("/api/v1/device/{device_id}"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let details: Value = response.json().await;
+    assert_eq!(details["key_escrow"], true);
+
+    let config_url = format!("/api/v1/network/{network_id}/device/{device_id}/config");
+    let response = client.get(&config_url).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.contains("YOUR_PRIVATE_KEY"));
+    let response = client
+        .get(format!("{config_url}?private_key=true"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(accesses(&pool, device_id).await.is_empty());
+
+    // admins in key escrow group get the real key, every access is recorded
+    let mut group = Group::new("keyescrow");
+    group.save(&pool).await.unwrap();
+    let admin = User::find_by_username(&pool, "admin")
+        .await
+        .unwrap()
+        .unwrap();
+    admin.add_to_group(&pool, &group).await.unwrap();
+    let response = client
+        .get(format!("{config_url}?private_key=true"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get(CACHE_CONTROL).unwrap(), "no-store");
+    let private_key = private_key_of(&response.text().await, &pubkey);
+    let response = client
+        .get(format!(
+            "/api/v1/device/{device_id}/config/{network_id}/qr?private_key=true"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        accesses(&pool, device_id).await,
+        [
+            (admin.id, Some(network_id), "config_download".into()),
+            (admin.id, Some(network_id), "config_qr".into()),
+        ]
+    );
+
+    // the key is encrypted at rest
+    let stored: String = query_scalar!(
+        "SELECT encrypted_private_key FROM device_key_escrow WHERE device_id = $1",
+        device_id
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert!(!stored.contains(&private_key));
+
+    // rotation replaces the keypair and escrows the new private key
+    let response = client
+        .post(format!("/api/v1/device/{device_id}/rotate_key"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let device: Value = response.json().await;
+    let rotated_pubkey = device["wireguard_pubkey"].as_str().unwrap().to_string();
+    assert_ne!(rotated_pubkey, pubkey);
+    let response = client
+        .get(format!("{config_url}?private_key=true"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let rotated_key = private_key_of(&response.text().await, &rotated_pubkey);
+    assert_ne!(rotated_key, private_key);
+    assert_eq!(accesses(&pool, device_id).await.len(), 3);
+
+    // new devices of permitted admins come with the key
+    let response = client
+        .post("/api/v1/device/hpotter?generate_keys=true")
+        .json(&json!({"name": "kiosk 2"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let result: Value = response.json().await;
+    private_key_of(
+        result["configs"][0]["config"].as_str().unwrap(),
+        result["device"]["wireguard_pubkey"].as_str().unwrap(),
+    );
+    assert_eq!(
+        accesses(&pool, result["device"]["id"].as_i64().unwrap()).await,
+        [(admin.id, None, "device_created".into())]
+    );
+
+    // device owners can't read escrowed keys nor request generated ones
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    login(&client, "hpotter").await;
+    let response = client
+        .get(format!("{config_url}?private_key=true"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = client
+        .post("/api/v1/device/hpotter?generate_keys=true")
+        .json(&json!({"name": "laptop"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = client
+        .post(format!("/api/v1/device/{device_id}/rotate_key"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // switching to a key of its own removes the escrowed one
+    let response = client
+        .put(format!("/api/v1/device/{device_id}"))
+        .json(&json!({
+            "name": "kiosk",
+            "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU="
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .get(format!("/api/v1/device/{device_id}"))
+        .send()
+        .await;
+    let details: Value = response.json().await;
+    assert_eq!(details["key_escrow"], false);
+
+    // keys aren't handed out once the location stops using server managed keys
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    login(&client, "admin").await;
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network_data(false))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let device_id = result["device"]["id"].as_i64().unwrap();
+    let response = client
+        .get(format!(
+            "/api/v1/network/{network_id}/device/{device_id}/config?private_key=true"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .post(format!("/api/v1/device/{device_id}/rotate_key"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,210 @@
+mod common;
+
+use std::time::Duration;
+
+use defguard::{grpc::GatewayMap, handlers::Auth, mail::Mail};
+use futures_util::{SinkExt, StreamExt};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use sqlx::query;
+use tokio::{net::TcpStream, sync::mpsc::unbounded_channel, time::timeout};
+use tokio_tungstenite::{
+    tungstenite::{protocol::frame::coding::CloseCode, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use self::common::{client::TestClient, make_test_client};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn make_client() -> TestClient {
+    let (client, _) = make_test_client().await;
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    client
+}
+
+/// Next JSON message, skipping heartbeats.
+async fn next_message(socket: &mut Socket) -> Value {
+    loop {
+        let message = timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("Timed out waiting for a message")
+            .unwrap()
+            .unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+async fn subscribe(socket: &mut Socket, events: Value) {
+    socket
+        .send(Message::Text(json!({ "subscribe": events }).to_string()))
+        .await
+        .unwrap();
+    let reply = next_message(socket).await;
+    assert!(reply["subscribed"].is_array(), "unexpected reply {reply}");
+}
+
+#[tokio::test]
+async fn test_live_events_admin_only() {
+    let (client, _) = make_test_client().await;
+
+    let response = client.get("/api/v1/ws/events").send().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/ws/events").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+/// Send a subscription, expecting the server to close the connection instead of replying.
+async fn assert_closed_on_subscribe(socket: &mut Socket) {
+    socket
+        .send(Message::Text(
+            json!({ "subscribe": ["device_added"] }).to_string(),
+        ))
+        .await
+        .unwrap();
+    loop {
+        let message = timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("Timed out waiting for the connection to close")
+            .unwrap()
+            .unwrap();
+        match message {
+            Message::Close(frame) => {
+                assert_eq!(frame.unwrap().code, CloseCode::Policy);
+                return;
+            }
+            Message::Text(text) => panic!("unexpected message {text}"),
+            _ => (),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_live_events_closed_when_unauthorized() {
+    let (client, state) = make_test_client().await;
+    let auth = Auth::new("admin", "pass123");
+
+    // logged out
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut socket = client.websocket("/api/v1/ws/events").await;
+    subscribe(&mut socket, json!(["device_added"])).await;
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_closed_on_subscribe(&mut socket).await;
+
+    // removed from admin group
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut socket = client.websocket("/api/v1/ws/events").await;
+    subscribe(&mut socket, json!(["device_added"])).await;
+    query(
+        "DELETE FROM group_user WHERE user_id = (SELECT id FROM \"user\" WHERE username = 'admin')",
+    )
+    .execute(&state.pool)
+    .await
+    .unwrap();
+    assert_closed_on_subscribe(&mut socket).await;
+}
+
+#[tokio::test]
+async fn test_live_device_events() {
+    let client = make_client().await;
+    let mut socket = client.websocket("/api/v1/ws/events").await;
+
+    // invalid subscriptions are reported
+    socket
+        .send(Message::Text(
+            json!({ "subscribe": ["no_such_event"] }).to_string(),
+        ))
+        .await
+        .unwrap();
+    let reply = next_message(&mut socket).await;
+    assert!(reply["error"].is_string());
+
+    subscribe(&mut socket, json!(["device_added"])).await;
+
+    let network = json!({
+        "name": "network",
+        "address": "10.1.1.1/24",
+        "port": 55555,
+        "endpoint": "192.168.4.14",
+        "allowed_ips": "10.1.1.0/24",
+        "dns": "1.1.1.1",
+        "allowed_groups": [],
+        "mfa_enabled": false,
+        "keepalive_interval": 25,
+        "peer_disconnect_threshold": 180
+    });
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: Value = response.json().await;
+
+    let device = json!({
+        "name": "device",
+        "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+    });
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&device)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    let device = &device["device"];
+
+    let event = next_message(&mut socket).await;
+    assert_eq!(
+        event,
+        json!({
+            "event": "device_added",
+            "device_id": device["id"],
+            "device_name": "device",
+            "user_id": device["user_id"],
+            "network_id": network["id"],
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_live_gateway_events() {
+    let (client, state) = make_test_client().await;
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut socket = client.websocket("/api/v1/ws/events").await;
+    subscribe(
+        &mut socket,
+        json!(["gateway_connected", "gateway_disconnected"]),
+    )
+    .await;
+
+    // gateway map is shared with the gRPC server, which is not running in tests
+    let (mail_tx, _mail_rx) = unbounded_channel::<Mail>();
+    let mut gateways = GatewayMap::new();
+    gateways.add_gateway(1234, "network", "gateway".into(), None, mail_tx);
+    gateways.connect_gateway(1234, "gateway").unwrap();
+
+    let event = next_message(&mut socket).await;
+    assert_eq!(
+        event,
+        json!({"event": "gateway_connected", "network_id": 1234, "hostname": "gateway"})
+    );
+
+    gateways
+        .disconnect_gateway(1234, "gateway".into(), &state.pool)
+        .unwrap();
+    let event = next_message(&mut socket).await;
+    assert_eq!(
+        event,
+        json!({"event": "gateway_disconnected", "network_id": 1234, "hostname": "gateway"})
+    );
+}
@@ -0,0 +1,232 @@
+mod common;
+
+use std::time::SystemTime;
+
+use chrono::{Duration, Utc};
+use claims::assert_err;
+use defguard::{
+    auth::TOTP_CODE_VALIDITY_PERIOD,
+    db::{DbPool, Settings},
+    handlers::{Auth, AuthCode, AuthTotp},
+    mail::Mail,
+    mfa_policy::enforce_mfa_policy,
+};
+use otpauth::TOTP;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::query_as;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use self::common::{client::TestClient, make_test_client, ClientState};
+
+static REMINDER_SUBJECT: &str = "Defguard: multi-factor authentication methods no longer allowed";
+
+#[derive(Deserialize)]
+struct RecoveryCodes {
+    codes: Option<Vec<String>>,
+}
+
+fn totp_code(auth_totp: &AuthTotp) -> AuthCode {
+    let auth = TOTP::from_base32(auth_totp.secret.clone()).unwrap();
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    AuthCode::new(auth.generate(TOTP_CODE_VALIDITY_PERIOD, timestamp))
+}
+
+fn drain(mail_rx: &mut UnboundedReceiver<Mail>) {
+    while mail_rx.try_recv().is_ok() {}
+}
+
+async fn login_admin(client: &TestClient) {
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn patch_settings(client: &TestClient, patch: Value) -> StatusCode {
+    login_admin(client).await;
+    let status = client
+        .patch("/api/v1/settings")
+        .json(&patch)
+        .send()
+        .await
+        .status();
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    status
+}
+
+/// Set up TOTP for hpotter and enable MFA, returning TOTP secret and recovery codes.
+async fn setup_totp(client: &TestClient) -> (AuthTotp, Vec<String>) {
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.post("/api/v1/auth/totp/init").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth_totp: AuthTotp = response.json().await;
+    let response = client
+        .post("/api/v1/auth/totp")
+        .json(&totp_code(&auth_totp))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let recovery_codes: RecoveryCodes = response.json().await;
+
+    // enabling MFA logs out all sessions of the user
+    let response = client.put("/api/v1/auth/mfa").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    (auth_totp, recovery_codes.codes.unwrap())
+}
+
+/// Log in as hpotter, expecting MFA to be required. Returns MFA info.
+async fn login_mfa(client: &TestClient) -> Value {
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    response.json().await
+}
+
+async fn totp_state(pool: &DbPool) -> (bool, bool) {
+    query_as("SELECT totp_enabled, mfa_enabled FROM \"user\" WHERE username = 'hpotter'")
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+async fn end_grace_period(pool: &DbPool) {
+    let mut settings = Settings::get_settings(pool).await.unwrap();
+    settings.mfa_grace_period_end = Some((Utc::now() - Duration::minutes(1)).naive_utc());
+    settings.save(pool).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_disallowed_method_cannot_be_set_up() {
+    let (client, _) = make_test_client().await;
+
+    // at least one method has to stay allowed
+    let status = patch_settings(
+        &client,
+        json!({
+            "mfa_totp_allowed": false,
+            "mfa_email_allowed": false,
+            "mfa_webauthn_allowed": false,
+            "mfa_web3_allowed": false,
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let status = patch_settings(&client, json!({"mfa_totp_allowed": false})).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post("/api/v1/auth/totp/init").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_grace_period() {
+    let (client, state) = make_test_client().await;
+    let ClientState {
+        pool, mut mail_rx, ..
+    } = state;
+    let (auth_totp, recovery_codes) = setup_totp(&client).await;
+    drain(&mut mail_rx);
+
+    let status = patch_settings(&client, json!({"mfa_totp_allowed": false})).await;
+    assert_eq!(status, StatusCode::OK);
+    let settings = Settings::get_settings(&pool).await.unwrap();
+    assert!(settings.mfa_grace_period_active());
+
+    // affected user is reminded
+    let mail = mail_rx.try_recv().unwrap();
+    assert_err!(mail_rx.try_recv());
+    assert_eq!(mail.to, "h.potter@hogwart.edu.uk");
+    assert_eq!(mail.subject, REMINDER_SUBJECT);
+
+    // TOTP still works during grace period
+    let mfa_info = login_mfa(&client).await;
+    assert_eq!(mfa_info["totp_available"], json!(true));
+    let response = client
+        .post("/api/v1/auth/totp/verify")
+        .json(&totp_code(&auth_totp))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // once it's over TOTP can't be used, even before the job removes it
+    end_grace_period(&pool).await;
+    let mfa_info = login_mfa(&client).await;
+    assert_eq!(mfa_info["totp_available"], json!(false));
+    assert_eq!(mfa_info["recovery_codes_available"], json!(true));
+    let response = client
+        .post("/api/v1/auth/totp/verify")
+        .json(&totp_code(&auth_totp))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // job removes the method and notifies the user
+    let (mail_tx, mut job_mail_rx) = unbounded_channel::<Mail>();
+    enforce_mfa_policy(&pool, &mail_tx).await.unwrap();
+    let mail = job_mail_rx.try_recv().unwrap();
+    assert_eq!(mail.subject, REMINDER_SUBJECT);
+    let settings = Settings::get_settings(&pool).await.unwrap();
+    assert!(settings.mfa_grace_period_end.is_none());
+    let (totp_enabled, mfa_enabled) = totp_state(&pool).await;
+    assert!(!totp_enabled);
+    assert!(mfa_enabled);
+
+    // recovery codes are the way back in
+    let response = client
+        .post("/api/v1/auth/recovery")
+        .json(&json!({ "code": recovery_codes[0] }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_invalidate_policy() {
+    let (client, state) = make_test_client().await;
+    let ClientState {
+        pool, mut mail_rx, ..
+    } = state;
+    let (_, recovery_codes) = setup_totp(&client).await;
+    drain(&mut mail_rx);
+
+    let status = patch_settings(
+        &client,
+        json!({"mfa_totp_allowed": false, "mfa_disallowed_policy": "invalidate"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let settings = Settings::get_settings(&pool).await.unwrap();
+    assert!(settings.mfa_grace_period_end.is_none());
+
+    // method is removed right away
+    let mail = mail_rx.try_recv().unwrap();
+    assert_eq!(mail.to, "h.potter@hogwart.edu.uk");
+    assert_eq!(mail.subject, REMINDER_SUBJECT);
+    let (totp_enabled, mfa_enabled) = totp_state(&pool).await;
+    assert!(!totp_enabled);
+    assert!(mfa_enabled);
+
+    let mfa_info = login_mfa(&client).await;
+    assert_eq!(mfa_info["totp_available"], json!(false));
+    let response = client
+        .post("/api/v1/auth/recovery")
+        .json(&json!({ "code": recovery_codes[0] }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
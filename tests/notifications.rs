@@ -0,0 +1,174 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, StatusCode as ServerStatusCode},
+    routing::post,
+    serve, Router,
+};
+use defguard::{
+    handlers::Auth,
+    mail::MailError,
+    notifications::{
+        notify_admins, sign_payload, AdminNotification, NotificationCategory, SIGNATURE_HEADER,
+    },
+};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use tokio::{net::TcpListener, sync::mpsc::unbounded_channel};
+
+use self::common::make_test_client;
+
+type Received = Arc<Mutex<Vec<(Option<String>, Bytes)>>>;
+
+/// Start a webhook endpoint which records signature header and body of each request.
+async fn webhook_server() -> (String, Received) {
+    let received = Received::default();
+    let state = Arc::clone(&received);
+    let app = Router::new().route(
+        "/hook",
+        post(move |headers: HeaderMap, body: Bytes| async move {
+            let signature = headers
+                .get(SIGNATURE_HEADER)
+                .map(|value| value.to_str().unwrap().to_string());
+            state.lock().unwrap().push((signature, body));
+            ServerStatusCode::NO_CONTENT
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { serve(listener, app).await.unwrap() });
+    (format!("http://{addr}/hook"), received)
+}
+
+#[tokio::test]
+async fn test_notification_recipients() {
+    let (client, client_state) = make_test_client().await;
+    let (webhook_url, received) = webhook_server().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // webhook recipient requires URL
+    let response = client
+        .put("/api/v1/settings/notifications")
+        .json(&json!([{
+            "name": "chat",
+            "channel": "webhook",
+            "email": null,
+            "webhook_url": null,
+            "webhook_secret": null,
+            "categories": ["gateway"],
+        }]))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // unknown category
+    let response = client
+        .put("/api/v1/settings/notifications")
+        .json(&json!([{
+            "name": "ops",
+            "channel": "email",
+            "email": "ops@example.com",
+            "webhook_url": null,
+            "webhook_secret": null,
+            "categories": ["billing"],
+        }]))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = client
+        .put("/api/v1/settings/notifications")
+        .json(&json!([
+            {
+                "name": "ops",
+                "channel": "email",
+                "email": "ops@example.com",
+                "webhook_url": null,
+                "webhook_secret": null,
+                "categories": ["gateway"],
+            },
+            {
+                "name": "chat",
+                "channel": "webhook",
+                "email": null,
+                "webhook_url": webhook_url,
+                "webhook_secret": "secret",
+                "categories": ["gateway", "system"],
+            },
+        ]))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.get("/api/v1/settings/notifications").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let recipients: Vec<Value> = response.json().await;
+    assert_eq!(recipients.len(), 2);
+
+    // report SMTP failure for every email
+    let mut mail_rx = client_state.mail_rx;
+    let mail = tokio::spawn(async move {
+        let mail = mail_rx.recv().await.unwrap();
+        if let Some(tx) = &mail.result_tx {
+            tx.send(Err(MailError::SmtpNotConfigured)).unwrap();
+        }
+        mail.to
+    });
+
+    let response = client
+        .post("/api/v1/settings/notifications/test")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(mail.await.unwrap(), "ops@example.com");
+    let results: Value = response.json().await;
+    assert_eq!(
+        results,
+        json!([
+            {
+                "recipient": "ops",
+                "channel": "email",
+                "success": false,
+                "error": "SMTP not configured",
+            },
+            {
+                "recipient": "chat",
+                "channel": "webhook",
+                "success": true,
+                "error": null,
+            },
+        ])
+    );
+
+    {
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (signature, body) = &received[0];
+        assert_eq!(
+            signature.as_deref(),
+            Some(sign_payload("secret", body).as_str())
+        );
+        let payload: Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(payload["category"], "system");
+    }
+
+    // only recipients subscribed to the category are notified
+    let notification = AdminNotification {
+        category: NotificationCategory::Security,
+        subject: "subject".into(),
+        message: "message".into(),
+        html: "message".into(),
+    };
+    let (mail_tx, _mail_rx) = unbounded_channel();
+    let results = notify_admins(&client_state.pool, &mail_tx, &notification)
+        .await
+        .unwrap();
+    assert!(results.is_empty());
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
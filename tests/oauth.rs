@@ -36,6 +36,8 @@ async fn test_authorize() {
         redirect_uri: vec!["http://test.server.tnt:12345/".into()],
         scope: vec!["openid".into()],
         enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: Vec::new(),
     };
     let response = client
         .post("/api/v1/oauth")
@@ -175,6 +177,8 @@ async fn test_openid_app_management_access() {
         redirect_uri: vec!["http://test.server.tnt:12345/".into()],
         scope: vec!["openid".into()],
         enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: Vec::new(),
     };
     let response = client
         .post("/api/v1/oauth")
@@ -206,6 +210,8 @@ async fn test_openid_app_management_access() {
         redirect_uri: vec!["http://test.server.tnt:12345/".into()],
         scope: vec!["openid email".into()],
         enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: Vec::new(),
     };
     let response = client
         .put(format!("/api/v1/oauth/{}", test_app.client_id))
@@ -254,6 +260,8 @@ async fn test_openid_app_management_access() {
         redirect_uri: vec!["http://test.server.tnt:12345/".into()],
         scope: vec!["openid phone".into()],
         enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: Vec::new(),
     };
     let response = client
         .post("/api/v1/oauth")
@@ -289,6 +297,8 @@ async fn test_openid_app_management_access() {
         redirect_uri: vec!["http://test.com/redirect".into()],
         scope: vec!["openid profile".into()],
         enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: Vec::new(),
     };
     let response = client
         .post("/api/v1/oauth")
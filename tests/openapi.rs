@@ -0,0 +1,173 @@
+mod common;
+
+use defguard::handlers::{openapi::openapi, wireguard::WireguardNetworkData, Auth};
+use regex::Regex;
+use reqwest::{header::CONTENT_TYPE, StatusCode};
+use serde_json::{json, Value};
+
+use self::common::{client::TestClient, make_test_client};
+
+async fn make_client() -> TestClient {
+    let (client, _) = make_test_client().await;
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    client
+}
+
+fn spec() -> Value {
+    serde_json::to_value(openapi()).unwrap()
+}
+
+// flattened fields make a schema a composition of its parts
+fn has_properties(schema: &Value) -> bool {
+    schema["properties"]
+        .as_object()
+        .is_some_and(|properties| !properties.is_empty())
+        || schema["allOf"]
+            .as_array()
+            .is_some_and(|parts| parts.iter().any(has_properties))
+}
+
+#[tokio::test]
+async fn test_openapi_spec() {
+    let (client, _) = make_test_client().await;
+
+    // spec is public
+    let response = client.get("/api/v1/openapi.json").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let served: Value = response.json().await;
+    assert_eq!(served, spec());
+    assert!(served["openapi"].as_str().unwrap().starts_with("3."));
+    assert!(!served["paths"].as_object().unwrap().is_empty());
+    assert!(served["components"]["securitySchemes"]["session"].is_object());
+}
+
+#[test]
+fn test_openapi_schemas_are_typed() {
+    let spec = spec();
+    let schemas = &spec["components"]["schemas"];
+    for name in [
+        "UserInfo",
+        "UserDetails",
+        "Device",
+        "WireguardNetwork",
+        "WireguardNetworkInfo",
+        "WireguardNetworkData",
+        "Settings",
+    ] {
+        assert!(
+            has_properties(&schemas[name]),
+            "schema {name} has no properties"
+        );
+    }
+
+    // JSON responses of core resources point to component schemas
+    let paths = spec["paths"].as_object().unwrap();
+    for (path, operations) in paths {
+        if !["/api/v1/user", "/api/v1/device", "/api/v1/network"]
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+        {
+            continue;
+        }
+        for (method, operation) in operations.as_object().unwrap() {
+            let Some(schema) =
+                operation["responses"]["200"]["content"]["application/json"].get("schema")
+            else {
+                continue;
+            };
+            let schema = schema.get("items").unwrap_or(schema);
+            assert!(
+                schema.get("$ref").is_some() || schema.get("type").is_some(),
+                "{method} {path} returns untyped body"
+            );
+            assert_ne!(
+                schema.get("type"),
+                Some(&json!("object")),
+                "{method} {path} returns untyped object"
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_openapi_example_is_valid() {
+    let spec = spec();
+    let example = spec["components"]["schemas"]["WireguardNetworkData"]["example"].clone();
+    assert!(serde_json::from_value::<WireguardNetworkData>(example.clone()).is_ok());
+
+    let client = make_client().await;
+    let response = client.post("/api/v1/network").json(&example).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+#[test]
+fn test_openapi_covers_routes() {
+    let spec = spec();
+    let paths = spec["paths"].as_object().unwrap();
+    let route = Regex::new(r#"\.route\(\s*"([^"]+)""#).unwrap();
+    let param = Regex::new(r":(\w+)").unwrap();
+    let documented = [
+        "/user",
+        "/me",
+        "/auth",
+        "/device",
+        "/network",
+        "/settings",
+        "/system/stats_ingestion",
+    ];
+    for captures in route.captures_iter(include_str!("../src/lib.rs")) {
+        let path = &captures[1];
+        // routes nested under other prefixes, like /oauth/authorize, don't match
+        if !documented
+            .iter()
+            .any(|prefix| path == *prefix || path.starts_with(&format!("{prefix}/")))
+        {
+            continue;
+        }
+        let path = format!("/api/v1{}", param.replace_all(path, "{$1}"));
+        assert!(
+            paths.contains_key(&path),
+            "{path} missing from OpenAPI spec"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_swagger_ui() {
+    let client = make_client().await;
+
+    // disabled by default
+    let response = client.get("/api/v1/swagger-ui/").send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"openapi_ui_enabled": true}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client.get("/api/v1/swagger-ui/").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()[CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let response = client
+        .get("/api/v1/swagger-ui/swagger-initializer.js")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.contains("/api/v1/openapi.json"));
+    let response = client.get("/api/v1/swagger-ui/no-such-file").send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // admins only
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/swagger-ui/").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
@@ -1,15 +1,29 @@
-use std::str::FromStr;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
-use axum::http::header::ToStrError;
+use axum::{
+    extract::Path,
+    http::{header::ToStrError, StatusCode as ServerStatusCode},
+    routing::post,
+    serve, Form, Router,
+};
 use claims::assert_err;
 use defguard::{
     config::DefGuardConfig,
     db::{
-        models::{oauth2client::OAuth2Client, NewOpenIDClient},
+        models::{
+            oauth2client::OAuth2Client, oauth2session::LogoutDeliveryStatus, NewOpenIDClient,
+        },
         DbPool,
     },
     handlers::Auth,
+    openid_backchannel_logout::{send_backchannel_logouts, BACKCHANNEL_LOGOUT_EVENT},
 };
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
 use openidconnect::{
     core::{
         CoreClient, CoreGenderClaim, CoreProviderMetadata, CoreResponseType, CoreTokenResponse,
@@ -25,6 +39,8 @@ use reqwest::{
 };
 use rsa::RsaPrivateKey;
 use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
 
 mod common;
 use self::common::{client::TestClient, init_test_db, make_base_client, make_test_client};
@@ -58,6 +74,8 @@ async fn test_openid_client() {
         redirect_uri: vec!["http://localhost:3000/".into()],
         scope: vec!["openid".into()],
         enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: Vec::new(),
     };
 
     let response = client
@@ -115,6 +133,8 @@ async fn test_openid_flow() {
         redirect_uri: vec!["http://localhost:3000/".into()],
         scope: vec!["openid".into()],
         enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: Vec::new(),
     };
 
     let response = client
@@ -422,6 +442,8 @@ async fn test_openid_authorization_code() {
         redirect_uri: vec!["http://test.server.tnt:12345/".into()],
         scope: vec!["openid".into()],
         enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: Vec::new(),
     };
     let response = client
         .post("/api/v1/oauth")
@@ -527,6 +549,8 @@ async fn test_openid_authorization_code_with_pkce() {
         redirect_uri: vec!["http://test.server.tnt:12345/".into()],
         scope: vec!["openid".into()],
         enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: Vec::new(),
     };
     let response = client
         .post("/api/v1/oauth")
@@ -635,6 +659,8 @@ async fn test_openid_flow_new_login_mail() {
         redirect_uri: vec!["http://localhost:3000/".into()],
         scope: vec!["openid".into()],
         enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: Vec::new(),
     };
 
     let response = client
@@ -705,3 +731,362 @@ async fn test_openid_flow_new_login_mail() {
     // No new mail recevied
     assert_err!(mail_rx.try_recv());
 }
+
+type LogoutRequests = Arc<Mutex<Vec<(String, HashMap<String, String>)>>>;
+
+/// Start back-channel logout endpoint of a client, recording each request.
+/// First request fails with internal server error.
+async fn logout_server() -> (SocketAddr, LogoutRequests) {
+    let received = LogoutRequests::default();
+    let state = Arc::clone(&received);
+    let app = Router::new().route(
+        "/:app/logout",
+        post(
+            move |Path(app): Path<String>, Form(form): Form<HashMap<String, String>>| async move {
+                let mut received = state.lock().unwrap();
+                received.push((app, form));
+                if received.len() == 1 {
+                    ServerStatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    ServerStatusCode::OK
+                }
+            },
+        ),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { serve(listener, app).await.unwrap() });
+    (addr, received)
+}
+
+#[tokio::test]
+async fn test_openid_backchannel_logout() {
+    let (client, client_state) = make_test_client().await;
+    let (addr, received) = logout_server().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // invalid logout URI
+    let response = client
+        .post("/api/v1/oauth")
+        .json(&NewOpenIDClient {
+            name: "Invalid".into(),
+            redirect_uri: vec!["http://localhost:3000/".into()],
+            scope: vec!["openid".into()],
+            enabled: true,
+            backchannel_logout_uri: Some("not a URI".into()),
+            allowed_groups: Vec::new(),
+        })
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let mut clients = Vec::new();
+    for app in ["used", "unused"] {
+        let response = client
+            .post("/api/v1/oauth")
+            .json(&NewOpenIDClient {
+                name: app.into(),
+                redirect_uri: vec!["http://localhost:3000/".into()],
+                scope: vec!["openid".into()],
+                enabled: true,
+                backchannel_logout_uri: Some(format!("http://{addr}/{app}/logout")),
+                allowed_groups: Vec::new(),
+            })
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let openid_client: OAuth2Client = response.json().await;
+        clients.push(openid_client);
+    }
+    let openid_client = &clients[0];
+
+    // authenticate to the first client only
+    let response = client
+        .post(format!(
+            "/api/v1/oauth/authorize?\
+            response_type=code&\
+            client_id={}&\
+            redirect_uri=http%3A%2F%2Flocalhost%3A3000&\
+            scope=openid&\
+            state=ABCDEF&\
+            allow=true&\
+            nonce=blabla",
+            openid_client.client_id
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    let location = response
+        .headers()
+        .get("Location")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let (_, query) = location.split_once('?').unwrap();
+    let auth_response: AuthenticationResponse = serde_qs::from_str(query).unwrap();
+
+    let response = client
+        .post("/api/v1/oauth/token")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", auth_response.code),
+            ("redirect_uri", "http://localhost:3000"),
+            ("client_id", &openid_client.client_id),
+            ("client_secret", &openid_client.client_secret),
+        ])
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let token_response: Value = response.json().await;
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_audience(&[&openid_client.client_id]);
+    let key = DecodingKey::from_secret(openid_client.client_secret.as_bytes());
+    let id_token = decode::<Value>(
+        token_response["id_token"].as_str().unwrap(),
+        &key,
+        &validation,
+    )
+    .unwrap();
+    let sid = id_token.claims["sid"].as_str().unwrap().to_string();
+
+    // nothing is sent while the session is active
+    send_backchannel_logouts(&client_state.pool).await.unwrap();
+    assert!(received.lock().unwrap().is_empty());
+
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // first request fails and is retried
+    send_backchannel_logouts(&client_state.pool).await.unwrap();
+    {
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        for (app, form) in received.iter() {
+            assert_eq!(app, "used");
+            let mut validation = Validation::new(Algorithm::HS256);
+            validation.set_audience(&[&openid_client.client_id]);
+            validation.set_required_spec_claims(&["exp", "aud", "iss", "sub"]);
+            let logout_token = decode::<Value>(&form["logout_token"], &key, &validation).unwrap();
+            assert_eq!(logout_token.header.typ.as_deref(), Some("logout+jwt"));
+            let claims = logout_token.claims;
+            assert_eq!(claims["sid"], sid);
+            assert_eq!(claims["sub"], "admin");
+            assert_eq!(claims["events"], json!({ BACKCHANNEL_LOGOUT_EVENT: {} }));
+            assert!(claims.get("nonce").is_none());
+        }
+    }
+
+    // delivered logouts are not sent again
+    send_backchannel_logouts(&client_state.pool).await.unwrap();
+    assert_eq!(received.lock().unwrap().len(), 2);
+
+    // delivery status
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .get(format!(
+            "/api/v1/oauth/{}/logout_status",
+            openid_client.client_id
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let status: Vec<LogoutDeliveryStatus> = response.json().await;
+    assert_eq!(status.len(), 1);
+    assert_eq!(status[0].username, "admin");
+    assert_eq!(status[0].attempts, 2);
+    assert!(status[0].delivered.is_some());
+    assert!(status[0].error.is_none());
+
+    let response = client
+        .get(format!(
+            "/api/v1/oauth/{}/logout_status",
+            clients[1].client_id
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let status: Vec<LogoutDeliveryStatus> = response.json().await;
+    assert!(status.is_empty());
+}
+
+#[tokio::test]
+async fn test_openid_allowed_groups() {
+    let client = make_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // hpotter is an effective member of "hogwarts" through a subgroup
+    let response = client
+        .post("/api/v1/group")
+        .json(&json!({"name": "hogwarts", "members": []}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/group")
+        .json(&json!({"name": "gryffindor", "members": ["hpotter"], "parent": "hogwarts"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let mut new_client = NewOpenIDClient {
+        name: "Restricted".into(),
+        redirect_uri: vec!["http://localhost:3000/".into()],
+        scope: vec!["openid".into()],
+        enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: vec!["hogwarts".into(), "nonexistent".into()],
+    };
+    let response = client.post("/api/v1/oauth").json(&new_client).send().await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client.get("/api/v1/oauth").send().await;
+    let openid_clients: Vec<OAuth2Client> = response.json().await;
+    assert!(openid_clients.is_empty());
+
+    new_client.allowed_groups = vec!["hogwarts".into()];
+    let response = client.post("/api/v1/oauth").json(&new_client).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let openid_client: Value = response.json().await;
+    assert_eq!(openid_client["allowed_groups"], json!(["hogwarts"]));
+    let client_id = openid_client["client_id"].as_str().unwrap().to_string();
+    let client_secret = openid_client["client_secret"].as_str().unwrap().to_string();
+    let response = client
+        .get(format!("/api/v1/oauth/{client_id}"))
+        .send()
+        .await;
+    let fetched_client: Value = response.json().await;
+    assert_eq!(fetched_client["allowed_groups"], json!(["hogwarts"]));
+
+    let authorize = format!(
+        "/api/v1/oauth/authorize?\
+        response_type=code&\
+        client_id={client_id}&\
+        redirect_uri=http%3A%2F%2Flocalhost%3A3000&\
+        scope=openid&\
+        state=ABCDEF&\
+        allow=true&\
+        nonce=blabla"
+    );
+
+    // admin isn't a member of allowed groups
+    let response = client.post(&authorize).send().await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    let location = response
+        .headers()
+        .get("Location")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(location.contains("error=access_denied"));
+    assert!(location.contains("error_description="));
+    assert!(location.contains("state=ABCDEF"));
+
+    // member completes the flow
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post(&authorize).send().await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    let location = response
+        .headers()
+        .get("Location")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let (_, query) = location.split_once('?').unwrap();
+    let auth_response: AuthenticationResponse = serde_qs::from_str(query).unwrap();
+    let response = client
+        .post("/api/v1/oauth/token")
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(format!(
+            "grant_type=authorization_code&\
+            code={}&\
+            redirect_uri=http%3A%2F%2Flocalhost%3A3000%2F&\
+            client_id={client_id}&\
+            client_secret={client_secret}",
+            auth_response.code
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let token_response: Value = response.json().await;
+
+    let refresh = |refresh_token: &str| {
+        client
+            .post("/api/v1/oauth/token")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(format!(
+                "grant_type=refresh_token&\
+                refresh_token={refresh_token}&\
+                client_id={client_id}&\
+                client_secret={client_secret}"
+            ))
+            .send()
+    };
+    let response = refresh(token_response["refresh_token"].as_str().unwrap()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let token_response: Value = response.json().await;
+    let refresh_token = token_response["refresh_token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // already authorized app is denied after removing user from the group
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .delete("/api/v1/group/gryffindor/user/hpotter")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = refresh(&refresh_token).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: Value = response.json().await;
+    assert_eq!(error["error"], "invalid_grant");
+    // token is revoked
+    let response = refresh(&refresh_token).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get(&authorize).send().await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    let location = response
+        .headers()
+        .get("Location")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(location.starts_with("http://localhost:3000/"));
+    assert!(location.contains("error=access_denied"));
+
+    // clearing allowed groups opens the app to everyone
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    new_client.allowed_groups.clear();
+    let response = client
+        .put(format!("/api/v1/oauth/{client_id}"))
+        .json(&new_client)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post(&authorize).send().await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    let location = response
+        .headers()
+        .get("Location")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(location.contains("code="));
+}
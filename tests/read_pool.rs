@@ -0,0 +1,109 @@
+mod common;
+
+use defguard::{db::read_pool::REPLICA_LAG_HEADER, handlers::Auth};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use sqlx::query;
+
+use self::common::{client::TestClient, fetch_user_details, ClientState, TestServerBuilder};
+
+async fn make_client() -> (TestClient, ClientState) {
+    TestServerBuilder::new().with_read_replica().build().await
+}
+
+#[tokio::test]
+async fn test_read_pool_endpoints() {
+    let (client, client_state) = make_client().await;
+    let read_pool = client_state.read_pool;
+    assert!(read_pool.is_replica());
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // writes always go to the primary
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": "network",
+            "address": "10.1.1.1/24",
+            "port": 55555,
+            "endpoint": "192.168.4.14",
+            "allowed_ips": "10.1.1.0/24",
+            "dns": "1.1.1.1",
+            "allowed_groups": [],
+            "mfa_enabled": false,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: Value = response.json().await;
+    let network_id = network["id"].as_i64().unwrap();
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&json!({"name": "laptop", "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU="}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/user/hpotter/disconnect")
+        .json(&json!({}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(read_pool.acquisitions(), 0);
+
+    // listings and stats are served by the replica
+    for path in [
+        "/api/v1/user".to_string(),
+        "/api/v1/device".to_string(),
+        "/api/v1/device/user/hpotter".to_string(),
+        format!("/api/v1/network/{network_id}/stats"),
+        format!("/api/v1/network/{network_id}/stats/users"),
+    ] {
+        let acquisitions = read_pool.acquisitions();
+        let response = client.get(&path).send().await;
+        assert_eq!(response.status(), StatusCode::OK, "{path}");
+        // replica without lag doesn't mark responses
+        assert!(response.headers().get(REPLICA_LAG_HEADER).is_none());
+        assert!(read_pool.acquisitions() > acquisitions, "{path}");
+    }
+    let response = client.get("/api/v1/device").send().await;
+    let devices: Vec<Value> = response.json().await;
+    assert_eq!(devices.len(), 1);
+
+    // other endpoints don't touch it
+    let acquisitions = read_pool.acquisitions();
+    let response = client
+        .get(format!("/api/v1/network/{network_id}"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut user_details = fetch_user_details(&client, "hpotter").await;
+    user_details.user.first_name = "Harold".into();
+    let response = client
+        .put("/api/v1/user/hpotter")
+        .json(&user_details.user)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(read_pool.acquisitions(), acquisitions);
+}
+
+#[tokio::test]
+async fn test_read_pool_rejects_writes() {
+    let (_, client_state) = make_client().await;
+    let read_pool = client_state.read_pool;
+
+    assert!(query("SELECT id FROM \"user\"")
+        .fetch_all(read_pool.pool())
+        .await
+        .is_ok());
+    assert!(query("UPDATE \"user\" SET first_name = 'Harold'")
+        .execute(read_pool.pool())
+        .await
+        .is_err());
+    assert_eq!(read_pool.lag().await, Some(std::time::Duration::ZERO));
+}
@@ -7,11 +7,13 @@ use defguard::{
 };
 use reqwest::StatusCode;
 
-use self::common::{client::TestClient, make_test_client};
+use self::common::{client::TestClient, TestServerBuilder};
 
 async fn make_client() -> (TestClient, ClientState) {
-    let (client, state) = make_test_client().await;
-    (client, state)
+    TestServerBuilder::new()
+        .with_settings(|settings| settings.instance_name = "Hogwarts".into())
+        .build()
+        .await
 }
 
 #[tokio::test]
@@ -24,6 +26,7 @@ async fn test_settings() {
     let response = client.get("/api/v1/settings").send().await;
     assert_eq!(response.status(), StatusCode::OK);
     let mut settings: Settings = response.json().await;
+    assert_eq!(settings.instance_name, "Hogwarts");
     // modify settings
     settings.wireguard_enabled = false;
     settings.challenge_template = "Modified".to_string();
@@ -0,0 +1,207 @@
+mod common;
+
+use defguard::handlers::Auth;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use sqlx::query;
+
+use self::common::{client::TestClient, make_test_client};
+
+// path of the download endpoint, taken from the link returned by share endpoint
+async fn share(client: &TestClient, device_id: i64, network_id: i64, body: Value) -> String {
+    let response = client
+        .post(format!(
+            "/api/v1/device/{device_id}/config/{network_id}/share"
+        ))
+        .json(&body)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let link: Value = response.json().await;
+    assert!(link.get("token").is_none());
+    let url = link["url"].as_str().unwrap();
+    let token = url.rsplit('/').next().unwrap();
+    format!("/api/v1/shared_config/{token}")
+}
+
+#[tokio::test]
+async fn test_shared_config() {
+    let (client, client_state) = make_test_client().await;
+    let mut mail_rx = client_state.mail_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": "network",
+            "address": "10.1.1.1/24",
+            "port": 55555,
+            "endpoint": "192.168.4.14",
+            "allowed_ips": "10.1.1.0/24",
+            "dns": "1.1.1.1",
+            "allowed_groups": [],
+            "mfa_enabled": false,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: Value = response.json().await;
+    let network_id = network["id"].as_i64().unwrap();
+
+    // device created by admin on behalf of a user
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&json!({
+            "name": "laptop",
+            "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    let device_id = device["device"]["id"].as_i64().unwrap();
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({
+            "name": "phone",
+            "wireguard_pubkey": "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=",
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    let admin_device_id = device["device"]["id"].as_i64().unwrap();
+    while mail_rx.try_recv().is_ok() {}
+
+    // config is served once, without a session
+    let path = share(&client, device_id, network_id, json!({})).await;
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get(&path).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("cache-control").unwrap(), "no-store");
+    let config = response.text().await;
+    assert!(config.contains("[Interface]"));
+    assert!(config.contains("Endpoint = 192.168.4.14:55555"));
+    let response = client.get(&path).send().await;
+    assert_eq!(response.status(), StatusCode::GONE);
+
+    // owner is notified about the download
+    let mail = mail_rx.try_recv().unwrap();
+    assert_eq!(mail.to, "h.potter@hogwart.edu.uk");
+    assert!(mail.content.contains("downloaded using a one-time link"));
+    assert!(mail.content.contains("Laptop"));
+    assert!(mail.content.contains("127.0.0.1"));
+    assert!(mail_rx.try_recv().is_err());
+
+    let response = client.get("/api/v1/shared_config/unknown").send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // failed downloads don't use links up
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let path = share(&client, device_id, network_id, json!({})).await;
+    for statement in [
+        "CREATE TABLE network_device_backup AS SELECT * FROM wireguard_network_device",
+        "DELETE FROM wireguard_network_device",
+    ] {
+        query(statement).execute(&client_state.pool).await.unwrap();
+    }
+    let response = client.get(&path).send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    for statement in [
+        "INSERT INTO wireguard_network_device SELECT * FROM network_device_backup",
+        "DROP TABLE network_device_backup",
+    ] {
+        query(statement).execute(&client_state.pool).await.unwrap();
+    }
+    let response = client.get(&path).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get(&path).send().await;
+    assert_eq!(response.status(), StatusCode::GONE);
+    assert!(mail_rx.try_recv().is_ok());
+
+    // links expire unused
+    let path = share(&client, device_id, network_id, json!({})).await;
+    query("UPDATE shared_config SET expires_at = now() - interval '1 minute'")
+        .execute(&client_state.pool)
+        .await
+        .unwrap();
+    let response = client.get(&path).send().await;
+    assert_eq!(response.status(), StatusCode::GONE);
+
+    // links can be revoked
+    let path = share(&client, device_id, network_id, json!({})).await;
+    let response = client
+        .get(format!("/api/v1/device/{device_id}/shared_config"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let links: Vec<Value> = response.json().await;
+    assert_eq!(links.len(), 1);
+    let link_id = links[0]["id"].as_i64().unwrap();
+    let response = client
+        .delete(format!(
+            "/api/v1/device/{device_id}/shared_config/{link_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get(&path).send().await;
+    assert_eq!(response.status(), StatusCode::GONE);
+    let response = client
+        .get(format!("/api/v1/device/{device_id}/shared_config"))
+        .send()
+        .await;
+    let links: Vec<Value> = response.json().await;
+    assert!(links.is_empty());
+    assert!(mail_rx.try_recv().is_err());
+
+    // sharing preshared keys held by the server has to be confirmed
+    query("UPDATE wireguard_network_device SET preshared_key = 'psk' WHERE device_id = $1")
+        .bind(device_id)
+        .execute(&client_state.pool)
+        .await
+        .unwrap();
+    let response = client
+        .post(format!(
+            "/api/v1/device/{device_id}/config/{network_id}/share"
+        ))
+        .json(&json!({}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    share(
+        &client,
+        device_id,
+        network_id,
+        json!({"confirm_secrets": true}),
+    )
+    .await;
+
+    // users can share only their own devices
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    share(
+        &client,
+        device_id,
+        network_id,
+        json!({"confirm_secrets": true}),
+    )
+    .await;
+    let response = client
+        .post(format!(
+            "/api/v1/device/{admin_device_id}/config/{network_id}/share"
+        ))
+        .json(&json!({"confirm_secrets": true}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,202 @@
+mod common;
+
+use defguard::{db::User, handlers::Auth};
+use rand_core::OsRng;
+use reqwest::StatusCode;
+use secrecy::Secret;
+use serde_json::{json, Value};
+use sqlx::query_scalar;
+use ssh_key::{Algorithm, Certificate, HashAlg, PrivateKey, PublicKey};
+
+use self::common::{client::TestClient, TestServerBuilder};
+
+fn random_public_key() -> String {
+    PrivateKey::random(&mut OsRng, Algorithm::Ed25519)
+        .unwrap()
+        .public_key()
+        .to_openssh()
+        .unwrap()
+}
+
+async fn add_key(client: &TestClient, username: &str, key: &str) {
+    let response = client
+        .post(format!("/api/v1/user/{username}/auth_key"))
+        .json(&json!({"key": key, "name": "key", "key_type": "ssh"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+async fn sign(client: &TestClient, key: &str, validity_minutes: Option<i32>) -> Value {
+    let response = client
+        .post("/api/v1/me/ssh/sign")
+        .json(&json!({"public_key": key, "validity_minutes": validity_minutes}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    response.json().await
+}
+
+#[tokio::test]
+async fn test_ssh_certificate_signing() {
+    let (client, client_state) = TestServerBuilder::new()
+        .with_config(|config| {
+            config.key_encryption_key = Some(Secret::new("k".repeat(32)));
+        })
+        .build()
+        .await;
+    let pool = client_state.pool;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let admin_key = random_public_key();
+    let foreign_key = random_public_key();
+    add_key(&client, "admin", &admin_key).await;
+    add_key(&client, "hpotter", &foreign_key).await;
+
+    // disabled by default
+    let response = client
+        .post("/api/v1/me/ssh/sign")
+        .json(&json!({"public_key": admin_key}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client.get("/api/v1/ssh/ca").send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"ssh_ca_principal_mapping": "admin root"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({
+            "ssh_ca_enabled": true,
+            "ssh_ca_max_validity_minutes": 30,
+            "ssh_ca_principal_mapping": "admin: root, ops\nother: nobody"
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // certificate is signed by the CA and has mapped principals
+    let signed = sign(&client, &admin_key, Some(10)).await;
+    let response = client.get("/api/v1/ssh/ca").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let ca_key: PublicKey = response.text().await.trim().parse().unwrap();
+    // generated CA key is stored encrypted
+    let stored: String = query_scalar("SELECT encrypted_private_key FROM ssh_ca_key")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(!stored.contains("PRIVATE KEY"));
+    let certificate = Certificate::from_openssh(signed["certificate"].as_str().unwrap()).unwrap();
+    certificate
+        .validate_at(
+            certificate.valid_after() + 1,
+            &[ca_key.fingerprint(HashAlg::Sha256)],
+        )
+        .unwrap();
+    assert!(certificate
+        .validate_at(
+            certificate.valid_before() + 1,
+            &[ca_key.fingerprint(HashAlg::Sha256)],
+        )
+        .is_err());
+    assert_eq!(certificate.valid_principals(), ["admin", "root", "ops"]);
+    assert_eq!(signed["principals"], json!(["admin", "root", "ops"]));
+    assert_eq!(certificate.serial(), signed["serial"].as_u64().unwrap());
+    assert_eq!(certificate.key_id(), signed["key_id"].as_str().unwrap());
+    assert_eq!(
+        certificate.public_key(),
+        admin_key.parse::<PublicKey>().unwrap().key_data()
+    );
+
+    // validity is limited by settings
+    assert_eq!(certificate.valid_before() - certificate.valid_after(), 600);
+    for validity in [Some(1000), None] {
+        let signed = sign(&client, &admin_key, validity).await;
+        let certificate =
+            Certificate::from_openssh(signed["certificate"].as_str().unwrap()).unwrap();
+        assert_eq!(certificate.valid_before() - certificate.valid_after(), 1800);
+    }
+    let response = client
+        .post("/api/v1/me/ssh/sign")
+        .json(&json!({"public_key": admin_key, "validity_minutes": 0}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // keys of other users, or not registered at all, aren't signed
+    for key in [foreign_key.clone(), random_public_key()] {
+        let response = client
+            .post("/api/v1/me/ssh/sign")
+            .json(&json!({"public_key": key}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    // revoked certificates are published in the KRL
+    let serial = signed["serial"].as_i64().unwrap();
+    let response = client.get("/api/v1/ssh/krl").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let empty_krl = response.bytes().await;
+    assert!(empty_krl.starts_with(b"SSHKRL\n\0"));
+    let response = client
+        .post(format!("/api/v1/ssh/certificate/{serial}/revoke"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/ssh/certificate/12345/revoke")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = client.get("/api/v1/ssh/krl").send().await;
+    let krl = response.bytes().await;
+    assert!(krl.starts_with(b"SSHKRL\n\0"));
+    assert!(krl.len() > empty_krl.len());
+    assert!(krl.ends_with(&(serial as u64).to_be_bytes()));
+    let ca_blob = ca_key.to_bytes().unwrap();
+    assert!(krl.windows(ca_blob.len()).any(|window| window == ca_blob));
+
+    let response = client.get("/api/v1/ssh/certificate").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let certificates: Vec<Value> = response.json().await;
+    assert_eq!(certificates.len(), 3);
+    let revoked: Vec<_> = certificates
+        .iter()
+        .filter(|certificate| !certificate["revoked_at"].is_null())
+        .collect();
+    assert_eq!(revoked.len(), 1);
+    assert_eq!(revoked[0]["id"], serial);
+
+    // only admins manage certificates
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/ssh/certificate").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let signed = sign(&client, &foreign_key, None).await;
+    assert_eq!(signed["principals"], json!(["hpotter"]));
+
+    // disabled users can't get certificates
+    let mut user = User::find_by_username(&pool, "hpotter")
+        .await
+        .unwrap()
+        .unwrap();
+    user.is_active = false;
+    user.save(&pool).await.unwrap();
+    let response = client
+        .post("/api/v1/me/ssh/sign")
+        .json(&json!({"public_key": foreign_key}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use defguard::handlers::{AddUserData, Auth};
+use reqwest::{header::USER_AGENT, StatusCode};
+use serde_json::json;
+
+use self::common::{TestServerBuilder, X_FORWARDED_FOR};
+
+// Requests from addresses which aren't trusted proxies can't set their client address.
+#[tokio::test]
+async fn test_forwarded_for_ignored_from_untrusted_peer() {
+    let (client, mut client_state) = TestServerBuilder::new()
+        .with_config(|config| config.trusted_proxies = Vec::new())
+        .build()
+        .await;
+
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client
+        .post("/api/v1/auth")
+        .header(USER_AGENT, "Mozilla/5.0 (iPhone; CPU iPhone OS 17_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Mobile/15E148 Safari/604.1")
+        .header(X_FORWARDED_FOR, "10.0.0.20, 10.1.1.10")
+        .json(&auth)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mail = client_state.mail_rx.try_recv().unwrap();
+    assert_eq!(mail.to, "h.potter@hogwart.edu.uk");
+    assert!(mail.content.contains("IP Address:</span> 127.0.0.1"));
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"enrollment_web_fallback_enabled": true}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let new_user = AddUserData {
+        username: "adumbledore".into(),
+        last_name: "Dumbledore".into(),
+        first_name: "Albus".into(),
+        email: "a.dumbledore@hogwart.edu.uk".into(),
+        phone: None,
+        password: None,
+    };
+    let response = client.post("/api/v1/user").json(&new_user).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/user/adumbledore/start_enrollment")
+        .json(&json!({
+            "email": "a.dumbledore@hogwart.edu.uk",
+            "send_enrollment_notification": false,
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // invalid token attempts are counted for the peer, whatever the header says
+    for index in 0..10 {
+        let response = client
+            .post("/api/v1/enrollment/start")
+            .header(X_FORWARDED_FOR, &format!("10.0.3.{index}"))
+            .json(&json!({"token": format!("{index:0>8}guess")}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+    let response = client
+        .post("/api/v1/enrollment/start")
+        .header(X_FORWARDED_FOR, "10.0.3.100")
+        .json(&json!({"token": "guess"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
@@ -93,7 +93,7 @@ async fn test_change_self_password() {
         .json(&bad_new_request)
         .send()
         .await;
-    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
 
     let response = client
         .put("/api/v1/user/change_password")
@@ -492,7 +492,7 @@ async fn test_check_password_strength() {
             .json(&weak_password_user)
             .send()
             .await;
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
     }
     let strong_password_user = AddUserData {
         username: "strongpass".into(),
@@ -510,6 +510,97 @@ async fn test_check_password_strength() {
     assert_eq!(response.status(), StatusCode::CREATED);
 }
 
+#[tokio::test]
+async fn test_password_policy() {
+    let client = make_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // invalid policy is rejected
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"password_min_length": 0}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({
+            "password_min_length": 14,
+            "password_require_special": false,
+            "password_disallow_user_data": true,
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // user creation
+    let mut new_user = AddUserData {
+        username: "adumbledore".into(),
+        last_name: "Dumbledore".into(),
+        first_name: "Albus".into(),
+        email: "a.dumbledore@hogwart.edu.uk".into(),
+        phone: None,
+        password: Some("Alohomora!12".into()),
+    };
+    let response = client.post("/api/v1/user").json(&new_user).send().await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = response.json().await;
+    assert_eq!(body["requirements"], json!(["min_length"]));
+
+    new_user.password = Some("Adumbledore Headmaster 1".into());
+    let response = client.post("/api/v1/user").json(&new_user).send().await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = response.json().await;
+    assert_eq!(body["requirements"], json!(["user_data"]));
+
+    new_user.password = Some("Lemon Drops Forever 1".into());
+    let response = client.post("/api/v1/user").json(&new_user).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // admin password change
+    let response = client
+        .put("/api/v1/user/hpotter/password")
+        .json(&PasswordChange {
+            new_password: "short".into(),
+        })
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = response.json().await;
+    assert_eq!(
+        body["requirements"],
+        json!(["min_length", "uppercase", "digit"])
+    );
+    let response = client
+        .put("/api/v1/user/hpotter/password")
+        .json(&PasswordChange {
+            new_password: "Expecto Patronum 1".into(),
+        })
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // self-service password change
+    let auth = Auth::new("hpotter", "Expecto Patronum 1");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .put("/api/v1/user/change_password")
+        .json(&PasswordChangeSelf {
+            old_password: "Expecto Patronum 1".into(),
+            new_password: "h.potter Password 1".into(),
+        })
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = response.json().await;
+    assert_eq!(body["requirements"], json!(["user_data"]));
+}
+
 #[tokio::test]
 async fn test_user_unregister_authorized_app() {
     let client = make_client().await;
@@ -521,6 +612,8 @@ async fn test_user_unregister_authorized_app() {
         redirect_uri: vec!["http://localhost:3000/".into()],
         scope: vec!["openid".into()],
         enabled: true,
+        backchannel_logout_uri: None,
+        allowed_groups: Vec::new(),
     };
     let response = client
         .post("/api/v1/oauth")
@@ -683,7 +776,7 @@ async fn test_user_add_device() {
         .json(&device_data)
         .send()
         .await;
-    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.status(), StatusCode::CONFLICT);
 
     // normal user cannot add a device for other users
     let device_data = AddDevice {
@@ -0,0 +1,167 @@
+mod common;
+
+use defguard::{
+    db::{models::device::WireguardNetworkDevice, GatewayEvent, User, WireguardNetwork},
+    handlers::Auth,
+};
+use matches::assert_matches;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use self::common::{client::TestClient, make_test_client};
+
+async fn create_network(client: &TestClient, name: &str, port: u16, mfa_enabled: bool) -> i64 {
+    let subnet = port - 50000;
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": name,
+            "address": format!("10.1.{subnet}.1/24"),
+            "port": port,
+            "endpoint": "192.168.4.14",
+            "allowed_ips": format!("10.1.{subnet}.0/24"),
+            "dns": "1.1.1.1",
+            "allowed_groups": [],
+            "mfa_enabled": mfa_enabled,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: Value = response.json().await;
+    network["id"].as_i64().unwrap()
+}
+
+#[tokio::test]
+async fn test_disconnect_user() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+    let mut wg_rx = client_state.wireguard_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let network_id = create_network(&client, "network", 50001, false).await;
+    let mfa_network_id = create_network(&client, "mfa network", 50002, true).await;
+
+    let mut device_ids = Vec::new();
+    for (name, pubkey) in [
+        ("laptop", "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU="),
+        ("phone", "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38="),
+    ] {
+        let response = client
+            .post("/api/v1/device/hpotter")
+            .json(&json!({"name": name, "wireguard_pubkey": pubkey}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let device: Value = response.json().await;
+        device_ids.push(device["device"]["id"].as_i64().unwrap());
+    }
+    while wg_rx.try_recv().is_ok() {}
+
+    // authorize the first device on the MFA location
+    let mut network_device = WireguardNetworkDevice::find(&pool, device_ids[0], mfa_network_id)
+        .await
+        .unwrap()
+        .unwrap();
+    network_device.is_authorized = true;
+    network_device.preshared_key = Some("psk".into());
+    network_device.update(&pool).await.unwrap();
+
+    // only admins can disconnect users
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/user/hpotter/disconnect")
+        .json(&json!({}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post("/api/v1/user/unknown/disconnect")
+        .json(&json!({}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // disconnect and block all devices
+    let response = client
+        .post("/api/v1/user/hpotter/disconnect")
+        .json(&json!({"block_devices": true}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let devices: Vec<Value> = response.json().await;
+    assert_eq!(devices.len(), 2);
+    for device in &devices {
+        assert_eq!(device["blocked"], true);
+        assert_eq!(device["locations"].as_array().unwrap().len(), 2);
+    }
+    for _ in 0..4 {
+        assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerRemoved(_));
+    }
+    assert!(wg_rx.try_recv().is_err());
+
+    // MFA authorization has to be repeated
+    let network_device = WireguardNetworkDevice::find(&pool, device_ids[0], mfa_network_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!network_device.is_authorized);
+    assert!(network_device.preshared_key.is_none());
+
+    // blocked devices aren't configured on gateways
+    let network = WireguardNetwork::find_by_id(&pool, network_id)
+        .await
+        .unwrap()
+        .unwrap();
+    let peers = network.get_peers(&pool).await.unwrap();
+    assert!(peers.is_empty());
+
+    // unblocking restores a single device
+    let response = client
+        .post(format!("/api/v1/device/{}/unblock", device_ids[1]))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let device: Value = response.json().await;
+    assert_eq!(device["blocked"], false);
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(_));
+    let peers = network.get_peers(&pool).await.unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(
+        peers[0].pubkey,
+        "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38="
+    );
+    while wg_rx.try_recv().is_ok() {}
+
+    // disabled users can be disconnected without blocking
+    let mut user = User::find_by_username(&pool, "hpotter")
+        .await
+        .unwrap()
+        .unwrap();
+    user.is_active = false;
+    user.save(&pool).await.unwrap();
+    let response = client
+        .post("/api/v1/user/hpotter/disconnect")
+        .json(&json!({}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let devices: Vec<Value> = response.json().await;
+    assert_eq!(devices.len(), 2);
+    assert_eq!(devices[0]["blocked"], true);
+    assert_eq!(devices[1]["blocked"], false);
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerRemoved(_));
+}
@@ -0,0 +1,209 @@
+mod common;
+
+use defguard::{
+    db::models::user_field::{UserFieldDefinition, UserFieldValue},
+    handlers::Auth,
+};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use self::common::{client::TestClient, fetch_user_details, make_test_client};
+
+async fn make_client() -> TestClient {
+    let (client, _) = make_test_client().await;
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    client
+}
+
+async fn add_field(client: &TestClient, field: Value) -> UserFieldDefinition {
+    let response = client.post("/api/v1/user_field").json(&field).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    response.json().await
+}
+
+#[tokio::test]
+async fn test_user_field_definitions() {
+    let client = make_client().await;
+
+    let office = add_field(
+        &client,
+        json!({"name": "office", "field_type": "choice", "options": ["Warsaw", "Berlin"]}),
+    )
+    .await;
+    add_field(
+        &client,
+        json!({"name": "cost_center", "field_type": "number", "required": true}),
+    )
+    .await;
+
+    // duplicate name
+    let response = client
+        .post("/api/v1/user_field")
+        .json(&json!({"name": "office", "field_type": "text"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    // choice without options
+    let response = client
+        .post("/api/v1/user_field")
+        .json(&json!({"name": "team", "field_type": "choice"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    // unknown type
+    let response = client
+        .post("/api/v1/user_field")
+        .json(&json!({"name": "team", "field_type": "color"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let response = client.get("/api/v1/user_field").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let fields: Vec<UserFieldDefinition> = response.json().await;
+    let names: Vec<_> = fields.iter().map(|field| field.name.as_str()).collect();
+    assert_eq!(names, ["cost_center", "office"]);
+
+    let id = office.id.unwrap();
+    let response = client
+        .put(&format!("/api/v1/user_field/{id}"))
+        .json(&json!({"name": "office", "field_type": "choice", "options": ["Warsaw", "Berlin", "Gdansk"]}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let office: UserFieldDefinition = response.json().await;
+    assert_eq!(office.options.len(), 3);
+
+    // unused field is deleted without confirmation
+    let response = client
+        .delete(&format!("/api/v1/user_field/{id}"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .delete(&format!("/api/v1/user_field/{id}"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // only admins manage definitions
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/user_field")
+        .json(&json!({"name": "team", "field_type": "text"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_user_field_values() {
+    let client = make_client().await;
+
+    let office = add_field(
+        &client,
+        json!({"name": "office", "field_type": "choice", "options": ["Warsaw", "Berlin"]}),
+    )
+    .await;
+    add_field(
+        &client,
+        json!({"name": "cost_center", "field_type": "number", "required": true}),
+    )
+    .await;
+    add_field(&client, json!({"name": "hired", "field_type": "date"})).await;
+
+    // type validation
+    for values in [
+        json!({"cost_center": "abc"}),
+        json!({"cost_center": "100", "office": "Paris"}),
+        json!({"cost_center": "100", "hired": "yesterday"}),
+        json!({"cost_center": "100", "no_such_field": "x"}),
+        // required field missing
+        json!({"office": "Warsaw"}),
+    ] {
+        let response = client
+            .put("/api/v1/user/hpotter/fields")
+            .json(&values)
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    let user_details = fetch_user_details(&client, "hpotter").await;
+    assert!(user_details.custom_fields.is_empty());
+
+    let response = client
+        .put("/api/v1/user/hpotter/fields")
+        .json(&json!({"cost_center": "1200", "office": "Berlin", "hired": "2024-07-01"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // values surface in user details
+    let user_details = fetch_user_details(&client, "hpotter").await;
+    let values: Vec<_> = user_details
+        .custom_fields
+        .iter()
+        .map(|field| (field.name.as_str(), field.value.as_str()))
+        .collect();
+    assert_eq!(
+        values,
+        [
+            ("cost_center", "1200"),
+            ("hired", "2024-07-01"),
+            ("office", "Berlin")
+        ]
+    );
+
+    // removing a value
+    let response = client
+        .put("/api/v1/user/hpotter/fields")
+        .json(&json!({"hired": null}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let values: Vec<UserFieldValue> = response.json().await;
+    assert_eq!(values.len(), 2);
+
+    // option in use can't be removed
+    let id = office.id.unwrap();
+    let response = client
+        .put(&format!("/api/v1/user_field/{id}"))
+        .json(&json!({"name": "office", "field_type": "choice", "options": ["Warsaw"]}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // deleting a field in use requires confirmation and removes values
+    let response = client
+        .delete(&format!("/api/v1/user_field/{id}"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = client
+        .delete(&format!("/api/v1/user_field/{id}?confirm=true"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let user_details = fetch_user_details(&client, "hpotter").await;
+    assert_eq!(user_details.custom_fields.len(), 1);
+    assert_eq!(user_details.custom_fields[0].name, "cost_center");
+
+    // users can see their own values, but not change them
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/user/hpotter/fields").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let values: Vec<UserFieldValue> = response.json().await;
+    assert_eq!(values.len(), 1);
+    let response = client
+        .put("/api/v1/user/hpotter/fields")
+        .json(&json!({"cost_center": "1"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
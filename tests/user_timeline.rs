@@ -0,0 +1,190 @@
+mod common;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use defguard::{
+    db::{
+        models::{device_login::DeviceLoginEvent, oauth2client::OAuth2Client},
+        Device, User, WireguardNetwork, WireguardPeerStats,
+    },
+    handlers::Auth,
+};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use sqlx::{query, PgPool};
+
+use self::common::{client::TestClient, make_test_client};
+
+async fn add_login(pool: &PgPool, user_id: i64, browser: &str, created: NaiveDateTime) {
+    let mut event = DeviceLoginEvent::new(
+        user_id,
+        "10.0.0.1".into(),
+        None,
+        "Other".into(),
+        None,
+        "Linux".into(),
+        browser.into(),
+        "AUTHENTICATION".into(),
+    );
+    event.created = created;
+    event.save(pool).await.unwrap();
+}
+
+async fn add_handshake(pool: &PgPool, device_id: i64, latest_handshake: NaiveDateTime) {
+    let mut stats = WireguardPeerStats {
+        id: None,
+        device_id,
+        collected_at: latest_handshake,
+        network: 1,
+        endpoint: Some("11.22.33.44:51820".into()),
+        upload: 10,
+        download: 20,
+        latest_handshake,
+        allowed_ips: Some("10.1.1.2/32".into()),
+    };
+    stats.save(pool).await.unwrap();
+}
+
+fn timestamp(time: NaiveDateTime) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.f").to_string()
+}
+
+async fn fetch_page(client: &TestClient, query: &str) -> Value {
+    let response = client
+        .get(format!("/api/v1/user/hpotter/timeline?{query}"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await
+}
+
+fn kinds(page: &Value) -> Vec<String> {
+    page["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| event["kind"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_user_timeline() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": "network",
+            "address": "10.1.1.1/24",
+            "port": 55555,
+            "endpoint": "192.168.4.14",
+            "allowed_ips": "10.1.1.0/24",
+            "dns": "1.1.1.1",
+            "allowed_groups": [],
+            "mfa_enabled": false,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let user = User::find_by_username(&pool, "hpotter")
+        .await
+        .unwrap()
+        .unwrap();
+    let user_id = user.id.unwrap();
+    let mut device = Device::new("laptop".into(), WireguardNetwork::genkey().public, user_id);
+    device.save(&pool).await.unwrap();
+    let device_id = device.id.unwrap();
+    let mut client_app = OAuth2Client::new(
+        vec!["http://localhost".into()],
+        vec!["openid".into()],
+        "Wiki".into(),
+    );
+    client_app.save(&pool).await.unwrap();
+
+    // events of all kinds, interleaved
+    let start = (Utc::now() - Duration::days(1)).naive_utc();
+    add_login(&pool, user_id, "Firefox", start).await;
+    for (offset, grant_type) in [(0, "authorization_code"), (5 * 60, "refresh_token")] {
+        query!(
+            "INSERT INTO oauth2_token_issuance (user_id, oauth2client_id, grant_type, issued_at) \
+            VALUES ($1, $2, $3, $4)",
+            user_id,
+            client_app.id.unwrap(),
+            grant_type,
+            start + Duration::seconds(offset)
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+    // two sessions, separated by a gap longer than disconnect threshold
+    for offset in [10, 70, 130, 30 * 60, 31 * 60] {
+        add_handshake(&pool, device_id, start + Duration::seconds(offset)).await;
+    }
+    add_login(&pool, user_id, "Chrome", start + Duration::minutes(40)).await;
+    // other users' events are not included
+    add_login(&pool, 1, "Safari", start + Duration::minutes(1)).await;
+
+    let range = format!(
+        "from={}&to={}",
+        timestamp(start - Duration::hours(1)),
+        timestamp(start + Duration::hours(1))
+    );
+    let page = fetch_page(&client, &format!("{range}&limit=4")).await;
+    assert_eq!(
+        kinds(&page),
+        ["device_login", "oidc_token", "vpn_session", "oidc_token"]
+    );
+    let session = &page["events"][2];
+    assert_eq!(session["device_name"], "laptop");
+    assert_eq!(session["network_name"], "network");
+    assert_eq!(session["duration_secs"], 120);
+    assert_eq!(page["events"][1]["app_name"], "Wiki");
+    let cursor = page["next_cursor"].as_str().unwrap().to_string();
+
+    // events recorded before the cursor don't shift the next page
+    add_login(&pool, user_id, "Edge", start - Duration::minutes(30)).await;
+    let page = fetch_page(&client, &format!("{range}&limit=4&cursor={cursor}")).await;
+    assert_eq!(kinds(&page), ["vpn_session", "device_login"]);
+    assert_eq!(page["events"][0]["duration_secs"], 60);
+    assert_eq!(page["events"][1]["browser"], "Chrome");
+    assert!(page["next_cursor"].is_null());
+
+    // time range
+    let page = fetch_page(
+        &client,
+        &format!("from={}", timestamp(start + Duration::minutes(20))),
+    )
+    .await;
+    assert_eq!(kinds(&page), ["vpn_session", "device_login"]);
+
+    // invalid requests
+    for query in [
+        format!("{range}&cursor=invalid"),
+        format!("{range}&limit=0"),
+        format!("from={}&to={}", timestamp(start), timestamp(start)),
+    ] {
+        let response = client
+            .get(format!("/api/v1/user/hpotter/timeline?{query}"))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    let response = client.get("/api/v1/user/unknown/timeline").send().await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // only admins can see timelines
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/user/hpotter/timeline").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
@@ -1,20 +1,23 @@
 mod common;
 
+use std::net::IpAddr;
+
 use defguard::{
     db::{
         models::{
-            device::WireguardNetworkDevice,
-            wireguard::{DEFAULT_DISCONNECT_THRESHOLD, DEFAULT_KEEPALIVE_INTERVAL},
+            device::{DevicePlatform, MachineHints, WireguardNetworkDevice},
+            wireguard::{NetworkOverlap, DEFAULT_DISCONNECT_THRESHOLD, DEFAULT_KEEPALIVE_INTERVAL},
         },
         Device, GatewayEvent, WireguardNetwork,
     },
     handlers::{wireguard::WireguardNetworkData, Auth, GroupInfo},
 };
+use ipnetwork::IpNetwork;
 use matches::assert_matches;
 use reqwest::StatusCode;
 use serde_json::{json, Value};
 
-use self::common::make_test_client;
+use self::common::{fetch_user_details, make_test_client};
 
 fn make_network() -> Value {
     json!({
@@ -70,6 +73,18 @@ async fn test_network() {
         mfa_enabled: false,
         keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
         peer_disconnect_threshold: DEFAULT_DISCONNECT_THRESHOLD,
+        psk_rotation_days: None,
+        gateway_allowed_ips: None,
+        mtu: None,
+        dns_zone: None,
+        upload_limit_kbps: None,
+        download_limit_kbps: None,
+        allowed_platforms: Vec::new(),
+        deny_unknown_platform: false,
+        client_routes: None,
+        masquerade_enabled: false,
+        nat_exempt_networks: None,
+        server_managed_keys: false,
     };
     let response = client
         .put(format!("/api/v1/network/{}", network.id.unwrap()))
@@ -150,7 +165,7 @@ async fn test_device() {
         .await;
     assert_eq!(response.status(), StatusCode::CREATED);
     let event = wg_rx.try_recv().unwrap();
-    assert_matches!(event, GatewayEvent::DeviceCreated(..));
+    assert_matches!(event, GatewayEvent::PeerAdded(..));
 
     // an IP was assigned for new device
     let network_devices = WireguardNetworkDevice::find_by_device(&client_state.pool, 1)
@@ -163,11 +178,10 @@ async fn test_device() {
     );
 
     // add another network
-    let response = client
-        .post("/api/v1/network")
-        .json(&make_network())
-        .send()
-        .await;
+    let mut network = make_network();
+    network["address"] = json!("10.2.2.1/24");
+    network["allowed_ips"] = json!("10.2.2.0/24");
+    let response = client.post("/api/v1/network").json(&network).send().await;
     assert_eq!(response.status(), StatusCode::CREATED);
     assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkCreated(..));
 
@@ -214,8 +228,11 @@ async fn test_device() {
         .send()
         .await;
     assert_eq!(response.status(), StatusCode::OK);
-    let event = wg_rx.try_recv().unwrap();
-    assert_matches!(event, GatewayEvent::DeviceModified(..));
+    // device belongs to both networks
+    for _ in 0..2 {
+        let event = wg_rx.try_recv().unwrap();
+        assert_matches!(event, GatewayEvent::PeerModified(..));
+    }
 
     // device details
     let response = client
@@ -249,7 +266,7 @@ async fn test_device() {
             PublicKey = {}\n\
             AllowedIPs = 10.1.1.0/24\n\
             Endpoint = 192.168.4.14:55555\n\
-            PersistentKeepalive = 300",
+            PersistentKeepalive = 25",
             network_from_details.pubkey
         )
     );
@@ -272,7 +289,7 @@ async fn test_device() {
         .await;
     assert_eq!(response.status(), StatusCode::OK);
     let event = wg_rx.try_recv().unwrap();
-    assert_matches!(event, GatewayEvent::DeviceDeleted(..));
+    assert_matches!(event, GatewayEvent::PeerRemoved(..));
 
     let response = client.get("/api/v1/device").json(&device).send().await;
     assert_eq!(response.status(), StatusCode::OK);
@@ -280,6 +297,81 @@ async fn test_device() {
     assert!(devices.is_empty());
 }
 
+#[tokio::test]
+async fn test_device_peer_events() {
+    let (client, client_state) = make_test_client().await;
+    let mut wg_rx = client_state.wireguard_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post("/api/v1/network")
+        .json(&make_network())
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: WireguardNetwork = response.json().await;
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkCreated(..));
+
+    // add, rename, change key and delete a device
+    let pubkey = "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=";
+    let new_pubkey = "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=";
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({"name": "device", "wireguard_pubkey": pubkey}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    let device_id = device["device"]["id"].as_i64().unwrap();
+
+    let response = client
+        .put(format!("/api/v1/device/{device_id}"))
+        .json(&json!({"name": "renamed", "wireguard_pubkey": pubkey}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .put(format!("/api/v1/device/{device_id}"))
+        .json(&json!({"name": "renamed", "wireguard_pubkey": new_pubkey}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .delete(format!("/api/v1/device/{device_id}"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // exactly one event per change, in order; renaming doesn't concern gateways
+    let GatewayEvent::PeerAdded(peer) = wg_rx.try_recv().unwrap() else {
+        panic!("Expected peer added event")
+    };
+    assert_eq!(peer.network_id(), network.id.unwrap());
+    assert_eq!(peer.device.wireguard_pubkey, pubkey);
+    let wireguard_ip = peer.network_info.device_wireguard_ip;
+
+    let GatewayEvent::PeerModified(peer, previous_pubkey) = wg_rx.try_recv().unwrap() else {
+        panic!("Expected peer modified event")
+    };
+    assert_eq!(peer.network_id(), network.id.unwrap());
+    assert_eq!(peer.device.wireguard_pubkey, new_pubkey);
+    assert_eq!(peer.network_info.device_wireguard_ip, wireguard_ip);
+    assert_eq!(previous_pubkey.as_deref(), Some(pubkey));
+
+    let GatewayEvent::PeerRemoved(peer) = wg_rx.try_recv().unwrap() else {
+        panic!("Expected peer removed event")
+    };
+    assert_eq!(peer.network_id(), network.id.unwrap());
+    assert_eq!(peer.device.wireguard_pubkey, new_pubkey);
+
+    assert!(wg_rx.try_recv().is_err());
+}
+
 #[tokio::test]
 async fn test_device_permissions() {
     let (client, _) = make_test_client().await;
@@ -470,7 +562,7 @@ async fn test_device_pubkey() {
         .json(&device)
         .send()
         .await;
-    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
 
     // create good device
     let device = json!({
@@ -520,7 +612,7 @@ async fn test_device_pubkey() {
         .json(&devices)
         .send()
         .await;
-    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
 
     // make sure no device was created
     let response = client.get("/api/v1/device").json(&device).send().await;
@@ -528,3 +620,1977 @@ async fn test_device_pubkey() {
     let devices: Vec<Device> = response.json().await;
     assert_eq!(devices.len(), 1);
 }
+
+#[tokio::test]
+async fn test_device_pubkey_normalization() {
+    let (client, client_state) = make_test_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/network")
+        .json(&make_network())
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // malformed keys are rejected with a reason
+    for (pubkey, msg) in [
+        ("", "Public key is empty"),
+        (
+            "c2hvcnQ=",
+            "Public key c2hvcnQ= is 5 bytes long, expected 32",
+        ),
+    ] {
+        let response = client
+            .post("/api/v1/device/admin")
+            .json(&json!({"name": "device", "wireguard_pubkey": pubkey}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let error: Value = response.json().await;
+        assert_eq!(error["msg"], msg);
+    }
+
+    // whitespace and missing padding are normalized
+    let pubkey = "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=";
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({"name": "laptop", "wireguard_pubkey": " LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU\n"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    assert_eq!(device["device"]["wireguard_pubkey"], pubkey);
+    let device_id = device["device"]["id"].as_i64().unwrap();
+
+    // the same key can't be used twice, in any form
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&json!({"name": "phone", "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let error: Value = response.json().await;
+    assert!(error["msg"].as_str().unwrap().contains("device laptop"));
+
+    let other_pubkey = "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=";
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&json!({"name": "phone", "wireguard_pubkey": other_pubkey}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    let other_id = device["device"]["id"].as_i64().unwrap();
+
+    // modifying
+    let response = client
+        .put(format!("/api/v1/device/{other_id}"))
+        .json(&json!({"name": "phone", "wireguard_pubkey": "invalid_key"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let response = client
+        .put(format!("/api/v1/device/{other_id}"))
+        .json(&json!({"name": "phone", "wireguard_pubkey": pubkey}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    // keeping own key is fine
+    let response = client
+        .put(format!("/api/v1/device/{device_id}"))
+        .json(&json!({"name": "renamed", "wireguard_pubkey": format!("{pubkey}  ")}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // network device provisioning
+    let response = client
+        .post("/api/v1/network/1/devices")
+        .json(&json!({"devices": [{
+            "name": "tablet",
+            "wireguard_ip": "10.1.1.20",
+            "wireguard_pubkey": other_pubkey,
+            "user_id": 1,
+            "created": "2023-05-05T23:56:04"
+        }]}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // devices saved before keys were validated are reported, until fixed
+    let response = client.get("/api/v1/device/invalid_pubkeys").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report: Vec<Value> = response.json().await;
+    assert!(report.is_empty());
+    let broken_id: i64 = sqlx::query_scalar(
+        "INSERT INTO device (name, wireguard_pubkey, user_id, created, pubkey_issue) \
+        VALUES ('broken', '', 1, now(), 'invalid') RETURNING id",
+    )
+    .fetch_one(&client_state.pool)
+    .await
+    .unwrap();
+    let response = client.get("/api/v1/device/invalid_pubkeys").send().await;
+    let report: Vec<Value> = response.json().await;
+    assert_eq!(
+        report,
+        vec![json!({
+            "id": broken_id,
+            "name": "broken",
+            "user_id": 1,
+            "username": "admin",
+            "wireguard_pubkey": "",
+            "issue": "invalid",
+        })]
+    );
+    let response = client
+        .put(format!("/api/v1/device/{broken_id}"))
+        .json(&json!({"name": "broken", "wireguard_pubkey": "o/8q3kmv5nnbrcb/7aceQWGE44a0yI707wObXRyyWGU="}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/device/invalid_pubkeys").send().await;
+    let report: Vec<Value> = response.json().await;
+    assert!(report.is_empty());
+
+    // report is for admins only
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/device/invalid_pubkeys").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_ipv6_only_network() {
+    let (client, client_state) = make_test_client().await;
+
+    let mut wg_rx = client_state.wireguard_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // create network
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": "network",
+            "address": "fd00::1/64",
+            "port": 55555,
+            "endpoint": "2001:db8::1",
+            "allowed_ips": "fd00::/64",
+            "dns": "2001:4860:4860::8888",
+            "allowed_groups": [],
+            "mfa_enabled": false,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: WireguardNetwork = response.json().await;
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkCreated(..));
+
+    // create device
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({
+            "name": "device",
+            "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let result: Value = response.json().await;
+    let device_id = result["device"]["id"].as_i64().unwrap();
+    let config = &result["configs"][0];
+    assert_eq!(config["address"], "fd00::2");
+    assert_eq!(config["endpoint"], "[2001:db8::1]:55555");
+    assert_eq!(config["allowed_ips"], json!(["fd00::/64"]));
+    assert_eq!(config["dns"], "2001:4860:4860::8888");
+    let config = config["config"].as_str().unwrap();
+    assert!(config.contains("Address = fd00::2\n"));
+    assert!(config.contains("DNS = 2001:4860:4860::8888\n"));
+    assert!(config.contains("AllowedIPs = fd00::/64\n"));
+    assert!(config.contains("Endpoint = [2001:db8::1]:55555\n"));
+
+    match wg_rx.try_recv().unwrap() {
+        GatewayEvent::PeerAdded(peer) => {
+            assert_eq!(peer.device.id, Some(device_id));
+            assert_eq!(peer.network_info.network_id, network.id.unwrap());
+            assert_eq!(
+                peer.network_info.device_wireguard_ip,
+                "fd00::2".parse::<IpAddr>().unwrap()
+            );
+        }
+        event => panic!("Unexpected event {event:?}"),
+    }
+
+    // downloaded config matches
+    let response = client
+        .get(format!(
+            "/api/v1/network/{}/device/{device_id}/config",
+            network.id.unwrap()
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await, config);
+}
+
+#[tokio::test]
+async fn test_archive_network() {
+    let (client, client_state) = make_test_client().await;
+
+    let mut wg_rx = client_state.wireguard_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // create network and a device
+    let response = client
+        .post("/api/v1/network")
+        .json(&make_network())
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: WireguardNetwork = response.json().await;
+    let network_id = network.id.unwrap();
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkCreated(..));
+
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({
+            "name": "device",
+            "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(..));
+    let peers = network.get_peers(&client_state.pool).await.unwrap();
+    assert_eq!(peers.len(), 1);
+
+    // archive network
+    let response = client
+        .post(format!("/api/v1/network/{network_id}/archive"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let archived: WireguardNetwork = response.json().await;
+    assert!(archived.archived);
+    match wg_rx.try_recv().unwrap() {
+        GatewayEvent::NetworkDeleted(id, name) => {
+            assert_eq!(id, network_id);
+            assert_eq!(name, "network");
+        }
+        event => panic!("Unexpected event {event:?}"),
+    }
+    let response = client
+        .post(format!("/api/v1/network/{network_id}/archive"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // archived network is listed separately
+    let response = client.get("/api/v1/network").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let networks: Vec<WireguardNetwork> = response.json().await;
+    assert!(networks.is_empty());
+    let response = client.get("/api/v1/network/archived").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let networks: Vec<WireguardNetwork> = response.json().await;
+    assert_eq!(networks.len(), 1);
+    assert_eq!(networks[0].id, Some(network_id));
+
+    // archived network can't be modified
+    let response = client
+        .post(format!("/api/v1/network/{network_id}/devices"))
+        .json(&json!({"devices": [{
+            "name": "imported",
+            "wireguard_ip": "10.1.1.10",
+            "wireguard_pubkey": "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=",
+            "user_id": 1,
+        }]}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = client
+        .get(format!("/api/v1/network/{network_id}/token"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // new devices are not added to archived network
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({
+            "name": "other",
+            "wireguard_pubkey": "hNuapt7lOxF93KUqZGUY00oKJxH8LYwwsUVB1uUa0y4=",
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let result: Value = response.json().await;
+    assert_eq!(result["configs"], json!([]));
+    assert!(wg_rx.try_recv().is_err());
+
+    // restore network
+    let response = client
+        .post(format!("/api/v1/network/{network_id}/unarchive"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    match wg_rx.try_recv().unwrap() {
+        GatewayEvent::NetworkModified(id, restored, restored_peers) => {
+            assert_eq!(id, network_id);
+            assert!(!restored.archived);
+            // device added while the network was archived gets its address on restore
+            assert_eq!(restored_peers[..peers.len()], peers);
+            assert_eq!(restored_peers.len(), peers.len() + 1);
+            assert_eq!(
+                restored_peers[peers.len()].pubkey,
+                "hNuapt7lOxF93KUqZGUY00oKJxH8LYwwsUVB1uUa0y4="
+            );
+        }
+        event => panic!("Unexpected event {event:?}"),
+    }
+    let response = client
+        .post(format!("/api/v1/network/{network_id}/unarchive"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = client.get("/api/v1/network").send().await;
+    let networks: Vec<WireguardNetwork> = response.json().await;
+    assert_eq!(networks.len(), 1);
+}
+
+#[tokio::test]
+async fn test_rotate_device_psk() {
+    let (client, client_state) = make_test_client().await;
+
+    let mut wg_rx = client_state.wireguard_rx;
+    let mut mail_rx = client_state.mail_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut network = make_network();
+    network["psk_rotation_days"] = json!(30);
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: WireguardNetwork = response.json().await;
+    assert_eq!(network.psk_rotation_days, Some(30));
+    let network_id = network.id.unwrap();
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkCreated(..));
+
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({
+            "name": "device",
+            "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    let device_id = device["device"]["id"].as_i64().unwrap();
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(..));
+    while mail_rx.try_recv().is_ok() {}
+
+    // nothing to confirm yet
+    let response = client
+        .post(format!(
+            "/api/v1/device/{device_id}/confirm_psk?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // new key is staged, gateway still uses the previous one
+    let response = client
+        .post(format!(
+            "/api/v1/device/{device_id}/rotate_psk?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let rotation: Value = response.json().await;
+    assert!(rotation["fingerprint"].is_string());
+    assert!(rotation["deadline"].is_string());
+    assert!(wg_rx.try_recv().is_err());
+    let mail = mail_rx.try_recv().unwrap();
+    assert_eq!(mail.subject, "Defguard: device preshared key rotation");
+    assert!(mail
+        .content
+        .contains(rotation["fingerprint"].as_str().unwrap()));
+
+    let network_device = WireguardNetworkDevice::find(&client_state.pool, device_id, network_id)
+        .await
+        .unwrap()
+        .unwrap();
+    let pending_key = network_device.pending_preshared_key.unwrap();
+    assert!(network_device.preshared_key.is_none());
+
+    // configs keep the key gateways use, the pending one is served on its own
+    let config_path = format!("/api/v1/network/{network_id}/device/{device_id}/config");
+    let response = client.get(&config_path).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.text().await.contains("PresharedKey"));
+    let response = client
+        .get(format!(
+            "/api/v1/device/{device_id}/pending_psk?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let pending: Value = response.json().await;
+    assert_eq!(pending["preshared_key"], pending_key);
+    assert_eq!(pending["fingerprint"], rotation["fingerprint"]);
+    assert_eq!(pending["deadline"], rotation["deadline"]);
+
+    // confirmation switches gateway to the new key, and configs along with it
+    let response = client
+        .post(format!(
+            "/api/v1/device/{device_id}/confirm_psk?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    match wg_rx.try_recv().unwrap() {
+        GatewayEvent::PeerModified(update, None) => {
+            assert_eq!(update.device.id, Some(device_id));
+            assert_eq!(update.network_info.preshared_key, Some(pending_key.clone()));
+        }
+        event => panic!("Unexpected event {event:?}"),
+    }
+    let response = client.get(&config_path).send().await;
+    assert!(response
+        .text()
+        .await
+        .contains(&format!("PresharedKey = {pending_key}")));
+    let response = client
+        .get(format!(
+            "/api/v1/device/{device_id}/pending_psk?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let network_device = WireguardNetworkDevice::find(&client_state.pool, device_id, network_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(network_device.preshared_key, Some(pending_key));
+    assert!(network_device.pending_preshared_key.is_none());
+
+    // MFA-protected locations renew keys on login
+    let mut network = make_network();
+    network["mfa_enabled"] = json!(true);
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post(format!(
+            "/api/v1/device/{device_id}/rotate_psk?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // invalid rotation period
+    network["mfa_enabled"] = json!(false);
+    network["psk_rotation_days"] = json!(0);
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_pinned_device_psk() {
+    let (client, client_state) = make_test_client().await;
+
+    let mut wg_rx = client_state.wireguard_rx;
+    let psk = "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=";
+    let other_psk = "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=";
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut network = make_network();
+    network["psk_rotation_days"] = json!(30);
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: WireguardNetwork = response.json().await;
+    let network_id = created.id.unwrap();
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkCreated(..));
+
+    // imported devices can come with their preshared keys
+    let mut mapped_device = json!({"devices": [{
+        "user_id": 1,
+        "name": "appliance",
+        "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+        "wireguard_ip": "10.1.1.10",
+        "preshared_key": "c2hvcnQ=",
+    }]});
+    let response = client
+        .post(format!("/api/v1/network/{network_id}/devices"))
+        .json(&mapped_device)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(!response.text().await.contains("c2hvcnQ="));
+    mapped_device["devices"][0]["preshared_key"] = json!(psk);
+    let response = client
+        .post(format!("/api/v1/network/{network_id}/devices"))
+        .json(&mapped_device)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device_id = match wg_rx.try_recv().unwrap() {
+        GatewayEvent::PeerAdded(update) => {
+            assert_eq!(update.network_info.network_id, network_id);
+            assert_eq!(update.network_info.preshared_key.as_deref(), Some(psk));
+            update.device.id.unwrap()
+        }
+        event => panic!("Unexpected event {event:?}"),
+    };
+    let network_device = WireguardNetworkDevice::find(&client_state.pool, device_id, network_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(network_device.preshared_key_pinned);
+
+    // reads only show the fingerprint
+    let response = client
+        .get(format!(
+            "/api/v1/device/{device_id}/effective_config?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let config = response.text().await;
+    assert!(!config.contains(psk));
+    let config: Value = serde_json::from_str(&config).unwrap();
+    assert_eq!(config["network_settings"]["preshared_key"], json!(true));
+    assert_eq!(
+        config["network_settings"]["preshared_key_pinned"],
+        json!(true)
+    );
+    let fingerprint = config["network_settings"]["preshared_key_fingerprint"].clone();
+    assert!(fingerprint.is_string());
+
+    // pinned keys aren't rotated
+    let response = client
+        .post(format!(
+            "/api/v1/device/{device_id}/rotate_psk?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // keys can be pinned for existing devices, gateways get the new key
+    let response = client
+        .put(format!(
+            "/api/v1/network/{network_id}/device/{device_id}/preshared_key"
+        ))
+        .json(&json!({"preshared_key": "not a key"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .put(format!(
+            "/api/v1/network/{network_id}/device/{device_id}/preshared_key"
+        ))
+        .json(&json!({"preshared_key": other_psk}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let pinned = response.text().await;
+    assert!(!pinned.contains(other_psk));
+    let pinned: Value = serde_json::from_str(&pinned).unwrap();
+    assert_eq!(pinned["pinned"], json!(true));
+    assert_ne!(pinned["fingerprint"], fingerprint);
+    match wg_rx.try_recv().unwrap() {
+        GatewayEvent::PeerModified(update, None) => {
+            assert_eq!(update.device.id, Some(device_id));
+            assert_eq!(
+                update.network_info.preshared_key.as_deref(),
+                Some(other_psk)
+            );
+        }
+        event => panic!("Unexpected event {event:?}"),
+    }
+    let response = client
+        .put(format!(
+            "/api/v1/network/{network_id}/device/12345/preshared_key"
+        ))
+        .json(&json!({"preshared_key": other_psk}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // unpinned keys are rotated again
+    let response = client
+        .delete(format!(
+            "/api/v1/network/{network_id}/device/{device_id}/preshared_key"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let network_device = WireguardNetworkDevice::find(&client_state.pool, device_id, network_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!network_device.preshared_key_pinned);
+    assert_eq!(network_device.preshared_key.as_deref(), Some(other_psk));
+    let response = client
+        .post(format!(
+            "/api/v1/device/{device_id}/rotate_psk?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // MFA-protected locations renew keys on login
+    network["mfa_enabled"] = json!(true);
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .put(format!(
+            "/api/v1/network/{network_id}/device/{device_id}/preshared_key"
+        ))
+        .json(&json!({"preshared_key": psk}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_gateway_allowed_ips() {
+    let (client, _) = make_test_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut network = make_network();
+    network["gateway_allowed_ips"] = json!("192.168.4.0/24, 2001:db8:1::/48");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: WireguardNetwork = response.json().await;
+    let network_id = created.id.unwrap();
+    assert_eq!(
+        created.gateway_allowed_ips,
+        vec![
+            "192.168.4.0/24".parse().unwrap(),
+            "2001:db8:1::/48".parse().unwrap()
+        ]
+    );
+
+    // invalid entries are rejected, not skipped
+    network["gateway_allowed_ips"] = json!("192.168.4.0/24, gateway.example.com");
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // empty list removes the restriction
+    network["gateway_allowed_ips"] = json!("");
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .get(format!("/api/v1/network/{network_id}"))
+        .send()
+        .await;
+    let network: Value = response.json().await;
+    assert_eq!(network["gateway_allowed_ips"], json!([]));
+}
+
+#[tokio::test]
+async fn test_network_mtu_and_keepalive() {
+    let (client, _) = make_test_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // bounds depend on address family
+    let mut network = make_network();
+    for mtu in [575, 1501] {
+        network["mtu"] = json!(mtu);
+        let response = client.post("/api/v1/network").json(&network).send().await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    let mut v6 = make_network();
+    v6["name"] = json!("v6");
+    v6["address"] = json!("fd00::1/64");
+    v6["allowed_ips"] = json!("fd00::/64");
+    v6["mtu"] = json!(1000);
+    let response = client.post("/api/v1/network").json(&v6).send().await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    v6["mtu"] = json!(1280);
+    let response = client.post("/api/v1/network").json(&v6).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    network["mtu"] = json!(576);
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: WireguardNetwork = response.json().await;
+    assert_eq!(created.mtu, Some(576));
+    let network_id = created.id.unwrap();
+
+    let device = json!({
+        "name": "phone",
+        "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+    });
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&device)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    let device_id = device["device"]["id"].as_i64().unwrap();
+
+    // both values end up in client config
+    network["mtu"] = json!(1280);
+    network["keepalive_interval"] = json!(15);
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .get(format!(
+            "/api/v1/network/{network_id}/device/{device_id}/config"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let config = response.text().await;
+    assert!(config.contains("DNS = 1.1.1.1\nMTU = 1280\n\n[Peer]"));
+    assert!(config.ends_with("PersistentKeepalive = 15"));
+
+    // MTU can be unset again
+    network["mtu"] = json!(null);
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .get(format!(
+            "/api/v1/network/{network_id}/device/{device_id}/config"
+        ))
+        .send()
+        .await;
+    assert!(!response.text().await.contains("MTU"));
+}
+
+#[tokio::test]
+async fn test_bandwidth_limits() {
+    let (client, client_state) = make_test_client().await;
+    let mut wg_rx = client_state.wireguard_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // limits have to be positive and within configured maximum
+    let mut network = make_network();
+    for limit in [json!(0), json!(-1), json!(i32::MAX)] {
+        network["upload_limit_kbps"] = limit;
+        let response = client.post("/api/v1/network").json(&network).send().await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    network["upload_limit_kbps"] = json!(null);
+    network["download_limit_kbps"] = json!(10_000);
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: WireguardNetwork = response.json().await;
+    assert_eq!(created.download_limit_kbps, Some(10_000));
+    let network_id = created.id.unwrap();
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkCreated(..));
+
+    let pubkey = "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=";
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({"name": "phone", "wireguard_pubkey": pubkey}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    let device_id = device["device"]["id"].as_i64().unwrap();
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(..));
+
+    // location defaults apply to all peers
+    let peers = created.get_peers(&client_state.pool).await.unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].upload_limit_kbps, None);
+    assert_eq!(peers[0].download_limit_kbps, Some(10_000));
+
+    // device overrides take precedence, and only the device peer is updated
+    let url = format!("/api/v1/network/{network_id}/device/{device_id}/limits");
+    let response = client
+        .put(&url)
+        .json(&json!({"upload_limit_kbps": 0}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(wg_rx.try_recv().is_err());
+    let response = client
+        .put(&url)
+        .json(&json!({"upload_limit_kbps": 500, "download_limit_kbps": 2000}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let GatewayEvent::PeerModified(peer, None) = wg_rx.try_recv().unwrap() else {
+        panic!("Expected peer modified event")
+    };
+    assert_eq!(peer.device.wireguard_pubkey, pubkey);
+    assert!(wg_rx.try_recv().is_err());
+    let network_device = WireguardNetworkDevice::find(&client_state.pool, device_id, network_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(network_device.upload_limit_kbps, Some(500));
+    assert_eq!(network_device.download_limit_kbps, Some(2000));
+    let peers = created.get_peers(&client_state.pool).await.unwrap();
+    assert_eq!(peers[0].upload_limit_kbps, Some(500));
+    assert_eq!(peers[0].download_limit_kbps, Some(2000));
+
+    // devices not in the location can't be limited
+    let response = client
+        .put(format!("/api/v1/network/{network_id}/device/12345/limits"))
+        .json(&json!({"upload_limit_kbps": 500}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // changing location defaults sends full configuration
+    network["upload_limit_kbps"] = json!(1000);
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let GatewayEvent::NetworkModified(_, _, peers) = wg_rx.try_recv().unwrap() else {
+        panic!("Expected network modified event")
+    };
+    assert_eq!(peers[0].upload_limit_kbps, Some(500));
+
+    // removing device overrides falls back to location defaults
+    let response = client.put(&url).json(&json!({})).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let updated = WireguardNetwork::find_by_id(&client_state.pool, network_id)
+        .await
+        .unwrap()
+        .unwrap();
+    let peers = updated.get_peers(&client_state.pool).await.unwrap();
+    assert_eq!(peers[0].upload_limit_kbps, Some(1000));
+    assert_eq!(peers[0].download_limit_kbps, Some(10_000));
+}
+
+#[tokio::test]
+async fn test_gateway_resync() {
+    let (client, client_state) = make_test_client().await;
+    let mut wg_rx = client_state.wireguard_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post("/api/v1/network")
+        .json(&make_network())
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: WireguardNetwork = response.json().await;
+    let network_id = network.id.unwrap();
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkCreated(..));
+    for (username, pubkey) in [
+        ("admin", "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU="),
+        ("hpotter", "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38="),
+    ] {
+        let response = client
+            .post(format!("/api/v1/device/{username}"))
+            .json(&json!({"name": username, "wireguard_pubkey": pubkey}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(..));
+    }
+
+    // complete peer set is sent
+    let url = format!("/api/v1/network/{network_id}/gateways/resync");
+    let response = client.post(&url).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let resync: Value = response.json().await;
+    assert_eq!(resync["peers"], 2);
+    assert_eq!(resync["gateways"], json!([]));
+    let GatewayEvent::FullResync(id, _, peers) = wg_rx.try_recv().unwrap() else {
+        panic!("Expected full resync event")
+    };
+    assert_eq!(id, network_id);
+    assert_eq!(peers, network.get_peers(&client_state.pool).await.unwrap());
+    assert_eq!(peers.len(), 2);
+    assert!(wg_rx.try_recv().is_err());
+
+    // repeated resync is rate limited
+    let response = client.post(&url).send().await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(wg_rx.try_recv().is_err());
+
+    let response = client
+        .post("/api/v1/network/12345/gateways/resync")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // only admins can resync
+    let response = client.post("/api/v1/auth/logout").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post(&url).send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(wg_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_network_overlaps() {
+    let (client, _) = make_test_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post("/api/v1/network")
+        .json(&make_network())
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // same subnet
+    let mut network = make_network();
+    network["name"] = json!("copy");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let body: Value = response.json().await;
+    assert_eq!(body["overlaps"][0]["other_network"], "network");
+    assert_eq!(body["overlaps"][0]["other_range"], "10.1.1.0/24");
+
+    // partial overlap through allowed IPs
+    network["address"] = json!("10.2.2.1/24");
+    network["allowed_ips"] = json!("10.0.0.0/8");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // disjoint ranges
+    network["allowed_ips"] = json!("10.2.2.0/24");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let copy: WireguardNetwork = response.json().await;
+    let copy_id = copy.id.unwrap();
+
+    // modifying a network is checked as well
+    network["address"] = json!("10.1.1.1/25");
+    let response = client
+        .put(format!("/api/v1/network/{copy_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // overlap explicitly allowed
+    let response = client
+        .put(format!("/api/v1/network/{copy_id}?allow_overlap=true"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // IPv6 networks
+    let mut v6 = make_network();
+    v6["name"] = json!("v6");
+    v6["address"] = json!("fd00::1/64");
+    v6["allowed_ips"] = json!("fd00::/48");
+    let response = client.post("/api/v1/network").json(&v6).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    v6["name"] = json!("other v6");
+    v6["address"] = json!("fd00:0:0:1::1/64");
+    v6["allowed_ips"] = json!("");
+    let response = client.post("/api/v1/network").json(&v6).send().await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = client.get("/api/v1/network/overlaps").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let overlaps: Vec<NetworkOverlap> = response.json().await;
+    assert_eq!(overlaps.len(), 1);
+    assert_eq!(overlaps[0].network, "network");
+    assert_eq!(overlaps[0].other_network, "copy");
+    assert_eq!(
+        overlaps[0].range,
+        "10.1.1.0/24".parse::<IpNetwork>().unwrap()
+    );
+    assert_eq!(
+        overlaps[0].other_range,
+        "10.1.1.0/25".parse::<IpNetwork>().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_device_dns_names() {
+    let (client, _) = make_test_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut network = make_network();
+    network["dns_zone"] = json!("office..example.com");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    network["dns_zone"] = json!("Office.VPN.example.com.");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: Value = response.json().await;
+    assert_eq!(network["dns_zone"], "office.vpn.example.com");
+
+    // RFC 2136 updates have to be signed
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"dns_provider": "rfc2136", "dns_server": "127.0.0.1"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({
+            "dns_provider": "rfc2136",
+            "dns_server": "127.0.0.1",
+            "dns_tsig_key_name": "defguard",
+            "dns_tsig_secret": "c2VjcmV0IGtleQ=="
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({
+            "name": "Anna's NAS",
+            "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU="
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    let device_id = device["device"]["id"].as_i64().unwrap();
+
+    // names which would get the same record are rejected
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&json!({
+            "name": "anna-s-nas",
+            "wireguard_pubkey": "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38="
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&json!({
+            "name": "???",
+            "wireguard_pubkey": "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38="
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // device details include DNS status, records are published in the background
+    let response = client
+        .get(format!("/api/v1/device/{device_id}"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let device: Value = response.json().await;
+    assert_eq!(device["name"], "Anna's NAS");
+    assert!(device["dns_status"].is_array());
+}
+
+#[tokio::test]
+async fn test_device_platform_filters() {
+    let (client, client_state) = make_test_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post("/api/v1/network")
+        .json(&make_network())
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let devices = [
+        (
+            "old-laptop",
+            "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+            Some(("Windows", "0.9.2")),
+        ),
+        (
+            "new-laptop",
+            "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=",
+            Some(("windows", "1.0.0")),
+        ),
+        (
+            "macbook",
+            "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=",
+            Some(("macos", "0.8.0")),
+        ),
+        (
+            "router",
+            "hNuapt7lOxF93KUqZGUY00oKJxH8LYwwsUVB1uUa0y4=",
+            None,
+        ),
+    ];
+    for (name, pubkey, platform) in devices {
+        let response = client
+            .post("/api/v1/device/admin")
+            .json(&json!({"name": name, "wireguard_pubkey": pubkey}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let device: Value = response.json().await;
+        assert_eq!(device["device"]["os"], Value::Null);
+        if let Some((os, client_version)) = platform {
+            let mut device = Device::find_by_pubkey(&client_state.pool, pubkey)
+                .await
+                .unwrap()
+                .unwrap();
+            let platform = DevicePlatform::new(Some(os.into()), None, Some(client_version.into()));
+            assert!(device
+                .update_platform(&client_state.pool, platform)
+                .await
+                .unwrap());
+        }
+    }
+
+    let names = |devices: Vec<Value>| {
+        let mut names: Vec<String> = devices
+            .iter()
+            .map(|device| device["name"].as_str().unwrap().to_string())
+            .collect();
+        names.sort();
+        names
+    };
+    let response = client.get("/api/v1/device").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.json::<Vec<Value>>().await.len(), 4);
+
+    let response = client.get("/api/v1/device?os=windows").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(names(response.json().await), ["new-laptop", "old-laptop"]);
+
+    let response = client
+        .get("/api/v1/device?client_version_lt=1.0")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(names(response.json().await), ["macbook", "old-laptop"]);
+
+    let response = client
+        .get("/api/v1/device?os=windows&client_version_lt=1.0")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let devices: Vec<Value> = response.json().await;
+    assert_eq!(names(devices.clone()), ["old-laptop"]);
+    assert_eq!(devices[0]["os"], "windows");
+    assert_eq!(devices[0]["client_version"], "0.9.2");
+}
+
+#[tokio::test]
+async fn test_device_likely_duplicates() {
+    let (client, client_state) = make_test_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post("/api/v1/network")
+        .json(&make_network())
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // two enrollments of the same laptop, another machine, and a device of another user
+    // reporting the same machine ID
+    let devices = [
+        (
+            "admin",
+            "laptop",
+            "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+            Some(("4c4c4544-0051", "admin-laptop")),
+        ),
+        (
+            "admin",
+            "laptop-reinstalled",
+            "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=",
+            Some(("4c4c4544-0051", "ubuntu")),
+        ),
+        (
+            "admin",
+            "desktop",
+            "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=",
+            Some(("8d1a7e2c-0002", "admin-desktop")),
+        ),
+        (
+            "hpotter",
+            "hp-laptop",
+            "hNuapt7lOxF93KUqZGUY00oKJxH8LYwwsUVB1uUa0y4=",
+            Some(("4c4c4544-0051", "admin-laptop")),
+        ),
+        (
+            "admin",
+            "router",
+            "o/8q3kmv5nnbrcb/7aceQWGE44a0yI707wObXRyyWGU=",
+            None,
+        ),
+    ];
+    let mut ids = Vec::new();
+    for (username, name, pubkey, hints) in devices {
+        let response = client
+            .post(format!("/api/v1/device/{username}"))
+            .json(&json!({"name": name, "wireguard_pubkey": pubkey}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let mut device = Device::find_by_pubkey(&client_state.pool, pubkey)
+            .await
+            .unwrap()
+            .unwrap();
+        if let Some((machine_id, hostname)) = hints {
+            device.set_machine_hints(MachineHints::new(
+                device.user_id,
+                Some(machine_id.into()),
+                Some(hostname.into()),
+            ));
+            device.save(&client_state.pool).await.unwrap();
+        }
+        ids.push(device.id.unwrap());
+    }
+
+    let response = client.get("/api/v1/device").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let devices: Vec<Value> = response.json().await;
+    assert_eq!(devices.len(), 5);
+    let duplicates = |id: i64| {
+        devices.iter().find(|device| device["id"] == id).unwrap()["likely_duplicates"].clone()
+    };
+    assert_eq!(duplicates(ids[0]), json!([ids[1]]));
+    assert_eq!(duplicates(ids[1]), json!([ids[0]]));
+    for id in &ids[2..] {
+        assert_eq!(duplicates(*id), json!([]));
+    }
+    // machine hash isn't exposed
+    assert!(devices
+        .iter()
+        .all(|device| device.get("machine_hash").is_none()));
+    assert_eq!(
+        devices
+            .iter()
+            .find(|device| device["id"] == ids[1])
+            .unwrap()["hostname"],
+        "ubuntu"
+    );
+}
+
+#[tokio::test]
+async fn test_network_allowed_platforms() {
+    let (client, client_state) = make_test_client().await;
+    let mut wg_rx = client_state.wireguard_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut network = make_network();
+    network["allowed_platforms"] = json!([""]);
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    network["allowed_platforms"] = json!(["Linux", " windows ", "linux"]);
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: WireguardNetwork = response.json().await;
+    assert_eq!(created.allowed_platforms, ["linux", "windows"]);
+    assert!(!created.deny_unknown_platform);
+    let network_id = created.id.unwrap();
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkCreated(..));
+
+    // policy is shown in location details
+    let response = client
+        .get(format!("/api/v1/network/{network_id}"))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let details: Value = response.json().await;
+    assert_eq!(details["allowed_platforms"], json!(["linux", "windows"]));
+    assert_eq!(details["deny_unknown_platform"], false);
+
+    // devices which haven't reported their platform are allowed by default
+    let pubkey = "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=";
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({"name": "phone", "wireguard_pubkey": pubkey}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    assert_eq!(device["configs"].as_array().unwrap().len(), 1);
+    let device_id = device["device"]["id"].as_i64().unwrap();
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(..));
+
+    // devices reporting disallowed platform are removed from the location
+    let mut device = Device::find_by_id(&client_state.pool, device_id)
+        .await
+        .unwrap()
+        .unwrap();
+    let platform = DevicePlatform::new(Some("Android".into()), None, None);
+    assert!(device
+        .update_platform(&client_state.pool, platform)
+        .await
+        .unwrap());
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let GatewayEvent::PeerRemoved(peer) = wg_rx.try_recv().unwrap() else {
+        panic!("Expected peer removed event")
+    };
+    assert_eq!(peer.device.id, Some(device_id));
+    assert!(
+        WireguardNetworkDevice::find(&client_state.pool, device_id, network_id)
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    // unknown platforms can be denied as well
+    network["deny_unknown_platform"] = json!(true);
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({
+            "name": "laptop",
+            "wireguard_pubkey": "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=",
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    assert!(device["configs"].as_array().unwrap().is_empty());
+
+    // explicit assignment of a disallowed device is refused, naming the policy
+    let mapped_device = json!({"devices": [{
+        "user_id": 1,
+        "name": "imported",
+        "wireguard_pubkey": "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=",
+        "wireguard_ip": "10.1.1.10",
+    }]});
+    let response = client
+        .post(format!("/api/v1/network/{network_id}/devices"))
+        .json(&mapped_device)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let error: Value = response.json().await;
+    assert_eq!(error["policy"], "allowed_platforms");
+    assert!(error["msg"]
+        .as_str()
+        .unwrap()
+        .contains("allowed platforms: linux, windows"));
+    let response = client.get("/api/v1/device").send().await;
+    assert_eq!(response.json::<Vec<Value>>().await.len(), 2);
+
+    network["deny_unknown_platform"] = json!(false);
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post(format!("/api/v1/network/{network_id}/devices"))
+        .json(&mapped_device)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn test_device_limit() {
+    let (client, _) = make_test_client().await;
+
+    let admin_auth = Auth::new("admin", "pass123");
+    let user_auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&admin_auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post("/api/v1/network")
+        .json(&make_network())
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"max_devices_per_user": -1}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"max_devices_per_user": 1}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let device = |name: &str, pubkey: &str| json!({"name": name, "wireguard_pubkey": pubkey});
+
+    // user reaches the limit
+    let response = client.post("/api/v1/auth").json(&user_auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/me").send().await;
+    assert_eq!(response.json::<Value>().await["device_quota"], 1);
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&device(
+            "laptop",
+            "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client.get("/api/v1/me").send().await;
+    assert_eq!(response.json::<Value>().await["device_quota"], 0);
+
+    let phone = device("phone", "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=");
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&phone)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let error: Value = response.json().await;
+    assert_eq!(error["count"], 1);
+    assert_eq!(error["limit"], 1);
+
+    // only admins can override the limit
+    let response = client
+        .post("/api/v1/device/hpotter?override_limit=true")
+        .json(&phone)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = client.post("/api/v1/auth").json(&admin_auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&phone)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = client
+        .post("/api/v1/device/hpotter?override_limit=true")
+        .json(&phone)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // quota doesn't go below zero
+    let user_details = fetch_user_details(&client, "hpotter").await;
+    assert_eq!(user_details.devices.len(), 2);
+    assert_eq!(user_details.user.device_quota, Some(0));
+
+    // per-user override takes precedence over the global limit
+    let mut user_details = fetch_user_details(&client, "hpotter").await;
+    user_details.user.max_devices = Some(-1);
+    let response = client
+        .put("/api/v1/user/hpotter")
+        .json(&user_details.user)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    user_details.user.max_devices = Some(3);
+    let response = client
+        .put("/api/v1/user/hpotter")
+        .json(&user_details.user)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        fetch_user_details(&client, "hpotter")
+            .await
+            .user
+            .device_quota,
+        Some(1)
+    );
+
+    // users can't change their own limit
+    let response = client.post("/api/v1/auth").json(&user_auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut user_info: Value = client.get("/api/v1/me").send().await.json().await;
+    user_info["max_devices"] = json!(10);
+    let response = client
+        .put("/api/v1/user/hpotter")
+        .json(&user_info)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let user_info: Value = client.get("/api/v1/me").send().await.json().await;
+    assert_eq!(user_info["max_devices"], 3);
+
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&device(
+            "tablet",
+            "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=",
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&device(
+            "router",
+            "hNuapt7lOxF93KUqZGUY00oKJxH8LYwwsUVB1uUa0y4=",
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let error: Value = response.json().await;
+    assert_eq!(error["count"], 3);
+    assert_eq!(error["limit"], 3);
+}
+
+#[tokio::test]
+async fn test_network_allowed_ips_normalization() {
+    let (client, _) = make_test_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // every invalid entry is reported
+    let mut network = make_network();
+    network["allowed_ips"] = json!("10.1.1.0/24, 10.1.300.0/24,, gateway");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let error: Value = response.json().await;
+    let entries: Vec<&str> = error["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["entry"].as_str().unwrap())
+        .collect();
+    assert_eq!(entries, ["10.1.300.0/24", "gateway"]);
+
+    // duplicates and host bits are removed
+    network["allowed_ips"] = json!("10.1.1.0/24, 10.1.1.0/24, 192.168.1.7/24, fd00::1/64,");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: Value = response.json().await;
+    assert_eq!(
+        created["allowed_ips"],
+        json!(["10.1.1.0/24", "192.168.1.0/24", "fd00::/64"])
+    );
+    let network_id = created["id"].as_i64().unwrap();
+
+    // ranges contained in other entries are collapsed
+    network["allowed_ips"] = json!("10.1.1.0/24, 10.1.2.0/24, 10.1.0.0/16, 10.1.2.7/32");
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .get(format!("/api/v1/network/{network_id}"))
+        .send()
+        .await;
+    let stored: Value = response.json().await;
+    assert_eq!(stored["allowed_ips"], json!(["10.1.0.0/16"]));
+
+    network["allowed_ips"] = json!("10.1.0.0/16, invalid");
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // client configs get the canonical list
+    let device = json!({
+        "name": "phone",
+        "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+    });
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&device)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    let device_id = device["device"]["id"].as_i64().unwrap();
+    let response = client
+        .get(format!(
+            "/api/v1/network/{network_id}/device/{device_id}/config"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.contains("AllowedIPs = 10.1.0.0/16\n"));
+}
+
+#[tokio::test]
+async fn test_network_client_routes() {
+    let (client, client_state) = make_test_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut network = make_network();
+    network["client_routes"] = json!("172.16.5.7/24, vpn");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // routes are normalized like allowed IPs
+    network["client_routes"] = json!("172.16.5.7/24, 172.16.5.0/24, 10.1.1.0/24,");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: Value = response.json().await;
+    assert_eq!(
+        created["client_routes"],
+        json!(["172.16.5.0/24", "10.1.1.0/24"])
+    );
+    let network_id = created["id"].as_i64().unwrap();
+
+    // overlap with network address is accepted with a warning
+    let warnings = created["warnings"].as_array().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].as_str().unwrap().contains("10.1.1.0/24"));
+    network["client_routes"] = json!("172.16.5.0/24, fd00:5::/64");
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let modified: Value = response.json().await;
+    assert!(modified.get("warnings").is_none());
+
+    // client configs route allowed IPs and client routes
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&json!({"name": "phone", "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU="}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device: Value = response.json().await;
+    let device_id = device["device"]["id"].as_i64().unwrap();
+    let response = client
+        .get(format!(
+            "/api/v1/network/{network_id}/device/{device_id}/config"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response
+        .text()
+        .await
+        .contains("AllowedIPs = 10.1.1.0/24,172.16.5.0/24,fd00:5::/64\n"));
+
+    // gateways only get device addresses
+    let network = WireguardNetwork::find_by_id(&client_state.pool, network_id)
+        .await
+        .unwrap()
+        .unwrap();
+    let peers = network.get_peers(&client_state.pool).await.unwrap();
+    let peer = peers
+        .iter()
+        .find(|peer| peer.pubkey == "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=")
+        .unwrap();
+    assert_eq!(peer.allowed_ips.len(), 1);
+    assert!(peer.allowed_ips[0].starts_with("10.1.1."));
+}
+
+#[tokio::test]
+async fn test_network_nat() {
+    let (client, client_state) = make_test_client().await;
+    let mut wg_rx = client_state.wireguard_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // exempt networks are validated and only make sense with masquerade
+    let mut network = make_network();
+    network["masquerade_enabled"] = json!(true);
+    network["nat_exempt_networks"] = json!("10.20.0.0/16, datacenter");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    network["masquerade_enabled"] = json!(false);
+    network["nat_exempt_networks"] = json!("10.20.0.0/16");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    network["masquerade_enabled"] = json!(true);
+    network["nat_exempt_networks"] = json!("10.20.1.1/16, fd00:20::/64,");
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: Value = response.json().await;
+    assert_eq!(created["masquerade_enabled"], true);
+    assert_eq!(
+        created["nat_exempt_networks"],
+        json!(["10.20.0.0/16", "fd00:20::/64"])
+    );
+    let network_id = created["id"].as_i64().unwrap();
+    let event = wg_rx.try_recv().unwrap();
+    assert_matches!(event, GatewayEvent::NetworkCreated(..));
+
+    // disabling masquerade is pushed to gateways
+    network["masquerade_enabled"] = json!(false);
+    network["nat_exempt_networks"] = json!(null);
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let event = wg_rx.try_recv().unwrap();
+    let GatewayEvent::NetworkModified(_, modified, _) = event else {
+        panic!("Expected network modification, got {event:?}");
+    };
+    assert!(!modified.masquerade_enabled);
+    assert!(modified.nat_exempt_networks.is_empty());
+
+    // unchanged NAT settings don't resync gateways
+    let response = client
+        .put(format!("/api/v1/network/{network_id}"))
+        .json(&network)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(wg_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_device_name_policy() {
+    let (client, _) = make_test_client().await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/network")
+        .json(&make_network())
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let device = |name: &str, pubkey: &str| json!({"name": name, "wireguard_pubkey": pubkey});
+    let config_file_name = |device_id: i64| {
+        let client = &client;
+        async move {
+            let response = client
+                .get(format!("/api/v1/network/1/device/{device_id}/config"))
+                .send()
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            response.headers()["content-disposition"]
+                .to_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+
+    // names are taken as they are without a policy
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&device(
+            "Laptop 💻\n",
+            "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let legacy: Value = response.json().await;
+    assert_eq!(legacy["device"]["name"], "Laptop 💻\n");
+    let legacy_id = legacy["device"]["id"].as_i64().unwrap();
+    assert_eq!(
+        config_file_name(legacy_id).await,
+        "attachment; filename=\"Laptop_network.conf\""
+    );
+
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"device_name_max_length": 0}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"device_name_policy": "reject", "device_name_max_length": 12}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // invalid names are rejected
+    for name in ["  ", "phone 📱", "phone\nphone", "a long phone name"] {
+        let response = client
+            .post("/api/v1/device/hpotter")
+            .json(&device(
+                name,
+                "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=",
+            ))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{name:?}");
+    }
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&device(
+            " tablet ",
+            "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=",
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let tablet: Value = response.json().await;
+    assert_eq!(tablet["device"]["name"], "tablet");
+
+    // names are unique per user, a free name is suggested
+    let response = client
+        .post("/api/v1/device/hpotter")
+        .json(&device(
+            "Tablet",
+            "gQYL5eMeFDj0R+lpC7oZyIl0/sNVmQDC6ckP7husZjc=",
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let error: Value = response.json().await;
+    assert_eq!(error["suggestion"], "Tablet-2");
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&device(
+            "tablet",
+            "gQYL5eMeFDj0R+lpC7oZyIl0/sNVmQDC6ckP7husZjc=",
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // legacy names are kept unless changed, and exported under the same name
+    let response = client
+        .put(format!("/api/v1/device/{legacy_id}"))
+        .json(&device(
+            "Laptop 💻\n",
+            "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=",
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        config_file_name(legacy_id).await,
+        "attachment; filename=\"Laptop_network.conf\""
+    );
+    let response = client
+        .put(format!("/api/v1/device/{legacy_id}"))
+        .json(&device(
+            "TABLET",
+            "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=",
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let error: Value = response.json().await;
+    assert_eq!(error["suggestion"], "TABLET-2");
+    let response = client
+        .put(format!("/api/v1/device/{legacy_id}"))
+        .json(&device(
+            "laptop 💻",
+            "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=",
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // unsafe characters are replaced when sanitizing
+    let response = client
+        .patch("/api/v1/settings")
+        .json(&json!({"device_name_policy": "sanitize"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .put(format!("/api/v1/device/{legacy_id}"))
+        .json(&device(
+            "Harry's  laptop 💻",
+            "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=",
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let renamed: Value = response.json().await;
+    assert_eq!(renamed["name"], "Harry-s lapt");
+    assert_eq!(
+        config_file_name(legacy_id).await,
+        "attachment; filename=\"Harry-s_lapt_network.conf\""
+    );
+}
@@ -2,12 +2,17 @@ mod common;
 
 use claims::assert_err;
 use defguard::{
-    db::{DbPool, Device, GatewayEvent, Group, User, WireguardNetwork},
+    db::{
+        models::device::EffectiveDeviceConfig, DbPool, Device, GatewayEvent, Group, User,
+        WireguardNetwork,
+    },
     handlers::{wireguard::ImportedNetworkData, Auth},
 };
+use ipnetwork::IpNetwork;
 use matches::assert_matches;
 use reqwest::StatusCode;
 use serde_json::json;
+use tokio::sync::broadcast::Receiver;
 
 use self::common::{fetch_user_details, make_test_client};
 
@@ -96,6 +101,22 @@ async fn setup_test_users(pool: &DbPool) -> (Vec<User>, Vec<Device>) {
     (users, devices)
 }
 
+// collect IDs of devices from pending peer events, returns (added, removed)
+fn pending_peer_changes(wg_rx: &mut Receiver<GatewayEvent>) -> (Vec<i64>, Vec<i64>) {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    while let Ok(event) = wg_rx.try_recv() {
+        match event {
+            GatewayEvent::PeerAdded(peer) => added.push(peer.device.id.unwrap()),
+            GatewayEvent::PeerRemoved(peer) => removed.push(peer.device.id.unwrap()),
+            event => panic!("Unexpected event {event:?}"),
+        }
+    }
+    added.sort_unstable();
+    removed.sort_unstable();
+    (added, removed)
+}
+
 #[tokio::test]
 async fn test_create_new_network() {
     let (client, client_state) = make_test_client().await;
@@ -138,6 +159,58 @@ async fn test_create_new_network() {
     assert_eq!(peers[1].pubkey, devices[1].wireguard_pubkey);
 }
 
+#[tokio::test]
+async fn test_allowed_subgroup() {
+    let (client, client_state) = make_test_client().await;
+    let (_users, devices) = setup_test_users(&client_state.pool).await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // move the other group under the allowed one
+    let response = client
+        .put("/api/v1/group/not allowed group")
+        .json(
+            &json!({"name": "not allowed group", "members": ["ssnape"], "parent": "allowed group"}),
+        )
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // cycles are rejected
+    let response = client
+        .put("/api/v1/group/allowed group")
+        .json(&json!({"name": "allowed group", "members": ["hpotter"], "parent": "not allowed group"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": "network",
+            "address": "10.1.1.1/24",
+            "port": 55555,
+            "endpoint": "192.168.4.14",
+            "allowed_ips": "10.1.1.0/24",
+            "dns": "1.1.1.1",
+            "allowed_groups": ["allowed group"],
+            "mfa_enabled": false,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: WireguardNetwork = response.json().await;
+
+    // members of the subgroup are allowed too
+    let peers = network.get_peers(&client_state.pool).await.unwrap();
+    assert_eq!(peers.len(), 3);
+    assert_eq!(peers[2].pubkey, devices[2].wireguard_pubkey);
+}
+
 #[tokio::test]
 async fn test_modify_network() {
     let (client, client_state) = make_test_client().await;
@@ -198,7 +271,10 @@ async fn test_modify_network() {
         .send()
         .await;
     assert_eq!(response.status(), StatusCode::OK);
-    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkModified(..));
+    assert_eq!(
+        pending_peer_changes(&mut wg_rx),
+        (vec![], vec![devices[2].id.unwrap(), devices[3].id.unwrap()])
+    );
 
     let new_peers = network.get_peers(&client_state.pool).await.unwrap();
     assert_eq!(new_peers.len(), 2);
@@ -223,7 +299,10 @@ async fn test_modify_network() {
         .send()
         .await;
     assert_eq!(response.status(), StatusCode::OK);
-    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkModified(..));
+    assert_eq!(
+        pending_peer_changes(&mut wg_rx),
+        (vec![devices[2].id.unwrap()], vec![])
+    );
 
     let new_peers = network.get_peers(&client_state.pool).await.unwrap();
     assert_eq!(new_peers.len(), 3);
@@ -249,7 +328,10 @@ async fn test_modify_network() {
         .send()
         .await;
     assert_eq!(response.status(), StatusCode::OK);
-    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkModified(..));
+    assert_eq!(
+        pending_peer_changes(&mut wg_rx),
+        (vec![], vec![devices[1].id.unwrap()])
+    );
 
     let new_peers = network.get_peers(&client_state.pool).await.unwrap();
     assert_eq!(new_peers.len(), 2);
@@ -274,7 +356,10 @@ async fn test_modify_network() {
         .send()
         .await;
     assert_eq!(response.status(), StatusCode::OK);
-    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::NetworkModified(..));
+    assert_eq!(
+        pending_peer_changes(&mut wg_rx),
+        (vec![devices[1].id.unwrap(), devices[3].id.unwrap()], vec![])
+    );
 
     let new_peers = network.get_peers(&client_state.pool).await.unwrap();
     assert_eq!(new_peers.len(), 4);
@@ -350,25 +435,23 @@ async fn test_import_network_existing_devices() {
     assert_matches!(event, GatewayEvent::NetworkCreated(..));
 
     // network config was only created for one of the existing devices and the admin device
-    let GatewayEvent::DeviceModified(device_info) = wg_rx.try_recv().unwrap() else {
+    let GatewayEvent::PeerAdded(peer) = wg_rx.try_recv().unwrap() else {
         panic!()
     };
-    assert_eq!(device_info.device.id.unwrap(), devices[1].id.unwrap());
-    assert_eq!(device_info.network_info.len(), 1);
-    assert_eq!(device_info.network_info[0].network_id, 1);
+    assert_eq!(peer.device.id.unwrap(), devices[1].id.unwrap());
+    assert_eq!(peer.network_id(), 1);
     assert_eq!(
-        device_info.network_info[0].device_wireguard_ip.to_string(),
+        peer.network_info.device_wireguard_ip.to_string(),
         peers[1].allowed_ips[0]
     );
 
-    let GatewayEvent::DeviceCreated(device_info) = wg_rx.try_recv().unwrap() else {
+    let GatewayEvent::PeerAdded(peer) = wg_rx.try_recv().unwrap() else {
         panic!()
     };
-    assert_eq!(device_info.device.id.unwrap(), devices[0].id.unwrap());
-    assert_eq!(device_info.network_info.len(), 1);
-    assert_eq!(device_info.network_info[0].network_id, 1);
+    assert_eq!(peer.device.id.unwrap(), devices[0].id.unwrap());
+    assert_eq!(peer.network_id(), 1);
     assert_eq!(
-        device_info.network_info[0].device_wireguard_ip.to_string(),
+        peer.network_info.device_wireguard_ip.to_string(),
         peers[0].allowed_ips[0]
     );
 
@@ -451,31 +534,29 @@ PersistentKeepalive = 300
     assert_eq!(peers[3].pubkey, mapped_devices[1].wireguard_pubkey);
 
     // assert events
-    let GatewayEvent::DeviceCreated(device_info) = wg_rx.try_recv().unwrap() else {
+    let GatewayEvent::PeerAdded(peer) = wg_rx.try_recv().unwrap() else {
         panic!()
     };
     assert_eq!(
-        device_info.device.wireguard_pubkey,
+        peer.device.wireguard_pubkey,
         mapped_devices[0].wireguard_pubkey
     );
-    assert_eq!(device_info.network_info.len(), 1);
-    assert_eq!(device_info.network_info[0].network_id, 1);
+    assert_eq!(peer.network_id(), 1);
     assert_eq!(
-        device_info.network_info[0].device_wireguard_ip,
+        peer.network_info.device_wireguard_ip,
         mapped_devices[0].wireguard_ip
     );
 
-    let GatewayEvent::DeviceCreated(device_info) = wg_rx.try_recv().unwrap() else {
+    let GatewayEvent::PeerAdded(peer) = wg_rx.try_recv().unwrap() else {
         panic!()
     };
     assert_eq!(
-        device_info.device.wireguard_pubkey,
+        peer.device.wireguard_pubkey,
         mapped_devices[1].wireguard_pubkey
     );
-    assert_eq!(device_info.network_info.len(), 1);
-    assert_eq!(device_info.network_info[0].network_id, 1);
+    assert_eq!(peer.network_id(), 1);
     assert_eq!(
-        device_info.network_info[0].device_wireguard_ip,
+        peer.network_info.device_wireguard_ip,
         mapped_devices[1].wireguard_ip
     );
 
@@ -535,7 +616,7 @@ async fn test_modify_user() {
     assert_eq!(response.status(), StatusCode::OK);
 
     let event = wg_rx.try_recv().unwrap();
-    assert_matches!(event, GatewayEvent::DeviceDeleted(..));
+    assert_matches!(event, GatewayEvent::PeerRemoved(..));
     assert_err!(wg_rx.try_recv());
 
     let peers = network.get_peers(&client_state.pool).await.unwrap();
@@ -569,7 +650,7 @@ async fn test_modify_user() {
     assert_eq!(response.status(), StatusCode::OK);
 
     let event = wg_rx.try_recv().unwrap();
-    assert_matches!(event, GatewayEvent::DeviceCreated(..));
+    assert_matches!(event, GatewayEvent::PeerAdded(..));
     assert_err!(wg_rx.try_recv());
 
     let peers = network.get_peers(&client_state.pool).await.unwrap();
@@ -629,3 +710,221 @@ async fn test_delete_only_allowed_group() {
     assert_eq!(peers[2].pubkey, devices[2].wireguard_pubkey);
     assert_eq!(peers[3].pubkey, devices[3].wireguard_pubkey);
 }
+
+fn transfer_url(device: &Device) -> String {
+    format!("/api/v1/device/{}/transfer", device.id.unwrap())
+}
+
+#[tokio::test]
+async fn test_transfer_device() {
+    let (client, client_state) = make_test_client().await;
+    let (users, devices) = setup_test_users(&client_state.pool).await;
+
+    let mut wg_rx = client_state.wireguard_rx;
+    let mut mail_rx = client_state.mail_rx;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // create network
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": "network",
+            "address": "10.1.1.1/24",
+            "port": 55555,
+            "endpoint": "192.168.4.14",
+            "allowed_ips": "10.1.1.0/24",
+            "dns": "1.1.1.1",
+            "allowed_groups": ["allowed group"],
+            "mfa_enabled": false,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: WireguardNetwork = response.json().await;
+    let event = wg_rx.try_recv().unwrap();
+    assert_matches!(event, GatewayEvent::NetworkCreated(..));
+    let peers = network.get_peers(&client_state.pool).await.unwrap();
+    assert_eq!(peers.len(), 2);
+
+    // transfer to the current owner
+    let response = client
+        .post(transfer_url(&devices[1]))
+        .json(&json!({"username": "hpotter"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // transfer to non-existing user
+    let response = client
+        .post(transfer_url(&devices[1]))
+        .json(&json!({"username": "voldemort"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(pending_peer_changes(&mut wg_rx), (Vec::new(), Vec::new()));
+
+    // both owners are allowed, device keeps its IP
+    let response = client
+        .post(transfer_url(&devices[0]))
+        .json(&json!({"username": "hpotter"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let device: Device = response.json().await;
+    assert_eq!(device.user_id, users[1].id.unwrap());
+    assert_eq!(pending_peer_changes(&mut wg_rx), (Vec::new(), Vec::new()));
+    let transferred_peers = network.get_peers(&client_state.pool).await.unwrap();
+    assert_eq!(transferred_peers.len(), 2);
+    assert_eq!(transferred_peers[0].pubkey, peers[0].pubkey);
+    assert_eq!(transferred_peers[0].allowed_ips, peers[0].allowed_ips);
+
+    // previous and new owner are notified
+    let mail = mail_rx.try_recv().unwrap();
+    assert_eq!(mail.to, users[0].email);
+    assert!(mail.content.contains("transferred from your account"));
+    let mail = mail_rx.try_recv().unwrap();
+    assert_eq!(mail.to, users[1].email);
+    assert!(mail.content.contains("transferred to your account"));
+
+    // new owner is not in allowed group
+    let response = client
+        .post(transfer_url(&devices[1]))
+        .json(&json!({"username": "ssnape"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        pending_peer_changes(&mut wg_rx),
+        (Vec::new(), vec![devices[1].id.unwrap()])
+    );
+    let peers = network.get_peers(&client_state.pool).await.unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].pubkey, devices[0].wireguard_pubkey);
+    assert_eq!(mail_rx.try_recv().unwrap().to, users[1].email);
+    assert_eq!(mail_rx.try_recv().unwrap().to, users[2].email);
+
+    // device of a not allowed user is transferred to an allowed one
+    let response = client
+        .post(transfer_url(&devices[3]))
+        .json(&json!({"username": "hpotter"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        pending_peer_changes(&mut wg_rx),
+        (vec![devices[3].id.unwrap()], Vec::new())
+    );
+    let peers = network.get_peers(&client_state.pool).await.unwrap();
+    assert_eq!(peers.len(), 2);
+    assert_eq!(peers[1].pubkey, devices[3].wireguard_pubkey);
+
+    // only admins can transfer devices
+    let auth = Auth::new("hpotter", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post(transfer_url(&devices[3]))
+        .json(&json!({"username": "dobby"}))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_device_effective_config() {
+    let (client, client_state) = make_test_client().await;
+    let (_users, devices) = setup_test_users(&client_state.pool).await;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post("/api/v1/network")
+        .json(&json!({
+            "name": "network",
+            "address": "10.1.1.1/24",
+            "port": 55555,
+            "endpoint": "192.168.4.14",
+            "allowed_ips": "10.1.1.0/24, 10.2.0.0/16",
+            "dns": "1.1.1.1",
+            "allowed_groups": ["allowed group"],
+            "mfa_enabled": false,
+            "keepalive_interval": 25,
+            "peer_disconnect_threshold": 180
+        }))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let network: WireguardNetwork = response.json().await;
+    let network_id = network.id.unwrap();
+
+    // device of a user in allowed group
+    let device_id = devices[1].id.unwrap();
+    let response = client
+        .get(format!(
+            "/api/v1/device/{device_id}/effective_config?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let config: EffectiveDeviceConfig = response.json().await;
+    assert!(config.allowed);
+    assert!(config.gateway_peer);
+    let settings = config.network_settings;
+    let address = settings.address.unwrap();
+    assert!(network.address.contains(address));
+    assert_eq!(settings.gateway_allowed_ips, [IpNetwork::from(address)]);
+    assert_eq!(
+        settings.allowed_ips,
+        [
+            "10.1.1.0/24".parse::<IpNetwork>().unwrap(),
+            "10.2.0.0/16".parse().unwrap()
+        ]
+    );
+    assert_eq!(settings.endpoint, "192.168.4.14:55555");
+    assert_eq!(settings.dns.as_deref(), Some("1.1.1.1"));
+    assert_eq!(settings.gateway_keepalive, 25);
+    assert!(!settings.mfa_required);
+
+    // device of a user outside allowed groups
+    let device_id = devices[2].id.unwrap();
+    let response = client
+        .get(format!(
+            "/api/v1/device/{device_id}/effective_config?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let config: EffectiveDeviceConfig = response.json().await;
+    assert!(!config.allowed);
+    assert!(!config.gateway_peer);
+    assert!(config.network_settings.address.is_none());
+    assert!(config.network_settings.gateway_allowed_ips.is_empty());
+
+    // device owner can check their own device only
+    let auth = Auth::new("hpotter", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let device_id = devices[1].id.unwrap();
+    let response = client
+        .get(format!(
+            "/api/v1/device/{device_id}/effective_config?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let device_id = devices[2].id.unwrap();
+    let response = client
+        .get(format!(
+            "/api/v1/device/{device_id}/effective_config?network_id={network_id}"
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -12,7 +12,7 @@ use defguard::{
 };
 use matches::assert_matches;
 use reqwest::StatusCode;
-use serde_json::json;
+use serde_json::{json, Value};
 use tokio::sync::broadcast::error::TryRecvError;
 
 use self::common::{fetch_user_details, make_test_client};
@@ -121,7 +121,7 @@ async fn test_config_import() {
 
     // existing devices assertion
     // imported config for an existing device
-    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::DeviceModified(..));
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(..));
     let user_device_1 = UserDevice::from_device(&pool, device_1)
         .await
         .unwrap()
@@ -129,7 +129,7 @@ async fn test_config_import() {
     assert_eq!(user_device_1.networks.len(), 2);
     assert_eq!(user_device_1.networks[1].device_wireguard_ip, "10.0.0.12");
     // generated IP for other existing device
-    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::DeviceCreated(..));
+    assert_matches!(wg_rx.try_recv().unwrap(), GatewayEvent::PeerAdded(..));
     let user_device_2 = UserDevice::from_device(&pool, device_2)
         .await
         .unwrap()
@@ -172,21 +172,21 @@ async fn test_config_import() {
         .await;
     assert_eq!(response.status(), StatusCode::CREATED);
 
-    // assert events
-    let event = wg_rx.try_recv().unwrap();
-    match event {
-        GatewayEvent::DeviceCreated(device_info) => {
-            assert_eq!(device_info.device.name, "device_1");
-        }
-        _ => unreachable!("Invalid event type received"),
-    }
-
-    let event = wg_rx.try_recv().unwrap();
-    match event {
-        GatewayEvent::DeviceCreated(device_info) => {
-            assert_eq!(device_info.device.name, "device_2");
+    // assert events, mapped devices are added to both networks
+    for device_name in ["device_1", "device_2"] {
+        let mut network_ids = Vec::new();
+        for _ in 0..2 {
+            let event = wg_rx.try_recv().unwrap();
+            match event {
+                GatewayEvent::PeerAdded(peer) => {
+                    assert_eq!(peer.device.name, device_name);
+                    network_ids.push(peer.network_id());
+                }
+                _ => unreachable!("Invalid event type received"),
+            }
         }
-        _ => unreachable!("Invalid event type received"),
+        network_ids.sort_unstable();
+        assert_eq!(network_ids, vec![1, network.id.unwrap()]);
     }
 
     let event = wg_rx.try_recv();
@@ -278,7 +278,7 @@ async fn test_config_import_invalid_key() {
     // import network
     let response = client
         .post("/api/v1/network/import")
-        .json(&json!({"name": "network", "endpoint": "192.168.1.1", "config": wg_config}))
+        .json(&json!({"name": "network", "endpoint": "192.168.1.1", "config": wg_config, "allowed_groups": []}))
         .send()
         .await;
     assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
@@ -303,10 +303,12 @@ async fn test_config_import_invalid_key() {
     ";
     let response = client
         .post("/api/v1/network/import")
-        .json(&json!({"name": "network", "endpoint": "192.168.1.1", "config": wg_config}))
+        .json(&json!({"name": "network", "endpoint": "192.168.1.1", "config": wg_config, "allowed_groups": []}))
         .send()
         .await;
     assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let error: Value = response.json().await;
+    assert_eq!(error["msg"], "Public key invalid_key is not valid base64");
 }
 
 #[tokio::test]
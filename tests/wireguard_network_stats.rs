@@ -3,15 +3,18 @@ mod common;
 use chrono::{Datelike, Duration, NaiveDate, SubsecRound, Timelike, Utc};
 use defguard::{
     db::{
-        models::wireguard::{
-            WireguardDeviceTransferRow, WireguardNetworkStats, WireguardUserStatsRow,
+        models::{
+            device::WireguardNetworkDevice,
+            wireguard::{WireguardDeviceTransferRow, WireguardNetworkStats, WireguardUserStatsRow},
         },
-        Device, WireguardPeerStats,
+        Device, GatewayEvent, WireguardPeerStats,
     },
     handlers::Auth,
+    wireguard_peer_disconnect::disconnect_inactive_peers,
 };
 use reqwest::StatusCode;
 use serde_json::{json, Value};
+use tokio::sync::broadcast;
 
 use self::common::make_test_client;
 
@@ -285,3 +288,223 @@ async fn test_stats() {
             .sum::<i64>()
     );
 }
+
+#[tokio::test]
+async fn test_stats_gateway() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .post("/api/v1/network")
+        .json(&make_network())
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let device = json!({
+        "name": "device-1",
+        "wireguard_pubkey": "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+    });
+    let response = client
+        .post("/api/v1/device/admin")
+        .json(&device)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // not attributed until a gateway reports the peer
+    let response = client.get("/api/v1/device/1").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let details: Value = response.json().await;
+    assert_eq!(details["gateways"], json!([]));
+
+    // the most recent handshake wins
+    let now = Utc::now().naive_utc();
+    for (gateway, handshake, recorded) in [
+        ("gw1", now - Duration::minutes(1), true),
+        ("gw2", now, true),
+        ("gw1", now - Duration::seconds(30), false),
+    ] {
+        let previous = WireguardNetworkDevice::record_gateway(&pool, 1, 1, gateway, handshake)
+            .await
+            .unwrap();
+        assert_eq!(previous.is_some(), recorded, "{gateway}");
+    }
+    let mut stats = WireguardPeerStats {
+        id: None,
+        device_id: 1,
+        collected_at: now,
+        network: 1,
+        endpoint: Some("11.22.33.44".into()),
+        upload: 10,
+        download: 20,
+        latest_handshake: now,
+        allowed_ips: Some("10.1.1.0/24".into()),
+    };
+    stats.save(&pool).await.unwrap();
+
+    let response = client.get("/api/v1/device/1").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let details: Value = response.json().await;
+    assert_eq!(details["gateways"][0]["network_id"], 1);
+    assert_eq!(details["gateways"][0]["hostname"], "gw2");
+    let response = client
+        .get(format!(
+            "/api/v1/network/1/stats/users?from={}",
+            (now - Duration::hours(1)).format("%Y-%m-%dT%H:%M:00Z"),
+        ))
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let stats: Vec<WireguardUserStatsRow> = response.json().await;
+    assert_eq!(stats[0].devices[0].gateway.as_deref(), Some("gw2"));
+}
+
+#[tokio::test]
+async fn test_connected_clients() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+
+    let auth = Auth::new("admin", "pass123");
+    let response = &client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut network = make_network();
+    network["mfa_enabled"] = json!(true);
+    let response = client.post("/api/v1/network").json(&network).send().await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // devices with handshakes on both sides of the 180 seconds disconnect threshold
+    let now = Utc::now().naive_utc();
+    let mut device_ids = Vec::new();
+    for (name, username, pubkey) in [
+        (
+            "recent",
+            "admin",
+            "LQKsT6/3HWKuJmMulH63R8iK+5sI8FyYEL6WDIi6lQU=",
+        ),
+        (
+            "threshold",
+            "hpotter",
+            "sIhx53MsX+iLk83sssybHrD7M+5m+CmpLzWL/zo8C38=",
+        ),
+        (
+            "stale",
+            "admin",
+            "sejIy0WCLvOR7vWNchP9Elsayp3UTK/QCnEJmhsHKTc=",
+        ),
+        (
+            "silent",
+            "hpotter",
+            "hNuapt7lOxF93KUqZGUY00oKJxH8LYwwsUVB1uUa0y4=",
+        ),
+    ] {
+        let response = client
+            .post(format!("/api/v1/device/{username}"))
+            .json(&json!({"name": name, "wireguard_pubkey": pubkey}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let device: Value = response.json().await;
+        let device_id = device["device"]["id"].as_i64().unwrap();
+        let mut network_device = WireguardNetworkDevice::find(&pool, device_id, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        network_device.is_authorized = true;
+        network_device.authorized_at = Some(now - Duration::hours(1));
+        network_device.update(&pool).await.unwrap();
+        device_ids.push(device_id);
+    }
+    let (recent, threshold, stale, silent) =
+        (device_ids[0], device_ids[1], device_ids[2], device_ids[3]);
+    for (device_id, collected_ago, handshake_ago, upload, download) in [
+        (recent, 120, 150, 100, 100),
+        (recent, 30, 60, 1100, 2100),
+        (threshold, 30, 170, 5000, 5000),
+        (stale, 30, 190, 7000, 7000),
+    ] {
+        let mut stats = WireguardPeerStats {
+            id: None,
+            device_id,
+            collected_at: now - Duration::seconds(collected_ago),
+            network: 1,
+            endpoint: Some(format!("11.22.33.{device_id}:51820")),
+            upload,
+            download,
+            latest_handshake: now - Duration::seconds(handshake_ago),
+            allowed_ips: Some("10.1.1.0/24".into()),
+        };
+        stats.save(&pool).await.unwrap();
+    }
+
+    let list = |query: &'static str| {
+        let client = &client;
+        async move {
+            let response = client
+                .get(format!("/api/v1/network/1/connected{query}"))
+                .send()
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            response.json::<Value>().await
+        }
+    };
+    let ids = |connected: &Value| -> Vec<i64> {
+        connected["clients"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|client| client["device_id"].as_i64().unwrap())
+            .collect()
+    };
+
+    // longest connected first by default
+    let connected = list("").await;
+    assert_eq!(connected["total"], 2);
+    assert_eq!(ids(&connected), [threshold, recent]);
+    let first = &connected["clients"][0];
+    assert_eq!(first["username"], "hpotter");
+    assert_eq!(first["device_name"], "threshold");
+    assert_eq!(first["endpoint"], format!("11.22.33.{threshold}:51820"));
+    assert!(first["mfa_session_remaining"].as_i64().unwrap() <= 10);
+    let second = &connected["clients"][1];
+    assert_eq!(second["username"], "admin");
+    assert_eq!(second["upload"], 1000);
+    assert_eq!(second["download"], 2000);
+    assert!(second["wireguard_ip"].is_string());
+    assert!(second["connected_since"].is_string());
+    let remaining = second["mfa_session_remaining"].as_i64().unwrap();
+    assert!(remaining > 100 && remaining <= 120);
+
+    // sorting and pagination
+    let connected = list("?sort=transfer").await;
+    assert_eq!(ids(&connected), [recent, threshold]);
+    let connected = list("?limit=1&offset=1").await;
+    assert_eq!(connected["total"], 2);
+    assert_eq!(ids(&connected), [recent]);
+    let connected = list("?username=hpotter").await;
+    assert_eq!(connected["total"], 1);
+    assert_eq!(ids(&connected), [threshold]);
+    let response = client
+        .get("/api/v1/network/1/connected?limit=0")
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // inactive peer disconnection agrees on who is connected
+    let (wireguard_tx, mut wireguard_rx) = broadcast::channel(16);
+    disconnect_inactive_peers(&pool, &wireguard_tx)
+        .await
+        .unwrap();
+    let mut disconnected = Vec::new();
+    while let Ok(event) = wireguard_rx.try_recv() {
+        match event {
+            GatewayEvent::PeerRemoved(update) => disconnected.push(update.device.id.unwrap()),
+            event => panic!("Unexpected event {event:?}"),
+        }
+    }
+    disconnected.sort_unstable();
+    assert_eq!(disconnected, [stale, silent]);
+    assert_eq!(ids(&list("").await), [threshold, recent]);
+}
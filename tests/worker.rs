@@ -3,13 +3,24 @@ mod common;
 use std::sync::{Arc, Mutex};
 
 use defguard::{
-    grpc::{worker::JobStatus, WorkerDetail, WorkerState},
+    db::models::worker_job::{WorkerJob, WorkerJobStatus},
+    grpc::{
+        worker::{
+            reclaim_abandoned_jobs, worker_service_server::WorkerService, JobStatus, Worker,
+            WorkerServer,
+        },
+        WorkerDetail, WorkerState,
+    },
     handlers::{
         worker::{JobData, Jobid},
         Auth,
     },
 };
 use reqwest::StatusCode;
+use serde_json::Value;
+use sqlx::query;
+use tokio::sync::mpsc::unbounded_channel;
+use tonic::{Code, Request};
 
 use self::common::{client::TestClient, make_test_client};
 
@@ -218,3 +229,113 @@ async fn test_worker_management_permissions() {
     let response = client.delete("/api/v1/worker/worker_2").send().await;
     assert_eq!(response.status(), StatusCode::FORBIDDEN);
 }
+
+#[tokio::test]
+async fn test_worker_jobs_survive_restart() {
+    let (client, client_state) = make_test_client().await;
+    let pool = client_state.pool;
+    client_state
+        .worker_state
+        .lock()
+        .unwrap()
+        .register_worker("YubiBridge".into());
+
+    let auth = Auth::new("admin", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // jobs can't be queued for unknown workers
+    let job_data = JobData {
+        username: "hpotter".to_string(),
+        worker: "unknown".to_string(),
+    };
+    let response = client
+        .post("/api/v1/worker/job")
+        .json(&job_data)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let job_data = JobData {
+        username: "hpotter".to_string(),
+        worker: "YubiBridge".to_string(),
+    };
+    let response = client
+        .post("/api/v1/worker/job")
+        .json(&job_data)
+        .send()
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let job_id = response.json::<Jobid>().await.id;
+
+    let response = client.get("/api/v1/worker/jobs").send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let jobs: Vec<Value> = response.json().await;
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0]["username"], "hpotter");
+    assert_eq!(jobs[0]["status"], "pending");
+
+    // simulate restart: rebuild state from the database, the worker isn't registered yet
+    let (tx, _rx) = unbounded_channel();
+    let mut state = WorkerState::new(tx);
+    state.restore_jobs(WorkerJob::unfinished(&pool).await.unwrap());
+    let state = Arc::new(Mutex::new(state));
+    let server = WorkerServer::new(pool.clone(), Arc::clone(&state));
+    let worker = || {
+        Request::new(Worker {
+            id: "YubiBridge".into(),
+        })
+    };
+
+    // job is dispatched exactly once
+    let job = server.get_job(worker()).await.unwrap().into_inner();
+    assert_eq!(job.job_id, job_id);
+    assert_eq!(job.email, "h.potter@hogwart.edu.uk");
+    let status = server.get_job(worker()).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    let jobs = WorkerJob::unfinished(&pool).await.unwrap();
+    assert_eq!(jobs[0].status, WorkerJobStatus::Claimed);
+
+    // claimed jobs aren't dispatched again before timeout
+    assert_eq!(reclaim_abandoned_jobs(&pool, &state).await.unwrap(), 0);
+    assert!(server.get_job(worker()).await.is_err());
+
+    // abandoned job is dispatched again
+    query!(
+        "UPDATE worker_job SET claimed_at = now() - interval '1 hour' WHERE id = $1",
+        i64::from(job_id)
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    assert_eq!(reclaim_abandoned_jobs(&pool, &state).await.unwrap(), 1);
+    let job = server.get_job(worker()).await.unwrap().into_inner();
+    assert_eq!(job.job_id, job_id);
+    assert!(server.get_job(worker()).await.is_err());
+
+    // failure is stored with its result
+    server
+        .set_job_done(Request::new(JobStatus {
+            id: "YubiBridge".to_string(),
+            job_id,
+            success: false,
+            public_key: String::new(),
+            ssh_key: String::new(),
+            yubikey_serial: String::new(),
+            error: "YubiKey not found".to_string(),
+        }))
+        .await
+        .unwrap();
+    assert!(WorkerJob::unfinished(&pool).await.unwrap().is_empty());
+    let response = client.get("/api/v1/worker/jobs").send().await;
+    let jobs: Vec<Value> = response.json().await;
+    assert_eq!(jobs[0]["status"], "failed");
+    assert_eq!(jobs[0]["error"], "YubiKey not found");
+
+    // normal user can't list jobs
+    let auth = Auth::new("hpotter", "pass123");
+    let response = client.post("/api/v1/auth").json(&auth).send().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get("/api/v1/worker/jobs").send().await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
@@ -23,6 +23,7 @@ import { PageContainer } from '../../shared/components/Layout/PageContainer/Page
 import { ProtectedRoute } from '../../shared/components/Router/Guards/ProtectedRoute/ProtectedRoute';
 import { ToastManager } from '../../shared/defguard-ui/components/Layout/ToastManager/ToastManager';
 import { useAuthStore } from '../../shared/hooks/store/useAuthStore';
+import { basePath } from '../../shared/utils/basePath';
 import { Navigation } from '../Navigation/Navigation';
 
 const App = () => {
@@ -31,7 +32,7 @@ const App = () => {
   return (
     <>
       <div id="app">
-        <Router>
+        <Router basename={basePath || '/'}>
           <Routes>
             <Route
               path="add-device"
@@ -17,6 +17,7 @@ import {
 import { useAuthStore } from '../../shared/hooks/store/useAuthStore';
 import useApi from '../../shared/hooks/useApi';
 import { useToaster } from '../../shared/hooks/useToaster';
+import { basePath } from '../../shared/utils/basePath';
 import { LoaderPage } from '../loader/LoaderPage';
 
 export const OpenidAllowPage = () => {
@@ -56,7 +57,7 @@ export const OpenidAllowPage = () => {
   const handleSubmit = useCallback(
     (allow: boolean) => {
       params.append('allow', String(allow));
-      const formAction = `/api/v1/oauth/authorize?${params.toString()}`;
+      const formAction = `${basePath}/api/v1/oauth/authorize?${params.toString()}`;
       if (inputRef.current) {
         inputRef.current.formAction = formAction;
         inputRef.current.click();
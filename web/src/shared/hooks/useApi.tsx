@@ -40,6 +40,7 @@ import {
   WorkerJobStatus,
   WorkerToken,
 } from '../types';
+import { basePath } from '../utils/basePath';
 import { removeNulls } from '../utils/removeNulls';
 import { useToaster } from './useToaster';
 
@@ -59,7 +60,8 @@ const useApi = (props?: HookProps): ApiHook => {
 
   const client = useMemo(() => {
     const res = axios.create({
-      baseURL: envBaseUrl && String(envBaseUrl).length > 0 ? envBaseUrl : '/api/v1',
+      baseURL:
+        envBaseUrl && String(envBaseUrl).length > 0 ? envBaseUrl : `${basePath}/api/v1`,
     });
 
     res.defaults.headers.common['Content-Type'] = 'application/json';
@@ -0,0 +1,5 @@
+// Path the app is served under, e.g. `/defguard`; empty when served at the root of the host.
+// Set by the server in the index page, taken from the path of the public URL.
+export const basePath: string =
+  document.querySelector<HTMLMetaElement>('meta[name="defguard-base-path"]')?.content ??
+  '';